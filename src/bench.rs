@@ -0,0 +1,397 @@
+//! Load generation against a running proxy (`bench`) and a synthetic OpenAI backend to point
+//! it at (`mock-backend`), so streaming performance can be measured without a real model.
+
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use axum::{
+    body::Body,
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures::StreamExt;
+use serde_json::{json, Value};
+use crate::config::env_or;
+use crate::constants::DEFAULT_HOST_PORT;
+use crate::services::SseEventParser;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchOptions {
+    /// Proxy base URL
+    pub url: String,
+    pub requests: usize,
+    pub concurrency: usize,
+    pub model: String,
+    pub max_tokens: u32,
+    pub prompt: String,
+    pub api_key: String,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        let port: u16 = env_or("HOST_PORT", DEFAULT_HOST_PORT);
+        Self {
+            url: format!("http://127.0.0.1:{}", port),
+            requests: 100,
+            concurrency: 10,
+            model: "mock-model".into(),
+            max_tokens: 256,
+            prompt: "Write a short story about a lighthouse keeper.".into(),
+            api_key: std::env::var("ANTHROPIC_API_KEY").unwrap_or_else(|_| "bench".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockOptions {
+    pub port: u16,
+    /// Output tokens per response, unless the request asks for fewer
+    pub tokens: u32,
+    /// Streaming rate per response; 0 streams as fast as possible
+    pub tokens_per_sec: u32,
+    /// Delay before the first token
+    pub ttft_ms: u64,
+}
+
+impl Default for MockOptions {
+    fn default() -> Self {
+        Self { port: 8000, tokens: 256, tokens_per_sec: 0, ttft_ms: 0 }
+    }
+}
+
+/// Parse `--name value` / `--name=value` flags into `(name, value)` pairs
+pub fn parse_flags(args: impl IntoIterator<Item = String>) -> Result<Vec<(String, String)>, String> {
+    let mut args = args.into_iter();
+    let mut flags = Vec::new();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(format!("Unexpected argument '{}'", arg));
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => (flag.to_string(), args.next().ok_or_else(|| format!("--{} needs a value", flag))?),
+        };
+        flags.push((name, value));
+    }
+    Ok(flags)
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value for --{}: '{}'", name, value))
+}
+
+impl BenchOptions {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self::default();
+        for (name, value) in parse_flags(args)? {
+            match name.as_str() {
+                "url" => options.url = value.trim_end_matches('/').to_string(),
+                "requests" => options.requests = parse_value(&name, &value)?,
+                "concurrency" => options.concurrency = parse_value(&name, &value)?,
+                "model" => options.model = value,
+                "max-tokens" => options.max_tokens = parse_value(&name, &value)?,
+                "prompt" => options.prompt = value,
+                "api-key" => options.api_key = value,
+                _ => return Err(format!("Unknown bench option --{}", name)),
+            }
+        }
+        if options.requests == 0 || options.concurrency == 0 {
+            return Err("--requests and --concurrency must be at least 1".into());
+        }
+        Ok(options)
+    }
+}
+
+impl MockOptions {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self::default();
+        for (name, value) in parse_flags(args)? {
+            match name.as_str() {
+                "port" => options.port = parse_value(&name, &value)?,
+                "tokens" => options.tokens = parse_value(&name, &value)?,
+                "tokens-per-sec" => options.tokens_per_sec = parse_value(&name, &value)?,
+                "ttft-ms" => options.ttft_ms = parse_value(&name, &value)?,
+                _ => return Err(format!("Unknown mock-backend option --{}", name)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+// ---------- bench ----------
+
+/// Outcome of one benchmark request
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Sample {
+    /// Why the request failed; `None` on success
+    error: Option<String>,
+    ttft: Option<Duration>,
+    total: Duration,
+    output_tokens: u32,
+}
+
+/// Nearest-rank percentile of sorted durations
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+async fn bench_one(client: &reqwest::Client, options: &BenchOptions) -> Sample {
+    let started = Instant::now();
+    let body = json!({
+        "model": options.model,
+        "max_tokens": options.max_tokens,
+        "stream": true,
+        "messages": [{ "role": "user", "content": options.prompt }]
+    });
+    let res = client
+        .post(format!("{}/v1/messages", options.url))
+        .header("x-api-key", &options.api_key)
+        .header("anthropic-version", "2023-06-01")
+        .json(&body)
+        .send()
+        .await;
+    let mut sample = Sample { error: Some("no message_stop".into()), ..Default::default() };
+    let res = match res {
+        Ok(res) if res.status().is_success() => res,
+        Ok(res) => {
+            sample.error = Some(format!("HTTP {}", res.status().as_u16()));
+            sample.total = started.elapsed();
+            return sample;
+        }
+        Err(e) => {
+            sample.error = Some(if e.is_connect() { "connect failed".into() } else { "request failed".into() });
+            sample.total = started.elapsed();
+            return sample;
+        }
+    };
+
+    let mut parser = SseEventParser::new();
+    let mut stream = res.bytes_stream();
+    let mut stop_reason = None;
+    while let Some(Ok(chunk)) = stream.next().await {
+        for payload in parser.push_and_drain_events(&chunk) {
+            let Ok(event) = serde_json::from_str::<Value>(&payload) else { continue };
+            match event["type"].as_str() {
+                Some("content_block_delta") if sample.ttft.is_none() => sample.ttft = Some(started.elapsed()),
+                Some("message_delta") => {
+                    sample.output_tokens = event["usage"]["output_tokens"].as_u64().unwrap_or(0) as u32;
+                    stop_reason = event["delta"]["stop_reason"].as_str().map(str::to_string);
+                }
+                Some("message_stop") => {
+                    sample.error = match stop_reason.as_deref() {
+                        Some("error") => Some("stop_reason error".into()),
+                        _ => None,
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    sample.total = started.elapsed();
+    sample
+}
+
+fn ms(d: Duration) -> String {
+    format!("{:.1}ms", d.as_secs_f64() * 1000.0)
+}
+
+fn report(samples: &[Sample], wall: Duration) {
+    let ok: Vec<&Sample> = samples.iter().filter(|s| s.error.is_none()).collect();
+    let errors = samples.len() - ok.len();
+    let mut reasons: Vec<(&str, usize)> = Vec::new();
+    for reason in samples.iter().filter_map(|s| s.error.as_deref()) {
+        match reasons.iter_mut().find(|(r, _)| *r == reason) {
+            Some((_, count)) => *count += 1,
+            None => reasons.push((reason, 1)),
+        }
+    }
+    let mut ttft: Vec<Duration> = ok.iter().filter_map(|s| s.ttft).collect();
+    let mut total: Vec<Duration> = ok.iter().map(|s| s.total).collect();
+    ttft.sort();
+    total.sort();
+    let tokens: u64 = ok.iter().map(|s| s.output_tokens as u64).sum();
+    let per_stream: Vec<f64> = ok
+        .iter()
+        .filter_map(|s| {
+            let streaming = s.total.saturating_sub(s.ttft?).as_secs_f64();
+            (streaming > 0.0).then(|| s.output_tokens as f64 / streaming)
+        })
+        .collect();
+
+    println!("Requests:      {} ({} errors, {:.1}%)", samples.len(), errors, errors as f64 * 100.0 / samples.len() as f64);
+    for (reason, count) in reasons {
+        println!("  {:>5} × {}", count, reason);
+    }
+    println!("Wall time:     {:.2}s ({:.1} req/s)", wall.as_secs_f64(), samples.len() as f64 / wall.as_secs_f64());
+    println!("TTFT:          p50 {}  p95 {}  max {}", ms(percentile(&ttft, 50.0)), ms(percentile(&ttft, 95.0)), ms(percentile(&ttft, 100.0)));
+    println!("Latency:       p50 {}  p95 {}  max {}", ms(percentile(&total, 50.0)), ms(percentile(&total, 95.0)), ms(percentile(&total, 100.0)));
+    println!(
+        "Output tokens: {} total, {:.0} tok/s aggregate, {:.0} tok/s per stream",
+        tokens,
+        tokens as f64 / wall.as_secs_f64(),
+        if per_stream.is_empty() { 0.0 } else { per_stream.iter().sum::<f64>() / per_stream.len() as f64 }
+    );
+}
+
+/// Fire `requests` streaming Claude requests at the proxy, `concurrency` at a time; exit code 1 if any failed
+pub async fn run_bench(options: BenchOptions) -> i32 {
+    println!(
+        "Benchmarking {} with {} requests, concurrency {}, model {}, max_tokens {}",
+        options.url, options.requests, options.concurrency, options.model, options.max_tokens
+    );
+    let client = reqwest::Client::new();
+    let options = Arc::new(options);
+    let started = Instant::now();
+    let samples: Vec<Sample> = futures::stream::iter(0..options.requests)
+        .map(|_| {
+            let client = client.clone();
+            let options = options.clone();
+            async move { bench_one(&client, &options).await }
+        })
+        .buffer_unordered(options.concurrency)
+        .collect()
+        .await;
+    report(&samples, started.elapsed());
+    if samples.iter().all(|s| s.error.is_none()) { 0 } else { 1 }
+}
+
+// ---------- mock backend ----------
+
+fn chunk(delta: Value, finish_reason: Option<&str>) -> Value {
+    json!({
+        "id": "chatcmpl-mock",
+        "object": "chat.completion.chunk",
+        "model": "mock-model",
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }]
+    })
+}
+
+fn sse(data: &Value) -> String {
+    format!("data: {}\n\n", data)
+}
+
+async fn mock_models() -> Json<Value> {
+    Json(json!({
+        "object": "list",
+        "data": [{ "id": "mock-model", "object": "model", "supported_features": ["tools"] }]
+    }))
+}
+
+async fn mock_completions(State(options): State<Arc<MockOptions>>, Json(req): Json<Value>) -> Response {
+    let max_tokens = req["max_completion_tokens"].as_u64().or_else(|| req["max_tokens"].as_u64());
+    let tokens = max_tokens.map_or(options.tokens, |m| options.tokens.min(m as u32));
+    let interval = (options.tokens_per_sec > 0).then(|| Duration::from_secs_f64(1.0 / options.tokens_per_sec as f64));
+    let ttft = Duration::from_millis(options.ttft_ms);
+
+    let events = mock_events(tokens, interval, ttft);
+    Response::builder()
+        .header("content-type", "text/event-stream")
+        .body(Body::from_stream(events.map(Ok::<_, Infallible>)))
+        .unwrap_or_else(|_| ().into_response())
+}
+
+/// One role chunk, `tokens` word deltas, a finish chunk with usage, and `[DONE]`
+fn mock_events(tokens: u32, interval: Option<Duration>, ttft: Duration) -> impl futures::Stream<Item = String> {
+    futures::stream::unfold(0u32, move |i| async move {
+        let event = match i {
+            0 => {
+                if !ttft.is_zero() {
+                    tokio::time::sleep(ttft).await;
+                }
+                sse(&chunk(json!({ "role": "assistant", "content": "" }), None))
+            }
+            n if n <= tokens => {
+                if let Some(interval) = interval {
+                    tokio::time::sleep(interval).await;
+                }
+                sse(&chunk(json!({ "content": "lorem " }), None))
+            }
+            n if n == tokens + 1 => {
+                let mut last = chunk(json!({}), Some("stop"));
+                last["usage"] = json!({ "prompt_tokens": 10, "completion_tokens": tokens, "total_tokens": tokens });
+                sse(&last)
+            }
+            n if n == tokens + 2 => "data: [DONE]\n\n".to_string(),
+            _ => return None,
+        };
+        Some((event, i + 1))
+    })
+}
+
+/// Serve a synthetic OpenAI-compatible backend until interrupted
+pub async fn run_mock_backend(options: MockOptions) -> i32 {
+    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], options.port));
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("❌ Failed to bind {}: {}", addr, e);
+            return 1;
+        }
+    };
+    println!(
+        "Mock backend on http://{}/v1/chat/completions ({} tokens per response, {}, first token after {}ms)",
+        addr,
+        options.tokens,
+        if options.tokens_per_sec > 0 { format!("{} tok/s", options.tokens_per_sec) } else { "unthrottled".into() },
+        options.ttft_ms
+    );
+    let router = Router::new()
+        .route("/v1/models", get(mock_models))
+        .route("/v1/chat/completions", post(mock_completions))
+        .with_state(Arc::new(options));
+    let server = axum::serve(listener, router).with_graceful_shutdown(async {
+        tokio::signal::ctrl_c().await.ok();
+    });
+    match server.await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("❌ Mock backend error: {}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_bench_options() {
+        let options = BenchOptions::parse(args(&["--requests", "5", "--concurrency=2", "--url", "http://x:1/"])).unwrap();
+        assert_eq!((options.requests, options.concurrency), (5, 2));
+        assert_eq!(options.url, "http://x:1");
+        assert!(BenchOptions::parse(args(&["--requests"])).is_err());
+        assert!(BenchOptions::parse(args(&["--concurrency", "0"])).is_err());
+        assert!(BenchOptions::parse(args(&["--bogus", "1"])).is_err());
+    }
+
+    #[test]
+    fn test_percentile_nearest_rank() {
+        let sorted: Vec<Duration> = (1..=20).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(10));
+        assert_eq!(percentile(&sorted, 95.0), Duration::from_millis(19));
+        assert_eq!(percentile(&sorted, 100.0), Duration::from_millis(20));
+        assert_eq!(percentile(&[], 95.0), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_mock_stream_shape() {
+        let events: Vec<String> = mock_events(2, None, Duration::ZERO).collect().await;
+        assert_eq!(events.len(), 5);
+        assert!(events[1].contains("lorem"));
+        assert!(events[3].contains("\"completion_tokens\":2"));
+        assert_eq!(events[4], "data: [DONE]\n\n");
+    }
+}
@@ -0,0 +1,302 @@
+//! Operational subcommands that reuse the proxy's services without starting the server
+
+use std::{env, str::FromStr};
+use axum::http::HeaderName;
+use crate::bench::{BenchOptions, MockOptions};
+use crate::config::{admin_bind_addrs, backend_env_key, public_bind_addrs, ProxyConfig};
+use crate::constants::*;
+use crate::models::{BackendRegistry, ClaudeTokenCountRequest};
+use crate::services::{AuthPrecedence, BudgetEnforcement, ErrorVerbosity, ExtraChoices, IpNet, MessageTemplates, ModelNotFound, Sandbox, SseBufferPolicy, StreamingMode, ThinkingDialect, ThinkingOutput, ToolLimitPolicy, ModerationAction, ModerationScope, CompactionThresholds};
+use crate::utils::{content_extraction::ContentFilterStopReason, prefill::PrefillMode, tool_ids::ToolIdFormat, tool_schema::StrictTools};
+
+pub const USAGE: &str = "\
+Usage: claude_openai_proxy [COMMAND]
+
+Commands:
+  serve               Run the proxy (default)
+  check-config        Validate the environment configuration and print the resolved backends
+  list-models         Fetch and print the backend model list with pricing tiers
+  count-tokens FILE   Count input tokens of a Claude request JSON file
+  bench [OPTIONS]     Fire streaming requests at a running proxy and report TTFT, tok/s and latency
+                        --url URL (default http://127.0.0.1:$HOST_PORT)  --requests N (100)
+                        --concurrency N (10)  --model NAME (mock-model)  --max-tokens N (256)
+                        --prompt TEXT  --api-key KEY (default $ANTHROPIC_API_KEY)
+  mock-backend [OPTIONS]
+                      Serve a synthetic OpenAI-compatible backend for benchmarking
+                        --port N (8000)  --tokens N (256)  --tokens-per-sec N (0 = unthrottled)
+                        --ttft-ms N (0)
+  help                Show this message";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    CheckConfig,
+    ListModels,
+    CountTokens(String),
+    Bench(BenchOptions),
+    MockBackend(MockOptions),
+    Help,
+}
+
+impl Command {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.into_iter();
+        let command = match args.next().as_deref() {
+            None | Some("serve") => Command::Serve,
+            Some("check-config") => Command::CheckConfig,
+            Some("list-models") => Command::ListModels,
+            Some("count-tokens") => match args.next() {
+                Some(path) => Command::CountTokens(path),
+                None => return Err("count-tokens requires a FILE argument".into()),
+            },
+            Some("bench") => return BenchOptions::parse(args).map(Command::Bench),
+            Some("mock-backend") => return MockOptions::parse(args).map(Command::MockBackend),
+            Some("help" | "-h" | "--help") => Command::Help,
+            Some(other) => return Err(format!("Unknown command '{}'", other)),
+        };
+        match args.next() {
+            Some(extra) => Err(format!("Unexpected argument '{}'", extra)),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Run a non-server command; returns the process exit code
+pub async fn run(command: Command) -> i32 {
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Help => {
+            println!("{}", USAGE);
+            0
+        }
+        Command::CheckConfig => check_config(),
+        Command::ListModels => list_models().await,
+        Command::CountTokens(path) => count_tokens(&path),
+        Command::Bench(options) => crate::bench::run_bench(options).await,
+        Command::MockBackend(options) => crate::bench::run_mock_backend(options).await,
+    }
+}
+
+/// Setting name and a check that its value parses
+type Check = (&'static str, fn(&str) -> bool);
+
+fn parses<T: FromStr>(value: &str) -> bool {
+    value.trim().parse::<T>().is_ok()
+}
+
+/// Every entry of a comma-separated list parses
+fn parses_list<T: FromStr>(value: &str) -> bool {
+    value.split(',').map(str::trim).filter(|entry| !entry.is_empty()).all(parses::<T>)
+}
+
+/// Typed global settings; anything set but unparseable would silently fall back to its default
+const GLOBAL_CHECKS: &[Check] = &[
+    ("HOST_PORT", parses::<u16>),
+    ("BACKEND_TIMEOUT_SECS", parses::<u64>),
+    ("BACKEND_CONNECT_TIMEOUT_SECS", parses::<u64>),
+    ("BACKEND_FIRST_TOKEN_TIMEOUT_SECS", parses::<u64>),
+    ("BACKEND_TOTAL_TIMEOUT_SECS", parses::<u64>),
+    ("ENABLE_CIRCUIT_BREAKER", parses::<bool>),
+    ("TRUSTED_PROXIES", parses_list::<IpNet>),
+    ("COMPRESSION", parses::<bool>),
+    ("COMPRESSION_MIN_BYTES", parses::<u16>),
+    ("STREAM_COALESCE_BYTES", parses::<usize>),
+    ("STREAM_COALESCE_MS", parses::<u64>),
+    ("STREAM_RESUME_SECS", parses::<u64>),
+    ("MESSAGE_STORE_TTL_SECS", parses::<u64>),
+    ("STREAM_SPLIT_BYTES", parses::<usize>),
+    ("STREAM_SPLIT_DELAY_MS", parses::<u64>),
+    ("SSE_BUFFER_LIMIT_KB", parses::<usize>),
+    ("SSE_BUFFER_POLICY", parses::<SseBufferPolicy>),
+    ("SSE_BUFFER_HARD_CAP_KB", parses::<usize>),
+    ("PREFILL_MODE", parses::<PrefillMode>),
+    ("AUTH_HEADER_PRECEDENCE", parses::<AuthPrecedence>),
+    ("ENFORCE_STOP_SEQUENCES", parses::<bool>),
+    ("AUTO_CONTINUE_TOKENS", parses::<u32>),
+    ("EXTRA_CHOICES", parses::<ExtraChoices>),
+    ("MODEL_NOT_FOUND", parses::<ModelNotFound>),
+    ("ERROR_VERBOSITY", parses::<ErrorVerbosity>),
+    ("MESSAGE_TEMPLATES", |value| MessageTemplates::load(value).is_ok()),
+    ("MAX_CONCURRENT_REQUESTS", parses::<usize>),
+    ("ADMISSION_QUEUE_DEPTH", parses::<usize>),
+    ("ADMISSION_QUEUE_WAIT_MS", parses::<u64>),
+    ("MAX_STREAMS_PER_KEY", parses::<usize>),
+    ("KEY_STREAM_QUEUE_DEPTH", parses::<usize>),
+    ("KEY_STREAM_QUEUE_WAIT_MS", parses::<u64>),
+    ("FILES_MAX_BYTES", parses::<usize>),
+    ("WEB_SEARCH_MAX_RESULTS", parses::<usize>),
+    ("CODE_EXECUTION_SANDBOX", parses::<Sandbox>),
+    ("CODE_EXECUTION_TIMEOUT_SECS", parses::<u64>),
+    ("THINKING_BUDGET_ENFORCEMENT", parses::<BudgetEnforcement>),
+    ("THINKING_OUTPUT", parses::<ThinkingOutput>),
+    ("CONTENT_FILTER_STOP_REASON", parses::<ContentFilterStopReason>),
+    ("LLM_TRACE_MAX_CONTENT_CHARS", parses::<usize>),
+    ("REQUEST_LOG_RETENTION_DAYS", parses::<u64>),
+    ("SLOW_BACKEND_MS", parses::<u64>),
+    ("SHADOW_PERCENT", parses::<f64>),
+    ("SHADOW_MAX_CONCURRENT", parses::<usize>),
+    ("MAX_TOOLS", parses::<usize>),
+    ("MAX_TOOL_SCHEMA_BYTES", parses::<usize>),
+    ("TOOL_LIMIT_POLICY", parses::<ToolLimitPolicy>),
+    ("COMPACTION_THRESHOLD_TOKENS", parses::<CompactionThresholds>),
+    ("COMPACTION_KEEP_MESSAGES", parses::<usize>),
+    ("COMPACTION_SUMMARY_MAX_TOKENS", parses::<u32>),
+    ("MODERATION_ACTION", parses::<ModerationAction>),
+    ("MODERATION_SCOPE", parses::<ModerationScope>),
+    ("MODERATION_STREAM_CHARS", parses::<usize>),
+    ("MODERATION_FAIL_CLOSED", parses::<bool>),
+    ("MODERATION_IMAGES", parses::<bool>),
+    ("PII_RESTORE", parses::<bool>),
+];
+
+/// Typed per-backend settings, checked both globally and as `BACKEND_<NAME>_<OPTION>`
+const BACKEND_CHECKS: &[Check] = &[
+    ("TEMPERATURE_SCALE", parses::<f32>),
+    ("TEMPERATURE_MAX", parses::<f32>),
+    ("THINKING_DIALECT", parses::<ThinkingDialect>),
+    ("SYSTEM_ROLE", |v| matches!(v.trim(), "system" | "developer")),
+    ("MERGE_SAME_ROLE", parses::<bool>),
+    ("REPAIR_ORDERING", parses::<bool>),
+    ("TOOL_EMULATION", parses::<bool>),
+    ("TOOL_RESULTS_AS_TEXT", parses::<bool>),
+    ("TOOL_DESCRIPTION_MAX_CHARS", parses::<usize>),
+    ("STRICT_TOOLS", parses::<StrictTools>),
+    ("PROMPT_CACHING", parses::<bool>),
+    ("TOOL_ID_FORMAT", parses::<ToolIdFormat>),
+    ("STREAMING", parses::<StreamingMode>),
+    ("SESSION_HEADER", parses::<HeaderName>),
+    ("REQUEST_GZIP_MIN_BYTES", parses::<usize>),
+    ("COST", parses::<f64>),
+    ("IMAGE_TRANSCODE", parses::<bool>),
+    ("MAX_IMAGES", parses::<usize>),
+    ("MAX_IMAGE_BYTES", parses::<usize>),
+];
+
+fn check_url(key: &str, url: &str, errors: &mut Vec<String>) {
+    match reqwest::Url::parse(url) {
+        Ok(u) if matches!(u.scheme(), "http" | "https") => {}
+        _ => errors.push(format!("{}: '{}' is not an http(s) URL", key, url)),
+    }
+}
+
+fn check_config() -> i32 {
+    let mut errors = Vec::new();
+    let check = |key: &str, valid: fn(&str) -> bool, errors: &mut Vec<String>| {
+        if let Ok(value) = env::var(key) {
+            if !valid(&value) {
+                errors.push(format!("{}: invalid value '{}'", key, value));
+            }
+        }
+    };
+    for (key, valid) in GLOBAL_CHECKS.iter().chain(BACKEND_CHECKS) {
+        check(key, *valid, &mut errors);
+    }
+
+    for (key, addrs) in [("BIND_ADDR", public_bind_addrs()), ("ADMIN_BIND_ADDR", admin_bind_addrs())] {
+        match addrs {
+            Ok(addrs) if addrs.is_empty() => {}
+            Ok(addrs) => println!("{}: {}", key, addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", ")),
+            Err(e) => errors.push(format!("{}: {}", key, e)),
+        }
+    }
+
+    let backend_url = env::var("BACKEND_URL").unwrap_or_else(|_| DEFAULT_BACKEND_URL.into());
+    check_url("BACKEND_URL", &backend_url, &mut errors);
+    let registry = BackendRegistry::new(backend_url, &crate::config::env_list("BACKENDS"));
+    for name in registry.names() {
+        let Some(backend) = registry.get(name) else { continue };
+        check_url(&format!("BACKENDS ({})", name), &backend.url, &mut errors);
+        for (key, valid) in BACKEND_CHECKS {
+            check(&backend_env_key(name, key), *valid, &mut errors);
+        }
+        println!("Backend '{}': {}", backend.name, backend.url);
+        println!("  {:?}", backend.options);
+    }
+
+    let config = ProxyConfig::from_env();
+    println!(
+        "Prefill: {:?}, thinking output: {:?}, budget enforcement: {:?}, stop sequences enforced: {}",
+        config.prefill_mode, config.thinking_output, config.thinking_budget_enforcement, config.enforce_stop_sequences
+    );
+    println!("Admin endpoints: {}", if config.admin_token.is_some() { "enabled" } else { "disabled" });
+
+    if errors.is_empty() {
+        println!("✅ Configuration OK");
+        0
+    } else {
+        for e in &errors {
+            eprintln!("❌ {}", e);
+        }
+        1
+    }
+}
+
+async fn list_models() -> i32 {
+    let backend_url = env::var("BACKEND_URL").unwrap_or_else(|_| DEFAULT_BACKEND_URL.into());
+    let client = reqwest::Client::new();
+    let mut models = match crate::services::model_cache::fetch_models(&client, &backend_url).await {
+        Ok(models) => models,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch models from {}: {}", backend_url, e);
+            return 1;
+        }
+    };
+    models.sort_by_key(|m| m.id.to_lowercase());
+    let price = |p: Option<f64>| p.map(|p| format!("${:.2}", p)).unwrap_or_else(|| "-".into());
+    println!("{:4} {:50} {:>9} {:>9}  FEATURES", "TIER", "MODEL", "IN/1M", "OUT/1M");
+    for m in &models {
+        println!(
+            "{:4} {:50} {:>9} {:>9}  {}",
+            get_price_tier(m.input_price_usd, m.output_price_usd),
+            m.id,
+            price(m.input_price_usd),
+            price(m.output_price_usd),
+            m.supported_features.join(",")
+        );
+    }
+    println!("{} models", models.len());
+    0
+}
+
+fn count_tokens(path: &str) -> i32 {
+    let req: ClaudeTokenCountRequest = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|body| serde_json::from_str(&body).map_err(|e| e.to_string()))
+    {
+        Ok(req) => req,
+        Err(e) => {
+            eprintln!("❌ Failed to read Claude request from {}: {}", path, e);
+            return 1;
+        }
+    };
+    println!("{}", crate::handlers::token_count::count_request_tokens(&req));
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        Command::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(parse(&[]), Ok(Command::Serve));
+        assert_eq!(parse(&["serve"]), Ok(Command::Serve));
+        assert_eq!(parse(&["check-config"]), Ok(Command::CheckConfig));
+        assert_eq!(parse(&["list-models"]), Ok(Command::ListModels));
+        assert_eq!(parse(&["count-tokens", "req.json"]), Ok(Command::CountTokens("req.json".into())));
+        assert_eq!(parse(&["--help"]), Ok(Command::Help));
+        assert!(matches!(parse(&["bench", "--requests", "3"]), Ok(Command::Bench(o)) if o.requests == 3));
+        assert!(matches!(parse(&["mock-backend", "--port=9000"]), Ok(Command::MockBackend(o)) if o.port == 9000));
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_arguments() {
+        assert!(parse(&["count-tokens"]).is_err());
+        assert!(parse(&["serve", "extra"]).is_err());
+        assert!(parse(&["bogus"]).is_err());
+    }
+}
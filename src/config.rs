@@ -0,0 +1,277 @@
+//! Environment-variable configuration helpers
+//!
+//! All runtime settings are read from the environment (optionally via `.env`).
+//! These helpers keep the parsing rules consistent across subsystems.
+
+use std::{env, str::FromStr};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use axum::http::{HeaderName, HeaderValue};
+use crate::constants::{DEFAULT_API_KEY_QUERY_PARAM, DEFAULT_STREAM_COALESCE_MS, DEFAULT_STREAM_SPLIT_DELAY_MS, DEFAULT_WEB_SEARCH_MAX_RESULTS,
+                       DEFAULT_CODE_EXECUTION_IMAGE, DEFAULT_CODE_EXECUTION_TIMEOUT_SECS, DEFAULT_SSE_BUFFER_LIMIT_KB, DEFAULT_SSE_BUFFER_HARD_CAP_KB};
+use crate::services::{AuthPrecedence, BackendTimeouts, BudgetEnforcement, ClientAuth, CoalesceConfig, ExtraChoices, ErrorVerbosity, ModelNotFound, SplitConfig, SseBufferLimit, SseBufferPolicy, ThinkingDialect, ThinkingOutput, ToolLimitPolicy, ToolLimits,
+                      CodeExecutionConfig, IpNet, Sandbox, ServerToolConfig, WebSearchConfig};
+use crate::utils::content_extraction::ContentFilterStopReason;
+use crate::utils::image::{parse_image_limit_overrides, ImageLimitsOverride};
+use crate::utils::prefill::PrefillMode;
+
+/// Parse an environment variable, returning `None` when unset or invalid
+pub fn env_parse<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|s| s.trim().parse::<T>().ok())
+}
+
+/// Parse an environment variable, falling back to `default` when unset or invalid
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env_parse(key).unwrap_or(default)
+}
+
+/// Read a comma-separated list, skipping blank entries
+pub fn env_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .map(|s| {
+            s.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Environment key for a per-backend setting: `BACKEND_<NAME>_<KEY>`
+pub fn backend_env_key(backend: &str, key: &str) -> String {
+    let prefix: String = backend
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("BACKEND_{}_{}", prefix, key)
+}
+
+/// Per-backend setting: `BACKEND_<NAME>_<KEY>` first, then the global `<KEY>`
+pub fn backend_env_parse<T: FromStr>(backend: &str, key: &str) -> Option<T> {
+    env_parse(&backend_env_key(backend, key)).or_else(|| env_parse(key))
+}
+
+/// Per-backend comma-separated list: `BACKEND_<NAME>_<KEY>` when set, otherwise the global `<KEY>`
+pub fn backend_env_list(backend: &str, key: &str) -> Vec<String> {
+    let backend_key = backend_env_key(backend, key);
+    if env::var(&backend_key).is_ok() {
+        env_list(&backend_key)
+    } else {
+        env_list(key)
+    }
+}
+
+/// Resolve listen addresses such as `0.0.0.0`, `::`, `[::1]:9090`, `localhost:9090`.
+///
+/// Entries without a port use `default_port`; with no default, a port is required.
+/// Host names may resolve to several addresses (e.g. `localhost` to `127.0.0.1` and `::1`).
+pub fn parse_bind_addrs(entries: &[String], default_port: Option<u16>) -> Result<Vec<SocketAddr>, String> {
+    let mut addrs = Vec::new();
+    for entry in entries {
+        let entry = entry.trim();
+        if let Ok(addr) = entry.parse::<SocketAddr>() {
+            addrs.push(addr);
+            continue;
+        }
+        let has_port = entry.rsplit_once(':').is_some_and(|(host, port)| {
+            port.parse::<u16>().is_ok() && (host.ends_with(']') || !host.contains(':'))
+        });
+        let with_port = match (has_port, default_port) {
+            (true, _) => entry.to_string(),
+            (false, Some(port)) => match entry.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+                Ok(ip) => {
+                    addrs.push(SocketAddr::new(ip, port));
+                    continue;
+                }
+                Err(_) => format!("{}:{}", entry, port),
+            },
+            (false, None) => return Err(format!("'{}' needs a port", entry)),
+        };
+        for addr in with_port.to_socket_addrs().map_err(|e| format!("'{}': {}", entry, e))? {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    Ok(addrs)
+}
+
+/// Public API listen addresses: `BIND_ADDR` (or `HOST`), default `0.0.0.0`, on `HOST_PORT`
+pub fn public_bind_addrs() -> Result<Vec<SocketAddr>, String> {
+    let mut entries = env_list("BIND_ADDR");
+    if entries.is_empty() {
+        entries = env_list("HOST");
+    }
+    if entries.is_empty() {
+        entries.push("0.0.0.0".into());
+    }
+    parse_bind_addrs(&entries, Some(env_or("HOST_PORT", crate::constants::DEFAULT_HOST_PORT)))
+}
+
+/// Separate listeners for the admin endpoints (`ADMIN_BIND_ADDR`); each entry needs a port
+pub fn admin_bind_addrs() -> Result<Vec<SocketAddr>, String> {
+    parse_bind_addrs(&env_list("ADMIN_BIND_ADDR"), None)
+}
+
+/// Plain per-request behaviour settings shared by the handlers
+#[derive(Debug, Clone, Default)]
+pub struct ProxyConfig {
+    /// Client keys allowed to use `x-proxy-*` override headers (`*` trusts every client)
+    pub trusted_override_keys: Vec<String>,
+    /// Reverse proxies whose `Forwarded` / `X-Forwarded-For` headers are believed (`TRUSTED_PROXIES`)
+    pub trusted_proxies: Vec<IpNet>,
+    /// Client headers forwarded to the backend (`FORWARD_HEADERS`)
+    pub forward_headers: Vec<HeaderName>,
+    /// Static headers added to every backend request (`BACKEND_EXTRA_HEADERS`)
+    pub extra_headers: Vec<(HeaderName, HeaderValue)>,
+    /// Merge small streaming deltas (`STREAM_COALESCE_BYTES` / `STREAM_COALESCE_MS`); `None` when disabled
+    pub stream_coalesce: Option<CoalesceConfig>,
+    /// Re-chunk large streaming deltas (`STREAM_SPLIT_BYTES` / `STREAM_SPLIT_DELAY_MS`); `None` when disabled
+    pub stream_split: Option<SplitConfig>,
+    /// Size limit of unfinished backend SSE events and what happens past it (`SSE_BUFFER_LIMIT_KB`,
+    /// `SSE_BUFFER_POLICY`, `SSE_BUFFER_HARD_CAP_KB`)
+    pub sse_buffer: SseBufferLimit,
+    /// How a trailing assistant message is translated (`PREFILL_MODE`)
+    pub prefill_mode: PrefillMode,
+    /// Scan streamed text for `stop_sequences` in the proxy (`ENFORCE_STOP_SEQUENCES`)
+    pub enforce_stop_sequences: bool,
+    /// Forward or drop `choices` past index 0 (`EXTRA_CHOICES`)
+    pub extra_choices: ExtraChoices,
+    /// Chat reply or structured 404 for unknown models (`MODEL_NOT_FOUND`)
+    pub model_not_found: ModelNotFound,
+    /// How much of a backend error is shown in the chat (`ERROR_VERBOSITY`)
+    pub error_verbosity: ErrorVerbosity,
+    /// Model for requests whose `model` is missing or blank (`DEFAULT_MODEL`)
+    pub default_model: Option<String>,
+    /// Extra output tokens for resuming responses truncated at `max_tokens` (`AUTO_CONTINUE_TOKENS`); 0 disables
+    pub auto_continue_tokens: u32,
+    /// Per-model thinking dialect overrides (`THINKING_DIALECT_MODELS`)
+    pub thinking_dialect_models: Vec<(String, ThinkingDialect)>,
+    /// Per-model image count/size limits (`IMAGE_LIMITS_MODELS`), overriding the backend's
+    pub image_limits_models: Vec<(String, ImageLimitsOverride)>,
+    /// Cut off streamed thinking past `budget_tokens` (`THINKING_BUDGET_ENFORCEMENT`)
+    pub thinking_budget_enforcement: BudgetEnforcement,
+    /// How thinking reaches the client (`THINKING_OUTPUT`)
+    pub thinking_output: ThinkingOutput,
+    /// Claude stop_reason for backend `content_filter` finishes (`CONTENT_FILTER_STOP_REASON`)
+    pub content_filter_stop_reason: ContentFilterStopReason,
+    /// Text block appended to filtered responses (`CONTENT_FILTER_NOTICE`)
+    pub content_filter_notice: Option<String>,
+    /// Executors of emulated server tools: `web_search` (`WEB_SEARCH_URL`), `code_execution` (`CODE_EXECUTION_SANDBOX`)
+    pub server_tools: ServerToolConfig,
+    /// Where client keys are read from (`AUTH_HEADER_PRECEDENCE`, `API_KEY_QUERY_PARAM`, `API_KEY_QUERY_ROUTES`)
+    pub client_auth: ClientAuth,
+    /// Bearer token for `/admin/*` endpoints; admin endpoints are disabled when unset
+    pub admin_token: Option<String>,
+    /// Connect, first-token and total backend deadlines (`BACKEND_*_TIMEOUT_SECS`)
+    pub timeouts: BackendTimeouts,
+    /// Tool count and definition size limits (`MAX_TOOLS`, `MAX_TOOL_SCHEMA_BYTES`, `TOOL_LIMIT_POLICY`)
+    pub tool_limits: ToolLimits,
+}
+
+impl ProxyConfig {
+    pub fn from_env() -> Self {
+        Self {
+            trusted_override_keys: env_list("TRUSTED_OVERRIDE_KEYS"),
+            trusted_proxies: crate::services::parse_trusted_proxies(&env_list("TRUSTED_PROXIES")),
+            forward_headers: crate::services::parse_forward_headers(&env_list("FORWARD_HEADERS")),
+            extra_headers: crate::services::parse_extra_headers(&env_list("BACKEND_EXTRA_HEADERS")),
+            stream_coalesce: env_parse::<usize>("STREAM_COALESCE_BYTES")
+                .filter(|&bytes| bytes > 0)
+                .map(|max_bytes| CoalesceConfig {
+                    max_bytes,
+                    max_delay: Duration::from_millis(env_or("STREAM_COALESCE_MS", DEFAULT_STREAM_COALESCE_MS)),
+                }),
+            stream_split: env_parse::<usize>("STREAM_SPLIT_BYTES")
+                .filter(|&bytes| bytes > 0)
+                .map(|max_bytes| SplitConfig {
+                    max_bytes,
+                    delay: Duration::from_millis(env_or("STREAM_SPLIT_DELAY_MS", DEFAULT_STREAM_SPLIT_DELAY_MS)),
+                }),
+            sse_buffer: {
+                let limit_bytes = env_parse::<usize>("SSE_BUFFER_LIMIT_KB").filter(|&kb| kb > 0).unwrap_or(DEFAULT_SSE_BUFFER_LIMIT_KB) * 1024;
+                SseBufferLimit {
+                    policy: env_or("SSE_BUFFER_POLICY", SseBufferPolicy::default()),
+                    limit_bytes,
+                    hard_cap_bytes: (env_or("SSE_BUFFER_HARD_CAP_KB", DEFAULT_SSE_BUFFER_HARD_CAP_KB) * 1024).max(limit_bytes),
+                }
+            },
+            prefill_mode: env_or("PREFILL_MODE", PrefillMode::default()),
+            enforce_stop_sequences: env_or("ENFORCE_STOP_SEQUENCES", false),
+            auto_continue_tokens: env_or("AUTO_CONTINUE_TOKENS", 0),
+            extra_choices: env_or("EXTRA_CHOICES", ExtraChoices::default()),
+            model_not_found: env_or("MODEL_NOT_FOUND", ModelNotFound::default()),
+            error_verbosity: env_or("ERROR_VERBOSITY", ErrorVerbosity::default()),
+            default_model: env::var("DEFAULT_MODEL").ok().map(|m| m.trim().to_string()).filter(|m| !m.is_empty()),
+            thinking_dialect_models: crate::services::parse_dialect_overrides(&env_list("THINKING_DIALECT_MODELS")),
+            image_limits_models: parse_image_limit_overrides(&env_list("IMAGE_LIMITS_MODELS")),
+            thinking_budget_enforcement: env_or("THINKING_BUDGET_ENFORCEMENT", BudgetEnforcement::default()),
+            thinking_output: env_or("THINKING_OUTPUT", ThinkingOutput::default()),
+            content_filter_stop_reason: env_or("CONTENT_FILTER_STOP_REASON", ContentFilterStopReason::default()),
+            content_filter_notice: env::var("CONTENT_FILTER_NOTICE").ok().filter(|t| !t.trim().is_empty()),
+            server_tools: ServerToolConfig {
+                web_search: env::var("WEB_SEARCH_URL")
+                    .ok()
+                    .filter(|url| !url.trim().is_empty())
+                    .map(|url| WebSearchConfig {
+                        url: url.trim().to_string(),
+                        max_results: env_parse::<usize>("WEB_SEARCH_MAX_RESULTS")
+                            .filter(|&n| n > 0)
+                            .unwrap_or(DEFAULT_WEB_SEARCH_MAX_RESULTS),
+                    }),
+                code_execution: env_parse::<Sandbox>("CODE_EXECUTION_SANDBOX").map(|sandbox| CodeExecutionConfig {
+                    sandbox,
+                    image: env_or("CODE_EXECUTION_IMAGE", DEFAULT_CODE_EXECUTION_IMAGE.to_string()),
+                    timeout: Duration::from_secs(env_or("CODE_EXECUTION_TIMEOUT_SECS", DEFAULT_CODE_EXECUTION_TIMEOUT_SECS)),
+                }),
+            },
+            client_auth: ClientAuth {
+                precedence: env_or("AUTH_HEADER_PRECEDENCE", AuthPrecedence::default()),
+                query_param: env::var("API_KEY_QUERY_PARAM")
+                    .ok()
+                    .map(|p| p.trim().to_string())
+                    .filter(|p| !p.is_empty())
+                    .unwrap_or_else(|| DEFAULT_API_KEY_QUERY_PARAM.into()),
+                query_routes: env_list("API_KEY_QUERY_ROUTES"),
+            },
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|t| !t.trim().is_empty()),
+            timeouts: BackendTimeouts::from_env(),
+            tool_limits: ToolLimits {
+                max_tools: env_parse::<usize>("MAX_TOOLS").filter(|&n| n > 0),
+                max_schema_bytes: env_parse::<usize>("MAX_TOOL_SCHEMA_BYTES").filter(|&n| n > 0),
+                policy: env_or("TOOL_LIMIT_POLICY", ToolLimitPolicy::default()),
+            },
+        }
+    }
+
+    pub fn is_trusted_for_overrides(&self, client_key: Option<&str>) -> bool {
+        self.trusted_override_keys.iter().any(|k| k == "*")
+            || client_key.is_some_and(|key| self.trusted_override_keys.iter().any(|k| k == key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(entries: &[&str], default_port: Option<u16>) -> Result<Vec<String>, String> {
+        let entries: Vec<String> = entries.iter().map(|s| s.to_string()).collect();
+        parse_bind_addrs(&entries, default_port).map(|a| a.iter().map(|a| a.to_string()).collect())
+    }
+
+    #[test]
+    fn test_parse_bind_addrs() {
+        assert_eq!(addrs(&["0.0.0.0"], Some(8080)), Ok(vec!["0.0.0.0:8080".into()]));
+        assert_eq!(addrs(&["::"], Some(8080)), Ok(vec!["[::]:8080".into()]));
+        assert_eq!(addrs(&["[::1]"], Some(8080)), Ok(vec!["[::1]:8080".into()]));
+        assert_eq!(addrs(&["127.0.0.1:9090", "[::1]:9091"], Some(8080)), Ok(vec!["127.0.0.1:9090".into(), "[::1]:9091".into()]));
+        assert!(addrs(&["localhost:9090"], None).unwrap().iter().all(|a| a.ends_with(":9090")));
+    }
+
+    #[test]
+    fn test_parse_bind_addrs_requires_port_without_default() {
+        assert!(addrs(&["127.0.0.1"], None).is_err());
+        assert!(addrs(&["::1"], None).is_err());
+    }
+}
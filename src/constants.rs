@@ -1,7 +1,7 @@
-/// Application-wide constants
-///
-/// This module centralizes all magic numbers and configuration values used throughout
-/// the application for better maintainability and documentation.
+//! Application-wide constants
+//!
+//! This module centralizes all magic numbers and configuration values used throughout
+//! the application for better maintainability and documentation.
 
 // ============================================================================
 // Request Validation Limits
@@ -26,14 +26,89 @@ pub const MAX_TOKENS_LIMIT: u32 = 100_000;
 /// Minimum max_tokens parameter value
 pub const MIN_TOKENS_LIMIT: u32 = 1;
 
+/// Default maximum images per request (`MAX_IMAGES`)
+/// Matches Anthropic's limit of 100 images per API request
+pub const DEFAULT_MAX_IMAGES: usize = 100;
+
+/// Default maximum decoded size of one image (`MAX_IMAGE_BYTES`, 5MB)
+/// Matches Anthropic's per-image limit
+pub const DEFAULT_MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+
+/// Default maximum size of one `/v1/files` upload (`FILES_MAX_BYTES`, 500MB)
+/// Matches Anthropic's per-file limit
+pub const DEFAULT_FILES_MAX_BYTES: usize = 500 * 1024 * 1024;
+
+/// Room for multipart boundaries and headers on top of `FILES_MAX_BYTES` in an upload body
+pub const FILES_MULTIPART_OVERHEAD_BYTES: usize = 64 * 1024;
+
+/// Default page size of `GET /v1/files`
+pub const DEFAULT_FILES_LIST_LIMIT: usize = 20;
+
+/// Default number of results returned by the emulated `web_search` server tool (`WEB_SEARCH_MAX_RESULTS`)
+pub const DEFAULT_WEB_SEARCH_MAX_RESULTS: usize = 5;
+
+/// Timeout of one `WEB_SEARCH_URL` query
+pub const WEB_SEARCH_TIMEOUT_SECS: u64 = 15;
+
+/// Default image of the `docker` code execution sandbox (`CODE_EXECUTION_IMAGE`)
+pub const DEFAULT_CODE_EXECUTION_IMAGE: &str = "python:3.12-slim";
+
+/// Default time limit of one `code_execution` call (`CODE_EXECUTION_TIMEOUT_SECS`)
+pub const DEFAULT_CODE_EXECUTION_TIMEOUT_SECS: u64 = 30;
+
+/// Bytes kept of each of stdout and stderr of one `code_execution` call
+pub const CODE_EXECUTION_MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// Default lifetime of a stored message (`MESSAGE_STORE_TTL_SECS`, 24 hours)
+pub const DEFAULT_MESSAGE_STORE_TTL_SECS: u64 = 86_400;
+
+/// Time between two sweeps of expired messages from `MESSAGE_STORE_DIR`
+pub const MESSAGE_STORE_PRUNE_INTERVAL_SECS: u64 = 600;
+
+/// Omitted tools named in the warning block before it says "and N more"
+pub const TOOL_NOTICE_MAX_NAMES: usize = 50;
+
+/// Shorter words of the user's message are ignored when ranking tools by relevance
+pub const TOOL_RELEVANCE_MIN_WORD_CHARS: usize = 3;
+
+/// Models one `/v1/experimental/compare` request may fan out to
+pub const COMPARE_MAX_MODELS: usize = 8;
+
+/// Follow-up backend requests after server tool calls before the turn ends with `pause_turn`
+/// Matches the iteration limit of Anthropic's server-side sampling loop
+pub const MAX_SERVER_TOOL_ROUNDS: u32 = 10;
+
 // ============================================================================
 // Token Estimation Constants
 // ============================================================================
 
-/// Approximate tokens per image for vision models
-/// Based on Claude's image token calculation
+/// Approximate tokens per image for vision models, used when an image's size can't be read
+/// (URL and file sources)
 pub const TOKENS_PER_IMAGE: usize = 85;
 
+/// Claude's image token formula: one token per this many pixels...
+pub const IMAGE_PIXELS_PER_TOKEN: f64 = 750.0;
+
+/// ...after scaling the image down to this long edge...
+pub const IMAGE_MAX_LONG_EDGE: f64 = 1568.0;
+
+/// ...and at most this many tokens per image
+pub const IMAGE_MAX_TOKENS: usize = 1600;
+
+/// Decoded bytes of a base64 image read for its size; the header is well within this even for
+/// JPEGs with large EXIF or ICC segments
+pub const IMAGE_HEADER_MAX_BYTES: usize = 256 * 1024;
+
+/// Approximate tokens per second of audio input
+/// Based on OpenAI's audio models (~600 tokens per minute)
+pub const TOKENS_PER_AUDIO_SECOND: usize = 10;
+
+/// Assumed bytes per second of MP3 audio (128 kbps) when estimating its duration
+pub const MP3_BYTES_PER_SECOND: usize = 16_000;
+
+/// Assumed bytes per second of WAV audio (16 kHz, 16-bit mono) when the header has no byte rate
+pub const WAV_BYTES_PER_SECOND: usize = 32_000;
+
 /// Character-to-token ratio for rough estimation (4 chars ≈ 1 token)
 /// Used as fallback when tiktoken is unavailable
 pub const CHARS_PER_TOKEN: usize = 4;
@@ -45,6 +120,37 @@ pub const CHARS_PER_TOKEN: usize = 4;
 /// Number of consecutive failures before circuit breaker opens
 pub const CIRCUIT_BREAKER_FAILURE_THRESHOLD: u32 = 5;
 
+// ============================================================================
+// Webhook Notifications
+// ============================================================================
+
+/// Default minimum seconds between two webhooks of the same event kind
+pub const DEFAULT_WEBHOOK_COOLDOWN_SECS: u64 = 900;
+
+/// Default backend failure ratio that triggers a high error rate alert
+pub const DEFAULT_WEBHOOK_ERROR_RATE_THRESHOLD: f64 = 0.5;
+
+/// Default window (seconds) over which the backend error rate is measured
+pub const DEFAULT_WEBHOOK_ERROR_RATE_WINDOW_SECS: u64 = 300;
+
+/// Minimum requests in a window before the error rate is considered meaningful
+pub const WEBHOOK_ERROR_RATE_MIN_REQUESTS: u32 = 20;
+
+/// Default time to response headers above which a backend counts as slow (`SLOW_BACKEND_MS`)
+pub const DEFAULT_SLOW_BACKEND_MS: u64 = 30_000;
+
+/// Backend error wordings (lowercase) meaning the backend account is out of credit or quota
+pub const BUDGET_EXHAUSTED_MARKERS: &[&str] = &["insufficient_quota", "insufficient credits", "credit balance is too low", "billing_hard_limit_reached", "exceeded your current quota"];
+
+/// Operational events buffered per `/admin/events` subscriber before a slow one skips ahead
+pub const OPS_EVENTS_BUFFER: usize = 64;
+
+/// How long a `PUT /admin/log-level` override lasts when no `minutes` are given
+pub const DEFAULT_LOG_OVERRIDE_MINUTES: u64 = 15;
+
+/// Longest accepted log filter override, so a forgotten debug filter can't stay on for days
+pub const MAX_LOG_OVERRIDE_MINUTES: u64 = 24 * 60;
+
 // ============================================================================
 // SSE Streaming Configuration
 // ============================================================================
@@ -53,13 +159,239 @@ pub const CIRCUIT_BREAKER_FAILURE_THRESHOLD: u32 = 5;
 /// Balances memory usage with streaming performance
 pub const SSE_CHANNEL_BUFFER_SIZE: usize = 64;
 
+/// Default maximum time a coalesced delta is held back when `STREAM_COALESCE_BYTES` is set
+pub const DEFAULT_STREAM_COALESCE_MS: u64 = 50;
+
+/// Default pause between the pieces of a split delta when `STREAM_SPLIT_BYTES` is set
+pub const DEFAULT_STREAM_SPLIT_DELAY_MS: u64 = 10;
+
+/// Delta size used to pace out a non-streamed (JSON body) response when `STREAM_SPLIT_BYTES` is unset
+pub const SIMULATED_STREAM_SPLIT_BYTES: usize = 32;
+
+/// Maximum queued stream tee records before new records are dropped
+pub const STREAM_TEE_QUEUE_SIZE: usize = 4096;
+
+/// Maximum records written to the stream tee sink in one batch
+pub const STREAM_TEE_BATCH_SIZE: usize = 256;
+
+/// Maximum time a stream tee record waits before being flushed
+pub const STREAM_TEE_FLUSH_INTERVAL_MS: u64 = 1000;
+
+/// Maximum queued LLM trace spans before new spans are dropped
+pub const LLM_TRACE_QUEUE_SIZE: usize = 1024;
+
+/// Default character limit for prompt/completion content in truncated trace mode
+pub const DEFAULT_LLM_TRACE_MAX_CONTENT_CHARS: usize = 4096;
+
+/// Recent errors kept for the dashboard
+pub const STATS_RECENT_ERRORS: usize = 50;
+
+/// Recent backend responses whose diagnostic headers `/debug/requests/:id` can look up
+pub const STATS_RECENT_RESPONSES: usize = 200;
+
+/// Backend response headers recorded for cross-referencing provider support tickets
+pub const DIAGNOSTIC_HEADERS: &[&str] = &[
+    "x-request-id",
+    "request-id",
+    "cf-ray",
+    "server",
+    "openai-model",
+    "openai-version",
+    "openai-processing-ms",
+    "x-model-version",
+];
+
+/// Prefixes of diagnostic rate-limit headers (`x-ratelimit-remaining-requests`, `ratelimit-reset`, ...)
+pub const DIAGNOSTIC_HEADER_PREFIXES: &[&str] = &["x-ratelimit-", "ratelimit-"];
+
+/// Error messages shown on the dashboard are cut to this many characters
+pub const STATS_ERROR_MESSAGE_CHARS: usize = 300;
+
+/// Window for the dashboard's request rate and token throughput
+pub const STATS_THROUGHPUT_WINDOW_SECS: u64 = 60;
+
+/// Maximum queued request log entries before new entries are dropped
+#[cfg(feature = "sqlite")]
+pub const REQUEST_LOG_QUEUE_SIZE: usize = 4096;
+
+/// Days of request log history kept when `REQUEST_LOG_RETENTION_DAYS` is unset
+#[cfg(feature = "sqlite")]
+pub const DEFAULT_REQUEST_LOG_RETENTION_DAYS: u64 = 30;
+
+/// How often expired request log entries are deleted
+#[cfg(feature = "sqlite")]
+pub const REQUEST_LOG_PRUNE_INTERVAL_SECS: u64 = 3600;
+
+/// Responses smaller than this are sent uncompressed when `COMPRESSION_MIN_BYTES` is unset
+pub const DEFAULT_COMPRESSION_MIN_BYTES: u16 = 1024;
+
+/// Response content types compressed when `COMPRESSION_TYPES` is unset
+pub const DEFAULT_COMPRESSION_TYPES: &[&str] = &["application/json", "text/html", "text/plain", "text/csv"];
+
+/// Public API port when `HOST_PORT` is unset
+pub const DEFAULT_HOST_PORT: u16 = 8080;
+
+/// Backend used when `BACKEND_URL` is unset
+pub const DEFAULT_BACKEND_URL: &str = "http://127.0.0.1:8000/v1/chat/completions";
+
+/// Size an unfinished backend SSE event may reach when `SSE_BUFFER_LIMIT_KB` is unset
+pub const DEFAULT_SSE_BUFFER_LIMIT_KB: usize = 1024;
+
+/// Ceiling of the `grow` SSE buffer policy when `SSE_BUFFER_HARD_CAP_KB` is unset
+pub const DEFAULT_SSE_BUFFER_HARD_CAP_KB: usize = 16 * 1024;
+
+/// Largest non-streamed (JSON body) backend response read into memory
+pub const BACKEND_JSON_BODY_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Global streaming memory limit when `STREAM_MEMORY_LIMIT_MB` is unset
+pub const DEFAULT_STREAM_MEMORY_LIMIT_MB: usize = 512;
+
+/// Memory accounted to every stream up front: parser buffer capacity plus queued events
+pub const STREAM_MEMORY_BASE_BYTES: usize = 64 * 1024;
+
+/// How long a stream whose parser buffer needs more memory waits for other streams to release some
+pub const STREAM_MEMORY_WAIT_MS: u64 = 2000;
+
+/// Events kept per response for `Last-Event-ID` resume; longer responses can't be resumed
+pub const STREAM_RESUME_MAX_EVENTS: usize = 20_000;
+
+/// Event data kept per response for `Last-Event-ID` resume; larger responses can't be resumed
+pub const STREAM_RESUME_MAX_BYTES: usize = 8 * 1024 * 1024;
+
+/// Bytes read from a backend stream after the Claude stream finished before the request is aborted
+pub const STREAM_DRAIN_MAX_BYTES: usize = 64 * 1024;
+
+/// Time spent reading a backend stream after the Claude stream finished before the request is aborted
+pub const STREAM_DRAIN_TIMEOUT_SECS: u64 = 5;
+
+/// Query parameter carrying the client key on `API_KEY_QUERY_ROUTES` when `API_KEY_QUERY_PARAM` is unset
+pub const DEFAULT_API_KEY_QUERY_PARAM: &str = "key";
+
+/// Requests that may wait for a slot when `MAX_CONCURRENT_REQUESTS` is reached and `ADMISSION_QUEUE_DEPTH` is unset
+pub const DEFAULT_ADMISSION_QUEUE_DEPTH: usize = 100;
+
+/// How long a queued request waits for a slot when `ADMISSION_QUEUE_WAIT_MS` is unset
+pub const DEFAULT_ADMISSION_QUEUE_WAIT_MS: u64 = 30_000;
+
+/// How long a request over its key's `MAX_STREAMS_PER_KEY` waits when `KEY_STREAM_QUEUE_WAIT_MS` is unset
+pub const DEFAULT_KEY_STREAM_QUEUE_WAIT_MS: u64 = 10_000;
+
+/// Status of chaos-injected backend errors when `CHAOS_ERROR_STATUS` is unset
+pub const DEFAULT_CHAOS_ERROR_STATUS: u16 = 503;
+
+/// Upper bound of the random chunk count after which chaos drops a stream
+pub const CHAOS_MAX_DROP_AFTER: usize = 20;
+
+/// Whole backend request, including the streamed body, when `BACKEND_TOTAL_TIMEOUT_SECS` is unset
+pub const DEFAULT_BACKEND_TIMEOUT_SECS: u64 = 600;
+
+/// Establishing a backend connection when `BACKEND_CONNECT_TIMEOUT_SECS` is unset
+pub const DEFAULT_BACKEND_CONNECT_TIMEOUT_SECS: u64 = 10;
+
+/// Upper bound on the backend probe made by the deep `/health` check
+pub const HEALTH_PROBE_TIMEOUT_SECS: u64 = 5;
+
+/// Upper bound on one warm-up request; loading a model from disk can take minutes
+pub const WARMUP_TIMEOUT_SECS: u64 = 300;
+
+/// Characters of a failed warm-up's response body kept for `/readyz`
+pub const WARMUP_ERROR_PREVIEW_CHARS: usize = 200;
+
+/// How long a model that rejected `stream: true` gets buffered requests under `STREAMING=auto`
+pub const STREAMING_FALLBACK_SECS: u64 = 600;
+
+/// Backend error wordings, lowercase, that mean `stream: true` isn't supported
+pub const STREAMING_REJECTIONS: &[&str] = &[
+    "streaming is not supported",
+    "streaming not supported",
+    "stream is not supported",
+    "does not support streaming",
+    "streaming is disabled",
+    "stream: invalid",
+    "'stream' is not supported",
+    "unsupported parameter: 'stream'",
+];
+
+/// Characters of the last shadow backend error kept for `/admin/stats`
+pub const SHADOW_ERROR_PREVIEW_CHARS: usize = 200;
+
+/// Share of requests mirrored to `SHADOW_BACKEND` when `SHADOW_PERCENT` is unset
+pub const DEFAULT_SHADOW_PERCENT: f64 = 1.0;
+
+/// Shadow requests in flight at once when `SHADOW_MAX_CONCURRENT` is unset
+pub const DEFAULT_SHADOW_MAX_CONCURRENT: usize = 4;
+
+/// Most recent messages kept verbatim by compaction when `COMPACTION_KEEP_MESSAGES` is unset
+pub const DEFAULT_COMPACTION_KEEP_MESSAGES: usize = 8;
+
+/// `max_tokens` of a compaction summary when `COMPACTION_SUMMARY_MAX_TOKENS` is unset
+pub const DEFAULT_COMPACTION_SUMMARY_MAX_TOKENS: u32 = 1024;
+
+/// Upper bound on one compaction summary request
+pub const COMPACTION_TIMEOUT_SECS: u64 = 120;
+
+/// Longest a request waits for its compaction summary; a slower one finishes in the background
+/// for later turns
+pub const COMPACTION_WAIT_SECS: u64 = 15;
+
+/// Upper bound on one content moderation request
+pub const MODERATION_TIMEOUT_SECS: u64 = 10;
+
+/// Response text held back and checked at once by content moderation (`MODERATION_STREAM_CHARS`)
+pub const DEFAULT_MODERATION_STREAM_CHARS: usize = 400;
+
+/// Tail of the previous window of response text checked again with the next one, so flagged
+/// phrases split across two windows are still seen whole
+pub const MODERATION_WINDOW_OVERLAP_CHARS: usize = 100;
+
+/// Windows of one stream checked at the same time; the stream waits for the oldest beyond this
+pub const MODERATION_MAX_PENDING_CHECKS: usize = 4;
+
+/// Replaces flagged text when `MODERATION_ACTION=redact`
+pub const MODERATION_REDACTION: &str = "[removed by content moderation]";
+
+/// Annotation keywords dropped from tool schemas for token-efficient tools
+pub const COMPACT_SCHEMA_KEYWORDS: &[&str] = &["$schema", "$comment", "title", "examples"];
+
+/// Longest tool or parameter description kept for token-efficient tools
+pub const TOKEN_EFFICIENT_DESCRIPTION_CHARS: usize = 300;
+
+/// Characters of one tool result copied into the transcript sent for summarizing
+pub const COMPACTION_TOOL_RESULT_CHARS: usize = 2_000;
+
+/// Summaries remembered so a growing conversation only summarizes its newly compacted turns
+pub const COMPACTION_CACHE_ENTRIES: usize = 256;
+
+/// How long a routed backend that failed (connection error, 429 or 5xx) is tried only after healthy ones
+pub const ROUTE_FAILURE_COOLDOWN_SECS: u64 = 30;
+
+/// Weight of the newest sample in a routed backend's moving latency average
+pub const ROUTE_LATENCY_EWMA_WEIGHT: f64 = 0.3;
+
+/// Highest temperature accepted by OpenAI-compatible backends
+pub const DEFAULT_MAX_TEMPERATURE: f32 = 2.0;
+
 // ============================================================================
 // Model Configuration
 // ============================================================================
 
+/// OpenRouter model variants (`model:variant`) kept through model name normalization
+pub const OPENROUTER_MODEL_VARIANTS: &[&str] = &["free", "nitro", "floor", "online", "extended", "thinking", "beta", "exacto"];
+
 /// Default thinking budget tokens for reasoning models
 pub const DEFAULT_THINKING_BUDGET_TOKENS: u32 = 10_000;
 
+/// Most logprob alternatives per token a backend is asked for (`x-proxy-debug: logprobs=<top>`);
+/// OpenAI-compatible APIs reject larger `top_logprobs`
+pub const MAX_TOP_LOGPROBS: u32 = 20;
+
+// ============================================================================
+// Error Reporting
+// ============================================================================
+
+/// Longest provider error line shown in the chat at `ERROR_VERBOSITY=provider`
+pub const PROVIDER_ERROR_MAX_CHARS: usize = 300;
+
 // ============================================================================
 // Helper Functions
 // ============================================================================
@@ -67,21 +399,7 @@ pub const DEFAULT_THINKING_BUDGET_TOKENS: u32 = 10_000;
 /// Get price tier emoji based on input/output pricing
 /// Used for model list formatting in error messages
 pub fn get_price_tier(input_price: Option<f64>, output_price: Option<f64>) -> &'static str {
-    // Calculate average price per million tokens
-    let avg_price = match (input_price, output_price) {
-        (Some(inp), Some(out)) => (inp + out) / 2.0,
-        (Some(p), None) | (None, Some(p)) => p,
-        (None, None) => return "    ", // No pricing info
-    };
-    
-    // Price tiers (rough estimates)
-    if avg_price < 1.0 {
-        "💰" // Very cheap (< $1/M tokens average)
-    } else if avg_price < 5.0 {
-        "💵"  // Affordable ($1-5/M tokens)
-    } else if avg_price < 15.0 {
-        "💸" // Moderate ($5-15/M tokens)
-    } else {
-        "💎" // Premium (> $15/M tokens)
-    }
+    use crate::models::PriceTier;
+    PriceTier::from_prices(input_price, output_price).map_or("    ", PriceTier::emoji) // No pricing info
 }
+
@@ -0,0 +1,200 @@
+use std::{
+    convert::Infallible,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::{sse::{Event, KeepAlive, Sse}, Html, Json},
+};
+use futures::{stream, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use crate::constants::{DEFAULT_LOG_OVERRIDE_MINUTES, MAX_LOG_OVERRIDE_MINUTES};
+use crate::models::App;
+use crate::services::{constant_time_eq, log_level, OpsEvent};
+
+/// Admin endpoints require `ADMIN_TOKEN` as a bearer token or `x-api-key`; they 404 when it is unset
+pub(crate) fn require_admin(app: &App, headers: &HeaderMap, uri: &Uri) -> Result<(), (StatusCode, &'static str)> {
+    let Some(token) = &app.config.admin_token else {
+        return Err((StatusCode::NOT_FOUND, "admin_disabled"));
+    };
+    match app.config.client_auth.client_key(headers, uri) {
+        Some(key) if constant_time_eq(&key, token) => Ok(()),
+        _ => Err((StatusCode::UNAUTHORIZED, "admin_unauthorized")),
+    }
+}
+
+/// Live traffic, recent errors and circuit breaker state for the dashboard
+pub async fn stats(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    require_admin(&app, &headers, &uri)?;
+    let mut snapshot = serde_json::to_value(app.stats.snapshot()).unwrap_or_default();
+    let circuit_breaker = app.circuit_breaker.read().await;
+    snapshot["circuit_breaker"] = serde_json::json!({
+        "enabled": circuit_breaker.enabled,
+        "is_open": circuit_breaker.is_open,
+        "consecutive_failures": circuit_breaker.consecutive_failures
+    });
+    snapshot["stream_memory"] = serde_json::to_value(app.stream_memory.snapshot()).unwrap_or_default();
+    if let Some(admission) = &app.admission {
+        snapshot["admission"] = serde_json::to_value(admission.snapshot()).unwrap_or_default();
+    }
+    if let Some(key_streams) = &app.key_streams {
+        snapshot["key_streams"] = serde_json::to_value(key_streams.snapshot()).unwrap_or_default();
+    }
+    if let Some(routes) = &app.routes {
+        snapshot["routes"] = serde_json::to_value(routes.snapshot(&app.backends)).unwrap_or_default();
+    }
+    if let Some(shadow) = &app.shadow {
+        snapshot["shadow"] = serde_json::to_value(shadow.snapshot()).unwrap_or_default();
+    }
+    if let Some(experiments) = &app.experiments {
+        snapshot["experiments"] = serde_json::to_value(experiments.snapshot()).unwrap_or_default();
+    }
+    if let Some(chaos) = &app.chaos {
+        snapshot["chaos"] = serde_json::to_value(chaos.snapshot()).unwrap_or_default();
+    }
+    Ok(Json(snapshot))
+}
+
+/// Operational events as they happen (circuit breaker, slow backends, error rate, model cache),
+/// for dashboards and alerting scripts. The stream opens with a `status` event holding the
+/// current circuit breaker state; each event's data is the event with `type`, `message` and `ts_ms`.
+pub async fn events(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, &'static str)> {
+    require_admin(&app, &headers, &uri)?;
+    let rx = app.notifier.subscribe();
+    let status = {
+        let circuit_breaker = app.circuit_breaker.read().await;
+        json!({
+            "type": "status",
+            "circuit_breaker": { "is_open": circuit_breaker.is_open, "consecutive_failures": circuit_breaker.consecutive_failures },
+            "ts_ms": now_ms(),
+        })
+    };
+    log::info!("📡 Admin event stream opened");
+    let first = stream::once(async move { Ok(Event::default().event("status").data(status.to_string())) });
+    let events = stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((Ok(ops_event(&event)), rx)),
+                Err(RecvError::Lagged(skipped)) => log::warn!("📡 Admin event stream fell behind - skipped {} event(s)", skipped),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Ok(Sse::new(first.chain(events)).keep_alive(KeepAlive::default()))
+}
+
+fn ops_event(event: &OpsEvent) -> Event {
+    let mut data = serde_json::to_value(event).unwrap_or_default();
+    data["message"] = json!(event.message());
+    data["ts_ms"] = json!(now_ms());
+    Event::default().event(event.kind()).data(data.to_string())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Single-page dashboard; the page itself holds no data and asks for `ADMIN_TOKEN` to poll `/admin/stats`
+pub async fn dashboard(State(app): State<App>) -> Result<Html<&'static str>, (StatusCode, &'static str)> {
+    if app.config.admin_token.is_none() {
+        return Err((StatusCode::NOT_FOUND, "admin_disabled"));
+    }
+    Ok(Html(include_str!("dashboard.html")))
+}
+
+#[derive(Deserialize)]
+pub struct LogLevelRequest {
+    /// `RUST_LOG`-style directives added to the base filter, e.g. `claude_openai_proxy::handlers::messages=debug`
+    pub filter: String,
+    /// How long the override lasts (default 15, at most 1440)
+    #[serde(default)]
+    pub minutes: Option<u64>,
+}
+
+/// The base log filter and the active override, if any
+pub async fn log_level(State(app): State<App>, headers: HeaderMap, uri: Uri) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    require_admin(&app, &headers, &uri)?;
+    let snapshot = log_level::snapshot().ok_or((StatusCode::SERVICE_UNAVAILABLE, "logging_not_initialized"))?;
+    Ok(Json(serde_json::to_value(snapshot).unwrap_or_default()))
+}
+
+/// Widen the log filter for a while; it reverts by itself
+pub async fn set_log_level(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    Json(request): Json<LogLevelRequest>,
+) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    require_admin(&app, &headers, &uri)?;
+    let minutes = request.minutes.unwrap_or(DEFAULT_LOG_OVERRIDE_MINUTES);
+    if !(1..=MAX_LOG_OVERRIDE_MINUTES).contains(&minutes) {
+        return Err((StatusCode::BAD_REQUEST, "invalid_minutes"));
+    }
+    let snapshot = log_level::set_override(request.filter.trim(), Duration::from_secs(minutes * 60)).map_err(|e| {
+        log::warn!("❌ Rejected log filter '{}': {}", request.filter, e);
+        (StatusCode::BAD_REQUEST, "invalid_log_filter")
+    })?;
+    Ok(Json(serde_json::to_value(snapshot).unwrap_or_default()))
+}
+
+/// Drop the override now
+pub async fn reset_log_level(State(app): State<App>, headers: HeaderMap, uri: Uri) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    require_admin(&app, &headers, &uri)?;
+    let snapshot = log_level::clear_override().ok_or((StatusCode::SERVICE_UNAVAILABLE, "logging_not_initialized"))?;
+    Ok(Json(serde_json::to_value(snapshot).unwrap_or_default()))
+}
+
+#[derive(Deserialize)]
+pub struct UsageParams {
+    /// Window to aggregate over, in hours (default 24)
+    #[serde(default)]
+    pub hours: Option<u64>,
+}
+
+/// Per-model request counts, errors, tokens and latency from the request log
+pub async fn usage(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    Query(params): Query<UsageParams>,
+) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    require_admin(&app, &headers, &uri)?;
+    let hours = params.hours.unwrap_or(24);
+    usage_since(&app, hours).await
+}
+
+#[cfg(feature = "sqlite")]
+async fn usage_since(app: &App, hours: u64) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    let Some(store) = app.request_log.clone() else {
+        return Err((StatusCode::NOT_FOUND, "request_log_disabled"));
+    };
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let since_ms = now_ms.saturating_sub(hours.saturating_mul(3_600_000));
+    let models = tokio::task::spawn_blocking(move || store.usage_by_model(since_ms))
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "request_log_error"))?
+        .map_err(|e| {
+            log::error!("❌ Request log query failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "request_log_error")
+        })?;
+    Ok(Json(serde_json::json!({ "hours": hours, "models": models })))
+}
+
+#[cfg(not(feature = "sqlite"))]
+async fn usage_since(_app: &App, _hours: u64) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    Err((StatusCode::NOT_FOUND, "request_log_disabled"))
+}
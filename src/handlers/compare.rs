@@ -0,0 +1,229 @@
+use std::{convert::Infallible, time::Instant};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{sse::{Event, Sse}, IntoResponse, Response},
+};
+use futures::StreamExt;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use crate::constants::{COMPARE_MAX_MODELS, SSE_CHANNEL_BUFFER_SIZE};
+use crate::handlers::{messages, ApiError};
+use crate::models::{App, ClaudeRequest};
+use crate::services::{ClientIp, SseEventParser};
+
+/// How one model did, sent in the closing `compare_done` event
+#[derive(Debug, Default, Serialize)]
+struct ModelResult {
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_token_ms: Option<u64>,
+    total_ms: u64,
+    output_tokens: u64,
+}
+
+/// Sends one Claude request to several models at once and multiplexes their streams.
+///
+/// The body is a Claude Messages request with `models` (a list) in place of `model`. Each model's
+/// events keep their Claude event name and arrive as `{"model": ..., "data": <event>}`, interleaved
+/// as they are produced; a final `compare_done` event lists status, stop reason, time to first token,
+/// total time and output tokens per model. Each model runs as its own `/v1/messages` request.
+pub async fn compare(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    client_ip: ClientIp,
+    axum::Json(mut body): axum::Json<Value>,
+) -> Result<Response, ApiError> {
+    let models: Vec<String> = body
+        .as_object_mut()
+        .and_then(|obj| obj.remove("models"))
+        .and_then(|models| serde_json::from_value(models).ok())
+        .unwrap_or_default();
+    if models.is_empty() {
+        return Err(ApiError::with_message(StatusCode::BAD_REQUEST, "no_models", "models: expected a non-empty list of model names"));
+    }
+    if models.len() > COMPARE_MAX_MODELS {
+        let message = format!("models: at most {} models can be compared at once", COMPARE_MAX_MODELS);
+        return Err(ApiError::with_message(StatusCode::BAD_REQUEST, "too_many_models", &message));
+    }
+    let mut requests = Vec::with_capacity(models.len());
+    for model in &models {
+        body["model"] = json!(model);
+        let cr: ClaudeRequest = serde_json::from_value(body.clone())
+            .map_err(|e| ApiError::with_message(StatusCode::BAD_REQUEST, "invalid_request", &e.to_string()))?;
+        requests.push(cr);
+    }
+    log::info!("⚖️  Comparing {} model(s): {}", models.len(), models.join(", "));
+
+    // Every model streams, whatever the client's Accept header asked for
+    let mut headers = headers;
+    headers.remove(header::ACCEPT);
+    let (tx, rx) = mpsc::channel::<Event>(SSE_CHANNEL_BUFFER_SIZE);
+    let runs: Vec<_> = requests
+        .into_iter()
+        .map(|cr| {
+            let (app, headers, uri, tx) = (app.clone(), headers.clone(), uri.clone(), tx.clone());
+            tokio::spawn(async move { run_model(app, headers, uri, client_ip, cr, tx).await })
+        })
+        .collect();
+    tokio::spawn(async move {
+        let mut results = Vec::with_capacity(runs.len());
+        for run in runs {
+            results.extend(run.await.ok());
+        }
+        let data = json!({ "type": "compare_done", "results": results }).to_string();
+        let _ = tx.send(Event::default().event("compare_done").data(data)).await;
+    });
+
+    let stream = ReceiverStream::new(rx).map(Ok::<Event, Infallible>);
+    Ok((
+        [("cache-control", "no-cache"), ("x-accel-buffering", "no")],
+        Sse::new(stream),
+    )
+        .into_response())
+}
+
+/// Run one model's request through the messages handler and forward its events tagged with the model
+async fn run_model(app: App, headers: HeaderMap, uri: Uri, client_ip: ClientIp, cr: ClaudeRequest, tx: mpsc::Sender<Event>) -> ModelResult {
+    let started = Instant::now();
+    let mut result = ModelResult { model: cr.model.clone(), ..Default::default() };
+    let model = result.model.clone();
+    let tag = |event: &str, data: Value| Event::default().event(event).data(json!({ "model": model, "data": data }).to_string());
+
+    let response = match messages(State(app), headers, uri, client_ip, axum::Json(cr)).await {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    };
+    result.status = Some(response.status().as_u16());
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap_or_default();
+        let error = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|v| v.get("error").cloned())
+            .unwrap_or_else(|| json!({ "type": "api_error", "message": String::from_utf8_lossy(&body) }));
+        log::warn!("⚖️  Compare: {} failed with {}", model, status);
+        let _ = tx.send(tag("error", json!({ "type": "error", "error": error }))).await;
+        result.total_ms = started.elapsed().as_millis() as u64;
+        return result;
+    }
+
+    let mut parser = SseEventParser::new();
+    let mut stream = response.into_body().into_data_stream();
+    'read: while let Some(chunk) = stream.next().await {
+        let Ok(chunk) = chunk else { break };
+        for event in parser.push_and_drain(&chunk) {
+            let Ok(data) = serde_json::from_str::<Value>(&event.data) else { continue };
+            let name = event.event.clone().or_else(|| data["type"].as_str().map(str::to_string)).unwrap_or_else(|| "message".into());
+            match name.as_str() {
+                "content_block_delta" if result.first_token_ms.is_none() => result.first_token_ms = Some(started.elapsed().as_millis() as u64),
+                "message_delta" => {
+                    result.stop_reason = data["delta"]["stop_reason"].as_str().map(str::to_string);
+                    result.output_tokens = data["usage"]["output_tokens"].as_u64().unwrap_or_default();
+                }
+                _ => {}
+            }
+            if tx.send(tag(&name, data)).await.is_err() {
+                log::debug!("🔌 Compare client disconnected - dropping {}", model);
+                break 'read;
+            }
+        }
+    }
+    result.total_ms = started.elapsed().as_millis() as u64;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use axum::{http::HeaderValue, routing::post, Router};
+    use crate::models::app::tests::test_app;
+
+    /// Backend answering each model by name, failing for `broken`; returns its URL and the models it was asked for
+    async fn model_backend() -> (String, Arc<Mutex<Vec<String>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let app = Router::new().route(
+            "/v1/chat/completions",
+            post(move |axum::Json(req): axum::Json<Value>| {
+                let log = log.clone();
+                async move {
+                    let model = req["model"].as_str().unwrap_or_default().to_string();
+                    log.lock().unwrap_or_else(|e| e.into_inner()).push(model.clone());
+                    if model == "broken" {
+                        return (StatusCode::BAD_REQUEST, axum::Json(json!({"error": {"message": "model not loaded"}})));
+                    }
+                    let body = json!({
+                        "choices": [{"index": 0, "message": {"role": "assistant", "content": format!("Hi from {}", model)}, "finish_reason": "stop"}],
+                        "usage": {"prompt_tokens": 5, "completion_tokens": 4}
+                    });
+                    (StatusCode::OK, axum::Json(body))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (format!("http://{}/v1/chat/completions", addr), seen)
+    }
+
+    async fn run_compare(app: App, models: Value) -> Result<Vec<(String, Value)>, ApiError> {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("cpk_test"));
+        let body = json!({"models": models, "max_tokens": 50, "messages": [{"role": "user", "content": "Hi"}]});
+        let response = compare(State(app), headers, Uri::from_static("/v1/compare"), ClientIp([127, 0, 0, 1].into()), axum::Json(body)).await?;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        Ok(SseEventParser::new()
+            .push_and_drain(&body)
+            .into_iter()
+            .map(|event| (event.event.unwrap_or_default(), serde_json::from_str(&event.data).unwrap()))
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn test_compare_fans_out_and_reports_partial_failure() {
+        let (url, seen) = model_backend().await;
+        let events = run_compare(test_app(url), json!(["alpha", "broken", "beta"])).await.unwrap();
+
+        let mut asked = seen.lock().unwrap().clone();
+        asked.sort();
+        assert_eq!(asked, ["alpha", "beta", "broken"], "one backend request per model");
+
+        let text = |model: &str| -> String {
+            events
+                .iter()
+                .filter(|(name, data)| name == "content_block_delta" && data["model"] == model)
+                .filter_map(|(_, data)| data["data"]["delta"]["text"].as_str())
+                .collect()
+        };
+        assert_eq!(text("alpha"), "Hi from alpha");
+        assert_eq!(text("beta"), "Hi from beta");
+        // A failing backend ends its model's stream with an error block; the others are unaffected
+        assert!(text("broken").contains("model not loaded"));
+
+        let (name, done) = events.last().unwrap();
+        assert_eq!(name, "compare_done");
+        let results = done["results"].as_array().unwrap();
+        let summary: Vec<(&str, &str, u64)> = results
+            .iter()
+            .map(|r| (r["model"].as_str().unwrap(), r["stop_reason"].as_str().unwrap(), r["output_tokens"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(summary, [("alpha", "end_turn", 4), ("broken", "error", 0), ("beta", "end_turn", 4)]);
+    }
+
+    #[tokio::test]
+    async fn test_compare_rejects_empty_and_oversized_model_lists() {
+        let app = test_app("http://127.0.0.1:9/v1/chat/completions".into());
+        assert_eq!(run_compare(app.clone(), json!([])).await.unwrap_err().code, "no_models");
+        let models: Vec<String> = (0..=COMPARE_MAX_MODELS).map(|i| format!("m{}", i)).collect();
+        assert_eq!(run_compare(app, json!(models)).await.unwrap_err().code, "too_many_models");
+    }
+}
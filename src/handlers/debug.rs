@@ -0,0 +1,139 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use crate::handlers::admin::require_admin;
+use crate::handlers::messages::{convert_request, resolve_model};
+use crate::handlers::ApiError;
+use crate::models::{App, ClaudeRequest};
+use crate::services::{anthropic_betas, backend_headers, TransformContext};
+
+/// Inline payloads (`data:` URLs, base64 audio) longer than this are replaced by their size
+const REDACT_PAYLOAD_CHARS: usize = 120;
+
+#[derive(Deserialize)]
+pub struct ConvertParams {
+    /// Named backend to convert for (default: `BACKEND_URL`)
+    #[serde(default)]
+    pub backend: Option<String>,
+}
+
+/// The OpenAI request `/v1/messages` would send for a Claude request, without contacting the backend.
+///
+/// Transforms run as for a real request. Inline images, documents and audio are shortened, static
+/// backend header values are hidden, and `file_id` sources are left unresolved.
+pub async fn convert(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    Query(params): Query<ConvertParams>,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    // Authenticate before parsing so unauthenticated callers learn nothing about the request format
+    require_admin(&app, &headers, &uri)?;
+    let mut cr: ClaudeRequest = serde_json::from_slice(&body).map_err(|e| {
+        log::warn!("❌ /debug/convert: invalid Claude request: {}", e);
+        (StatusCode::BAD_REQUEST, "invalid_request")
+    })?;
+    resolve_model(&app, &mut cr);
+    let backend = match &params.backend {
+        Some(name) => app.backends.get(name).ok_or((StatusCode::BAD_REQUEST, "unknown_backend"))?.clone(),
+        None => app.backends.default_backend().clone(),
+    };
+
+    let mut transform_ctx = TransformContext::new("msg_debug_convert".into(), cr.model.clone());
+    transform_ctx.betas = anthropic_betas(&headers, &uri);
+    app.transforms.on_claude_request(&mut transform_ctx, &mut cr).await?;
+    let conversion = convert_request(&app, &backend, cr, &mut transform_ctx).await?;
+
+    let mut sent_headers = Map::new();
+    sent_headers.insert("authorization".into(), json!("Bearer <client key>"));
+    sent_headers.insert("content-type".into(), json!("application/json"));
+    for (name, value) in &backend_headers(&headers, &app.config.forward_headers, &[]) {
+        sent_headers.insert(name.to_string(), json!(value.to_str().unwrap_or("<binary>")));
+    }
+    for (name, _) in &app.config.extra_headers {
+        sent_headers.insert(name.to_string(), json!("<redacted>"));
+    }
+    if let Some(name) = &backend.options.session_header {
+        sent_headers.insert(name.to_string(), json!("<per-client session hash>"));
+    }
+    let min_bytes = backend.options.request_gzip_min_bytes;
+    if min_bytes > 0 && serde_json::to_vec(&conversion.oai).map_or(0, |b| b.len()) >= min_bytes {
+        sent_headers.insert("content-encoding".into(), json!("gzip"));
+    }
+
+    let mut body = serde_json::to_value(&conversion.oai).unwrap_or_default();
+    redact_payloads(&mut body);
+    Ok(Json(json!({
+        "backend": backend.name,
+        "url": backend.url,
+        "headers": sent_headers,
+        "body": body,
+        "tool_emulation": conversion.tool_scanner.is_some(),
+        "server_tools": conversion.server_tool_specs.len(),
+        "notices": conversion.notices,
+        "dropped_features": conversion.dropped,
+    })))
+}
+
+/// Diagnostic headers (`x-request-id`, rate limits, server, model version) of the backend
+/// responses to a recent request, for cross-referencing provider support tickets
+pub async fn request(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path(request_id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    require_admin(&app, &headers, &uri)?;
+    let responses = app.stats.backend_responses(&request_id);
+    if responses.is_empty() {
+        return Err(ApiError::with_message(StatusCode::NOT_FOUND, "request_not_found", &format!("No recent backend response for {}", request_id)));
+    }
+    Ok(Json(json!({ "request_id": request_id, "backend_responses": responses })))
+}
+
+/// Shorten inline payloads (`data:` URLs and `data` fields such as `input_audio.data`) so the
+/// converted request stays readable
+fn redact_payloads(value: &mut Value) {
+    match value {
+        Value::String(s) if s.starts_with("data:") && s.len() > REDACT_PAYLOAD_CHARS => {
+            let head = s.split_once(',').map_or("data:", |(head, _)| head);
+            *s = format!("{},<{} bytes>", head, s.len());
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_payloads),
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                match item {
+                    Value::String(s) if key == "data" && s.len() > REDACT_PAYLOAD_CHARS => *s = format!("<{} bytes>", s.len()),
+                    _ => redact_payloads(item),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_payloads() {
+        let image = format!("data:image/png;base64,{}", "A".repeat(500));
+        let mut body = json!({"messages": [{"role": "user", "content": [
+            {"type": "image_url", "image_url": {"url": image}},
+            {"type": "input_audio", "input_audio": {"data": "B".repeat(300), "format": "wav"}},
+            {"type": "text", "text": "x".repeat(500)}
+        ]}]});
+        redact_payloads(&mut body);
+        let parts = &body["messages"][0]["content"];
+        assert_eq!(parts[0]["image_url"]["url"], "data:image/png;base64,<522 bytes>");
+        assert_eq!(parts[1]["input_audio"]["data"], "<300 bytes>");
+        assert_eq!(parts[2]["text"].as_str().unwrap().len(), 500);
+    }
+}
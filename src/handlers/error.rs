@@ -0,0 +1,170 @@
+use std::time::Duration;
+use axum::{
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Value};
+use crate::services::{provider_summary, ErrorVerbosity};
+
+/// Backend headers passed through on retryable errors so clients can back off accurately
+const RETRY_HEADERS: &[&str] = &["retry-after", "retry-after-ms"];
+
+/// Longest backend error text copied into a client error message
+const MAX_ERROR_MESSAGE_CHARS: usize = 500;
+
+/// Handler error: a status with a short code, sent as plain text unless it carries an
+/// Anthropic-shaped JSON body
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub headers: HeaderMap,
+    pub body: Option<Value>,
+}
+
+impl From<(StatusCode, &'static str)> for ApiError {
+    fn from((status, code): (StatusCode, &'static str)) -> Self {
+        Self { status, code, headers: HeaderMap::new(), body: None }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self.body {
+            Some(body) => (self.status, self.headers, Json(body)).into_response(),
+            None => (self.status, self.headers, self.code).into_response(),
+        }
+    }
+}
+
+impl ApiError {
+    /// Backend failure passed through: keeps the status and `Retry-After` and turns the backend's
+    /// error body into an Anthropic error, as much of it as `ERROR_VERBOSITY` allows
+    pub fn from_backend(status: StatusCode, code: &'static str, backend_headers: &HeaderMap, backend_body: &str, verbosity: ErrorVerbosity) -> Self {
+        let mut headers = HeaderMap::new();
+        for name in RETRY_HEADERS {
+            if let Some(value) = backend_headers.get(*name) {
+                headers.insert(HeaderName::from_static(name), value.clone());
+            }
+        }
+        let message = match (verbosity, backend_error_message(backend_body)) {
+            (ErrorVerbosity::Friendly, _) | (_, None) => format!("The backend returned {}", status),
+            (ErrorVerbosity::Provider, Some(message)) => provider_summary(&message),
+            (ErrorVerbosity::Raw, Some(message)) => message,
+        };
+        let body = anthropic_error_body(status, &message);
+        Self { status, code, headers, body: Some(body) }
+    }
+
+    /// Anthropic error body with `message`
+    pub fn with_message(status: StatusCode, code: &'static str, message: &str) -> Self {
+        Self { status, code, headers: HeaderMap::new(), body: Some(anthropic_error_body(status, message)) }
+    }
+
+    /// 429 with `Retry-After` (whole seconds) and an Anthropic `rate_limit_error` body
+    pub fn rate_limited(code: &'static str, retry_after: Duration, message: &str) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from(retry_after.as_secs()));
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        Self { status, code, headers, body: Some(anthropic_error_body(status, message)) }
+    }
+
+    /// 404 `not_found_error` for an unknown model, listing what the backend offers (`MODEL_NOT_FOUND=error`)
+    pub fn model_not_found(model: &str, available_models: Vec<Value>) -> Self {
+        let status = StatusCode::NOT_FOUND;
+        let mut body = anthropic_error_body(status, &format!("model: {}", model));
+        body["error"]["available_models"] = Value::Array(available_models);
+        Self { status, code: "model_not_found", headers: HeaderMap::new(), body: Some(body) }
+    }
+}
+
+/// Anthropic error `type` for an HTTP status
+pub fn anthropic_error_type(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        503 | 529 => "overloaded_error",
+        _ => "api_error",
+    }
+}
+
+/// `{"type":"error","error":{"type":...,"message":...}}`
+pub fn anthropic_error_body(status: StatusCode, message: &str) -> Value {
+    json!({ "type": "error", "error": { "type": anthropic_error_type(status), "message": message } })
+}
+
+/// Human-readable message from an OpenAI-style (or plain text) error body
+pub fn backend_error_message(body: &str) -> Option<String> {
+    let message = match serde_json::from_str::<Value>(body) {
+        Ok(value) => {
+            let error = value.get("error").unwrap_or(&value);
+            let message = error.get("message").or_else(|| error.get("detail")).or(Some(error).filter(|e| e.is_string()));
+            message?.as_str()?.to_string()
+        }
+        Err(_) => body.trim().to_string(),
+    };
+    let message: String = message.chars().take(MAX_ERROR_MESSAGE_CHARS).collect();
+    (!message.is_empty()).then_some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_backend_keeps_retry_after() {
+        let mut backend = HeaderMap::new();
+        backend.insert("retry-after", HeaderValue::from_static("17"));
+        backend.insert("x-request-id", HeaderValue::from_static("abc"));
+        let body = r#"{"error":{"message":"Rate limit reached for requests","type":"requests","code":"rate_limit_exceeded"}}"#;
+        let error = ApiError::from_backend(StatusCode::TOO_MANY_REQUESTS, "backend_error_retryable", &backend, body, ErrorVerbosity::Provider);
+        assert_eq!(error.headers.get("retry-after").unwrap(), "17");
+        assert!(error.headers.get("x-request-id").is_none());
+        assert_eq!(
+            error.body,
+            Some(json!({ "type": "error", "error": { "type": "rate_limit_error", "message": "Rate limit reached for requests" } }))
+        );
+    }
+
+    #[test]
+    fn test_backend_error_message() {
+        assert_eq!(backend_error_message(r#"{"detail":"Service busy"}"#).as_deref(), Some("Service busy"));
+        assert_eq!(backend_error_message(r#"{"error":"overloaded"}"#).as_deref(), Some("overloaded"));
+        assert_eq!(backend_error_message("<html>Bad Gateway</html>\n").as_deref(), Some("<html>Bad Gateway</html>"));
+        assert_eq!(backend_error_message(r#"{"status":500}"#), None);
+        assert_eq!(backend_error_message(""), None);
+        let error = ApiError::from_backend(StatusCode::SERVICE_UNAVAILABLE, "backend_error_retryable", &HeaderMap::new(), "", ErrorVerbosity::Provider);
+        assert_eq!(error.body.unwrap()["error"], json!({ "type": "overloaded_error", "message": "The backend returned 503 Service Unavailable" }));
+    }
+
+    #[test]
+    fn test_from_backend_follows_verbosity() {
+        let body = r#"{"error":{"message":"pool exhausted at http://10.0.0.5:8000/v1\n  at worker.py:12"}}"#;
+        let message = |verbosity| {
+            let error = ApiError::from_backend(StatusCode::BAD_GATEWAY, "backend_error", &HeaderMap::new(), body, verbosity);
+            error.body.unwrap()["error"]["message"].as_str().unwrap().to_string()
+        };
+        assert_eq!(message(ErrorVerbosity::Friendly), "The backend returned 502 Bad Gateway");
+        assert_eq!(message(ErrorVerbosity::Provider), "pool exhausted at [internal]");
+        assert!(message(ErrorVerbosity::Raw).contains("10.0.0.5") && message(ErrorVerbosity::Raw).contains("worker.py"));
+    }
+
+    #[test]
+    fn test_model_not_found_lists_models() {
+        let error = ApiError::model_not_found("glm-5", vec![json!({"type": "model", "id": "glm-4.6"})]);
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            error.body.unwrap(),
+            json!({ "type": "error", "error": {
+                "type": "not_found_error",
+                "message": "model: glm-5",
+                "available_models": [{"type": "model", "id": "glm-4.6"}]
+            } })
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use axum::{
+    extract::{Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Json, Response},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::constants::DEFAULT_FILES_LIST_LIMIT;
+use crate::handlers::ApiError;
+use crate::models::App;
+use crate::services::{key_fingerprint, AdmissionPermit, AdmissionPriority, FileError, FileMetadata, FileStore};
+
+/// `/v1/files` endpoints 404 unless `FILES_DIR` or `FILES_S3_BUCKET` is set
+fn store(app: &App) -> Result<&FileStore, (StatusCode, &'static str)> {
+    app.files.as_deref().ok_or((StatusCode::NOT_FOUND, "files_disabled"))
+}
+
+/// Files are scoped to the fingerprint of the client key that uploaded them; there is no access
+/// without one
+fn owner(app: &App, headers: &HeaderMap, uri: &Uri) -> Result<String, (StatusCode, &'static str)> {
+    match app.config.client_auth.client_key(headers, uri) {
+        Some(key) if key.contains("sk-ant-") => Err((StatusCode::UNAUTHORIZED, "invalid_auth_token")),
+        Some(key) => Ok(key_fingerprint(&key)),
+        None => Err((StatusCode::UNAUTHORIZED, "missing_api_key")),
+    }
+}
+
+/// Uploads and downloads take a slot of `MAX_CONCURRENT_REQUESTS` like messages do
+async fn admit(app: &App) -> Result<Option<AdmissionPermit>, ApiError> {
+    let Some(admission) = &app.admission else { return Ok(None) };
+    match admission.admit(AdmissionPriority::Normal).await {
+        Ok(permit) => Ok(Some(permit)),
+        Err(e) => {
+            log::warn!("🚦 File transfer not admitted ({:?})", e);
+            let message = "The proxy is at its concurrent request limit; retry after the indicated delay.";
+            Err(ApiError::rate_limited(e.code(), admission.retry_after(), message))
+        }
+    }
+}
+
+fn file_error(e: FileError) -> (StatusCode, &'static str) {
+    let status = match &e {
+        FileError::NotFound | FileError::Disabled => StatusCode::NOT_FOUND,
+        FileError::TooLarge { bytes, limit } => {
+            log::warn!("❌ Upload rejected: {} bytes is over the {} byte limit", bytes, limit);
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+        FileError::Upload(message) => {
+            log::warn!("❌ Upload failed: {}", message);
+            StatusCode::BAD_REQUEST
+        }
+        FileError::Storage(message) => {
+            log::error!("❌ File storage error: {}", message);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, e.code())
+}
+
+/// Multipart upload; the `file` field is streamed to storage
+pub async fn upload(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    mut multipart: Multipart,
+) -> Result<Json<FileMetadata>, ApiError> {
+    let files = store(&app)?;
+    let owner = owner(&app, &headers, &uri)?;
+    let _admission = admit(&app).await?;
+    let invalid = |_| (StatusCode::BAD_REQUEST, "invalid_multipart");
+    while let Some(field) = multipart.next_field().await.map_err(invalid)? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let filename = field.file_name().unwrap_or("upload").to_string();
+        let mime_type = field.content_type().map(String::from);
+        let file = files.upload(&filename, mime_type.as_deref(), field, &owner).await.map_err(file_error)?;
+        log::info!("📁 Uploaded {} '{}' ({}, {} bytes)", file.id, file.filename, file.mime_type, file.size_bytes);
+        return Ok(Json(file));
+    }
+    Err((StatusCode::BAD_REQUEST, "missing_file_field").into())
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    /// Page size, 1-1000 (default 20)
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Return files older than this one
+    #[serde(default)]
+    pub after_id: Option<String>,
+    /// Return files newer than this one
+    #[serde(default)]
+    pub before_id: Option<String>,
+}
+
+/// Files of the calling key, newest first
+pub async fn list(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    Query(params): Query<ListParams>,
+) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    let files = store(&app)?;
+    let owner = owner(&app, &headers, &uri)?;
+    let mut ids = files.list_ids(&owner).await.map_err(file_error)?;
+    let limit = params.limit.unwrap_or(DEFAULT_FILES_LIST_LIMIT).clamp(1, 1000);
+    let position = |ids: &[String], id: &str| ids.iter().position(|i| i == id);
+
+    // Page through ids first so only the returned files' metadata is read
+    let has_more = if let Some(before) = params.before_id.as_deref().and_then(|id| position(&ids, id)) {
+        ids.truncate(before);
+        let skip = ids.len().saturating_sub(limit);
+        ids.drain(..skip);
+        skip > 0
+    } else {
+        if let Some(after) = params.after_id.as_deref().and_then(|id| position(&ids, id)) {
+            ids.drain(..=after);
+        }
+        let has_more = ids.len() > limit;
+        ids.truncate(limit);
+        has_more
+    };
+    let mut page = Vec::with_capacity(ids.len());
+    for id in &ids {
+        match files.get(id, &owner).await {
+            Ok(file) => page.push(file),
+            // Deleted since it was listed
+            Err(FileError::NotFound) => {}
+            Err(e) => return Err(file_error(e)),
+        }
+    }
+    let files = page;
+    Ok(Json(json!({
+        "data": files,
+        "first_id": files.first().map(|f| &f.id),
+        "last_id": files.last().map(|f| &f.id),
+        "has_more": has_more,
+    })))
+}
+
+pub async fn get(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path(file_id): Path<String>,
+) -> Result<Json<FileMetadata>, (StatusCode, &'static str)> {
+    let owner = owner(&app, &headers, &uri)?;
+    store(&app)?.get(&file_id, &owner).await.map(Json).map_err(file_error)
+}
+
+/// Raw file bytes with the stored media type
+pub async fn content(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path(file_id): Path<String>,
+) -> Result<Response, ApiError> {
+    let owner = owner(&app, &headers, &uri)?;
+    let _admission = admit(&app).await?;
+    let (file, data) = store(&app)?.content(&file_id, &owner).await.map_err(file_error)?;
+    Ok(([(header::CONTENT_TYPE, file.mime_type)], data).into_response())
+}
+
+pub async fn delete(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path(file_id): Path<String>,
+) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    let owner = owner(&app, &headers, &uri)?;
+    store(&app)?.delete(&file_id, &owner).await.map_err(file_error)?;
+    log::info!("🗑️  Deleted {}", file_id);
+    Ok(Json(json!({ "id": file_id, "type": "file_deleted" })))
+}
@@ -1,16 +1,36 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use axum::{
     extract::State,
+    http::StatusCode,
     response::Json,
 };
 use serde_json::{json, Value};
+use crate::constants::HEALTH_PROBE_TIMEOUT_SECS;
 use crate::models::App;
+use crate::services::WarmupStatus;
 
-/// Health check endpoint
+/// Deep health check: probes the backend's model list and reports circuit breaker state
 pub async fn health_check(State(app): State<App>) -> Json<Value> {
-    let models = crate::services::model_cache::get_available_models(&app).await;
+    let started = Instant::now();
+    let probe = tokio::time::timeout(
+        Duration::from_secs(HEALTH_PROBE_TIMEOUT_SECS),
+        // The boxed error isn't Send, so stringify it before the await point
+        async { crate::services::model_cache::fetch_models(&app.client, &app.backend_url).await.map_err(|e| e.to_string()) },
+    )
+    .await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let backend = match probe {
+        Ok(Ok(models)) => json!({ "reachable": true, "latency_ms": latency_ms, "models": models.len() }),
+        Ok(Err(e)) => json!({ "reachable": false, "latency_ms": latency_ms, "error": e }),
+        Err(_) => json!({ "reachable": false, "latency_ms": latency_ms, "error": "timed out" }),
+    };
+    let models_cached = app.cached_models().await.map_or(0, |m| m.len());
     let circuit_breaker = app.circuit_breaker.read().await;
 
-    let status = if circuit_breaker.is_open {
+    let status = if circuit_breaker.is_open || backend["reachable"] != true {
         "unhealthy"
     } else {
         "healthy"
@@ -19,11 +39,42 @@ pub async fn health_check(State(app): State<App>) -> Json<Value> {
     Json(json!({
         "status": status,
         "backend_url": app.backend_url,
-        "models_cached": models.len(),
+        "backend": backend,
+        "models_cached": models_cached,
         "circuit_breaker": {
             "enabled": circuit_breaker.enabled,
             "is_open": circuit_breaker.is_open,
             "consecutive_failures": circuit_breaker.consecutive_failures
         }
     }))
-}
\ No newline at end of file
+}
+
+/// Liveness check for container healthchecks: never waits on the backend or on locks
+pub async fn liveness(State(app): State<App>) -> Json<Value> {
+    let updated = app.models_cache_updated.load(Ordering::Relaxed);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Json(json!({
+        "status": "ok",
+        "uptime_secs": app.stats.uptime_secs(),
+        "models_cache_age_secs": (updated > 0).then(|| now.saturating_sub(updated)),
+        "circuit_open": app.circuit_breaker.try_read().ok().map(|cb| cb.is_open),
+    }))
+}
+
+/// Readiness for load balancers: `503` while the circuit is open or the startup warm-up
+/// (`WARMUP_MODELS`) is still running; failed warm-ups are reported but don't block traffic
+pub async fn readiness(State(app): State<App>) -> (StatusCode, Json<Value>) {
+    let warmup = app.warmup.as_ref().map(|w| w.results()).unwrap_or_default();
+    let circuit_open = app.circuit_breaker.read().await.is_open;
+    let pending = warmup.iter().any(|r| r.status == WarmupStatus::Pending && r.trigger == "startup");
+    let (code, status) = if circuit_open {
+        (StatusCode::SERVICE_UNAVAILABLE, "circuit_open")
+    } else if pending {
+        (StatusCode::SERVICE_UNAVAILABLE, "warming_up")
+    } else if warmup.iter().any(|r| r.status == WarmupStatus::Failed) {
+        (StatusCode::OK, "degraded")
+    } else {
+        (StatusCode::OK, "ready")
+    };
+    (code, Json(json!({ "status": status, "warmup": warmup })))
+}
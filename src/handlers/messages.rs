@@ -1,115 +1,393 @@
 use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
-    response::sse::{Event, Sse},
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{sse::{Event, Sse}, IntoResponse, Json, Response},
 };
-use futures::{Stream, StreamExt};
+use futures::StreamExt;
 use serde_json::{json, Value};
 use std::{
-    collections::HashMap,
+    collections::HashSet,
     convert::Infallible,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tokio::sync::OwnedSemaphorePermit;
 use tokio_stream::wrappers::ReceiverStream;
 use crate::constants::*;
-use crate::models::{App, ClaudeRequest, ClaudeContentBlock, OAIMessage, OAIChatReq, OAIStreamChunk};
-use crate::services::{SseEventParser, ToolBuf, ToolsMap, extract_client_key, mask_token,
-                     get_available_models, format_backend_error, build_model_list_content};
+use crate::models::{App, Backend, ClaudeRequest, ClaudeContentBlock, OAIMessage, OAIChatReq};
+use crate::services::{accepts_json, budget_exhausted, OpsEvent, anthropic_betas, dropped_betas, negotiate_version, Beta, DebugSidecar, DroppedFeature, MESSAGES_API_VERSIONS, VERSION_HEADER, refusal_text, ModerationVerdict, Shadow, BackendSendError, BackendTimeout, MessageCollector, MessageTemplates, render, ErrorRecorded, SseBufferLimit, StreamErrorKind, AdmissionPermit, AdmissionPriority, ClientIp, is_failover_status, backend_event_stream, replay_events, is_json_body, rejects_streaming, SplitConfig, FileError, resolve_file_sources, SseEventParser, DrainOutcome, drain_with_budget, json_body, key_fingerprint, mask_token, session_hint,
+                     get_available_models, format_backend_error, build_model_list_content, ModelNotFound,
+                     ClaudeSseEmitter, ClientInfo, CompletionSummary, EventSender, RequestOverrides, StopSequenceScanner, strip_unsupported_params,
+                     ThinkingBudget, ToolActionScanner, emulate_tools, emulate_history,
+                     apply_thinking_dialect, dialect_for_model, TransformContext, backend_headers, diagnostic_headers, ChaosStream, Continuation,
+                     omitted_tools_notice, prepare_server_tools, server_tool_result_text, container_notice, container_upload_text, ServerToolOutput, ServerToolSession, ServerToolSpec, StreamTranslator};
+use crate::handlers::error::backend_error_message;
+use crate::handlers::ApiError;
+use crate::handlers::models::model_entries;
+use crate::handlers::token_count::token_breakdown_blocking;
 use crate::utils::normalize_model_name;
-use crate::utils::content_extraction::{translate_finish_reason, build_oai_tools, convert_system_content, convert_tool_choice, serialize_tool_result_content};
-
-/// Count tokens in a Claude request using tiktoken
-fn count_input_tokens(
-    messages: &[crate::models::ClaudeMessage],
-    system: &Option<serde_json::Value>,
-    tools: &Option<Vec<crate::models::ClaudeTool>>,
-) -> u32 {
-    let mut text_parts = Vec::new();
-    let mut image_count = 0;
+use crate::utils::prefill::apply_prefill;
+use crate::utils::tool_schema::apply_strict_tools;
+use crate::utils::audio::input_audio_part;
+use crate::utils::image::image_data_uri;
+use crate::utils::conversation::{fold_tool_messages, merge_same_role, orphaned_results_notice, rename_system_role, repair_ordering};
+use crate::utils::tool_ids::ToolIdMap;
+use crate::utils::content_extraction::{build_oai_tools, search_result_to_text, document_part, convert_system_content, convert_tool_choice, serialize_tool_result_content};
+
+/// Send the follow-up request carrying server tool results; `None` ends the turn with `pause_turn`
+async fn resume_after_server_tools(
+    session: Option<&mut ServerToolSession>,
+    req: Option<&reqwest::RequestBuilder>,
+    outputs: &[ServerToolOutput],
+    emulated_tools: bool,
+    fold_tool_results: bool,
+    gzip_min_bytes: usize,
+) -> Option<ChaosStream> {
+    let session = session?;
+    let Some(mut oai) = session.next_request(outputs) else {
+        log::warn!("⚠️  Server tool loop reached {} follow-up requests - pausing the turn", MAX_SERVER_TOOL_ROUNDS);
+        return None;
+    };
+    if emulated_tools {
+        emulate_history(&mut oai.messages);
+    } else if fold_tool_results {
+        fold_tool_messages(&mut oai.messages);
+    }
+    log::info!("🛰️  Continuing with {} server tool result(s) (request #{})", outputs.len(), session.rounds);
+    match json_body(req?.try_clone()?, &oai, gzip_min_bytes).send().await {
+        Ok(res) if res.status().is_success() => Some(backend_event_stream(res)),
+        Ok(res) => {
+            log::warn!("⚠️  Server tool follow-up request failed with {} - pausing the turn", res.status());
+            None
+        }
+        Err(e) => {
+            log::warn!("⚠️  Server tool follow-up request failed: {} - pausing the turn", e);
+            None
+        }
+    }
+}
 
-    // System prompt
-    if let Some(sys) = system {
-        let sys_text = match sys {
-            serde_json::Value::String(s) => s.clone(),
-            _ => serde_json::to_string(sys).unwrap_or_default(),
-        };
-        if !sys_text.is_empty() {
-            text_parts.push(sys_text);
+/// `STREAMING=auto`: when the backend rejects `stream: true`, stop streaming to it and resend the
+/// request without streaming; any other response is returned as received
+async fn retry_without_streaming(
+    res: reqwest::Response,
+    retry: reqwest::RequestBuilder,
+    oai: &mut OAIChatReq,
+    backend: &Backend,
+) -> reqwest::Result<reqwest::Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let headers = res.headers().clone();
+    let body = res.bytes().await?;
+    if !rejects_streaming(status, &String::from_utf8_lossy(&body)) {
+        let mut response = axum::http::Response::new(body);
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        return Ok(response.into());
+    }
+    if backend.options.streaming.mark_unsupported(&oai.model) {
+        log::warn!("📴 Backend '{}' rejected stream: true for {} - sending buffered requests for it for {}s", backend.name, oai.model, STREAMING_FALLBACK_SECS);
+    }
+    oai.stream = false;
+    json_body(retry, oai, backend.options.request_gzip_min_bytes).send().await
+}
+
+/// Send the next auto-continuation request when the answer was `truncated` at max_tokens; returns its stream
+async fn resume_truncated(
+    continuation: Option<&mut Continuation>,
+    req: Option<&reqwest::RequestBuilder>,
+    truncated: bool,
+    gzip_min_bytes: usize,
+) -> Option<ChaosStream> {
+    let (continuation, req) = (continuation?, req?);
+    if !truncated {
+        return None;
+    }
+    let oai = continuation.next_request()?;
+    log::info!(
+        "⏩ Output truncated at max_tokens - continuing (request #{}, max_tokens={})",
+        continuation.requests,
+        oai.max_tokens.unwrap_or_default()
+    );
+    match json_body(req.try_clone()?, &oai, gzip_min_bytes).send().await {
+        Ok(res) if res.status().is_success() => Some(backend_event_stream(res)),
+        Ok(res) => {
+            log::warn!("⚠️  Continuation request failed with {} - ending at max_tokens", res.status());
+            None
         }
+        Err(e) => {
+            log::warn!("⚠️  Continuation request failed: {} - ending at max_tokens", e);
+            None
+        }
+    }
+}
+
+/// OpenAI assistant message from the thinking, text and tool calls of a Claude assistant message
+fn assistant_message(thinking_parts: &[&str], text_parts: &[&str], tool_calls: Vec<Value>) -> OAIMessage {
+    // Interleave thinking: prepend thinking blocks as <think> tags
+    // Always use a string (even if empty) for better backend compatibility
+    let mut combined = String::new();
+
+    // Add thinking content first, wrapped in <think> tags
+    if !thinking_parts.is_empty() {
+        let thinking_text = thinking_parts.join("\n");
+        let thinking_len = thinking_text.len();
+        combined.push_str(&format!("<think>{}</think>\n", thinking_text));
+        log::info!("🧠 INPUT: Converted {} thinking block(s) ({} chars) to interleaved <think> format", thinking_parts.len(), thinking_len);
+    }
+
+    // Add regular text content
+    if !text_parts.is_empty() {
+        combined.push_str(&text_parts.join("\n"));
+    }
+
+    // Use empty string instead of null for tool-only messages (better compatibility)
+    OAIMessage {
+        role: "assistant".into(),
+        content: json!(combined),
+        tool_call_id: None,
+        tool_calls: if tool_calls.is_empty() { None } else { Some(tool_calls) },
+        prefix: None,
+    }
+}
+
+/// Backend request for `oai` with forwarded headers and the client's key; the second builder
+/// resends without streaming under `STREAMING=auto`
+fn backend_request(
+    app: &App,
+    backend: &Backend,
+    headers: &HeaderMap,
+    oai: &OAIChatReq,
+    client_key: Option<&str>,
+    owner: &str,
+) -> Result<(reqwest::RequestBuilder, Option<reqwest::RequestBuilder>), (StatusCode, &'static str)> {
+    let mut req = app
+        .client
+        .post(&backend.url)
+        .header("content-type", "application/json")
+        .headers(backend_headers(headers, &app.config.forward_headers, &app.config.extra_headers));
+    if let Some(name) = &backend.options.session_header {
+        req = req.header(name, session_hint(oai, owner));
     }
 
-    // Messages
-    for msg in messages {
-        let (msg_text, msg_image_count) = crate::utils::content_extraction::extract_text_from_content(&msg.content);
-        if !msg_text.is_empty() {
-            text_parts.push(format!("{}: {}", msg.role, msg_text));
+    // Auth: Forward client key to backend, or reject if invalid/missing
+    if let Some(key) = client_key {
+        if key.contains("sk-ant-") {
+            log::warn!("❌ Anthropic OAuth tokens (sk-ant-*) are not supported - use backend-compatible key (cpk_*)");
+            return Err((StatusCode::UNAUTHORIZED, "invalid_auth_token"));
         }
-        image_count += msg_image_count;
+        req = req.bearer_auth(key);
+        log::info!("🔄 Auth: Forwarding client key to backend");
+    } else {
+        log::warn!("❌ No client API key provided");
+        return Err((StatusCode::UNAUTHORIZED, "missing_api_key"));
     }
 
-    // Tools
-    if let Some(tools) = tools {
-        for tool in tools {
-            text_parts.push(tool.name.clone());
-            if let Some(desc) = &tool.description {
-                text_parts.push(desc.clone());
-            }
-            if let Ok(schema_str) = serde_json::to_string(&tool.input_schema) {
-                text_parts.push(schema_str);
+    // Debug request body (image data truncated)
+    if log::log_enabled!(log::Level::Debug) {
+        if let Ok(mut json_body) = serde_json::to_string_pretty(oai) {
+            if json_body.contains("\"image_url\"") {
+                // Try to truncate large data URL bodies in logs
+                let needle = "\"url\": \"data:";
+                if let Some(start) = json_body.find(needle) {
+                    // naive truncation around the data url
+                    let after = &json_body[start + needle.len()..];
+                    if let Some(end_quote) = after.find('"') {
+                        if end_quote > 120 {
+                            let replace = format!("{}{}...TRUNCATED...\"", needle, &after[..120]);
+                            let end_abs = start + needle.len() + end_quote + 1;
+                            json_body.replace_range(start..end_abs, &replace);
+                        }
+                    }
+                }
+                log::info!("📸 Request contains image data (truncated in logs)");
             }
+            let auth_header_str = client_key
+                .as_ref()
+                .map(|k| format!("Bearer {}", mask_token(k)))
+                .unwrap_or_else(|| "Not Set".into());
+            log::debug!(
+                "\n------------------ Request to Backend ------------------\n\
+                 POST {}\n\
+                 Authorization: {}\n\
+                 Content-Type: application/json\n\n\
+                 {}\n\
+                 ------------------------------------------------------------",
+                backend.url,
+                auth_header_str,
+                json_body
+            );
         }
     }
 
-    let combined_text = text_parts.join("\n");
+    let fallback_req = req.try_clone().filter(|_| oai.stream && backend.options.streaming.can_fall_back());
+    Ok((req, fallback_req))
+}
 
-    // Count tokens using tiktoken
-    match tiktoken_rs::cl100k_base() {
-        Ok(encoder) => {
-            let text_tokens = encoder.encode_with_special_tokens(&combined_text).len();
-            let image_tokens = image_count * TOKENS_PER_IMAGE;
-            (text_tokens + image_tokens) as u32
+/// Send a copy of a request to `SHADOW_BACKEND` and record how it did; the response is read to
+/// the end and discarded. `transform_ctx` is the shadow's own, so the production request's
+/// transform state is left alone. Timing starts after conversion, like the production request's
+async fn shadow_request(app: App, shadow: Arc<Shadow>, _permit: OwnedSemaphorePermit, headers: HeaderMap, mut cr: ClaudeRequest, owner: String, mut transform_ctx: TransformContext) {
+    let Some(backend) = app.backends.get(&shadow.backend).cloned() else { return };
+    if let Some(model) = &shadow.model {
+        cr.model = model.clone();
+    }
+    let outcome = async {
+        let conversion = convert_request(&app, &backend, cr, &mut transform_ctx).await.map_err(|e| format!("conversion failed: {}", e.code))?;
+        let (req, _) = backend_request(&app, &backend, &headers, &conversion.oai, Some(&shadow.api_key), &owner).map_err(|(_, code)| code.to_string())?;
+        let started = Instant::now();
+        let res = json_body(req, &conversion.oai, backend.options.request_gzip_min_bytes).send().await.map_err(|e| e.to_string())?;
+        let headers_elapsed = started.elapsed();
+        let status = res.status();
+        let mut body = res.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            chunk.map_err(|e| e.to_string())?;
+        }
+        if !status.is_success() {
+            return Err(status.to_string());
         }
-        Err(_) => {
-            // Fallback to rough estimation
-            let text_estimate = std::cmp::max(1, combined_text.len() / CHARS_PER_TOKEN);
-            let image_tokens = image_count * TOKENS_PER_IMAGE;
-            (text_estimate + image_tokens) as u32
+        Ok((headers_elapsed, started.elapsed()))
+    }
+    .await;
+    match &outcome {
+        Ok((headers_elapsed, total)) => log::info!(target: "metrics",
+            "shadow: backend={}, model={}, status=ok, headers_ms={}, total_ms={}",
+            backend.name, transform_ctx.model, headers_elapsed.as_millis(), total.as_millis()
+        ),
+        Err(e) => {
+            log::warn!("👥 Shadow request to '{}' failed: {}", backend.name, e);
+            log::info!(target: "metrics", "shadow: backend={}, model={}, status=error", backend.name, transform_ctx.model);
         }
     }
+    shadow.record_shadow(outcome);
 }
 
-pub async fn messages(
-    State(app): State<App>,
-    headers: HeaderMap,
-    axum::Json(cr): axum::Json<ClaudeRequest>,
-) -> Result<
-    (HeaderMap, Sse<impl Stream<Item = Result<Event, Infallible>>>),
-    (StatusCode, &'static str),
-> {
-    let request_start = SystemTime::now();
+/// A request the proxy refuses before contacting a backend
+#[derive(Debug)]
+pub(crate) struct Invalid {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub detail: String,
+}
 
-    // Count input tokens
-    let input_token_count = count_input_tokens(&cr.messages, &cr.system, &cr.tools);
-    log::debug!("📊 Input tokens: {}", input_token_count);
+/// Trim the requested model, using `DEFAULT_MODEL` when it is missing or blank
+pub(crate) fn apply_default_model(cr: &mut ClaudeRequest, default_model: Option<&str>) {
+    let model = cr.model.trim();
+    if model.len() != cr.model.len() {
+        cr.model = model.to_string();
+    }
+    if let Some(default_model) = default_model.filter(|_| cr.model.is_empty()) {
+        log::info!("🎯 Request has no model - using DEFAULT_MODEL {}", default_model);
+        cr.model = default_model.to_string();
+    }
+}
 
-    // Circuit breaker check
-    {
-        let mut cb = app.circuit_breaker.write().await;
-        if !cb.should_allow_request() {
-            log::error!("🔴 Circuit breaker is open - rejecting request");
-            return Err((StatusCode::SERVICE_UNAVAILABLE, "backend_unavailable_circuit_open"));
+/// `DEFAULT_MODEL` for a blank model, then the defaults of a `MODEL_PRESETS` alias; returns the preset applied
+pub(crate) fn resolve_model(app: &App, cr: &mut ClaudeRequest) -> Option<String> {
+    apply_default_model(cr, app.config.default_model.as_deref());
+    app.presets.as_ref().and_then(|presets| presets.apply(cr))
+}
+
+/// A request after header overrides, model resolution, request transforms, validation and file
+/// inlining: the steps `/v1/messages` and its dry run `/v1/messages/validate` share
+pub(crate) struct PreparedRequest {
+    pub cr: ClaudeRequest,
+    /// `x-proxy-backend`, else the default backend
+    pub backend: Backend,
+    /// An `x-proxy-backend` override pinned the backend; alias routing is skipped
+    pub pinned_backend: bool,
+    /// `MODEL_PRESETS` alias applied
+    pub preset: Option<String>,
+    pub transform_ctx: TransformContext,
+    /// Every reason the request would be refused, in the order `/v1/messages` checks them
+    pub problems: Vec<Invalid>,
+}
+
+/// Prepare a request for conversion. With `dry_run` the transforms are told the request won't
+/// be sent and nothing is logged as applied; a failing transform ends preparation early
+pub(crate) async fn prepare_request(
+    app: &App,
+    headers: &HeaderMap,
+    uri: &Uri,
+    mut cr: ClaudeRequest,
+    message_id: &str,
+    dry_run: bool,
+) -> Result<PreparedRequest, ApiError> {
+    let client_key = app.config.client_auth.client_key(headers, uri);
+    let mut problems = Vec::new();
+
+    // Trusted clients may override model/backend/max_tokens via x-proxy-* headers
+    let overrides = RequestOverrides::from_headers(headers);
+    let mut backend = app.backends.default_backend().clone();
+    let mut pinned_backend = false;
+    if !overrides.is_empty() {
+        if app.config.is_trusted_for_overrides(client_key.as_deref()) {
+            overrides.apply(&mut cr);
+            if let Some(name) = &overrides.backend {
+                match app.backends.get(name) {
+                    Some(selected) => {
+                        if !dry_run {
+                            log::info!("🎛️  Header override: backend → {}", selected.name);
+                        }
+                        backend = selected.clone();
+                        pinned_backend = true;
+                    }
+                    None => problems.push(Invalid { status: StatusCode::BAD_REQUEST, code: "unknown_backend", detail: format!("Unknown backend '{}'", name) }),
+                }
+            }
+        } else if !dry_run {
+            log::warn!("⚠️  Ignoring x-proxy-* override headers from untrusted client");
         }
     }
+    let preset = resolve_model(app, &mut cr);
+    if let Some(preset) = preset.as_ref().filter(|_| !dry_run) {
+        log::info!("🎚️  Model preset {} → {}", preset, cr.model);
+    }
+
+    // Custom request rewriting (runs before validation so transforms see the final request)
+    let mut transform_ctx = TransformContext::new(message_id.to_string(), cr.model.clone());
+    transform_ctx.client = ClientInfo::from_headers(headers);
+    transform_ctx.key_fingerprint = client_key.as_deref().map(key_fingerprint);
+    transform_ctx.betas = anthropic_betas(headers, uri);
+    transform_ctx.dry_run = dry_run;
+    app.transforms.on_claude_request(&mut transform_ctx, &mut cr).await?;
 
     // Request validation
-    if cr.messages.is_empty() {
-        log::warn!("❌ Validation failed: empty messages");
-        return Err((StatusCode::BAD_REQUEST, "empty_messages"));
+    problems.extend(validate_request(&cr));
+
+    // Files API: inline `file_id` sources of image and document blocks (a live request stops at its first problem)
+    if !dry_run && !problems.is_empty() {
+        return Ok(PreparedRequest { cr, backend, pinned_backend, preset, transform_ctx, problems });
+    }
+    match resolve_file_sources(app.files.as_deref(), &mut cr.messages, transform_ctx.key_fingerprint.as_deref()).await {
+        Ok(0) => {}
+        Ok(resolved) if !dry_run => log::info!("📁 Inlined {} uploaded file(s)", resolved),
+        Ok(_) => {}
+        Err(e) => {
+            let status = if matches!(e, FileError::Storage(_)) { StatusCode::BAD_GATEWAY } else { StatusCode::BAD_REQUEST };
+            problems.push(Invalid { status, code: e.code(), detail: format!("file reference not resolved ({:?})", e) });
+        }
+    }
+
+    Ok(PreparedRequest { cr, backend, pinned_backend, preset, transform_ctx, problems })
+}
+
+/// Everything wrong with a request's model and size limits, in the order `/v1/messages` checks them
+pub(crate) fn validate_request(cr: &ClaudeRequest) -> Vec<Invalid> {
+    let mut invalid = Vec::new();
+    let mut fail = |status, code, detail: String| invalid.push(Invalid { status, code, detail });
+
+    if cr.model.is_empty() {
+        fail(StatusCode::BAD_REQUEST, "missing_model", "model is required (no DEFAULT_MODEL is configured)".into());
     }
 
+    if cr.messages.is_empty() {
+        fail(StatusCode::BAD_REQUEST, "empty_messages", "empty messages".into());
+    }
     if cr.messages.len() > MAX_MESSAGES_PER_REQUEST {
-        log::warn!("❌ Validation failed: too many messages ({})", cr.messages.len());
-        return Err((StatusCode::BAD_REQUEST, "too_many_messages"));
+        fail(StatusCode::BAD_REQUEST, "too_many_messages", format!("too many messages ({})", cr.messages.len()));
     }
 
     // Validate message size (rough check)
@@ -122,70 +400,103 @@ pub async fn messages(
             }
         })
         .sum();
-
     if total_content_size > MAX_TOTAL_CONTENT_SIZE {
-        log::warn!("❌ Validation failed: content too large ({} bytes)", total_content_size);
-        return Err((StatusCode::PAYLOAD_TOO_LARGE, "content_too_large"));
+        fail(StatusCode::PAYLOAD_TOO_LARGE, "content_too_large", format!("content too large ({} bytes)", total_content_size));
     }
 
-    // Validate max_tokens if provided
     if let Some(max_tokens) = cr.max_tokens {
-        if max_tokens < MIN_TOKENS_LIMIT || max_tokens > MAX_TOKENS_LIMIT {
-            log::warn!("❌ Validation failed: max_tokens out of range ({})", max_tokens);
-            return Err((StatusCode::BAD_REQUEST, "invalid_max_tokens"));
+        if !(MIN_TOKENS_LIMIT..=MAX_TOKENS_LIMIT).contains(&max_tokens) {
+            fail(StatusCode::BAD_REQUEST, "invalid_max_tokens", format!("max_tokens out of range ({})", max_tokens));
         }
     }
 
-    // Validate system prompt length if provided
     if let Some(ref system) = cr.system {
         let system_size = match system {
             serde_json::Value::String(s) => s.len(),
             other => serde_json::to_string(other).unwrap_or_default().len(),
         };
         if system_size > MAX_SYSTEM_PROMPT_SIZE {
-            log::warn!("❌ Validation failed: system prompt too large ({} bytes)", system_size);
-            return Err((StatusCode::BAD_REQUEST, "system_prompt_too_large"));
+            fail(StatusCode::BAD_REQUEST, "system_prompt_too_large", format!("system prompt too large ({} bytes)", system_size));
         }
     }
+    invalid
+}
 
-    // Log warning for service_tier (not supported by OpenAI, will be ignored)
-    if cr.service_tier.is_some() {
-        log::debug!("ℹ️  'service_tier' parameter forwarded (may be ignored by backend)");
-    }
-
-    // Debug: Log incoming headers (names only)
-    log::debug!("📥 Incoming headers:");
-    for (name, _) in headers.iter() {
-        log::debug!("   {}", name);
-    }
+/// A Claude request converted for one backend, with the state needed to translate its response
+pub(crate) struct Conversion {
+    /// The request as it is sent to the backend
+    pub oai: OAIChatReq,
+    /// Model name after case correction, before transforms
+    pub backend_model: String,
+    pub thinking_budget: Option<ThinkingBudget>,
+    pub stop_scanner: Option<StopSequenceScanner>,
+    pub tool_scanner: Option<ToolActionScanner>,
+    pub server_tool_specs: Vec<ServerToolSpec>,
+    pub tool_ids: ToolIdMap,
+    /// Warning blocks sent before the answer for request features the proxy can't provide
+    pub notices: Vec<String>,
+    /// Request features that didn't reach the backend, for `x-proxy-debug: dropped` clients
+    pub dropped: Vec<DroppedFeature>,
+}
 
-    // Auth extraction: Authorization or x-api-key
-    let client_key = extract_client_key(&headers);
+/// `cache_control` breakpoints on system and message blocks; chat completions have nowhere to put them
+fn cache_breakpoints(cr: &ClaudeRequest) -> usize {
+    let count = |content: &Value| content.as_array().map_or(0, |blocks| blocks.iter().filter(|b| b.get("cache_control").is_some()).count());
+    cr.system.as_ref().map_or(0, count) + cr.messages.iter().map(|m| count(&m.content)).sum::<usize>()
+}
 
-    if let Some(key) = &client_key {
-        log::info!("🔑 Client API Key: Bearer {}", mask_token(key));
-    } else {
-        log::info!("🔑 No client API key (no 'authorization' or 'x-api-key' header)");
+/// Tool definitions for clients with the token-efficient tools beta; at debug level the bytes
+/// saved are logged, which takes building the full definitions too
+fn compact_tools(tools: Option<Vec<crate::models::ClaudeTool>>, backend: &Backend) -> Option<Vec<crate::models::OAITool>> {
+    if !log::log_enabled!(log::Level::Debug) {
+        return build_oai_tools(tools, &backend.options.schema_cleaning.compact(), backend.options.prompt_caching);
+    }
+    let size = |tools: &Option<Vec<crate::models::OAITool>>| serde_json::to_vec(tools).map_or(0, |bytes| bytes.len());
+    let full = build_oai_tools(tools.clone(), &backend.options.schema_cleaning, backend.options.prompt_caching);
+    let compact = build_oai_tools(tools, &backend.options.schema_cleaning.compact(), backend.options.prompt_caching);
+    let (before, after) = (size(&full), size(&compact));
+    if before > after {
+        log::debug!("🗜️  Token-efficient tools: {} tool(s), {} → {} bytes", compact.as_ref().map_or(0, Vec::len), before, after);
     }
+    compact
+}
 
-    let has_client_auth = client_key.is_some();
-    log::info!(
-        "📨 Request: model={}, client_auth={}, backend={}",
-        cr.model, has_client_auth, app.backend_url
-    );
+/// Convert a validated Claude request into the OpenAI request for `backend`, running the
+/// `on_oai_request` transforms; shared by `/v1/messages` and `/debug/convert`
+pub(crate) async fn convert_request(
+    app: &App,
+    backend: &Backend,
+    mut cr: ClaudeRequest,
+    transform_ctx: &mut TransformContext,
+) -> Result<Conversion, ApiError> {
+    // Normalize model name (case-correction only; the model cache describes the default backend)
+    let backend_model = if backend.name == app.backends.default_backend().name {
+        normalize_model_name(&cr.model, &app.models_cache).await
+    } else {
+        cr.model.clone()
+    };
 
-    // Normalize model name (case-correction only)
-    let backend_model = normalize_model_name(&cr.model, &app.models_cache).await;
-    let backend_model_for_metrics = backend_model.clone();
+    let mut dropped = Vec::new();
+    if let Some(tier) = &cr.service_tier {
+        dropped.push(DroppedFeature::new("service_tier", format!("'{}' only orders the proxy's admission queue; it is not sent to the backend", tier)));
+    }
+    dropped.extend(dropped_betas(&transform_ctx.betas));
+    let breakpoints = cache_breakpoints(&cr);
+    if breakpoints > 0 {
+        dropped.push(DroppedFeature::new("cache_control", format!("{} breakpoint(s) on system or message blocks; chat completions have no cache breakpoints", breakpoints)));
+    }
+    if !backend.options.prompt_caching && cr.tools.iter().flatten().any(|t| t.cache_control.is_some()) {
+        dropped.push(DroppedFeature::new("tools.cache_control", format!("backend '{}' doesn't have PROMPT_CACHING enabled", backend.name)));
+    }
 
     // Auto-enable thinking for reasoning models if not explicitly provided
-    let thinking_config = if cr.thinking.is_some() {
-        cr.thinking
+    let thinking_config = if let Some(thinking) = cr.thinking {
+        // `disabled` (sent by clients, or by a MODEL_PRESETS `thinking=off`) also skips auto-enabling
+        (thinking.type_ != "disabled").then_some(thinking)
     } else {
         // Check if this is a reasoning model by querying model cache
         let is_reasoning_model = {
-            let cache = app.models_cache.read().await;
-            cache.as_ref()
+            app.cached_models().await
                 .and_then(|models| {
                     // Look for model in cache
                     models.iter()
@@ -212,6 +523,21 @@ pub async fn messages(
         }
     };
 
+    // Drop tools over MAX_TOOLS / MAX_TOOL_SCHEMA_BYTES before the history is consumed below
+    let mut omitted_tools = None;
+    if let Some(tools) = cr.tools.as_mut() {
+        let total = tools.len();
+        let omitted = app.config.tool_limits.apply(tools, &cr.messages, cr.tool_choice.as_ref())
+            .map_err(|e| ApiError::with_message(StatusCode::BAD_REQUEST, e.code(), &e.message()))?;
+        if !omitted.is_empty() && !transform_ctx.dry_run {
+            log::warn!("🧰 Omitted {} of {} tools to stay within the tool limits ({:?})", omitted.len(), total, app.config.tool_limits.policy);
+            log::info!(target: "metrics", "tools_omitted: backend={}, omitted={}, total={}", backend.name, omitted.len(), total);
+        }
+        if !omitted.is_empty() {
+            omitted_tools = Some(omitted_tools_notice(&omitted, total));
+        }
+    }
+
     let mut msgs = Vec::with_capacity(cr.messages.len() + 1);
     if let Some(sys) = cr.system {
         let system_content = convert_system_content(&sys);
@@ -220,10 +546,15 @@ pub async fn messages(
             content: system_content,
             tool_call_id: None,
             tool_calls: None,
+            prefix: None,
         });
     }
 
     let original_message_count = cr.messages.len();
+    let image_limits = backend.options.image_limits.for_model(&backend_model, &app.config.image_limits_models);
+    let mut image_count = 0;
+    let mut container_uploads = 0;
+    let mut tool_ids = ToolIdMap::new(backend.options.tool_id_format);
 
     // Convert Claude messages → OpenAI messages
     for m in cr.messages {
@@ -235,6 +566,7 @@ pub async fn messages(
                 content: m.content,
                 tool_call_id: None,
                 tool_calls: None,
+                prefix: None,
             });
             continue;
         }
@@ -250,6 +582,7 @@ pub async fn messages(
                     content: m.content,
                     tool_call_id: None,
                     tool_calls: None,
+                    prefix: None,
                 });
                 continue;
             }
@@ -266,17 +599,25 @@ pub async fn messages(
                     msgs.push(OAIMessage {
                         role: "tool".into(),
                         content: json!(tool_content),
-                        tool_call_id: Some(tool_use_id.clone()),
+                        tool_call_id: Some(tool_ids.backend_id(tool_use_id)),
                         tool_calls: None,
+                        prefix: None,
                     });
                 }
             }
 
             // Also pass any user text (if present) after tool results
-            let text_parts: Vec<&str> = blocks
+            let text_parts: Vec<String> = blocks
                 .iter()
                 .filter_map(|b| match b {
-                    ClaudeContentBlock::Text { text } => Some(text.as_str()),
+                    ClaudeContentBlock::Text { text } => Some(text.clone()),
+                    ClaudeContentBlock::SearchResult { source, title, content } => {
+                        Some(search_result_to_text(source, title, content))
+                    }
+                    ClaudeContentBlock::ContainerUpload { file_id } => {
+                        container_uploads += 1;
+                        Some(container_upload_text(file_id))
+                    }
                     _ => None,
                 })
                 .collect();
@@ -287,6 +628,7 @@ pub async fn messages(
                     content: json!(text_parts.join("\n")),
                     tool_call_id: None,
                     tool_calls: None,
+                    prefix: None,
                 });
             }
         } else if m.role == "assistant" {
@@ -294,6 +636,10 @@ pub async fn messages(
             let mut thinking_parts = Vec::new();
             let mut text_parts = Vec::new();
             let mut tool_calls = Vec::new();
+            // Client tool calls stay with the last part so their results, in the next user message, follow them
+            let mut client_calls = Vec::new();
+            // Server tool results split the message: the call ends one assistant turn, the result follows as a tool message
+            let mut split = false;
 
             for block in &blocks {
                 match block {
@@ -302,49 +648,47 @@ pub async fn messages(
                         log::info!("🧠 INPUT: Extracted thinking block ({} chars) from assistant message", thinking.len());
                     }
                     ClaudeContentBlock::Text { text } => text_parts.push(text.as_str()),
-                    ClaudeContentBlock::ToolUse { id, name, input } => {
-                        tool_calls.push(json!({
-                            "id": id,
+                    ClaudeContentBlock::ToolUse { id, name, input } | ClaudeContentBlock::ServerToolUse { id, name, input } => {
+                        let call = json!({
+                            "id": tool_ids.backend_id(id),
                             "type": "function",
                             "function": {
                                 "name": name,
                                 "arguments": serde_json::to_string(input).unwrap_or_else(|_| "{}".into())
                             }
-                        }));
+                        });
+                        match block {
+                            ClaudeContentBlock::ToolUse { .. } => client_calls.push(call),
+                            _ => tool_calls.push(call),
+                        }
+                    }
+                    ClaudeContentBlock::WebSearchToolResult { tool_use_id, content }
+                    | ClaudeContentBlock::CodeExecutionToolResult { tool_use_id, content }
+                    | ClaudeContentBlock::BashCodeExecutionToolResult { tool_use_id, content }
+                    | ClaudeContentBlock::TextEditorCodeExecutionToolResult { tool_use_id, content } => {
+                        msgs.push(assistant_message(&thinking_parts, &text_parts, std::mem::take(&mut tool_calls)));
+                        thinking_parts.clear();
+                        text_parts.clear();
+                        msgs.push(OAIMessage {
+                            role: "tool".into(),
+                            content: json!(server_tool_result_text(content)),
+                            tool_call_id: Some(tool_ids.backend_id(tool_use_id)),
+                            tool_calls: None,
+                            prefix: None,
+                        });
+                        split = true;
                     }
                     _ => {}
                 }
             }
 
-            // Interleave thinking: prepend thinking blocks as <think> tags
-            // Always use a string (even if empty) for better backend compatibility
-            let mut combined = String::new();
-
-            // Add thinking content first, wrapped in <think> tags
-            if !thinking_parts.is_empty() {
-                let thinking_text = thinking_parts.join("\n");
-                let thinking_len = thinking_text.len();
-                combined.push_str(&format!("<think>{}</think>\n", thinking_text));
-                log::info!("🧠 INPUT: Converted {} thinking block(s) ({} chars) to interleaved <think> format", thinking_parts.len(), thinking_len);
-            }
-
-            // Add regular text content
-            if !text_parts.is_empty() {
-                combined.push_str(&text_parts.join("\n"));
+            tool_calls.append(&mut client_calls);
+            if !(split && thinking_parts.is_empty() && text_parts.is_empty() && tool_calls.is_empty()) {
+                msgs.push(assistant_message(&thinking_parts, &text_parts, tool_calls));
             }
-
-            // Use empty string instead of null for tool-only messages (better compatibility)
-            let content = json!(combined);
-
-            msgs.push(OAIMessage {
-                role: m.role,
-                content,
-                tool_call_id: None,
-                tool_calls: if tool_calls.is_empty() { None } else { Some(tool_calls) },
-            });
         } else {
             // User messages with possible images
-            let mut has_images = false;
+            let mut has_media = false;
             let mut oai_content_blocks = Vec::new();
 
             for block in &blocks {
@@ -352,28 +696,56 @@ pub async fn messages(
                     ClaudeContentBlock::Text { text } => {
                         oai_content_blocks.push(json!({ "type": "text", "text": text }));
                     }
+                    ClaudeContentBlock::SearchResult { source, title, content } => {
+                        let text = search_result_to_text(source, title, content);
+                        oai_content_blocks.push(json!({ "type": "text", "text": text }));
+                    }
+                    ClaudeContentBlock::ContainerUpload { file_id } => {
+                        container_uploads += 1;
+                        oai_content_blocks.push(json!({ "type": "text", "text": container_upload_text(file_id) }));
+                    }
                     ClaudeContentBlock::Image { source } => {
-                        has_images = true;
+                        has_media = true;
+                        image_count += 1;
+                        image_limits.check(image_count, &source.data).map_err(|e| {
+                            log::warn!("❌ Validation failed: image #{} over the limit ({:?})", image_count, e);
+                            (StatusCode::BAD_REQUEST, e.code())
+                        })?;
                         log::info!(
                             "🖼️ Processing image: media_type={}, size={} bytes",
                             source.media_type,
                             source.data.len()
                         );
-                        if source.data.starts_with("data:") {
-                            log::warn!("⚠️ Image data already appears to be a data URI (double-encoding?)");
-                        }
                         // Convert Claude image to OpenAI data URL
-                        let data_uri = format!("data:{};base64,{}", source.media_type, source.data);
+                        let data_uri = image_data_uri(&source.media_type, &source.data, &backend.options.images).await.map_err(|e| {
+                            log::warn!("❌ Validation failed: image rejected ({:?})", e);
+                            (StatusCode::BAD_REQUEST, e.code())
+                        })?;
                         oai_content_blocks.push(json!({
                             "type": "image_url",
                             "image_url": { "url": data_uri }
                         }));
                     }
+                    ClaudeContentBlock::Document { source, title } => {
+                        log::info!("📄 Processing document: media_type={}, size={} bytes", source.media_type, source.data.len());
+                        let part = document_part(source, title.as_deref());
+                        has_media |= part["type"] == "file";
+                        oai_content_blocks.push(part);
+                    }
+                    ClaudeContentBlock::Audio { source } => {
+                        has_media = true;
+                        log::info!("🔊 Processing audio: media_type={}, size={} bytes", source.media_type, source.data.len());
+                        let part = input_audio_part(&source.media_type, &source.data).map_err(|e| {
+                            log::warn!("❌ Validation failed: audio rejected ({:?})", e);
+                            (StatusCode::BAD_REQUEST, e.code())
+                        })?;
+                        oai_content_blocks.push(part);
+                    }
                     _ => {}
                 }
             }
 
-            let content = if has_images {
+            let content = if has_media {
                 json!(oai_content_blocks)
             } else {
                 let text = oai_content_blocks
@@ -389,6 +761,7 @@ pub async fn messages(
                 content,
                 tool_call_id: None,
                 tool_calls: None,
+                prefix: None,
             });
         }
     }
@@ -415,117 +788,466 @@ pub async fn messages(
 
     if msgs.is_empty() {
         log::error!("❌ No messages remaining after conversion!");
-        return Err((StatusCode::BAD_REQUEST, "no_messages"));
+        return Err((StatusCode::BAD_REQUEST, "no_messages").into());
     }
 
-    let tools = build_oai_tools(cr.tools);
+    // Server tools become function tools the proxy executes (WEB_SEARCH_URL, CODE_EXECUTION_SANDBOX)
+    let server_tool_specs = prepare_server_tools(&mut cr.tools, &app.config.server_tools);
+    // Containers can't be emulated: the client gets a warning block instead of silently losing them
+    let mut notices: Vec<String> = container_notice(cr.container.as_ref(), container_uploads).into_iter().collect();
+    if !notices.is_empty() {
+        log::warn!("⚠️  Code execution container requested ({} uploaded file(s)) - not supported, adding a warning block", container_uploads);
+    }
+    notices.extend(omitted_tools);
+    let tools = if Beta::TokenEfficientTools.enabled(&transform_ctx.betas) {
+        compact_tools(cr.tools, backend)
+    } else {
+        build_oai_tools(cr.tools, &backend.options.schema_cleaning, backend.options.prompt_caching)
+    };
     let (tool_choice, parallel_tool_calls) = convert_tool_choice(cr.tool_choice);
 
-    let backend_model_for_error = backend_model.clone();
 
+    let thinking_budget = ThinkingBudget::new(
+        thinking_config.as_ref().map(|tc| tc.budget_tokens),
+        app.config.thinking_budget_enforcement,
+    );
+    // Client-side stop sequence matching, including sequences past the 4 the backend gets (ENFORCE_STOP_SEQUENCES)
+    let stop_scanner = if app.config.enforce_stop_sequences {
+        cr.stop_sequences.as_deref().and_then(StopSequenceScanner::new)
+    } else {
+        None
+    };
     // Limit stop sequences to 4 to avoid backend errors (OpenAI limit)
     let stop = cr.stop_sequences.map(|mut s| {
         if s.len() > 4 {
             log::warn!("⚠️  Truncating stop_sequences from {} to 4 items", s.len());
+            if stop_scanner.is_none() {
+                dropped.push(DroppedFeature::new("stop_sequences", format!("only the first 4 of {} are sent to the backend", s.len())));
+            }
             s.truncate(4);
         }
         s
     });
 
     // Preserve your behavior: always stream SSE to backend
-    let oai = OAIChatReq {
-        model: backend_model,
+    let mut oai = OAIChatReq {
+        model: backend_model.clone(),
         messages: msgs,
         // Do not hard-default; allow backend default if None (safer across models)
         max_tokens: cr.max_tokens,
-        temperature: cr.temperature,
+        temperature: cr.temperature.map(|t| backend.options.scale_temperature(t)),
         top_p: cr.top_p,
         top_k: cr.top_k,
         stop,
         tools,
         tool_choice,
-        thinking: thinking_config.map(|tc| serde_json::to_value(tc).unwrap_or(Value::Null)),
+        thinking: thinking_config.as_ref().map(|tc| serde_json::to_value(tc).unwrap_or(Value::Null)),
         parallel_tool_calls,
         metadata: cr.metadata,
         stream: true,
+        ..Default::default()
     };
+    let non_strict = apply_strict_tools(&mut oai, backend.options.strict_tools, &backend.options.strict_tools_exclude);
+    if !non_strict.is_empty() {
+        log::debug!("🔧 Tools left non-strict (schema can't be made strict): [{}]", non_strict.join(", "));
+    }
+    apply_prefill(&mut oai, app.config.prefill_mode);
 
-    let mut req = app
-        .client
-        .post(&app.backend_url)
-        .header("content-type", "application/json");
+    transform_ctx.model = oai.model.clone();
+    app.transforms.on_oai_request(transform_ctx, &mut oai).await?;
 
-    // Auth: Forward client key to backend, or reject if invalid/missing
-    if let Some(key) = &client_key {
-        if key.contains("sk-ant-") {
-            log::warn!("❌ Anthropic OAuth tokens (sk-ant-*) are not supported - use backend-compatible key (cpk_*)");
-            return Err((StatusCode::UNAUTHORIZED, "invalid_auth_token"));
-        }
-        req = req.bearer_auth(key);
-        log::info!("🔄 Auth: Forwarding client key to backend");
+    // Backends without function calling get tools described in the prompt instead
+    let declared_tools: HashSet<String> = oai.tools.iter().flatten().map(|t| t.function.name.clone()).collect();
+    let tool_scanner = if backend.options.tool_emulation && emulate_tools(&mut oai) {
+        log::info!("🔧 Emulating tool calls through the prompt for backend '{}'", backend.name);
+        Some(ToolActionScanner::new(&transform_ctx.request_id, declared_tools))
     } else {
-        log::warn!("❌ No client API key provided");
-        return Err((StatusCode::UNAUTHORIZED, "missing_api_key"));
-    }
+        None
+    };
 
-    // Debug request body (image data truncated)
-    if log::log_enabled!(log::Level::Debug) {
-        if let Ok(mut json_body) = serde_json::to_string_pretty(&oai) {
-            if json_body.contains("\"image_url\"") {
-                // Try to truncate large data URL bodies in logs
-                let needle = "\"url\": \"data:";
-                if let Some(start) = json_body.find(needle) {
-                    // naive truncation around the data url
-                    let after = &json_body[start + needle.len()..];
-                    if let Some(end_quote) = after.find('"') {
-                        if end_quote > 120 {
-                            let replace = format!("{}{}...TRUNCATED...\"", needle, &after[..120]);
-                            let end_abs = start + needle.len() + end_quote + 1;
-                            json_body.replace_range(start..end_abs, &replace);
-                        }
-                    }
-                }
-                log::info!("📸 Request contains image data (truncated in logs)");
-            }
-            let auth_header_str = client_key
-                .as_ref()
-                .map(|k| format!("Bearer {}", mask_token(k)))
-                .unwrap_or_else(|| "Not Set".into());
-            log::debug!(
-                "\n------------------ Request to Backend ------------------\n\
-                 POST {}\n\
-                 Authorization: {}\n\
-                 Content-Type: application/json\n\n\
-                 {}\n\
-                 ------------------------------------------------------------",
-                app.backend_url,
-                auth_header_str,
-                json_body
-            );
+    // Drop parameters the backend/model doesn't support instead of forwarding them into a 400
+    let model_features = if backend.name == app.backends.default_backend().name {
+        app.cached_models()
+            .await
+            .and_then(|models| {
+                let model = models.iter().find(|m| m.id.eq_ignore_ascii_case(&oai.model))?;
+                Some(model.supported_features.clone())
+            })
+    } else {
+        None
+    };
+    let stripped = strip_unsupported_params(&mut oai, &backend.options.unsupported_params, model_features.as_deref());
+    if !stripped.is_empty() {
+        log::warn!(
+            "✂️  Stripped unsupported parameters: model={}, backend={}, params=[{}]",
+            oai.model, backend.name, stripped.join(", ")
+        );
+    }
+    dropped.extend(stripped.iter().map(|param| DroppedFeature::new(*param, format!("not supported by model '{}' on backend '{}'", oai.model, backend.name))));
+    // Role conventions differ between chat templates
+    rename_system_role(&mut oai.messages, &backend.options.system_role);
+    if backend.options.repair_ordering {
+        let repair = repair_ordering(&mut oai.messages);
+        if repair.changed {
+            log::debug!("🩹 Repaired message ordering for backend '{}'", backend.name);
+        }
+        if !repair.orphaned.is_empty() {
+            log::warn!("🩹 Dropped {} tool result(s) with no matching call: [{}]", repair.orphaned.len(), repair.orphaned.join(", "));
+            notices.push(orphaned_results_notice(&repair.orphaned));
+        }
+    }
+    if backend.options.tool_results_as_text {
+        let folded = fold_tool_messages(&mut oai.messages);
+        if folded > 0 {
+            log::debug!("📝 Folded {} tool call(s)/result(s) into text for backend '{}'", folded, backend.name);
+        }
+    }
+    if backend.options.merge_same_role {
+        let merged = merge_same_role(&mut oai.messages);
+        if merged > 0 {
+            log::debug!("🔗 Merged {} consecutive same-role message(s)", merged);
         }
     }
+    let dialect = dialect_for_model(&oai.model, &app.config.thinking_dialect_models, backend.options.thinking_dialect);
+    apply_thinking_dialect(&mut oai, dialect);
+    // Backends without streaming get `stream: false`; the buffered completion is replayed as SSE
+    if !backend.options.streaming.enabled(&oai.model) {
+        log::debug!("📴 Backend '{}' doesn't stream - requesting a buffered completion", backend.name);
+        oai.stream = false;
+    }
 
-    log::debug!("🚀 Sending request to backend with {} messages", oai.messages.len());
-    let res = req.json(&oai).send().await.map_err(|e| {
-        log::error!("❌ Backend connection failed: {}", e);
-        // Record circuit breaker failure
-        tokio::spawn({
-            let cb = app.circuit_breaker.clone();
-            async move {
-                cb.write().await.record_failure();
-            }
-        });
-        (StatusCode::BAD_GATEWAY, "backend_unavailable")
-    })?;
+    Ok(Conversion { oai, backend_model, thinking_budget, stop_scanner, tool_scanner, server_tool_specs, tool_ids, notices, dropped })
+}
 
-    let status = res.status();
-    log::debug!("📥 Backend response status: {}", status);
+pub async fn messages(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    client_ip: ClientIp,
+    axum::Json(cr): axum::Json<ClaudeRequest>,
+) -> Result<Response, ApiError> {
+    // A client without a usable key learns that before anything about its request
+    match app.config.client_auth.client_key(&headers, &uri) {
+        Some(key) if key.contains("sk-ant-") => return Err((StatusCode::UNAUTHORIZED, "invalid_auth_token").into()),
+        Some(_) => {}
+        None => return Err((StatusCode::UNAUTHORIZED, "missing_api_key").into()),
+    }
+    // Answered with the version used, the newest one not newer than the client's `anthropic-version`,
+    // on errors as well as successes
+    let version = negotiate_version(&headers, MESSAGES_API_VERSIONS)
+        .map_err(|e| ApiError::with_message(StatusCode::BAD_REQUEST, "unsupported_anthropic_version", &e))?;
+    let mut response = handle_messages(app, headers, uri, client_ip, cr).await.unwrap_or_else(IntoResponse::into_response);
+    response.headers_mut().insert(VERSION_HEADER, HeaderValue::from_static(version));
+    Ok(response)
+}
 
-    // Validate Content-Type for better error messages
-    let content_type = res.headers()
-        .get("content-type")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
+/// Per-key stream slot (`MAX_STREAMS_PER_KEY`) and shared backend slot (`MAX_CONCURRENT_REQUESTS`),
+/// held until the response ends
+async fn admit(
+    app: &App,
+    owner: &str,
+    priority: AdmissionPriority,
+) -> Result<(Option<AdmissionPermit>, Option<AdmissionPermit>), ApiError> {
+    // A key's own stream cap (MAX_STREAMS_PER_KEY) is checked first so its excess never occupies the shared queue
+    let key_stream = match app.key_streams.as_ref().filter(|_| !owner.is_empty()) {
+        Some(key_streams) => match key_streams.admit(owner, priority).await {
+            Ok(permit) => Some(permit),
+            Err(e) => {
+                log::warn!("🚦 Request over the per-key stream limit ({:?}): key {} already has {} open stream(s)", e, owner, key_streams.limit());
+                log::info!(target: "metrics", "key_stream_rejected: reason={}, key={}", e.code(), owner);
+                let message = format!(
+                    "Too many concurrent requests for this API key (limit {}); retry after the indicated delay.",
+                    key_streams.limit()
+                );
+                return Err(ApiError::rate_limited("key_stream_limit", key_streams.retry_after(), &message));
+            }
+        },
+        None => None,
+    };
+    let admission = match &app.admission {
+        Some(admission) => match admission.admit(priority).await {
+            Ok(permit) => Some(permit),
+            Err(e) => {
+                let snapshot = admission.snapshot();
+                log::warn!("🚦 Request not admitted ({:?}, {:?} priority): {} active, {} queued", e, priority, snapshot.active, snapshot.queued);
+                log::info!(target: "metrics",
+                    "admission_rejected: reason={}, priority={:?}, active={}, queued={}", e.code(), priority, snapshot.active, snapshot.queued
+                );
+                let message = "The proxy is at its concurrent request limit; retry after the indicated delay.";
+                return Err(ApiError::rate_limited(e.code(), admission.retry_after(), message));
+            }
+        },
+        None => None,
+    };
+    Ok((key_stream, admission))
+}
+
+async fn handle_messages(app: App, headers: HeaderMap, uri: Uri, client_ip: ClientIp, cr: ClaudeRequest) -> Result<Response, ApiError> {
+    let request_start = SystemTime::now();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let message_id = format!("msg_{now}");
+
+    // Auth extraction: Authorization or x-api-key (AUTH_HEADER_PRECEDENCE), or a query key on allowed routes
+    let client_key = app.config.client_auth.client_key(&headers, &uri);
+    let client_info = ClientInfo::from_headers(&headers);
+    // `Accept: application/json` gets one message object (and JSON errors) instead of an event stream
+    let wants_json = accepts_json(&headers);
+    let owner = client_key.as_deref().map(key_fingerprint).unwrap_or_default();
+
+    // A client reconnecting with Last-Event-ID picks up its response where it left off (STREAM_RESUME_SECS);
+    // only a client with a key can resume, and the replay takes the same admission slots as a new request
+    let last_event_id = headers.get("last-event-id").and_then(|v| v.to_str().ok());
+    let resumable = app.stream_resume.as_ref().zip(last_event_id).filter(|_| !owner.is_empty());
+    if let Some((buffer, after)) = resumable.and_then(|(resume, id)| resume.find(id, &owner)) {
+        if client_key.as_deref().is_some_and(|key| key.contains("sk-ant-")) {
+            return Err((StatusCode::UNAUTHORIZED, "invalid_auth_token").into());
+        }
+        let permits = admit(&app, &owner, AdmissionPriority::Normal).await?;
+        log::info!("⏯️  Resuming {} after event {}", buffer.message_id(), after);
+        let (event_tx, rx) = tokio::sync::mpsc::channel::<Event>(SSE_CHANNEL_BUFFER_SIZE);
+        tokio::spawn(async move {
+            let _permits = permits;
+            replay_events(buffer, after, event_tx).await;
+        });
+
+        let mut headers = HeaderMap::new();
+        headers.insert("cache-control", "no-cache".parse().unwrap());
+        headers.insert("connection", "keep-alive".parse().unwrap());
+        headers.insert("x-accel-buffering", "no".parse().unwrap());
+        let stream = ReceiverStream::new(rx).map(Ok::<Event, Infallible>);
+        return Ok((headers, Sse::new(stream)).into_response());
+    }
+
+    // Header overrides, model presets, request transforms, validation and file inlining
+    let prepared = prepare_request(&app, &headers, &uri, cr, &message_id, false).await?;
+    if let Some(invalid) = prepared.problems.into_iter().next() {
+        log::warn!("❌ Validation failed: {}", invalid.detail);
+        return Err((invalid.status, invalid.code).into());
+    }
+    let PreparedRequest { mut cr, backend, pinned_backend, mut transform_ctx, .. } = prepared;
+    let in_flight = app.stats.begin(&message_id, &cr.model, &client_info.to_string());
+
+    // Count input tokens
+    let mut input_token_count = token_breakdown_blocking(&cr.messages, &cr.system, &cr.tools).await.total() as u32;
+    log::debug!("📊 Input tokens: {}", input_token_count);
+
+    // Circuit breaker check
+    {
+        let mut cb = app.circuit_breaker.write().await;
+        if !cb.should_allow_request() {
+            log::error!("🔴 Circuit breaker is open - rejecting request");
+            return Err((StatusCode::SERVICE_UNAVAILABLE, "backend_unavailable_circuit_open").into());
+        }
+    }
+
+    // Streaming memory admission: shed new streams rather than grow without bound
+    let Some(mut stream_memory) = app.stream_memory.reserve(STREAM_MEMORY_BASE_BYTES) else {
+        log::warn!("🧱 Streaming memory limit reached - rejecting request");
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "stream_memory_exhausted").into());
+    };
+
+    // Content moderation (MODERATION_URL) of the whole request
+    if let Some(moderation) = app.moderation.as_ref().filter(|m| m.checks_requests()) {
+        match moderation.check_request(&mut cr).await {
+            ModerationVerdict::Allowed => {}
+            ModerationVerdict::Redacted(blocks) => {
+                log::info!("🛡️  Moderation redacted {} block(s) of the request", blocks);
+                log::info!(target: "metrics", "moderation: stage=request, action=redacted, blocks={}", blocks);
+            }
+            ModerationVerdict::Blocked(categories) => {
+                log::warn!("🛡️  Moderation blocked the request ({})", categories.join(", "));
+                log::info!(target: "metrics", "moderation: stage=request, action=blocked, categories={}", categories.join("|"));
+                let response = refusal_response(&app, transform_ctx, &cr.model, input_token_count, &refusal_text(&app.templates, &categories));
+                return if wants_json { collect_message(response, &app.templates).await } else { Ok(response) };
+            }
+        }
+    }
+
+    // Admission control (MAX_CONCURRENT_REQUESTS): wait for a backend slot or turn the request away.
+    // service_tier sets the queue priority.
+    let priority = AdmissionPriority::from_service_tier(cr.service_tier.as_deref());
+    let (key_stream, admission) = admit(&app, &owner, priority).await?;
+
+    if let Some(tier) = &cr.service_tier {
+        log::debug!("ℹ️  service_tier '{}': {:?} admission priority", tier, priority);
+    }
+
+    // Debug: Log incoming headers (names only)
+    log::debug!("📥 Incoming headers:");
+    for (name, _) in headers.iter() {
+        log::debug!("   {}", name);
+    }
+
+    if let Some(key) = &client_key {
+        log::info!("🔑 Client API Key: Bearer {}", mask_token(key));
+    } else {
+        log::info!("🔑 No client API key (no 'authorization' or 'x-api-key' header)");
+    }
+
+    // Long histories are summarized before forwarding (COMPACTION_THRESHOLD_TOKENS)
+    let mut compaction_notice = None;
+    if let Some(compaction) = &app.compaction {
+        let compacted = compaction.compact(&app, &message_id, &cr.model, &mut cr.messages, input_token_count as usize, client_key.as_deref()).await;
+        if let Some(compacted) = compacted {
+            input_token_count = token_breakdown_blocking(&cr.messages, &cr.system, &cr.tools).await.total() as u32;
+            log::info!(
+                "🗜️  Compacted {} message(s): ~{} → ~{} input tokens (threshold {})",
+                compacted.messages, compacted.tokens_before, input_token_count, compacted.threshold_tokens
+            );
+            log::info!(target: "metrics", "compaction: messages={}, tokens_before={}, tokens_after={}", compacted.messages, compacted.tokens_before, input_token_count);
+            compaction_notice = Some(compacted.notice());
+        }
+    }
+
+    // Experiment names (MODEL_EXPERIMENTS) are swapped for the conversation's arm before alias routing
+    let experiment = app.experiments.as_ref().and_then(|experiments| experiments.assign(&cr, &owner));
+    if let Some(assignment) = &experiment {
+        log::info!("🧪 Experiment {}: conversation assigned to {}", assignment.experiment, assignment.model);
+        log::info!(target: "metrics", "experiment: name={}, arm={}, client={}", assignment.experiment, assignment.model, client_info);
+        cr.model = assignment.model.clone();
+        transform_ctx.experiment = Some(assignment.experiment.clone());
+    }
+
+    // Aliased models (MODEL_ROUTES) try the cheapest healthy backend first and fail over upward;
+    // a header override pins the backend
+    let candidates = match app.routes.as_ref().filter(|_| !pinned_backend).and_then(|routes| routes.plan(&cr.model, &app.backends)) {
+        Some(plan) if !plan.is_empty() => {
+            log::info!(
+                "🧭 Routing {} across [{}]",
+                cr.model,
+                plan.iter().map(|(b, m)| format!("{}:{}", b.name, m)).collect::<Vec<_>>().join(", ")
+            );
+            plan.into_iter().map(|(backend, model)| (backend, Some(model))).collect()
+        }
+        _ => vec![(backend, None)],
+    };
+
+    let has_client_auth = client_key.is_some();
+    log::info!(
+        "📨 Request: model={}, client_auth={}, client={}, ip={}, backend={}",
+        cr.model, has_client_auth, client_info, client_ip, candidates[0].0.url
+    );
+
+    // Fault injection (CHAOS_ENABLED): latency and 5xx bursts replace or delay the backend call
+    let chaos = app.chaos.as_ref().map(|chaos| chaos.faults(&headers));
+    if let Some(faults) = chaos.as_ref().filter(|f| !f.latency.is_zero()) {
+        log::warn!("🐒 Chaos: delaying backend request by {:?}", faults.latency);
+        tokio::time::sleep(faults.latency).await;
+    }
+    let mut injected_error = chaos.as_ref().zip(app.chaos.as_ref()).and_then(|(faults, chaos)| chaos.injected_error(faults));
+
+    // A sampled share of requests is mirrored to SHADOW_BACKEND (SHADOW_PERCENT, SHADOW_MAX_CONCURRENT)
+    let shadow = app.shadow.clone().and_then(|shadow| shadow.sample().map(|permit| (shadow, permit)));
+    let shadow = shadow.map(|(shadow, permit)| {
+        log::debug!("👥 Mirroring request to shadow backend '{}'", shadow.backend);
+        let mut shadow_ctx = TransformContext::new(format!("{}_shadow", message_id), cr.model.clone());
+        shadow_ctx.betas = transform_ctx.betas.clone();
+        tokio::spawn(shadow_request(app.clone(), shadow.clone(), permit, headers.clone(), cr.clone(), owner.clone(), shadow_ctx));
+        shadow
+    });
+
+    // Logprobs and annotations for evaluation harnesses, as `proxy_debug` events (x-proxy-debug)
+    let debug_sidecar = DebugSidecar::from_headers(&headers);
+
+    let original_message_count = cr.messages.len();
+    let mut pending = Some(cr);
+    let mut candidates = candidates.into_iter().peekable();
+    let (backend, conversion, follow_up_req, sent, started, injected) = loop {
+        let (backend, routed_model) = candidates.next().expect("at least one backend candidate");
+        let has_fallback = candidates.peek().is_some();
+        let mut cr = if has_fallback { pending.clone() } else { pending.take() }.expect("request kept for every candidate");
+        if let Some(model) = routed_model {
+            cr.model = model;
+        }
+        let mut conversion = convert_request(&app, &backend, cr, &mut transform_ctx).await?;
+        conversion.notices.extend(compaction_notice.clone());
+        if let Some(dropped) = debug_sidecar.and_then(|debug| debug.request(&mut conversion.oai, &backend.options.unsupported_params)) {
+            log::debug!("🔬 Backend '{}' doesn't support logprobs - not asking for them", backend.name);
+            conversion.dropped.push(dropped);
+        }
+        let (req, fallback_req) = backend_request(&app, &backend, &headers, &conversion.oai, client_key.as_deref(), &owner)?;
+        let follow_up_req = req.try_clone();
+
+        log::debug!("🚀 Sending request to backend '{}' with {} messages", backend.name, conversion.oai.messages.len());
+        let started = Instant::now();
+        // A fault injected on the client's request says nothing about the backend's health
+        let injected = injected_error.is_some();
+        let send = async {
+            let sent = match injected_error.take() {
+                Some(res) => Ok(res),
+                None => json_body(req, &conversion.oai, backend.options.request_gzip_min_bytes).send().await,
+            };
+            match (sent, fallback_req) {
+                (Ok(res), Some(retry)) => retry_without_streaming(res, retry, &mut conversion.oai, &backend).await,
+                (sent, _) => sent,
+            }
+        };
+        // The first-token deadline starts with the request, so a backend still loading the model
+        // before it sends headers runs into it too
+        let sent = match app.config.timeouts.first_token {
+            Some(limit) => match tokio::time::timeout(limit, send).await {
+                Ok(sent) => sent.map_err(BackendSendError::from),
+                Err(_) => Err(BackendSendError::Timeout(BackendTimeout::FirstToken)),
+            },
+            None => send.await.map_err(BackendSendError::from),
+        };
+        if let Err(BackendSendError::Timeout(timeout)) = &sent {
+            app.stats.record_timeout(&backend.name, *timeout);
+            log::info!(target: "metrics", "backend_timeout: phase={}, backend={}", timeout.as_str(), backend.name);
+        }
+        if let Ok(res) = &sent {
+            app.notifier.record_backend_latency(&backend.name, started.elapsed());
+            let recorded = diagnostic_headers(res.headers());
+            app.stats.record_backend_response(&message_id, &backend.name, res.status().as_u16(), recorded.clone());
+            transform_ctx.backend_headers = recorded;
+        }
+
+        let failed = match &sent {
+            Ok(res) => is_failover_status(res.status()).then(|| res.status().to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(routes) = app.routes.as_ref().filter(|_| !injected) {
+            match &failed {
+                None => routes.record_success(&backend.name, started.elapsed()),
+                Some(_) => routes.record_failure(&backend.name),
+            }
+        }
+        match failed {
+            Some(reason) if has_fallback => log::warn!("🧭 Backend '{}' failed ({}) - failing over to the next route", backend.name, reason),
+            _ => break (backend, conversion, follow_up_req, sent, started, injected),
+        }
+    };
+    if let Some(shadow) = &shadow {
+        shadow.record_primary(sent.as_ref().ok().filter(|res| res.status().is_success()).map(|_| started.elapsed()));
+    }
+    let Conversion { oai, backend_model, thinking_budget, stop_scanner, tool_scanner, server_tool_specs, tool_ids, notices, dropped } = conversion;
+    let backend_model_for_metrics = backend_model.clone();
+    let backend_model_for_error = backend_model;
+
+    let res = sent.map_err(|e| {
+        log::error!("❌ Backend request failed: {}", e);
+        app.stats.record_error(&message_id, &oai.model, None, &format!("backend request failed: {}", e));
+        // Record circuit breaker failure
+        tokio::spawn({
+            let app = app.clone();
+            async move {
+                app.record_backend_failure().await;
+            }
+        });
+        match e {
+            BackendSendError::Timeout(timeout) => (StatusCode::GATEWAY_TIMEOUT, timeout.code()),
+            BackendSendError::Failed(_) => (StatusCode::BAD_GATEWAY, "backend_unavailable"),
+        }
+    })?;
+
+    let status = res.status();
+    log::debug!("📥 Backend response status: {}", status);
+
+    // Validate Content-Type for better error messages
+    let content_type = res.headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
     log::debug!("📥 Backend Content-Type: {}", content_type);
 
     // Warn if unexpected content type (but don't fail - be permissive)
@@ -537,16 +1259,25 @@ pub async fn messages(
     }
 
     if !status.is_success() {
-        // Record circuit breaker failure
-        tokio::spawn({
-            let cb = app.circuit_breaker.clone();
-            async move {
-                cb.write().await.record_failure();
-            }
-        });
+        // Only errors the backend caused count against the circuit breaker, not a 4xx for a bad
+        // request or a fault injected through `x-proxy-chaos`
+        if is_failover_status(status) && !injected {
+            tokio::spawn({
+                let app = app.clone();
+                async move {
+                    app.record_backend_failure().await;
+                }
+            });
+        }
 
-        // Read error response body
+        // Read error response body (keeping headers such as Retry-After for the client)
+        let backend_response_headers = res.headers().clone();
         let error_body = res.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        app.stats.record_error(&message_id, &oai.model, Some(status.as_u16()), &error_body);
+        transform_ctx.extensions.insert(ErrorRecorded);
+        if budget_exhausted(status.as_u16(), &error_body) {
+            app.notifier.notify(OpsEvent::BudgetExhausted { backend: backend.name.clone(), status: status.as_u16() });
+        }
 
         log::error!(
             "❌ Backend returned error: {} {} - {}",
@@ -555,68 +1286,36 @@ pub async fn messages(
             error_body
         );
 
-        // If 404, return synthetic Claude-like SSE with model list
+        // If 404, return synthetic Claude-like SSE with model list, or a structured 404 (MODEL_NOT_FOUND=error)
         if status == StatusCode::NOT_FOUND {
             let models = get_available_models(&app).await;
+            if app.config.model_not_found == ModelNotFound::Error || wants_json {
+                log::info!("💡 Model '{}' not found - returning 404 with {} available model(s)", backend_model_for_error, models.len());
+                return Err(ApiError::model_not_found(&backend_model_for_error, model_entries(&models)));
+            }
             if !models.is_empty() {
                 log::info!("💡 Model '{}' not found - sending model list to user", backend_model_for_error);
 
-                let (tx, rx) = tokio::sync::mpsc::channel::<Event>(SSE_CHANNEL_BUFFER_SIZE);
+                let (event_tx, rx) = tokio::sync::mpsc::channel::<Event>(SSE_CHANNEL_BUFFER_SIZE);
+                let mut sse = ClaudeSseEmitter::new(EventSender::new(event_tx, app.transforms.clone(), transform_ctx));
                 let requested_model = backend_model_for_error.clone();
-                let model_name_for_response = backend_model_for_error.clone();
                 let models_for_task = models.clone();
+                let templates = app.templates.clone();
 
                 tokio::spawn(async move {
                     log::debug!(
                         "🎬 Synthetic 404 response task started for model: {}",
                         requested_model
                     );
-                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-
-                    let message_obj = serde_json::json!({
-                        "id": format!("msg_{}", now),
-                        "type": "message",
-                        "role": "assistant",
-                        "content": serde_json::json!([]),  // Explicitly create empty array
-                        "model": model_name_for_response,
-                        "stop_reason": Value::Null,
-                        "stop_sequence": Value::Null,
-                        "usage": { "input_tokens": input_token_count, "output_tokens": 0 }
-                    });
-                    let start = json!({
-                        "type": "message_start",
-                        "message": message_obj
-                    });
-                    let _ = tx.send(Event::default().event("message_start").data(start.to_string())).await;
-
-                    let block_start = json!({
-                        "type": "content_block_start",
-                        "index": 0,
-                        "content_block": { "type": "text", "text": "" }
-                    });
-                    let _ = tx.send(Event::default().event("content_block_start").data(block_start.to_string())).await;
-
-                    let content = build_model_list_content(&requested_model, &models_for_task);
-
-                    let delta = json!({
-                        "type": "content_block_delta",
-                        "index": 0,
-                        "delta": { "type": "text_delta", "text": content }
-                    });
-                    let _ = tx.send(Event::default().event("content_block_delta").data(delta.to_string())).await;
-
-                    let block_stop = json!({ "type": "content_block_stop", "index": 0 });
-                    let _ = tx.send(Event::default().event("content_block_stop").data(block_stop.to_string())).await;
-
-                    let msg_delta = json!({
-                        "type": "message_delta",
-                        "delta": { "stop_reason": "end_turn", "stop_sequence": Value::Null },
-                        "usage": { "output_tokens": 50 }
-                    });
-                    let _ = tx.send(Event::default().event("message_delta").data(msg_delta.to_string())).await;
-
-                    let msg_stop = json!({ "type": "message_stop" });
-                    let _ = tx.send(Event::default().event("message_stop").data(msg_stop.to_string())).await;
+                    let content = build_model_list_content(&requested_model, &models_for_task, &templates);
+                    let _ = sse.text_message(&requested_model, input_token_count, &content, "end_turn", 50).await;
+                    sse.complete(&CompletionSummary {
+                        stop_reason: "end_turn".into(),
+                        input_tokens: input_token_count,
+                        output_tokens: 50,
+                        fatal_error: false,
+                        stream_errors: Default::default(),
+                    }).await;
                     log::debug!("🏁 Synthetic 404 response completed");
                 });
 
@@ -625,7 +1324,7 @@ pub async fn messages(
                 headers.insert("connection", "keep-alive".parse().unwrap());
                 headers.insert("x-accel-buffering", "no".parse().unwrap());
                 let stream = ReceiverStream::new(rx).map(Ok::<Event, Infallible>);
-                return Ok((headers, Sse::new(stream)));
+                return Ok((headers, Sse::new(stream)).into_response());
             }
         }
 
@@ -639,60 +1338,30 @@ pub async fn messages(
             StatusCode::GATEWAY_TIMEOUT  // 504
         ) {
             log::info!("⚠️  Returning retryable error status {} for automatic retry", status);
-            return Err((status, "backend_error_retryable"));
+            return Err(ApiError::from_backend(status, "backend_error_retryable", &backend_response_headers, &error_body, app.config.error_verbosity));
+        }
+
+        // A client that asked for JSON gets the error as JSON
+        if wants_json {
+            return Err(ApiError::from_backend(status, "backend_error", &backend_response_headers, &error_body, app.config.error_verbosity));
         }
 
         // For non-retryable errors (auth, bad request), return formatted SSE message
-        let (tx, rx) = tokio::sync::mpsc::channel::<Event>(64);
-        let error_msg = format_backend_error(&error_body, &error_body);
+        let (event_tx, rx) = tokio::sync::mpsc::channel::<Event>(SSE_CHANNEL_BUFFER_SIZE);
+        let mut sse = ClaudeSseEmitter::new(EventSender::new(event_tx, app.transforms.clone(), transform_ctx));
+        let error_msg = format_backend_error(&backend_error_message(&error_body).unwrap_or_else(|| error_body.trim().to_string()), &error_body, app.config.error_verbosity, &app.templates);
         let model_name = backend_model_for_error.clone();
 
         tokio::spawn(async move {
             log::debug!("🎬 Synthetic error response task started");
-            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-
-            let message_obj = serde_json::json!({
-                "id": format!("msg_{}", now),
-                "type": "message",
-                "role": "assistant",
-                "content": serde_json::json!([]),  // Explicitly create empty array
-                "model": model_name,
-                "stop_reason": Value::Null,
-                "stop_sequence": Value::Null,
-                "usage": { "input_tokens": input_token_count, "output_tokens": 0 }
-            });
-            let start = json!({
-                "type": "message_start",
-                "message": message_obj
-            });
-            let _ = tx.send(Event::default().event("message_start").data(start.to_string())).await;
-
-            let block_start = json!({
-                "type": "content_block_start",
-                "index": 0,
-                "content_block": { "type": "text", "text": "" }
-            });
-            let _ = tx.send(Event::default().event("content_block_start").data(block_start.to_string())).await;
-
-            let delta = json!({
-                "type": "content_block_delta",
-                "index": 0,
-                "delta": { "type": "text_delta", "text": error_msg }
-            });
-            let _ = tx.send(Event::default().event("content_block_delta").data(delta.to_string())).await;
-
-            let block_stop = json!({ "type": "content_block_stop", "index": 0 });
-            let _ = tx.send(Event::default().event("content_block_stop").data(block_stop.to_string())).await;
-
-            let msg_delta = json!({
-                "type": "message_delta",
-                "delta": { "stop_reason": "error", "stop_sequence": Value::Null },
-                "usage": { "output_tokens": 0 }
-            });
-            let _ = tx.send(Event::default().event("message_delta").data(msg_delta.to_string())).await;
-
-            let msg_stop = json!({ "type": "message_stop" });
-            let _ = tx.send(Event::default().event("message_stop").data(msg_stop.to_string())).await;
+            let _ = sse.text_message(&model_name, input_token_count, &error_msg, "error", 0).await;
+            sse.complete(&CompletionSummary {
+                stop_reason: "error".into(),
+                input_tokens: input_token_count,
+                output_tokens: 0,
+                fatal_error: true,
+                stream_errors: Default::default(),
+            }).await;
             log::debug!("🏁 Synthetic error response completed");
         });
 
@@ -701,593 +1370,507 @@ pub async fn messages(
         headers.insert("connection", "keep-alive".parse().unwrap());
         headers.insert("x-accel-buffering", "no".parse().unwrap());
         let stream = ReceiverStream::new(rx).map(Ok::<Event, Infallible>);
-        return Ok((headers, Sse::new(stream)));
+        return Ok((headers, Sse::new(stream)).into_response());
     }
 
     log::info!("✅ Backend responded successfully ({})", status);
 
-    let (tx, rx) = tokio::sync::mpsc::channel::<Event>(64);
+    // Answers cut off at max_tokens are resumed with follow-up requests (AUTO_CONTINUE_TOKENS)
+    let continuation = Continuation::new(&oai, app.config.auto_continue_tokens, app.config.prefill_mode)
+        .filter(|_| tool_scanner.is_none());
+    let continuation_req = continuation.as_ref().and_then(|_| follow_up_req.as_ref()?.try_clone());
+    // Server tool calls are run here and their results sent back in follow-up requests
+    let server_tools = ServerToolSession::new(server_tool_specs, app.config.server_tools.clone(), app.client.clone(), &oai);
+    let emulated_tools = tool_scanner.is_some();
+    let server_tools_req = server_tools.as_ref().and_then(|_| follow_up_req.as_ref()?.try_clone());
+    let fold_tool_results = backend.options.tool_results_as_text;
+    let gzip_min_bytes = backend.options.request_gzip_min_bytes;
+    let strict_tools: HashSet<String> = oai.tools.iter().flatten()
+        .filter(|t| t.function.strict == Some(true))
+        .map(|t| t.function.name.clone())
+        .collect();
+
+    // A buffered completion arrives in one piece: pace it out like a stream unless STREAM_SPLIT_BYTES is set
+    let paced_split = is_json_body(&res).then_some(SplitConfig {
+        max_bytes: SIMULATED_STREAM_SPLIT_BYTES,
+        delay: Duration::from_millis(DEFAULT_STREAM_SPLIT_DELAY_MS),
+    });
+
+    let (event_tx, rx) = tokio::sync::mpsc::channel::<Event>(SSE_CHANNEL_BUFFER_SIZE);
+    let tx = EventSender::new(event_tx, app.transforms.clone(), transform_ctx)
+        .with_coalescing(app.config.stream_coalesce)
+        .with_splitting(app.config.stream_split.or(paced_split))
+        .with_thinking_output(app.config.thinking_output)
+        .with_replay(app.stream_resume.as_ref().filter(|_| !owner.is_empty()).map(|resume| resume.register(&message_id, &owner)));
+    let mut translator = StreamTranslator::new(ClaudeSseEmitter::new(tx), tool_ids)
+        .with_stop_scanner(stop_scanner)
+        .with_moderation(app.moderation.as_ref())
+        .with_strict_tools(strict_tools)
+        .with_debug_sidecar(debug_sidecar)
+        .with_thinking_budget(thinking_budget)
+        .with_tool_scanner(tool_scanner)
+        .with_continuation(continuation)
+        .with_server_tools(server_tools)
+        .with_extra_choices(app.config.extra_choices)
+        .with_content_filter(app.config.content_filter_stop_reason, app.config.content_filter_notice.clone())
+        .with_error_format(app.config.error_verbosity, app.templates.clone());
 
     // Per-request ephemeral state for re-chunking.
     let model_for_header = oai.model.clone();
+    let backend_tee = app.stream_tee.clone().filter(|t| t.tees_backend());
+    let backend_name = backend.name.clone();
+    let sse_buffer = app.config.sse_buffer;
+    let timeouts = app.config.timeouts;
+    let templates = app.templates.clone();
+    let mut first_token_deadline = timeouts.first_token.map(|limit| started + limit);
 
     tokio::spawn(async move {
         log::debug!("🎬 Streaming task started");
-
-        // Emit Claude "message_start" - ensure content is always an array
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-        let message_obj = serde_json::json!({
-            "id": format!("msg_{now}"),
-            "type": "message",
-            "role": "assistant",
-            "content": serde_json::json!([]),  // Explicitly create empty array
-            "model": model_for_header,
-            "stop_reason": serde_json::Value::Null,
-            "stop_sequence": serde_json::Value::Null,
-            "usage": {
-                "input_tokens": input_token_count,
-                "output_tokens": 0
-            }
-        });
-
-        let start = json!({
-            "type": "message_start",
-            "message": message_obj
-        });
+        let _in_flight = in_flight;
+        let _admission = admission;
+        let _key_stream = key_stream;
 
         // If we can't send message_start, client is gone - no point continuing
-        if tx.send(Event::default().event("message_start").data(start.to_string())).await.is_err() {
+        if translator.sse.message_start(&model_for_header, input_token_count).await.is_err() {
             log::debug!("🔌 Client disconnected before message_start - aborting stream");
+            translator.fail();
+            let summary = translator.summary(input_token_count);
+            translator.sse.complete(&summary).await;
             return;
         }
+        for notice in &notices {
+            translator.notice(notice).await;
+        }
+        translator.report_dropped(&dropped).await;
 
-        let mut bytes_stream = res.bytes_stream();
-
-        // Block indexing
-        let mut next_block_index: i32 = 0;
-        let mut thinking_open = false;
-        let mut thinking_index: i32 = -1;
-        let mut text_open = false;
-        let mut text_index: i32 = -1;
-
-        let mut tools: ToolsMap = HashMap::new();
-
-        let mut sse_parser = SseEventParser::new();
-        let mut done = false;
-        let mut final_stop_reason = "end_turn"; // Default, will be updated if backend provides finish_reason
-        let mut fatal_error = false;
-
-        // Track output tokens
-        let mut output_token_count: u32 = 0;
+        let mut bytes_stream = match (&chaos, &app.chaos) {
+            (Some(faults), Some(state)) => faults.wrap_stream(backend_event_stream(res), state),
+            _ => backend_event_stream(res),
+        };
+        let mut sse_parser = SseEventParser::with_limit(sse_buffer);
 
         log::debug!("🌊 Begin processing SSE from backend");
-        while let Some(item) = bytes_stream.next().await {
-            let chunk = match item {
-                Ok(chunk) => chunk,
-                Err(_) => {
-                    log::debug!("❌ Error reading chunk from stream");
-                    break;
-                }
-            };
-
-            for payload in sse_parser.push_and_drain_events(&chunk) {
-                let data = payload.trim();
-                if data == "[DONE]" {
-                    log::debug!("🏁 Received [DONE] marker from backend");
-                    done = true;
-                    break;
-                }
-                if data.is_empty() {
-                    continue;
-                }
-
-                // First, try to parse as generic JSON to understand the structure
-                // Optimization: Parse directly into OAIStreamChunk first to avoid double parsing
-                let parsed: serde_json::Result<OAIStreamChunk> = serde_json::from_str(data);
-
-                let chunk = match parsed {
-                    Ok(c) => c,
-                    Err(e) => {
-                        // Only if strict parsing fails, try generic Value to inspect error structure
-                        // or log the failure with more context
-                        let json_value: serde_json::Result<Value> = serde_json::from_str(data);
-
-                        if let Ok(val) = json_value {
-                            // Check if it's an error response
-                            if let Some(error_obj) = val.get("error") {
-                                let error_msg = error_obj.get("message")
-                                    .or_else(|| error_obj.get("type"))
-                                    .and_then(|v| v.as_str())
-                                    .unwrap_or("Unknown error");
-                                let error_details = if error_msg.is_empty() {
-                                    serde_json::to_string(error_obj).unwrap_or_else(|_| "Unknown backend error".into())
-                                } else {
-                                    error_msg.to_string()
-                                };
-
-                                log::warn!("⚠️  Backend returned error in chunk: {}", error_details);
-
-                                // Close any open text block before emitting the error
-                                if text_open {
-                                    let stop = json!({"type":"content_block_stop","index":text_index});
-                                    if tx.send(Event::default().event("content_block_stop").data(stop.to_string())).await.is_err() {
-                                        log::debug!("🔌 Client disconnected during error block close");
-                                        break;
-                                    }
-                                    text_open = false;
-                                }
-
-                                // Emit error message to the client as a text block
-                                let error_index = next_block_index;
-                                next_block_index += 1;
-
-                                let start = json!({
-                                    "type":"content_block_start",
-                                    "index":error_index,
-                                    "content_block":{"type":"text","text":""}
-                                });
-                                if tx.send(Event::default().event("content_block_start").data(start.to_string())).await.is_err() {
-                                    log::debug!("🔌 Client disconnected during error start");
-                                    break;
-                                }
-
-                                // Format structured error message
-                                let formatted_error = format_backend_error(&error_details, data);
-
-                                let delta = json!({
-                                    "type":"content_block_delta",
-                                    "index":error_index,
-                                    "delta":{"type":"text_delta","text":formatted_error}
-                                });
-                                if tx.send(Event::default().event("content_block_delta").data(delta.to_string())).await.is_err() {
-                                    log::debug!("🔌 Client disconnected during error delta");
-                                    break;
-                                }
-
-                                let stop = json!({
-                                    "type":"content_block_stop",
-                                    "index":error_index
-                                });
-                                let _ = tx
-                                    .send(Event::default().event("content_block_stop").data(stop.to_string()))
-                                    .await;
-
-                                final_stop_reason = "error";
-                                done = true;
-                                fatal_error = true;
-                                break;
-                            }
-
-                            // Check if it's a valid JSON object but missing required fields
-                            if val.is_object() {
-                                let preview = if data.len() > 500 {
-                                    format!("{}...", &data[..500])
-                                } else {
-                                    data.to_string()
-                                };
-                                log::warn!("⚠️  Chunk missing 'choices' field ({} chars), structure: {}", data.len(), preview);
-                                continue;
-                            }
+        loop {
+            // With delta coalescing, don't hold buffered text back while the backend is quiet
+            let item = match translator.sse.flush_deadline() {
+                Some(deadline) => match tokio::time::timeout_at(deadline.into(), bytes_stream.next()).await {
+                    Ok(item) => item,
+                    Err(_) => {
+                        if translator.sse.flush().await.is_err() {
+                            log::debug!("🔌 Client disconnected during coalesced delta flush");
+                            translator.fail();
+                            break;
                         }
-
-                        // Malformed JSON or unexpected format
-                        let preview = if data.len() > 500 {
-                            format!("{}...", &data[..500])
-                        } else {
-                            data.to_string()
-                        };
-                        log::warn!("⚠️  JSON parse failed ({} chars): {}\nResponse preview: {}", data.len(), e, preview);
                         continue;
                     }
-                };
-
-                // Handle error responses in parsed chunk
-                if let Some(error_val) = &chunk.error {
-                    let error_msg = error_val
-                        .get("message")
-                        .or_else(|| error_val.get("type"))
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("Unknown error");
-                    let error_details = if error_msg.is_empty() {
-                        serde_json::to_string(error_val).unwrap_or_else(|_| "Unknown backend error".into())
-                    } else {
-                        error_msg.to_string()
-                    };
-
-                    log::warn!("⚠️  Backend returned error: {}", error_details);
-
-                    // Close any open text block before emitting the error
-                    if text_open {
-                        let stop = json!({"type":"content_block_stop","index":text_index});
-                        if tx.send(Event::default().event("content_block_stop").data(stop.to_string())).await.is_err() {
-                            log::debug!("🔌 Client disconnected during chunk error block close");
+                },
+                None => match first_token_deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline.into(), bytes_stream.next()).await {
+                        Ok(item) => item,
+                        Err(_) => {
+                            log::warn!("⏱️  No event from backend '{}' within {:?} (BACKEND_FIRST_TOKEN_TIMEOUT_SECS)", backend_name, timeouts.first_token.unwrap_or_default());
+                            app.stats.record_timeout(&backend_name, BackendTimeout::FirstToken);
+                            log::info!(target: "metrics", "backend_timeout: phase=first_token, backend={}", backend_name);
+                            translator.record_stream_error(StreamErrorKind::StallTimeout);
+                            let secs = timeouts.first_token.unwrap_or_default().as_secs().to_string();
+                            let notice = render(&app.templates.first_token_timeout, &[("code", BackendTimeout::FirstToken.code()), ("secs", &secs)]);
+                            let _ = translator.error_block(&notice).await;
                             break;
                         }
-                        text_open = false;
-                    }
-
-                    // Emit error message to the client as a text block
-                    let error_index = next_block_index;
-                    next_block_index += 1;
-
-                    let start = json!({
-                        "type":"content_block_start",
-                        "index":error_index,
-                        "content_block":{"type":"text","text":""}
-                    });
-                    if tx.send(Event::default().event("content_block_start").data(start.to_string())).await.is_err() {
-                        log::debug!("🔌 Client disconnected during chunk error start");
-                        break;
-                    }
-
-                                // Format structured error message
-                                let formatted_error = format_backend_error(&error_details, data);
-
-                                let delta = json!({
-                                    "type":"content_block_delta",
-                                    "index":error_index,
-                                    "delta":{"type":"text_delta","text":formatted_error}
-                                });
-                    if tx.send(Event::default().event("content_block_delta").data(delta.to_string())).await.is_err() {
-                        log::debug!("🔌 Client disconnected during chunk error delta");
-                        break;
+                    },
+                    None => bytes_stream.next().await,
+                },
+            };
+            let (chunk, exhausted) = match item {
+                Some(Ok(chunk)) => (chunk, false),
+                Some(Err(e)) => {
+                    let kind = if e.is_timeout() { StreamErrorKind::StallTimeout } else { StreamErrorKind::ConnectionReset };
+                    log::warn!("❌ Error reading backend stream ({}): {}", kind.as_str(), e);
+                    translator.record_stream_error(kind);
+                    // Nothing bounds a stream's reads but the client's total timeout
+                    if e.is_timeout() {
+                        app.stats.record_timeout(&backend_name, BackendTimeout::Total);
+                        log::info!(target: "metrics", "backend_timeout: phase=total, backend={}", backend_name);
+                        let secs = timeouts.total.as_secs().to_string();
+                        let notice = render(&app.templates.total_timeout, &[("code", BackendTimeout::Total.code()), ("secs", &secs)]);
+                        let _ = translator.error_block(&notice).await;
                     }
-
-                    let stop = json!({
-                        "type":"content_block_stop",
-                        "index":error_index
-                    });
-                    let _ = tx
-                        .send(Event::default().event("content_block_stop").data(stop.to_string()))
-                        .await;
-
-                    final_stop_reason = "error";
-                    done = true;
-                    fatal_error = true;
                     break;
                 }
+                // Backend closed the stream without `[DONE]`: ends the round like `[DONE]` below
+                None => (bytes::Bytes::new(), true),
+            };
 
-                if chunk.choices.is_empty() {
-                    log::debug!("⚠️  Chunk has no choices, skipping");
-                    continue;
-                }
-
-                let choice = &chunk.choices[0];
-
-                // Capture finish_reason if provided
-                if let Some(reason) = &choice.finish_reason {
-                    final_stop_reason = translate_finish_reason(Some(reason));
-                    log::debug!("📍 Backend finish_reason: {} → Claude stop_reason: {}", reason, final_stop_reason);
-                }
+            let events = sse_parser.push_and_drain(&chunk);
+            if !events.is_empty() {
+                first_token_deadline = None;
+            }
+            // Backpressure: stop reading from the backend until the parser's buffer fits the global limit
+            let wanted = STREAM_MEMORY_BASE_BYTES.max(sse_parser.buffered_bytes());
+            if !stream_memory.resize_or_wait(wanted, Duration::from_millis(STREAM_MEMORY_WAIT_MS)).await {
+                log::warn!("🧱 Streaming memory limit reached mid-stream ({} bytes buffered) - ending stream", wanted);
+                app.stream_memory.record_shed();
+                translator.fail();
+                break;
+            }
 
-                // Handle non-streaming complete response (fallback)
-                if let Some(message) = &choice.message {
-                    log::debug!("📦 Received non-streaming complete response, converting to SSE");
-                    if let Some(content_str) = message.get("content").and_then(|v| v.as_str()) {
-                        if !text_open {
-                            text_index = next_block_index;
-                            let ev = json!({
-                                "type":"content_block_start",
-                                "index":text_index,
-                                "content_block":{"type":"text","text":""}
-                            });
-                            let _ = tx
-                                .send(Event::default().event("content_block_start").data(ev.to_string()))
-                                .await;
-                            text_open = true;
-                        }
-                        let ev = json!({
-                            "type":"content_block_delta",
-                            "index":text_index,
-                            "delta":{"type":"text_delta","text":content_str}
-                        });
-                        let _ = tx
-                            .send(Event::default().event("content_block_delta").data(ev.to_string()))
-                            .await;
-                    }
-                    continue;
+            for event in events {
+                if let Some(tee) = &backend_tee {
+                    tee.record_backend(translator.sse.message_id(), event.data.trim());
                 }
-
-                // Handle streaming delta response
-                let Some(d) = &choice.delta else {
-                    log::debug!("⚠️  Chunk has no delta or message, skipping");
-                    continue;
-                };
-
-                // Check if backend provides usage statistics (more accurate than our approximation)
-                if let Some(usage) = &chunk.usage {
-                    if let Some(prompt_tokens) = usage.prompt_tokens {
-                        log::debug!("📊 Backend reported prompt tokens: {}", prompt_tokens);
-                    }
-                    if let Some(total_tokens) = usage.total_tokens {
-                        // total_tokens is most accurate - always prefer it
-                        output_token_count = total_tokens;
-                        log::debug!("📊 Backend reported total tokens: {}", total_tokens);
-                    } else if let Some(completion_tokens) = usage.completion_tokens {
-                        // Use completion_tokens as fallback if total_tokens not available
-                        // This is more accurate than our streaming approximation
-                        output_token_count = completion_tokens;
-                        log::debug!("📊 Backend reported completion tokens: {}", completion_tokens);
-                    }
+                // Send errors only surface when nothing keeps the response going without the
+                // client; the rest of the generation is unwanted, so stop reading it
+                if translator.handle_event(&event).await.is_err() {
+                    log::debug!("🔌 Client disconnected while translating a chunk");
+                    translator.fail();
+                    break;
                 }
-
-                // Reasoning/thinking content - stream as proper thinking blocks
-                if let Some(r) = &d.reasoning_content {
-                    if !r.is_empty() {
-                        if !thinking_open {
-                            thinking_index = next_block_index;
-                            next_block_index += 1;
-                            let ev = json!({
-                                "type":"content_block_start",
-                                "index":thinking_index,
-                                "content_block":{"type":"thinking","thinking":""}
-                            });
-                            let _ = tx
-                                .send(Event::default().event("content_block_start").data(ev.to_string()))
-                                .await;
-                            thinking_open = true;
-                            log::info!("🧠 OUTPUT: Opened thinking block (index={})", thinking_index);
-                        }
-                        let ev = json!({
-                            "type":"content_block_delta",
-                            "index":thinking_index,
-                            "delta":{"type":"thinking_delta","thinking":r}
-                        });
-                        let _ = tx
-                            .send(Event::default().event("content_block_delta").data(ev.to_string()))
-                            .await;
-                        log::debug!("🧠 OUTPUT: Streamed thinking delta ({} chars)", r.len());
-
-                        // Count reasoning tokens (approximate)
-                        let reasoning_tokens = std::cmp::max(1, r.len() / CHARS_PER_TOKEN) as u32;
-                        output_token_count += reasoning_tokens;
-                    }
+                if translator.done {
+                    break;
                 }
+            }
 
-                // Text deltas
-                if let Some(c) = &d.content {
-                    if !c.is_empty() {
-                        // Close thinking block if still open (thinking comes before text)
-                        if thinking_open {
-                            let ev = json!({ "type":"content_block_stop", "index":thinking_index });
-                            let _ = tx
-                                .send(Event::default().event("content_block_stop").data(ev.to_string()))
-                                .await;
-                            thinking_open = false;
-                            log::info!("🧠 OUTPUT: Closed thinking block before text (index={})", thinking_index);
-                        }
+            for _ in 0..sse_parser.take_limit_hits() {
+                translator.record_stream_error(StreamErrorKind::BufferLimit);
+            }
+            if sse_parser.aborted() && !translator.fatal_error {
+                let kb = (sse_buffer.limit_bytes / 1024).to_string();
+                let notice = render(&app.templates.sse_buffer_limit, &[("kb", &kb)]);
+                let _ = translator.error_block(&notice).await;
+            }
 
-                        if !text_open {
-                            text_index = next_block_index;
-                            next_block_index += 1;
-                            let ev = json!({
-                                "type":"content_block_start",
-                                "index":text_index,
-                                "content_block":{"type":"text","text":""}
-                            });
-                            let _ = tx
-                                .send(Event::default().event("content_block_start").data(ev.to_string()))
-                                .await;
-                            text_open = true;
-                        }
-                        let ev = json!({
-                            "type":"content_block_delta",
-                            "index":text_index,
-                            "delta":{"type":"text_delta","text":c}
-                        });
-                        let _ = tx
-                            .send(Event::default().event("content_block_delta").data(ev.to_string()))
-                            .await;
+            if translator.fatal_error {
+                break;
+            }
 
-                        // Count text tokens (approximate)
-                        let text_tokens = std::cmp::max(1, c.len() / CHARS_PER_TOKEN) as u32;
-                        output_token_count += text_tokens;
+            // A stream that closes without `[DONE]` or a finish_reason was cut off, not finished
+            if exhausted && !translator.done {
+                let parser = std::mem::replace(&mut sse_parser, SseEventParser::with_limit(sse_buffer));
+                if let Some(event) = parser.flush() {
+                    if translator.handle_event(&event).await.is_err() {
+                        log::debug!("🔌 Client disconnected while translating a chunk");
+                        translator.fail();
+                        break;
                     }
                 }
+                if !translator.done && !translator.finished {
+                    log::warn!("❌ Backend '{}' closed the stream before it finished", backend_name);
+                    translator.record_stream_error(StreamErrorKind::ConnectionReset);
+                    let _ = translator.error_block(&app.templates.stream_dropped).await;
+                    break;
+                }
+            }
 
-                // Tool call deltas
-                if let Some(tool_calls) = &d.tool_calls {
-                    if !tool_calls.is_empty() {
-                        // Close text block if open
-                        if text_open {
-                            let ev = json!({"type":"content_block_stop","index":text_index});
-                            let _ = tx
-                                .send(Event::default().event("content_block_stop").data(ev.to_string()))
-                                .await;
-                            text_open = false;
-                        }
-
-                        for tc in tool_calls {
-                            let idx = tc.index.unwrap_or(0);
-                            
-                            // Initialize tool buffer if not present
-                            let tb = tools.entry(idx).or_insert_with(|| {
-                                ToolBuf {
-                                    block_index: next_block_index,
-                                    id: None,
-                                    name: None,
-                                    pending_args: String::new(),
-                                    has_sent_start: false,
-                                }
-                            });
-
-                            // Update fields from delta
-                            if let Some(id) = &tc.id {
-                                tb.id = Some(id.clone());
-                            }
-                            if let Some(name) = tc.function.as_ref().and_then(|f| f.name.clone()) {
-                                tb.name = Some(name);
-                            }
-
-                            // Capture arguments in buffer first
-                            if let Some(args) = tc.function.as_ref().and_then(|f| f.arguments.clone()) {
-                                tb.pending_args.push_str(&args);
-                            }
-
-                            // Check if we can start the block (need ID and Name)
-                            // Only increment next_block_index ONCE when we actually start the block
-                            if !tb.has_sent_start && tb.id.is_some() && tb.name.is_some() {
-                                // Assign the block index now
-                                tb.block_index = next_block_index;
-                                next_block_index += 1;
-                                
-                                let start = json!({
-                                    "type":"content_block_start",
-                                    "index":tb.block_index,
-                                    "content_block":{
-                                        "type":"tool_use",
-                                        "id":tb.id.as_ref().unwrap(),
-                                        "name":tb.name.as_ref().unwrap(),
-                                        "input":{}
-                                    }
-                                });
-                                if tx.send(Event::default().event("content_block_start").data(start.to_string())).await.is_err() {
-                                    log::debug!("🔌 Client disconnected during tool start");
-                                    break;
-                                }
-                                tb.has_sent_start = true;
-                                log::info!("🔧 Tool call started: id={}, name={}", tb.id.as_ref().unwrap(), tb.name.as_ref().unwrap());
-                            }
-
-                            // If started, flush pending args and stream
-                            if tb.has_sent_start && !tb.pending_args.is_empty() {
-                                let ev = json!({
-                                    "type":"content_block_delta",
-                                    "index": tb.block_index,
-                                    "delta":{"type":"input_json_delta","partial_json": tb.pending_args}
-                                });
-                                if tx.send(Event::default().event("content_block_delta").data(ev.to_string())).await.is_err() {
-                                    log::debug!("🔌 Client disconnected during tool args");
-                                    break;
-                                }
-                                tb.pending_args.clear();
+            if translator.done || exhausted {
+                let truncated = translator.truncated();
+                let mut next = resume_truncated(translator.continuation.as_mut(), continuation_req.as_ref(), truncated, gzip_min_bytes).await;
+                if next.is_none() {
+                    if let Some(outputs) = translator.run_server_tools().await {
+                        // Client tool calls in the same turn go to the client first
+                        if !translator.has_tool_calls() {
+                            next = resume_after_server_tools(translator.server_tools.as_mut(), server_tools_req.as_ref(), &outputs, emulated_tools, fold_tool_results, gzip_min_bytes).await;
+                            if next.is_none() {
+                                translator.stop_reason = "pause_turn";
                             }
                         }
                     }
                 }
-            }
-
-            if fatal_error {
-                break;
-            }
-
-            if done {
+                if let Some(next) = next {
+                    bytes_stream = next;
+                    sse_parser = SseEventParser::with_limit(sse_buffer);
+                    translator.next_round();
+                    continue;
+                }
                 break;
             }
         }
 
         // Flush any trailing event if backend didn't send final blank line
-        if !done {
-            if let Some(payload) = sse_parser.flush() {
-                let data = payload.trim();
-                if data != "[DONE]" && !data.is_empty() {
-                    if let Ok(chunk) = serde_json::from_str::<OAIStreamChunk>(data) {
-                        if let Some(c) = chunk.choices.get(0).and_then(|ch| ch.delta.as_ref()).and_then(|d| d.content.as_ref()) {
-                            if !c.is_empty() {
-                                if !text_open {
-                                    text_index = next_block_index;
-                                    let ev = json!({
-                                        "type":"content_block_start",
-                                        "index":text_index,
-                                        "content_block":{"type":"text","text":""}
-                                    });
-                                    let _ = tx
-                                        .send(Event::default().event("content_block_start").data(ev.to_string()))
-                                        .await;
-                                    text_open = true;
-                                }
-                                let ev = json!({
-                                    "type":"content_block_delta",
-                                    "index":text_index,
-                                    "delta":{"type":"text_delta","text":c}
-                                });
-                                let _ = tx
-                                    .send(Event::default().event("content_block_delta").data(ev.to_string()))
-                                    .await;
-                            }
-                        }
-                    }
-                }
+        if !translator.done {
+            if let Some(event) = sse_parser.flush() {
+                let _ = translator.handle_event(&event).await;
             }
         }
 
-        // Close any open blocks and finish message
-        if thinking_open {
-            let ev = json!({ "type":"content_block_stop", "index":thinking_index });
-            let _ = tx
-                .send(Event::default().event("content_block_stop").data(ev.to_string()))
-                .await;
-            log::info!("🧠 OUTPUT: Closed thinking block at end (index={})", thinking_index);
-        }
-        if text_open {
-            let ev = json!({ "type":"content_block_stop", "index":text_index });
-            let _ = tx
-                .send(Event::default().event("content_block_stop").data(ev.to_string()))
-                .await;
-        }
-        for tb in tools.values() {
-            let stop = json!({ "type":"content_block_stop", "index":tb.block_index });
-            let _ = tx
-                .send(Event::default().event("content_block_stop").data(stop.to_string()))
-                .await;
+        let summary = translator.finish(input_token_count).await;
+        for (kind, count) in &summary.stream_errors {
+            log::info!(target: "metrics", "stream_error: backend={}, model={}, kind={}, count={}", backend_name, model_for_header, kind.as_str(), count);
         }
+        app.stats.record_stream_errors(&backend_name, &summary.stream_errors);
+        log::debug!("🏁 Streaming task completed");
+        translator.sse.complete(&summary).await;
 
-        let md = json!({
-            "type":"message_delta",
-            "delta":{"stop_reason":final_stop_reason,"stop_sequence":null},
-            "usage":{"output_tokens":output_token_count}
-        });
-        // Critical: if these final events fail, stream is incomplete - but log it
-        if tx.send(Event::default().event("message_delta").data(md.to_string())).await.is_err() {
-            log::debug!("🔌 Client disconnected before message_delta");
+        // A proxy-matched stop sequence means the rest of the generation is unwanted:
+        // dropping the stream cancels the backend request
+        if translator.matched_stop().is_some() {
+            drop(bytes_stream);
+            tokio::spawn(async move {
+                app.record_backend_success().await;
+            });
             return;
         }
 
-        if tx.send(Event::default().event("message_stop").data(json!({"type":"message_stop"}).to_string())).await.is_err() {
-            log::debug!("🔌 Client disconnected before message_stop");
+        // After a failed translation the rest of the generation is useless: abort it
+        if summary.fatal_error {
+            log::debug!("✂️  Aborting backend request after stream error");
+            drop(bytes_stream);
             return;
         }
 
-        log::debug!("🏁 Streaming task completed");
-
-        // Drain any remaining bytes from backend stream to avoid cancelling the request
-        // This ensures the backend doesn't see a connection reset/cancellation
+        // Drain the remaining bytes (usually just `[DONE]` or a usage chunk) so the backend doesn't
+        // see a cancellation, but don't let a backend that keeps streaming pin this task
         log::debug!("🔄 Draining remaining backend stream...");
-        let mut drained_bytes = 0;
-        while let Some(item) = bytes_stream.next().await {
-            if let Ok(chunk) = item {
-                drained_bytes += chunk.len();
+        match drain_with_budget(
+            &mut bytes_stream,
+            STREAM_DRAIN_MAX_BYTES,
+            Duration::from_secs(STREAM_DRAIN_TIMEOUT_SECS),
+        )
+        .await
+        {
+            DrainOutcome::Finished { bytes: 0 } => log::debug!("✅ Backend stream was already fully consumed"),
+            DrainOutcome::Finished { bytes } => log::debug!("🔄 Drained {} additional bytes from backend stream", bytes),
+            DrainOutcome::BudgetExceeded { bytes } => {
+                log::warn!("✂️  Backend kept streaming after the response finished ({} bytes drained); aborting request", bytes);
+                drop(bytes_stream);
             }
         }
-        if drained_bytes > 0 {
-            log::debug!("🔄 Drained {} additional bytes from backend stream", drained_bytes);
-        } else {
-            log::debug!("✅ Backend stream was already fully consumed");
-        }
 
-        // Record circuit breaker success if no fatal error
-        if !fatal_error {
-            let cb_clone = app.circuit_breaker.clone();
-            tokio::spawn(async move {
-                cb_clone.write().await.record_success();
-            });
-        }
+        tokio::spawn(async move {
+            app.record_backend_success().await;
+        });
     });
 
     let mut out_headers = HeaderMap::new();
     out_headers.insert("cache-control", "no-cache".parse().unwrap());
     out_headers.insert("connection", "keep-alive".parse().unwrap());
     out_headers.insert("x-accel-buffering", "no".parse().unwrap());
+    // Which backend served the request; MODEL_ROUTES may have picked one of several
+    if let Ok(name) = backend.name.parse() {
+        out_headers.insert("x-proxy-backend", name);
+    }
+    if let Some(Ok(value)) = experiment.map(|a| format!("{}={}", a.experiment, a.model).parse()) {
+        out_headers.insert("x-proxy-experiment", value);
+    }
 
     let stream = ReceiverStream::new(rx).map(Ok::<Event, Infallible>);
 
     // Log structured metrics
     if let Ok(elapsed) = request_start.elapsed() {
         log::info!(target: "metrics",
-            "request_completed: model={}, duration_ms={}, messages={}, status=success",
-            backend_model_for_metrics, elapsed.as_millis(), original_message_count
+            "request_completed: model={}, client={}, duration_ms={}, messages={}, status=success",
+            backend_model_for_metrics, client_info, elapsed.as_millis(), original_message_count
+        );
+    }
+
+    let response = (out_headers, Sse::new(stream)).into_response();
+    if wants_json {
+        return collect_message(response, &templates).await;
+    }
+    Ok(response)
+}
+
+/// A message the proxy writes itself in place of a moderated request, ending with `stop_reason: "refusal"`
+fn refusal_response(app: &App, transform_ctx: TransformContext, model: &str, input_tokens: u32, text: &str) -> Response {
+    let (event_tx, rx) = tokio::sync::mpsc::channel::<Event>(SSE_CHANNEL_BUFFER_SIZE);
+    let mut sse = ClaudeSseEmitter::new(EventSender::new(event_tx, app.transforms.clone(), transform_ctx));
+    let (model, text) = (model.to_string(), text.to_string());
+    tokio::spawn(async move {
+        let _ = sse.text_message(&model, input_tokens, &text, "refusal", 0).await;
+        sse.complete(&CompletionSummary {
+            stop_reason: "refusal".into(),
+            input_tokens,
+            output_tokens: 0,
+            fatal_error: false,
+            stream_errors: Default::default(),
+        })
+        .await;
+    });
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cache-control", "no-cache".parse().unwrap());
+    headers.insert("connection", "keep-alive".parse().unwrap());
+    headers.insert("x-accel-buffering", "no".parse().unwrap());
+    let stream = ReceiverStream::new(rx).map(Ok::<Event, Infallible>);
+    (headers, Sse::new(stream)).into_response()
+}
+
+/// Buffer the proxy's own event stream into a single `message` object for a client that
+/// negotiated JSON; a response that ended in an error becomes an Anthropic error
+async fn collect_message(response: Response, templates: &MessageTemplates) -> Result<Response, ApiError> {
+    let (mut parts, body) = response.into_parts();
+    // The proxy's own events, which may legitimately be large: no buffer limit
+    let mut parser = SseEventParser::with_limit(SseBufferLimit { limit_bytes: usize::MAX, ..Default::default() });
+    let mut collector = MessageCollector::default();
+    let mut chunks = body.into_data_stream();
+    while let Some(Ok(chunk)) = chunks.next().await {
+        for event in parser.push_and_drain(&chunk) {
+            collector.push(&event);
+        }
+    }
+    let message = collector.finish(templates).map_err(|message| ApiError::with_message(StatusCode::BAD_GATEWAY, "backend_stream_error", &message))?;
+    // Only the event-stream framing headers go; x-proxy-* and the like stay
+    for name in ["cache-control", "connection", "x-accel-buffering", "content-type"] {
+        parts.headers.remove(name);
+    }
+    Ok((parts.headers, Json(message)).into_response())
+}
+
+/// A finished message by id, for clients that lost the stream (`MESSAGE_STORE_DIR`); only the
+/// client key that requested it can read it
+pub async fn get_message(
+    State(app): State<App>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path(message_id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let store = app.message_store.as_deref().ok_or((StatusCode::NOT_FOUND, "message_store_disabled"))?;
+    // Messages are only stored for a client key, and only that key reads them back
+    let Some(owner) = app.config.client_auth.client_key(&headers, &uri).as_deref().map(key_fingerprint) else {
+        return Err((StatusCode::UNAUTHORIZED, "missing_api_key").into());
+    };
+    match store.get(&message_id, &owner).await {
+        Some(message) => Ok(Json(message)),
+        None => Err(ApiError::with_message(StatusCode::NOT_FOUND, "message_not_found", &format!("message_id: {}", message_id))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Router};
+    use crate::models::app::tests::test_app;
+
+    /// Backend streaming `chunks` as SSE `data:` events and then closing the connection
+    async fn streaming_backend(chunks: &'static [&'static str]) -> String {
+        let app = Router::new().route(
+            "/v1/chat/completions",
+            post(move || async move {
+                let body: String = chunks.iter().map(|c| format!("data: {}\n\n", c)).collect();
+                ([("content-type", "text/event-stream")], body)
+            }),
         );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}/v1/chat/completions", addr)
     }
 
-    Ok((out_headers, Sse::new(stream)))
+    async fn stream_request(app: App) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("cpk_test"));
+        let cr = json!({"model": "m", "max_tokens": 50, "stream": true, "messages": [{"role": "user", "content": "Hi"}]});
+        let cr: ClaudeRequest = serde_json::from_value(cr).unwrap();
+        messages(State(app), headers, Uri::from_static("/v1/messages"), ClientIp([127, 0, 0, 1].into()), axum::Json(cr)).await.unwrap()
+    }
+
+    async fn stream_events(app: App) -> Vec<(String, Value)> {
+        let response = stream_request(app).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        SseEventParser::new()
+            .push_and_drain(&body)
+            .into_iter()
+            .filter_map(|event| Some((event.event?, serde_json::from_str(&event.data).ok()?)))
+            .collect()
+    }
+
+    fn stop_reason(events: &[(String, Value)]) -> &str {
+        events.iter().find(|(name, _)| name == "message_delta").and_then(|(_, data)| data["delta"]["stop_reason"].as_str()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stream_closed_without_finish_reason_ends_with_error() {
+        let url = streaming_backend(&[r#"{"choices":[{"index":0,"delta":{"content":"Hel"}}]}"#]).await;
+        let events = stream_events(test_app(url)).await;
+        let text: String = events.iter().filter_map(|(_, data)| data["delta"]["text"].as_str()).collect();
+        assert!(text.starts_with("Hel") && text.contains("stream_dropped"), "{}", text);
+        assert_eq!(stop_reason(&events), "error");
+
+        // A finish_reason without `[DONE]` is a complete answer
+        let url = streaming_backend(&[r#"{"choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":"stop"}]}"#]).await;
+        let events = stream_events(test_app(url)).await;
+        assert_eq!(stop_reason(&events), "end_turn");
+    }
+
+    /// Sets its flag when dropped, i.e. once the backend stops serving the body that owns it
+    struct DropFlag(Arc<std::sync::atomic::AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_disconnect_stops_backend_read() {
+        let stopped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = stopped.clone();
+        let backend = Router::new().route(
+            "/v1/chat/completions",
+            post(move || {
+                let guard = DropFlag(flag.clone());
+                async move {
+                    // Text deltas that never end
+                    let chunks = futures::stream::unfold(guard, |guard| async move {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        let chunk = bytes::Bytes::from_static(b"data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"more \"}}]}\n\n");
+                        Some((Ok::<_, Infallible>(chunk), guard))
+                    });
+                    ([("content-type", "text/event-stream")], axum::body::Body::from_stream(chunks))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, backend).await });
+
+        // Leave once the text is streaming
+        let response = stream_request(test_app(format!("http://{}/v1/chat/completions", addr))).await;
+        let mut body = response.into_body().into_data_stream();
+        let mut received = Vec::new();
+        while !String::from_utf8_lossy(&received).contains("text_delta") {
+            received.extend_from_slice(&body.next().await.unwrap().unwrap());
+        }
+        drop(body);
+        let backend_released = async {
+            while !stopped.load(std::sync::atomic::Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        assert!(tokio::time::timeout(Duration::from_secs(5), backend_released).await.is_ok(), "backend still being read after the client left");
+    }
+
+    #[tokio::test]
+    async fn test_mixed_tool_history_keeps_results_after_their_calls() {
+        let app = test_app("http://127.0.0.1:9/v1/chat/completions".into());
+        let cr: ClaudeRequest = serde_json::from_value(json!({
+            "model": "m",
+            "max_tokens": 100,
+            "tools": [{"name": "read_file", "input_schema": {"type": "object", "properties": {"path": {"type": "string"}}}}],
+            "messages": [
+                {"role": "user", "content": "Find the docs and read a.rs"},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_a", "name": "read_file", "input": {"path": "a.rs"}},
+                    {"type": "server_tool_use", "id": "srvtoolu_s", "name": "web_search", "input": {"query": "tokio"}},
+                    {"type": "web_search_tool_result", "tool_use_id": "srvtoolu_s", "content": []}
+                ]},
+                {"role": "user", "content": [{"type": "tool_result", "tool_use_id": "toolu_a", "content": "fn main() {}"}]}
+            ]
+        }))
+        .unwrap();
+
+        let roles = |oai: &OAIChatReq| oai.messages.iter().map(|m| m.role.clone()).collect::<Vec<_>>();
+        let mut ctx = TransformContext::new("msg_test".into(), cr.model.clone());
+        let oai = convert_request(&app, app.backends.default_backend(), cr.clone(), &mut ctx).await.unwrap().oai;
+        assert_eq!(roles(&oai), ["user", "assistant", "tool", "assistant", "tool"]);
+        assert_eq!(oai.messages[1].tool_calls.as_ref().unwrap()[0]["function"]["name"], "web_search");
+        assert_eq!(oai.messages[3].tool_calls.as_ref().unwrap()[0]["id"], "toolu_a");
+        assert_eq!(oai.messages[4].tool_call_id.as_deref(), Some("toolu_a"));
+
+        let mut backend = app.backends.default_backend().clone();
+        backend.options.tool_emulation = true;
+        let oai = convert_request(&app, &backend, cr, &mut ctx).await.unwrap().oai;
+        assert_eq!(roles(&oai), ["system", "user", "assistant", "user", "assistant", "user"]);
+        assert!(oai.messages[4].content.as_str().unwrap().contains("\"tool\":\"read_file\""));
+        assert!(oai.messages[5].content.as_str().unwrap().starts_with("[Result of tool call toolu_a]"));
+    }
 }
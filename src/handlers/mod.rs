@@ -1,7 +1,15 @@
+pub mod admin;
+pub mod compare;
+pub mod debug;
+pub mod error;
+pub mod files;
 pub mod health;
 pub mod messages;
+pub mod models;
 pub mod token_count;
+pub mod validate;
 
-pub use health::health_check;
+pub use error::ApiError;
+pub use health::{health_check, liveness, readiness};
 pub use messages::messages;
 pub use token_count::count_tokens;
\ No newline at end of file
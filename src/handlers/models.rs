@@ -0,0 +1,65 @@
+use axum::{extract::State, response::Json};
+use serde_json::{json, Value};
+use crate::models::{App, ModelInfo};
+use crate::services::{classify_models, get_available_models};
+
+/// `GET /v1/models`: the backend's models in the Anthropic list shape, annotated with the same
+/// classification as the model-not-found message (reasoning vs standard, price tier)
+pub async fn list(State(app): State<App>) -> Json<Value> {
+    let models = get_available_models(&app).await;
+    // Presets first, so they head Claude Code's model picker
+    let mut data = app.presets.as_ref().map(|presets| presets.model_entries()).unwrap_or_default();
+    data.extend(model_entries(&models));
+    Json(json!({
+        "data": data,
+        "has_more": false,
+        "first_id": data.first().map(|m| m["id"].clone()),
+        "last_id": data.last().map(|m| m["id"].clone()),
+    }))
+}
+
+/// `models` as listed by `/v1/models`: reasoning models first, then standard ones
+pub fn model_entries(models: &[ModelInfo]) -> Vec<Value> {
+    let (reasoning, standard) = classify_models(models);
+    reasoning.iter().chain(&standard).map(|model| model_entry(model)).collect()
+}
+
+/// One model as listed by `/v1/models` and in `available_models` of model-not-found errors
+pub fn model_entry(model: &ModelInfo) -> Value {
+    json!({
+        "type": "model",
+        "id": model.id,
+        "display_name": model.id,
+        "category": if model.is_reasoning() { "reasoning" } else { "standard" },
+        "features": model.supported_features,
+        "price_tier": model.price_tier(),
+        "pricing": {
+            "input_usd_per_mtok": model.input_price_usd,
+            "output_usd_per_mtok": model.output_price_usd,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_entry() {
+        let model = ModelInfo {
+            id: "deepseek-ai/DeepSeek-R1".into(),
+            input_price_usd: Some(0.5),
+            output_price_usd: Some(2.0),
+            supported_features: vec!["tools".into(), "reasoning".into()],
+        };
+        let entry = model_entry(&model);
+        assert_eq!(entry["category"], "reasoning");
+        assert_eq!(entry["price_tier"], "affordable");
+        assert_eq!(entry["features"], json!(["tools", "reasoning"]));
+
+        let unpriced = ModelInfo { input_price_usd: None, output_price_usd: None, supported_features: Vec::new(), ..model };
+        let entry = model_entry(&unpriced);
+        assert_eq!(entry["category"], "standard");
+        assert!(entry["price_tier"].is_null());
+    }
+}
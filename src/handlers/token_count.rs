@@ -1,86 +1,174 @@
 use axum::{
     extract::State,
-    http::StatusCode,
-    response::Result,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response, Result},
 };
+use std::sync::OnceLock;
+use serde::Serialize;
 use serde_json::{json, Value};
+use tiktoken_rs::CoreBPE;
 use crate::constants::*;
-use crate::models::{App, ClaudeTokenCountRequest};
+use crate::handlers::ApiError;
+use crate::models::{App, ClaudeMessage, ClaudeTokenCountRequest, ClaudeTool};
+use crate::services::{negotiate_version, COUNT_TOKENS_API_VERSIONS, VERSION_HEADER};
+use crate::utils::audio::audio_tokens_in_content;
+use crate::utils::content_extraction::{content_parts, PartKind};
+use crate::utils::image::image_source_tokens;
+
+/// Input tokens of a request by component, returned as `proxy_token_breakdown` by `count_tokens`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TokenBreakdown {
+    pub system: usize,
+    /// Message text, with role prefixes
+    pub messages: usize,
+    pub thinking: usize,
+    /// Tool call ids, names and inputs in the history
+    pub tool_use: usize,
+    pub tool_results: usize,
+    /// Tool names, descriptions and schemas
+    pub tool_definitions: usize,
+    pub images: usize,
+    pub audio: usize,
+}
+
+impl TokenBreakdown {
+    pub fn total(&self) -> usize {
+        self.system + self.messages + self.thinking + self.tool_use + self.tool_results + self.tool_definitions + self.images + self.audio
+    }
+}
 
 /// Count tokens using tiktoken (cl100k_base encoding baseline)
 pub async fn count_tokens(
     State(_app): State<App>,
+    headers: HeaderMap,
     axum::Json(req): axum::Json<ClaudeTokenCountRequest>,
-) -> Result<axum::Json<Value>, (StatusCode, &'static str)> {
-    let mut text_parts = Vec::new();
-    let mut image_count = 0;
+) -> Result<Response, ApiError> {
+    let version = negotiate_version(&headers, COUNT_TOKENS_API_VERSIONS)
+        .map_err(|e| ApiError::with_message(StatusCode::BAD_REQUEST, "unsupported_anthropic_version", &e))?;
+    let breakdown = tokio::task::spawn_blocking(move || token_breakdown(&req.messages, &req.system, &req.tools))
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "tokenization_failed"))?;
+
+    let body = json!({ "input_tokens": breakdown.total(), "proxy_token_breakdown": breakdown });
+    Ok(([(VERSION_HEADER, version)], axum::Json(body)).into_response())
+}
+
+/// `token_breakdown` on the blocking thread pool, for handlers that count long histories
+pub async fn token_breakdown_blocking(messages: &[ClaudeMessage], system: &Option<Value>, tools: &Option<Vec<ClaudeTool>>) -> TokenBreakdown {
+    let (messages, system, tools) = (messages.to_vec(), system.clone(), tools.clone());
+    tokio::task::spawn_blocking(move || token_breakdown(&messages, &system, &tools))
+        .await
+        .unwrap_or_else(|e| {
+            log::warn!("Token counting failed: {}", e);
+            TokenBreakdown::default()
+        })
+}
+
+/// The cl100k_base encoder, built once; `None` when it can't be loaded
+static ENCODER: OnceLock<Option<CoreBPE>> = OnceLock::new();
+
+/// Input tokens of a Claude request: system prompt, messages, and tool definitions (blocking)
+pub fn count_request_tokens(req: &ClaudeTokenCountRequest) -> usize {
+    token_breakdown(&req.messages, &req.system, &req.tools).total()
+}
+
+/// Input tokens of a Claude request by component (blocking)
+pub fn token_breakdown(messages: &[ClaudeMessage], system: &Option<Value>, tools: &Option<Vec<ClaudeTool>>) -> TokenBreakdown {
+    let encoder = ENCODER.get_or_init(|| {
+        tiktoken_rs::cl100k_base()
+            .map_err(|e| log::warn!("Failed to initialize tiktoken: {}, falling back to estimation", e))
+            .ok()
+    });
+    let count = |texts: &[String]| {
+        if texts.is_empty() {
+            return 0;
+        }
+        let text = texts.join("\n");
+        match &encoder {
+            Some(encoder) => encoder.encode_with_special_tokens(&text).len(),
+            None => std::cmp::max(1, text.len() / CHARS_PER_TOKEN),
+        }
+    };
 
-    if let Some(sys) = &req.system {
-        let sys_text = if sys.is_string() {
-            sys.as_str().unwrap_or("").to_string()
-        } else if let Some(blocks) = sys.as_array() {
+    let mut system_text = Vec::new();
+    match system {
+        Some(Value::String(sys)) => system_text.push(sys.clone()),
+        Some(Value::Array(blocks)) => system_text.extend(
             blocks
                 .iter()
-                .filter_map(|block| {
-                    block
-                        .as_object()
-                        .and_then(|obj| {
-                            if obj.get("type").and_then(|t| t.as_str()) == Some("text") {
-                                obj.get("text").and_then(|t| t.as_str())
-                            } else {
-                                None
-                            }
-                        })
-                })
-                .collect::<Vec<_>>()
-                .join("\n")
-        } else {
-            String::new()
-        };
-        if !sys_text.is_empty() {
-            text_parts.push(sys_text);
-        }
+                .filter(|block| block.get("type").and_then(|t| t.as_str()) == Some("text"))
+                .filter_map(|block| block.get("text").and_then(|t| t.as_str()).map(str::to_string)),
+        ),
+        _ => {}
     }
 
-    for msg in &req.messages {
-        let (msg_text, msg_image_count) = crate::utils::content_extraction::extract_text_from_content(&msg.content);
+    let (mut text, mut thinking, mut tool_use, mut tool_results) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let mut breakdown = TokenBreakdown::default();
+    for msg in messages {
+        let parts = content_parts(&msg.content);
+        let mut msg_text = Vec::new();
+        for (kind, part) in parts.texts {
+            match kind {
+                PartKind::Text => msg_text.push(part),
+                PartKind::Thinking => thinking.push(part),
+                PartKind::ToolUse => tool_use.push(part),
+                PartKind::ToolResult => tool_results.push(part),
+            }
+        }
         if !msg_text.is_empty() {
-            text_parts.push(format!("{}: {}", msg.role, msg_text));
+            text.push(format!("{}: {}", msg.role, msg_text.join("\n")));
         }
-        image_count += msg_image_count;
+        breakdown.images += parts.images.into_iter().map(image_source_tokens).sum::<usize>();
+        breakdown.audio += audio_tokens_in_content(&msg.content);
     }
 
-    if let Some(tools) = &req.tools {
-        for tool in tools {
-            text_parts.push(tool.name.clone());
-            if let Some(desc) = &tool.description {
-                text_parts.push(desc.clone());
-            }
-            if let Ok(schema_str) = serde_json::to_string(&tool.input_schema) {
-                text_parts.push(schema_str);
-            }
+    let mut tool_definitions = Vec::new();
+    for tool in tools.iter().flatten() {
+        tool_definitions.push(tool.name.clone());
+        if let Some(desc) = &tool.description {
+            tool_definitions.push(desc.clone());
+        }
+        if let Ok(schema_str) = serde_json::to_string(&tool.input_schema) {
+            tool_definitions.push(schema_str);
         }
     }
 
-    let combined_text = text_parts.join("\n");
+    breakdown.system = count(&system_text);
+    breakdown.messages = count(&text);
+    breakdown.thinking = count(&thinking);
+    breakdown.tool_use = count(&tool_use);
+    breakdown.tool_results = count(&tool_results);
+    breakdown.tool_definitions = count(&tool_definitions);
+    breakdown
+}
 
-    let token_count = tokio::task::spawn_blocking(move || {
-        match tiktoken_rs::cl100k_base() {
-            Ok(encoder) => {
-                let text_tokens = encoder.encode_with_special_tokens(&combined_text).len();
-                let image_tokens = image_count * TOKENS_PER_IMAGE;
-                text_tokens + image_tokens
-            }
-            Err(e) => {
-                log::warn!("Failed to initialize tiktoken: {}, falling back to estimation", e);
-                let text_estimate = std::cmp::max(1, combined_text.len() / CHARS_PER_TOKEN);
-                let image_tokens = image_count * TOKENS_PER_IMAGE;
-                text_estimate + image_tokens
-            }
-        }
-    })
-    .await
-    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "tokenization_failed"))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(axum::Json(json!({ "input_tokens": token_count })))
-}
\ No newline at end of file
+    #[test]
+    fn test_breakdown_counts_history_components() {
+        let messages: Vec<ClaudeMessage> = serde_json::from_value(json!([
+            {"role": "user", "content": "Read a.rs"},
+            {"role": "assistant", "content": [
+                {"type": "thinking", "thinking": "The user wants the file read, so I will call the read tool.", "signature": "s"},
+                {"type": "tool_use", "id": "toolu_01", "name": "read", "input": {"path": "a.rs"}}
+            ]},
+            {"role": "user", "content": [{"type": "tool_result", "tool_use_id": "toolu_01", "content": [
+                {"type": "text", "text": "fn main() {}"},
+                {"type": "image", "source": {"type": "url", "url": "https://example.com/a.png"}}
+            ]}]}
+        ]))
+        .unwrap();
+        let breakdown = token_breakdown(&messages, &Some(json!([{"type": "text", "text": "Be brief."}])), &None);
+        assert!(breakdown.system > 0 && breakdown.messages > 0);
+        assert!(breakdown.thinking > 10, "thinking text is counted");
+        assert!(breakdown.tool_use > 0 && breakdown.tool_results > 0);
+        assert_eq!(breakdown.images, TOKENS_PER_IMAGE);
+        assert_eq!((breakdown.tool_definitions, breakdown.audio), (0, 0));
+        assert_eq!(
+            breakdown.total(),
+            breakdown.system + breakdown.messages + breakdown.thinking + breakdown.tool_use + breakdown.tool_results + breakdown.images
+        );
+    }
+}
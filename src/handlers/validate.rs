@@ -0,0 +1,176 @@
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::{json, Value};
+use crate::handlers::messages::{convert_request, prepare_request, PreparedRequest};
+use crate::handlers::token_count::token_breakdown_blocking;
+use crate::handlers::ApiError;
+use crate::models::{App, ClaudeRequest};
+use crate::services::{negotiate_version, MESSAGES_API_VERSIONS};
+
+/// One reason the request would be refused
+fn problem(status: StatusCode, code: &str, message: &str) -> Value {
+    json!({ "status": status.as_u16(), "code": code, "message": message })
+}
+
+fn api_problem(e: &ApiError) -> Value {
+    let message = e.body.as_ref().and_then(|body| body["error"]["message"].as_str()).unwrap_or(e.code);
+    problem(e.status, e.code, message)
+}
+
+/// Dry run of `/v1/messages`: validation, overrides, transforms, alias routing, model
+/// normalization, capability filtering and token estimation, without calling a backend.
+///
+/// Answers 200 with `"valid": true`, or the status the real request would get with `"valid":
+/// false` and every problem found under `errors`, so CI jobs can fail on the status alone.
+pub async fn validate(State(app): State<App>, headers: HeaderMap, uri: Uri, body: Bytes) -> Response {
+    let cr: ClaudeRequest = match serde_json::from_slice(&body) {
+        Ok(cr) => cr,
+        Err(e) => return report(json!({ "errors": [problem(StatusCode::BAD_REQUEST, "invalid_request", &e.to_string())] })),
+    };
+    let mut errors = Vec::new();
+
+    let version = negotiate_version(&headers, MESSAGES_API_VERSIONS)
+        .map_err(|e| errors.push(problem(StatusCode::BAD_REQUEST, "unsupported_anthropic_version", &e)))
+        .ok();
+
+    // The same preparation `/v1/messages` runs, with transforms told the request won't be sent
+    let prepared = match prepare_request(&app, &headers, &uri, cr, "msg_validate", true).await {
+        Ok(prepared) => prepared,
+        Err(e) => return report(json!({ "errors": [api_problem(&e)] })),
+    };
+    errors.extend(prepared.problems.iter().map(|invalid| problem(invalid.status, invalid.code, &invalid.detail)));
+    let PreparedRequest { mut cr, backend, pinned_backend, preset, mut transform_ctx, .. } = prepared;
+
+    let breakdown = token_breakdown_blocking(&cr.messages, &cr.system, &cr.tools).await;
+    let input_tokens = breakdown.total();
+    let compaction = app
+        .compaction
+        .as_ref()
+        .and_then(|c| c.thresholds.get(&cr.model))
+        .map(|threshold| json!({ "threshold_tokens": threshold, "would_compact": input_tokens > threshold }));
+
+    let experiment = app.experiments.as_ref().and_then(|experiments| experiments.pick(&cr, transform_ctx.key_fingerprint.as_deref().unwrap_or_default()));
+    if let Some(assignment) = &experiment {
+        cr.model = assignment.model.clone();
+    }
+
+    let routes: Vec<(_, Option<String>)> = match app.routes.as_ref().filter(|_| !pinned_backend).and_then(|routes| routes.plan(&cr.model, &app.backends)) {
+        Some(plan) if !plan.is_empty() => plan.into_iter().map(|(backend, model)| (backend, Some(model))).collect(),
+        _ => vec![(backend, None)],
+    };
+    let route_list: Vec<Value> = routes
+        .iter()
+        .map(|(backend, model)| json!({ "backend": backend.name, "model": model.as_deref().unwrap_or(&cr.model) }))
+        .collect();
+
+    let mut report_body = json!({
+        "model": cr.model,
+        "routes": route_list,
+        "input_tokens": input_tokens,
+        "token_breakdown": breakdown,
+        "max_tokens": cr.max_tokens,
+        "compaction": compaction,
+        "anthropic_version": version,
+        "betas": transform_ctx.betas,
+    });
+    if let Some(preset) = preset {
+        report_body["preset"] = json!(preset);
+    }
+    if let Some(assignment) = experiment {
+        report_body["experiment"] = json!({ "name": assignment.experiment, "arm": assignment.model });
+    }
+
+    // Conversion is only meaningful for a request that passed validation; it runs for the first route
+    if errors.is_empty() {
+        let (backend, routed_model) = routes.into_iter().next().expect("at least one backend candidate");
+        let is_default_backend = backend.name == app.backends.default_backend().name;
+        if let Some(model) = routed_model {
+            cr.model = model;
+        }
+        match convert_request(&app, &backend, cr, &mut transform_ctx).await {
+            Ok(conversion) => {
+                // Known only when the default backend's model list is cached
+                let model_known = match app.cached_models().await.filter(|_| is_default_backend) {
+                    Some(models) => json!(models.iter().any(|m| m.id.eq_ignore_ascii_case(&conversion.backend_model))),
+                    None => Value::Null,
+                };
+                report_body["backend"] = json!(backend.name);
+                report_body["backend_model"] = json!(conversion.backend_model);
+                report_body["model_known"] = model_known;
+                report_body["backend_messages"] = json!(conversion.oai.messages.len());
+                report_body["backend_tools"] = json!(conversion.oai.tools.as_ref().map_or(0, Vec::len));
+                report_body["tool_emulation"] = json!(conversion.tool_scanner.is_some());
+                report_body["server_tools"] = json!(conversion.server_tool_specs.len());
+                report_body["notices"] = json!(conversion.notices);
+                report_body["dropped_features"] = json!(conversion.dropped);
+            }
+            Err(e) => errors.push(api_problem(&e)),
+        }
+    }
+    report_body["errors"] = Value::Array(errors);
+    report(report_body)
+}
+
+/// Mark the report valid or not and answer with the status of its first problem
+fn report(mut body: Value) -> Response {
+    let status = body["errors"]
+        .get(0)
+        .and_then(|e| e["status"].as_u64())
+        .and_then(|s| StatusCode::from_u16(s as u16).ok())
+        .unwrap_or(StatusCode::OK);
+    body["valid"] = json!(status == StatusCode::OK);
+    if status != StatusCode::OK {
+        log::info!("🧪 Validation: request would be refused with {}", status);
+    }
+    (status, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::messages::{apply_default_model, validate_request};
+
+    #[test]
+    fn test_every_problem_is_reported() {
+        let cr: ClaudeRequest = serde_json::from_value(json!({"model": "m", "max_tokens": 0, "messages": []})).unwrap();
+        let codes: Vec<_> = validate_request(&cr).iter().map(|invalid| invalid.code).collect();
+        assert_eq!(codes, ["empty_messages", "invalid_max_tokens"]);
+    }
+
+    #[test]
+    fn test_blank_model_uses_default_model() {
+        let mut cr: ClaudeRequest = serde_json::from_value(json!({"model": "  ", "messages": [{"role": "user", "content": "hi"}]})).unwrap();
+        apply_default_model(&mut cr, None);
+        assert_eq!(validate_request(&cr).iter().map(|invalid| invalid.code).collect::<Vec<_>>(), ["missing_model"]);
+
+        let mut cr: ClaudeRequest = serde_json::from_value(json!({"messages": [{"role": "user", "content": "hi"}]})).unwrap();
+        apply_default_model(&mut cr, Some("qwen3:8b"));
+        assert_eq!(cr.model, "qwen3:8b");
+        assert!(validate_request(&cr).is_empty());
+
+        cr.model = " glm-4.6\n".into();
+        apply_default_model(&mut cr, Some("qwen3:8b"));
+        assert_eq!(cr.model, "glm-4.6");
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_prepares_like_messages() {
+        let app = crate::models::app::tests::test_app("http://127.0.0.1:9".into());
+        let cr: ClaudeRequest = serde_json::from_value(json!({"model": "m", "max_tokens": 0, "messages": []})).unwrap();
+        let prepared = prepare_request(&app, &HeaderMap::new(), &Uri::from_static("/v1/messages/validate"), cr, "msg_validate", true).await.unwrap();
+        assert!(prepared.transform_ctx.dry_run);
+        let codes: Vec<_> = prepared.problems.iter().map(|invalid| invalid.code).collect();
+        assert_eq!(codes, ["empty_messages", "invalid_max_tokens"]);
+    }
+
+    #[test]
+    fn test_report_status() {
+        assert_eq!(report(json!({"errors": []})).status(), StatusCode::OK);
+        let refused = report(json!({"errors": [problem(StatusCode::PAYLOAD_TOO_LARGE, "content_too_large", "")]}));
+        assert_eq!(refused.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -1,4 +1,6 @@
 use axum::{
+    extract::DefaultBodyLimit,
+    handler::Handler,
     routing::{get, post},
     Router,
 };
@@ -11,27 +13,45 @@ use std::{
 use tokio::sync::RwLock;
 
 // Import our modules
+mod bench;
+mod cli;
+mod config;
 mod constants;
 mod handlers;
 mod models;
 mod services;
 mod utils;
 
-use models::{App, CircuitBreakerState};
+use config::ProxyConfig;
+use models::{App, BackendRegistry, CircuitBreakerState};
 use services::model_cache::refresh_models_cache;
+use services::{LlmTraceExporter, Notifier, NotifierConfig, OpsEvent, StreamTee, TransformChain};
 
 #[tokio::main]
 async fn main() {
     let _ = dotenvy::dotenv();
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let command = match cli::Command::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    // Operational commands only log problems, keeping their output readable
+    let default_level = if command == cli::Command::Serve { "info" } else { "warn" };
+    services::log_level::init(default_level);
+
+    if command != cli::Command::Serve {
+        std::process::exit(cli::run(command).await);
+    }
+    serve().await;
+}
 
+async fn serve() {
     let backend_url = env::var("BACKEND_URL")
-        .unwrap_or_else(|_| "http://127.0.0.1:8000/v1/chat/completions".into());
-    let backend_timeout_secs = env::var("BACKEND_TIMEOUT_SECS")
-        .ok()
-        .and_then(|s| s.parse::<u64>().ok())
-        .unwrap_or(600);
+        .unwrap_or_else(|_| constants::DEFAULT_BACKEND_URL.into());
+    let timeouts = services::BackendTimeouts::from_env();
     let circuit_breaker_enabled = env::var("ENABLE_CIRCUIT_BREAKER")
         .ok()
         .and_then(|s| s.parse::<bool>().ok())
@@ -39,30 +59,117 @@ async fn main() {
 
     info!("🚀 Claude-to-OpenAI Proxy starting...");
     info!("   Backend URL: {}", backend_url);
-    info!("   Backend Timeout: {}s", backend_timeout_secs);
+    info!(
+        "   Backend Timeouts: connect {}s, first token {}, total {}s",
+        timeouts.connect.as_secs(),
+        timeouts.first_token.map_or("off".to_string(), |t| format!("{}s", t.as_secs())),
+        timeouts.total.as_secs()
+    );
     info!("   Circuit Breaker: {}", if circuit_breaker_enabled { "enabled" } else { "disabled" });
     info!("   Mode: Passthrough with case-correction");
 
+    let backends = BackendRegistry::new(backend_url.clone(), &config::env_list("BACKENDS"));
+    info!("   Backends: {}", backends.names().collect::<Vec<_>>().join(", "));
+
     let models_cache = Arc::new(RwLock::new(None));
     let circuit_breaker = Arc::new(RwLock::new(CircuitBreakerState::new(circuit_breaker_enabled)));
 
+    // Custom request/response transforms (register deployment-specific rewriting here)
+    let mut transforms = TransformChain::default();
+    // PII is masked before any other transform, trace or log sees the request
+    if let Some(masker) = services::PiiMasker::from_env() {
+        transforms.register(Arc::new(masker));
+    }
+    if let Ok(paths) = env::var("TRANSFORM_SCRIPTS") {
+        load_transform_scripts(&mut transforms, &paths);
+    }
+
+    let client = reqwest::Client::builder()
+        .pool_max_idle_per_host(1024)
+        .tcp_keepalive(Some(Duration::from_secs(60)))
+        .connect_timeout(timeouts.connect)
+        .timeout(timeouts.total)
+        .build()
+        .unwrap();
+
+    let notifier_config = NotifierConfig::from_env();
+    if !notifier_config.webhook_urls.is_empty() {
+        info!("   Webhooks: {} endpoint(s)", notifier_config.webhook_urls.len());
+    }
+    let notifier = Arc::new(Notifier::new(client.clone(), notifier_config));
+
+    if let Some(exporter) = LlmTraceExporter::from_env(client.clone()) {
+        transforms.register(Arc::new(exporter));
+    }
+
+    let stats = Arc::new(services::Stats::default());
+    transforms.register(stats.clone());
+
+    let message_store = services::MessageStore::from_env().map(Arc::new);
+    if let Some(store) = &message_store {
+        store.spawn_pruning();
+        transforms.register(store.clone());
+    }
+
+    // Stream tee is registered last so it records events after all other transforms
+    let stream_tee = StreamTee::from_env(client.clone()).map(Arc::new);
+    if let Some(tee) = &stream_tee {
+        transforms.register(tee.clone());
+    }
+
+    #[cfg(feature = "sqlite")]
+    let request_log = open_request_log(&mut transforms);
+    #[cfg(not(feature = "sqlite"))]
+    open_request_log(&mut transforms);
+
+    let config = Arc::new(ProxyConfig::from_env());
+    let warmup = services::Warmup::from_env(client.clone(), &backends, config.extra_headers.clone()).map(Arc::new);
+    let compaction = services::Compaction::from_env(client.clone(), &backends, config.extra_headers.clone()).map(Arc::new);
+    let routes = services::ModelRoutes::from_env(&backends).map(Arc::new);
+    let shadow = services::Shadow::from_env(&backends).map(Arc::new);
+    let moderation = services::Moderation::from_env(client.clone()).map(Arc::new);
+
     let app = App {
-        client: reqwest::Client::builder()
-            .pool_max_idle_per_host(1024)
-            .tcp_keepalive(Some(Duration::from_secs(60)))
-            .connect_timeout(Duration::from_secs(10))
-            .timeout(Duration::from_secs(backend_timeout_secs))
-            .build()
-            .unwrap(),
+        client,
         backend_url: backend_url.clone(),
+        backends: Arc::new(backends),
+        config,
         models_cache: models_cache.clone(),
+        models_cache_updated: Default::default(),
         circuit_breaker: circuit_breaker.clone(),
+        transforms: Arc::new(transforms),
+        notifier,
+        stream_tee,
+        stats,
+        stream_memory: Arc::new(services::StreamMemory::from_env()),
+        chaos: services::Chaos::from_env().map(Arc::new),
+        admission: services::Admission::from_env().map(Arc::new),
+        key_streams: services::KeyStreamLimit::from_env().map(Arc::new),
+        files: services::FileStore::from_env().await.map(Arc::new),
+        stream_resume: services::StreamResume::from_env().map(Arc::new),
+        message_store,
+        warmup,
+        compaction,
+        routes,
+        shadow,
+        experiments: services::Experiments::from_env().map(Arc::new),
+        presets: services::ModelPresets::from_env().map(Arc::new),
+        templates: Arc::new(services::MessageTemplates::from_env()),
+        moderation,
+        #[cfg(feature = "sqlite")]
+        request_log,
     };
 
     // Initial model cache load (blocking - must complete before accepting requests)
     info!("🔄 Loading initial model cache...");
     if let Err(e) = refresh_models_cache(&app).await {
         log::warn!("⚠️  Failed to load initial model cache: {}. Continuing anyway.", e);
+        app.notifier.notify(OpsEvent::ModelCacheFailure { error: e.to_string() });
+    }
+
+    // Warm-up runs in the background; /readyz reports not ready until it has finished
+    if let Some(warmup) = app.warmup.clone() {
+        tokio::spawn(async move { warmup.run("startup").await });
     }
 
     // Background model cache refresh (every 60s) with graceful shutdown
@@ -73,6 +180,7 @@ async fn main() {
             loop {
                 if let Err(e) = refresh_models_cache(&app_clone).await {
                     log::warn!("Failed to refresh models cache: {}", e);
+                    app_clone.notifier.notify(OpsEvent::ModelCacheFailure { error: e.to_string() });
                 }
                 
                 tokio::select! {
@@ -88,33 +196,112 @@ async fn main() {
         })
     };
 
-    let router = Router::new()
+    let upload_limit = app.files.as_ref().map_or(0, |files| files.max_bytes) + constants::FILES_MULTIPART_OVERHEAD_BYTES;
+    let api = Router::new()
         .route("/health", get(handlers::health_check))
+        .route("/healthz", get(handlers::liveness))
+        .route("/readyz", get(handlers::readiness))
         .route("/v1/messages", post(handlers::messages))
         .route("/v1/messages/count_tokens", post(handlers::count_tokens))
-        .layer(axum::extract::DefaultBodyLimit::max(10 * 1024 * 1024)) // 10MB limit
-        .layer(tower_http::compression::CompressionLayer::new())
-        .with_state(app);
-
-    let port = env::var("HOST_PORT")
-        .unwrap_or_else(|_| "8080".into())
-        .parse::<u16>()
-        .unwrap_or(8080);
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
-        .await
-        .unwrap();
-    info!("   Listening on: 0.0.0.0:{}", port);
-    
-    // Graceful shutdown: use axum's built-in mechanism
-    let server = axum::serve(listener, router)
-        .with_graceful_shutdown(async {
-            tokio::signal::ctrl_c().await.ok();
-            info!("🛑 Received shutdown signal, draining connections...");
-        });
-    
-    // Run server (this will complete when graceful shutdown finishes)
-    if let Err(e) = server.await {
-        log::error!("Server error: {}", e);
+        .route("/v1/messages/validate", post(handlers::validate::validate))
+        .route("/v1/messages/:message_id", get(handlers::messages::get_message))
+        .route("/v1/experimental/compare", post(handlers::compare::compare))
+        .route("/v1/models", get(handlers::models::list))
+        // Uploads stream past the 10MB body limit to FILES_MAX_BYTES plus multipart framing
+        .route("/v1/files", get(handlers::files::list).post(handlers::files::upload.layer(DefaultBodyLimit::max(upload_limit))))
+        .route("/v1/files/:file_id", get(handlers::files::get).delete(handlers::files::delete))
+        .route("/v1/files/:file_id/content", get(handlers::files::content));
+    let admin = Router::new()
+        .route("/admin/usage", get(handlers::admin::usage))
+        .route("/admin/stats", get(handlers::admin::stats))
+        .route("/admin/events", get(handlers::admin::events))
+        .route(
+            "/admin/log-level",
+            get(handlers::admin::log_level).put(handlers::admin::set_log_level).delete(handlers::admin::reset_log_level),
+        )
+        .route("/debug/convert", post(handlers::debug::convert))
+        .route("/debug/requests/:request_id", get(handlers::debug::request))
+        .route("/dashboard", get(handlers::admin::dashboard));
+    // Compression is opt-in per content type so SSE streams are never buffered by the encoder
+    let compression = services::ResponseCompression::from_env();
+    let finish = |router: Router<App>| {
+        router
+            .layer(DefaultBodyLimit::max(10 * 1024 * 1024)) // 10MB limit
+            .layer(compression.layer())
+            .with_state(app.clone())
+    };
+
+    let (public_addrs, admin_addrs) = match (config::public_bind_addrs(), config::admin_bind_addrs()) {
+        (Ok(public), Ok(admin)) => (public, admin),
+        (Err(e), _) | (_, Err(e)) => {
+            log::error!("❌ Invalid listen address: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // With a dedicated admin listener, admin routes are not reachable on the public port
+    let (public_router, admin_router) = if admin_addrs.is_empty() {
+        (finish(api.merge(admin)), None)
+    } else {
+        let admin = admin
+            .route("/health", get(handlers::health_check))
+            .route("/healthz", get(handlers::liveness))
+            .route("/readyz", get(handlers::readiness));
+        (finish(api), Some(finish(admin)))
+    };
+
+    let mut listeners = Vec::new();
+    match services::systemd::take_listener() {
+        Ok(Some(listener)) => {
+            info!("   Listening on: {} (systemd socket)", listener.local_addr().map(|a| a.to_string()).unwrap_or_default());
+            listeners.push((listener, public_router.clone()));
+        }
+        Ok(None) => {
+            for listener in bind_listeners(&public_addrs, "API").await {
+                listeners.push((listener, public_router.clone()));
+            }
+        }
+        Err(e) => {
+            log::error!("❌ Socket passed by systemd is unusable: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(admin_router) = admin_router {
+        for listener in bind_listeners(&admin_addrs, "admin").await {
+            listeners.push((listener, admin_router.clone()));
+        }
+    }
+    let shutdown = shutdown_signal();
+    // Model cache is loaded and the sockets are bound: tell systemd (Type=notify) we're up
+    services::systemd::notify("READY=1");
+
+    // Graceful shutdown: use axum's built-in mechanism on every listener
+    let (stop_tx, stop_rx) = tokio::sync::watch::channel(false);
+    let servers: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, router)| {
+            let mut stop_rx = stop_rx.clone();
+            tokio::spawn(async move {
+                axum::serve(listener, router.into_make_service_with_connect_info::<std::net::SocketAddr>())
+                    .with_graceful_shutdown(async move {
+                        let _ = stop_rx.changed().await;
+                    })
+                    .await
+            })
+        })
+        .collect();
+
+    shutdown.await;
+    info!("🛑 Received shutdown signal, draining connections...");
+    services::systemd::notify("STOPPING=1");
+    let _ = stop_tx.send(true);
+
+    // Servers complete when graceful shutdown finishes
+    for server in servers {
+        match server.await {
+            Ok(Err(e)) => log::error!("Server error: {}", e),
+            Err(e) => log::error!("Server task failed: {}", e),
+            Ok(Ok(())) => {}
+        }
     }
     
     // After server is shut down, clean up background tasks
@@ -122,4 +309,73 @@ async fn main() {
     let _ = shutdown_tx.send(()).await;
     let _ = tokio::time::timeout(Duration::from_secs(5), cache_task).await;
     info!("✅ Shutdown complete");
-}
\ No newline at end of file
+}
+
+/// Bind every address or exit: a missing listener is a deployment error, not something to run without
+async fn bind_listeners(addrs: &[std::net::SocketAddr], label: &str) -> Vec<tokio::net::TcpListener> {
+    let mut listeners = Vec::new();
+    for addr in addrs {
+        match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => {
+                info!("   Listening on: {} ({})", addr, label);
+                listeners.push(listener);
+            }
+            Err(e) => {
+                log::error!("❌ Failed to bind {} listener on {}: {}", label, addr, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    listeners
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM (how systemd and Docker stop services).
+/// Handlers are installed on call, so signals arriving before the server is polled aren't lost.
+fn shutdown_signal() -> impl std::future::Future<Output = ()> {
+    #[cfg(unix)]
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to install SIGTERM handler");
+    let ctrl_c = tokio::signal::ctrl_c();
+    async move {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = term.recv() => {}
+        }
+        #[cfg(not(unix))]
+        ctrl_c.await.ok();
+    }
+}
+
+/// Open the SQLite request log (`REQUEST_LOG_DB`) and record completed requests into it
+#[cfg(feature = "sqlite")]
+fn open_request_log(transforms: &mut TransformChain) -> Option<Arc<services::RequestLogStore>> {
+    let store = Arc::new(services::RequestLogStore::from_env()?);
+    transforms.register(store.clone());
+    Some(store)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_request_log(_transforms: &mut TransformChain) {
+    if env::var("REQUEST_LOG_DB").is_ok_and(|p| !p.trim().is_empty()) {
+        log::warn!("⚠️  REQUEST_LOG_DB is set but the proxy was built without the 'sqlite' feature");
+    }
+}
+
+/// Load comma-separated Rhai script paths into the transform chain
+#[cfg(feature = "scripting")]
+fn load_transform_scripts(transforms: &mut TransformChain, paths: &str) {
+    for path in paths.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match services::scripting::ScriptTransform::load(path) {
+            Ok(script) => transforms.register(Arc::new(script)),
+            Err(e) => log::error!("❌ Failed to load transform script {}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn load_transform_scripts(_transforms: &mut TransformChain, paths: &str) {
+    if !paths.trim().is_empty() {
+        log::warn!("⚠️  TRANSFORM_SCRIPTS is set but the proxy was built without the 'scripting' feature");
+    }
+}
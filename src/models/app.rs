@@ -1,11 +1,15 @@
 use std::{
-    sync::Arc,
+    sync::{atomic::AtomicU64, Arc},
     time::SystemTime,
 };
 use tokio::sync::RwLock;
 use log::warn;
 use reqwest::Client;
+use serde::Serialize;
+use crate::config::ProxyConfig;
 use crate::constants::*;
+use crate::models::BackendRegistry;
+use crate::services::{Admission, Chaos, KeyStreamLimit, FileStore, Notifier, OpsEvent, Stats, StreamMemory, StreamResume, StreamTee, TransformChain, Warmup, ModelRoutes, Compaction, MessageStore, Shadow, Experiments, Moderation, ModelPresets, MessageTemplates};
 
 #[derive(Clone, Debug)]
 pub struct ModelInfo {
@@ -15,14 +19,145 @@ pub struct ModelInfo {
     pub supported_features: Vec<String>,
 }
 
+impl ModelInfo {
+    /// Listed with reasoning (extended thinking) support
+    pub fn is_reasoning(&self) -> bool {
+        self.supported_features.iter().any(|f| f.to_lowercase().contains("reasoning"))
+    }
+
+    pub fn price_tier(&self) -> Option<PriceTier> {
+        PriceTier::from_prices(self.input_price_usd, self.output_price_usd)
+    }
+}
+
+/// Rough price class of a model from its average USD price per million tokens
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceTier {
+    /// Under $1/M tokens
+    Budget,
+    /// $1–5/M tokens
+    Affordable,
+    /// $5–15/M tokens
+    Moderate,
+    /// Over $15/M tokens
+    Premium,
+}
+
+impl PriceTier {
+    /// `None` without any pricing information
+    pub fn from_prices(input_price: Option<f64>, output_price: Option<f64>) -> Option<Self> {
+        let avg_price = match (input_price, output_price) {
+            (Some(inp), Some(out)) => (inp + out) / 2.0,
+            (Some(p), None) | (None, Some(p)) => p,
+            (None, None) => return None,
+        };
+        Some(if avg_price < 1.0 {
+            PriceTier::Budget
+        } else if avg_price < 5.0 {
+            PriceTier::Affordable
+        } else if avg_price < 15.0 {
+            PriceTier::Moderate
+        } else {
+            PriceTier::Premium
+        })
+    }
+
+    pub fn emoji(self) -> &'static str {
+        match self {
+            PriceTier::Budget => "💰",
+            PriceTier::Affordable => "💵",
+            PriceTier::Moderate => "💸",
+            PriceTier::Premium => "💎",
+        }
+    }
+}
+
+/// Shared, immutable snapshot of the backend's model list; cloning only bumps a refcount
+pub type ModelList = Arc<Vec<ModelInfo>>;
+
+/// Model list cache, replaced wholesale on every refresh
+pub type ModelsCache = Arc<RwLock<Option<ModelList>>>;
+
 // ---------- App with cached models and circuit breaker ----------
 
 #[derive(Clone)]
 pub struct App {
     pub client: Client,
     pub backend_url: String,
-    pub models_cache: Arc<RwLock<Option<Vec<ModelInfo>>>>,
+    pub backends: Arc<BackendRegistry>,
+    pub config: Arc<ProxyConfig>,
+    pub models_cache: ModelsCache,
+    /// Unix seconds of the last successful model cache refresh (0 = never)
+    pub models_cache_updated: Arc<AtomicU64>,
     pub circuit_breaker: Arc<RwLock<CircuitBreakerState>>,
+    pub transforms: Arc<TransformChain>,
+    pub notifier: Arc<Notifier>,
+    pub stream_tee: Option<Arc<StreamTee>>,
+    pub stats: Arc<Stats>,
+    pub stream_memory: Arc<StreamMemory>,
+    /// Fault injection for resilience testing; `None` unless `CHAOS_ENABLED`
+    pub chaos: Option<Arc<Chaos>>,
+    /// Concurrency limit and wait queue (`MAX_CONCURRENT_REQUESTS`); `None` when disabled
+    pub admission: Option<Arc<Admission>>,
+    /// Simultaneous streams per client key (`MAX_STREAMS_PER_KEY`); `None` when disabled
+    pub key_streams: Option<Arc<KeyStreamLimit>>,
+    /// Uploads for `/v1/files` and `file_id` references (`FILES_DIR` / `FILES_S3_BUCKET`); `None` when unset
+    pub files: Option<Arc<FileStore>>,
+    /// Replay buffers for `Last-Event-ID` resume (`STREAM_RESUME_SECS`); `None` when disabled
+    pub stream_resume: Option<Arc<StreamResume>>,
+    /// Finished messages for `GET /v1/messages/{id}` (`MESSAGE_STORE_DIR`); `None` when unset
+    pub message_store: Option<Arc<MessageStore>>,
+    /// Startup and circuit-recovery warm-up of `WARMUP_MODELS`; `None` when unset
+    pub warmup: Option<Arc<Warmup>>,
+    /// Summarizing of long histories (`COMPACTION_THRESHOLD_TOKENS`); `None` when unset
+    pub compaction: Option<Arc<Compaction>>,
+    /// Cost- and health-ordered backends for aliased models (`MODEL_ROUTES`); `None` when unset
+    pub routes: Option<Arc<ModelRoutes>>,
+    /// Mirroring of requests to `SHADOW_BACKEND`; `None` when unset
+    pub shadow: Option<Arc<Shadow>>,
+    /// Weighted per-conversation model experiments (`MODEL_EXPERIMENTS`); `None` when unset
+    pub experiments: Option<Arc<Experiments>>,
+    /// Model aliases with default sampling parameters (`MODEL_PRESETS`); `None` when unset
+    pub presets: Option<Arc<ModelPresets>>,
+    /// Text of synthetic error, notice and model-list replies (`MESSAGE_TEMPLATES`)
+    pub templates: Arc<MessageTemplates>,
+    /// Request and response checks against `MODERATION_URL`; `None` when unset
+    pub moderation: Option<Arc<Moderation>>,
+    #[cfg(feature = "sqlite")]
+    pub request_log: Option<Arc<crate::services::RequestLogStore>>,
+}
+
+impl App {
+    /// Current model list without fetching; the lock is held only to clone the `Arc`
+    pub async fn cached_models(&self) -> Option<ModelList> {
+        self.models_cache.read().await.clone()
+    }
+
+    /// Record a failed backend call (circuit breaker + error rate alerting)
+    pub async fn record_backend_failure(&self) {
+        let opened = {
+            let mut cb = self.circuit_breaker.write().await;
+            cb.record_failure().then_some(cb.consecutive_failures)
+        };
+        self.notifier.record_backend_result(false);
+        if let Some(consecutive_failures) = opened {
+            self.notifier.notify(OpsEvent::CircuitOpened { consecutive_failures });
+        }
+    }
+
+    /// Record a successful backend call
+    pub async fn record_backend_success(&self) {
+        let closed = self.circuit_breaker.write().await.record_success();
+        self.notifier.record_backend_result(true);
+        if closed {
+            self.notifier.notify(OpsEvent::CircuitClosed);
+            // A recovered backend may have restarted and unloaded its models
+            if let Some(warmup) = self.warmup.clone() {
+                tokio::spawn(async move { warmup.run("circuit_recovery").await });
+            }
+        }
+    }
 }
 
 // ---------- Circuit breaker state ----------
@@ -32,6 +167,7 @@ pub struct CircuitBreakerState {
     pub consecutive_failures: u32,
     pub last_failure_time: Option<SystemTime>,
     pub is_open: bool,
+    pub half_open: bool,
     pub enabled: bool,
 }
 
@@ -41,23 +177,37 @@ impl CircuitBreakerState {
             consecutive_failures: 0,
             last_failure_time: None,
             is_open: false,
+            half_open: false,
             enabled,
         }
     }
 
-    pub fn record_success(&mut self) {
+    /// Returns true if this success closed a previously open circuit
+    pub fn record_success(&mut self) -> bool {
+        let recovered = self.enabled && (self.is_open || self.half_open);
         self.consecutive_failures = 0;
         self.is_open = false;
+        self.half_open = false;
         self.last_failure_time = None;
+        if recovered {
+            log::info!("🟢 Circuit breaker closed");
+        }
+        recovered
     }
 
-    pub fn record_failure(&mut self) {
+    /// Returns true if this failure opened the circuit
+    pub fn record_failure(&mut self) -> bool {
+        let was_open = self.is_open;
         self.consecutive_failures += 1;
         self.last_failure_time = Some(SystemTime::now());
         if self.consecutive_failures >= CIRCUIT_BREAKER_FAILURE_THRESHOLD {
             self.is_open = true;
-            warn!("🔴 Circuit breaker opened after {} consecutive failures", self.consecutive_failures);
+            self.half_open = false;
+            if !was_open {
+                warn!("🔴 Circuit breaker opened after {} consecutive failures", self.consecutive_failures);
+            }
         }
+        self.enabled && !was_open && self.is_open
     }
 
     pub fn should_allow_request(&mut self) -> bool {
@@ -73,6 +223,7 @@ impl CircuitBreakerState {
                 if elapsed.as_secs() >= 30 {
                     log::info!("🟡 Circuit breaker attempting half-open state");
                     self.is_open = false;
+                    self.half_open = true;
                     self.consecutive_failures = 0;
                     return true;
                 }
@@ -80,4 +231,87 @@ impl CircuitBreakerState {
         }
         false
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::services::NotifierConfig;
+
+    /// App with default settings and no optional features, talking to `backend_url`
+    pub(crate) fn test_app(backend_url: String) -> App {
+        let client = Client::builder().no_proxy().timeout(Duration::from_secs(10)).build().expect("http client");
+        let notifier = NotifierConfig {
+            webhook_urls: Vec::new(),
+            cooldown: Duration::ZERO,
+            error_rate_threshold: 1.0,
+            error_rate_window: Duration::from_secs(60),
+            slow_backend: Duration::from_secs(60),
+        };
+        App {
+            client: client.clone(),
+            backend_url: backend_url.clone(),
+            backends: Arc::new(BackendRegistry::new(backend_url, &[])),
+            config: Arc::new(ProxyConfig::default()),
+            models_cache: Arc::new(RwLock::new(None)),
+            models_cache_updated: Default::default(),
+            circuit_breaker: Arc::new(RwLock::new(CircuitBreakerState::new(true))),
+            transforms: Arc::new(TransformChain::default()),
+            notifier: Arc::new(Notifier::new(client, notifier)),
+            stream_tee: None,
+            stats: Arc::new(Stats::default()),
+            stream_memory: Arc::new(StreamMemory::new(0)),
+            chaos: None,
+            admission: None,
+            key_streams: None,
+            routes: None,
+            shadow: None,
+            experiments: None,
+            presets: None,
+            templates: Default::default(),
+            moderation: None,
+            files: None,
+            stream_resume: None,
+            message_store: None,
+            warmup: None,
+            compaction: None,
+            #[cfg(feature = "sqlite")]
+            request_log: None,
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker_reports_open_once() {
+        let mut cb = CircuitBreakerState::new(true);
+        for _ in 1..CIRCUIT_BREAKER_FAILURE_THRESHOLD {
+            assert!(!cb.record_failure());
+        }
+        assert!(cb.record_failure());
+        assert!(cb.is_open);
+        // Further failures while open are not new transitions
+        assert!(!cb.record_failure());
+    }
+
+    #[test]
+    fn test_circuit_breaker_reports_close_after_half_open() {
+        let mut cb = CircuitBreakerState::new(true);
+        for _ in 0..CIRCUIT_BREAKER_FAILURE_THRESHOLD {
+            cb.record_failure();
+        }
+        cb.last_failure_time = Some(SystemTime::now() - Duration::from_secs(31));
+        assert!(cb.should_allow_request());
+        assert!(cb.half_open);
+        assert!(cb.record_success());
+        // Normal successes are not transitions
+        assert!(!cb.record_success());
+    }
+
+    #[test]
+    fn test_disabled_circuit_breaker_reports_no_transitions() {
+        let mut cb = CircuitBreakerState::new(false);
+        for _ in 0..CIRCUIT_BREAKER_FAILURE_THRESHOLD {
+            assert!(!cb.record_failure());
+        }
+        assert!(!cb.record_success());
+    }
+}
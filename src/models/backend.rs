@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use axum::http::HeaderName;
+use crate::config::{backend_env_list, backend_env_parse};
+use crate::constants::{DEFAULT_MAX_IMAGES, DEFAULT_MAX_IMAGE_BYTES, DEFAULT_MAX_TEMPERATURE};
+use crate::services::{StreamingSupport, ThinkingDialect, STRIPPABLE_PARAMS};
+use crate::utils::image::{ImageLimits, ImagePolicy};
+use crate::utils::tool_ids::ToolIdFormat;
+use crate::utils::tool_schema::{SchemaCleaning, StrictTools};
+
+/// A chat completions endpoint the proxy can route to
+#[derive(Clone, Debug)]
+pub struct Backend {
+    pub name: String,
+    pub url: String,
+    pub options: BackendOptions,
+}
+
+impl Backend {
+    fn new(name: String, url: String) -> Self {
+        let options = BackendOptions::from_env(&name);
+        Self { name, url, options }
+    }
+}
+
+/// Conversion tweaks that differ between backends (`BACKEND_<NAME>_<OPTION>`, falling back to `<OPTION>`)
+#[derive(Clone, Debug)]
+pub struct BackendOptions {
+    /// Multiplier from Claude's 0–1 temperature to the backend's range (`TEMPERATURE_SCALE`)
+    pub temperature_scale: f32,
+    /// Upper clamp applied after scaling (`TEMPERATURE_MAX`)
+    pub max_temperature: f32,
+    /// Request parameters this backend rejects and that are stripped before forwarding (`UNSUPPORTED_PARAMS`)
+    pub unsupported_params: Vec<String>,
+    /// How `thinking` is sent to this backend (`THINKING_DIALECT`)
+    pub thinking_dialect: ThinkingDialect,
+    /// Role used for system messages: `system` or `developer` (`SYSTEM_ROLE`)
+    pub system_role: String,
+    /// Merge consecutive same-role messages for strict-alternation templates (`MERGE_SAME_ROLE`)
+    pub merge_same_role: bool,
+    /// Insert placeholder user turns and move tool results next to their calls (`REPAIR_ORDERING`)
+    pub repair_ordering: bool,
+    /// Describe tools in the prompt and parse fenced JSON actions from the reply (`TOOL_EMULATION`)
+    pub tool_emulation: bool,
+    /// Send tool calls and results as plain assistant/user text, for backends without the `tool` role (`TOOL_RESULTS_AS_TEXT`)
+    pub tool_results_as_text: bool,
+    /// Tool schema keywords to strip and description limit (`SCHEMA_STRIP_KEYWORDS`, `TOOL_DESCRIPTION_MAX_CHARS`)
+    pub schema_cleaning: SchemaCleaning,
+    /// Mark tool definitions strict (`STRICT_TOOLS`), except tools named in `STRICT_TOOLS_EXCLUDE`
+    pub strict_tools: StrictTools,
+    pub strict_tools_exclude: Vec<String>,
+    /// Forward `cache_control` breakpoints on tool definitions, for backends with prompt caching (`PROMPT_CACHING`)
+    pub prompt_caching: bool,
+    /// Image formats this backend accepts (`IMAGE_FORMATS`) and whether others are converted (`IMAGE_TRANSCODE`)
+    pub images: ImagePolicy,
+    /// Images per request and decoded bytes per image (`MAX_IMAGES`, `MAX_IMAGE_BYTES`)
+    pub image_limits: ImageLimits,
+    /// Tool call ID rules; history IDs are rewritten to match (`TOOL_ID_FORMAT`)
+    pub tool_id_format: ToolIdFormat,
+    /// Whether requests are streamed; `auto` stops streaming once the backend rejects it (`STREAMING`)
+    pub streaming: StreamingSupport,
+    /// Header carrying a stable per-conversation hash for prefix-cache-aware scheduling (`SESSION_HEADER`)
+    pub session_header: Option<HeaderName>,
+    /// Gzip request bodies of at least this many bytes; 0 never compresses (`REQUEST_GZIP_MIN_BYTES`)
+    pub request_gzip_min_bytes: usize,
+    /// Relative price for `MODEL_ROUTES`: the cheapest healthy backend serving an alias is tried first (`COST`)
+    pub cost: f64,
+}
+
+impl Default for BackendOptions {
+    fn default() -> Self {
+        Self {
+            temperature_scale: 1.0,
+            max_temperature: DEFAULT_MAX_TEMPERATURE,
+            unsupported_params: Vec::new(),
+            thinking_dialect: ThinkingDialect::default(),
+            system_role: "system".into(),
+            merge_same_role: false,
+            repair_ordering: false,
+            tool_emulation: false,
+            tool_results_as_text: false,
+            schema_cleaning: SchemaCleaning::default(),
+            strict_tools: StrictTools::default(),
+            strict_tools_exclude: Vec::new(),
+            prompt_caching: false,
+            images: ImagePolicy::default(),
+            image_limits: ImageLimits::default(),
+            tool_id_format: ToolIdFormat::default(),
+            streaming: StreamingSupport::default(),
+            session_header: None,
+            request_gzip_min_bytes: 0,
+            cost: 0.0,
+        }
+    }
+}
+
+impl BackendOptions {
+    fn from_env(backend: &str) -> Self {
+        let defaults = Self::default();
+        Self {
+            temperature_scale: backend_env_parse(backend, "TEMPERATURE_SCALE").unwrap_or(defaults.temperature_scale),
+            max_temperature: backend_env_parse(backend, "TEMPERATURE_MAX").unwrap_or(defaults.max_temperature),
+            unsupported_params: backend_env_list(backend, "UNSUPPORTED_PARAMS")
+                .into_iter()
+                .filter(|p| {
+                    let known = STRIPPABLE_PARAMS.iter().any(|k| k.eq_ignore_ascii_case(p));
+                    if !known {
+                        log::warn!("⚠️  Backend '{}': ignoring unknown UNSUPPORTED_PARAMS entry '{}'", backend, p);
+                    }
+                    known
+                })
+                .collect(),
+            thinking_dialect: backend_env_parse(backend, "THINKING_DIALECT").unwrap_or(defaults.thinking_dialect),
+            system_role: backend_env_parse::<String>(backend, "SYSTEM_ROLE")
+                .filter(|role| role == "system" || role == "developer")
+                .unwrap_or(defaults.system_role),
+            merge_same_role: backend_env_parse(backend, "MERGE_SAME_ROLE").unwrap_or(defaults.merge_same_role),
+            repair_ordering: backend_env_parse(backend, "REPAIR_ORDERING").unwrap_or(defaults.repair_ordering),
+            tool_emulation: backend_env_parse(backend, "TOOL_EMULATION").unwrap_or(defaults.tool_emulation),
+            tool_results_as_text: backend_env_parse(backend, "TOOL_RESULTS_AS_TEXT").unwrap_or(defaults.tool_results_as_text),
+            schema_cleaning: SchemaCleaning {
+                strip_keywords: backend_env_list(backend, "SCHEMA_STRIP_KEYWORDS"),
+                max_description_chars: backend_env_parse(backend, "TOOL_DESCRIPTION_MAX_CHARS").filter(|&n: &usize| n > 0),
+                collapse_whitespace: false,
+            },
+            strict_tools: backend_env_parse(backend, "STRICT_TOOLS").unwrap_or(defaults.strict_tools),
+            strict_tools_exclude: backend_env_list(backend, "STRICT_TOOLS_EXCLUDE"),
+            prompt_caching: backend_env_parse(backend, "PROMPT_CACHING").unwrap_or(defaults.prompt_caching),
+            images: ImagePolicy::new(&backend_env_list(backend, "IMAGE_FORMATS"), image_transcode(backend)),
+            image_limits: ImageLimits {
+                max_images: backend_env_parse(backend, "MAX_IMAGES").unwrap_or(DEFAULT_MAX_IMAGES),
+                max_image_bytes: backend_env_parse(backend, "MAX_IMAGE_BYTES").unwrap_or(DEFAULT_MAX_IMAGE_BYTES),
+            },
+            tool_id_format: backend_env_parse(backend, "TOOL_ID_FORMAT").unwrap_or(defaults.tool_id_format),
+            streaming: backend_env_parse(backend, "STREAMING").map(StreamingSupport::new).unwrap_or(defaults.streaming),
+            session_header: backend_env_parse(backend, "SESSION_HEADER"),
+            request_gzip_min_bytes: backend_env_parse(backend, "REQUEST_GZIP_MIN_BYTES").unwrap_or(defaults.request_gzip_min_bytes),
+            cost: backend_env_parse(backend, "COST").unwrap_or(defaults.cost),
+        }
+    }
+
+    /// Map a Claude temperature onto this backend's range
+    pub fn scale_temperature(&self, temperature: f32) -> f32 {
+        (temperature * self.temperature_scale).clamp(0.0, self.max_temperature)
+    }
+}
+
+fn image_transcode(backend: &str) -> bool {
+    let enabled = backend_env_parse(backend, "IMAGE_TRANSCODE").unwrap_or(false);
+    if enabled && cfg!(not(feature = "image-transcode")) {
+        log::warn!("⚠️  Backend '{}': IMAGE_TRANSCODE needs the image-transcode feature; unsupported images will be rejected", backend);
+    }
+    enabled
+}
+
+/// The default backend (`BACKEND_URL`) plus optional named backends (`BACKENDS=name=url,...`)
+#[derive(Clone, Debug)]
+pub struct BackendRegistry {
+    default: Backend,
+    named: HashMap<String, Backend>,
+}
+
+impl BackendRegistry {
+    pub fn new(default_url: String, named: &[String]) -> Self {
+        let named = named
+            .iter()
+            .filter_map(|entry| {
+                let Some((name, url)) = entry.split_once('=') else {
+                    log::warn!("⚠️  Ignoring malformed BACKENDS entry '{}' (expected name=url)", entry);
+                    return None;
+                };
+                let name = name.trim().to_string();
+                Some((name.clone(), Backend::new(name, url.trim().to_string())))
+            })
+            .collect();
+        Self {
+            default: Backend::new("default".into(), default_url),
+            named,
+        }
+    }
+
+    pub fn default_backend(&self) -> &Backend {
+        &self.default
+    }
+
+    /// Look up a backend by name (`default` always resolves)
+    pub fn get(&self, name: &str) -> Option<&Backend> {
+        if name == self.default.name {
+            return Some(&self.default);
+        }
+        self.named.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.default.name.as_str()).chain(self.named.keys().map(String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_options_leave_temperature_unchanged() {
+        assert_eq!(BackendOptions::default().scale_temperature(0.7), 0.7);
+    }
+
+    #[test]
+    fn test_temperature_is_scaled_and_clamped() {
+        let options = BackendOptions {
+            temperature_scale: 2.0,
+            max_temperature: 1.5,
+            ..Default::default()
+        };
+        assert_eq!(options.scale_temperature(0.5), 1.0);
+        assert_eq!(options.scale_temperature(1.0), 1.5);
+        assert_eq!(options.scale_temperature(-1.0), 0.0);
+    }
+}
@@ -4,30 +4,60 @@ use serde_json::Value;
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ThinkingConfig {
     #[serde(rename = "type")]
-    pub type_: String, // "enabled"
+    pub type_: String, // "enabled" or "disabled"
+    #[serde(default)]
     pub budget_tokens: u32,
 }
 
-#[derive(Deserialize, Debug)]
+/// Base64 source of an image, audio or document block (`text` sources for plain-text documents)
+#[derive(Deserialize, Debug, Clone)]
 pub struct ClaudeImageSource {
     #[serde(rename = "type")]
-    #[allow(dead_code)]
     pub source_type: String,
     pub media_type: String,
     pub data: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum ClaudeContentBlock {
+    // `citations` on text blocks are dropped: OpenAI backends have no equivalent
     #[serde(rename = "text")]
     Text { text: String },
     #[serde(rename = "image")]
     Image { source: ClaudeImageSource },
+    /// Base64 WAV or MP3, sent to the backend as an OpenAI `input_audio` part
+    #[serde(rename = "audio")]
+    Audio { source: ClaudeImageSource },
+    /// PDF or text document: text is inlined, other types become an OpenAI `file` part
+    #[serde(rename = "document")]
+    Document {
+        source: ClaudeImageSource,
+        #[serde(default)]
+        title: Option<String>,
+    },
     #[serde(rename = "thinking")]
     Thinking { thinking: String },
     #[serde(rename = "tool_use")]
     ToolUse { id: String, name: String, input: Value },
+    /// Server tool call executed by the proxy; its result follows in the same assistant message
+    #[serde(rename = "server_tool_use")]
+    ServerToolUse { id: String, name: String, input: Value },
+    #[serde(rename = "web_search_tool_result")]
+    WebSearchToolResult { tool_use_id: String, content: Value },
+    #[serde(rename = "code_execution_tool_result")]
+    CodeExecutionToolResult { tool_use_id: String, content: Value },
+    /// Result of the bash tool of newer code execution versions (`code_execution_20250825`)
+    #[serde(rename = "bash_code_execution_tool_result")]
+    BashCodeExecutionToolResult { tool_use_id: String, content: Value },
+    /// Result of the file editing tool of newer code execution versions
+    #[serde(rename = "text_editor_code_execution_tool_result")]
+    TextEditorCodeExecutionToolResult { tool_use_id: String, content: Value },
+    /// File from the Files API made available inside the code execution container
+    #[serde(rename = "container_upload")]
+    ContainerUpload { file_id: String },
+    #[serde(rename = "search_result")]
+    SearchResult { source: String, title: String, content: Value },
     #[serde(rename = "tool_result")]
     ToolResult {
         tool_use_id: String,
@@ -38,22 +68,39 @@ pub enum ClaudeContentBlock {
     },
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct ClaudeMessage {
     pub role: String,
     pub content: Value, // String or Vec<ClaudeContentBlock>
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct ClaudeTool {
+    /// Set for server tools (`web_search_20250305`, ...); client tools have no type or `custom`
+    #[serde(rename = "type", default)]
+    pub type_: Option<String>,
     pub name: String,
     #[serde(default)]
     pub description: Option<String>,
+    /// Absent for server tools
+    #[serde(default)]
     pub input_schema: Value,
+    // Server tool options
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+    #[serde(default)]
+    pub allowed_domains: Option<Vec<String>>,
+    #[serde(default)]
+    pub blocked_domains: Option<Vec<String>>,
+    /// Prompt caching breakpoint, forwarded to backends with `PROMPT_CACHING`
+    #[serde(default)]
+    pub cache_control: Option<Value>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct ClaudeRequest {
+    /// May be missing or blank; `DEFAULT_MODEL` fills it in
+    #[serde(default)]
     pub model: String,
     pub messages: Vec<ClaudeMessage>,
     #[serde(default)]
@@ -81,11 +128,16 @@ pub struct ClaudeRequest {
     pub metadata: Option<Value>,
     #[serde(default)]
     pub service_tier: Option<String>,
+    /// Code execution container to reuse: an id, or `{id, skills}` (code execution betas). The
+    /// proxy's sandbox is throwaway, so this only produces a warning block
+    #[serde(default)]
+    pub container: Option<Value>,
 }
 
 #[derive(Deserialize)]
 pub struct ClaudeTokenCountRequest {
     #[allow(dead_code)]
+    #[serde(default)]
     pub model: String,
     pub messages: Vec<ClaudeMessage>,
     #[serde(default)]
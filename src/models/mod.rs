@@ -1,7 +1,9 @@
 pub mod claude;
 pub mod openai;
 pub mod app;
+pub mod backend;
 
 pub use claude::*;
 pub use openai::*;
-pub use app::*;
\ No newline at end of file
+pub use app::*;
+pub use backend::*;
\ No newline at end of file
@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::borrow::Cow;
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct OAIMessage {
     pub role: String,
     pub content: Value, // String or Array for multimodal
@@ -9,24 +10,35 @@ pub struct OAIMessage {
     pub tool_call_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<Value>>,
+    // Marks an assistant prefill for backends using the `prefix` convention
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<bool>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct OAIFunction {
     pub name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
     pub parameters: Value,
+    // Structured outputs: arguments are guaranteed to match `parameters`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct OAITool {
     #[serde(rename = "type")]
     pub type_: String,
     pub function: OAIFunction,
+    // Prompt caching breakpoint (LiteLLM, OpenRouter); only set for backends with `PROMPT_CACHING`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<Value>,
 }
 
-#[derive(Serialize)]
+/// Chat completions request. Fields not listed here, such as `n`, are never sent, even when a
+/// transform script sets them.
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct OAIChatReq {
     pub model: String,
     pub messages: Vec<OAIMessage>,
@@ -46,10 +58,30 @@ pub struct OAIChatReq {
     pub tool_choice: Option<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub thinking: Option<Value>,
+    // Backend-specific reasoning controls (see services::thinking::ThinkingDialect)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_template_kwargs: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub parallel_tool_calls: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Value>,
+    // vLLM / SGLang chat template controls for assistant prefill
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continue_final_message: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub add_generation_prompt: Option<bool>,
+    // vLLM guided decoding against a JSON schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guided_json: Option<Value>,
+    // Per-token log probabilities, requested through `x-proxy-debug` (see services::debug_sidecar)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
     pub stream: bool,
 }
 
@@ -81,15 +113,63 @@ pub struct OAIChoiceDelta {
     pub content: Option<String>,
     #[serde(default)]
     pub tool_calls: Option<Vec<OAIToolCallDelta>>,
+    // Legacy `functions` API: one call per message, streamed without an id
+    #[serde(default)]
+    pub function_call: Option<OAIToolFunctionDelta>,
     // Extended reasoning streams (optional in some backends)
     #[serde(default)]
     pub reasoning_content: Option<String>,
+    // Alternative reasoning fields: `reasoning` (string or `{text}`), `thinking`, OpenRouter `reasoning_details`
+    #[serde(default)]
+    pub reasoning: Option<Value>,
+    #[serde(default)]
+    pub thinking: Option<Value>,
+    #[serde(default)]
+    pub reasoning_details: Option<Vec<Value>>,
+    // Source annotations (OpenAI search models, OpenRouter web plugin)
+    #[serde(default)]
+    pub annotations: Option<Vec<serde_json::Value>>,
+}
+
+impl OAIChoiceDelta {
+    /// A legacy `function_call` delta as tool call 0. The delta that names the function gets
+    /// `id`, since the legacy format has none.
+    pub fn legacy_tool_call(&self, id: impl FnOnce() -> String) -> Option<OAIToolCallDelta> {
+        let function = self.function_call.as_ref()?;
+        Some(OAIToolCallDelta {
+            index: Some(0),
+            id: function.name.is_some().then(id),
+            _type: None,
+            function: Some(OAIToolFunctionDelta { name: function.name.clone(), arguments: function.arguments.clone() }),
+        })
+    }
+
+    /// Reasoning text from whichever field this backend uses (first non-empty wins, since
+    /// some backends send the same text in several fields)
+    pub fn reasoning_text(&self) -> Option<Cow<'_, str>> {
+        fn text_of(v: &Value) -> Option<&str> {
+            v.as_str().or_else(|| v.get("text").and_then(Value::as_str))
+        }
+        if let Some(r) = self.reasoning_content.as_deref().filter(|r| !r.is_empty()) {
+            return Some(Cow::Borrowed(r));
+        }
+        if let Some(r) = [&self.reasoning, &self.thinking]
+            .into_iter()
+            .flatten()
+            .filter_map(text_of)
+            .find(|r| !r.is_empty())
+        {
+            return Some(Cow::Borrowed(r));
+        }
+        let details: String = self.reasoning_details.iter().flatten().filter_map(text_of).collect();
+        (!details.is_empty()).then_some(Cow::Owned(details))
+    }
 }
 
 #[derive(Deserialize, Default, Debug)]
 pub struct OAIChoice {
     #[serde(default)]
-    pub _index: usize,
+    pub index: usize,
     // Streaming responses use 'delta', non-streaming use 'message'
     #[serde(default)]
     pub delta: Option<OAIChoiceDelta>,
@@ -98,6 +178,9 @@ pub struct OAIChoice {
     pub message: Option<serde_json::Value>,
     #[serde(default)]
     pub finish_reason: Option<String>,
+    // `{content: [{token, logprob, top_logprobs}]}` when logprobs were requested
+    #[serde(default)]
+    pub logprobs: Option<Value>,
 }
 
 #[derive(Deserialize, Default, Debug)]
@@ -125,4 +208,73 @@ pub struct OAIUsage {
     pub completion_tokens: Option<u32>,
     #[serde(default)]
     pub total_tokens: Option<u32>,
-}
\ No newline at end of file
+    // Prompt cache statistics (OpenAI / OpenRouter)
+    #[serde(default)]
+    pub prompt_tokens_details: Option<OAIPromptTokensDetails>,
+    // Prompt cache statistics (DeepSeek)
+    #[serde(default)]
+    pub prompt_cache_hit_tokens: Option<u32>,
+    // Anthropic-style cache writes (LiteLLM and other Claude-backed gateways)
+    #[serde(default)]
+    pub cache_creation_input_tokens: Option<u32>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct OAIPromptTokensDetails {
+    #[serde(default)]
+    pub cached_tokens: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn delta(v: Value) -> OAIChoiceDelta {
+        serde_json::from_value(v).unwrap()
+    }
+
+    #[test]
+    fn test_chat_request_drops_n() {
+        let req: OAIChatReq = serde_json::from_value(json!({"model": "m", "messages": [], "stream": true, "n": 3})).unwrap();
+        assert!(serde_json::to_value(&req).unwrap().get("n").is_none());
+    }
+
+    #[test]
+    fn test_choice_index() {
+        let chunk: OAIStreamChunk = serde_json::from_value(json!({"choices": [{"delta": {}}, {"index": 2, "delta": {}}]})).unwrap();
+        let indices: Vec<usize> = chunk.choices.iter().map(|c| c.index).collect();
+        assert_eq!(indices, [0, 2]);
+    }
+
+    #[test]
+    fn test_reasoning_text_variants() {
+        assert_eq!(delta(json!({"reasoning_content": "a"})).reasoning_text().as_deref(), Some("a"));
+        assert_eq!(delta(json!({"reasoning": "b"})).reasoning_text().as_deref(), Some("b"));
+        assert_eq!(delta(json!({"reasoning": {"text": "c"}})).reasoning_text().as_deref(), Some("c"));
+        assert_eq!(delta(json!({"thinking": "d"})).reasoning_text().as_deref(), Some("d"));
+        assert_eq!(
+            delta(json!({"reasoning_details": [{"type": "reasoning.text", "text": "e"}, {"type": "reasoning.text", "text": "f"}]}))
+                .reasoning_text()
+                .as_deref(),
+            Some("ef")
+        );
+    }
+
+    #[test]
+    fn test_reasoning_text_prefers_first_field() {
+        let d = delta(json!({"reasoning": "x", "reasoning_details": [{"type": "reasoning.text", "text": "x"}]}));
+        assert_eq!(d.reasoning_text().as_deref(), Some("x"));
+        assert_eq!(delta(json!({"content": "hi", "reasoning": null})).reasoning_text(), None);
+    }
+
+    #[test]
+    fn test_legacy_tool_call() {
+        let first = delta(json!({"function_call": {"name": "f", "arguments": ""}})).legacy_tool_call(|| "toolu_1".into()).unwrap();
+        assert_eq!((first.index, first.id.as_deref()), (Some(0), Some("toolu_1")));
+        let next = delta(json!({"function_call": {"arguments": "{}"}})).legacy_tool_call(|| "toolu_1".into()).unwrap();
+        assert_eq!(next.id, None);
+        assert_eq!(next.function.unwrap().arguments.as_deref(), Some("{}"));
+        assert!(delta(json!({"content": "hi"})).legacy_tool_call(|| "toolu_1".into()).is_none());
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+use serde::Serialize;
+use tokio::sync::oneshot;
+use crate::config::env_or;
+use crate::constants::*;
+
+/// Admission control for backend requests: at most `limit` run at once, up to `max_queue` more wait
+/// up to `max_wait` for a slot, and the rest are turned away so clients back off.
+///
+/// Freed slots go to the highest-priority waiter first. When the queue is full, a request evicts the
+/// newest waiter of a lower priority instead of being rejected.
+pub struct Admission {
+    limit: usize,
+    max_queue: usize,
+    max_wait: Duration,
+    state: Mutex<AdmissionState>,
+    peak_queued: AtomicUsize,
+    rejected: AtomicU64,
+    timed_out: AtomicU64,
+    evicted: AtomicU64,
+}
+
+/// Queue class of a request, from its `service_tier`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AdmissionPriority {
+    /// `batch` / `flex`: background work that yields to everything else
+    Low = 0,
+    Normal = 1,
+    /// `priority` / `scale`: interactive sessions that jump the queue
+    High = 2,
+}
+
+impl AdmissionPriority {
+    pub fn from_service_tier(tier: Option<&str>) -> Self {
+        match tier.map(|t| t.trim().to_ascii_lowercase()).as_deref() {
+            Some("priority" | "scale") => AdmissionPriority::High,
+            Some("batch" | "flex") => AdmissionPriority::Low,
+            _ => AdmissionPriority::Normal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdmissionError {
+    /// `max_queue` requests were already waiting, or a higher-priority request took this one's place
+    QueueFull,
+    /// No slot freed up within `max_wait`
+    Timeout,
+}
+
+impl AdmissionError {
+    /// Short error code returned to the client
+    pub fn code(&self) -> &'static str {
+        match self {
+            AdmissionError::QueueFull => "admission_queue_full",
+            AdmissionError::Timeout => "admission_queue_timeout",
+        }
+    }
+}
+
+/// Waiting requests per priority, indexed by `AdmissionPriority as usize`
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct QueuedByPriority {
+    pub low: usize,
+    pub normal: usize,
+    pub high: usize,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AdmissionSnapshot {
+    pub limit: usize,
+    pub active: usize,
+    pub queued: usize,
+    pub queued_by_priority: QueuedByPriority,
+    pub max_queue: usize,
+    pub peak_queued: usize,
+    /// Requests turned away because the queue was full
+    pub rejected: u64,
+    /// Requests that waited `max_wait` without getting a slot
+    pub timed_out: u64,
+    /// Queued requests dropped for a higher-priority one (also counted in `rejected`)
+    pub evicted: u64,
+}
+
+/// Sent to a waiter: `true` hands it a slot, `false` evicts it
+type Waiter = (u64, oneshot::Sender<bool>);
+
+#[derive(Default)]
+struct AdmissionState {
+    active: usize,
+    queues: [VecDeque<Waiter>; 3],
+    next_waiter: u64,
+}
+
+impl AdmissionState {
+    fn queued(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// A running request's slot, handed to the next waiter (or freed) on drop
+pub struct AdmissionPermit {
+    admission: Arc<Admission>,
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        let mut state = self.admission.lock();
+        // Highest priority first, oldest first; skip waiters that just gave up
+        for queue in state.queues.iter_mut().rev() {
+            while let Some((_, waiter)) = queue.pop_front() {
+                if waiter.send(true).is_ok() {
+                    return;
+                }
+            }
+        }
+        state.active -= 1;
+    }
+}
+
+/// A queued request. Dropping it while still queued (timeout, client gone) leaves the queue, and a
+/// slot handed over but never taken is passed on.
+struct QueueTicket {
+    admission: Arc<Admission>,
+    priority: AdmissionPriority,
+    id: u64,
+    admitted: Option<oneshot::Receiver<bool>>,
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        let Some(mut admitted) = self.admitted.take() else { return };
+        let mut state = self.admission.lock();
+        let queue = &mut state.queues[self.priority as usize];
+        if let Some(position) = queue.iter().position(|(waiter, _)| *waiter == self.id) {
+            queue.remove(position);
+            return;
+        }
+        drop(state);
+        if admitted.try_recv() == Ok(true) {
+            drop(AdmissionPermit { admission: self.admission.clone() });
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(limit: usize, max_queue: usize, max_wait: Duration) -> Self {
+        Self {
+            limit,
+            max_queue,
+            max_wait,
+            state: Mutex::new(AdmissionState::default()),
+            peak_queued: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    /// `MAX_CONCURRENT_REQUESTS`, `ADMISSION_QUEUE_DEPTH` and `ADMISSION_QUEUE_WAIT_MS`; `None` when
+    /// the concurrency limit is unset or 0
+    pub fn from_env() -> Option<Self> {
+        let limit: usize = env_or("MAX_CONCURRENT_REQUESTS", 0);
+        if limit == 0 {
+            return None;
+        }
+        let max_queue = env_or("ADMISSION_QUEUE_DEPTH", DEFAULT_ADMISSION_QUEUE_DEPTH);
+        let max_wait = Duration::from_millis(env_or("ADMISSION_QUEUE_WAIT_MS", DEFAULT_ADMISSION_QUEUE_WAIT_MS));
+        log::info!("🚦 Admission control: {} concurrent requests, queue of {} waiting up to {:?}", limit, max_queue, max_wait);
+        Some(Self::new(limit, max_queue, max_wait))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, AdmissionState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait for a slot; the permit is held until the response stream finishes
+    pub async fn admit(self: &Arc<Self>, priority: AdmissionPriority) -> Result<AdmissionPermit, AdmissionError> {
+        let mut ticket = {
+            let mut state = self.lock();
+            if state.active < self.limit {
+                state.active += 1;
+                return Ok(AdmissionPermit { admission: self.clone() });
+            }
+            if state.queued() >= self.max_queue && !self.evict_below(&mut state, priority) {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(AdmissionError::QueueFull);
+            }
+            let (tx, rx) = oneshot::channel();
+            let id = state.next_waiter;
+            state.next_waiter += 1;
+            state.queues[priority as usize].push_back((id, tx));
+            self.peak_queued.fetch_max(state.queued(), Ordering::Relaxed);
+            QueueTicket { admission: self.clone(), priority, id, admitted: Some(rx) }
+        };
+
+        let Some(admitted) = ticket.admitted.as_mut() else { unreachable!("ticket created with a receiver") };
+        let outcome = tokio::time::timeout(self.max_wait, admitted).await;
+        match outcome {
+            Ok(Ok(true)) => {
+                ticket.admitted = None;
+                Ok(AdmissionPermit { admission: self.clone() })
+            }
+            Ok(_) => {
+                ticket.admitted = None;
+                Err(AdmissionError::QueueFull)
+            }
+            Err(_) => {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                Err(AdmissionError::Timeout)
+            }
+        }
+    }
+
+    /// Make room by evicting the newest waiter below `priority`
+    fn evict_below(&self, state: &mut AdmissionState, priority: AdmissionPriority) -> bool {
+        let Some(queue) = state.queues[..priority as usize].iter_mut().find(|q| !q.is_empty()) else {
+            return false;
+        };
+        if let Some((_, waiter)) = queue.pop_back() {
+            let _ = waiter.send(false);
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// `Retry-After` for rejected requests: the queue wait, in whole seconds (at least 1)
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs(self.max_wait.as_millis().div_ceil(1000).max(1) as u64)
+    }
+
+    pub fn snapshot(&self) -> AdmissionSnapshot {
+        let state = self.lock();
+        AdmissionSnapshot {
+            limit: self.limit,
+            active: state.active,
+            queued: state.queued(),
+            queued_by_priority: QueuedByPriority {
+                low: state.queues[AdmissionPriority::Low as usize].len(),
+                normal: state.queues[AdmissionPriority::Normal as usize].len(),
+                high: state.queues[AdmissionPriority::High as usize].len(),
+            },
+            max_queue: self.max_queue,
+            peak_queued: self.peak_queued.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Per-client-key cap on simultaneous streams (`MAX_STREAMS_PER_KEY`), so one client fanning out
+/// subagents can't take every backend slot. Each active key gets its own small `Admission` queue;
+/// idle keys are forgotten.
+pub struct KeyStreamLimit {
+    limit: usize,
+    max_queue: usize,
+    max_wait: Duration,
+    /// Per key fingerprint; permits and queue tickets hold the other references
+    keys: Mutex<HashMap<String, Arc<Admission>>>,
+    rejected: AtomicU64,
+    timed_out: AtomicU64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct KeyStreamSnapshot {
+    pub limit: usize,
+    /// Keys with at least one open or queued stream
+    pub active_keys: usize,
+    /// Keys currently at their limit
+    pub saturated_keys: usize,
+    pub queued: usize,
+    pub rejected: u64,
+    pub timed_out: u64,
+}
+
+impl KeyStreamLimit {
+    pub fn new(limit: usize, max_queue: usize, max_wait: Duration) -> Self {
+        Self {
+            limit,
+            max_queue,
+            max_wait,
+            keys: Mutex::new(HashMap::new()),
+            rejected: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+        }
+    }
+
+    /// `MAX_STREAMS_PER_KEY`, `KEY_STREAM_QUEUE_DEPTH` and `KEY_STREAM_QUEUE_WAIT_MS`; `None` when the
+    /// limit is unset or 0
+    pub fn from_env() -> Option<Self> {
+        let limit: usize = env_or("MAX_STREAMS_PER_KEY", 0);
+        if limit == 0 {
+            return None;
+        }
+        let max_queue = env_or("KEY_STREAM_QUEUE_DEPTH", limit);
+        let max_wait = Duration::from_millis(env_or("KEY_STREAM_QUEUE_WAIT_MS", DEFAULT_KEY_STREAM_QUEUE_WAIT_MS));
+        log::info!("🚦 Per-key stream limit: {} concurrent streams, queue of {} waiting up to {:?}", limit, max_queue, max_wait);
+        Some(Self::new(limit, max_queue, max_wait))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Arc<Admission>>> {
+        self.keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait for one of `key`'s stream slots; the permit is held until the response stream finishes
+    pub async fn admit(&self, key: &str, priority: AdmissionPriority) -> Result<AdmissionPermit, AdmissionError> {
+        let admission = {
+            let mut keys = self.lock();
+            keys.retain(|_, admission| Arc::strong_count(admission) > 1);
+            keys.entry(key.to_string())
+                .or_insert_with(|| Arc::new(Admission::new(self.limit, self.max_queue, self.max_wait)))
+                .clone()
+        };
+        let admitted = admission.admit(priority).await;
+        match admitted {
+            Err(AdmissionError::Timeout) => self.timed_out.fetch_add(1, Ordering::Relaxed),
+            Err(AdmissionError::QueueFull) => self.rejected.fetch_add(1, Ordering::Relaxed),
+            Ok(_) => 0,
+        };
+        admitted
+    }
+
+    /// `Retry-After` for rejected requests: the queue wait, in whole seconds (at least 1)
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs(self.max_wait.as_millis().div_ceil(1000).max(1) as u64)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn snapshot(&self) -> KeyStreamSnapshot {
+        let keys = self.lock();
+        let states: Vec<(usize, usize)> = keys
+            .values()
+            .filter(|admission| Arc::strong_count(admission) > 1)
+            .map(|admission| {
+                let state = admission.lock();
+                (state.active, state.queued())
+            })
+            .collect();
+        KeyStreamSnapshot {
+            limit: self.limit,
+            active_keys: states.len(),
+            saturated_keys: states.iter().filter(|(active, _)| *active >= self.limit).count(),
+            queued: states.iter().map(|(_, queued)| queued).sum(),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AdmissionPriority::*;
+
+    /// Queue a request in the background and wait until it is waiting
+    async fn enqueue(admission: &Arc<Admission>, priority: AdmissionPriority) -> tokio::task::JoinHandle<Result<AdmissionPermit, AdmissionError>> {
+        let queued = admission.snapshot().queued;
+        let handle = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.admit(priority).await }
+        });
+        while admission.snapshot().queued == queued {
+            tokio::task::yield_now().await;
+        }
+        handle
+    }
+
+    #[tokio::test]
+    async fn test_queue_full_is_rejected() {
+        let admission = Arc::new(Admission::new(1, 1, Duration::from_secs(5)));
+        let first = admission.admit(Normal).await.unwrap();
+        let waiting = enqueue(&admission, Normal).await;
+        assert_eq!(admission.admit(Normal).await.err(), Some(AdmissionError::QueueFull));
+        drop(first);
+        drop(waiting.await.unwrap().unwrap());
+        let snapshot = admission.snapshot();
+        assert_eq!((snapshot.active, snapshot.queued, snapshot.peak_queued, snapshot.rejected), (0, 0, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_wait_times_out() {
+        let admission = Arc::new(Admission::new(1, 10, Duration::from_millis(10)));
+        let _first = admission.admit(Normal).await.unwrap();
+        assert_eq!(admission.admit(Normal).await.err(), Some(AdmissionError::Timeout));
+        assert_eq!(admission.snapshot().timed_out, 1);
+        assert_eq!(admission.snapshot().queued, 0);
+        assert_eq!(admission.retry_after(), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_higher_priority_is_admitted_first() {
+        let admission = Arc::new(Admission::new(1, 10, Duration::from_secs(5)));
+        let first = admission.admit(Normal).await.unwrap();
+        let batch = enqueue(&admission, Low).await;
+        let normal = enqueue(&admission, Normal).await;
+        let interactive = enqueue(&admission, High).await;
+        assert_eq!(admission.snapshot().queued_by_priority, QueuedByPriority { low: 1, normal: 1, high: 1 });
+
+        drop(first);
+        let interactive = interactive.await.unwrap().unwrap();
+        assert!(!normal.is_finished() && !batch.is_finished());
+        drop(interactive);
+        drop(normal.await.unwrap().unwrap());
+        drop(batch.await.unwrap().unwrap());
+        assert_eq!(admission.snapshot().active, 0);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_evicts_lower_priority() {
+        let admission = Arc::new(Admission::new(1, 1, Duration::from_secs(5)));
+        let _first = admission.admit(Normal).await.unwrap();
+        let batch = enqueue(&admission, Low).await;
+        let interactive = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.admit(High).await.is_ok() }
+        });
+        assert_eq!(batch.await.unwrap().err(), Some(AdmissionError::QueueFull));
+        assert_eq!(admission.snapshot().evicted, 1);
+        // Same priority never evicts
+        assert_eq!(admission.admit(High).await.err(), Some(AdmissionError::QueueFull));
+        interactive.abort();
+    }
+
+    #[test]
+    fn test_priority_from_service_tier() {
+        assert_eq!(AdmissionPriority::from_service_tier(Some("priority")), High);
+        assert_eq!(AdmissionPriority::from_service_tier(Some("Flex")), Low);
+        assert_eq!(AdmissionPriority::from_service_tier(Some("batch")), Low);
+        assert_eq!(AdmissionPriority::from_service_tier(Some("auto")), Normal);
+        assert_eq!(AdmissionPriority::from_service_tier(None), Normal);
+    }
+
+    #[tokio::test]
+    async fn test_key_stream_limit_is_per_key() {
+        let limit = KeyStreamLimit::new(2, 0, Duration::from_millis(10));
+        let a1 = limit.admit("fp_a", Normal).await.unwrap();
+        let _a2 = limit.admit("fp_a", Normal).await.unwrap();
+        assert_eq!(limit.admit("fp_a", Normal).await.err(), Some(AdmissionError::QueueFull));
+        let _b = limit.admit("fp_b", Normal).await.unwrap();
+        let snapshot = limit.snapshot();
+        assert_eq!((snapshot.active_keys, snapshot.saturated_keys, snapshot.rejected), (2, 1, 1));
+
+        drop(a1);
+        let _a3 = limit.admit("fp_a", Normal).await.unwrap();
+        drop(_b);
+        assert_eq!(limit.snapshot().active_keys, 1, "idle keys are forgotten");
+    }
+}
@@ -1,4 +1,19 @@
-use axum::http::{HeaderMap, HeaderName, header::AUTHORIZATION};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use axum::{
+    extract::Query,
+    http::{header::{AsHeaderName, AUTHORIZATION}, HeaderMap, Uri},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Key of `keyed_hash`: `FINGERPRINT_SECRET`, or random bytes per process when unset
+static FINGERPRINT_KEY: OnceLock<Vec<u8>> = OnceLock::new();
 
 /// Normalize an Authorization header value into a bare API key
 pub fn normalize_auth_value_to_key(value: &str) -> String {
@@ -21,24 +36,115 @@ pub fn mask_token(token: &str) -> String {
     }
 }
 
-/// Extract client key from headers
-pub fn extract_client_key(headers: &HeaderMap) -> Option<String> {
-    let x_api_key_header = HeaderName::from_static("x-api-key");
-    let raw_x_api_key = headers
-        .get(&x_api_key_header)
-        .and_then(|h| h.to_str().ok())
-        .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty());
-    let raw_authorization = headers
-        .get(AUTHORIZATION)
+fn fingerprint_key() -> &'static [u8] {
+    FINGERPRINT_KEY.get_or_init(|| match std::env::var("FINGERPRINT_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+        _ => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+            (0..4)
+                .flat_map(|_| {
+                    let mut h = RandomState::new().build_hasher();
+                    h.write_u128(now);
+                    h.finish().to_le_bytes()
+                })
+                .collect()
+        }
+    })
+}
+
+/// HMAC-SHA256 of `parts`, NUL-separated, under `FINGERPRINT_SECRET`, truncated to 64 bits. Without
+/// the secret nobody can test guesses against a hash; without `FINGERPRINT_SECRET` the hashes
+/// change on every restart.
+pub fn keyed_hash(parts: &[&str]) -> u64 {
+    let mut mac = Hmac::<Sha256>::new_from_slice(fingerprint_key()).expect("HMAC takes keys of any length");
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            mac.update(&[0]);
+        }
+        mac.update(part.as_bytes());
+    }
+    let digest = mac.finalize().into_bytes();
+    u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 digests are 32 bytes"))
+}
+
+/// Fingerprint of an API key for usage records and ownership, keyed so it can't be matched
+/// against guessed keys without `FINGERPRINT_SECRET`
+pub fn key_fingerprint(token: &str) -> String {
+    format!("fp_{:016x}", keyed_hash(&[token]))
+}
+
+/// Compare secrets without short-circuiting on the first differing byte
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Which client header supplies the key when both are sent (`AUTH_HEADER_PRECEDENCE`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AuthPrecedence {
+    /// `Authorization` wins over `x-api-key` (previous behaviour)
+    #[default]
+    Authorization,
+    /// `x-api-key` wins; `Authorization` is the fallback
+    XApiKey,
+    /// Only `x-api-key`; `Authorization` is ignored (gateways that inject their own)
+    XApiKeyOnly,
+}
+
+impl FromStr for AuthPrecedence {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "authorization" => Ok(AuthPrecedence::Authorization),
+            "x-api-key" => Ok(AuthPrecedence::XApiKey),
+            "x-api-key-only" => Ok(AuthPrecedence::XApiKeyOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where client keys are read from: header precedence, plus a query parameter on selected routes
+/// for clients that cannot set headers (`API_KEY_QUERY_ROUTES`)
+#[derive(Debug, Clone, Default)]
+pub struct ClientAuth {
+    pub precedence: AuthPrecedence,
+    pub query_param: String,
+    /// Paths accepting the key as `?<query_param>=`; empty disables query keys
+    pub query_routes: Vec<String>,
+}
+
+impl ClientAuth {
+    /// Key from the headers, else from the query string on an allowed route
+    pub fn client_key(&self, headers: &HeaderMap, uri: &Uri) -> Option<String> {
+        extract_client_key(headers, self.precedence).or_else(|| self.query_key(uri))
+    }
+
+    fn query_key(&self, uri: &Uri) -> Option<String> {
+        if !self.query_routes.iter().any(|route| route == uri.path()) {
+            return None;
+        }
+        let Query(params) = Query::<HashMap<String, String>>::try_from_uri(uri).ok()?;
+        params.get(&self.query_param).map(|key| key.trim().to_string()).filter(|key| !key.is_empty())
+    }
+}
+
+fn header_value(headers: &HeaderMap, name: impl AsHeaderName) -> Option<String> {
+    headers
+        .get(name)
         .and_then(|h| h.to_str().ok())
         .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty());
+        .filter(|s| !s.is_empty())
+}
 
-    raw_authorization
-        .as_ref()
-        .map(|auth| normalize_auth_value_to_key(auth))
-        .or_else(|| raw_x_api_key.clone())
+/// Extract client key from headers
+pub fn extract_client_key(headers: &HeaderMap, precedence: AuthPrecedence) -> Option<String> {
+    let x_api_key = header_value(headers, "x-api-key");
+    let authorization = || header_value(headers, AUTHORIZATION).map(|auth| normalize_auth_value_to_key(&auth));
+    match precedence {
+        AuthPrecedence::Authorization => authorization().or(x_api_key),
+        AuthPrecedence::XApiKey => x_api_key.or_else(authorization),
+        AuthPrecedence::XApiKeyOnly => x_api_key,
+    }
 }
 
 #[cfg(test)]
@@ -46,6 +152,22 @@ mod tests {
     use super::*;
     use axum::http::HeaderValue;
 
+    #[test]
+    fn test_key_fingerprint_is_stable_and_hides_key() {
+        let fp = key_fingerprint("sk-1234567890abcdef");
+        assert_eq!(fp, key_fingerprint("sk-1234567890abcdef"));
+        assert_ne!(fp, key_fingerprint("sk-1234567890abcdeg"));
+        assert!(fp.starts_with("fp_") && !fp.contains("1234"));
+        assert_ne!(keyed_hash(&["ab", "c"]), keyed_hash(&["a", "bc"]));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret2"));
+    }
+
     // ============================================================================
     // normalize_auth_value_to_key tests
     // ============================================================================
@@ -160,7 +282,7 @@ mod tests {
         let mut headers = HeaderMap::new();
         headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer sk-test-123"));
         
-        let result = extract_client_key(&headers);
+        let result = extract_client_key(&headers, AuthPrecedence::Authorization);
         assert_eq!(result, Some("sk-test-123".to_string()));
     }
 
@@ -169,7 +291,7 @@ mod tests {
         let mut headers = HeaderMap::new();
         headers.insert("x-api-key", HeaderValue::from_static("sk-test-456"));
         
-        let result = extract_client_key(&headers);
+        let result = extract_client_key(&headers, AuthPrecedence::Authorization);
         assert_eq!(result, Some("sk-test-456".to_string()));
     }
 
@@ -179,7 +301,7 @@ mod tests {
         headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer sk-auth-key"));
         headers.insert("x-api-key", HeaderValue::from_static("sk-x-key"));
         
-        let result = extract_client_key(&headers);
+        let result = extract_client_key(&headers, AuthPrecedence::Authorization);
         assert_eq!(result, Some("sk-auth-key".to_string()));
     }
 
@@ -187,7 +309,7 @@ mod tests {
     fn test_extract_client_key_no_headers() {
         let headers = HeaderMap::new();
         
-        let result = extract_client_key(&headers);
+        let result = extract_client_key(&headers, AuthPrecedence::Authorization);
         assert_eq!(result, None);
     }
 
@@ -197,7 +319,7 @@ mod tests {
         headers.insert(AUTHORIZATION, HeaderValue::from_static(""));
         headers.insert("x-api-key", HeaderValue::from_static("sk-fallback"));
         
-        let result = extract_client_key(&headers);
+        let result = extract_client_key(&headers, AuthPrecedence::Authorization);
         assert_eq!(result, Some("sk-fallback".to_string()));
     }
 
@@ -207,7 +329,7 @@ mod tests {
         headers.insert(AUTHORIZATION, HeaderValue::from_static("   "));
         headers.insert("x-api-key", HeaderValue::from_static("sk-fallback"));
         
-        let result = extract_client_key(&headers);
+        let result = extract_client_key(&headers, AuthPrecedence::Authorization);
         assert_eq!(result, Some("sk-fallback".to_string()));
     }
 
@@ -217,7 +339,7 @@ mod tests {
         headers.insert(AUTHORIZATION, HeaderValue::from_static(""));
         headers.insert("x-api-key", HeaderValue::from_static(""));
         
-        let result = extract_client_key(&headers);
+        let result = extract_client_key(&headers, AuthPrecedence::Authorization);
         assert_eq!(result, None);
     }
 
@@ -226,7 +348,39 @@ mod tests {
         let mut headers = HeaderMap::new();
         headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer  sk-with-spaces  "));
         
-        let result = extract_client_key(&headers);
+        let result = extract_client_key(&headers, AuthPrecedence::Authorization);
         assert_eq!(result, Some("sk-with-spaces".to_string()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_extract_client_key_precedence() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer gateway-junk"));
+        headers.insert("x-api-key", HeaderValue::from_static("sk-x-key"));
+        assert_eq!(extract_client_key(&headers, AuthPrecedence::XApiKey).as_deref(), Some("sk-x-key"));
+        assert_eq!(extract_client_key(&headers, AuthPrecedence::XApiKeyOnly).as_deref(), Some("sk-x-key"));
+
+        headers.remove("x-api-key");
+        assert_eq!(extract_client_key(&headers, AuthPrecedence::XApiKey).as_deref(), Some("gateway-junk"));
+        assert_eq!(extract_client_key(&headers, AuthPrecedence::XApiKeyOnly), None);
+    }
+
+    #[test]
+    fn test_client_auth_query_key_on_allowed_routes() {
+        let auth = ClientAuth {
+            query_param: "key".into(),
+            query_routes: vec!["/v1/messages".into()],
+            ..Default::default()
+        };
+        let uri: Uri = "/v1/messages?beta=true&key=sk%2Dquery".parse().unwrap();
+        assert_eq!(auth.client_key(&HeaderMap::new(), &uri).as_deref(), Some("sk-query"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("sk-header"));
+        assert_eq!(auth.client_key(&headers, &uri).as_deref(), Some("sk-header"));
+
+        let other: Uri = "/admin/stats?key=sk-query".parse().unwrap();
+        assert_eq!(auth.client_key(&HeaderMap::new(), &other), None);
+        assert_eq!(ClientAuth::default().client_key(&HeaderMap::new(), &uri), None);
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use crate::constants::{DIAGNOSTIC_HEADERS, DIAGNOSTIC_HEADER_PREFIXES};
+use crate::models::OAIChatReq;
+use crate::services::auth::keyed_hash;
+
+/// Client headers that are never forwarded, whatever the allowlist says
+const NEVER_FORWARD: &[&str] = &[
+    "authorization",
+    "x-api-key",
+    "host",
+    "content-length",
+    "content-type",
+    "connection",
+    "transfer-encoding",
+    "accept-encoding",
+];
+
+/// Parse `FORWARD_HEADERS` entries into valid, forwardable header names
+pub fn parse_forward_headers(names: &[String]) -> Vec<HeaderName> {
+    names
+        .iter()
+        .filter_map(|n| {
+            let name = HeaderName::from_bytes(n.trim().to_ascii_lowercase().as_bytes()).ok();
+            match name {
+                Some(name) if !NEVER_FORWARD.contains(&name.as_str()) => Some(name),
+                Some(name) => {
+                    log::warn!("⚠️  Refusing to forward sensitive header '{}'", name);
+                    None
+                }
+                None => {
+                    log::warn!("⚠️  Ignoring invalid header name '{}' in FORWARD_HEADERS", n);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Parse `BACKEND_EXTRA_HEADERS` entries (`Name=value`) into static headers
+pub fn parse_extra_headers(entries: &[String]) -> Vec<(HeaderName, HeaderValue)> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let parsed = entry.split_once('=').and_then(|(name, value)| {
+                Some((
+                    HeaderName::from_bytes(name.trim().to_ascii_lowercase().as_bytes()).ok()?,
+                    HeaderValue::from_str(value.trim()).ok()?,
+                ))
+            });
+            if parsed.is_none() {
+                log::warn!("⚠️  Ignoring malformed BACKEND_EXTRA_HEADERS entry (expected Name=value)");
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Headers to add to the backend request: allowlisted client headers, then static extras
+pub fn backend_headers(
+    client_headers: &HeaderMap,
+    forward: &[HeaderName],
+    extra: &[(HeaderName, HeaderValue)],
+) -> HeaderMap {
+    let mut out = HeaderMap::new();
+    for name in forward {
+        for value in client_headers.get_all(name) {
+            out.append(name.clone(), value.clone());
+        }
+    }
+    for (name, value) in extra {
+        out.insert(name.clone(), value.clone());
+    }
+    out
+}
+
+/// Backend response headers worth keeping for diagnostics: request ids, server, model version and
+/// rate-limit state (`DIAGNOSTIC_HEADERS`, plus `x-ratelimit-*`)
+pub fn diagnostic_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            DIAGNOSTIC_HEADERS.contains(&name) || DIAGNOSTIC_HEADER_PREFIXES.iter().any(|p| name.starts_with(p))
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+/// Stable per-conversation value for cache-aware backends (`SESSION_HEADER`).
+///
+/// Claude Code's `metadata.user_id` carries its session id; without it the conversation is keyed
+/// by the client key, the system prompt and the first user message, which stay the same as the
+/// conversation grows. Only a hash is sent.
+pub fn session_hint(req: &OAIChatReq, owner: &str) -> HeaderValue {
+    let user_id = req.metadata.as_ref().and_then(|m| m.get("user_id")).and_then(|v| v.as_str());
+    let source = match user_id {
+        Some(id) => id.to_string(),
+        None => {
+            let first_user = req.messages.iter().position(|m| m.role == "user").map_or(0, |i| i + 1);
+            req.messages[..first_user].iter().map(|m| m.content.to_string()).collect::<Vec<_>>().join("\n")
+        }
+    };
+    let hash = conversation_hash(owner, &source);
+    HeaderValue::from_str(&format!("sess_{:016x}", hash)).expect("hex is a valid header value")
+}
+
+/// Keyed hash of a client key fingerprint and a conversation identity; stable across restarts
+/// only with `FINGERPRINT_SECRET`
+pub fn conversation_hash(owner: &str, source: &str) -> u64 {
+    keyed_hash(&[owner, source])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OAIMessage;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_forward_headers_drops_sensitive() {
+        let names = parse_forward_headers(&["X-Title".into(), "Authorization".into(), "http-referer".into()]);
+        let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+        assert_eq!(names, vec!["x-title", "http-referer"]);
+    }
+
+    #[test]
+    fn test_parse_extra_headers() {
+        let extra = parse_extra_headers(&["OpenAI-Organization=org-123".into(), "broken".into()]);
+        assert_eq!(extra.len(), 1);
+        assert_eq!(extra[0].0.as_str(), "openai-organization");
+        assert_eq!(extra[0].1, "org-123");
+    }
+
+    #[test]
+    fn test_diagnostic_headers() {
+        let mut backend = HeaderMap::new();
+        backend.insert("x-request-id", HeaderValue::from_static("req_123"));
+        backend.insert("x-ratelimit-remaining-tokens", HeaderValue::from_static("9000"));
+        backend.insert("set-cookie", HeaderValue::from_static("session=secret"));
+        backend.insert("content-type", HeaderValue::from_static("text/event-stream"));
+        let recorded = diagnostic_headers(&backend);
+        assert_eq!(recorded.keys().collect::<Vec<_>>(), ["x-ratelimit-remaining-tokens", "x-request-id"]);
+        assert_eq!(recorded["x-request-id"], "req_123");
+    }
+
+    #[test]
+    fn test_backend_headers_forwards_allowlisted_only() {
+        let mut client = HeaderMap::new();
+        client.insert("x-title", HeaderValue::from_static("My App"));
+        client.insert("x-other", HeaderValue::from_static("nope"));
+        let forward = parse_forward_headers(&["x-title".into()]);
+        let extra = parse_extra_headers(&["x-static=1".into()]);
+
+        let out = backend_headers(&client, &forward, &extra);
+        assert_eq!(out.get("x-title").unwrap(), "My App");
+        assert_eq!(out.get("x-static").unwrap(), "1");
+        assert!(out.get("x-other").is_none());
+    }
+
+    #[test]
+    fn test_extra_headers_override_forwarded() {
+        let mut client = HeaderMap::new();
+        client.insert("x-title", HeaderValue::from_static("client"));
+        let forward = parse_forward_headers(&["x-title".into()]);
+        let extra = parse_extra_headers(&["x-title=static".into()]);
+
+        let out = backend_headers(&client, &forward, &extra);
+        assert_eq!(out.get_all("x-title").iter().count(), 1);
+        assert_eq!(out.get("x-title").unwrap(), "static");
+    }
+
+    #[test]
+    fn test_session_hint_is_stable_across_turns() {
+        let msg = |role: &str, text: &str| OAIMessage {
+            role: role.into(),
+            content: json!(text),
+            tool_call_id: None,
+            tool_calls: None,
+            prefix: None,
+        };
+        let mut req = OAIChatReq { messages: vec![msg("system", "You are helpful"), msg("user", "hi")], ..Default::default() };
+        let first = session_hint(&req, "fp_a");
+        req.messages.extend([msg("assistant", "hello"), msg("user", "more")]);
+        assert_eq!(session_hint(&req, "fp_a"), first);
+        assert_ne!(session_hint(&req, "fp_b"), first, "different clients never share a session");
+
+        req.messages[1] = msg("user", "another conversation");
+        assert_ne!(session_hint(&req, "fp_a"), first);
+
+        req.metadata = Some(json!({"user_id": "user_x_account__session_1"}));
+        let tagged = session_hint(&req, "fp_a");
+        req.messages[1] = msg("user", "hi");
+        assert_eq!(session_hint(&req, "fp_a"), tagged, "metadata.user_id wins over the prompt");
+    }
+}
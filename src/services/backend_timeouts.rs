@@ -0,0 +1,124 @@
+use std::{fmt, time::Duration};
+use serde::Serialize;
+use crate::config::{env_or, env_parse};
+use crate::constants::{DEFAULT_BACKEND_CONNECT_TIMEOUT_SECS, DEFAULT_BACKEND_TIMEOUT_SECS};
+
+/// Which backend deadline ran out, counted per backend in `/admin/stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendTimeout {
+    /// No TCP/TLS connection within `BACKEND_CONNECT_TIMEOUT_SECS`
+    Connect,
+    /// Connected, but no streamed event within `BACKEND_FIRST_TOKEN_TIMEOUT_SECS` (model loading, queueing)
+    FirstToken,
+    /// The whole exchange took longer than `BACKEND_TOTAL_TIMEOUT_SECS`
+    Total,
+}
+
+impl BackendTimeout {
+    /// Error code sent to the client
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::Connect => "backend_connect_timeout",
+            Self::FirstToken => "backend_first_token_timeout",
+            Self::Total => "backend_total_timeout",
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::FirstToken => "first_token",
+            Self::Total => "total",
+        }
+    }
+}
+
+/// Backend deadlines; connect and total are enforced by the HTTP client, first-token by the handler
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackendTimeouts {
+    pub connect: Duration,
+    /// From sending the request to the first streamed event; `None` when unset
+    pub first_token: Option<Duration>,
+    /// Whole request including the streamed body (`BACKEND_TOTAL_TIMEOUT_SECS`, formerly `BACKEND_TIMEOUT_SECS`)
+    pub total: Duration,
+}
+
+impl Default for BackendTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(DEFAULT_BACKEND_CONNECT_TIMEOUT_SECS),
+            first_token: None,
+            total: Duration::from_secs(DEFAULT_BACKEND_TIMEOUT_SECS),
+        }
+    }
+}
+
+impl BackendTimeouts {
+    pub fn from_env() -> Self {
+        let legacy_total = env_or("BACKEND_TIMEOUT_SECS", DEFAULT_BACKEND_TIMEOUT_SECS);
+        Self {
+            connect: Duration::from_secs(env_or("BACKEND_CONNECT_TIMEOUT_SECS", DEFAULT_BACKEND_CONNECT_TIMEOUT_SECS)),
+            first_token: env_parse::<u64>("BACKEND_FIRST_TOKEN_TIMEOUT_SECS").filter(|&s| s > 0).map(Duration::from_secs),
+            total: Duration::from_secs(env_or("BACKEND_TOTAL_TIMEOUT_SECS", legacy_total)),
+        }
+    }
+}
+
+/// Why a backend request produced no response
+#[derive(Debug)]
+pub enum BackendSendError {
+    Timeout(BackendTimeout),
+    Failed(reqwest::Error),
+}
+
+impl From<reqwest::Error> for BackendSendError {
+    fn from(e: reqwest::Error) -> Self {
+        match request_timeout(&e) {
+            Some(timeout) => Self::Timeout(timeout),
+            None => Self::Failed(e),
+        }
+    }
+}
+
+impl fmt::Display for BackendSendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout(timeout) => write!(f, "{} timeout", timeout.as_str()),
+            Self::Failed(e) => e.fmt(f),
+        }
+    }
+}
+
+/// The HTTP client's deadline behind a request error: a timed-out connect, or the total timeout
+pub fn request_timeout(e: &reqwest::Error) -> Option<BackendTimeout> {
+    if !e.is_timeout() {
+        return None;
+    }
+    Some(if e.is_connect() { BackendTimeout::Connect } else { BackendTimeout::Total })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_total_timeout_is_classified() {
+        // Accepts the connection but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _held = listener.accept().await;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        let client = reqwest::Client::builder().no_proxy().timeout(Duration::from_millis(100)).build().unwrap();
+        let e = client.get(format!("http://{}/", addr)).send().await.unwrap_err();
+        assert!(matches!(BackendSendError::from(e), BackendSendError::Timeout(BackendTimeout::Total)));
+    }
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(BackendTimeout::FirstToken.code(), "backend_first_token_timeout");
+        assert_eq!(serde_json::to_value(BackendTimeout::FirstToken).unwrap(), "first_token");
+    }
+}
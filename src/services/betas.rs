@@ -0,0 +1,156 @@
+//! Anthropic beta flags and API versions.
+//!
+//! Betas arrive in `anthropic-beta` headers or, for SDKs that put them in the URL, as
+//! `?beta=<name>` query parameters (`?beta=true` alone only marks a beta client). Every beta the
+//! proxy knows is listed in `BETAS` with what it does, so supporting a new one, or turning one
+//! off, is a change to that table.
+use axum::{extract::Query, http::{HeaderMap, Uri}};
+use crate::services::DroppedFeature;
+
+/// Request header naming the API version the client was written against
+pub const VERSION_HEADER: &str = "anthropic-version";
+
+/// API versions of `/v1/messages` and `/v1/messages/validate`, newest first. Only `2023-06-01`
+/// is listed: the proxy doesn't implement the `2023-01-01` streaming format
+pub const MESSAGES_API_VERSIONS: &[&str] = &["2023-06-01"];
+
+/// API versions of `/v1/messages/count_tokens`, which didn't exist before `2023-06-01`
+pub const COUNT_TOKENS_API_VERSIONS: &[&str] = &["2023-06-01"];
+
+/// Betas whose behavior the proxy implements itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beta {
+    /// Compact tool definitions (see `SchemaCleaning::compact`)
+    TokenEfficientTools,
+}
+
+/// How the proxy treats a beta
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BetaSupport {
+    /// The proxy changes its behavior for it
+    Applied(Beta),
+    /// Works without the flag: the proxy translates the feature for every request
+    Native,
+    /// Accepted but has no effect on OpenAI-compatible backends
+    Unsupported(&'static str),
+}
+
+struct BetaSpec {
+    /// Beta name without its date suffix
+    prefix: &'static str,
+    support: BetaSupport,
+}
+
+const BETAS: &[BetaSpec] = &[
+    BetaSpec { prefix: "token-efficient-tools", support: BetaSupport::Applied(Beta::TokenEfficientTools) },
+    BetaSpec { prefix: "interleaved-thinking", support: BetaSupport::Native },
+    BetaSpec { prefix: "fine-grained-tool-streaming", support: BetaSupport::Native },
+    BetaSpec { prefix: "prompt-caching", support: BetaSupport::Native },
+    BetaSpec { prefix: "files-api", support: BetaSupport::Native },
+    BetaSpec { prefix: "code-execution", support: BetaSupport::Native },
+    BetaSpec { prefix: "context-1m", support: BetaSupport::Unsupported("the context window is the backend model's") },
+    BetaSpec { prefix: "output-128k", support: BetaSupport::Unsupported("the output limit is the backend model's") },
+];
+
+fn spec(beta: &str) -> Option<&'static BetaSpec> {
+    BETAS.iter().find(|spec| beta.starts_with(spec.prefix))
+}
+
+impl Beta {
+    /// The client enabled this beta and the proxy applies it
+    pub fn enabled(self, betas: &[String]) -> bool {
+        betas.iter().any(|beta| spec(beta).is_some_and(|spec| spec.support == BetaSupport::Applied(self)))
+    }
+}
+
+/// Betas the client enabled through `anthropic-beta` headers and `?beta=` query parameters, lowercased
+pub fn anthropic_betas(headers: &HeaderMap, uri: &Uri) -> Vec<String> {
+    let query = Query::<Vec<(String, String)>>::try_from_uri(uri).map(|Query(params)| params).unwrap_or_default();
+    let query_betas = query
+        .into_iter()
+        .filter(|(name, _)| name == "beta" || name == "betas")
+        .map(|(_, value)| value)
+        // `?beta=true` is how the Anthropic SDKs mark their beta client, not a beta name
+        .filter(|value| !matches!(value.trim().to_ascii_lowercase().as_str(), "true" | "false" | "1" | "0"));
+    let mut betas: Vec<String> = headers
+        .get_all("anthropic-beta")
+        .iter()
+        .filter_map(|value| value.to_str().ok().map(str::to_string))
+        .chain(query_betas)
+        .flat_map(|value| value.split(',').map(|beta| beta.trim().to_ascii_lowercase()).collect::<Vec<_>>())
+        .filter(|beta| !beta.is_empty())
+        .collect();
+    let mut seen = std::collections::HashSet::new();
+    betas.retain(|beta| seen.insert(beta.clone()));
+    betas
+}
+
+/// Enabled betas that change nothing, for the dropped feature report
+pub fn dropped_betas(betas: &[String]) -> Vec<DroppedFeature> {
+    betas
+        .iter()
+        .filter_map(|beta| match spec(beta).map(|spec| spec.support) {
+            Some(BetaSupport::Applied(_) | BetaSupport::Native) => None,
+            Some(BetaSupport::Unsupported(reason)) => Some(DroppedFeature::new(format!("anthropic-beta: {}", beta), reason)),
+            None => Some(DroppedFeature::new(format!("anthropic-beta: {}", beta), "unknown to the proxy")),
+        })
+        .collect()
+}
+
+/// The newest of a route's `supported` versions (newest first) that isn't newer than the one the
+/// client asked for; the newest without `anthropic-version`
+pub fn negotiate_version(headers: &HeaderMap, supported: &[&'static str]) -> Result<&'static str, String> {
+    let Some(requested) = headers.get(VERSION_HEADER) else {
+        return Ok(supported[0]);
+    };
+    let requested = requested.to_str().unwrap_or_default().trim();
+    let is_date = requested.len() == 10 && requested.bytes().enumerate().all(|(i, b)| if i == 4 || i == 7 { b == b'-' } else { b.is_ascii_digit() });
+    // ISO dates compare correctly as strings
+    supported
+        .iter()
+        .find(|version| is_date && **version <= requested)
+        .copied()
+        .ok_or_else(|| format!("{} '{}' is not supported; supported versions: {}", VERSION_HEADER, requested, supported.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_anthropic_betas_from_headers_and_query() {
+        let mut client = HeaderMap::new();
+        client.append("anthropic-beta", HeaderValue::from_static("Token-Efficient-Tools-2025-02-19, interleaved-thinking-2025-05-14"));
+        client.append("anthropic-beta", HeaderValue::from_static("prompt-caching-2024-07-31,"));
+        let uri: Uri = "/v1/messages?beta=true".parse().unwrap();
+        assert_eq!(anthropic_betas(&client, &uri), [
+            "token-efficient-tools-2025-02-19",
+            "interleaved-thinking-2025-05-14",
+            "prompt-caching-2024-07-31",
+        ]);
+
+        let uri: Uri = "/v1/messages?beta=token-efficient-tools-2025-02-19,context-1m-2025-08-07&beta=mystery-2030-01-01".parse().unwrap();
+        let betas = anthropic_betas(&client, &uri);
+        assert_eq!(betas.len(), 5, "duplicates are dropped");
+        assert!(Beta::TokenEfficientTools.enabled(&betas));
+        assert!(!Beta::TokenEfficientTools.enabled(&["interleaved-thinking-2025-05-14".into()]));
+        let dropped: Vec<_> = dropped_betas(&betas).into_iter().map(|d| d.feature).collect();
+        assert_eq!(dropped, ["anthropic-beta: context-1m-2025-08-07", "anthropic-beta: mystery-2030-01-01"]);
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        let version = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(VERSION_HEADER, HeaderValue::from_str(value).unwrap());
+            negotiate_version(&headers, MESSAGES_API_VERSIONS)
+        };
+        assert_eq!(negotiate_version(&HeaderMap::new(), MESSAGES_API_VERSIONS), Ok("2023-06-01"));
+        assert_eq!(version("2023-06-01"), Ok("2023-06-01"));
+        assert_eq!(version("2025-01-01"), Ok("2023-06-01"), "newer clients get the newest version");
+        assert!(version("2023-03-15").is_err(), "older than every supported version");
+        assert!(version("2022-12-01").is_err());
+        assert!(version("latest").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::models::OAIChatReq;
+
+/// Request parameters that can be stripped for backends or models that reject them
+pub const STRIPPABLE_PARAMS: &[&str] = &[
+    "temperature",
+    "top_p",
+    "top_k",
+    "stop",
+    "thinking",
+    "tools",
+    "parallel_tool_calls",
+    "metadata",
+    "logprobs",
+];
+
+/// Remove parameters the target doesn't support, returning what was stripped.
+///
+/// `unsupported` is the backend's static profile (`UNSUPPORTED_PARAMS`). `model_features` is
+/// the model's `supported_features` from the model cache; when it is known and non-empty,
+/// tools and thinking are only kept if the model advertises them.
+pub fn strip_unsupported_params(
+    oai: &mut OAIChatReq,
+    unsupported: &[String],
+    model_features: Option<&[String]>,
+) -> Vec<&'static str> {
+    let has_feature = |names: &[&str]| {
+        model_features
+            .filter(|features| !features.is_empty())
+            .is_none_or(|features| features.iter().any(|f| names.iter().any(|n| f.eq_ignore_ascii_case(n))))
+    };
+    let blocked = |param: &str| unsupported.iter().any(|p| p.eq_ignore_ascii_case(param));
+
+    let mut stripped = Vec::new();
+    let mut strip = |param: &'static str, present: bool| {
+        if present {
+            stripped.push(param);
+        }
+        present
+    };
+
+    if blocked("temperature") && strip("temperature", oai.temperature.is_some()) {
+        oai.temperature = None;
+    }
+    if blocked("top_p") && strip("top_p", oai.top_p.is_some()) {
+        oai.top_p = None;
+    }
+    if blocked("top_k") && strip("top_k", oai.top_k.is_some()) {
+        oai.top_k = None;
+    }
+    if blocked("stop") && strip("stop", oai.stop.is_some()) {
+        oai.stop = None;
+    }
+    if (blocked("thinking") || !has_feature(&["thinking", "extended_thinking", "reasoning"]))
+        && strip("thinking", oai.thinking.is_some())
+    {
+        oai.thinking = None;
+    }
+    let has_tools = oai.tools.as_ref().is_some_and(|t| !t.is_empty());
+    if (blocked("tools") || !has_feature(&["tools", "tool_use", "function_calling"])) && strip("tools", has_tools) {
+        // tool_choice / parallel_tool_calls are meaningless without tools
+        oai.tools = None;
+        oai.tool_choice = None;
+        oai.parallel_tool_calls = None;
+    }
+    if blocked("parallel_tool_calls") && strip("parallel_tool_calls", oai.parallel_tool_calls.is_some()) {
+        oai.parallel_tool_calls = None;
+    }
+    if blocked("metadata") && strip("metadata", oai.metadata.is_some()) {
+        oai.metadata = None;
+    }
+    if blocked("logprobs") && strip("logprobs", oai.logprobs.is_some()) {
+        oai.logprobs = None;
+        oai.top_logprobs = None;
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{OAIFunction, OAITool};
+    use serde_json::json;
+
+    fn request() -> OAIChatReq {
+        OAIChatReq {
+            model: "m".into(),
+            messages: vec![],
+            max_tokens: Some(100),
+            temperature: Some(0.5),
+            top_p: None,
+            top_k: Some(40),
+            stop: None,
+            tools: Some(vec![OAITool {
+                type_: "function".into(),
+                function: OAIFunction {
+                    name: "read".into(),
+                    description: None,
+                    parameters: json!({}),
+                    strict: None,
+                },
+                cache_control: None,
+            }]),
+            tool_choice: Some(json!("auto")),
+            thinking: Some(json!({"type": "enabled", "budget_tokens": 1024})),
+            parallel_tool_calls: Some(true),
+            metadata: None,
+            stream: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_profile_strips_listed_params() {
+        let mut req = request();
+        let stripped = strip_unsupported_params(&mut req, &["top_k".into(), "metadata".into()], None);
+        assert_eq!(stripped, vec!["top_k"]);
+        assert_eq!(req.top_k, None);
+        assert_eq!(req.temperature, Some(0.5));
+        assert!(req.thinking.is_some());
+    }
+
+    #[test]
+    fn test_model_features_drop_tools_and_thinking() {
+        let mut req = request();
+        let features = vec!["json_mode".to_string()];
+        let stripped = strip_unsupported_params(&mut req, &[], Some(&features));
+        assert_eq!(stripped, vec!["thinking", "tools"]);
+        assert!(req.tools.is_none());
+        assert!(req.tool_choice.is_none());
+        assert!(req.parallel_tool_calls.is_none());
+    }
+
+    #[test]
+    fn test_unknown_features_keep_everything() {
+        let mut req = request();
+        assert!(strip_unsupported_params(&mut req, &[], Some(&[])).is_empty());
+        assert!(strip_unsupported_params(&mut req, &[], None).is_empty());
+        assert!(req.tools.is_some());
+    }
+
+    #[test]
+    fn test_advertised_features_are_kept() {
+        let mut req = request();
+        let features = vec!["tools".to_string(), "reasoning".to_string()];
+        assert!(strip_unsupported_params(&mut req, &[], Some(&features)).is_empty());
+    }
+}
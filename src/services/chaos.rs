@@ -0,0 +1,261 @@
+//! Fault injection for resilience testing: artificial backend latency, 5xx bursts, streams
+//! dropped mid-generation and malformed SSE chunks.
+//!
+//! Disabled unless `CHAOS_ENABLED=true`. Defaults come from the `CHAOS_*` variables; each request
+//! can override them with the `x-proxy-chaos` header, e.g. `x-proxy-chaos: error_rate=1,error_status=529`
+//! or `x-proxy-chaos: drop_rate=1,drop_after=5`.
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    pin::Pin,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use axum::http::HeaderMap;
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use crate::config::{env_or, env_parse};
+use crate::constants::*;
+
+pub const CHAOS_HEADER: &str = "x-proxy-chaos";
+
+/// SSE event whose JSON is cut off mid-object, as a backend might send on a torn write
+const MALFORMED_EVENT: &[u8] = b"data: {\"id\":\"chaos\",\"object\":\"chat.completion.chunk\",\"choices\":[{\"delta\":{\"content\":\"\n\n";
+
+pub type ChaosStream = Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>>;
+
+/// Faults to inject into one request
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChaosFaults {
+    /// Delay before the backend request is sent
+    pub latency: Duration,
+    /// Probability that the backend call is replaced by an error response
+    pub error_rate: f64,
+    /// Status of injected error responses
+    pub error_status: u16,
+    /// Probability that the stream is cut off mid-generation
+    pub drop_rate: f64,
+    /// Backend chunks delivered before a dropped stream ends (random when unset)
+    pub drop_after: Option<usize>,
+    /// Probability that each backend chunk is preceded by a malformed SSE event
+    pub malformed_rate: f64,
+}
+
+impl Default for ChaosFaults {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            error_rate: 0.0,
+            error_status: DEFAULT_CHAOS_ERROR_STATUS,
+            drop_rate: 0.0,
+            drop_after: None,
+            malformed_rate: 0.0,
+        }
+    }
+}
+
+impl ChaosFaults {
+    fn from_env() -> Self {
+        Self {
+            latency: Duration::from_millis(env_or("CHAOS_LATENCY_MS", 0)),
+            error_rate: env_or("CHAOS_ERROR_RATE", 0.0),
+            error_status: env_or("CHAOS_ERROR_STATUS", DEFAULT_CHAOS_ERROR_STATUS),
+            drop_rate: env_or("CHAOS_DROP_RATE", 0.0),
+            drop_after: env_parse("CHAOS_DROP_AFTER"),
+            malformed_rate: env_or("CHAOS_MALFORMED_RATE", 0.0),
+        }
+    }
+
+    /// Apply `key=value` overrides from the chaos header; unknown keys and bad values are ignored
+    fn apply_header(&mut self, header: &str) {
+        for (key, value) in header.split(',').filter_map(|pair| pair.split_once('=')) {
+            let (key, value) = (key.trim(), value.trim());
+            let applied = match key {
+                "latency_ms" => value.parse().map(|ms| self.latency = Duration::from_millis(ms)).is_ok(),
+                "error_rate" => value.parse().map(|r| self.error_rate = r).is_ok(),
+                "error_status" => value.parse().map(|s| self.error_status = s).is_ok(),
+                "drop_rate" => value.parse().map(|r| self.drop_rate = r).is_ok(),
+                "drop_after" => value.parse().map(|n| self.drop_after = Some(n)).is_ok(),
+                "malformed_rate" => value.parse().map(|r| self.malformed_rate = r).is_ok(),
+                _ => false,
+            };
+            if !applied {
+                log::warn!("⚠️  Ignoring invalid {} entry '{}={}'", CHAOS_HEADER, key, value);
+            }
+        }
+    }
+
+    /// Wrap the backend byte stream with the drop and malformed-chunk faults
+    pub fn wrap_stream<S>(&self, stream: S, chaos: &Chaos) -> ChaosStream
+    where
+        S: Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+    {
+        let drop_after = chance(self.drop_rate).then(|| {
+            let after = self.drop_after.unwrap_or_else(|| 1 + (random_unit() * CHAOS_MAX_DROP_AFTER as f64) as usize);
+            log::warn!("🐒 Chaos: dropping backend stream after {} chunk(s)", after);
+            chaos.dropped.fetch_add(1, Ordering::Relaxed);
+            after
+        });
+        let malformed_rate = self.malformed_rate;
+        let stream = stream.enumerate().map(move |(i, item)| {
+            if drop_after.is_some_and(|after| i >= after) {
+                return None;
+            }
+            Some(item.map(|chunk| if chance(malformed_rate) { corrupt(&chunk) } else { chunk }))
+        });
+        Box::pin(stream.take_while(|item| std::future::ready(item.is_some())).filter_map(std::future::ready))
+    }
+}
+
+/// Prefix a chunk with a truncated SSE event, keeping the real data intact after it
+fn corrupt(chunk: &Bytes) -> Bytes {
+    log::warn!("🐒 Chaos: injecting malformed SSE chunk");
+    let mut out = BytesMut::with_capacity(MALFORMED_EVENT.len() + chunk.len());
+    out.extend_from_slice(MALFORMED_EVENT);
+    out.extend_from_slice(chunk);
+    out.freeze()
+}
+
+/// Uniform random number in `[0, 1)`; chaos doesn't need a quality RNG
+fn random_unit() -> f64 {
+    let mut h = RandomState::new().build_hasher();
+    h.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+    (h.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// True with probability `rate`
+pub(crate) fn chance(rate: f64) -> bool {
+    rate >= 1.0 || (rate > 0.0 && random_unit() < rate)
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ChaosSnapshot {
+    pub errors: u64,
+    pub dropped_streams: u64,
+}
+
+/// Fault injection state shared across requests (`CHAOS_ENABLED`)
+pub struct Chaos {
+    defaults: ChaosFaults,
+    /// Consecutive requests failed once an error is injected (`CHAOS_ERROR_BURST`)
+    burst: u32,
+    burst_remaining: AtomicU32,
+    errors: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Chaos {
+    pub fn new(defaults: ChaosFaults, burst: u32) -> Self {
+        Self {
+            defaults,
+            burst: burst.max(1),
+            burst_remaining: AtomicU32::new(0),
+            errors: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        if !env_or("CHAOS_ENABLED", false) {
+            return None;
+        }
+        let chaos = Self::new(ChaosFaults::from_env(), env_or("CHAOS_ERROR_BURST", 1));
+        log::warn!("🐒 Chaos fault injection ENABLED (defaults: {:?}, burst {}) - not for production", chaos.defaults, chaos.burst);
+        Some(chaos)
+    }
+
+    /// Faults for one request: the configured defaults with `x-proxy-chaos` overrides applied
+    pub fn faults(&self, headers: &HeaderMap) -> ChaosFaults {
+        let mut faults = self.defaults.clone();
+        if let Some(header) = headers.get(CHAOS_HEADER).and_then(|h| h.to_str().ok()) {
+            faults.apply_header(header);
+        }
+        faults
+    }
+
+    /// Synthetic backend error response, if this request falls in (or starts) a 5xx burst
+    pub fn injected_error(&self, faults: &ChaosFaults) -> Option<reqwest::Response> {
+        let in_burst = self
+            .burst_remaining
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+            .is_ok();
+        if !in_burst {
+            if !chance(faults.error_rate) {
+                return None;
+            }
+            self.burst_remaining.store(self.burst - 1, Ordering::Release);
+        }
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        log::warn!("🐒 Chaos: injecting backend error {}", faults.error_status);
+        let body = serde_json::json!({
+            "error": { "message": "Injected by proxy fault injection", "type": "chaos", "code": faults.error_status }
+        });
+        let response = axum::http::Response::builder()
+            .status(faults.error_status)
+            .header("content-type", "application/json")
+            .body(body.to_string())
+            .ok()?;
+        Some(response.into())
+    }
+
+    pub fn snapshot(&self) -> ChaosSnapshot {
+        ChaosSnapshot {
+            errors: self.errors.load(Ordering::Relaxed),
+            dropped_streams: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn chunks(n: usize) -> impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static {
+        futures::stream::iter((0..n).map(|i| Ok(Bytes::from(format!("data: {}\n\n", i)))))
+    }
+
+    #[test]
+    fn test_header_overrides_defaults() {
+        let chaos = Chaos::new(ChaosFaults { latency: Duration::from_millis(100), ..Default::default() }, 1);
+        let mut headers = HeaderMap::new();
+        headers.insert(CHAOS_HEADER, HeaderValue::from_static("error_rate=1, error_status=529,drop_after=x"));
+        let faults = chaos.faults(&headers);
+        assert_eq!(faults.latency, Duration::from_millis(100));
+        assert_eq!((faults.error_rate, faults.error_status, faults.drop_after), (1.0, 529, None));
+        assert!(chaos.faults(&HeaderMap::new()).error_rate == 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_error_burst() {
+        let chaos = Chaos::new(ChaosFaults::default(), 3);
+        let always = ChaosFaults { error_rate: 1.0, error_status: 503, ..Default::default() };
+        assert!(chaos.injected_error(&ChaosFaults::default()).is_none());
+        let res = chaos.injected_error(&always).unwrap();
+        assert_eq!(res.status().as_u16(), 503);
+        assert!(res.text().await.unwrap().contains("chaos"));
+        // The rest of the burst fails even without an error rate
+        assert!(chaos.injected_error(&ChaosFaults::default()).is_some());
+        assert!(chaos.injected_error(&ChaosFaults::default()).is_some());
+        assert!(chaos.injected_error(&ChaosFaults::default()).is_none());
+        assert_eq!(chaos.snapshot().errors, 3);
+    }
+
+    #[tokio::test]
+    async fn test_wrap_stream_drops_and_corrupts() {
+        let chaos = Chaos::new(ChaosFaults::default(), 1);
+        let drop = ChaosFaults { drop_rate: 1.0, drop_after: Some(2), ..Default::default() };
+        let out: Vec<_> = drop.wrap_stream(chunks(5), &chaos).collect().await;
+        assert_eq!(out.len(), 2);
+        assert_eq!(chaos.snapshot().dropped_streams, 1);
+
+        let malformed = ChaosFaults { malformed_rate: 1.0, ..Default::default() };
+        let out: Vec<_> = malformed.wrap_stream(chunks(2), &chaos).collect().await;
+        let first = out[0].as_ref().unwrap();
+        assert!(first.starts_with(MALFORMED_EVENT) && first.ends_with(b"data: 0\n\n"));
+
+        let out: Vec<_> = ChaosFaults::default().wrap_stream(chunks(3), &chaos).collect().await;
+        assert_eq!(out.len(), 3);
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+use axum::http::{header::USER_AGENT, HeaderMap};
+
+/// Broad category of the calling client
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ClientKind {
+    /// Claude Code CLI (`claude-cli/<version>` user agent or `x-app: cli`)
+    ClaudeCode,
+    /// An official Anthropic SDK, tagged with its language (`python`, `js`, ...)
+    Sdk(String),
+    #[default]
+    Unknown,
+}
+
+/// Client classification derived from `user-agent` / `x-app` / `x-stainless-*` headers,
+/// used to tag metrics and usage records so issues can be tied to specific client releases
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientInfo {
+    pub kind: ClientKind,
+    pub version: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+        let user_agent = header(USER_AGENT.as_str()).unwrap_or("");
+
+        // Claude Code: "claude-cli/1.0.45 (external, cli)"
+        if let Some(rest) = user_agent.strip_prefix("claude-cli/") {
+            return Self {
+                kind: ClientKind::ClaudeCode,
+                version: rest.split_whitespace().next().map(String::from),
+            };
+        }
+        if header("x-app") == Some("cli") {
+            return Self {
+                kind: ClientKind::ClaudeCode,
+                version: None,
+            };
+        }
+
+        // Stainless-generated SDKs send their language and package version explicitly
+        if let Some(lang) = header("x-stainless-lang").filter(|l| !l.is_empty()) {
+            return Self {
+                kind: ClientKind::Sdk(lang.to_ascii_lowercase()),
+                version: header("x-stainless-package-version").map(String::from),
+            };
+        }
+
+        // Fallback: "Anthropic/Python 0.40.0"
+        if let Some(rest) = user_agent.strip_prefix("Anthropic/") {
+            let mut parts = rest.split_whitespace();
+            if let Some(lang) = parts.next() {
+                return Self {
+                    kind: ClientKind::Sdk(lang.to_ascii_lowercase()),
+                    version: parts.next().map(String::from),
+                };
+            }
+        }
+
+        Self::default()
+    }
+}
+
+impl fmt::Display for ClientInfo {
+    /// Short label such as `claude-code/1.0.45`, `sdk-python/0.40.0` or `unknown`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ClientKind::ClaudeCode => write!(f, "claude-code")?,
+            ClientKind::Sdk(lang) => write!(f, "sdk-{}", lang)?,
+            ClientKind::Unknown => return write!(f, "unknown"),
+        }
+        if let Some(version) = &self.version {
+            write!(f, "/{}", version)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for (k, v) in pairs {
+            h.insert(*k, v.parse().unwrap());
+        }
+        h
+    }
+
+    #[test]
+    fn test_claude_code_user_agent() {
+        let info = ClientInfo::from_headers(&headers(&[("user-agent", "claude-cli/1.0.45 (external, cli)")]));
+        assert_eq!(info.kind, ClientKind::ClaudeCode);
+        assert_eq!(info.to_string(), "claude-code/1.0.45");
+    }
+
+    #[test]
+    fn test_claude_code_x_app_without_version() {
+        let info = ClientInfo::from_headers(&headers(&[("x-app", "cli")]));
+        assert_eq!(info.to_string(), "claude-code");
+    }
+
+    #[test]
+    fn test_stainless_sdk_headers() {
+        let info = ClientInfo::from_headers(&headers(&[
+            ("user-agent", "Anthropic/Python 0.40.0"),
+            ("x-stainless-lang", "python"),
+            ("x-stainless-package-version", "0.40.0"),
+        ]));
+        assert_eq!(info.kind, ClientKind::Sdk("python".into()));
+        assert_eq!(info.to_string(), "sdk-python/0.40.0");
+    }
+
+    #[test]
+    fn test_sdk_user_agent_fallback() {
+        let info = ClientInfo::from_headers(&headers(&[("user-agent", "Anthropic/JS 0.30.1")]));
+        assert_eq!(info.to_string(), "sdk-js/0.30.1");
+    }
+
+    #[test]
+    fn test_unknown_client() {
+        let info = ClientInfo::from_headers(&headers(&[("user-agent", "curl/8.5.0")]));
+        assert_eq!(info, ClientInfo::default());
+        assert_eq!(info.to_string(), "unknown");
+    }
+}
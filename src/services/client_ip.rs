@@ -0,0 +1,209 @@
+use std::{
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use crate::models::App;
+
+/// An address or CIDR range of a trusted reverse proxy (`TRUSTED_PROXIES`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpNet {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().map_err(|_| ())?, Some(prefix.parse::<u8>().map_err(|_| ())?)),
+            None => (s.trim().parse::<IpAddr>().map_err(|_| ())?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        match prefix {
+            Some(prefix) if prefix > max => Err(()),
+            prefix => Ok(Self { addr, prefix: prefix.unwrap_or(max) }),
+        }
+    }
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped IPv6 peers (dual-stack listeners) match IPv4 ranges
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => masked(u32::from(net) as u128, 32, self.prefix) == masked(u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => masked(u128::from(net), 128, self.prefix) == masked(u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn masked(bits: u128, width: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        bits >> (width - prefix)
+    }
+}
+
+/// Parse `TRUSTED_PROXIES` entries, dropping (and logging) malformed ones
+pub fn parse_trusted_proxies(entries: &[String]) -> Vec<IpNet> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let net = entry.parse().ok();
+            if net.is_none() {
+                log::warn!("⚠️  Ignoring malformed TRUSTED_PROXIES entry '{}' (expected an IP or CIDR range)", entry);
+            }
+            net
+        })
+        .collect()
+}
+
+/// Address of the original client.
+///
+/// The peer address is used unless it is a trusted proxy; then the `Forwarded` (RFC 7239) or
+/// `X-Forwarded-For` chain is walked from the nearest hop back, skipping further trusted proxies,
+/// so a client can't spoof its address by sending the header itself.
+pub fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+    let hops = forwarded_for(headers);
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        let Some(ip) = hop else {
+            // Obfuscated or unparsable hop: nothing before it can be trusted
+            break;
+        };
+        client = *ip;
+        if !is_trusted(*ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Client chain from `Forwarded` `for=` parameters, or else `X-Forwarded-For`, nearest hop last
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<Option<IpAddr>> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim().eq_ignore_ascii_case("for").then(|| parse_node(value.trim().trim_matches('"')))
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+/// `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` or `[2001:db8::1]:80`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// Extractor for the original client's address, honoring `TRUSTED_PROXIES`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<App> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, app: &App) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |ConnectInfo(addr)| addr.ip());
+        Ok(Self(resolve_client_ip(peer, &parts.headers, &app.config.trusted_proxies)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_ip_net_contains() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!("fd00::/8".parse::<IpNet>().unwrap().contains(ip("fd12::1")));
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains(ip("8.8.8.8")));
+        assert!("127.0.0.1".parse::<IpNet>().unwrap().contains(ip("127.0.0.1")));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_untrusted_peer_is_the_client() {
+        let trusted = parse_trusted_proxies(&["10.0.0.0/8".into()]);
+        let spoofed = headers(&[("x-forwarded-for", "1.1.1.1")]);
+        assert_eq!(resolve_client_ip(ip("203.0.113.9"), &spoofed, &trusted), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn test_forwarded_chain_skips_trusted_hops() {
+        let trusted = parse_trusted_proxies(&["10.0.0.0/8".into(), "broken".into()]);
+        // The client prepended a fake entry; the rightmost untrusted hop is the real client
+        let xff = headers(&[("x-forwarded-for", "6.6.6.6, 203.0.113.9"), ("x-forwarded-for", "10.0.0.2")]);
+        assert_eq!(resolve_client_ip(ip("10.0.0.1"), &xff, &trusted), ip("203.0.113.9"));
+
+        let forwarded = headers(&[
+            ("forwarded", r#"for="[2001:db8::7]:4711";proto=https, for=10.0.0.2"#),
+            ("x-forwarded-for", "9.9.9.9"),
+        ]);
+        assert_eq!(resolve_client_ip(ip("10.0.0.1"), &forwarded, &trusted), ip("2001:db8::7"));
+
+        assert_eq!(resolve_client_ip(ip("10.0.0.1"), &HeaderMap::new(), &trusted), ip("10.0.0.1"));
+        let obfuscated = headers(&[("forwarded", "for=_hidden, for=10.0.0.2")]);
+        assert_eq!(resolve_client_ip(ip("10.0.0.1"), &obfuscated, &trusted), ip("10.0.0.2"));
+    }
+}
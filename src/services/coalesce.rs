@@ -0,0 +1,170 @@
+use std::time::{Duration, Instant};
+use crate::services::transform::{BlockDelta, StreamEvent, TextDeltaKind};
+
+/// Flush thresholds for delta coalescing
+#[derive(Debug, Clone, Copy)]
+pub struct CoalesceConfig {
+    /// Flush once the buffered delta text reaches this many bytes
+    pub max_bytes: usize,
+    /// Flush once the oldest buffered delta has waited this long
+    pub max_delay: Duration,
+}
+
+/// A `content_block_delta` being accumulated
+struct PendingDelta {
+    index: u64,
+    kind: TextDeltaKind,
+    text: String,
+    since: Instant,
+}
+
+impl PendingDelta {
+    fn into_event(self) -> StreamEvent {
+        StreamEvent::block_delta(self.index, BlockDelta::text_of(self.kind, &self.text))
+    }
+}
+
+/// Merges consecutive text/thinking/tool-argument deltas for the same content block.
+///
+/// Backends that stream one token per SSE event would otherwise produce one
+/// `content_block_delta` per token. Any other event flushes the buffer first, so event
+/// order is preserved.
+pub struct DeltaCoalescer {
+    config: CoalesceConfig,
+    pending: Option<PendingDelta>,
+}
+
+impl DeltaCoalescer {
+    pub fn new(config: CoalesceConfig) -> Self {
+        Self { config, pending: None }
+    }
+
+    /// Offer an outgoing event; returns the events that are ready to send, in order
+    pub fn push(&mut self, event: StreamEvent) -> Vec<StreamEvent> {
+        let mut ready = Vec::new();
+        let Some((index, kind, text)) = mergeable_delta(&event) else {
+            ready.extend(self.take());
+            ready.push(event);
+            return ready;
+        };
+
+        match &mut self.pending {
+            Some(p) if p.index == index && p.kind == kind => p.text.push_str(text),
+            _ => {
+                ready.extend(self.take());
+                self.pending = Some(PendingDelta {
+                    index,
+                    kind,
+                    text: text.to_string(),
+                    since: Instant::now(),
+                });
+            }
+        }
+
+        if self.pending.as_ref().is_some_and(|p| {
+            p.text.len() >= self.config.max_bytes || p.since.elapsed() >= self.config.max_delay
+        }) {
+            ready.extend(self.take());
+        }
+        ready
+    }
+
+    /// Remove the buffered delta, if any
+    pub fn take(&mut self) -> Option<StreamEvent> {
+        self.pending.take().map(PendingDelta::into_event)
+    }
+
+    /// When the buffered delta must be flushed even if no further events arrive
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|p| p.since + self.config.max_delay)
+    }
+}
+
+/// `(index, kind, text)` for deltas that can be concatenated
+pub(crate) fn mergeable_delta(event: &StreamEvent) -> Option<(u64, TextDeltaKind, &str)> {
+    if event.event != "content_block_delta" {
+        return None;
+    }
+    let index = event.data["index"].as_u64()?;
+    let delta = &event.data["delta"];
+    let kind = TextDeltaKind::parse(delta["type"].as_str()?)?;
+    Some((index, kind, delta[kind.field()].as_str()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn coalescer(max_bytes: usize) -> DeltaCoalescer {
+        DeltaCoalescer::new(CoalesceConfig {
+            max_bytes,
+            max_delay: Duration::from_secs(60),
+        })
+    }
+
+    fn text(index: u64, t: &str) -> StreamEvent {
+        StreamEvent {
+            event: "content_block_delta",
+            data: json!({"type": "content_block_delta", "index": index, "delta": {"type": "text_delta", "text": t}}),
+        }
+    }
+
+    #[test]
+    fn test_merges_consecutive_deltas_until_flushed() {
+        let mut c = coalescer(1024);
+        assert!(c.push(text(0, "Hel")).is_empty());
+        assert!(c.push(text(0, "lo")).is_empty());
+
+        let stop = StreamEvent {
+            event: "content_block_stop",
+            data: json!({"type": "content_block_stop", "index": 0}),
+        };
+        let ready = c.push(stop);
+        assert_eq!(ready.len(), 2);
+        assert_eq!(ready[0].data["delta"]["text"], "Hello");
+        assert_eq!(ready[1].event, "content_block_stop");
+        assert!(c.deadline().is_none());
+    }
+
+    #[test]
+    fn test_flushes_at_byte_threshold() {
+        let mut c = coalescer(4);
+        assert!(c.push(text(0, "ab")).is_empty());
+        let ready = c.push(text(0, "cd"));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].data["delta"]["text"], "abcd");
+    }
+
+    #[test]
+    fn test_different_blocks_are_not_merged() {
+        let mut c = coalescer(1024);
+        c.push(text(0, "a"));
+        let ready = c.push(text(1, "b"));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].data["index"], 0);
+        assert_eq!(c.take().unwrap().data["delta"]["text"], "b");
+    }
+
+    #[test]
+    fn test_tool_argument_deltas_keep_their_field() {
+        let mut c = coalescer(1024);
+        let json_delta = |s: &str| StreamEvent {
+            event: "content_block_delta",
+            data: json!({"type": "content_block_delta", "index": 2, "delta": {"type": "input_json_delta", "partial_json": s}}),
+        };
+        c.push(json_delta("{\"a\":"));
+        c.push(json_delta("1}"));
+        let ev = c.take().unwrap();
+        assert_eq!(ev.data["delta"], json!({"type": "input_json_delta", "partial_json": "{\"a\":1}"}));
+    }
+
+    #[test]
+    fn test_zero_delay_flushes_immediately() {
+        let mut c = DeltaCoalescer::new(CoalesceConfig {
+            max_bytes: 1024,
+            max_delay: Duration::ZERO,
+        });
+        assert_eq!(c.push(text(0, "a")).len(), 1);
+    }
+}
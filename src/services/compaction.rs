@@ -0,0 +1,497 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use axum::http::{HeaderName, HeaderValue};
+use reqwest::Client;
+use serde_json::{json, Value};
+use crate::config::env_parse;
+use crate::constants::{
+    COMPACTION_CACHE_ENTRIES, COMPACTION_TIMEOUT_SECS, COMPACTION_TOOL_RESULT_CHARS, COMPACTION_WAIT_SECS, DEFAULT_COMPACTION_KEEP_MESSAGES,
+    DEFAULT_COMPACTION_SUMMARY_MAX_TOKENS,
+};
+use crate::models::{App, BackendRegistry, ClaudeMessage, OAIChatReq, OAIMessage};
+use crate::services::{diagnostic_headers, json_body};
+use crate::utils::content_extraction::{content_parts, PartKind};
+
+const SUMMARY_INSTRUCTIONS: &str = "You compact the history of a conversation between a user and a coding assistant. \
+Summarize the transcript below so the assistant can continue the work without it: the user's goals and instructions, \
+decisions made, files and commands involved, tool results that still matter, and what remains to be done. \
+Be concise and factual; answer with the summary only.";
+
+/// A summary of `messages[..len]` of some conversation, keyed by the hash of those messages
+#[derive(Debug, Clone)]
+struct CachedSummary {
+    len: usize,
+    hash: u64,
+    summary: String,
+}
+
+/// History replaced by a summary, for the client notice and logs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compacted {
+    pub messages: usize,
+    pub tokens_before: usize,
+    /// Threshold of the request's model that was exceeded
+    pub threshold_tokens: usize,
+}
+
+impl Compacted {
+    pub fn notice(&self) -> String {
+        format!(
+            "The proxy compacted {} earlier message(s) (about {} input tokens in total) into a summary to fit the model's context.",
+            self.messages, self.tokens_before
+        )
+    }
+}
+
+/// `COMPACTION_THRESHOLD_TOKENS`: a default threshold and per-model ones as `model=tokens`,
+/// comma-separated (e.g. `100000,glm-4.5-air=24000`)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionThresholds {
+    default: Option<usize>,
+    models: HashMap<String, usize>,
+}
+
+impl FromStr for CompactionThresholds {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut thresholds = Self::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.rsplit_once('=') {
+                Some((model, tokens)) => {
+                    thresholds.models.insert(model.trim().to_string(), tokens.trim().parse().map_err(|_| ())?);
+                }
+                None => thresholds.default = Some(entry.parse().map_err(|_| ())?),
+            }
+        }
+        Ok(thresholds)
+    }
+}
+
+impl CompactionThresholds {
+    /// Threshold for `model` as the client names it; `None` when its requests aren't compacted
+    pub fn get(&self, model: &str) -> Option<usize> {
+        self.models.get(model).copied().or(self.default).filter(|&t| t > 0)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.default.is_none() && self.models.is_empty()
+    }
+}
+
+/// Proxy-side history compaction (`COMPACTION_THRESHOLD_TOKENS`): once a request's estimated input
+/// exceeds its model's threshold, older turns are summarized by `COMPACTION_MODEL` and replaced
+/// with the summary, keeping the last `COMPACTION_KEEP_MESSAGES` messages verbatim.
+///
+/// No bytes can reach the client before the response starts, so a request waits at most
+/// `COMPACTION_WAIT_SECS` for its summary. A slower summary keeps running in the background and
+/// is used from the next turn on, while this turn is forwarded with the full history. Summary
+/// requests run under the triggering request's admission slot and count towards `/admin/stats`
+/// and the circuit breaker like any backend call
+pub struct Compaction {
+    client: Client,
+    /// Bearer token for summary requests (`COMPACTION_API_KEY`); the client's key when unset
+    api_key: Option<String>,
+    extra_headers: Vec<(HeaderName, HeaderValue)>,
+    /// Backend serving `COMPACTION_MODEL`
+    backend: String,
+    url: String,
+    model: String,
+    gzip_min_bytes: usize,
+    pub thresholds: CompactionThresholds,
+    keep_messages: usize,
+    summary_max_tokens: u32,
+    /// Longest a request waits for its summary (`COMPACTION_WAIT_SECS`)
+    wait: Duration,
+    cache: Mutex<VecDeque<CachedSummary>>,
+    /// Hashes of the histories being summarized, so concurrent turns don't summarize them twice
+    pending: Mutex<HashSet<u64>>,
+}
+
+impl Compaction {
+    /// Needs `COMPACTION_THRESHOLD_TOKENS` and `COMPACTION_MODEL` (`model` on the default backend or
+    /// `backend=model`); `None` when either is unset
+    pub fn from_env(client: Client, backends: &BackendRegistry, extra_headers: Vec<(HeaderName, HeaderValue)>) -> Option<Self> {
+        let thresholds = env_parse::<CompactionThresholds>("COMPACTION_THRESHOLD_TOKENS").filter(|t| !t.is_empty())?;
+        let entry = std::env::var("COMPACTION_MODEL").ok().filter(|m| !m.trim().is_empty());
+        let Some(entry) = entry else {
+            log::warn!("⚠️  COMPACTION_THRESHOLD_TOKENS is set without COMPACTION_MODEL - compaction disabled");
+            return None;
+        };
+        let (name, model) = entry.split_once('=').unwrap_or(("default", &entry));
+        let Some(backend) = backends.get(name.trim()) else {
+            log::warn!("⚠️  Ignoring COMPACTION_MODEL '{}': unknown backend '{}' - compaction disabled", entry, name.trim());
+            return None;
+        };
+        let compaction = Self::new(client, backend.name.clone(), backend.url.clone(), model.trim().to_string(), thresholds, extra_headers);
+        Some(Self {
+            api_key: std::env::var("COMPACTION_API_KEY").ok().filter(|k| !k.trim().is_empty()),
+            gzip_min_bytes: backend.options.request_gzip_min_bytes,
+            keep_messages: env_parse("COMPACTION_KEEP_MESSAGES").unwrap_or(DEFAULT_COMPACTION_KEEP_MESSAGES),
+            summary_max_tokens: env_parse("COMPACTION_SUMMARY_MAX_TOKENS").unwrap_or(DEFAULT_COMPACTION_SUMMARY_MAX_TOKENS),
+            ..compaction
+        })
+    }
+
+    fn new(client: Client, backend: String, url: String, model: String, thresholds: CompactionThresholds, extra_headers: Vec<(HeaderName, HeaderValue)>) -> Self {
+        Self {
+            client,
+            api_key: None,
+            extra_headers,
+            backend,
+            url,
+            model,
+            gzip_min_bytes: usize::MAX,
+            thresholds,
+            keep_messages: DEFAULT_COMPACTION_KEEP_MESSAGES,
+            summary_max_tokens: DEFAULT_COMPACTION_SUMMARY_MAX_TOKENS,
+            wait: Duration::from_secs(COMPACTION_WAIT_SECS),
+            cache: Mutex::new(VecDeque::new()),
+            pending: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, VecDeque<CachedSummary>> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn pending(&self) -> MutexGuard<'_, HashSet<u64>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replace older turns of `messages` with a summary when `input_tokens` is over the threshold
+    /// of `model`.
+    ///
+    /// Returns `None` when nothing was compacted: a failed or late summary leaves the messages unchanged.
+    pub async fn compact(
+        self: &Arc<Self>,
+        app: &App,
+        request_id: &str,
+        model: &str,
+        messages: &mut Vec<ClaudeMessage>,
+        input_tokens: usize,
+        client_key: Option<&str>,
+    ) -> Option<Compacted> {
+        let threshold_tokens = self.thresholds.get(model)?;
+        if input_tokens <= threshold_tokens {
+            return None;
+        }
+        let split = split_point(messages, self.keep_messages)?;
+        let hashes = prefix_hashes(&messages[..split]);
+        let hash = hashes[split - 1];
+
+        // Only the turns after the longest already-summarized prefix are sent for summarizing
+        let cached = self.cache().iter().filter(|c| c.len <= split && hashes[c.len - 1] == c.hash).max_by_key(|c| c.len).cloned();
+        let summary = match cached {
+            Some(c) if c.len == split => c.summary,
+            cached => {
+                if !self.pending().insert(hash) {
+                    log::info!("⏳ Summary of {} message(s) is already being written - forwarding the full history", split);
+                    return None;
+                }
+                let (start, previous) = cached.map_or((0, None), |c| (c.len, Some(c.summary)));
+                let (this, app, request_id) = (self.clone(), app.clone(), request_id.to_string());
+                let (turns, client_key) = (messages[start..split].to_vec(), client_key.map(str::to_string));
+                let summarizing = tokio::spawn(async move {
+                    let summary = this.summarize(&app, &request_id, previous.as_deref(), &turns, client_key.as_deref()).await;
+                    if let Ok(summary) = &summary {
+                        let mut cache = this.cache();
+                        if cache.len() >= COMPACTION_CACHE_ENTRIES {
+                            cache.pop_front();
+                        }
+                        cache.push_back(CachedSummary { len: split, hash, summary: summary.clone() });
+                    }
+                    this.pending().remove(&hash);
+                    summary
+                });
+                let summary = match tokio::time::timeout(self.wait, summarizing).await {
+                    Ok(joined) => joined.map_err(|e| e.to_string()).and_then(|summary| summary),
+                    Err(_) => {
+                        log::info!(
+                            "⏳ Summary of {} message(s) not ready after {:?} - forwarding the full history; later turns use it once written",
+                            split, self.wait
+                        );
+                        return None;
+                    }
+                };
+                match summary {
+                    Ok(summary) => summary,
+                    Err(e) => {
+                        log::warn!("⚠️  Compaction of {} message(s) with {} failed: {} - forwarding the full history", split, self.model, e);
+                        return None;
+                    }
+                }
+            }
+        };
+
+        messages.drain(..split);
+        prepend_summary(&mut messages[0], &summary);
+        Some(Compacted { messages: split, tokens_before: input_tokens, threshold_tokens })
+    }
+
+    /// Ask `COMPACTION_MODEL` for a summary, recorded in stats and the circuit breaker like a
+    /// client request to the backend
+    async fn summarize(&self, app: &App, request_id: &str, previous: Option<&str>, messages: &[ClaudeMessage], client_key: Option<&str>) -> Result<String, String> {
+        let mut prompt = String::new();
+        if let Some(previous) = previous {
+            prompt.push_str(&format!("Summary of the conversation so far:\n{}\n\nLater messages:\n", previous));
+        }
+        prompt.push_str(&transcript(messages));
+        let oai = OAIChatReq {
+            model: self.model.clone(),
+            messages: vec![
+                OAIMessage { role: "system".into(), content: json!(SUMMARY_INSTRUCTIONS), tool_call_id: None, tool_calls: None, prefix: None },
+                OAIMessage { role: "user".into(), content: json!(prompt), tool_call_id: None, tool_calls: None, prefix: None },
+            ],
+            max_tokens: Some(self.summary_max_tokens),
+            stream: false,
+            ..Default::default()
+        };
+        let mut req = self.client.post(&self.url).timeout(Duration::from_secs(COMPACTION_TIMEOUT_SECS));
+        for (name, value) in &self.extra_headers {
+            req = req.header(name, value);
+        }
+        if let Some(key) = self.api_key.as_deref().or(client_key) {
+            req = req.bearer_auth(key);
+        }
+        let started = Instant::now();
+        let res = match json_body(req, &oai, self.gzip_min_bytes).send().await {
+            Ok(res) => res,
+            Err(e) => {
+                app.record_backend_failure().await;
+                app.stats.record_error(request_id, &self.model, None, &format!("compaction summary: {}", e));
+                return Err(e.to_string());
+            }
+        };
+        let status = res.status();
+        app.stats.record_backend_response(request_id, &self.backend, status.as_u16(), diagnostic_headers(res.headers()));
+        log::info!(target: "metrics", "compaction_summary: backend={}, model={}, status={}, ms={}", self.backend, self.model, status.as_u16(), started.elapsed().as_millis());
+        if !status.is_success() {
+            // Only the backend's own failures count towards the circuit breaker
+            if status.is_server_error() {
+                app.record_backend_failure().await;
+            }
+            app.stats.record_error(request_id, &self.model, Some(status.as_u16()), "compaction summary failed");
+            return Err(format!("backend returned {}", status.as_u16()));
+        }
+        app.record_backend_success().await;
+        let body: Value = res.json().await.map_err(|e| e.to_string())?;
+        let usage = &body["usage"];
+        app.stats.record_usage(&self.model, usage["prompt_tokens"].as_u64().unwrap_or_default(), usage["completion_tokens"].as_u64().unwrap_or_default());
+        body["choices"][0]["message"]["content"]
+            .as_str()
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| "empty summary".to_string())
+    }
+}
+
+/// Number of leading messages to compact so that at least `keep` remain; the kept history must
+/// start with a user message that answers no tool call, so tool calls stay paired with their results
+fn split_point(messages: &[ClaudeMessage], keep: usize) -> Option<usize> {
+    let mut split = messages.len().checked_sub(keep.max(1))?;
+    while split > 0 {
+        let message = &messages[split];
+        let answers_tool = message.content.as_array().is_some_and(|blocks| blocks.iter().any(|b| b["type"] == "tool_result"));
+        if message.role == "user" && !answers_tool {
+            break;
+        }
+        split -= 1;
+    }
+    // Compacting a single message saves nothing
+    (split > 1).then_some(split)
+}
+
+/// Hash of `messages[..=i]` for every `i`
+fn prefix_hashes(messages: &[ClaudeMessage]) -> Vec<u64> {
+    let mut hasher = DefaultHasher::new();
+    messages
+        .iter()
+        .map(|m| {
+            m.role.hash(&mut hasher);
+            m.content.to_string().hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect()
+}
+
+/// Plain-text rendering of `messages` for the summarizer; thinking is left out and long tool
+/// results are shortened
+fn transcript(messages: &[ClaudeMessage]) -> String {
+    let mut lines = Vec::new();
+    for message in messages {
+        let parts = content_parts(&message.content);
+        let mut texts = Vec::new();
+        let mut call = Vec::new();
+        for (kind, text) in parts.texts {
+            match kind {
+                PartKind::Text => texts.push(text),
+                PartKind::Thinking => {}
+                PartKind::ToolUse => call.push(text),
+                PartKind::ToolResult => {
+                    let mut result: String = text.chars().take(COMPACTION_TOOL_RESULT_CHARS).collect();
+                    if result.len() < text.len() {
+                        result.push_str(" […]");
+                    }
+                    texts.push(format!("[tool result] {}", result));
+                }
+            }
+        }
+        if !call.is_empty() {
+            texts.push(format!("[tool call] {}", call.join(" ")));
+        }
+        if !parts.images.is_empty() {
+            texts.push(format!("[{} image(s)]", parts.images.len()));
+        }
+        if !texts.is_empty() {
+            lines.push(format!("{}: {}", message.role, texts.join("\n")));
+        }
+    }
+    lines.join("\n\n")
+}
+
+/// Put the summary before the first kept message's own content
+fn prepend_summary(message: &mut ClaudeMessage, summary: &str) {
+    let block = json!({
+        "type": "text",
+        "text": format!("<conversation_summary>\nEarlier messages were compacted into this summary:\n{}\n</conversation_summary>", summary),
+    });
+    message.content = match message.content.take() {
+        Value::String(text) => json!([block, { "type": "text", "text": text }]),
+        Value::Array(mut blocks) => {
+            blocks.insert(0, block);
+            Value::Array(blocks)
+        }
+        _ => json!([block]),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use axum::{http::StatusCode, routing::post, Json, Router};
+    use crate::models::app::tests::test_app;
+
+    /// Summarizing backend answering with `status` after `delay`; returns its URL and hit counter
+    async fn summary_backend(status: StatusCode, delay: Duration) -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let app = Router::new().route(
+            "/v1/chat/completions",
+            post(move || {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(delay).await;
+                    let body = json!({"choices": [{"message": {"role": "assistant", "content": "Fixed an import."}}], "usage": {"prompt_tokens": 120, "completion_tokens": 8}});
+                    (status, Json(body))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (format!("http://{}/v1/chat/completions", addr), hits)
+    }
+
+    fn compaction(url: &str, wait: Duration) -> Arc<Compaction> {
+        let client = Client::builder().no_proxy().build().unwrap();
+        let thresholds = "1000,big-model=100000".parse().unwrap();
+        Arc::new(Compaction { keep_messages: 3, wait, ..Compaction::new(client, "default".into(), url.into(), "summarizer".into(), thresholds, Vec::new()) })
+    }
+
+    #[tokio::test]
+    async fn test_compact_summarizes_once_and_records_the_call() {
+        let (url, hits) = summary_backend(StatusCode::OK, Duration::ZERO).await;
+        let (compaction, app) = (compaction(&url, Duration::from_secs(5)), test_app(url));
+
+        let mut messages = history();
+        assert_eq!(compaction.compact(&app, "msg_1", "big-model", &mut messages, 5000, Some("k")).await, None, "under the model's own threshold");
+        let compacted = compaction.compact(&app, "msg_1", "small-model", &mut messages, 5000, Some("k")).await;
+        assert_eq!(compacted, Some(Compacted { messages: 4, tokens_before: 5000, threshold_tokens: 1000 }));
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].content[0]["text"].as_str().unwrap().contains("Fixed an import."));
+
+        let mut again = history();
+        assert!(compaction.compact(&app, "msg_2", "small-model", &mut again, 5000, Some("k")).await.is_some());
+        assert_eq!(hits.load(Ordering::SeqCst), 1, "the summary is cached");
+        let stats = app.stats.snapshot();
+        assert_eq!((stats.models["summarizer"].requests, stats.models["summarizer"].input_tokens), (1, 120));
+        assert_eq!(app.stats.backend_responses("msg_1").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_compact_forwards_full_history_while_a_slow_summary_finishes() {
+        let (url, hits) = summary_backend(StatusCode::OK, Duration::from_millis(300)).await;
+        let (compaction, app) = (compaction(&url, Duration::from_millis(50)), test_app(url));
+        let mut messages = history();
+        assert_eq!(compaction.compact(&app, "msg_1", "m", &mut messages, 5000, None).await, None);
+        assert_eq!(messages.len(), 7, "the late turn keeps its history");
+        assert_eq!(compaction.compact(&app, "msg_2", "m", &mut history(), 5000, None).await, None, "no second summary while one runs");
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(compaction.compact(&app, "msg_3", "m", &mut history(), 5000, None).await.is_some());
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_summary_counts_towards_circuit_breaker() {
+        let (url, _) = summary_backend(StatusCode::INTERNAL_SERVER_ERROR, Duration::ZERO).await;
+        let (compaction, app) = (compaction(&url, Duration::from_secs(5)), test_app(url));
+        assert_eq!(compaction.compact(&app, "msg_1", "m", &mut history(), 5000, None).await, None);
+        assert_eq!(app.circuit_breaker.read().await.consecutive_failures, 1);
+        assert_eq!(app.stats.snapshot().models["summarizer"].errors, 1);
+    }
+
+    fn history() -> Vec<ClaudeMessage> {
+        serde_json::from_value(json!([
+            {"role": "user", "content": "Fix the build"},
+            {"role": "assistant", "content": [{"type": "tool_use", "id": "toolu_1", "name": "bash", "input": {"cmd": "cargo build"}}]},
+            {"role": "user", "content": [{"type": "tool_result", "tool_use_id": "toolu_1", "content": "error[E0425]"}]},
+            {"role": "assistant", "content": "Fixed the missing import."},
+            {"role": "user", "content": "Now run the tests"},
+            {"role": "assistant", "content": [{"type": "tool_use", "id": "toolu_2", "name": "bash", "input": {"cmd": "cargo test"}}]},
+            {"role": "user", "content": [{"type": "tool_result", "tool_use_id": "toolu_2", "content": "ok"}]},
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_split_point_keeps_tool_pairs() {
+        let messages = history();
+        // Keeping 2 would start at a tool_result; the split moves back to the plain user turn
+        assert_eq!(split_point(&messages, 2), Some(4));
+        assert_eq!(split_point(&messages, 3), Some(4));
+        assert_eq!(split_point(&messages, 4), None);
+        assert_eq!(split_point(&messages, 20), None);
+    }
+
+    #[test]
+    fn test_transcript_and_summary_block() {
+        let mut messages = history();
+        let text = transcript(&messages[..4]);
+        assert!(text.starts_with("user: Fix the build\n\nassistant: [tool call] toolu_1 bash {\"cmd\":\"cargo build\"}"));
+        assert!(text.contains("user: [tool result] error[E0425]"));
+
+        prepend_summary(&mut messages[4], "Fixed an import.");
+        let blocks = messages[4].content.as_array().unwrap();
+        assert!(blocks[0]["text"].as_str().unwrap().contains("Fixed an import."));
+        assert_eq!(blocks[1], json!({"type": "text", "text": "Now run the tests"}));
+    }
+
+    #[test]
+    fn test_prefix_hashes_extend() {
+        let messages = history();
+        let short = prefix_hashes(&messages[..4]);
+        let long = prefix_hashes(&messages);
+        assert_eq!(short[..], long[..4]);
+        assert_ne!(long[3], long[4]);
+    }
+}
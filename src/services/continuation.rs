@@ -0,0 +1,116 @@
+use serde_json::Value;
+use crate::models::{OAIChatReq, OAIMessage};
+use crate::utils::prefill::PrefillMode;
+
+/// User turn asking the model to resume, for backends without a native continuation mode
+pub const CONTINUE_PROMPT: &str = "Continue exactly where you left off. Do not repeat any text you already wrote.";
+
+/// Follow-up requests for responses truncated at `max_tokens` (`AUTO_CONTINUE_TOKENS`).
+///
+/// Collects the text generated so far and builds requests that resume it, until the extra token
+/// budget is spent. Each follow-up asks for at most the original `max_tokens`.
+pub struct Continuation {
+    base: OAIChatReq,
+    mode: PrefillMode,
+    remaining: u32,
+    text: String,
+    /// Follow-up requests sent so far
+    pub requests: u32,
+}
+
+impl Continuation {
+    /// `None` when auto-continuation is disabled (`budget` 0)
+    pub fn new(base: &OAIChatReq, budget: u32, mode: PrefillMode) -> Option<Self> {
+        (budget > 0).then(|| Self { base: base.clone(), mode, remaining: budget, text: String::new(), requests: 0 })
+    }
+
+    /// Record generated answer text
+    pub fn push_text(&mut self, text: &str) {
+        self.text.push_str(text);
+    }
+
+    /// Request resuming the answer, or `None` once the budget is spent or nothing was generated
+    pub fn next_request(&mut self) -> Option<OAIChatReq> {
+        if self.remaining == 0 || self.text.is_empty() {
+            return None;
+        }
+        let max_tokens = self.base.max_tokens.unwrap_or(self.remaining).min(self.remaining);
+        self.remaining -= max_tokens;
+        self.requests += 1;
+
+        let mut oai = self.base.clone();
+        oai.max_tokens = Some(max_tokens);
+        // A prefilled assistant turn is extended; otherwise the answer so far becomes one
+        match oai.messages.last_mut() {
+            Some(last) if last.role == "assistant" && last.tool_calls.is_none() && last.content.is_string() => {
+                let prefill = last.content.as_str().unwrap_or_default();
+                last.content = Value::String(format!("{}{}", prefill, self.text));
+            }
+            _ => oai.messages.push(message("assistant", &self.text)),
+        }
+        match self.mode {
+            PrefillMode::Continue => {
+                oai.continue_final_message = Some(true);
+                oai.add_generation_prompt = Some(false);
+            }
+            PrefillMode::Prefix => {
+                if let Some(last) = oai.messages.last_mut() {
+                    last.prefix = Some(true);
+                }
+            }
+            PrefillMode::Passthrough | PrefillMode::Splice => oai.messages.push(message("user", CONTINUE_PROMPT)),
+        }
+        Some(oai)
+    }
+}
+
+fn message(role: &str, text: &str) -> OAIMessage {
+    OAIMessage { role: role.into(), content: Value::String(text.into()), tool_call_id: None, tool_calls: None, prefix: None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> OAIChatReq {
+        OAIChatReq { messages: vec![message("user", "write a long file")], max_tokens: Some(100), ..Default::default() }
+    }
+
+    #[test]
+    fn test_disabled_without_budget() {
+        assert!(Continuation::new(&base(), 0, PrefillMode::default()).is_none());
+    }
+
+    #[test]
+    fn test_continue_prompt_and_budget() {
+        let mut continuation = Continuation::new(&base(), 150, PrefillMode::Passthrough).unwrap();
+        assert!(continuation.next_request().is_none(), "nothing generated yet");
+        continuation.push_text("fn main() {");
+
+        let first = continuation.next_request().unwrap();
+        assert_eq!(first.max_tokens, Some(100));
+        let roles: Vec<&str> = first.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant", "user"]);
+        assert_eq!(first.messages[1].content, "fn main() {");
+        assert_eq!(first.messages[2].content, CONTINUE_PROMPT);
+
+        continuation.push_text("\n}");
+        let second = continuation.next_request().unwrap();
+        assert_eq!(second.max_tokens, Some(50));
+        assert_eq!(second.messages[1].content, "fn main() {\n}");
+        assert!(continuation.next_request().is_none());
+        assert_eq!(continuation.requests, 2);
+    }
+
+    #[test]
+    fn test_prefix_extends_prefill() {
+        let mut request = base();
+        request.messages.push(OAIMessage { prefix: Some(true), ..message("assistant", "```rust\n") });
+        let mut continuation = Continuation::new(&request, 500, PrefillMode::Prefix).unwrap();
+        continuation.push_text("fn main() {");
+        let next = continuation.next_request().unwrap();
+        assert_eq!(next.messages.len(), 2);
+        assert_eq!(next.messages[1].content, "```rust\nfn main() {");
+        assert_eq!(next.messages[1].prefix, Some(true));
+    }
+}
@@ -0,0 +1,144 @@
+//! Per-token backend data for evaluation harnesses: with `x-proxy-debug: logprobs,annotations`
+//! the stream carries `proxy_debug` events next to the standard Claude events, holding the
+//! backend's logprobs and raw annotations, and the request features the proxy dropped. Clients
+//! that don't send the header never see them.
+use axum::http::HeaderMap;
+use serde::Serialize;
+use serde_json::{json, Value};
+use crate::constants::MAX_TOP_LOGPROBS;
+use crate::models::OAIChatReq;
+
+pub const DEBUG_HEADER: &str = "x-proxy-debug";
+
+/// SSE event name of the sidecar events
+pub const DEBUG_EVENT: &str = "proxy_debug";
+
+/// What a request asked to see through `x-proxy-debug`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DebugSidecar {
+    /// Forward logprobs, asking the backend for this many alternatives per token (`logprobs=5`)
+    pub logprobs: Option<u32>,
+    /// Forward the backend's raw source annotations
+    pub annotations: bool,
+    /// Report request features the proxy couldn't pass on, before the answer
+    pub dropped: bool,
+}
+
+/// A request feature that didn't reach the backend, and why
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct DroppedFeature {
+    pub feature: String,
+    pub reason: String,
+}
+
+impl DroppedFeature {
+    pub fn new(feature: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self { feature: feature.into(), reason: reason.into() }
+    }
+}
+
+impl DebugSidecar {
+    /// `logprobs`, `logprobs=<top>`, `annotations`, `dropped` or `all`, comma-separated; `None` without the header
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = headers.get(DEBUG_HEADER)?.to_str().ok()?;
+        let mut sidecar = Self::default();
+        for item in header.split(',').map(|s| s.trim().to_ascii_lowercase()).filter(|s| !s.is_empty()) {
+            match item.split_once('=') {
+                None if item == "logprobs" => sidecar.logprobs = Some(0),
+                None if item == "annotations" => sidecar.annotations = true,
+                None if item == "dropped" => sidecar.dropped = true,
+                None if item == "all" || item == "true" => {
+                    sidecar.logprobs = Some(0);
+                    sidecar.annotations = true;
+                    sidecar.dropped = true;
+                }
+                Some(("logprobs", top)) if top.parse::<u32>().is_ok() => sidecar.logprobs = top.parse().ok(),
+                _ => log::warn!("⚠️  Ignoring unknown {} item '{}'", DEBUG_HEADER, item),
+            }
+        }
+        (sidecar != Self::default()).then_some(sidecar)
+    }
+
+    /// Ask the backend for logprobs when they were requested, at most `MAX_TOP_LOGPROBS`
+    /// alternatives per token. A backend listing `logprobs` in `UNSUPPORTED_PARAMS` isn't asked;
+    /// the feature is returned as dropped instead
+    pub fn request(&self, oai: &mut OAIChatReq, unsupported: &[String]) -> Option<DroppedFeature> {
+        let top = self.logprobs?;
+        if unsupported.iter().any(|param| param.eq_ignore_ascii_case("logprobs")) {
+            return Some(DroppedFeature::new(format!("{}: logprobs", DEBUG_HEADER), "not supported by the backend (UNSUPPORTED_PARAMS)"));
+        }
+        oai.logprobs = Some(true);
+        oai.top_logprobs = (top > 0).then_some(top.min(MAX_TOP_LOGPROBS));
+        None
+    }
+
+    /// The `proxy_debug` payload for one backend chunk, if it carried anything asked for
+    pub fn event(&self, logprobs: Option<&Value>, annotations: Option<&Vec<Value>>) -> Option<Value> {
+        let mut event = json!({ "type": DEBUG_EVENT });
+        // Chat completions nest per-token entries under `content` (and `refusal`)
+        if let Some(tokens) = logprobs.filter(|_| self.logprobs.is_some()).and_then(|l| l["content"].as_array()).filter(|t| !t.is_empty()) {
+            event["logprobs"] = json!(tokens);
+        }
+        if let Some(annotations) = annotations.filter(|a| self.annotations && !a.is_empty()) {
+            event["annotations"] = json!(annotations);
+        }
+        (event.as_object().map_or(0, |e| e.len()) > 1).then_some(event)
+    }
+
+    /// The `proxy_debug` payload listing dropped request features, if asked for and there are any
+    pub fn dropped_event(&self, dropped: &[DroppedFeature]) -> Option<Value> {
+        (self.dropped && !dropped.is_empty()).then(|| json!({ "type": DEBUG_EVENT, "dropped_features": dropped }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn sidecar(header: &str) -> Option<DebugSidecar> {
+        let mut headers = HeaderMap::new();
+        headers.insert(DEBUG_HEADER, HeaderValue::from_str(header).unwrap());
+        DebugSidecar::from_headers(&headers)
+    }
+
+    #[test]
+    fn test_from_headers() {
+        assert_eq!(DebugSidecar::from_headers(&HeaderMap::new()), None);
+        assert_eq!(sidecar("bogus"), None);
+        assert_eq!(sidecar("logprobs=5, annotations"), Some(DebugSidecar { logprobs: Some(5), annotations: true, dropped: false }));
+        assert_eq!(sidecar("all"), Some(DebugSidecar { logprobs: Some(0), annotations: true, dropped: true }));
+
+        let mut oai = OAIChatReq::default();
+        assert_eq!(sidecar("logprobs").unwrap().request(&mut oai, &[]), None);
+        assert_eq!((oai.logprobs, oai.top_logprobs), (Some(true), None));
+        sidecar("logprobs=50").unwrap().request(&mut oai, &[]);
+        assert_eq!(oai.top_logprobs, Some(MAX_TOP_LOGPROBS));
+
+        let mut oai = OAIChatReq::default();
+        let dropped = sidecar("logprobs=5").unwrap().request(&mut oai, &["logprobs".into()]).unwrap();
+        assert_eq!(dropped.feature, "x-proxy-debug: logprobs");
+        assert_eq!((oai.logprobs, oai.top_logprobs), (None, None));
+    }
+
+    #[test]
+    fn test_event_only_carries_requested_data() {
+        let logprobs = json!({"content": [{"token": "Hi", "logprob": -0.1, "top_logprobs": []}]});
+        let annotations = vec![json!({"type": "url_citation", "url_citation": {"url": "https://example.com"}})];
+        let only_logprobs = sidecar("logprobs").unwrap();
+        assert_eq!(
+            only_logprobs.event(Some(&logprobs), Some(&annotations)),
+            Some(json!({"type": "proxy_debug", "logprobs": [{"token": "Hi", "logprob": -0.1, "top_logprobs": []}]}))
+        );
+        assert_eq!(only_logprobs.event(Some(&json!({"content": []})), None), None);
+        assert_eq!(sidecar("annotations").unwrap().event(Some(&logprobs), Some(&annotations)).unwrap()["annotations"], json!(annotations));
+
+        let dropped = [DroppedFeature::new("service_tier", "not forwarded")];
+        assert_eq!(only_logprobs.dropped_event(&dropped), None);
+        assert_eq!(
+            sidecar("dropped").unwrap().dropped_event(&dropped),
+            Some(json!({"type": "proxy_debug", "dropped_features": [{"feature": "service_tier", "reason": "not forwarded"}]}))
+        );
+        assert_eq!(sidecar("dropped").unwrap().dropped_event(&[]), None);
+    }
+}
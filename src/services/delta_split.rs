@@ -0,0 +1,101 @@
+use std::time::Duration;
+use serde_json::Value;
+use crate::services::coalesce::mergeable_delta;
+use crate::services::transform::StreamEvent;
+
+/// Re-chunking settings for oversized deltas
+#[derive(Debug, Clone, Copy)]
+pub struct SplitConfig {
+    /// Largest delta text forwarded in one event
+    pub max_bytes: usize,
+    /// Pause between the pieces of one split delta
+    pub delay: Duration,
+}
+
+/// Split a text/thinking/tool-argument delta larger than `max_bytes` into several deltas.
+///
+/// Pieces break on UTF-8 character boundaries, preferring the last whitespace in the
+/// window so words aren't cut in half. Other events are returned unchanged.
+pub fn split_delta(event: StreamEvent, max_bytes: usize) -> Vec<StreamEvent> {
+    let Some((_, kind, text)) = mergeable_delta(&event) else {
+        return vec![event];
+    };
+    if max_bytes == 0 || text.len() <= max_bytes {
+        return vec![event];
+    }
+
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let end = piece_end(rest, max_bytes);
+        let (piece, tail) = rest.split_at(end);
+        let mut ev = event.clone();
+        ev.data["delta"][kind.field()] = Value::String(piece.to_string());
+        pieces.push(ev);
+        rest = tail;
+    }
+    pieces
+}
+
+/// Byte offset where the next piece of `s` ends
+fn piece_end(s: &str, max_bytes: usize) -> usize {
+    if s.len() <= max_bytes {
+        return s.len();
+    }
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    if end == 0 {
+        // A single character wider than max_bytes
+        return s.chars().next().map(char::len_utf8).unwrap_or(s.len());
+    }
+    match s[..end].rfind(char::is_whitespace) {
+        Some(ws) if ws > 0 => ws + s[ws..].chars().next().map(char::len_utf8).unwrap_or(1),
+        _ => end,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn text(t: &str) -> StreamEvent {
+        StreamEvent {
+            event: "content_block_delta",
+            data: json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": t}}),
+        }
+    }
+
+    fn texts(events: &[StreamEvent]) -> Vec<&str> {
+        events.iter().map(|e| e.data["delta"]["text"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_small_delta_is_untouched() {
+        assert_eq!(texts(&split_delta(text("hello"), 16)), vec!["hello"]);
+    }
+
+    #[test]
+    fn test_splits_on_whitespace() {
+        let pieces = split_delta(text("alpha beta gamma delta"), 12);
+        assert_eq!(texts(&pieces), vec!["alpha beta ", "gamma delta"]);
+        assert!(pieces.iter().all(|p| p.data["index"] == 0));
+    }
+
+    #[test]
+    fn test_splits_long_word_on_char_boundary() {
+        let pieces = split_delta(text("ééééé"), 3);
+        assert_eq!(texts(&pieces), vec!["é", "é", "é", "é", "é"]);
+    }
+
+    #[test]
+    fn test_other_events_pass_through() {
+        let stop = StreamEvent {
+            event: "content_block_stop",
+            data: json!({"type": "content_block_stop", "index": 0}),
+        };
+        assert_eq!(split_delta(stop, 1).len(), 1);
+    }
+}
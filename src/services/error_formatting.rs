@@ -1,7 +1,55 @@
+use std::{str::FromStr, sync::OnceLock};
+use regex::Regex;
 use serde_json::Value;
+use crate::constants::PROVIDER_ERROR_MAX_CHARS;
+use crate::services::{render, MessageTemplates};
+
+/// How much of a backend error reaches the chat transcript (`ERROR_VERBOSITY`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ErrorVerbosity {
+    /// Only a generic notice and suggestions; nothing the backend wrote
+    Friendly,
+    /// The first line of the provider's error message, capped at `PROVIDER_ERROR_MAX_CHARS`, with
+    /// URLs and host addresses masked
+    #[default]
+    Provider,
+    /// The full error message and the raw response body, for trusted deployments
+    Raw,
+}
+
+impl FromStr for ErrorVerbosity {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "friendly" => Ok(ErrorVerbosity::Friendly),
+            "provider" => Ok(ErrorVerbosity::Provider),
+            "raw" | "full" => Ok(ErrorVerbosity::Raw),
+            _ => Err(()),
+        }
+    }
+}
+
+/// URLs, IPv4 addresses and `host:port` pairs in backend error messages
+static INTERNAL_ADDRESS: OnceLock<Regex> = OnceLock::new();
+
+/// First line of `message`, shortened to `PROVIDER_ERROR_MAX_CHARS`, so stack traces stay out of
+/// the chat; URLs and host addresses are masked so internal endpoints don't either
+pub fn provider_summary(message: &str) -> String {
+    let line = message.lines().map(str::trim).find(|l| !l.is_empty()).unwrap_or("Unknown error");
+    let address = INTERNAL_ADDRESS.get_or_init(|| {
+        Regex::new(r"(?i)\b[a-z][a-z0-9+.-]*://[^\s'\x22<>]+|\b\d{1,3}(?:\.\d{1,3}){3}(?::\d+)?\b|\b[a-z0-9-]+(?:\.[a-z0-9-]+)*:\d{2,5}\b")
+            .expect("built-in address pattern")
+    });
+    let line = address.replace_all(line, "[internal]");
+    match line.char_indices().nth(PROVIDER_ERROR_MAX_CHARS) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.into_owned(),
+    }
+}
 
 /// Format backend error into user-friendly structured message
-pub fn format_backend_error(error_msg: &str, raw_json: &str) -> String {
+pub fn format_backend_error(error_msg: &str, raw_json: &str, verbosity: ErrorVerbosity, templates: &MessageTemplates) -> String {
     // Try to extract model name from context if available
     let model_name = if let Ok(val) = serde_json::from_str::<Value>(raw_json) {
         val.get("model")
@@ -11,57 +59,80 @@ pub fn format_backend_error(error_msg: &str, raw_json: &str) -> String {
         None
     };
 
-    let mut formatted = String::from("⚠️ Backend Error\n\n");
+    let mut formatted = format!("{}\n\n", templates.backend_error_title);
 
     if let Some(model) = model_name {
-        formatted.push_str(&format!("Model: {}\n", model));
+        formatted.push_str(&format!("{}\n", render(&templates.backend_error_model, &[("model", &model)])));
     }
 
-    formatted.push_str(&format!("Error: {}\n\n", error_msg));
+    match verbosity {
+        ErrorVerbosity::Friendly => formatted.push_str(&format!("{}\n\n", templates.backend_error_generic)),
+        ErrorVerbosity::Provider => {
+            formatted.push_str(&format!("{}\n\n", render(&templates.backend_error_detail, &[("message", &provider_summary(error_msg))])))
+        }
+        ErrorVerbosity::Raw => {
+            formatted.push_str(&format!("{}\n\n", render(&templates.backend_error_detail, &[("message", error_msg)])));
+            if raw_json.trim() != error_msg.trim() {
+                formatted.push_str(&format!("{}\n\n", render(&templates.backend_error_response, &[("response", raw_json.trim())])));
+            }
+        }
+    }
 
     // Add specific suggestions based on error type
-    if error_msg.contains("token") && error_msg.contains("exceed") {
+    let suggestions = if error_msg.contains("token") && error_msg.contains("exceed") {
         if let Some(requested) = error_msg.split("total of ").nth(1).and_then(|s| s.split(" tokens").next()) {
-            formatted.push_str(&format!("Requested: {} tokens\n", requested));
+            formatted.push_str(&format!("{}\n", render(&templates.context_requested, &[("tokens", requested)])));
         }
         if let Some(limit) = error_msg.split("maximum context length of ").nth(1).and_then(|s| s.split(" tokens").next()) {
-            formatted.push_str(&format!("Limit: {} tokens\n\n", limit));
+            formatted.push_str(&format!("{}\n\n", render(&templates.context_limit, &[("tokens", limit)])));
         }
-        formatted.push_str("💡 Suggestions:\n");
-        formatted.push_str("• Reduce message history\n");
-        formatted.push_str("• Use a model with larger context\n");
-        formatted.push_str("• Decrease max_tokens parameter\n");
+        &templates.suggestions_context_length
     } else if error_msg.contains("rate limit") {
-        formatted.push_str("💡 Suggestions:\n");
-        formatted.push_str("• Wait a moment before retrying\n");
-        formatted.push_str("• Check your API quota\n");
+        &templates.suggestions_rate_limit
     } else if error_msg.contains("insufficient") || error_msg.contains("quota") {
-        formatted.push_str("💡 Suggestions:\n");
-        formatted.push_str("• Check your account balance\n");
-        formatted.push_str("• Verify API key permissions\n");
+        &templates.suggestions_quota
+    } else {
+        return formatted;
+    };
+    if !suggestions.is_empty() {
+        formatted.push_str(&format!("{}\n", templates.suggestions_title));
+        for suggestion in suggestions {
+            formatted.push_str(&format!("{}\n", render(&templates.suggestion_item, &[("suggestion", suggestion)])));
+        }
     }
 
     formatted
 }
 
-/// Build markdown content for synthetic 404 response listing available models
-pub fn build_model_list_content(requested_model: &str, models: &[crate::models::ModelInfo]) -> String {
-    let mut content = format!(
-        "❌ Model `{}` not found.\n\n## 📋 Available Models ({} total)\n\n",
-        requested_model,
-        models.len()
-    );
+/// Response to a request for a model the backend doesn't know (`MODEL_NOT_FOUND`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ModelNotFound {
+    /// Chat reply listing the available models, readable inside Claude Code
+    #[default]
+    Chat,
+    /// `404` with an Anthropic `not_found_error` body carrying `available_models`, for scripted clients
+    Error,
+}
+
+impl FromStr for ModelNotFound {
+    type Err = ();
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "chat" => Ok(ModelNotFound::Chat),
+            "error" => Ok(ModelNotFound::Error),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Split models into reasoning and standard ones, each sorted by provider, then by name descending
+pub fn classify_models(models: &[crate::models::ModelInfo]) -> (Vec<&crate::models::ModelInfo>, Vec<&crate::models::ModelInfo>) {
     let mut reasoning_models: Vec<&crate::models::ModelInfo> = vec![];
     let mut standard_models: Vec<&crate::models::ModelInfo> = vec![];
 
     for model in models {
-        let has_reasoning = model
-            .supported_features
-            .iter()
-            .any(|f| f.to_lowercase().contains("reasoning"));
-
-        if has_reasoning {
+        if model.is_reasoning() {
             reasoning_models.push(model);
         } else {
             standard_models.push(model);
@@ -73,10 +144,10 @@ pub fn build_model_list_content(requested_model: &str, models: &[crate::models::
         let b_parts: Vec<&str> = b.id.split('/').collect();
 
         let first_cmp = a_parts
-            .get(0)
+            .first()
             .unwrap_or(&"")
             .to_lowercase()
-            .cmp(&b_parts.get(0).unwrap_or(&"").to_lowercase());
+            .cmp(&b_parts.first().unwrap_or(&"").to_lowercase());
 
         if first_cmp != std::cmp::Ordering::Equal {
             return first_cmp;
@@ -91,17 +162,29 @@ pub fn build_model_list_content(requested_model: &str, models: &[crate::models::
 
     reasoning_models.sort_by(sort_models);
     standard_models.sort_by(sort_models);
+    (reasoning_models, standard_models)
+}
+
+/// Build markdown content for synthetic 404 response listing available models
+pub fn build_model_list_content(requested_model: &str, models: &[crate::models::ModelInfo], templates: &MessageTemplates) -> String {
+    let mut content = format!(
+        "{}\n\n{}\n\n",
+        render(&templates.model_not_found, &[("model", requested_model)]),
+        render(&templates.available_models, &[("count", &models.len().to_string())])
+    );
+
+    let (reasoning_models, standard_models) = classify_models(models);
 
     let format_two_columns = |models: &[&crate::models::ModelInfo]| -> String {
         let mut result = String::new();
-        let half = (models.len() + 1) / 2;
+        let half = models.len().div_ceil(2);
         for i in 0..half {
             if let Some(&left_model) = models.get(i) {
-                let left_price = crate::constants::get_price_tier(left_model.input_price_usd, left_model.output_price_usd);
+                let left_price = templates.price_tier(left_model.input_price_usd, left_model.output_price_usd);
                 let left_formatted = format!("{:4} {}", left_price, left_model.id);
                 if let Some(&right_model) = models.get(i + half) {
                     let right_price =
-                        crate::constants::get_price_tier(right_model.input_price_usd, right_model.output_price_usd);
+                        templates.price_tier(right_model.input_price_usd, right_model.output_price_usd);
                     let right_formatted = format!("{:4} {}", right_price, right_model.id);
                     result.push_str(&format!("  {:48} {}\n", left_formatted, right_formatted));
                 } else {
@@ -113,16 +196,39 @@ pub fn build_model_list_content(requested_model: &str, models: &[crate::models::
     };
 
     if !reasoning_models.is_empty() {
-        content.push_str("### 🧠 REASONING (Extended Thinking)\n\n");
+        content.push_str(&format!("{}\n\n", templates.reasoning_models));
         content.push_str(&format_two_columns(&reasoning_models));
         content.push('\n');
     }
     if !standard_models.is_empty() {
-        content.push_str("### ⚡ STANDARD\n\n");
+        content.push_str(&format!("{}\n\n", templates.standard_models));
         content.push_str(&format_two_columns(&standard_models));
         content.push('\n');
     }
 
-    content.push_str("---\n\n💡 **To switch models:** Use `/model <model-name>`");
+    content.push_str(&format!("---\n\n{}", templates.switch_model_hint));
     content
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::error::backend_error_message;
+
+    #[test]
+    fn test_error_verbosity_levels() {
+        let body = r#"{"error":{"message":"upstream failed at http://10.0.0.5:8000/v1\nTraceback (most recent call last):\n  File \"server.py\""}}"#;
+        let message = backend_error_message(body).unwrap();
+        assert!(message.starts_with("upstream failed"));
+
+        let friendly = format_backend_error(&message, body, ErrorVerbosity::Friendly, &MessageTemplates::default());
+        assert!(!friendly.contains("10.0.0.5") && friendly.contains("could not complete"));
+        let provider = format_backend_error(&message, body, ErrorVerbosity::Provider, &MessageTemplates::default());
+        assert!(provider.contains("Error: upstream failed at [internal]\n") && !provider.contains("10.0.0.5") && !provider.contains("Traceback"));
+        let raw = format_backend_error(&message, body, ErrorVerbosity::Raw, &MessageTemplates::default());
+        assert!(raw.contains("10.0.0.5") && raw.contains("Traceback") && raw.contains("Response:\n{\"error\""));
+
+        assert_eq!(provider_summary("connect to vllm-internal:8000 refused (10.1.2.3)"), "connect to [internal] refused ([internal])");
+        assert_eq!(provider_summary("Rate limit: 3.5 requests per second"), "Rate limit: 3.5 requests per second");
+    }
+}
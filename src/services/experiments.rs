@@ -0,0 +1,179 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+use serde::Serialize;
+use crate::config::env_list;
+use crate::models::ClaudeRequest;
+use crate::services::backend_headers::conversation_hash;
+
+/// One model of an experiment and its share of conversations
+#[derive(Debug, Clone, PartialEq)]
+struct Arm {
+    weight: u64,
+    model: String,
+}
+
+/// The arm a request was assigned to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    /// The experiment, i.e. the model name the client asked for
+    pub experiment: String,
+    /// Model the request is sent as; may itself be a `MODEL_ROUTES` alias
+    pub model: String,
+}
+
+/// Arm of an experiment in `/admin/stats`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ArmSnapshot {
+    pub model: String,
+    pub weight: u64,
+    pub assigned: u64,
+}
+
+/// Weighted A/B experiments on model names (`MODEL_EXPERIMENTS`).
+///
+/// A request for an experiment's name is sent as one of its arms' models instead. The arm is picked
+/// from a hash of the conversation (Claude Code's `metadata.user_id`, else the client key, system
+/// prompt and first user message), so a conversation stays on one model as it grows.
+pub struct Experiments {
+    experiments: HashMap<String, Vec<Arm>>,
+    assigned: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl Experiments {
+    /// `MODEL_EXPERIMENTS` entries are `name=90%model-a|10%model-b`; `None` when unset
+    pub fn from_env() -> Option<Self> {
+        let experiments = parse_experiments(&env_list("MODEL_EXPERIMENTS"));
+        if experiments.is_empty() {
+            return None;
+        }
+        for (name, arms) in &experiments {
+            let arms: Vec<String> = arms.iter().map(|arm| format!("{}%{}", arm.weight, arm.model)).collect();
+            log::info!("🧪 Experiment '{}': {}", name, arms.join(" | "));
+        }
+        Some(Self { experiments, assigned: Mutex::new(HashMap::new()) })
+    }
+
+    /// The arm for this request when its model is an experiment; `owner` is the client key fingerprint
+    pub fn pick(&self, cr: &ClaudeRequest, owner: &str) -> Option<Assignment> {
+        let arms = self.experiments.get(&cr.model)?;
+        let total: u64 = arms.iter().map(|arm| arm.weight).sum();
+        let mut bucket = conversation_hash(owner, &format!("{}\0{}", cr.model, conversation_source(cr))) % total;
+        let arm = arms
+            .iter()
+            .find(|arm| {
+                if bucket < arm.weight {
+                    return true;
+                }
+                bucket -= arm.weight;
+                false
+            })
+            .unwrap_or(&arms[0]);
+        Some(Assignment { experiment: cr.model.clone(), model: arm.model.clone() })
+    }
+
+    /// Like [`Self::pick`], counting the assignment in the snapshot
+    pub fn assign(&self, cr: &ClaudeRequest, owner: &str) -> Option<Assignment> {
+        let assignment = self.pick(cr, owner)?;
+        *self.assigned.lock().unwrap_or_else(|e| e.into_inner()).entry((assignment.experiment.clone(), assignment.model.clone())).or_default() += 1;
+        Some(assignment)
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, Vec<ArmSnapshot>> {
+        let assigned = self.assigned.lock().unwrap_or_else(|e| e.into_inner());
+        self.experiments
+            .iter()
+            .map(|(name, arms)| {
+                let arms = arms
+                    .iter()
+                    .map(|arm| ArmSnapshot {
+                        model: arm.model.clone(),
+                        weight: arm.weight,
+                        assigned: assigned.get(&(name.clone(), arm.model.clone())).copied().unwrap_or_default(),
+                    })
+                    .collect();
+                (name.clone(), arms)
+            })
+            .collect()
+    }
+}
+
+/// What identifies a conversation across its turns
+fn conversation_source(cr: &ClaudeRequest) -> String {
+    if let Some(user_id) = cr.metadata.as_ref().and_then(|m| m["user_id"].as_str()) {
+        return user_id.to_string();
+    }
+    let system = cr.system.as_ref().map(|s| s.to_string()).unwrap_or_default();
+    let first_user = cr.messages.iter().find(|m| m.role == "user").map(|m| m.content.to_string()).unwrap_or_default();
+    format!("{}\n{}", system, first_user)
+}
+
+/// Parse `name=90%model|10%model` entries; malformed arms and experiments without weight are skipped
+fn parse_experiments(entries: &[String]) -> HashMap<String, Vec<Arm>> {
+    let mut experiments = HashMap::new();
+    for entry in entries {
+        let Some((name, arms)) = entry.split_once('=') else {
+            log::warn!("⚠️  Ignoring malformed MODEL_EXPERIMENTS entry '{}' (expected name=90%model|10%model)", entry);
+            continue;
+        };
+        let arms: Vec<Arm> = arms
+            .split('|')
+            .filter_map(|arm| {
+                let parsed = arm.trim().split_once('%').and_then(|(weight, model)| {
+                    let model = model.trim();
+                    Some(Arm { weight: weight.trim().parse().ok()?, model: model.to_string() }).filter(|_| !model.is_empty())
+                });
+                if parsed.is_none() {
+                    log::warn!("⚠️  Ignoring MODEL_EXPERIMENTS arm '{}' for '{}' (expected weight%model)", arm, name.trim());
+                }
+                parsed
+            })
+            .collect();
+        if arms.iter().map(|arm| arm.weight).sum::<u64>() > 0 {
+            experiments.insert(name.trim().to_string(), arms);
+        }
+    }
+    experiments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn experiments(entries: &[&str]) -> Experiments {
+        let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        Experiments { experiments: parse_experiments(&entries), assigned: Mutex::new(HashMap::new()) }
+    }
+
+    fn request(model: &str, first_message: &str) -> ClaudeRequest {
+        serde_json::from_value(json!({"model": model, "messages": [{"role": "user", "content": first_message}]})).unwrap()
+    }
+
+    #[test]
+    fn test_parse_experiments() {
+        let parsed = parse_experiments(&["sonnet=90%glm-4.6|10%qwen3:8b|x%bad".into(), "broken".into(), "zero=0%a".into()]);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed["sonnet"], vec![
+            Arm { weight: 90, model: "glm-4.6".into() },
+            Arm { weight: 10, model: "qwen3:8b".into() },
+        ]);
+    }
+
+    #[test]
+    fn test_assignment_is_sticky_and_weighted() {
+        let experiments = experiments(&["sonnet=75%a|25%b"]);
+        assert!(experiments.assign(&request("other", "hi"), "key").is_none());
+
+        let mut conversation = request("sonnet", "fix the build");
+        let first = experiments.assign(&conversation, "key").unwrap();
+        conversation.messages.push(serde_json::from_value(json!({"role": "assistant", "content": "done"})).unwrap());
+        assert_eq!(experiments.assign(&conversation, "key").unwrap(), first, "later turns keep their arm");
+
+        let picks: Vec<String> = (0..400).map(|i| experiments.assign(&request("sonnet", &format!("task {i}")), "key").unwrap().model).collect();
+        let a = picks.iter().filter(|m| *m == "a").count();
+        assert!((250..350).contains(&a), "about 75% of conversations get arm a, got {a}");
+        assert_eq!(experiments.snapshot()["sonnet"].iter().map(|arm| arm.assigned).sum::<u64>(), 402);
+    }
+}
@@ -0,0 +1,498 @@
+//! Anthropic Files API emulation: `/v1/files` uploads are kept on local disk (`FILES_DIR`) or in an
+//! S3 bucket (`FILES_S3_BUCKET`, `files-s3` feature), and `file_id` sources in image and document
+//! blocks are replaced with the stored bytes before a request is translated.
+//!
+//! Each file is stored as two objects: `<id>` with the bytes and `<owner>.<id>.json` with its
+//! metadata, where `<owner>` is the fingerprint of the client key that uploaded it. Files are only
+//! visible to that key, and listing a key's files reads only the metadata of the requested page.
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::config::env_or;
+use crate::constants::*;
+use crate::models::ClaudeMessage;
+use crate::services::llm_trace::rfc3339_from_ns;
+use crate::utils::image::sniff_media_type;
+
+const METADATA_SUFFIX: &str = ".json";
+
+/// Suffix of an upload still being received
+const PARTIAL_SUFFIX: &str = ".part";
+
+/// Leading bytes kept from an upload to detect its media type
+const SNIFF_BYTES: usize = 512;
+
+/// File object returned by the Files API
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileMetadata {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub object_type: String,
+    pub filename: String,
+    pub mime_type: String,
+    pub size_bytes: u64,
+    pub created_at: String,
+    pub downloadable: bool,
+}
+
+/// Metadata as persisted next to the file
+#[derive(Serialize, Deserialize)]
+struct StoredMetadata {
+    #[serde(flatten)]
+    file: FileMetadata,
+    /// Fingerprint of the uploading client key
+    owner: String,
+}
+
+#[derive(Debug)]
+pub enum FileError {
+    /// No such file, or it belongs to another client key
+    NotFound,
+    /// A request references a `file_id` but no file storage is configured
+    Disabled,
+    TooLarge { bytes: usize, limit: usize },
+    /// The upload body could not be read
+    Upload(String),
+    Storage(String),
+}
+
+impl FileError {
+    /// Short error code returned to the client
+    pub fn code(&self) -> &'static str {
+        match self {
+            FileError::NotFound => "file_not_found",
+            FileError::Disabled => "files_disabled",
+            FileError::TooLarge { .. } => "file_too_large",
+            FileError::Upload(_) => "invalid_multipart",
+            FileError::Storage(_) => "file_storage_error",
+        }
+    }
+}
+
+enum Storage {
+    Disk(PathBuf),
+    #[cfg(feature = "files-s3")]
+    S3 { client: aws_sdk_s3::Client, bucket: String, prefix: String },
+}
+
+#[cfg(feature = "files-s3")]
+fn s3_error<E: std::error::Error>(e: E) -> FileError {
+    FileError::Storage(aws_sdk_s3::error::DisplayErrorContext(e).to_string())
+}
+
+impl Storage {
+    async fn read(&self, key: &str) -> Result<Option<Vec<u8>>, FileError> {
+        match self {
+            Storage::Disk(dir) => match tokio::fs::read(dir.join(key)).await {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(FileError::Storage(e.to_string())),
+            },
+            #[cfg(feature = "files-s3")]
+            Storage::S3 { client, bucket, prefix } => {
+                match client.get_object().bucket(bucket).key(format!("{prefix}{key}")).send().await {
+                    Ok(object) => Ok(Some(object.body.collect().await.map_err(s3_error)?.to_vec())),
+                    Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+                    Err(e) => Err(s3_error(e)),
+                }
+            }
+        }
+    }
+
+    /// Where uploads are received before `commit` moves them into place
+    fn spool_dir(&self) -> PathBuf {
+        match self {
+            Storage::Disk(dir) => dir.clone(),
+            #[cfg(feature = "files-s3")]
+            Storage::S3 { .. } => env::temp_dir(),
+        }
+    }
+
+    /// Store the received upload at `spool` under `key`
+    async fn commit(&self, key: &str, spool: &Path) -> Result<(), FileError> {
+        match self {
+            Storage::Disk(dir) => tokio::fs::rename(spool, dir.join(key)).await.map_err(|e| FileError::Storage(e.to_string())),
+            #[cfg(feature = "files-s3")]
+            Storage::S3 { client, bucket, prefix } => {
+                let body = aws_sdk_s3::primitives::ByteStream::from_path(spool).await.map_err(s3_error)?;
+                let sent = client.put_object().bucket(bucket).key(format!("{prefix}{key}")).body(body).send().await;
+                let _ = tokio::fs::remove_file(spool).await;
+                sent.map_err(s3_error)?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn write(&self, key: &str, data: Bytes) -> Result<(), FileError> {
+        match self {
+            Storage::Disk(dir) => tokio::fs::write(dir.join(key), data).await.map_err(|e| FileError::Storage(e.to_string())),
+            #[cfg(feature = "files-s3")]
+            Storage::S3 { client, bucket, prefix } => {
+                client.put_object().bucket(bucket).key(format!("{prefix}{key}")).body(data.into()).send().await.map_err(s3_error)?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), FileError> {
+        match self {
+            Storage::Disk(dir) => match tokio::fs::remove_file(dir.join(key)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(FileError::Storage(e.to_string())),
+                _ => Ok(()),
+            },
+            #[cfg(feature = "files-s3")]
+            Storage::S3 { client, bucket, prefix } => {
+                client.delete_object().bucket(bucket).key(format!("{prefix}{key}")).send().await.map_err(s3_error)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Names of the stored objects starting with `start`
+    async fn keys(&self, start: &str) -> Result<Vec<String>, FileError> {
+        match self {
+            Storage::Disk(dir) => {
+                let mut entries = tokio::fs::read_dir(dir).await.map_err(|e| FileError::Storage(e.to_string()))?;
+                let mut keys = Vec::new();
+                while let Some(entry) = entries.next_entry().await.map_err(|e| FileError::Storage(e.to_string()))? {
+                    keys.extend(entry.file_name().to_str().filter(|name| name.starts_with(start)).map(String::from));
+                }
+                Ok(keys)
+            }
+            #[cfg(feature = "files-s3")]
+            Storage::S3 { client, bucket, prefix } => {
+                let mut keys = Vec::new();
+                let mut token = None;
+                loop {
+                    let page = client
+                        .list_objects_v2()
+                        .bucket(bucket)
+                        .prefix(format!("{prefix}{start}"))
+                        .set_continuation_token(token)
+                        .send()
+                        .await
+                        .map_err(s3_error)?;
+                    keys.extend(
+                        page.contents().iter().filter_map(|o| o.key()?.strip_prefix(prefix.as_str())).map(String::from),
+                    );
+                    match page.next_continuation_token() {
+                        Some(next) => token = Some(next.to_string()),
+                        None => return Ok(keys),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Uploaded files for the `/v1/files` endpoints and `file_id` references
+pub struct FileStore {
+    storage: Storage,
+    /// Largest accepted upload (`FILES_MAX_BYTES`)
+    pub max_bytes: usize,
+    next_id: AtomicU64,
+}
+
+impl FileStore {
+    /// Store files as plain files in `dir`
+    pub fn disk(dir: PathBuf, max_bytes: usize) -> Self {
+        Self { storage: Storage::Disk(dir), max_bytes, next_id: AtomicU64::new(0) }
+    }
+
+    /// `FILES_S3_BUCKET` (with `FILES_S3_PREFIX` / `FILES_S3_ENDPOINT`) or `FILES_DIR`; `None` when
+    /// neither is set
+    pub async fn from_env() -> Option<Self> {
+        let max_bytes = env_or("FILES_MAX_BYTES", DEFAULT_FILES_MAX_BYTES);
+        if let Some(bucket) = env::var("FILES_S3_BUCKET").ok().filter(|b| !b.trim().is_empty()) {
+            return Self::s3(bucket.trim().to_string(), max_bytes).await;
+        }
+        let dir = env::var("FILES_DIR").ok().filter(|d| !d.trim().is_empty())?;
+        let dir = PathBuf::from(dir.trim());
+        if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+            log::error!("❌ FILES_DIR {} is not usable: {}", dir.display(), e);
+            return None;
+        }
+        log::info!("📁 Files API: storing uploads in {}", dir.display());
+        Some(Self::disk(dir, max_bytes))
+    }
+
+    #[cfg(feature = "files-s3")]
+    async fn s3(bucket: String, max_bytes: usize) -> Option<Self> {
+        let prefix = env::var("FILES_S3_PREFIX").unwrap_or_default().trim().to_string();
+        let aws = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        let mut config = aws_sdk_s3::config::Builder::from(&aws);
+        // S3-compatible stores (MinIO, R2, ...) usually need path-style addressing
+        if let Some(endpoint) = env::var("FILES_S3_ENDPOINT").ok().filter(|e| !e.trim().is_empty()) {
+            config = config.endpoint_url(endpoint.trim()).force_path_style(true);
+        }
+        let client = aws_sdk_s3::Client::from_conf(config.build());
+        log::info!("📁 Files API: storing uploads in s3://{}/{}", bucket, prefix);
+        Some(Self { storage: Storage::S3 { client, bucket, prefix }, max_bytes, next_id: AtomicU64::new(0) })
+    }
+
+    #[cfg(not(feature = "files-s3"))]
+    async fn s3(_bucket: String, _max_bytes: usize) -> Option<Self> {
+        log::warn!("⚠️  FILES_S3_BUCKET is set but the proxy was built without the 'files-s3' feature");
+        None
+    }
+
+    /// Store an upload read from `body`, which is cut off once it exceeds `max_bytes`. It is
+    /// received into a file rather than memory; the media type is detected from its first bytes.
+    pub async fn upload<S, E>(&self, filename: &str, mime_type: Option<&str>, body: S, owner: &str) -> Result<FileMetadata, FileError>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        E: std::fmt::Display,
+    {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let id = format!("file_{:x}{:04x}", now.as_nanos(), self.next_id.fetch_add(1, Ordering::Relaxed) & 0xffff);
+        let spool = self.storage.spool_dir().join(format!("{id}{PARTIAL_SUFFIX}"));
+        let (size, head) = match receive(body, &spool, self.max_bytes).await {
+            Ok(received) => received,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&spool).await;
+                return Err(e);
+            }
+        };
+        let file = FileMetadata {
+            id: id.clone(),
+            object_type: "file".into(),
+            filename: filename.to_string(),
+            mime_type: detect_mime_type(filename, mime_type, &head),
+            size_bytes: size as u64,
+            created_at: rfc3339_from_ns(now.as_nanos()),
+            downloadable: true,
+        };
+        let stored = StoredMetadata { file: file.clone(), owner: owner.to_string() };
+        let metadata = serde_json::to_vec(&stored).map_err(|e| FileError::Storage(e.to_string()))?;
+        // Bytes first: a file is only listed once its metadata exists
+        self.storage.commit(&id, &spool).await?;
+        self.storage.write(&metadata_key(owner, &id), metadata.into()).await?;
+        Ok(file)
+    }
+
+    async fn stored(&self, id: &str, owner: &str) -> Result<StoredMetadata, FileError> {
+        if !is_valid_file_id(id) {
+            return Err(FileError::NotFound);
+        }
+        // Another key's file has a different metadata key, so it is never read
+        let metadata = self.storage.read(&metadata_key(owner, id)).await?.ok_or(FileError::NotFound)?;
+        serde_json::from_slice(&metadata).map_err(|e| FileError::Storage(e.to_string()))
+    }
+
+    pub async fn get(&self, id: &str, owner: &str) -> Result<FileMetadata, FileError> {
+        Ok(self.stored(id, owner).await?.file)
+    }
+
+    pub async fn content(&self, id: &str, owner: &str) -> Result<(FileMetadata, Vec<u8>), FileError> {
+        let stored = self.stored(id, owner).await?;
+        let data = self.storage.read(id).await?.ok_or(FileError::NotFound)?;
+        Ok((stored.file, data))
+    }
+
+    /// Ids of `owner`'s files, newest first, without reading their metadata
+    pub async fn list_ids(&self, owner: &str) -> Result<Vec<String>, FileError> {
+        let start = format!("{owner}.");
+        let mut ids: Vec<String> = self
+            .storage
+            .keys(&start)
+            .await?
+            .iter()
+            .filter_map(|key| key.strip_prefix(start.as_str())?.strip_suffix(METADATA_SUFFIX))
+            .filter(|id| is_valid_file_id(id))
+            .map(String::from)
+            .collect();
+        // Ids start with the upload time in hex nanoseconds
+        ids.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| b.cmp(a)));
+        Ok(ids)
+    }
+
+    pub async fn delete(&self, id: &str, owner: &str) -> Result<(), FileError> {
+        self.stored(id, owner).await?;
+        self.storage.remove(&metadata_key(owner, id)).await?;
+        self.storage.remove(id).await
+    }
+}
+
+fn metadata_key(owner: &str, id: &str) -> String {
+    format!("{owner}.{id}{METADATA_SUFFIX}")
+}
+
+/// Write `body` to `spool`; its size and first bytes
+async fn receive<S, E>(body: S, spool: &Path, max_bytes: usize) -> Result<(usize, Vec<u8>), FileError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: std::fmt::Display,
+{
+    let storage_error = |e: std::io::Error| FileError::Storage(e.to_string());
+    let mut out = tokio::fs::File::create(spool).await.map_err(storage_error)?;
+    let (mut size, mut head) = (0, Vec::new());
+    let mut body = std::pin::pin!(body);
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| FileError::Upload(e.to_string()))?;
+        size += chunk.len();
+        if size > max_bytes {
+            return Err(FileError::TooLarge { bytes: size, limit: max_bytes });
+        }
+        if head.len() < SNIFF_BYTES {
+            head.extend_from_slice(&chunk[..chunk.len().min(SNIFF_BYTES - head.len())]);
+        }
+        out.write_all(&chunk).await.map_err(storage_error)?;
+    }
+    out.flush().await.map_err(storage_error)?;
+    Ok((size, head))
+}
+
+/// Ids this store issued; anything else (including path separators) never reaches the storage
+fn is_valid_file_id(id: &str) -> bool {
+    id.starts_with("file_") && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+fn detect_mime_type(filename: &str, declared: Option<&str>, data: &[u8]) -> String {
+    if let Some(sniffed) = sniff_media_type(data) {
+        return sniffed.to_string();
+    }
+    if data.starts_with(b"%PDF-") {
+        return "application/pdf".into();
+    }
+    if let Some(declared) = declared.map(str::trim).filter(|m| !m.is_empty() && *m != "application/octet-stream") {
+        return declared.to_ascii_lowercase();
+    }
+    let extension = filename.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "pdf" => "application/pdf",
+        "txt" | "log" => "text/plain",
+        "md" | "markdown" => "text/markdown",
+        "csv" => "text/csv",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        _ => "application/octet-stream",
+    }
+    .to_string()
+}
+
+fn is_text_mime_type(mime_type: &str) -> bool {
+    mime_type.starts_with("text/") || matches!(mime_type, "application/json" | "application/xml")
+}
+
+/// `file_id` of an image or document block with a `{"type": "file"}` source
+fn file_source_id(block: &Value) -> Option<&str> {
+    if !matches!(block.get("type").and_then(Value::as_str), Some("image" | "document")) {
+        return None;
+    }
+    let source = block.get("source")?;
+    (source.get("type")?.as_str()? == "file").then(|| source.get("file_id")?.as_str()).flatten()
+}
+
+/// Replace `file_id` sources in image and document blocks with the stored bytes (text documents as
+/// a `text` source); only the client key that uploaded a file can reference it. Returns how many
+/// were inlined.
+pub async fn resolve_file_sources(store: Option<&FileStore>, messages: &mut [ClaudeMessage], owner: Option<&str>) -> Result<usize, FileError> {
+    let mut resolved = 0;
+    for message in messages.iter_mut() {
+        let Some(blocks) = message.content.as_array_mut() else { continue };
+        for block in blocks.iter_mut() {
+            let Some(id) = file_source_id(block) else { continue };
+            let (file, data) = store.ok_or(FileError::Disabled)?.content(id, owner.ok_or(FileError::NotFound)?).await?;
+            let is_document = block["type"] == "document";
+            block["source"] = match std::str::from_utf8(&data) {
+                Ok(text) if is_document && is_text_mime_type(&file.mime_type) => {
+                    json!({ "type": "text", "media_type": "text/plain", "data": text })
+                }
+                _ => json!({ "type": "base64", "media_type": file.mime_type, "data": BASE64.encode(&data) }),
+            };
+            if is_document && block.get("title").is_none() {
+                block["title"] = json!(file.filename);
+            }
+            resolved += 1;
+        }
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store() -> FileStore {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        let dir = std::env::temp_dir().join(format!("files_test_{}", nanos));
+        std::fs::create_dir_all(&dir).unwrap();
+        FileStore::disk(dir, 1024)
+    }
+
+    fn body(data: &'static [u8]) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+        futures::stream::iter(data.chunks(3).map(|chunk| Ok(Bytes::from_static(chunk))).collect::<Vec<_>>())
+    }
+
+    #[tokio::test]
+    async fn test_upload_get_list_delete() {
+        let store = temp_store();
+        let first = store.upload("notes.txt", None, body(b"hello"), "fp_a").await.unwrap();
+        assert_eq!(first.mime_type, "text/plain");
+        assert_eq!(first.size_bytes, 5);
+        let second = store.upload("upload", Some("application/octet-stream"), body(b"\x89PNG\r\n\x1a\n...."), "fp_a").await.unwrap();
+        assert_eq!(second.mime_type, "image/png");
+        assert_eq!(store.content(&second.id, "fp_a").await.unwrap().1, b"\x89PNG\r\n\x1a\n....");
+
+        assert_eq!(store.get(&first.id, "fp_a").await.unwrap(), first);
+        assert_eq!(store.list_ids("fp_a").await.unwrap(), [second.id.clone(), first.id.clone()]);
+
+        // Another key sees none of them
+        assert!(matches!(store.get(&first.id, "fp_b").await, Err(FileError::NotFound)));
+        assert!(matches!(store.delete(&first.id, "fp_b").await, Err(FileError::NotFound)));
+        assert!(store.list_ids("fp_b").await.unwrap().is_empty());
+
+        store.delete(&first.id, "fp_a").await.unwrap();
+        assert!(matches!(store.content(&first.id, "fp_a").await, Err(FileError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_upload_limits_and_ids() {
+        let store = temp_store();
+        let big = futures::stream::iter((0..4).map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![0u8; 512]))));
+        // Cut off at the chunk that crosses the limit, with nothing left behind
+        assert!(matches!(store.upload("big.bin", None, big, "fp_a").await, Err(FileError::TooLarge { bytes: 1536, limit: 1024 })));
+        assert_eq!(std::fs::read_dir(store.storage.spool_dir()).unwrap().count(), 0);
+        assert!(matches!(store.get("../secrets", "fp_a").await, Err(FileError::NotFound)));
+        assert!(matches!(store.get("file_../../etc/passwd", "fp_a").await, Err(FileError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_file_sources() {
+        let store = temp_store();
+        let text = store.upload("spec.md", None, body(b"# Spec"), "fp_a").await.unwrap();
+        let image = store.upload("a.png", None, body(b"\x89PNG\r\n\x1a\n"), "fp_a").await.unwrap();
+        let mut messages = vec![ClaudeMessage {
+            role: "user".into(),
+            content: json!([
+                { "type": "document", "source": { "type": "file", "file_id": text.id } },
+                { "type": "image", "source": { "type": "file", "file_id": image.id } },
+                { "type": "text", "text": "summarize" }
+            ]),
+        }];
+        let mut keyless = messages.clone();
+        assert!(matches!(resolve_file_sources(Some(&store), &mut keyless, None).await, Err(FileError::NotFound)));
+        assert_eq!(resolve_file_sources(Some(&store), &mut messages, Some("fp_a")).await.unwrap(), 2);
+        let blocks = &messages[0].content;
+        assert_eq!(blocks[0]["source"], json!({ "type": "text", "media_type": "text/plain", "data": "# Spec" }));
+        assert_eq!(blocks[0]["title"], "spec.md");
+        assert_eq!(blocks[1]["source"]["media_type"], "image/png");
+        assert_eq!(blocks[1]["source"]["data"], BASE64.encode(b"\x89PNG\r\n\x1a\n"));
+
+        let mut missing = vec![ClaudeMessage {
+            role: "user".into(),
+            content: json!([{ "type": "image", "source": { "type": "file", "file_id": "file_missing" } }]),
+        }];
+        assert!(matches!(resolve_file_sources(Some(&store), &mut missing, Some("fp_a")).await, Err(FileError::NotFound)));
+        assert!(matches!(resolve_file_sources(None, &mut missing, Some("fp_a")).await, Err(FileError::Disabled)));
+    }
+}
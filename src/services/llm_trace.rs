@@ -0,0 +1,463 @@
+use std::{
+    hash::{BuildHasher, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use futures::future::BoxFuture;
+use reqwest::Client;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use crate::constants::*;
+use crate::models::OAIChatReq;
+use crate::services::pii::PiiMasker;
+use crate::services::transform::{CompletionSummary, StreamEvent, Transform, TransformContext, TransformResult};
+
+/// Where LLM spans are exported
+#[derive(Debug, Clone)]
+pub enum TraceBackend {
+    Langfuse { host: String, public_key: String, secret_key: String },
+    /// OTLP/HTTP JSON collector base URL (spans are POSTed to `{endpoint}/v1/traces`)
+    Otlp { endpoint: String },
+}
+
+/// How much prompt/completion content is included in exported spans
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentCapture {
+    /// Metadata only (model, usage, latency)
+    Omit,
+    /// Content truncated to `LLM_TRACE_MAX_CONTENT_CHARS`
+    Truncate,
+    Full,
+}
+
+impl ContentCapture {
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "omit" | "none" => Some(ContentCapture::Omit),
+            "truncate" => Some(ContentCapture::Truncate),
+            "full" => Some(ContentCapture::Full),
+            _ => None,
+        }
+    }
+}
+
+/// Per-request trace state carried in `TransformContext::extensions`
+#[derive(Clone)]
+struct TraceState {
+    start_ns: u128,
+    session_id: Option<String>,
+    prompt: Value,
+    completion: String,
+}
+
+/// One finished LLM call, ready to export
+struct LlmSpan {
+    request_id: String,
+    session_id: Option<String>,
+    client: String,
+    model: String,
+    prompt: Option<Value>,
+    completion: Option<String>,
+    input_tokens: u32,
+    output_tokens: u32,
+    stop_reason: String,
+    is_error: bool,
+    start_ns: u128,
+    end_ns: u128,
+}
+
+/// Transform that records prompt, completion, usage and latency per request and exports them
+pub struct LlmTraceExporter {
+    capture: ContentCapture,
+    max_chars: usize,
+    /// `LLM_TRACE_REDACT` and `LLM_TRACE_REDACT_PATTERN_<NAME>` matches, replaced before export
+    redactor: Option<PiiMasker>,
+    tx: mpsc::Sender<LlmSpan>,
+}
+
+impl LlmTraceExporter {
+    /// Build from `LLM_TRACE_EXPORTER` (`langfuse` or `otlp`); `None` when disabled
+    pub fn from_env(client: Client) -> Option<Self> {
+        use crate::config::env_or;
+        let backend = match std::env::var("LLM_TRACE_EXPORTER").ok()?.trim().to_ascii_lowercase().as_str() {
+            "langfuse" => {
+                let (Ok(public_key), Ok(secret_key)) =
+                    (std::env::var("LANGFUSE_PUBLIC_KEY"), std::env::var("LANGFUSE_SECRET_KEY"))
+                else {
+                    log::error!("❌ LLM_TRACE_EXPORTER=langfuse requires LANGFUSE_PUBLIC_KEY and LANGFUSE_SECRET_KEY");
+                    return None;
+                };
+                TraceBackend::Langfuse {
+                    host: env_or("LANGFUSE_HOST", "https://cloud.langfuse.com".to_string()),
+                    public_key,
+                    secret_key,
+                }
+            }
+            "otlp" => TraceBackend::Otlp {
+                endpoint: env_or("OTEL_EXPORTER_OTLP_ENDPOINT", "http://127.0.0.1:4318".to_string()),
+            },
+            other => {
+                log::error!("❌ Unknown LLM_TRACE_EXPORTER '{}' (expected langfuse or otlp)", other);
+                return None;
+            }
+        };
+        let capture = std::env::var("LLM_TRACE_CONTENT")
+            .ok()
+            .and_then(|s| ContentCapture::parse(&s))
+            .unwrap_or(ContentCapture::Omit);
+        log::info!("   LLM traces: {:?} (content: {:?})", backend, capture);
+
+        let (tx, rx) = mpsc::channel(LLM_TRACE_QUEUE_SIZE);
+        tokio::spawn(run_exporter(backend, rx, client));
+        Some(Self {
+            capture,
+            max_chars: env_or("LLM_TRACE_MAX_CONTENT_CHARS", DEFAULT_LLM_TRACE_MAX_CONTENT_CHARS),
+            redactor: PiiMasker::from_vars("LLM_TRACE_REDACT", "LLM_TRACE_REDACT_PATTERN_", false),
+            tx,
+        })
+    }
+
+    fn redact_text(&self, text: &str) -> Option<String> {
+        if self.capture == ContentCapture::Omit {
+            return None;
+        }
+        // Redact before truncating so a match cut in half can't slip through
+        let text = match &self.redactor {
+            Some(redactor) => redactor.redact(text),
+            None => text.to_string(),
+        };
+        match self.capture {
+            ContentCapture::Truncate => Some(truncate_chars(&text, self.max_chars)),
+            _ => Some(text),
+        }
+    }
+}
+
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}…", &text[..idx]),
+        None => text.to_string(),
+    }
+}
+
+fn now_ns() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
+/// Random lowercase hex id of `bytes` bytes (trace ids are 16 bytes, span ids 8)
+fn random_hex(bytes: usize) -> String {
+    let mut out = String::with_capacity(bytes * 2);
+    while out.len() < bytes * 2 {
+        let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+        h.write_u128(now_ns());
+        out.push_str(&format!("{:016x}", h.finish()));
+    }
+    out.truncate(bytes * 2);
+    out
+}
+
+impl Transform for LlmTraceExporter {
+    fn name(&self) -> &str {
+        "llm_trace"
+    }
+
+    fn on_oai_request<'a>(
+        &'a self,
+        ctx: &'a mut TransformContext,
+        req: &'a mut OAIChatReq,
+    ) -> BoxFuture<'a, TransformResult> {
+        Box::pin(async move {
+            // Claude Code puts a per-session identifier in metadata.user_id
+            let session_id = req
+                .metadata
+                .as_ref()
+                .and_then(|m| m.get("user_id"))
+                .and_then(|v| v.as_str())
+                .map(String::from);
+            let prompt = if self.capture == ContentCapture::Omit {
+                Value::Null
+            } else {
+                serde_json::to_value(&req.messages).unwrap_or(Value::Null)
+            };
+            ctx.extensions.insert(TraceState {
+                start_ns: now_ns(),
+                session_id,
+                prompt,
+                completion: String::new(),
+            });
+            Ok(())
+        })
+    }
+
+    fn on_stream_event<'a>(
+        &'a self,
+        ctx: &'a mut TransformContext,
+        event: &'a mut StreamEvent,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if self.capture == ContentCapture::Omit || event.event != "content_block_delta" {
+                return;
+            }
+            if let Some(state) = ctx.extensions.get_mut::<TraceState>() {
+                if let Some(text) = event.data["delta"]["text"].as_str() {
+                    state.completion.push_str(text);
+                } else if let Some(json) = event.data["delta"]["partial_json"].as_str() {
+                    state.completion.push_str(json);
+                }
+            }
+        })
+    }
+
+    fn on_complete<'a>(
+        &'a self,
+        ctx: &'a mut TransformContext,
+        summary: &'a CompletionSummary,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let Some(state) = ctx.extensions.remove::<TraceState>() else {
+                return;
+            };
+            let prompt = match self.capture {
+                ContentCapture::Omit => None,
+                _ => self.redact_text(&state.prompt.to_string()).map(|p| {
+                    // Keep structured prompts when they fit, otherwise export the truncated string
+                    serde_json::from_str(&p).unwrap_or(Value::String(p))
+                }),
+            };
+            let span = LlmSpan {
+                request_id: ctx.request_id.clone(),
+                session_id: state.session_id,
+                client: ctx.client.to_string(),
+                model: ctx.model.clone(),
+                prompt,
+                completion: self.redact_text(&state.completion),
+                input_tokens: summary.input_tokens,
+                output_tokens: summary.output_tokens,
+                stop_reason: summary.stop_reason.clone(),
+                is_error: summary.fatal_error,
+                start_ns: state.start_ns,
+                end_ns: now_ns(),
+            };
+            if self.tx.try_send(span).is_err() {
+                log::debug!("⚠️  LLM trace queue full, dropping span");
+            }
+        })
+    }
+}
+
+async fn run_exporter(backend: TraceBackend, mut rx: mpsc::Receiver<LlmSpan>, client: Client) {
+    while let Some(span) = rx.recv().await {
+        let req = match &backend {
+            TraceBackend::Langfuse { host, public_key, secret_key } => client
+                .post(format!("{}/api/public/ingestion", host.trim_end_matches('/')))
+                .basic_auth(public_key, Some(secret_key))
+                .json(&langfuse_batch(&span)),
+            TraceBackend::Otlp { endpoint } => client
+                .post(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+                .json(&otlp_payload(&span)),
+        };
+        match req.send().await {
+            Ok(res) if !res.status().is_success() => {
+                log::warn!("⚠️  LLM trace export returned {}", res.status());
+            }
+            Err(e) => log::warn!("⚠️  LLM trace export failed: {}", e),
+            _ => {}
+        }
+    }
+}
+
+pub(crate) fn rfc3339_from_ns(ns: u128) -> String {
+    // Millisecond-precision UTC timestamp without pulling in a date library
+    let secs = (ns / 1_000_000_000) as i64;
+    let millis = (ns / 1_000_000 % 1000) as u32;
+    let days = secs.div_euclid(86_400);
+    let rem = secs.rem_euclid(86_400);
+    // Civil-from-days (Howard Hinnant)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        y, m, d, rem / 3600, rem % 3600 / 60, rem % 60, millis
+    )
+}
+
+/// Langfuse ingestion batch: one trace per request plus its generation
+fn langfuse_batch(span: &LlmSpan) -> Value {
+    let start = rfc3339_from_ns(span.start_ns);
+    let end = rfc3339_from_ns(span.end_ns);
+    json!({
+        "batch": [
+            {
+                "id": random_hex(16),
+                "type": "trace-create",
+                "timestamp": start,
+                "body": {
+                    "id": span.request_id,
+                    "name": "claude-proxy",
+                    "sessionId": span.session_id,
+                    "tags": [format!("client:{}", span.client)],
+                    "input": span.prompt,
+                    "output": span.completion,
+                }
+            },
+            {
+                "id": random_hex(16),
+                "type": "generation-create",
+                "timestamp": end,
+                "body": {
+                    "id": format!("{}-generation", span.request_id),
+                    "traceId": span.request_id,
+                    "name": "chat",
+                    "model": span.model,
+                    "startTime": start,
+                    "endTime": end,
+                    "input": span.prompt,
+                    "output": span.completion,
+                    "level": if span.is_error { "ERROR" } else { "DEFAULT" },
+                    "metadata": { "stop_reason": span.stop_reason, "client": span.client },
+                    "usage": {
+                        "input": span.input_tokens,
+                        "output": span.output_tokens,
+                        "unit": "TOKENS"
+                    }
+                }
+            }
+        ]
+    })
+}
+
+/// OTLP/HTTP JSON payload using the GenAI semantic conventions
+fn otlp_payload(span: &LlmSpan) -> Value {
+    let str_attr = |k: &str, v: &str| json!({ "key": k, "value": { "stringValue": v } });
+    let int_attr = |k: &str, v: u32| json!({ "key": k, "value": { "intValue": v.to_string() } });
+
+    let mut attributes = vec![
+        str_attr("gen_ai.operation.name", "chat"),
+        str_attr("gen_ai.system", "openai"),
+        str_attr("gen_ai.request.model", &span.model),
+        str_attr("gen_ai.response.id", &span.request_id),
+        str_attr("claude_proxy.client", &span.client),
+        int_attr("gen_ai.usage.input_tokens", span.input_tokens),
+        int_attr("gen_ai.usage.output_tokens", span.output_tokens),
+        json!({
+            "key": "gen_ai.response.finish_reasons",
+            "value": { "arrayValue": { "values": [{ "stringValue": span.stop_reason }] } }
+        }),
+    ];
+    if let Some(session) = &span.session_id {
+        attributes.push(str_attr("session.id", session));
+    }
+    if let Some(prompt) = &span.prompt {
+        attributes.push(str_attr("gen_ai.prompt", &prompt.to_string()));
+    }
+    if let Some(completion) = &span.completion {
+        attributes.push(str_attr("gen_ai.completion", completion));
+    }
+
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [str_attr("service.name", "claude-proxy")] },
+            "scopeSpans": [{
+                "scope": { "name": "claude-proxy" },
+                "spans": [{
+                    "traceId": random_hex(16),
+                    "spanId": random_hex(8),
+                    "name": format!("chat {}", span.model),
+                    "kind": 3,
+                    "startTimeUnixNano": span.start_ns.to_string(),
+                    "endTimeUnixNano": span.end_ns.to_string(),
+                    "attributes": attributes,
+                    "status": { "code": if span.is_error { 2 } else { 1 } }
+                }]
+            }]
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span() -> LlmSpan {
+        LlmSpan {
+            request_id: "msg_1".into(),
+            session_id: Some("session_abc".into()),
+            client: "claude-code/1.0.45".into(),
+            model: "test-model".into(),
+            prompt: None,
+            completion: Some("hello".into()),
+            input_tokens: 10,
+            output_tokens: 2,
+            stop_reason: "end_turn".into(),
+            is_error: false,
+            start_ns: 1_700_000_000_000_000_000,
+            end_ns: 1_700_000_001_500_000_000,
+        }
+    }
+
+    #[test]
+    fn test_rfc3339_from_ns() {
+        assert_eq!(rfc3339_from_ns(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(rfc3339_from_ns(1_700_000_001_500_000_000), "2023-11-14T22:13:21.500Z");
+    }
+
+    #[test]
+    fn test_random_hex_length() {
+        assert_eq!(random_hex(16).len(), 32);
+        assert_eq!(random_hex(8).len(), 16);
+        assert!(random_hex(8).chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn test_redact_text_masks_patterns_before_truncating() {
+        let (tx, _rx) = mpsc::channel(1);
+        let custom = vec![("TICKET".to_string(), "TCK-[0-9]{4}".to_string())];
+        let exporter = LlmTraceExporter {
+            capture: ContentCapture::Truncate,
+            max_chars: 40,
+            redactor: Some(PiiMasker::new(&["email".to_string()], &custom, false)),
+            tx,
+        };
+        assert_eq!(
+            exporter.redact_text("mail jane@example.com about TCK-1234").unwrap(),
+            "mail [EMAIL_1] about [TICKET_1]"
+        );
+        let omit = LlmTraceExporter { capture: ContentCapture::Omit, ..exporter };
+        assert_eq!(omit.redact_text("jane@example.com"), None);
+    }
+
+    #[test]
+    fn test_truncate_chars_is_utf8_safe() {
+        assert_eq!(truncate_chars("héllo wörld", 4), "héll…");
+        assert_eq!(truncate_chars("short", 10), "short");
+    }
+
+    #[test]
+    fn test_langfuse_batch_contains_usage() {
+        let batch = langfuse_batch(&span());
+        let generation = &batch["batch"][1];
+        assert_eq!(generation["type"], "generation-create");
+        assert_eq!(generation["body"]["traceId"], "msg_1");
+        assert_eq!(generation["body"]["usage"]["input"], 10);
+        assert_eq!(batch["batch"][0]["body"]["sessionId"], "session_abc");
+        assert_eq!(batch["batch"][0]["body"]["tags"][0], "client:claude-code/1.0.45");
+    }
+
+    #[test]
+    fn test_otlp_payload_uses_genai_attributes() {
+        let payload = otlp_payload(&span());
+        let s = &payload["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        let attrs = s["attributes"].as_array().unwrap();
+        let find = |k: &str| attrs.iter().find(|a| a["key"] == k).cloned();
+        assert_eq!(find("gen_ai.request.model").unwrap()["value"]["stringValue"], "test-model");
+        assert_eq!(find("gen_ai.usage.output_tokens").unwrap()["value"]["intValue"], "2");
+        assert!(find("gen_ai.prompt").is_none());
+        assert_eq!(s["traceId"].as_str().unwrap().len(), 32);
+    }
+}
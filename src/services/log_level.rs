@@ -0,0 +1,202 @@
+//! Runtime log filter overrides (`PUT /admin/log-level`).
+//!
+//! The process logs through a logger built from `RUST_LOG` (or the command's default level). An
+//! override adds directives such as `claude_openai_proxy::handlers::messages=debug` on top of it
+//! and reverts by itself, so debugging a live issue needs no restart.
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use log::{Log, Metadata, Record};
+use serde::Serialize;
+
+static LOGGER: OnceLock<DynamicLogger> = OnceLock::new();
+
+/// Filters in effect, for `GET /admin/log-level`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LogLevelSnapshot {
+    /// `RUST_LOG`, or the default level when it is unset
+    pub base: String,
+    #[serde(rename = "override")]
+    pub active_override: Option<OverrideSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct OverrideSnapshot {
+    pub filter: String,
+    pub expires_in_secs: u64,
+}
+
+struct LogOverride {
+    filter: String,
+    logger: env_logger::Logger,
+    until: Instant,
+    generation: u64,
+}
+
+/// Logger whose filter can be widened for a while
+struct DynamicLogger {
+    base_filter: String,
+    base: env_logger::Logger,
+    active: RwLock<Option<LogOverride>>,
+    generation: AtomicU64,
+}
+
+impl DynamicLogger {
+    fn new(base_filter: &str) -> Self {
+        Self {
+            base_filter: base_filter.to_string(),
+            base: build_logger(&[base_filter]),
+            active: RwLock::new(None),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Add `filter` to the base filter until `duration` has passed; returns the override's generation
+    fn set(&self, filter: &str, duration: Duration) -> Result<u64, String> {
+        env_filter::Builder::new().try_parse(filter).map_err(|e| e.to_string())?;
+        let logger = build_logger(&[&self.base_filter, filter]);
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        log::set_max_level(logger.filter().max(self.base.filter()));
+        *self.active.write().unwrap_or_else(|e| e.into_inner()) = Some(LogOverride { filter: filter.to_string(), logger, until: Instant::now() + duration, generation });
+        Ok(generation)
+    }
+
+    /// Drop the override, or only the given generation so a newer override survives an old timer
+    fn clear(&self, generation: Option<u64>) -> bool {
+        let cleared = {
+            let mut active = self.active.write().unwrap_or_else(|e| e.into_inner());
+            match active.as_ref() {
+                Some(o) if generation.is_none_or(|g| g == o.generation) => active.take().is_some(),
+                _ => false,
+            }
+        };
+        if cleared {
+            log::set_max_level(self.base.filter());
+        }
+        cleared
+    }
+
+    fn snapshot(&self) -> LogLevelSnapshot {
+        let now = Instant::now();
+        let active = self.active.read().unwrap_or_else(|e| e.into_inner());
+        LogLevelSnapshot {
+            base: self.base_filter.clone(),
+            active_override: active.as_ref().filter(|o| o.until > now).map(|o| OverrideSnapshot {
+                filter: o.filter.clone(),
+                expires_in_secs: o.until.saturating_duration_since(now).as_secs(),
+            }),
+        }
+    }
+
+    fn with_active<T>(&self, f: impl FnOnce(&env_logger::Logger) -> T) -> T {
+        let active = self.active.read().unwrap_or_else(|e| e.into_inner());
+        match active.as_ref().filter(|o| o.until > Instant::now()) {
+            Some(o) => f(&o.logger),
+            None => f(&self.base),
+        }
+    }
+}
+
+impl Log for DynamicLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.with_active(|logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.with_active(|logger| logger.log(record))
+    }
+
+    fn flush(&self) {
+        self.base.flush()
+    }
+}
+
+/// env_logger with `filters` applied in order; later directives for the same module win
+fn build_logger(filters: &[&str]) -> env_logger::Logger {
+    let mut builder = env_logger::Builder::new();
+    if let Ok(style) = env::var("RUST_LOG_STYLE") {
+        builder.parse_write_style(&style);
+    }
+    for filter in filters {
+        builder.parse_filters(filter);
+    }
+    builder.build()
+}
+
+/// Install the process logger: `RUST_LOG`, or `default_level` when it is unset
+pub fn init(default_level: &str) {
+    let base_filter = env::var("RUST_LOG").ok().filter(|f| !f.trim().is_empty()).unwrap_or_else(|| default_level.to_string());
+    let logger = LOGGER.get_or_init(|| DynamicLogger::new(&base_filter));
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(logger.base.filter());
+    }
+}
+
+/// Widen the log filter for `duration`, replacing an earlier override
+pub fn set_override(filter: &str, duration: Duration) -> Result<LogLevelSnapshot, String> {
+    let logger = LOGGER.get().ok_or("logging is not initialized")?;
+    let generation = logger.set(filter, duration)?;
+    log::warn!("🔊 Log filter '{}' added for {}s", filter, duration.as_secs());
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        if logger.clear(Some(generation)) {
+            log::info!("🔉 Log filter override expired, back to '{}'", logger.base_filter);
+        }
+    });
+    Ok(logger.snapshot())
+}
+
+/// Return to the base filter now
+pub fn clear_override() -> Option<LogLevelSnapshot> {
+    let logger = LOGGER.get()?;
+    if logger.clear(None) {
+        log::warn!("🔉 Log filter override removed, back to '{}'", logger.base_filter);
+    }
+    Some(logger.snapshot())
+}
+
+pub fn snapshot() -> Option<LogLevelSnapshot> {
+    LOGGER.get().map(DynamicLogger::snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled(logger: &DynamicLogger, target: &str, level: log::Level) -> bool {
+        logger.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn test_override_widens_filter_until_cleared() {
+        let logger = DynamicLogger::new("info");
+        let messages = "claude_openai_proxy::handlers::messages";
+        assert!(!enabled(&logger, messages, log::Level::Debug));
+        assert!(logger.set("not a=valid=filter", Duration::from_secs(60)).is_err());
+
+        let first = logger.set(&format!("{messages}=debug"), Duration::from_secs(60)).unwrap();
+        assert!(enabled(&logger, messages, log::Level::Debug));
+        assert!(!enabled(&logger, "claude_openai_proxy::services::stats", log::Level::Debug));
+        assert!(enabled(&logger, "claude_openai_proxy::services::stats", log::Level::Info), "base directives still apply");
+        assert_eq!(logger.snapshot().active_override.unwrap().filter, format!("{messages}=debug"));
+
+        logger.set("trace", Duration::from_secs(60)).unwrap();
+        assert!(!logger.clear(Some(first)), "an old timer leaves a newer override alone");
+        assert!(logger.clear(None));
+        assert!(!enabled(&logger, messages, log::Level::Debug));
+        assert_eq!(logger.snapshot(), LogLevelSnapshot { base: "info".into(), active_override: None });
+    }
+
+    #[test]
+    fn test_expired_override_is_ignored() {
+        let logger = DynamicLogger::new("warn");
+        logger.set("debug", Duration::ZERO).unwrap();
+        assert!(!enabled(&logger, "anything", log::Level::Info));
+        assert!(logger.snapshot().active_override.is_none());
+    }
+}
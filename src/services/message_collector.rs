@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use axum::http::{header, HeaderMap};
+use serde_json::{json, Value};
+use crate::services::{MessageTemplates, SseEvent, DEBUG_EVENT};
+
+/// Whether the client asked for a JSON message rather than an event stream: its `Accept` header
+/// lists `application/json` but not `text/event-stream` (a missing header or `*/*` streams)
+pub fn accepts_json(headers: &HeaderMap) -> bool {
+    let media_types: Vec<String> = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|t| t.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+        .collect();
+    media_types.iter().any(|t| t == "application/json") && !media_types.iter().any(|t| t == "text/event-stream")
+}
+
+/// Rebuilds the Claude `message` object from the proxy's own event stream, for clients that
+/// negotiated JSON (`Accept: application/json`)
+#[derive(Debug, Clone, Default)]
+pub struct MessageCollector {
+    message: Option<Value>,
+    blocks: BTreeMap<i64, Value>,
+    /// `input_json_delta` fragments of tool blocks, parsed when the block stops
+    partial_json: BTreeMap<i64, String>,
+    /// `proxy_debug` events, returned in the message's `proxy_debug` field
+    debug: Vec<Value>,
+}
+
+impl MessageCollector {
+    /// Apply one event, routed by its `event:` name (the payload's `type` for unnamed events)
+    pub fn push(&mut self, sse: &SseEvent) {
+        let Ok(event) = serde_json::from_str::<Value>(&sse.data) else {
+            return;
+        };
+        self.push_event(sse.event.as_deref().or(event["type"].as_str()), &event);
+    }
+
+    /// Apply one already parsed event
+    pub fn push_event(&mut self, name: Option<&str>, event: &Value) {
+        let index = event["index"].as_i64().unwrap_or_default();
+        match name {
+            Some("message_start") => self.message = Some(event["message"].clone()),
+            Some("content_block_start") => {
+                self.blocks.insert(index, event["content_block"].clone());
+            }
+            Some("content_block_delta") => {
+                let Some(block) = self.blocks.get_mut(&index) else {
+                    return;
+                };
+                let delta = &event["delta"];
+                match delta["type"].as_str() {
+                    Some("text_delta") => append(block, "text", delta["text"].as_str()),
+                    Some("thinking_delta") => append(block, "thinking", delta["thinking"].as_str()),
+                    Some("signature_delta") => block["signature"] = delta["signature"].clone(),
+                    Some("input_json_delta") => {
+                        self.partial_json.entry(index).or_default().push_str(delta["partial_json"].as_str().unwrap_or_default())
+                    }
+                    Some("citations_delta") => match block["citations"].as_array_mut() {
+                        Some(citations) => citations.push(delta["citation"].clone()),
+                        None => block["citations"] = json!([delta["citation"]]),
+                    },
+                    _ => {}
+                }
+            }
+            Some("content_block_stop") => {
+                let json = self.partial_json.remove(&index).filter(|j| !j.trim().is_empty());
+                if let Some((block, json)) = self.blocks.get_mut(&index).zip(json) {
+                    block["input"] = serde_json::from_str(&json).unwrap_or_else(|_| json!({}));
+                }
+            }
+            Some("message_delta") => {
+                let Some(message) = self.message.as_mut() else {
+                    return;
+                };
+                for field in ["stop_reason", "stop_sequence"] {
+                    if let Some(value) = event["delta"].get(field) {
+                        message[field] = value.clone();
+                    }
+                }
+                if let (Some(usage), Some(Value::Object(update))) = (message["usage"].as_object_mut(), event.get("usage")) {
+                    usage.extend(update.clone());
+                }
+            }
+            Some(DEBUG_EVENT) => self.debug.push(event.clone()),
+            _ => {}
+        }
+    }
+
+    /// The finished message; `Err` with the message text when the stream ended in an error
+    pub fn finish(self, templates: &MessageTemplates) -> Result<Value, String> {
+        let mut message = self.message.ok_or_else(|| "The response ended before it started".to_string())?;
+        let content: Vec<Value> = self.blocks.into_values().collect();
+        if message["stop_reason"] == "error" {
+            let text: Vec<&str> = content.iter().filter_map(|b| b["text"].as_str()).collect();
+            return Err(if text.is_empty() { templates.stream_failed.clone() } else { text.join("\n") });
+        }
+        message["content"] = Value::Array(content);
+        if !self.debug.is_empty() {
+            message[DEBUG_EVENT] = Value::Array(self.debug);
+        }
+        Ok(message)
+    }
+}
+
+/// Extend a block's text in place: a long answer arrives in many small deltas
+fn append(block: &mut Value, field: &str, text: Option<&str>) {
+    let text = text.unwrap_or_default();
+    match &mut block[field] {
+        Value::String(current) => current.push_str(text),
+        other => *other = Value::String(text.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn unnamed(data: &str) -> SseEvent {
+        SseEvent { event: None, data: data.to_string() }
+    }
+
+    #[test]
+    fn test_accepts_json() {
+        let accept = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+            accepts_json(&headers)
+        };
+        assert!(accept("application/json"));
+        assert!(accept("application/json; charset=utf-8, text/plain"));
+        assert!(!accept("text/event-stream"));
+        assert!(!accept("application/json, text/event-stream"));
+        assert!(!accept("*/*"));
+        assert!(!accepts_json(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_collects_blocks_and_usage() {
+        let mut collector = MessageCollector::default();
+        for payload in [
+            r#"{"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"m","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":12,"output_tokens":0}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"thinking","thinking":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"Let me check"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"text_delta","text":"Reading "}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"text_delta","text":"it."}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"content_block_start","index":2,"content_block":{"type":"tool_use","id":"toolu_1","name":"read","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":2,"delta":{"type":"input_json_delta","partial_json":"{\"path\":"}}"#,
+            r#"{"type":"content_block_delta","index":2,"delta":{"type":"input_json_delta","partial_json":"\"a.rs\"}"}}"#,
+            r#"{"type":"content_block_stop","index":2}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"tool_use","stop_sequence":null},"usage":{"output_tokens":9}}"#,
+            r#"{"type":"message_stop"}"#,
+        ] {
+            collector.push(&unnamed(payload));
+        }
+        let message = collector.finish(&MessageTemplates::default()).unwrap();
+        assert_eq!(message["stop_reason"], "tool_use");
+        assert_eq!(message["usage"], json!({"input_tokens": 12, "output_tokens": 9}));
+        assert_eq!(message["content"], json!([
+            {"type": "thinking", "thinking": "Let me check"},
+            {"type": "text", "text": "Reading it."},
+            {"type": "tool_use", "id": "toolu_1", "name": "read", "input": {"path": "a.rs"}},
+        ]));
+    }
+
+    #[test]
+    fn test_error_stop_reason_is_an_error() {
+        let mut collector = MessageCollector::default();
+        collector.push(&unnamed(r#"{"type":"message_start","message":{"content":[],"usage":{}}}"#));
+        collector.push(&unnamed(r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":"Backend error: overloaded"}}"#));
+        collector.push(&unnamed(r#"{"type":"message_delta","delta":{"stop_reason":"error"},"usage":{"output_tokens":0}}"#));
+        assert_eq!(collector.finish(&MessageTemplates::default()).unwrap_err(), "Backend error: overloaded");
+        assert!(MessageCollector::default().finish(&MessageTemplates::default()).is_err());
+    }
+
+    #[test]
+    fn test_event_name_routes_before_payload_type() {
+        let mut collector = MessageCollector::default();
+        let named = |event: &str, data: &str| SseEvent { event: Some(event.into()), data: data.into() };
+        collector.push(&named("message_start", r#"{"message":{"id":"msg_1","content":[],"usage":{"output_tokens":0}}}"#));
+        collector.push(&named("content_block_start", r#"{"index":0,"content_block":{"type":"text","text":""}}"#));
+        collector.push(&named("content_block_delta", r#"{"index":0,"delta":{"type":"text_delta","text":"ok"}}"#));
+        let message = collector.finish(&MessageTemplates::default()).unwrap();
+        assert_eq!(message["content"], json!([{"type": "text", "text": "ok"}]));
+    }
+}
@@ -0,0 +1,203 @@
+//! Completed messages kept on disk (`MESSAGE_STORE_DIR`) so a client that lost the stream can
+//! fetch the finished message with `GET /v1/messages/{id}`.
+//!
+//! Each message is one `<id>.json` file holding the assembled message, the fingerprint of the
+//! client key that requested it and when it was stored. Only requests with a client key are
+//! stored, and only that key can read them back. Files older than `MESSAGE_STORE_TTL_SECS` are no
+//! longer served and are removed by a background sweep.
+use std::{
+    env,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::config::env_or;
+use crate::constants::{DEFAULT_MESSAGE_STORE_TTL_SECS, MESSAGE_STORE_PRUNE_INTERVAL_SECS};
+use crate::services::message_collector::MessageCollector;
+use crate::services::MessageTemplates;
+use crate::services::transform::{CompletionSummary, StreamEvent, Transform, TransformContext};
+
+#[derive(Serialize, Deserialize)]
+struct StoredMessage {
+    /// Fingerprint of the requesting client key
+    owner: String,
+    stored_ms: u64,
+    message: Value,
+}
+
+/// Finished messages by id, written as their streams complete
+pub struct MessageStore {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Ids the proxy issues (`msg_<digits>`); anything else never reaches the filesystem
+fn is_valid_message_id(id: &str) -> bool {
+    id.starts_with("msg_") && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+impl MessageStore {
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        Self { dir, ttl }
+    }
+
+    /// `MESSAGE_STORE_DIR` with `MESSAGE_STORE_TTL_SECS`; `None` when unset or unusable
+    pub fn from_env() -> Option<Self> {
+        let dir = env::var("MESSAGE_STORE_DIR").ok().filter(|d| !d.trim().is_empty())?;
+        let dir = PathBuf::from(dir.trim());
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            log::error!("❌ MESSAGE_STORE_DIR {} is not usable: {}", dir.display(), e);
+            return None;
+        }
+        let ttl = Duration::from_secs(env_or("MESSAGE_STORE_TTL_SECS", DEFAULT_MESSAGE_STORE_TTL_SECS));
+        log::info!("🗄️  Message store: finished messages kept in {} for {}s", dir.display(), ttl.as_secs());
+        Some(Self::new(dir, ttl))
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    fn expired(&self, stored_ms: u64) -> bool {
+        now_ms().saturating_sub(stored_ms) >= self.ttl.as_millis() as u64
+    }
+
+    /// The stored message, if it exists, hasn't expired and belongs to `owner`
+    pub async fn get(&self, id: &str, owner: &str) -> Option<Value> {
+        if !is_valid_message_id(id) {
+            return None;
+        }
+        let data = tokio::fs::read(self.path(id)).await.ok()?;
+        let stored: StoredMessage = serde_json::from_slice(&data).ok()?;
+        (stored.owner == owner && !self.expired(stored.stored_ms)).then_some(stored.message)
+    }
+
+    pub async fn put(&self, id: &str, owner: String, message: Value) {
+        if !is_valid_message_id(id) {
+            return;
+        }
+        let stored = StoredMessage { owner, stored_ms: now_ms(), message };
+        let data = serde_json::to_vec(&stored).unwrap_or_default();
+        // Readers never see a half-written file: write aside, then rename into place
+        let tmp = self.dir.join(format!("{id}.json.tmp"));
+        let written = match tokio::fs::write(&tmp, data).await {
+            Ok(()) => tokio::fs::rename(&tmp, self.path(id)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            log::warn!("⚠️  Message store: failed to write {}: {}", id, e);
+            let _ = tokio::fs::remove_file(&tmp).await;
+        }
+    }
+
+    /// Remove expired messages every `MESSAGE_STORE_PRUNE_INTERVAL_SECS`
+    pub fn spawn_pruning(self: &Arc<Self>) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(MESSAGE_STORE_PRUNE_INTERVAL_SECS));
+            loop {
+                interval.tick().await;
+                match store.prune().await {
+                    Ok(0) => {}
+                    Ok(removed) => log::info!("🗄️  Message store: removed {} expired message(s)", removed),
+                    Err(e) => log::warn!("⚠️  Message store: pruning failed: {}", e),
+                }
+            }
+        });
+    }
+
+    async fn prune(&self) -> std::io::Result<usize> {
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            // Skips `.json.tmp` files still being written
+            if entry.path().extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Ok(data) = tokio::fs::read(entry.path()).await else { continue };
+            let stored_ms = serde_json::from_slice::<StoredMessage>(&data).map_or(0, |stored| stored.stored_ms);
+            if self.expired(stored_ms) && tokio::fs::remove_file(entry.path()).await.is_ok() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl Transform for MessageStore {
+    fn name(&self) -> &str {
+        "message_store"
+    }
+
+    /// Messages for a client key are finished and stored even if the client has left
+    fn outlives_client(&self, ctx: &TransformContext) -> bool {
+        ctx.key_fingerprint.is_some()
+    }
+
+    fn on_stream_event<'a>(&'a self, ctx: &'a mut TransformContext, event: &'a mut StreamEvent) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if ctx.key_fingerprint.is_none() {
+                return;
+            }
+            if ctx.extensions.get::<MessageCollector>().is_none() {
+                ctx.extensions.insert(MessageCollector::default());
+            }
+            if let Some(collector) = ctx.extensions.get_mut::<MessageCollector>() {
+                collector.push_event(Some(event.event), &event.data);
+            }
+        })
+    }
+
+    fn on_complete<'a>(&'a self, ctx: &'a mut TransformContext, summary: &'a CompletionSummary) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let Some(collector) = ctx.extensions.remove::<MessageCollector>() else { return };
+            let Some(owner) = ctx.key_fingerprint.clone().filter(|_| !summary.fatal_error) else { return };
+            // Only finished messages are kept, so the wording of an error doesn't matter here
+            if let Ok(message) = collector.finish(&MessageTemplates::default()) {
+                self.put(&ctx.request_id, owner, message).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn store(name: &str, ttl: Duration) -> (MessageStore, PathBuf) {
+        let dir = env::temp_dir().join(format!("message_store_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        (MessageStore::new(dir.clone(), ttl), dir)
+    }
+
+    #[tokio::test]
+    async fn test_get_checks_owner_and_id() {
+        let (store, dir) = store("owner", Duration::from_secs(60));
+        store.put("msg_1", "owner".into(), json!({"id": "msg_1"})).await;
+        assert_eq!(store.get("msg_1", "owner").await, Some(json!({"id": "msg_1"})));
+        assert_eq!(store.get("msg_1", "someone else").await, None);
+        assert_eq!(store.get("../msg_1", "owner").await, None);
+        assert!(!dir.join("msg_1.json.tmp").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_messages_are_hidden_and_pruned() {
+        let (store, dir) = store("expiry", Duration::from_secs(60));
+        store.put("msg_1", "owner".into(), json!({})).await;
+        std::fs::write(dir.join("msg_2.json.tmp"), "").unwrap();
+        let expired = MessageStore::new(dir.clone(), Duration::ZERO);
+        assert_eq!(expired.get("msg_1", "owner").await, None);
+        assert_eq!(expired.prune().await.unwrap(), 1, "files being written are left alone");
+        assert_eq!(store.get("msg_1", "owner").await, None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! Text of the synthetic replies the proxy writes into the chat: backend errors, timeout and
+//! moderation notices, and the unknown-model list. `MESSAGE_TEMPLATES` replaces the built-in English set, for branding,
+//! another language, or terminals that garble emoji.
+use serde::Deserialize;
+
+/// Per price tier marker in the model list
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PriceTierLabels {
+    pub budget: String,
+    pub affordable: String,
+    pub moderate: String,
+    pub premium: String,
+    /// Models without pricing information
+    pub unknown: String,
+}
+
+impl Default for PriceTierLabels {
+    fn default() -> Self {
+        Self {
+            budget: "💰".into(),
+            affordable: "💵".into(),
+            moderate: "💸".into(),
+            premium: "💎".into(),
+            unknown: "    ".into(),
+        }
+    }
+}
+
+/// User-facing strings; `{name}` placeholders are filled in when rendering
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageTemplates {
+    pub backend_error_title: String,
+    /// `{model}`
+    pub backend_error_model: String,
+    /// Shown instead of the provider's message at `ERROR_VERBOSITY=friendly`
+    pub backend_error_generic: String,
+    /// `{message}`
+    pub backend_error_detail: String,
+    /// `{response}`, the raw body at `ERROR_VERBOSITY=raw`
+    pub backend_error_response: String,
+    /// `{code}`, `{secs}`: nothing arrived within `BACKEND_FIRST_TOKEN_TIMEOUT_SECS`
+    pub first_token_timeout: String,
+    /// `{code}`, `{secs}`: the response ran past `BACKEND_TOTAL_TIMEOUT_SECS`
+    pub total_timeout: String,
+    /// `{kb}`: a backend event outgrew `SSE_BUFFER_LIMIT_KB`
+    pub sse_buffer_limit: String,
+    /// The backend closed the stream before sending a finish_reason
+    pub stream_dropped: String,
+    /// `{categories}`: content moderation blocked the request or the response
+    pub moderation_refusal: String,
+    /// Error of a JSON response whose stream failed without an error text
+    pub stream_failed: String,
+    /// `{tokens}`
+    pub context_requested: String,
+    /// `{tokens}`
+    pub context_limit: String,
+    pub suggestions_title: String,
+    /// `{suggestion}`
+    pub suggestion_item: String,
+    pub suggestions_context_length: Vec<String>,
+    pub suggestions_rate_limit: Vec<String>,
+    pub suggestions_quota: Vec<String>,
+    /// `{model}`
+    pub model_not_found: String,
+    /// `{count}`
+    pub available_models: String,
+    pub reasoning_models: String,
+    pub standard_models: String,
+    pub switch_model_hint: String,
+    pub price_tiers: PriceTierLabels,
+}
+
+impl Default for MessageTemplates {
+    fn default() -> Self {
+        Self {
+            backend_error_title: "⚠️ Backend Error".into(),
+            backend_error_model: "Model: {model}".into(),
+            backend_error_generic: "The model backend could not complete this request.".into(),
+            backend_error_detail: "Error: {message}".into(),
+            backend_error_response: "Response:\n{response}".into(),
+            first_token_timeout: "[proxy error: {code}] The backend sent nothing within {secs}s (BACKEND_FIRST_TOKEN_TIMEOUT_SECS).".into(),
+            total_timeout: "[proxy error: {code}] The response took longer than {secs}s (BACKEND_TOTAL_TIMEOUT_SECS) and was cut off.".into(),
+            sse_buffer_limit: "[proxy error: sse_buffer_limit] A backend event exceeded the {kb}KB SSE buffer limit (SSE_BUFFER_LIMIT_KB); the response was cut off.".into(),
+            stream_dropped: "[proxy error: stream_dropped] The backend closed the stream before the response finished.".into(),
+            moderation_refusal: "This content was blocked by the proxy's content moderation ({categories}).".into(),
+            stream_failed: "The backend failed while generating the response".into(),
+            context_requested: "Requested: {tokens} tokens".into(),
+            context_limit: "Limit: {tokens} tokens".into(),
+            suggestions_title: "💡 Suggestions:".into(),
+            suggestion_item: "• {suggestion}".into(),
+            suggestions_context_length: vec![
+                "Reduce message history".into(),
+                "Use a model with larger context".into(),
+                "Decrease max_tokens parameter".into(),
+            ],
+            suggestions_rate_limit: vec!["Wait a moment before retrying".into(), "Check your API quota".into()],
+            suggestions_quota: vec!["Check your account balance".into(), "Verify API key permissions".into()],
+            model_not_found: "❌ Model `{model}` not found.".into(),
+            available_models: "## 📋 Available Models ({count} total)".into(),
+            reasoning_models: "### 🧠 REASONING (Extended Thinking)".into(),
+            standard_models: "### ⚡ STANDARD".into(),
+            switch_model_hint: "💡 **To switch models:** Use `/model <model-name>`".into(),
+            price_tiers: PriceTierLabels::default(),
+        }
+    }
+}
+
+impl MessageTemplates {
+    /// The built-in set without emoji (`MESSAGE_TEMPLATES=plain`)
+    pub fn plain() -> Self {
+        Self {
+            backend_error_title: "Backend Error".into(),
+            suggestions_title: "Suggestions:".into(),
+            suggestion_item: "- {suggestion}".into(),
+            model_not_found: "Model `{model}` not found.".into(),
+            available_models: "## Available Models ({count} total)".into(),
+            reasoning_models: "### REASONING (Extended Thinking)".into(),
+            standard_models: "### STANDARD".into(),
+            switch_model_hint: "**To switch models:** Use `/model <model-name>`".into(),
+            price_tiers: PriceTierLabels {
+                budget: "$".into(),
+                affordable: "$$".into(),
+                moderate: "$$$".into(),
+                premium: "$$$$".into(),
+                unknown: "".into(),
+            },
+            ..Self::default()
+        }
+    }
+
+    /// `plain`, or a JSON file whose keys override the built-in set
+    pub fn load(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("plain") {
+            return Ok(Self::plain());
+        }
+        let source = std::fs::read_to_string(value).map_err(|e| format!("{}: {}", value, e))?;
+        serde_json::from_str(&source).map_err(|e| format!("{}: {}", value, e))
+    }
+
+    /// `MESSAGE_TEMPLATES`, falling back to the built-in set when unset or unreadable
+    pub fn from_env() -> Self {
+        match std::env::var("MESSAGE_TEMPLATES").ok().filter(|v| !v.trim().is_empty()) {
+            None => Self::default(),
+            Some(value) => match Self::load(&value) {
+                Ok(templates) => {
+                    log::info!("💬 Message templates loaded from '{}'", value.trim());
+                    templates
+                }
+                Err(e) => {
+                    log::error!("❌ Failed to load MESSAGE_TEMPLATES, using the built-in messages: {}", e);
+                    Self::default()
+                }
+            },
+        }
+    }
+
+    pub fn price_tier(&self, input_price: Option<f64>, output_price: Option<f64>) -> &str {
+        use crate::models::PriceTier;
+        let labels = &self.price_tiers;
+        match PriceTier::from_prices(input_price, output_price) {
+            Some(PriceTier::Budget) => &labels.budget,
+            Some(PriceTier::Affordable) => &labels.affordable,
+            Some(PriceTier::Moderate) => &labels.moderate,
+            Some(PriceTier::Premium) => &labels.premium,
+            None => &labels.unknown,
+        }
+    }
+}
+
+/// `template` with each `{name}` replaced by its value
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    values.iter().fold(template.to_string(), |text, (name, value)| text.replace(&format!("{{{}}}", name), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_overrides_only_given_keys() {
+        let path = std::env::temp_dir().join(format!("message-templates-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"backend_error_title": "Fehler im Backend", "price_tiers": {"premium": "€€€€"}}"#).unwrap();
+        let templates = MessageTemplates::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(templates.backend_error_title, "Fehler im Backend");
+        assert_eq!(templates.price_tier(Some(30.0), Some(60.0)), "€€€€");
+        assert_eq!(templates.price_tiers.budget, "💰");
+        assert_eq!(templates.switch_model_hint, MessageTemplates::default().switch_model_hint);
+
+        assert!(MessageTemplates::load("/nonexistent/templates.json").is_err());
+        assert!(MessageTemplates::plain().model_not_found.is_ascii());
+        assert_eq!(render("Model `{model}` ({model})", &[("model", "glm")]), "Model `glm` (glm)");
+    }
+}
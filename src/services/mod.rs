@@ -1,9 +1,97 @@
 pub mod model_cache;
+pub mod admission;
 pub mod auth;
 pub mod streaming;
+pub mod stream_translator;
+pub mod stream_resume;
 pub mod error_formatting;
+pub mod transform;
+pub mod notifier;
+pub mod stream_tee;
+pub mod llm_trace;
+pub mod request_overrides;
+pub mod backend_headers;
+pub mod backend_timeouts;
+pub mod request_compression;
+pub mod response_compression;
+pub mod client_info;
+pub mod client_ip;
+pub mod coalesce;
+pub mod delta_split;
+pub mod stop_sequences;
+pub mod capabilities;
+pub mod thinking;
+pub mod tool_emulation;
+pub mod tool_limits;
+pub mod stats;
+pub mod stream_memory;
+pub mod systemd;
+pub mod log_level;
+pub mod chaos;
+pub mod continuation;
+pub mod files;
+pub mod server_tools;
+pub mod shadow;
+pub mod experiments;
+pub mod presets;
+pub mod moderation;
+pub mod pii;
+pub mod debug_sidecar;
+pub mod warmup;
+pub mod compaction;
+pub mod routing;
+pub mod message_collector;
+pub mod message_store;
+pub mod message_templates;
+pub mod betas;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+#[cfg(feature = "sqlite")]
+pub mod request_log;
 
 pub use model_cache::*;
+pub use admission::*;
 pub use auth::*;
 pub use streaming::*;
-pub use error_formatting::*;
\ No newline at end of file
+pub use stream_translator::*;
+pub use stream_resume::*;
+pub use error_formatting::*;
+pub use transform::*;
+pub use notifier::*;
+pub use stream_tee::*;
+pub use llm_trace::*;
+pub use request_overrides::*;
+pub use backend_headers::*;
+pub use backend_timeouts::*;
+pub use request_compression::*;
+pub use response_compression::*;
+pub use client_info::*;
+pub use client_ip::*;
+pub use coalesce::*;
+pub use delta_split::*;
+pub use stop_sequences::*;
+pub use capabilities::*;
+pub use thinking::*;
+pub use tool_emulation::*;
+pub use tool_limits::*;
+pub use stats::*;
+pub use stream_memory::*;
+pub use chaos::*;
+pub use continuation::*;
+pub use files::*;
+pub use server_tools::*;
+pub use shadow::*;
+pub use experiments::*;
+pub use presets::*;
+pub use moderation::*;
+pub use debug_sidecar::*;
+pub use pii::*;
+pub use warmup::*;
+pub use compaction::*;
+pub use routing::*;
+pub use message_collector::*;
+pub use message_templates::*;
+pub use betas::*;
+pub use message_store::*;
+#[cfg(feature = "sqlite")]
+pub use request_log::*;
\ No newline at end of file
@@ -1,5 +1,10 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use reqwest::Client;
 use serde_json::Value;
-use crate::models::{App, ModelInfo};
+use crate::models::{App, ModelInfo, ModelList};
 
 /// Build `/v1/models` URL from backend chat completions URL.
 fn models_url_from_backend_url(backend_url: &str) -> String {
@@ -17,11 +22,21 @@ fn models_url_from_backend_url(backend_url: &str) -> String {
 
 /// Refresh the models cache from backend
 pub async fn refresh_models_cache(app: &App) -> Result<(), Box<dyn std::error::Error>> {
-    let models_url = models_url_from_backend_url(&app.backend_url);
+    let models = fetch_models(&app.client, &app.backend_url).await?;
+    log::info!("✅ Cached {} models from backend", models.len());
+    *app.models_cache.write().await = Some(ModelList::new(models));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    app.models_cache_updated.store(now, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Fetch the model list (with pricing and features) from the backend's `/v1/models`
+pub async fn fetch_models(client: &Client, backend_url: &str) -> Result<Vec<ModelInfo>, Box<dyn std::error::Error>> {
+    let models_url = models_url_from_backend_url(backend_url);
     log::info!("🔄 Fetching available models from {}", models_url);
 
     // Models endpoint is public (no auth required)
-    let res = app.client.get(&models_url).send().await?;
+    let res = client.get(&models_url).send().await?;
     let status = res.status();
     if !status.is_success() {
         // Read error body for debugging
@@ -69,25 +84,17 @@ pub async fn refresh_models_cache(app: &App) -> Result<(), Box<dyn std::error::E
                 .collect()
         })
         .unwrap_or_default();
-
-    log::info!("✅ Cached {} models from backend", models.len());
-    let mut cache = app.models_cache.write().await;
-    *cache = Some(models);
-    Ok(())
+    Ok(models)
 }
 
 /// Get cached models or fetch if not available
-pub async fn get_available_models(app: &App) -> Vec<ModelInfo> {
-    {
-        let cache = app.models_cache.read().await;
-        if let Some(models) = cache.as_ref() {
-            return models.clone();
-        }
+pub async fn get_available_models(app: &App) -> ModelList {
+    if let Some(models) = app.cached_models().await {
+        return models;
     }
     if let Err(e) = refresh_models_cache(app).await {
         log::warn!("Failed to fetch models: {}", e);
-        return vec![];
+        return ModelList::default();
     }
-    let cache = app.models_cache.read().await;
-    cache.as_ref().cloned().unwrap_or_default()
-}
\ No newline at end of file
+    app.cached_models().await.unwrap_or_default()
+}
@@ -0,0 +1,509 @@
+//! Content moderation against an OpenAI-compatible `/v1/moderations` endpoint.
+//!
+//! Requests are checked whole before they are forwarded: the system prompt, the text of every
+//! turn, tool results and text documents, and images with `MODERATION_IMAGES`. Responses are
+//! checked while they stream, answer text and thinking in overlapping windows and tool call
+//! arguments once the call is complete. Not checked: tool_use inputs of earlier assistant turns
+//! (they were checked as responses), images without `MODERATION_IMAGES`, PDF documents, and the
+//! output of the proxy's own server tools.
+use std::{collections::VecDeque, str::FromStr, sync::Arc, time::Duration};
+use reqwest::Client;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+use crate::config::{env_or, env_parse};
+use crate::constants::{DEFAULT_MODERATION_STREAM_CHARS, MODERATION_MAX_PENDING_CHECKS, MODERATION_REDACTION, MODERATION_TIMEOUT_SECS, MODERATION_WINDOW_OVERLAP_CHARS};
+use crate::models::ClaudeRequest;
+use crate::services::{render, MessageTemplates};
+
+/// What happens to flagged content (`MODERATION_ACTION`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ModerationAction {
+    /// Refuse the request, or end the response, with `stop_reason: "refusal"`
+    #[default]
+    Block,
+    /// Replace the flagged text and carry on
+    Redact,
+}
+
+impl FromStr for ModerationAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "block" => Ok(ModerationAction::Block),
+            "redact" => Ok(ModerationAction::Redact),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which side of the conversation is checked (`MODERATION_SCOPE`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ModerationScope {
+    Requests,
+    Responses,
+    #[default]
+    Both,
+}
+
+impl FromStr for ModerationScope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "requests" | "request" => Ok(ModerationScope::Requests),
+            "responses" | "response" => Ok(ModerationScope::Responses),
+            "both" => Ok(ModerationScope::Both),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Outcome of checking a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModerationVerdict {
+    Allowed,
+    /// This many text and image blocks were replaced
+    Redacted(usize),
+    /// Flagged categories
+    Blocked(Vec<String>),
+}
+
+/// Text shown to the client in place of a blocked request or response
+pub fn refusal_text(templates: &MessageTemplates, categories: &[String]) -> String {
+    render(&templates.moderation_refusal, &[("categories", &categories.join(", "))])
+}
+
+/// Content moderation (`MODERATION_URL`): text is sent to an OpenAI-compatible `/v1/moderations`
+/// endpoint, such as OpenAI's or a local classifier serving the same API, before the request
+/// reaches the backend and while the response streams
+pub struct Moderation {
+    client: Client,
+    url: String,
+    /// Bearer token for the moderation endpoint (`MODERATION_API_KEY`); client keys are never sent
+    api_key: Option<String>,
+    /// `model` field of moderation requests (`MODERATION_MODEL`); the endpoint's default when unset
+    model: Option<String>,
+    pub action: ModerationAction,
+    scope: ModerationScope,
+    /// Response text held back and checked at once (`MODERATION_STREAM_CHARS`)
+    stream_chars: usize,
+    /// Treat an unreachable endpoint as flagged (`MODERATION_FAIL_CLOSED`)
+    fail_closed: bool,
+    /// Send request images to the endpoint too (`MODERATION_IMAGES`)
+    images: bool,
+}
+
+impl Moderation {
+    /// `MODERATION_URL` with `MODERATION_API_KEY`, `MODERATION_MODEL`, `MODERATION_ACTION`,
+    /// `MODERATION_SCOPE`, `MODERATION_STREAM_CHARS`, `MODERATION_FAIL_CLOSED` and `MODERATION_IMAGES`;
+    /// `None` when unset
+    pub fn from_env(client: Client) -> Option<Self> {
+        let url = std::env::var("MODERATION_URL").ok().map(|u| u.trim().to_string()).filter(|u| !u.is_empty())?;
+        let moderation = Self {
+            client,
+            url,
+            api_key: std::env::var("MODERATION_API_KEY").ok().filter(|k| !k.trim().is_empty()),
+            model: std::env::var("MODERATION_MODEL").ok().map(|m| m.trim().to_string()).filter(|m| !m.is_empty()),
+            action: env_or("MODERATION_ACTION", ModerationAction::default()),
+            scope: env_or("MODERATION_SCOPE", ModerationScope::default()),
+            stream_chars: env_parse("MODERATION_STREAM_CHARS").filter(|&c| c > 0).unwrap_or(DEFAULT_MODERATION_STREAM_CHARS),
+            fail_closed: env_or("MODERATION_FAIL_CLOSED", false),
+            images: env_or("MODERATION_IMAGES", false),
+        };
+        log::info!("🛡️  Content moderation via {} (action: {:?}, scope: {:?})", moderation.url, moderation.action, moderation.scope);
+        Some(moderation)
+    }
+
+    pub fn checks_requests(&self) -> bool {
+        self.scope != ModerationScope::Responses
+    }
+
+    pub fn checks_responses(&self) -> bool {
+        self.scope != ModerationScope::Requests
+    }
+
+    /// Per-stream checker of response text; `None` when responses aren't moderated
+    pub fn response_moderator(self: &Arc<Self>) -> Option<ResponseModerator> {
+        self.checks_responses().then(|| ResponseModerator {
+            moderation: self.clone(),
+            held: String::new(),
+            context: String::new(),
+            checks: VecDeque::new(),
+        })
+    }
+
+    /// Flagged categories of each of `count` inputs, empty for inputs that passed
+    async fn classify(&self, input: Value, count: usize) -> Result<Vec<Vec<String>>, String> {
+        let mut body = json!({ "input": input });
+        if let Some(model) = &self.model {
+            body["model"] = json!(model);
+        }
+        let mut req = self.client.post(&self.url).timeout(Duration::from_secs(MODERATION_TIMEOUT_SECS)).json(&body);
+        if let Some(key) = &self.api_key {
+            req = req.bearer_auth(key);
+        }
+        let res = req.send().await.map_err(|e| e.to_string())?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(format!("moderation endpoint returned {}", status.as_u16()));
+        }
+        let body: Value = res.json().await.map_err(|e| e.to_string())?;
+        let results = body["results"].as_array().filter(|r| r.len() == count).ok_or("unexpected moderation response")?;
+        Ok(results.iter().map(flagged_categories).collect())
+    }
+
+    /// `classify` with the endpoint's failures resolved by `MODERATION_FAIL_CLOSED`
+    async fn verdicts(&self, inputs: &[String]) -> Vec<Vec<String>> {
+        self.resolve(self.classify(json!(inputs), inputs.len()).await, inputs.len())
+    }
+
+    /// Verdicts of request images, one moderation request each since multimodal inputs are
+    /// classified together
+    async fn image_verdicts(&self, urls: &[String]) -> Vec<Vec<String>> {
+        let checks = urls.iter().map(|url| async move {
+            let input = json!([{ "type": "image_url", "image_url": { "url": url } }]);
+            self.resolve(self.classify(input, 1).await, 1).concat()
+        });
+        futures::future::join_all(checks).await
+    }
+
+    fn resolve(&self, verdicts: Result<Vec<Vec<String>>, String>, count: usize) -> Vec<Vec<String>> {
+        match verdicts {
+            Ok(verdicts) => verdicts,
+            Err(e) if self.fail_closed => {
+                log::warn!("⚠️  Content moderation failed, treating content as flagged: {}", e);
+                vec![vec!["moderation_unavailable".to_string()]; count]
+            }
+            Err(e) => {
+                log::warn!("⚠️  Content moderation failed, letting content through: {}", e);
+                vec![Vec::new(); count]
+            }
+        }
+    }
+
+    /// Check every input of the request: system prompt, all turns, tool results and text
+    /// documents, and images with `MODERATION_IMAGES`; under `redact` flagged blocks are replaced
+    pub async fn check_request(&self, cr: &mut ClaudeRequest) -> ModerationVerdict {
+        let (mut texts, mut images) = (Vec::new(), Vec::new());
+        if let Some(system) = cr.system.as_mut() {
+            collect_inputs(system, &mut texts, &mut images);
+        }
+        for message in cr.messages.iter_mut() {
+            collect_inputs(&mut message.content, &mut texts, &mut images);
+        }
+        texts.retain(|t| t.as_str().is_some_and(|s| !s.trim().is_empty()));
+        let image_urls: Vec<(&mut Value, String)> = if self.images {
+            images.into_iter().filter_map(|image| image_url(image).map(|url| (image, url))).collect()
+        } else {
+            Vec::new()
+        };
+        if texts.is_empty() && image_urls.is_empty() {
+            return ModerationVerdict::Allowed;
+        }
+        let inputs: Vec<String> = texts.iter().map(|t| t.as_str().unwrap_or_default().to_string()).collect();
+        let urls: Vec<String> = image_urls.iter().map(|(_, url)| url.clone()).collect();
+        let text_verdicts = if inputs.is_empty() { Vec::new() } else { self.verdicts(&inputs).await };
+        let image_verdicts = self.image_verdicts(&urls).await;
+        let mut categories: Vec<String> = text_verdicts.iter().chain(&image_verdicts).flatten().cloned().collect();
+        if categories.is_empty() {
+            return ModerationVerdict::Allowed;
+        }
+        categories.sort();
+        categories.dedup();
+        if self.action == ModerationAction::Block || categories.iter().any(|c| c == "moderation_unavailable") {
+            return ModerationVerdict::Blocked(categories);
+        }
+        let mut redacted = 0;
+        for (text, flagged) in texts.into_iter().zip(&text_verdicts) {
+            if !flagged.is_empty() {
+                *text = json!(MODERATION_REDACTION);
+                redacted += 1;
+            }
+        }
+        for ((image, _), flagged) in image_urls.into_iter().zip(&image_verdicts) {
+            if !flagged.is_empty() {
+                *image = json!({ "type": "text", "text": MODERATION_REDACTION });
+                redacted += 1;
+            }
+        }
+        ModerationVerdict::Redacted(redacted)
+    }
+
+    /// Flagged categories of complete tool call arguments; these are never redacted, since a
+    /// tool call with part of its input replaced is useless
+    pub async fn check_tool_inputs(&self, inputs: &[String]) -> Option<Vec<String>> {
+        if inputs.is_empty() {
+            return None;
+        }
+        let mut categories = self.verdicts(inputs).await.concat();
+        categories.sort();
+        categories.dedup();
+        (!categories.is_empty()).then_some(categories)
+    }
+
+    /// Check `input` and release `window`, its new text: the window itself, the redaction, or the
+    /// flagged categories when blocked
+    async fn check_text(&self, input: String, window: String) -> Result<String, Vec<String>> {
+        let categories = self.verdicts(std::slice::from_ref(&input)).await.concat();
+        match (categories.is_empty(), self.action) {
+            (true, _) => Ok(window),
+            (false, ModerationAction::Redact) if !categories.iter().any(|c| c == "moderation_unavailable") => {
+                log::info!("🛡️  Moderation redacted response text ({})", categories.join(", "));
+                log::info!(target: "metrics", "moderation: stage=response, action=redacted, categories={}", categories.join("|"));
+                Ok(MODERATION_REDACTION.to_string())
+            }
+            (false, _) => Err(categories),
+        }
+    }
+}
+
+/// Gather the checkable parts of message or system content: strings, text blocks, tool results,
+/// text documents and images
+fn collect_inputs<'a>(content: &'a mut Value, texts: &mut Vec<&'a mut Value>, images: &mut Vec<&'a mut Value>) {
+    match content {
+        Value::String(_) => texts.push(content),
+        Value::Array(blocks) => {
+            for block in blocks {
+                match block["type"].as_str() {
+                    Some("text") => texts.push(&mut block["text"]),
+                    Some("tool_result") => collect_inputs(&mut block["content"], texts, images),
+                    Some("document") if block["source"]["type"] == "text" => texts.push(&mut block["source"]["data"]),
+                    Some("image") => images.push(block),
+                    _ => {}
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// URL of an image block for the moderation endpoint; base64 images become data URLs
+fn image_url(block: &Value) -> Option<String> {
+    let source = &block["source"];
+    match source["type"].as_str()? {
+        "base64" => Some(format!("data:{};base64,{}", source["media_type"].as_str()?, source["data"].as_str()?)),
+        "url" => source["url"].as_str().map(str::to_string),
+        _ => None,
+    }
+}
+
+/// Last `n` characters of `text`
+fn tail_chars(text: &str, n: usize) -> String {
+    let skip = text.chars().count().saturating_sub(n);
+    text.chars().skip(skip).collect()
+}
+
+/// Categories of one moderation result that are flagged; `flagged` alone reports as `flagged`
+fn flagged_categories(result: &Value) -> Vec<String> {
+    if result["flagged"] != true {
+        return Vec::new();
+    }
+    let categories: Vec<String> = result["categories"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(_, flagged)| **flagged == true)
+        .map(|(name, _)| name.clone())
+        .collect();
+    if categories.is_empty() { vec!["flagged".to_string()] } else { categories }
+}
+
+/// Text released by response moderation: what passed, then the flagged categories once a
+/// window was blocked
+#[derive(Debug, Default, PartialEq)]
+pub struct Released {
+    pub text: String,
+    pub blocked: Option<Vec<String>>,
+}
+
+/// Response moderation of one stream: text is held back until `MODERATION_STREAM_CHARS` have
+/// accumulated and released only after the moderation endpoint passed it. Windows are checked
+/// in the background with the tail of the previous window as context, so the stream keeps
+/// flowing while a check runs and phrases split across windows are still caught
+pub struct ResponseModerator {
+    moderation: Arc<Moderation>,
+    held: String,
+    /// Last `MODERATION_WINDOW_OVERLAP_CHARS` of the text already submitted
+    context: String,
+    /// Windows being checked, oldest first
+    checks: VecDeque<JoinHandle<Result<String, Vec<String>>>>,
+}
+
+impl ResponseModerator {
+    /// Add streamed text; returns the text of windows whose check has finished
+    pub async fn push(&mut self, text: &str) -> Released {
+        self.held.push_str(text);
+        if self.held.chars().count() >= self.moderation.stream_chars {
+            self.submit();
+        }
+        let wait_for = self.checks.len().saturating_sub(MODERATION_MAX_PENDING_CHECKS);
+        self.release(wait_for).await
+    }
+
+    /// Check and release everything held back
+    pub async fn flush(&mut self) -> Released {
+        self.submit();
+        self.release(usize::MAX).await
+    }
+
+    /// Start checking the held text as a new window
+    fn submit(&mut self) {
+        if self.held.is_empty() {
+            return;
+        }
+        let window = std::mem::take(&mut self.held);
+        let input = format!("{}{}", self.context, window);
+        self.context = tail_chars(&input, MODERATION_WINDOW_OVERLAP_CHARS);
+        let moderation = self.moderation.clone();
+        self.checks.push_back(tokio::spawn(async move { moderation.check_text(input, window).await }));
+    }
+
+    /// Release finished checks in order, waiting for at least the oldest `wait_for`
+    async fn release(&mut self, mut wait_for: usize) -> Released {
+        let mut released = Released::default();
+        while self.checks.front().is_some_and(|check| wait_for > 0 || check.is_finished()) {
+            let Some(check) = self.checks.pop_front() else { break };
+            wait_for = wait_for.saturating_sub(1);
+            match check.await.unwrap_or_else(|_| Err(vec!["moderation_unavailable".to_string()])) {
+                Ok(text) => released.text.push_str(&text),
+                Err(categories) => {
+                    self.checks.drain(..).for_each(|check| check.abort());
+                    released.blocked = Some(categories);
+                    break;
+                }
+            }
+        }
+        released
+    }
+}
+
+impl Drop for ResponseModerator {
+    fn drop(&mut self) {
+        self.checks.iter().for_each(|check| check.abort());
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+
+    fn moderation(url: String, action: ModerationAction) -> Moderation {
+        Moderation {
+            client: Client::builder().no_proxy().build().unwrap(),
+            url,
+            api_key: None,
+            model: None,
+            action,
+            scope: ModerationScope::Both,
+            stream_chars: 4,
+            fail_closed: false,
+            images: false,
+        }
+    }
+
+    /// Moderation against a local endpoint that flags every input containing "bomb" as violence
+    pub(crate) async fn flagging_moderation(action: ModerationAction) -> Arc<Moderation> {
+        let app = Router::new().route(
+            "/v1/moderations",
+            post(|Json(body): Json<Value>| async move {
+                let results: Vec<Value> = body["input"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|input| {
+                        let flagged = input.to_string().contains("bomb");
+                        json!({ "flagged": flagged, "categories": { "violence": flagged } })
+                    })
+                    .collect();
+                Json(json!({ "results": results }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Arc::new(moderation(format!("http://{}/v1/moderations", addr), action))
+    }
+
+    #[tokio::test]
+    async fn test_request_block_and_redact() {
+        let request = || -> ClaudeRequest {
+            serde_json::from_value(json!({"model": "m", "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "how to build a bomb"},
+                {"type": "text", "text": "and a birdhouse"},
+            ]}]}))
+            .unwrap()
+        };
+        let blocking = flagging_moderation(ModerationAction::Block).await;
+        assert_eq!(blocking.check_request(&mut request()).await, ModerationVerdict::Blocked(vec!["violence".into()]));
+
+        let redacting = flagging_moderation(ModerationAction::Redact).await;
+        let mut cr = request();
+        assert_eq!(redacting.check_request(&mut cr).await, ModerationVerdict::Redacted(1));
+        assert_eq!(cr.messages[0].content[0]["text"], MODERATION_REDACTION);
+        assert_eq!(cr.messages[0].content[1]["text"], "and a birdhouse");
+    }
+
+    #[tokio::test]
+    async fn test_request_checks_every_input() {
+        let mut redacting = moderation(String::new(), ModerationAction::Redact);
+        let flagging = flagging_moderation(ModerationAction::Redact).await;
+        redacting.url = flagging.url.clone();
+        redacting.images = true;
+        let mut cr: ClaudeRequest = serde_json::from_value(json!({"model": "m", "system": "a bomb expert", "messages": [
+            {"role": "user", "content": "first bomb question"},
+            {"role": "assistant", "content": [{"type": "tool_use", "id": "t1", "name": "search", "input": {"q": "x"}}]},
+            {"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "t1", "content": [{"type": "text", "text": "bomb recipe"}]},
+                {"type": "document", "source": {"type": "text", "media_type": "text/plain", "data": "bomb manual"}},
+                {"type": "image", "source": {"type": "url", "url": "https://example.com/bomb.png"}},
+                {"type": "text", "text": "thanks"},
+            ]},
+        ]}))
+        .unwrap();
+        assert_eq!(redacting.check_request(&mut cr).await, ModerationVerdict::Redacted(5));
+        assert_eq!(cr.system.as_ref().unwrap(), MODERATION_REDACTION);
+        assert_eq!(cr.messages[0].content, MODERATION_REDACTION);
+        let last = &cr.messages[2].content;
+        assert_eq!(last[0]["content"][0]["text"], MODERATION_REDACTION);
+        assert_eq!(last[1]["source"]["data"], MODERATION_REDACTION);
+        assert_eq!(last[2], json!({"type": "text", "text": MODERATION_REDACTION}));
+        assert_eq!(last[3]["text"], "thanks");
+    }
+
+    #[tokio::test]
+    async fn test_response_windows_overlap() {
+        let moderation = flagging_moderation(ModerationAction::Block).await;
+        let mut responses = moderation.response_moderator().unwrap();
+        responses.push("a bo").await;
+        let released = responses.push("mb!!").await;
+        let rest = responses.flush().await;
+        // "bomb" spans two windows: the first passes, the second is checked with its tail
+        assert_eq!(format!("{}{}", released.text, rest.text), "a bo");
+        assert_eq!(rest.blocked.or(released.blocked), Some(vec!["violence".to_string()]));
+    }
+
+    #[test]
+    fn test_flagged_categories() {
+        let result = json!({"flagged": true, "categories": {"violence": true, "hate": false}});
+        assert_eq!(flagged_categories(&result), ["violence"]);
+        assert_eq!(flagged_categories(&json!({"flagged": true})), ["flagged"]);
+        assert!(flagged_categories(&json!({"flagged": false, "categories": {"violence": true}})).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_endpoint_follows_fail_closed() {
+        let mut moderation = moderation("http://127.0.0.1:9/v1/moderations".into(), ModerationAction::Redact);
+        let mut cr: ClaudeRequest = serde_json::from_value(json!({"model": "m", "messages": [{"role": "user", "content": "hello"}]})).unwrap();
+        assert_eq!(moderation.check_request(&mut cr).await, ModerationVerdict::Allowed);
+
+        moderation.fail_closed = true;
+        let moderation = Arc::new(moderation);
+        assert_eq!(moderation.check_request(&mut cr).await, ModerationVerdict::Blocked(vec!["moderation_unavailable".into()]));
+        let mut responses = moderation.response_moderator().unwrap();
+        assert_eq!(responses.push("ab").await, Released::default(), "short text is held back");
+        let pushed = responses.push("cd").await;
+        assert!(pushed.blocked.or(responses.flush().await.blocked).is_some());
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::broadcast;
+use crate::constants::*;
+
+/// Operational events worth telling a human about; serialized with `type` set to `kind()` for
+/// `/admin/events`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OpsEvent {
+    CircuitOpened { consecutive_failures: u32 },
+    CircuitClosed,
+    HighErrorRate { failures: u32, total: u32, window_secs: u64 },
+    ModelCacheFailure { error: String },
+    /// A backend took longer than `SLOW_BACKEND_MS` to start responding
+    BackendSlow { backend: String, latency_ms: u64 },
+    /// A backend reported slow responded within `SLOW_BACKEND_MS` again
+    BackendRecovered { backend: String, latency_ms: u64 },
+    /// A backend refused a request because its account ran out of credit or quota
+    BudgetExhausted { backend: String, status: u16 },
+}
+
+impl OpsEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OpsEvent::CircuitOpened { .. } => "circuit_opened",
+            OpsEvent::CircuitClosed => "circuit_closed",
+            OpsEvent::HighErrorRate { .. } => "high_error_rate",
+            OpsEvent::ModelCacheFailure { .. } => "model_cache_failure",
+            OpsEvent::BackendSlow { .. } => "backend_slow",
+            OpsEvent::BackendRecovered { .. } => "backend_recovered",
+            OpsEvent::BudgetExhausted { .. } => "budget_exhausted",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            OpsEvent::CircuitOpened { consecutive_failures } => {
+                format!("🔴 Circuit breaker opened after {} consecutive backend failures", consecutive_failures)
+            }
+            OpsEvent::CircuitClosed => "🟢 Circuit breaker closed, backend recovered".to_string(),
+            OpsEvent::HighErrorRate { failures, total, window_secs } => format!(
+                "🟠 Backend error rate {:.0}% ({} of {} requests in the last {}s)",
+                *failures as f64 * 100.0 / (*total).max(1) as f64,
+                failures,
+                total,
+                window_secs
+            ),
+            OpsEvent::ModelCacheFailure { error } => format!("🟡 Model cache refresh failed: {}", error),
+            OpsEvent::BackendSlow { backend, latency_ms } => format!("🐢 Backend '{}' took {}ms to respond", backend, latency_ms),
+            OpsEvent::BackendRecovered { backend, latency_ms } => {
+                format!("🟢 Backend '{}' is responding normally again ({}ms)", backend, latency_ms)
+            }
+            OpsEvent::BudgetExhausted { backend, status } => {
+                format!("💸 Backend '{}' is out of credit or quota (HTTP {}); requests to it fail until it is topped up", backend, status)
+            }
+        }
+    }
+}
+
+/// Whether a backend error response says the backend account is out of credit or quota: HTTP 402,
+/// or one of the wordings in `BUDGET_EXHAUSTED_MARKERS`
+pub fn budget_exhausted(status: u16, body: &str) -> bool {
+    if status == 402 {
+        return true;
+    }
+    let body = body.to_ascii_lowercase();
+    BUDGET_EXHAUSTED_MARKERS.iter().any(|marker| body.contains(marker))
+}
+
+#[derive(Debug, Clone)]
+pub struct NotifierConfig {
+    pub webhook_urls: Vec<String>,
+    /// Minimum time between two notifications of the same kind
+    pub cooldown: Duration,
+    /// Failure ratio (0.0-1.0) that triggers a high error rate alert
+    pub error_rate_threshold: f64,
+    pub error_rate_window: Duration,
+    /// Time to response headers above which a backend counts as slow (`SLOW_BACKEND_MS`)
+    pub slow_backend: Duration,
+}
+
+impl NotifierConfig {
+    pub fn from_env() -> Self {
+        use crate::config::{env_list, env_or};
+        Self {
+            webhook_urls: env_list("WEBHOOK_URLS"),
+            cooldown: Duration::from_secs(env_or("WEBHOOK_COOLDOWN_SECS", DEFAULT_WEBHOOK_COOLDOWN_SECS)),
+            error_rate_threshold: env_or("WEBHOOK_ERROR_RATE_THRESHOLD", DEFAULT_WEBHOOK_ERROR_RATE_THRESHOLD),
+            error_rate_window: Duration::from_secs(env_or(
+                "WEBHOOK_ERROR_RATE_WINDOW_SECS",
+                DEFAULT_WEBHOOK_ERROR_RATE_WINDOW_SECS,
+            )),
+            slow_backend: Duration::from_millis(env_or("SLOW_BACKEND_MS", DEFAULT_SLOW_BACKEND_MS)),
+        }
+    }
+}
+
+/// Fixed-window backend outcome counter
+struct ErrorRateWindow {
+    started: Instant,
+    total: u32,
+    failures: u32,
+    alerted: bool,
+}
+
+/// Fires Slack-compatible webhooks for operational events and broadcasts them to `/admin/events`
+pub struct Notifier {
+    client: Client,
+    config: NotifierConfig,
+    last_sent: Mutex<HashMap<&'static str, Instant>>,
+    window: Mutex<ErrorRateWindow>,
+    /// Backends whose last response was slow
+    slow_backends: Mutex<HashSet<String>>,
+    events: broadcast::Sender<OpsEvent>,
+}
+
+impl Notifier {
+    pub fn new(client: Client, config: NotifierConfig) -> Self {
+        Self {
+            client,
+            config,
+            last_sent: Mutex::new(HashMap::new()),
+            window: Mutex::new(ErrorRateWindow {
+                started: Instant::now(),
+                total: 0,
+                failures: 0,
+                alerted: false,
+            }),
+            slow_backends: Mutex::new(HashSet::new()),
+            events: broadcast::channel(OPS_EVENTS_BUFFER).0,
+        }
+    }
+
+    /// Whether anyone listens: webhooks are configured or an `/admin/events` stream is open
+    pub fn is_enabled(&self) -> bool {
+        !self.config.webhook_urls.is_empty() || self.events.receiver_count() > 0
+    }
+
+    /// Every event from now on, for `/admin/events`
+    pub fn subscribe(&self) -> broadcast::Receiver<OpsEvent> {
+        self.events.subscribe()
+    }
+
+    /// Broadcast an event to `/admin/events` subscribers and send it to all webhooks in the
+    /// background (webhooks are rate limited per event kind)
+    pub fn notify(&self, event: OpsEvent) {
+        // No subscribers is the common case, not an error
+        let _ = self.events.send(event.clone());
+        if self.config.webhook_urls.is_empty() {
+            return;
+        }
+        {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            let now = Instant::now();
+            if let Some(prev) = last_sent.get(event.kind()) {
+                if now.duration_since(*prev) < self.config.cooldown {
+                    log::debug!("🔕 Suppressing '{}' webhook (cooldown)", event.kind());
+                    return;
+                }
+            }
+            last_sent.insert(event.kind(), now);
+        }
+
+        let payload = json!({
+            "text": format!("claude-proxy: {}", event.message()),
+            "event": event.kind(),
+        });
+        for url in &self.config.webhook_urls {
+            let client = self.client.clone();
+            let url = url.clone();
+            let payload = payload.clone();
+            tokio::spawn(async move {
+                match client.post(&url).json(&payload).send().await {
+                    Ok(res) if !res.status().is_success() => {
+                        log::warn!("⚠️  Webhook returned {}", res.status());
+                    }
+                    Err(e) => log::warn!("⚠️  Webhook delivery failed: {}", e),
+                    _ => {}
+                }
+            });
+        }
+    }
+
+    /// Track a backend outcome and alert when the failure ratio stays above the threshold
+    pub fn record_backend_result(&self, success: bool) {
+        if !self.is_enabled() {
+            return;
+        }
+        let alert = {
+            let mut w = self.window.lock().unwrap();
+            if w.started.elapsed() >= self.config.error_rate_window {
+                *w = ErrorRateWindow {
+                    started: Instant::now(),
+                    total: 0,
+                    failures: 0,
+                    alerted: false,
+                };
+            }
+            w.total += 1;
+            if !success {
+                w.failures += 1;
+            }
+            let rate = w.failures as f64 / w.total as f64;
+            if !w.alerted && w.total >= WEBHOOK_ERROR_RATE_MIN_REQUESTS && rate >= self.config.error_rate_threshold {
+                w.alerted = true;
+                Some(OpsEvent::HighErrorRate {
+                    failures: w.failures,
+                    total: w.total,
+                    window_secs: self.config.error_rate_window.as_secs(),
+                })
+            } else {
+                None
+            }
+        };
+        if let Some(event) = alert {
+            self.notify(event);
+        }
+    }
+
+    /// Track a backend's time to response headers; announces when it turns slow and when it recovers
+    pub fn record_backend_latency(&self, backend: &str, latency: Duration) {
+        if !self.is_enabled() {
+            return;
+        }
+        let slow = latency > self.config.slow_backend;
+        let changed = {
+            let mut slow_backends = self.slow_backends.lock().unwrap_or_else(|e| e.into_inner());
+            if slow { slow_backends.insert(backend.to_string()) } else { slow_backends.remove(backend) }
+        };
+        if changed {
+            let (backend, latency_ms) = (backend.to_string(), latency.as_millis() as u64);
+            self.notify(if slow { OpsEvent::BackendSlow { backend, latency_ms } } else { OpsEvent::BackendRecovered { backend, latency_ms } });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slow_backend_is_announced_on_change() {
+        let config = NotifierConfig {
+            webhook_urls: Vec::new(),
+            cooldown: Duration::ZERO,
+            error_rate_threshold: 1.0,
+            error_rate_window: Duration::from_secs(60),
+            slow_backend: Duration::from_secs(1),
+        };
+        let notifier = Notifier::new(Client::new(), config);
+        let mut rx = notifier.subscribe();
+        for secs in [5, 5, 0, 0] {
+            notifier.record_backend_latency("local", Duration::from_secs(secs));
+        }
+        assert_eq!(rx.try_recv().unwrap().kind(), "backend_slow");
+        assert_eq!(rx.try_recv().unwrap().kind(), "backend_recovered");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_budget_exhausted() {
+        assert!(budget_exhausted(402, ""));
+        assert!(budget_exhausted(429, r#"{"error":{"type":"insufficient_quota","message":"You exceeded your current quota"}}"#));
+        assert!(!budget_exhausted(429, r#"{"error":{"message":"Rate limit reached, retry in 2s"}}"#));
+        assert!(!budget_exhausted(500, "internal error"));
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use futures::future::BoxFuture;
+use regex::{Captures, Regex};
+use serde_json::{json, Value};
+use crate::config::{env_list, env_or};
+use crate::models::ClaudeRequest;
+use crate::services::transform::{BlockDelta, StreamEvent, TextDeltaKind, Transform, TransformContext, TransformResult};
+
+const EMAIL: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}";
+/// Groups of digits with separators, optionally with a country code, or E.164 numbers
+const PHONE: &str = r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{2,4}\)|\b\d{2,4})[\s.-]\d{3,4}[\s.-]\d{3,4}\b|\+\d{10,15}\b";
+/// 13 to 19 digits, optionally grouped by spaces or dashes; matches must pass the Luhn check
+const CREDIT_CARD: &str = r"\b\d(?:[ -]?\d){12,18}\b";
+
+/// Keys whose string values are never masked: identifiers, block types and binary sources
+const UNMASKED_KEYS: &[&str] = &["type", "id", "tool_use_id", "name", "source", "signature", "cache_control", "media_type"];
+
+/// One kind of personal data and how to find it
+struct Detector {
+    /// Placeholder label, e.g. `EMAIL` for `[EMAIL_1]`
+    label: String,
+    regex: Regex,
+    luhn: bool,
+}
+
+/// Placeholders of one request and the values they stand for, kept in `TransformContext::extensions`
+#[derive(Debug, Clone, Default)]
+pub struct PiiVault {
+    /// Put the original values back into the response (`PII_RESTORE`)
+    pub restore: bool,
+    by_value: HashMap<String, String>,
+    by_placeholder: HashMap<String, String>,
+    counts: HashMap<String, usize>,
+}
+
+impl PiiVault {
+    /// The placeholder for `value`; a value seen before gets the same one
+    fn placeholder(&mut self, label: &str, value: &str) -> String {
+        if let Some(placeholder) = self.by_value.get(value) {
+            return placeholder.clone();
+        }
+        let count = self.counts.entry(label.to_string()).or_default();
+        *count += 1;
+        let placeholder = format!("[{}_{}]", label, count);
+        self.by_value.insert(value.to_string(), placeholder.clone());
+        self.by_placeholder.insert(placeholder.clone(), value.to_string());
+        placeholder
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_placeholder.is_empty()
+    }
+}
+
+/// Masks emails, phone numbers, credit card numbers and `PII_PATTERN_<NAME>` matches in the
+/// system prompt and messages with numbered placeholders (`[EMAIL_1]`) before the request
+/// leaves the proxy. With `PII_RESTORE`, the response gets the original values back.
+pub struct PiiMasker {
+    detectors: Vec<Detector>,
+    restore: bool,
+}
+
+impl PiiMasker {
+    /// `PII_MASKING` (`email`, `phone`, `credit_card`) and `PII_PATTERN_<NAME>` regexes; `None` when
+    /// neither is set
+    pub fn from_env() -> Option<Self> {
+        let masker = Self::from_vars("PII_MASKING", "PII_PATTERN_", env_or("PII_RESTORE", false))?;
+        let labels: Vec<&str> = masker.detectors.iter().map(|d| d.label.as_str()).collect();
+        log::info!("🕶️  PII masking: {} (restore: {})", labels.join(", "), masker.restore);
+        Some(masker)
+    }
+
+    /// Detectors named by `list_var` plus one per `<pattern_prefix><NAME>` regex; `None` when there are none
+    pub fn from_vars(list_var: &str, pattern_prefix: &str, restore: bool) -> Option<Self> {
+        let custom: Vec<(String, String)> = std::env::vars()
+            .filter_map(|(key, pattern)| Some((key.strip_prefix(pattern_prefix)?.to_string(), pattern)))
+            .filter(|(name, pattern)| !name.is_empty() && !pattern.trim().is_empty())
+            .collect();
+        let masker = Self::new(&env_list(list_var), &custom, restore);
+        (!masker.detectors.is_empty()).then_some(masker)
+    }
+
+    pub(crate) fn new(builtin: &[String], custom: &[(String, String)], restore: bool) -> Self {
+        let mut detectors = Vec::new();
+        // Cards before phones, whose looser pattern would take grouped card numbers
+        for (name, pattern, luhn) in [("email", EMAIL, false), ("credit_card", CREDIT_CARD, true), ("phone", PHONE, false)] {
+            if builtin.iter().any(|b| b.eq_ignore_ascii_case(name) || b.eq_ignore_ascii_case("all")) {
+                let regex = Regex::new(pattern).expect("built-in PII pattern");
+                detectors.push(Detector { label: name.to_ascii_uppercase(), regex, luhn });
+            }
+        }
+        for b in builtin.iter().filter(|b| !["email", "credit_card", "phone", "all"].contains(&b.to_ascii_lowercase().as_str())) {
+            log::warn!("⚠️  Ignoring unknown PII kind '{}' (expected email, phone, credit_card or all)", b);
+        }
+        for (name, pattern) in custom {
+            match Regex::new(pattern.trim()) {
+                Ok(regex) => detectors.push(Detector { label: name.to_ascii_uppercase(), regex, luhn: false }),
+                Err(e) => log::warn!("⚠️  Ignoring PII pattern {}: {}", name, e),
+            }
+        }
+        Self { detectors, restore }
+    }
+
+    /// `text` with every match replaced by its placeholder, for copies that never need restoring
+    pub fn redact(&self, text: &str) -> String {
+        self.mask_text(&mut PiiVault::default(), text).unwrap_or_else(|| text.to_string())
+    }
+
+    fn mask_text(&self, vault: &mut PiiVault, text: &str) -> Option<String> {
+        let mut masked = None;
+        for detector in &self.detectors {
+            let current: &str = masked.as_deref().unwrap_or(text);
+            if !detector.regex.is_match(current) {
+                continue;
+            }
+            let replaced = detector.regex.replace_all(current, |caps: &Captures| {
+                let value = &caps[0];
+                if detector.luhn && !luhn_valid(value) {
+                    return value.to_string();
+                }
+                vault.placeholder(&detector.label, value)
+            });
+            masked = Some(replaced.into_owned());
+        }
+        masked.filter(|m| m != text)
+    }
+
+    /// Mask every string in `value` except identifiers and binary sources
+    fn mask_value(&self, vault: &mut PiiVault, value: &mut Value) {
+        match value {
+            Value::String(text) => {
+                if let Some(masked) = self.mask_text(vault, text) {
+                    *text = masked;
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.mask_value(vault, item)),
+            Value::Object(map) => {
+                for (_, item) in map.iter_mut().filter(|(key, _)| !UNMASKED_KEYS.contains(&key.as_str())) {
+                    self.mask_value(vault, item);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Mask the system prompt and messages; returns the vault of placeholders used
+    fn mask_request(&self, req: &mut ClaudeRequest) -> PiiVault {
+        let mut vault = PiiVault { restore: self.restore, ..Default::default() };
+        if let Some(system) = req.system.as_mut() {
+            self.mask_value(&mut vault, system);
+        }
+        for message in &mut req.messages {
+            self.mask_value(&mut vault, &mut message.content);
+        }
+        vault
+    }
+}
+
+/// Luhn checksum of the digits in `number`
+fn luhn_valid(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| if i % 2 == 1 { if d * 2 > 9 { d * 2 - 9 } else { d * 2 } } else { d })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+impl Transform for PiiMasker {
+    fn name(&self) -> &str {
+        "pii"
+    }
+
+    fn handles_stream_events(&self) -> bool {
+        false
+    }
+
+    fn on_claude_request<'a>(&'a self, ctx: &'a mut TransformContext, req: &'a mut ClaudeRequest) -> BoxFuture<'a, TransformResult> {
+        Box::pin(async move {
+            let vault = self.mask_request(req);
+            if !vault.is_empty() && !ctx.dry_run {
+                let kinds: Vec<String> = vault.counts.iter().map(|(label, count)| format!("{}={}", label, count)).collect();
+                log::info!("🕶️  Masked {} PII value(s) in the request ({})", vault.by_placeholder.len(), kinds.join(", "));
+                log::info!(target: "metrics", "pii_masked: values={}, restore={}", vault.by_placeholder.len(), vault.restore);
+            }
+            if !vault.is_empty() {
+                ctx.extensions.insert(vault);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Delta field carrying the streamed text of each delta type
+/// Puts masked values back into response deltas (`PII_RESTORE`). A placeholder split across
+/// deltas is held back until it is complete; whatever is held is sent before its block ends.
+pub struct PiiRestorer {
+    vault: PiiVault,
+    longest: usize,
+    /// Held text and its delta kind per block index
+    held: HashMap<u64, (String, TextDeltaKind)>,
+}
+
+impl PiiRestorer {
+    /// `None` unless the request had values masked and `PII_RESTORE` is on
+    pub fn new(vault: Option<&PiiVault>) -> Option<Self> {
+        let vault = vault.filter(|v| v.restore && !v.is_empty())?.clone();
+        let longest = vault.by_placeholder.keys().map(String::len).max().unwrap_or(0);
+        Some(Self { vault, longest, held: HashMap::new() })
+    }
+
+    fn restore(&self, text: &str, json: bool) -> String {
+        let mut out = text.to_string();
+        for (placeholder, value) in &self.vault.by_placeholder {
+            if out.contains(placeholder.as_str()) {
+                let value = if json { json!(value).to_string().trim_matches('"').to_string() } else { value.clone() };
+                out = out.replace(placeholder.as_str(), &value);
+            }
+        }
+        out
+    }
+
+    /// Length of the suffix of `text` that may be the start of a placeholder
+    fn holdback_len(&self, text: &str) -> usize {
+        let Some(start) = text.rfind('[') else { return 0 };
+        let tail = &text[start..];
+        let partial = tail.len() < self.longest && self.vault.by_placeholder.keys().any(|p| p.starts_with(tail));
+        if partial { tail.len() } else { 0 }
+    }
+
+    pub fn apply(&mut self, mut event: StreamEvent) -> Vec<StreamEvent> {
+        let Some(index) = event.data["index"].as_u64() else {
+            return vec![event];
+        };
+        if event.event == "content_block_stop" {
+            let Some((text, kind)) = self.held.remove(&index).filter(|(text, _)| !text.is_empty()) else {
+                return vec![event];
+            };
+            return vec![StreamEvent::block_delta(index, BlockDelta::text_of(kind, &text)), event];
+        }
+        if event.event != "content_block_delta" {
+            return vec![event];
+        }
+        let Some(kind) = event.data["delta"]["type"].as_str().and_then(TextDeltaKind::parse) else {
+            return vec![event];
+        };
+        let field = kind.field();
+        let held = self.held.remove(&index).map(|(held, _)| held).unwrap_or_default();
+        let text = held + event.data["delta"][field].as_str().unwrap_or_default();
+        let mut restored = self.restore(&text, field == "partial_json");
+        let hold = self.holdback_len(&restored);
+        let pending = restored.split_off(restored.len() - hold);
+        if !pending.is_empty() {
+            self.held.insert(index, (pending, kind));
+        }
+        if restored.is_empty() {
+            return Vec::new();
+        }
+        event.data["delta"][field] = json!(restored);
+        vec![event]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masker(builtin: &[&str], custom: &[(&str, &str)]) -> PiiMasker {
+        let builtin: Vec<String> = builtin.iter().map(|b| b.to_string()).collect();
+        let custom: Vec<(String, String)> = custom.iter().map(|(n, p)| (n.to_string(), p.to_string())).collect();
+        PiiMasker::new(&builtin, &custom, true)
+    }
+
+    fn delta(index: u64, delta_type: &str, field: &str, text: &str) -> StreamEvent {
+        StreamEvent {
+            event: "content_block_delta",
+            data: json!({ "type": "content_block_delta", "index": index, "delta": { "type": delta_type, field: text } }),
+        }
+    }
+
+    #[test]
+    fn test_masks_builtin_and_custom_patterns() {
+        let masker = masker(&["all"], &[("employee_id", r"EMP-\d{6}")]);
+        let mut vault = PiiVault::default();
+        let text = "Mail ana@example.com or bo@example.org, call +1 415-555-0100, card 4111 1111 1111 1111, \
+                    order 4111111111111112, id EMP-004211. Again: ana@example.com";
+        assert_eq!(
+            masker.mask_text(&mut vault, text).unwrap(),
+            "Mail [EMAIL_1] or [EMAIL_2], call [PHONE_1], card [CREDIT_CARD_1], \
+             order 4111111111111112, id [EMPLOYEE_ID_1]. Again: [EMAIL_1]"
+        );
+        assert_eq!(masker.mask_text(&mut vault, "nothing to see, version 1.2.3"), None);
+    }
+
+    #[test]
+    fn test_request_masking_skips_identifiers() {
+        let masker = masker(&["email"], &[]);
+        let mut req: ClaudeRequest = serde_json::from_value(json!({
+            "model": "m",
+            "system": "Owner: ops@example.com",
+            "messages": [{"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "a@b.io", "content": "sent to ana@example.com"},
+                {"type": "text", "text": "reply to ops@example.com"},
+            ]}],
+        }))
+        .unwrap();
+        let vault = masker.mask_request(&mut req);
+        assert_eq!(req.system, Some(json!("Owner: [EMAIL_1]")));
+        assert_eq!(req.messages[0].content[0]["tool_use_id"], "a@b.io");
+        assert_eq!(req.messages[0].content[0]["content"], "sent to [EMAIL_2]");
+        assert_eq!(req.messages[0].content[1]["text"], "reply to [EMAIL_1]");
+        assert_eq!(vault.by_placeholder.len(), 2);
+    }
+
+    #[test]
+    fn test_restorer_joins_split_placeholders() {
+        let masker = masker(&["email"], &[("name", r#"O"Brien"#)]);
+        let mut vault = PiiVault { restore: true, ..Default::default() };
+        masker.mask_text(&mut vault, r#"ana@example.com, O"Brien"#).unwrap();
+        let mut restorer = PiiRestorer::new(Some(&vault)).unwrap();
+
+        let mut out = Vec::new();
+        for piece in ["Write to [EMA", "IL_1] [sic", "] or [EM"] {
+            out.extend(restorer.apply(delta(0, "text_delta", "text", piece)));
+        }
+        out.extend(restorer.apply(delta(1, "input_json_delta", "partial_json", r#"{"to":"[NAME_1]"}"#)));
+        out.extend(restorer.apply(StreamEvent { event: "content_block_stop", data: json!({"type": "content_block_stop", "index": 0}) }));
+
+        let text: String = out.iter().filter_map(|e| e.data["delta"]["text"].as_str()).collect();
+        assert_eq!(text, "Write to ana@example.com [sic] or [EM", "partial placeholders are flushed at the block's end");
+        assert_eq!(out.last().unwrap().event, "content_block_stop");
+        let args = out.iter().find_map(|e| e.data["delta"]["partial_json"].as_str()).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(args).unwrap()["to"], r#"O"Brien"#);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use serde_json::{json, Value};
+use crate::config::env_list;
+use crate::models::{ClaudeRequest, ThinkingConfig};
+
+/// Defaults an alias fills in when the client omits them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Preset {
+    /// Model the alias is sent as; may itself be a `MODEL_EXPERIMENTS` name or `MODEL_ROUTES` alias
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<u32>,
+    pub max_tokens: Option<u32>,
+    /// Thinking budget; `Some(0)` turns thinking off, also for models that would auto-enable it
+    pub thinking: Option<u32>,
+}
+
+impl Preset {
+    /// The preset as listed by `/v1/models`
+    fn describe(&self) -> Value {
+        let mut preset = json!({ "model": self.model });
+        let fields = [
+            ("temperature", self.temperature.map(decimal)),
+            ("top_p", self.top_p.map(decimal)),
+            ("top_k", self.top_k.map(Value::from)),
+            ("max_tokens", self.max_tokens.map(Value::from)),
+            ("thinking", self.thinking.map(|budget| if budget == 0 { json!("off") } else { json!(budget) })),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                preset[key] = value;
+            }
+        }
+        preset
+    }
+}
+
+/// An `f32` as the decimal it was configured as (`0.3`, not `0.30000001192092896`)
+fn decimal(value: f32) -> Value {
+    value.to_string().parse::<f64>().map_or(Value::Null, Value::from)
+}
+
+/// Model aliases bundling sampling defaults (`MODEL_PRESETS`), so operators can offer presets such
+/// as a low-temperature `claude-haiku` in Claude Code's model picker
+pub struct ModelPresets {
+    presets: BTreeMap<String, Preset>,
+}
+
+impl ModelPresets {
+    /// `MODEL_PRESETS` entries are `alias=model|temperature=0.3|max_tokens=4096|thinking=off`; `None` when unset
+    pub fn from_env() -> Option<Self> {
+        let presets = parse_presets(&env_list("MODEL_PRESETS"));
+        if presets.is_empty() {
+            return None;
+        }
+        for (alias, preset) in &presets {
+            log::info!("🎚️  Model preset '{}' → {}", alias, preset.describe());
+        }
+        Some(Self { presets })
+    }
+
+    /// Swap a preset alias for its model and fill in the parameters the client left out; returns the alias
+    pub fn apply(&self, cr: &mut ClaudeRequest) -> Option<String> {
+        let preset = self.presets.get(&cr.model)?;
+        let alias = std::mem::replace(&mut cr.model, preset.model.clone());
+        cr.temperature = cr.temperature.or(preset.temperature);
+        cr.top_p = cr.top_p.or(preset.top_p);
+        cr.top_k = cr.top_k.or(preset.top_k);
+        cr.max_tokens = cr.max_tokens.or(preset.max_tokens);
+        if cr.thinking.is_none() {
+            cr.thinking = preset.thinking.map(|budget_tokens| ThinkingConfig {
+                type_: if budget_tokens == 0 { "disabled" } else { "enabled" }.into(),
+                budget_tokens,
+            });
+        }
+        log::debug!("🎚️  Applied model preset '{}' → {}", alias, preset.model);
+        Some(alias)
+    }
+
+    /// Presets in the `/v1/models` list shape, so clients can pick them
+    pub fn model_entries(&self) -> Vec<Value> {
+        self.presets
+            .iter()
+            .map(|(alias, preset)| {
+                json!({
+                    "type": "model",
+                    "id": alias,
+                    "display_name": alias,
+                    "category": "preset",
+                    "preset": preset.describe(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Parse `alias=model|key=value|...` entries; malformed entries and unknown keys are skipped
+fn parse_presets(entries: &[String]) -> BTreeMap<String, Preset> {
+    let mut presets = BTreeMap::new();
+    for entry in entries {
+        let Some((alias, spec)) = entry.split_once('=') else {
+            log::warn!("⚠️  Ignoring malformed MODEL_PRESETS entry '{}' (expected alias=model|key=value)", entry);
+            continue;
+        };
+        let (alias, mut parts) = (alias.trim(), spec.split('|'));
+        let model = parts.next().unwrap_or_default().trim();
+        if alias.is_empty() || model.is_empty() {
+            log::warn!("⚠️  Ignoring MODEL_PRESETS entry '{}' without an alias or model", entry);
+            continue;
+        }
+        let mut preset = Preset { model: model.to_string(), ..Preset::default() };
+        for part in parts {
+            let (key, value) = part.split_once('=').map_or((part.trim(), ""), |(k, v)| (k.trim(), v.trim()));
+            let known = match key {
+                "temperature" => value.parse().map(|v| preset.temperature = Some(v)).is_ok(),
+                "top_p" => value.parse().map(|v| preset.top_p = Some(v)).is_ok(),
+                "top_k" => value.parse().map(|v| preset.top_k = Some(v)).is_ok(),
+                "max_tokens" => value.parse().map(|v| preset.max_tokens = Some(v)).is_ok(),
+                "thinking" => match value.to_ascii_lowercase().as_str() {
+                    "off" | "false" | "disabled" | "0" => {
+                        preset.thinking = Some(0);
+                        true
+                    }
+                    budget => budget.parse().map(|v| preset.thinking = Some(v)).is_ok(),
+                },
+                _ => false,
+            };
+            if !known {
+                log::warn!("⚠️  Ignoring MODEL_PRESETS setting '{}' for '{}'", part.trim(), alias);
+            }
+        }
+        presets.insert(alias.to_string(), preset);
+    }
+    presets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presets(entries: &[&str]) -> ModelPresets {
+        let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        ModelPresets { presets: parse_presets(&entries) }
+    }
+
+    #[test]
+    fn test_parse_presets() {
+        let parsed = parse_presets(&[
+            "claude-haiku=qwen-7b|temperature=0.3|max_tokens=4096|thinking=off|colour=red".into(),
+            "deep=r1|thinking=16000".into(),
+            "broken".into(),
+            "nomodel=|temperature=1".into(),
+        ]);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed["claude-haiku"], Preset {
+            model: "qwen-7b".into(),
+            temperature: Some(0.3),
+            max_tokens: Some(4096),
+            thinking: Some(0),
+            ..Preset::default()
+        });
+        assert_eq!(parsed["deep"].thinking, Some(16000));
+    }
+
+    #[test]
+    fn test_apply_fills_only_omitted_fields() {
+        let presets = presets(&["claude-haiku=qwen-7b|temperature=0.3|max_tokens=4096|thinking=off"]);
+        let mut cr: ClaudeRequest = serde_json::from_value(json!({
+            "model": "claude-haiku",
+            "max_tokens": 512,
+            "messages": [{"role": "user", "content": "hi"}]
+        }))
+        .unwrap();
+        assert_eq!(presets.apply(&mut cr).as_deref(), Some("claude-haiku"));
+        assert_eq!((cr.model.as_str(), cr.temperature, cr.max_tokens), ("qwen-7b", Some(0.3), Some(512)));
+        assert_eq!(cr.thinking.as_ref().map(|t| t.type_.as_str()), Some("disabled"));
+
+        assert!(presets.apply(&mut cr).is_none(), "only aliases are presets");
+        assert_eq!(presets.model_entries()[0]["preset"], json!({"model": "qwen-7b", "temperature": 0.3, "max_tokens": 4096, "thinking": "off"}));
+    }
+}
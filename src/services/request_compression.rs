@@ -0,0 +1,77 @@
+use std::io::Write;
+use flate2::{write::GzEncoder, Compression};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::RequestBuilder;
+use serde::Serialize;
+
+/// Attach `body` as JSON, gzip-compressed with `Content-Encoding: gzip` once it reaches
+/// `gzip_min_bytes` (`REQUEST_GZIP_MIN_BYTES`; 0 never compresses)
+pub fn json_body<T: Serialize>(req: RequestBuilder, body: &T, gzip_min_bytes: usize) -> RequestBuilder {
+    if gzip_min_bytes == 0 {
+        return req.json(body);
+    }
+    let Ok(json) = serde_json::to_vec(body) else {
+        // Let reqwest report the serialization error when the request is sent
+        return req.json(body);
+    };
+    // `headers` replaces rather than appends, so a content-type set by the caller isn't doubled
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if json.len() < gzip_min_bytes {
+        return req.headers(headers).body(json);
+    }
+    match gzip(&json) {
+        Ok(compressed) => {
+            log::debug!("🗜️  Compressed request body {} -> {} bytes", json.len(), compressed.len());
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            req.headers(headers).body(compressed)
+        }
+        Err(e) => {
+            log::warn!("⚠️  Request body compression failed: {} - sending it uncompressed", e);
+            req.headers(headers).body(json)
+        }
+    }
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 4), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use serde_json::json;
+    use std::io::Read;
+
+    fn sent(body: &serde_json::Value, min_bytes: usize) -> reqwest::Request {
+        let req = reqwest::Client::new().post("http://backend.invalid/v1").header("content-type", "application/json");
+        json_body(req, body, min_bytes).build().unwrap()
+    }
+
+    #[test]
+    fn test_json_body_gzips_large_bodies() {
+        let body = json!({"messages": [{"role": "system", "content": "x".repeat(4096)}]});
+        let req = sent(&body, 1024);
+        assert_eq!(req.headers().get("content-encoding").unwrap(), "gzip");
+        assert_eq!(req.headers().get_all("content-type").iter().count(), 1);
+
+        let compressed = req.body().and_then(|b| b.as_bytes()).unwrap();
+        assert!(compressed.len() < 1024);
+        let mut decoded = String::new();
+        GzDecoder::new(compressed).read_to_string(&mut decoded).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&decoded).unwrap(), body);
+    }
+
+    #[test]
+    fn test_json_body_leaves_small_bodies_alone() {
+        let body = json!({"messages": []});
+        for min_bytes in [0, 1024] {
+            let req = sent(&body, min_bytes);
+            assert!(req.headers().get("content-encoding").is_none());
+            assert_eq!(req.body().and_then(|b| b.as_bytes()).unwrap(), serde_json::to_vec(&body).unwrap());
+        }
+    }
+}
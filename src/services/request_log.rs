@@ -0,0 +1,319 @@
+use std::{
+    sync::{mpsc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use futures::future::BoxFuture;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use crate::constants::*;
+use crate::services::transform::{CompletionSummary, Transform, TransformContext, TransformResult};
+use crate::models::ClaudeRequest;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS requests (
+        id INTEGER PRIMARY KEY,
+        request_id TEXT NOT NULL,
+        ts_ms INTEGER NOT NULL,
+        key_fingerprint TEXT,
+        client TEXT NOT NULL,
+        model TEXT NOT NULL,
+        input_tokens INTEGER NOT NULL,
+        output_tokens INTEGER NOT NULL,
+        latency_ms INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        stop_reason TEXT NOT NULL,
+        backend_headers TEXT,
+        experiment TEXT
+    );
+    CREATE INDEX IF NOT EXISTS idx_requests_ts ON requests (ts_ms);
+";
+
+/// One finished request
+#[derive(Debug, Clone)]
+pub struct RequestRecord {
+    pub request_id: String,
+    pub ts_ms: u64,
+    pub key_fingerprint: Option<String>,
+    pub client: String,
+    pub model: String,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub latency_ms: u64,
+    pub status: &'static str,
+    pub stop_reason: String,
+    /// Diagnostic backend response headers, stored as a JSON object (`NULL` when there were none)
+    pub backend_headers: Option<String>,
+    /// `MODEL_EXPERIMENTS` experiment the request was assigned in
+    pub experiment: Option<String>,
+}
+
+/// Per-model totals over a time window; experiment traffic is counted apart from the model's other requests
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ModelUsage {
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<String>,
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub avg_latency_ms: u64,
+}
+
+/// Request start time, carried in `TransformContext::extensions`
+#[derive(Clone, Copy)]
+struct RequestStart(Instant);
+
+/// Transform that persists per-request metadata to SQLite (`REQUEST_LOG_DB`)
+pub struct RequestLogStore {
+    tx: mpsc::SyncSender<RequestRecord>,
+    reader: Mutex<Connection>,
+}
+
+impl RequestLogStore {
+    /// Open the database at `REQUEST_LOG_DB`; `None` when unset or the database can't be opened
+    pub fn from_env() -> Option<Self> {
+        use crate::config::env_or;
+        let path = std::env::var("REQUEST_LOG_DB").ok().filter(|p| !p.trim().is_empty())?;
+        let retention_days: u64 = env_or("REQUEST_LOG_RETENTION_DAYS", DEFAULT_REQUEST_LOG_RETENTION_DAYS);
+        let retention = (retention_days > 0).then(|| Duration::from_secs(retention_days * 86_400));
+        match Self::open(&path, retention) {
+            Ok(store) => {
+                log::info!("   Request log: {} (retention: {} days)", path, retention_days);
+                Some(store)
+            }
+            Err(e) => {
+                log::error!("❌ Failed to open REQUEST_LOG_DB '{}': {}", path, e);
+                None
+            }
+        }
+    }
+
+    pub fn open(path: &str, retention: Option<Duration>) -> rusqlite::Result<Self> {
+        let writer = Connection::open(path)?;
+        writer.pragma_update(None, "journal_mode", "WAL")?;
+        writer.execute_batch(SCHEMA)?;
+        migrate(&writer)?;
+        let reader = Connection::open(path)?;
+        let (tx, rx) = mpsc::sync_channel(REQUEST_LOG_QUEUE_SIZE);
+        std::thread::spawn(move || run_writer(writer, rx, retention));
+        Ok(Self { tx, reader: Mutex::new(reader) })
+    }
+
+    /// Per-model usage for requests since `since_ms` (Unix milliseconds)
+    pub fn usage_by_model(&self, since_ms: u64) -> rusqlite::Result<Vec<ModelUsage>> {
+        let conn = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+        query_usage(&conn, since_ms)
+    }
+}
+
+/// Add columns introduced after a database was created
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('requests')")?;
+    let columns: Vec<String> = stmt.query_map([], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+    if !columns.iter().any(|c| c == "backend_headers") {
+        conn.execute_batch("ALTER TABLE requests ADD COLUMN backend_headers TEXT")?;
+    }
+    if !columns.iter().any(|c| c == "experiment") {
+        conn.execute_batch("ALTER TABLE requests ADD COLUMN experiment TEXT")?;
+    }
+    Ok(())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn run_writer(conn: Connection, rx: mpsc::Receiver<RequestRecord>, retention: Option<Duration>) {
+    let prune_interval = Duration::from_secs(REQUEST_LOG_PRUNE_INTERVAL_SECS);
+    let mut last_prune: Option<Instant> = None;
+    loop {
+        if let Some(retention) = retention.filter(|_| last_prune.is_none_or(|t| t.elapsed() >= prune_interval)) {
+            match prune(&conn, now_ms().saturating_sub(retention.as_millis() as u64)) {
+                Ok(0) => {}
+                Ok(n) => log::info!("🧹 Pruned {} request log entries", n),
+                Err(e) => log::warn!("⚠️  Request log pruning failed: {}", e),
+            }
+            last_prune = Some(Instant::now());
+        }
+        let Ok(record) = rx.recv() else { return };
+        if let Err(e) = insert(&conn, &record) {
+            log::warn!("⚠️  Failed to write request log entry: {}", e);
+        }
+    }
+}
+
+fn insert(conn: &Connection, r: &RequestRecord) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO requests (request_id, ts_ms, key_fingerprint, client, model, input_tokens,
+                               output_tokens, latency_ms, status, stop_reason, backend_headers, experiment)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![
+            r.request_id, r.ts_ms as i64, r.key_fingerprint, r.client, r.model, r.input_tokens,
+            r.output_tokens, r.latency_ms as i64, r.status, r.stop_reason, r.backend_headers, r.experiment
+        ],
+    )?;
+    Ok(())
+}
+
+fn prune(conn: &Connection, before_ms: u64) -> rusqlite::Result<usize> {
+    conn.execute("DELETE FROM requests WHERE ts_ms < ?1", params![before_ms as i64])
+}
+
+fn query_usage(conn: &Connection, since_ms: u64) -> rusqlite::Result<Vec<ModelUsage>> {
+    let mut stmt = conn.prepare(
+        "SELECT model, experiment, COUNT(*), SUM(status = 'error'), SUM(input_tokens), SUM(output_tokens), AVG(latency_ms)
+         FROM requests WHERE ts_ms >= ?1 GROUP BY model, experiment ORDER BY COUNT(*) DESC, model, experiment",
+    )?;
+    let rows = stmt.query_map(params![since_ms as i64], |row| {
+        Ok(ModelUsage {
+            model: row.get(0)?,
+            experiment: row.get(1)?,
+            requests: row.get::<_, i64>(2)? as u64,
+            errors: row.get::<_, i64>(3)? as u64,
+            input_tokens: row.get::<_, i64>(4)? as u64,
+            output_tokens: row.get::<_, i64>(5)? as u64,
+            avg_latency_ms: row.get::<_, f64>(6)? as u64,
+        })
+    })?;
+    rows.collect()
+}
+
+impl Transform for RequestLogStore {
+    fn name(&self) -> &str {
+        "request_log"
+    }
+
+    fn handles_stream_events(&self) -> bool {
+        false
+    }
+
+    fn on_claude_request<'a>(
+        &'a self,
+        ctx: &'a mut TransformContext,
+        _req: &'a mut ClaudeRequest,
+    ) -> BoxFuture<'a, TransformResult> {
+        Box::pin(async move {
+            ctx.extensions.insert(RequestStart(Instant::now()));
+            Ok(())
+        })
+    }
+
+    fn on_complete<'a>(
+        &'a self,
+        ctx: &'a mut TransformContext,
+        summary: &'a CompletionSummary,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let latency_ms = ctx
+                .extensions
+                .get::<RequestStart>()
+                .map(|start| start.0.elapsed().as_millis() as u64)
+                .unwrap_or(0);
+            let record = RequestRecord {
+                request_id: ctx.request_id.clone(),
+                ts_ms: now_ms(),
+                key_fingerprint: ctx.key_fingerprint.clone(),
+                client: ctx.client.to_string(),
+                model: ctx.model.clone(),
+                input_tokens: summary.input_tokens,
+                output_tokens: summary.output_tokens,
+                latency_ms,
+                status: if summary.fatal_error { "error" } else { "ok" },
+                stop_reason: summary.stop_reason.clone(),
+                backend_headers: (!ctx.backend_headers.is_empty()).then(|| serde_json::to_string(&ctx.backend_headers).unwrap_or_default()),
+                experiment: ctx.experiment.clone(),
+            };
+            if self.tx.try_send(record).is_err() {
+                log::debug!("⚠️  Request log queue full, dropping entry");
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(model: &str, ts_ms: u64, status: &'static str, latency_ms: u64) -> RequestRecord {
+        RequestRecord {
+            request_id: "msg_1".into(),
+            ts_ms,
+            key_fingerprint: Some("fp_0123".into()),
+            client: "claude-code/1.0.0".into(),
+            model: model.into(),
+            input_tokens: 100,
+            output_tokens: 10,
+            latency_ms,
+            status,
+            stop_reason: "end_turn".into(),
+            backend_headers: None,
+            experiment: None,
+        }
+    }
+
+    fn db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_usage_aggregates_per_model_since() {
+        let conn = db();
+        insert(&conn, &record("qwen", 1_000, "ok", 100)).unwrap();
+        insert(&conn, &record("qwen", 2_000, "error", 300)).unwrap();
+        insert(&conn, &record("llama", 2_000, "ok", 50)).unwrap();
+        insert(&conn, &record("old", 10, "ok", 50)).unwrap();
+
+        let usage = query_usage(&conn, 500).unwrap();
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[0], ModelUsage {
+            model: "qwen".into(),
+            experiment: None,
+            requests: 2,
+            errors: 1,
+            input_tokens: 200,
+            output_tokens: 20,
+            avg_latency_ms: 200,
+        });
+        assert_eq!(usage[1].model, "llama");
+    }
+
+    #[test]
+    fn test_migrate_adds_backend_headers() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&SCHEMA.replace(",\n        backend_headers TEXT,\n        experiment TEXT", "")).unwrap();
+        migrate(&conn).unwrap();
+        migrate(&conn).unwrap();
+        let mut with_headers = record("a", 10, "ok", 1);
+        with_headers.backend_headers = Some(r#"{"x-request-id":"req_1"}"#.into());
+        insert(&conn, &with_headers).unwrap();
+        let stored: String = conn.query_row("SELECT backend_headers FROM requests", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, r#"{"x-request-id":"req_1"}"#);
+    }
+
+    #[test]
+    fn test_usage_splits_experiment_traffic() {
+        let conn = db();
+        let mut arm = record("qwen", 1_000, "ok", 100);
+        arm.experiment = Some("claude-sonnet".into());
+        insert(&conn, &arm).unwrap();
+        insert(&conn, &arm).unwrap();
+        insert(&conn, &record("qwen", 1_000, "ok", 100)).unwrap();
+
+        let usage = query_usage(&conn, 0).unwrap();
+        assert_eq!(usage.len(), 2);
+        assert_eq!((usage[0].experiment.as_deref(), usage[0].requests), (Some("claude-sonnet"), 2));
+        assert_eq!((usage[1].experiment.as_deref(), usage[1].requests), (None, 1));
+    }
+
+    #[test]
+    fn test_prune_removes_old_entries() {
+        let conn = db();
+        insert(&conn, &record("a", 10, "ok", 1)).unwrap();
+        insert(&conn, &record("a", 5_000, "ok", 1)).unwrap();
+        assert_eq!(prune(&conn, 1_000).unwrap(), 1);
+        assert_eq!(query_usage(&conn, 0).unwrap()[0].requests, 1);
+    }
+}
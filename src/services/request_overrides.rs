@@ -0,0 +1,87 @@
+use axum::http::HeaderMap;
+use crate::models::ClaudeRequest;
+
+pub const MODEL_OVERRIDE_HEADER: &str = "x-proxy-model";
+pub const BACKEND_OVERRIDE_HEADER: &str = "x-proxy-backend";
+pub const MAX_TOKENS_OVERRIDE_HEADER: &str = "x-proxy-max-tokens";
+
+/// Overrides requested via `x-proxy-*` headers
+#[derive(Debug, Default, PartialEq)]
+pub struct RequestOverrides {
+    pub model: Option<String>,
+    pub backend: Option<String>,
+    pub max_tokens: Option<u32>,
+}
+
+impl RequestOverrides {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        };
+        let max_tokens = get(MAX_TOKENS_OVERRIDE_HEADER).and_then(|s| {
+            let parsed = s.parse::<u32>().ok();
+            if parsed.is_none() {
+                log::warn!("⚠️  Ignoring invalid {} header: {}", MAX_TOKENS_OVERRIDE_HEADER, s);
+            }
+            parsed
+        });
+        Self {
+            model: get(MODEL_OVERRIDE_HEADER),
+            backend: get(BACKEND_OVERRIDE_HEADER),
+            max_tokens,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Apply body-level overrides (model, max_tokens) to the Claude request
+    pub fn apply(&self, cr: &mut ClaudeRequest) {
+        if let Some(model) = &self.model {
+            log::info!("🎛️  Header override: model {} → {}", cr.model, model);
+            cr.model = model.clone();
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            log::info!("🎛️  Header override: max_tokens {:?} → {}", cr.max_tokens, max_tokens);
+            cr.max_tokens = Some(max_tokens);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_overrides_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(MODEL_OVERRIDE_HEADER, HeaderValue::from_static("qwen-7b"));
+        headers.insert(BACKEND_OVERRIDE_HEADER, HeaderValue::from_static("local"));
+        headers.insert(MAX_TOKENS_OVERRIDE_HEADER, HeaderValue::from_static("2048"));
+
+        let o = RequestOverrides::from_headers(&headers);
+        assert_eq!(o.model.as_deref(), Some("qwen-7b"));
+        assert_eq!(o.backend.as_deref(), Some("local"));
+        assert_eq!(o.max_tokens, Some(2048));
+    }
+
+    #[test]
+    fn test_overrides_ignore_blank_and_invalid_values() {
+        let mut headers = HeaderMap::new();
+        headers.insert(MODEL_OVERRIDE_HEADER, HeaderValue::from_static("  "));
+        headers.insert(MAX_TOKENS_OVERRIDE_HEADER, HeaderValue::from_static("lots"));
+
+        assert!(RequestOverrides::from_headers(&headers).is_empty());
+    }
+
+    #[test]
+    fn test_no_override_headers() {
+        assert!(RequestOverrides::from_headers(&HeaderMap::new()).is_empty());
+    }
+}
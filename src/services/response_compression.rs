@@ -0,0 +1,105 @@
+use std::sync::Arc;
+use axum::http::{header, Extensions, HeaderMap, StatusCode, Version};
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use crate::config::{env_list, env_or};
+use crate::constants::{DEFAULT_COMPRESSION_MIN_BYTES, DEFAULT_COMPRESSION_TYPES};
+
+/// Content type that is never compressed: gzip encoders hold events back until a block fills
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// Which responses the response compression layer may compress.
+///
+/// Compression is opt-in per content type, so `text/event-stream` and binary file downloads pass
+/// through untouched while JSON endpoints are still compressed.
+#[derive(Debug, Clone)]
+pub struct ResponseCompression {
+    /// Compressible media types (`COMPRESSION_TYPES`); `type/*` matches a whole top-level type
+    types: Arc<Vec<String>>,
+    /// Smaller responses are sent as-is (`COMPRESSION_MIN_BYTES`)
+    min_bytes: u16,
+}
+
+impl ResponseCompression {
+    pub fn new(types: &[String], min_bytes: u16) -> Self {
+        let types = types
+            .iter()
+            .map(|t| t.trim().to_ascii_lowercase())
+            .filter(|t| {
+                let streaming = t == EVENT_STREAM || t == "text/*" || t == "*/*";
+                if streaming {
+                    log::warn!("⚠️  COMPRESSION_TYPES: '{}' would buffer SSE streams - event-stream responses stay uncompressed", t);
+                }
+                t != EVENT_STREAM
+            })
+            .collect();
+        Self { types: Arc::new(types), min_bytes }
+    }
+
+    /// `COMPRESSION=false` disables response compression entirely
+    pub fn from_env() -> Self {
+        if !env_or("COMPRESSION", true) {
+            return Self::new(&[], DEFAULT_COMPRESSION_MIN_BYTES);
+        }
+        let types = env_list("COMPRESSION_TYPES");
+        let types = if types.is_empty() { DEFAULT_COMPRESSION_TYPES.iter().map(|t| t.to_string()).collect() } else { types };
+        Self::new(&types, env_or("COMPRESSION_MIN_BYTES", DEFAULT_COMPRESSION_MIN_BYTES))
+    }
+
+    /// Whether a response with these headers may be compressed
+    pub fn allows(&self, headers: &HeaderMap) -> bool {
+        let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        if essence == EVENT_STREAM {
+            return false;
+        }
+        self.types.iter().any(|t| match t.strip_suffix("/*") {
+            Some("*") => true,
+            Some(top) => essence.split('/').next() == Some(top),
+            None => *t == essence,
+        })
+    }
+
+    /// Compression layer applying these rules
+    pub fn layer(&self) -> CompressionLayer<impl Predicate> {
+        let rules = self.clone();
+        let allowed = move |_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions| rules.allows(headers);
+        CompressionLayer::new().compress_when(SizeAbove::new(self.min_bytes).and(allowed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn with_type(content_type: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_event_streams_are_never_compressed() {
+        let defaults: Vec<String> = DEFAULT_COMPRESSION_TYPES.iter().map(|t| t.to_string()).collect();
+        let rules = ResponseCompression::new(&defaults, 0);
+        assert!(rules.allows(&with_type("application/json")));
+        assert!(rules.allows(&with_type("text/html; charset=utf-8")));
+        assert!(!rules.allows(&with_type("text/event-stream")));
+        assert!(!rules.allows(&with_type("application/pdf")));
+        assert!(!rules.allows(&HeaderMap::new()));
+
+        let everything = ResponseCompression::new(&["*/*".into(), "text/event-stream".into()], 0);
+        assert!(everything.allows(&with_type("image/png")));
+        assert!(!everything.allows(&with_type("Text/Event-Stream; charset=utf-8")));
+    }
+
+    #[test]
+    fn test_wildcard_types() {
+        let rules = ResponseCompression::new(&["application/*".into()], 0);
+        assert!(rules.allows(&with_type("application/json")));
+        assert!(!rules.allows(&with_type("text/plain")));
+    }
+}
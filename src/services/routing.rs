@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use axum::http::StatusCode;
+use serde::Serialize;
+use crate::config::{env_list, env_parse};
+use crate::constants::{ROUTE_FAILURE_COOLDOWN_SECS, ROUTE_LATENCY_EWMA_WEIGHT};
+use crate::models::{Backend, BackendRegistry};
+use crate::utils::split_model_suffix;
+
+/// One backend able to serve an aliased model, and the model name it knows it by
+#[derive(Debug, Clone, PartialEq)]
+struct Route {
+    backend: String,
+    model: String,
+}
+
+/// Recent behaviour of a routed backend
+#[derive(Debug, Clone, Default)]
+struct RouteHealth {
+    /// Moving average of time to response headers
+    latency_ms: Option<f64>,
+    /// Set by a failure; the backend is tried after healthy ones until then
+    cooling_until: Option<Instant>,
+}
+
+/// Ordering of one candidate: healthy before cooling down, fast before over `ROUTE_LATENCY_LIMIT_MS`,
+/// then cheapest, then fastest
+#[derive(Debug, PartialEq, PartialOrd)]
+struct RouteRank {
+    cooling: bool,
+    slow: bool,
+    cost: f64,
+    latency_ms: f64,
+}
+
+/// Whether a backend response is worth retrying on the next route: rate limits and server errors
+pub fn is_failover_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Aliased models served by several backends (`MODEL_ROUTES`).
+///
+/// A request for an alias goes to the cheapest healthy backend (per-backend `COST`); backends that
+/// just failed or whose latency exceeds `ROUTE_LATENCY_LIMIT_MS` are only tried once the others have
+/// failed, so a request fails over upward in cost.
+pub struct ModelRoutes {
+    aliases: HashMap<String, Vec<Route>>,
+    latency_limit: Option<Duration>,
+    health: Mutex<HashMap<String, RouteHealth>>,
+}
+
+impl ModelRoutes {
+    /// `MODEL_ROUTES` entries are `alias=backend:model|backend:model`; `None` when unset
+    pub fn from_env(backends: &BackendRegistry) -> Option<Self> {
+        let aliases = parse_routes(&env_list("MODEL_ROUTES"), backends);
+        if aliases.is_empty() {
+            return None;
+        }
+        Some(Self {
+            aliases,
+            latency_limit: env_parse::<u64>("ROUTE_LATENCY_LIMIT_MS").filter(|&ms| ms > 0).map(Duration::from_millis),
+            health: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Backends to try for `model`, best first, with the model name to send to each; `None` when
+    /// `model` isn't an alias
+    pub fn plan(&self, model: &str, backends: &BackendRegistry) -> Option<Vec<(Backend, String)>> {
+        let (routes, suffix) = self.resolve(model)?;
+        let ranked = self.rank(routes, |name| backends.get(name).map(|b| b.options.cost));
+        Some(
+            ranked
+                .into_iter()
+                .filter_map(|route| Some((backends.get(&route.backend)?.clone(), format!("{}{}", route.model, suffix))))
+                .collect(),
+        )
+    }
+
+    /// Routes of an alias, and the OpenRouter suffix to append to each target model: `glm:nitro`
+    /// uses the `glm` routes with `:nitro` unless it is an alias of its own
+    fn resolve<'a>(&self, model: &'a str) -> Option<(&[Route], &'a str)> {
+        if let Some(routes) = self.aliases.get(model) {
+            return Some((routes, ""));
+        }
+        let (base, suffix) = split_model_suffix(model);
+        let routes = self.aliases.get(base).filter(|_| !suffix.is_empty())?;
+        Some((routes, suffix))
+    }
+
+    /// `routes` in the order they should be tried; `cost` is `None` for unknown backends, which are dropped
+    fn rank<'a>(&self, routes: &'a [Route], cost: impl Fn(&str) -> Option<f64>) -> Vec<&'a Route> {
+        let health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let mut ranked: Vec<(RouteRank, &Route)> = routes
+            .iter()
+            .filter_map(|route| {
+                let health = health.get(&route.backend).cloned().unwrap_or_default();
+                let latency_ms = health.latency_ms.unwrap_or(0.0);
+                let rank = RouteRank {
+                    cooling: health.cooling_until.is_some_and(|until| until > now),
+                    slow: self.latency_limit.is_some_and(|limit| latency_ms > limit.as_millis() as f64),
+                    cost: cost(&route.backend)?,
+                    latency_ms,
+                };
+                Some((rank, route))
+            })
+            .collect();
+        // Stable, so equally ranked routes keep their configured order
+        ranked.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        ranked.into_iter().map(|(_, route)| route).collect()
+    }
+
+    /// A response arrived after `latency`
+    pub fn record_success(&self, backend: &str, latency: Duration) {
+        let sample = latency.as_secs_f64() * 1000.0;
+        let mut health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = health.entry(backend.to_string()).or_default();
+        entry.latency_ms = Some(match entry.latency_ms {
+            Some(avg) => avg + ROUTE_LATENCY_EWMA_WEIGHT * (sample - avg),
+            None => sample,
+        });
+        entry.cooling_until = None;
+    }
+
+    /// The backend couldn't be reached or answered with a rate limit or server error
+    pub fn record_failure(&self, backend: &str) {
+        let mut health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        health.entry(backend.to_string()).or_default().cooling_until =
+            Some(Instant::now() + Duration::from_secs(ROUTE_FAILURE_COOLDOWN_SECS));
+    }
+
+    pub fn snapshot(&self, backends: &BackendRegistry) -> HashMap<String, Vec<RouteSnapshot>> {
+        let health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        self.aliases
+            .iter()
+            .map(|(alias, routes)| {
+                let routes = routes
+                    .iter()
+                    .map(|route| {
+                        let health = health.get(&route.backend).cloned().unwrap_or_default();
+                        RouteSnapshot {
+                            backend: route.backend.clone(),
+                            model: route.model.clone(),
+                            cost: backends.get(&route.backend).map_or(0.0, |b| b.options.cost),
+                            latency_ms: health.latency_ms.map(|ms| ms.round() as u64),
+                            healthy: health.cooling_until.is_none_or(|until| until <= now),
+                        }
+                    })
+                    .collect();
+                (alias.clone(), routes)
+            })
+            .collect()
+    }
+}
+
+/// One route of an alias as reported by `/admin/stats`
+#[derive(Debug, Serialize)]
+pub struct RouteSnapshot {
+    pub backend: String,
+    pub model: String,
+    pub cost: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    pub healthy: bool,
+}
+
+/// Resolve `alias=backend:model|...` entries; routes to unknown backends are skipped with a warning
+fn parse_routes(entries: &[String], backends: &BackendRegistry) -> HashMap<String, Vec<Route>> {
+    let mut aliases = HashMap::new();
+    for entry in entries {
+        let Some((alias, targets)) = entry.split_once('=') else {
+            log::warn!("⚠️  Ignoring malformed MODEL_ROUTES entry '{}' (expected alias=backend:model|...)", entry);
+            continue;
+        };
+        let routes: Vec<Route> = targets
+            .split('|')
+            .filter_map(|target| {
+                // Split on the first ':' only: model names such as `qwen3:8b` contain colons
+                let Some((backend, model)) = target.trim().split_once(':') else {
+                    log::warn!("⚠️  Ignoring MODEL_ROUTES target '{}' for '{}' (expected backend:model)", target, alias.trim());
+                    return None;
+                };
+                if backends.get(backend.trim()).is_none() {
+                    log::warn!("⚠️  Ignoring MODEL_ROUTES target '{}' for '{}': unknown backend '{}'", target, alias.trim(), backend.trim());
+                    return None;
+                }
+                Some(Route { backend: backend.trim().to_string(), model: model.trim().to_string() })
+            })
+            .collect();
+        if !routes.is_empty() {
+            aliases.insert(alias.trim().to_string(), routes);
+        }
+    }
+    aliases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(entries: &[&str]) -> ModelRoutes {
+        let backends = BackendRegistry::new(
+            "http://main/v1/chat/completions".into(),
+            &["cheap=http://cheap/v1".into(), "mid=http://mid/v1".into(), "pricey=http://pricey/v1".into()],
+        );
+        let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        ModelRoutes {
+            aliases: parse_routes(&entries, &backends),
+            latency_limit: Some(Duration::from_millis(5_000)),
+            health: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn order(routes: &ModelRoutes, alias: &str) -> Vec<String> {
+        let cost = |name: &str| match name {
+            "cheap" => Some(0.5),
+            "mid" => Some(1.0),
+            "pricey" => Some(3.0),
+            _ => None,
+        };
+        routes.rank(&routes.aliases[alias], cost).into_iter().map(|r| r.backend.clone()).collect()
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let backends = BackendRegistry::new("http://main/v1/chat/completions".into(), &["local=http://local/v1".into()]);
+        let routes = parse_routes(
+            &["glm=local:qwen3:8b|default:zai-org/GLM-4.5|missing:m".into(), "broken".into(), "empty=missing:m".into()],
+            &backends,
+        );
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes["glm"], vec![
+            Route { backend: "local".into(), model: "qwen3:8b".into() },
+            Route { backend: "default".into(), model: "zai-org/GLM-4.5".into() },
+        ]);
+    }
+
+    #[test]
+    fn test_cheapest_healthy_backend_first() {
+        let routes = routes(&["glm=pricey:glm-big|cheap:glm|mid:glm-4.5"]);
+        assert_eq!(order(&routes, "glm"), ["cheap", "mid", "pricey"]);
+
+        // A failed backend drops behind the healthy ones until it answers again
+        routes.record_failure("cheap");
+        assert_eq!(order(&routes, "glm"), ["mid", "pricey", "cheap"]);
+
+        // Too slow counts against a backend, but it still beats one that just failed
+        routes.record_success("mid", Duration::from_millis(9_000));
+        assert_eq!(order(&routes, "glm"), ["pricey", "mid", "cheap"]);
+
+        routes.record_success("cheap", Duration::from_millis(100));
+        assert_eq!(order(&routes, "glm"), ["cheap", "pricey", "mid"]);
+    }
+
+    #[test]
+    fn test_suffixed_alias_keeps_its_suffix() {
+        let routes = routes(&["glm=cheap:z-ai/glm-4.6|mid:glm-4.6", "glm:free=pricey:z-ai/glm-4.5-air:free"]);
+        let targets = |model: &str| {
+            let (routes, suffix) = routes.resolve(model).unwrap();
+            routes.iter().map(|r| format!("{}{}", r.model, suffix)).collect::<Vec<_>>()
+        };
+        assert_eq!(targets("glm:nitro"), ["z-ai/glm-4.6:nitro", "glm-4.6:nitro"]);
+        assert_eq!(targets("glm@preset/fast"), ["z-ai/glm-4.6@preset/fast", "glm-4.6@preset/fast"]);
+        assert_eq!(targets("glm:free"), ["z-ai/glm-4.5-air:free"]);
+        assert!(routes.resolve("glm:8b").is_none());
+    }
+
+    #[test]
+    fn test_failover_status() {
+        assert!(is_failover_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_failover_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_failover_status(StatusCode::BAD_REQUEST));
+        assert!(!is_failover_status(StatusCode::OK));
+    }
+}
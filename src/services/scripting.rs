@@ -0,0 +1,171 @@
+use futures::future::BoxFuture;
+use rhai::{Dynamic, Engine, Scope, AST};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::models::OAIChatReq;
+use crate::services::transform::{Transform, TransformContext, TransformResult};
+
+/// Upper bound on script operations per call so a buggy script can't stall a request
+const MAX_SCRIPT_OPERATIONS: u64 = 1_000_000;
+
+/// Name of the script function invoked with the converted OpenAI request
+const REQUEST_HOOK_FN: &str = "on_request";
+
+/// Transform backed by a Rhai script.
+///
+/// The script defines `fn on_request(req)` which receives the converted OpenAI request as
+/// an object map and returns the (possibly modified) map. Returning `()` leaves the request
+/// unchanged. Script errors are logged and the request is forwarded unmodified.
+///
+/// ```rhai
+/// fn on_request(req) {
+///     if utc_hour() >= 22 { req.model = "small-model"; }
+///     req
+/// }
+/// ```
+pub struct ScriptTransform {
+    name: String,
+    engine: Engine,
+    ast: AST,
+}
+
+impl ScriptTransform {
+    /// Compile a script from disk
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let source = std::fs::read_to_string(path)?;
+        Self::compile(path, &source)
+    }
+
+    fn compile(name: &str, source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_SCRIPT_OPERATIONS);
+        engine.register_fn("utc_hour", || -> i64 {
+            let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            ((secs / 3600) % 24) as i64
+        });
+        engine.on_print(|s| log::info!("📜 script: {}", s));
+
+        let ast = engine.compile(source)?;
+        if !ast.iter_functions().any(|f| f.name == REQUEST_HOOK_FN) {
+            return Err(format!("script does not define fn {}(req)", REQUEST_HOOK_FN).into());
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            engine,
+            ast,
+        })
+    }
+
+    fn run(&self, req: &OAIChatReq) -> Result<Option<OAIChatReq>, Box<dyn std::error::Error>> {
+        let input = rhai::serde::to_dynamic(req)?;
+        let output: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, REQUEST_HOOK_FN, (input,))?;
+        if output.is_unit() {
+            return Ok(None);
+        }
+        Ok(Some(rhai::serde::from_dynamic(&output)?))
+    }
+}
+
+impl Transform for ScriptTransform {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn handles_stream_events(&self) -> bool {
+        false
+    }
+
+    fn on_oai_request<'a>(
+        &'a self,
+        ctx: &'a mut TransformContext,
+        req: &'a mut OAIChatReq,
+    ) -> BoxFuture<'a, TransformResult> {
+        Box::pin(async move {
+            match self.run(req) {
+                Ok(Some(rewritten)) => {
+                    if rewritten.model != req.model && !ctx.dry_run {
+                        log::info!("📜 Script '{}' rewrote model: {} → {}", self.name, req.model, rewritten.model);
+                    }
+                    *req = rewritten;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("⚠️  Script '{}' failed, forwarding request unchanged: {}", self.name, e);
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OAIMessage;
+    use serde_json::json;
+
+    fn request() -> OAIChatReq {
+        OAIChatReq {
+            model: "big-model".into(),
+            messages: vec![OAIMessage {
+                role: "system".into(),
+                content: json!("original"),
+                tool_call_id: None,
+                tool_calls: None,
+                prefix: None,
+            }],
+            max_tokens: Some(100),
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            stop: None,
+            tools: Some(vec![]),
+            tool_choice: None,
+            thinking: None,
+            parallel_tool_calls: None,
+            metadata: None,
+            stream: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_script_rewrites_model_and_system_prompt() {
+        let script = ScriptTransform::compile(
+            "test",
+            r#"
+                fn on_request(req) {
+                    req.model = "small-model";
+                    req.messages[0].content = "rewritten";
+                    req
+                }
+            "#,
+        )
+        .unwrap();
+
+        let out = script.run(&request()).unwrap().unwrap();
+        assert_eq!(out.model, "small-model");
+        assert_eq!(out.messages[0].content, json!("rewritten"));
+        assert_eq!(out.max_tokens, Some(100));
+        assert!(out.stream);
+    }
+
+    #[test]
+    fn test_script_returning_unit_leaves_request_unchanged() {
+        let script = ScriptTransform::compile("test", "fn on_request(req) { }").unwrap();
+        assert!(script.run(&request()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_script_without_hook_is_rejected() {
+        assert!(ScriptTransform::compile("test", "fn other(x) { x }").is_err());
+    }
+
+    #[test]
+    fn test_runaway_script_is_stopped() {
+        let script = ScriptTransform::compile("test", "fn on_request(req) { loop {} }").unwrap();
+        assert!(script.run(&request()).is_err());
+    }
+}
@@ -0,0 +1,675 @@
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use crate::constants::{CODE_EXECUTION_MAX_OUTPUT_BYTES, MAX_SERVER_TOOL_ROUNDS, WEB_SEARCH_TIMEOUT_SECS};
+use crate::models::{ClaudeTool, OAIChatReq, OAIMessage};
+use crate::services::systemd::ACTIVATION_VARS;
+
+/// Executors for the server tools the proxy emulates; a tool without one is dropped from requests
+#[derive(Debug, Clone, Default)]
+pub struct ServerToolConfig {
+    pub web_search: Option<WebSearchConfig>,
+    pub code_execution: Option<CodeExecutionConfig>,
+}
+
+/// Search endpoint behind the emulated `web_search` server tool (`WEB_SEARCH_URL`, `WEB_SEARCH_MAX_RESULTS`)
+#[derive(Debug, Clone)]
+pub struct WebSearchConfig {
+    /// SearXNG-compatible endpoint, queried with `q=<query>&format=json`
+    pub url: String,
+    pub max_results: usize,
+}
+
+/// Sandbox running `code_execution` calls (`CODE_EXECUTION_SANDBOX`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sandbox {
+    /// Throwaway container without network (`CODE_EXECUTION_IMAGE`)
+    Docker,
+    /// firejail with a private home and no network, using the host's `python3`
+    Firejail,
+}
+
+impl FromStr for Sandbox {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "docker" => Ok(Sandbox::Docker),
+            "firejail" => Ok(Sandbox::Firejail),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Sandboxed Python behind the emulated `code_execution` server tool
+/// (`CODE_EXECUTION_SANDBOX`, `CODE_EXECUTION_IMAGE`, `CODE_EXECUTION_TIMEOUT_SECS`)
+#[derive(Debug, Clone)]
+pub struct CodeExecutionConfig {
+    pub sandbox: Sandbox,
+    pub image: String,
+    pub timeout: Duration,
+}
+
+impl CodeExecutionConfig {
+    fn command(&self, name: &str) -> Command {
+        let mut command = match self.sandbox {
+            Sandbox::Docker => {
+                let mut command = Command::new("docker");
+                command.args(["run", "--rm", "-i", "--name", name, "--network", "none", "--memory", "512m", "--cpus", "1"]);
+                command.args(["--pids-limit", "128", "--read-only", "--tmpfs", "/tmp", "-w", "/tmp", &self.image, "python3", "-"]);
+                command
+            }
+            Sandbox::Firejail => {
+                let mut command = Command::new("firejail");
+                command.args(["--quiet", "--net=none", "--private", "--noroot", "--rlimit-as=1073741824", "python3", "-"]);
+                command
+            }
+        };
+        for var in ACTIVATION_VARS {
+            command.env_remove(var);
+        }
+        command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true);
+        command
+    }
+
+    /// Run Python `code`: `(stdout, stderr, return_code)`
+    async fn run(&self, id: &str, code: &str) -> Result<(String, String, i32), &'static str> {
+        let name = container_name(id);
+        let mut child = self.command(&name).spawn().map_err(|e| {
+            log::warn!("⚠️  Code execution sandbox ({:?}) failed to start: {}", self.sandbox, e);
+            "unavailable"
+        })?;
+        let (stdin, stdout, stderr) = (child.stdin.take(), child.stdout.take(), child.stderr.take());
+        // Writing the code counts against the timeout too: a sandbox that never reads stdin can't hang the request
+        let execution = async {
+            let write = async move {
+                if let Some(mut stdin) = stdin {
+                    let _ = stdin.write_all(code.as_bytes()).await;
+                }
+            };
+            let ((), stdout, stderr, status) = tokio::join!(write, read_capped(stdout), read_capped(stderr), child.wait());
+            status.map(|status| (stdout, stderr, status))
+        };
+        match tokio::time::timeout(self.timeout, execution).await {
+            Ok(Ok((stdout, stderr, status))) => Ok((truncate_output(&stdout), truncate_output(&stderr), status.code().unwrap_or(-1))),
+            Ok(Err(e)) => {
+                log::warn!("⚠️  Code execution failed: {}", e);
+                Err("unavailable")
+            }
+            Err(_) => {
+                log::warn!("⏱️  Code execution timed out after {:?}", self.timeout);
+                // Killing the docker client leaves the container running
+                if self.sandbox == Sandbox::Docker {
+                    let _ = Command::new("docker").args(["kill", &name]).output().await;
+                }
+                Err("code_execution_exceeded")
+            }
+        }
+    }
+}
+
+/// Container name for tool call `id`; call ids repeat across requests (`call_<round>_<index>`
+/// when the backend sends none), so a per-process counter keeps concurrent runs apart
+fn container_name(id: &str) -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let id = id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "-");
+    format!("claude-proxy-{}-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed), id)
+}
+
+/// Up to one byte past `CODE_EXECUTION_MAX_OUTPUT_BYTES` of a sandbox pipe (enough to mark it
+/// truncated); the rest is read and discarded so the sandbox doesn't block on a full pipe
+async fn read_capped(pipe: Option<impl AsyncRead + Unpin>) -> Vec<u8> {
+    let Some(mut pipe) = pipe else { return Vec::new() };
+    let mut out = Vec::new();
+    let _ = (&mut pipe).take(CODE_EXECUTION_MAX_OUTPUT_BYTES as u64 + 1).read_to_end(&mut out).await;
+    let _ = tokio::io::copy(&mut pipe, &mut tokio::io::sink()).await;
+    out
+}
+
+/// Sandbox output as text, cut at `CODE_EXECUTION_MAX_OUTPUT_BYTES`
+fn truncate_output(bytes: &[u8]) -> String {
+    let mut text = String::from_utf8_lossy(&bytes[..bytes.len().min(CODE_EXECUTION_MAX_OUTPUT_BYTES)]).into_owned();
+    if bytes.len() > CODE_EXECUTION_MAX_OUTPUT_BYTES {
+        text.push_str("\n[output truncated]");
+    }
+    text
+}
+
+/// Anthropic server tools the proxy executes itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServerToolKind {
+    WebSearch,
+    CodeExecution,
+}
+
+impl ServerToolKind {
+    /// Kind of a versioned server tool type such as `web_search_20250305`
+    fn from_type(type_: &str) -> Option<Self> {
+        if type_.starts_with("web_search_") {
+            Some(ServerToolKind::WebSearch)
+        } else if type_.starts_with("code_execution_") {
+            Some(ServerToolKind::CodeExecution)
+        } else {
+            None
+        }
+    }
+
+    /// Variable that enables the executor
+    fn env_var(&self) -> &'static str {
+        match self {
+            ServerToolKind::WebSearch => "WEB_SEARCH_URL",
+            ServerToolKind::CodeExecution => "CODE_EXECUTION_SANDBOX",
+        }
+    }
+
+    fn available(&self, config: &ServerToolConfig) -> bool {
+        match self {
+            ServerToolKind::WebSearch => config.web_search.is_some(),
+            ServerToolKind::CodeExecution => config.code_execution.is_some(),
+        }
+    }
+
+    /// Function definition the backend sees in place of the server tool
+    fn function_tool(&self, name: &str, cache_control: Option<Value>) -> ClaudeTool {
+        let (description, input_schema) = match self {
+            ServerToolKind::WebSearch => (
+                "Search the web for current information. Returns the title, URL and a snippet of each result.",
+                json!({
+                    "type": "object",
+                    "properties": { "query": { "type": "string", "description": "Search query" } },
+                    "required": ["query"]
+                }),
+            ),
+            ServerToolKind::CodeExecution => (
+                "Run Python code in a sandbox without network access. Returns stdout, stderr and the return code.",
+                json!({
+                    "type": "object",
+                    "properties": { "code": { "type": "string", "description": "Python code to run" } },
+                    "required": ["code"]
+                }),
+            ),
+        };
+        ClaudeTool {
+            type_: None,
+            name: name.to_string(),
+            description: Some(description.into()),
+            input_schema,
+            max_uses: None,
+            allowed_domains: None,
+            blocked_domains: None,
+            cache_control,
+        }
+    }
+}
+
+/// A server tool declared in the request's `tools`
+#[derive(Debug, Clone)]
+pub struct ServerToolSpec {
+    pub kind: ServerToolKind,
+    pub name: String,
+    max_uses: Option<u32>,
+    allowed_domains: Vec<String>,
+    blocked_domains: Vec<String>,
+}
+
+/// Replace the server tools in `tools` with function definitions the backend can call.
+///
+/// Server tools without a configured executor are dropped with a warning rather than forwarded,
+/// since OpenAI-style backends reject tools without a schema.
+pub fn prepare_server_tools(tools: &mut Option<Vec<ClaudeTool>>, config: &ServerToolConfig) -> Vec<ServerToolSpec> {
+    let mut specs = Vec::new();
+    let Some(list) = tools.as_mut() else {
+        return specs;
+    };
+    for tool in std::mem::take(list) {
+        let Some(type_) = tool.type_.as_deref().filter(|t| *t != "custom") else {
+            list.push(tool);
+            continue;
+        };
+        let kind = match ServerToolKind::from_type(type_) {
+            Some(kind) if kind.available(config) => kind,
+            Some(kind) => {
+                log::warn!("⚠️  Dropping server tool '{}' ({}): {} is not set", tool.name, type_, kind.env_var());
+                continue;
+            }
+            None => {
+                log::warn!("⚠️  Dropping unsupported server tool '{}' ({})", tool.name, type_);
+                continue;
+            }
+        };
+        list.push(kind.function_tool(&tool.name, tool.cache_control.clone()));
+        specs.push(ServerToolSpec {
+            kind,
+            name: tool.name,
+            max_uses: tool.max_uses,
+            allowed_domains: tool.allowed_domains.unwrap_or_default(),
+            blocked_domains: tool.blocked_domains.unwrap_or_default(),
+        });
+    }
+    specs
+}
+
+#[derive(Debug, Default)]
+struct PendingCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+/// One executed server tool call
+pub struct ServerToolOutput {
+    /// `server_tool_use` block, streamed with its input as one `input_json_delta`
+    pub id: String,
+    pub name: String,
+    pub input: Value,
+    /// Result block (`web_search_tool_result`, `code_execution_tool_result`), sent whole in `content_block_start`
+    pub result_block: Value,
+    backend_id: String,
+    arguments: String,
+    result_text: String,
+}
+
+/// Server tool calls of one request.
+///
+/// Calls to server tools are held back from the client while the backend streams them. Once the
+/// backend finishes its turn the proxy runs them, the client receives `server_tool_use` and result
+/// blocks, and a follow-up request with the results continues the same message.
+pub struct ServerToolSession {
+    tools: Vec<ServerToolSpec>,
+    config: ServerToolConfig,
+    client: reqwest::Client,
+    base: OAIChatReq,
+    calls: BTreeMap<usize, PendingCall>,
+    uses: BTreeMap<String, u32>,
+    text: String,
+    /// Follow-up requests sent so far
+    pub rounds: u32,
+    /// Searches run, reported as `usage.server_tool_use.web_search_requests`
+    pub web_search_requests: u32,
+    /// Sandbox runs, reported as `usage.server_tool_use.code_execution_requests`
+    pub code_execution_requests: u32,
+}
+
+impl ServerToolSession {
+    /// `None` when the request declares no executable server tools
+    pub fn new(tools: Vec<ServerToolSpec>, config: ServerToolConfig, client: reqwest::Client, base: &OAIChatReq) -> Option<Self> {
+        (!tools.is_empty()).then(|| Self {
+            tools,
+            config,
+            client,
+            base: base.clone(),
+            calls: BTreeMap::new(),
+            uses: BTreeMap::new(),
+            text: String::new(),
+            rounds: 0,
+            web_search_requests: 0,
+            code_execution_requests: 0,
+        })
+    }
+
+    /// Take a tool call delta if it belongs to a server tool call; `false` leaves it to the client
+    pub fn push_call_delta(&mut self, index: usize, id: Option<&str>, name: Option<&str>, arguments: Option<&str>) -> bool {
+        if !self.calls.contains_key(&index) {
+            match name {
+                Some(name) if self.tools.iter().any(|t| t.name == name) => {
+                    self.calls.insert(index, PendingCall { name: name.to_string(), ..Default::default() });
+                }
+                _ => return false,
+            }
+        }
+        let call = self.calls.get_mut(&index).expect("inserted above");
+        if let Some(id) = id {
+            call.id = id.to_string();
+        }
+        call.arguments.push_str(arguments.unwrap_or_default());
+        true
+    }
+
+    /// Take a whole call parsed from emulated output (`TOOL_EMULATION`) if it is a server tool call
+    pub fn push_call(&mut self, id: &str, name: &str, input: &Value) -> bool {
+        let index = self.calls.keys().next_back().map_or(0, |last| last + 1);
+        self.push_call_delta(index, Some(id), Some(name), Some(&input.to_string()))
+    }
+
+    /// Record answer text of the current round, replayed in the follow-up request
+    pub fn push_text(&mut self, text: &str) {
+        self.text.push_str(text);
+    }
+
+    pub fn has_calls(&self) -> bool {
+        !self.calls.is_empty()
+    }
+
+    /// Run the server tool calls of the finished round
+    pub async fn execute(&mut self) -> Vec<ServerToolOutput> {
+        let mut outputs = Vec::new();
+        for (index, call) in std::mem::take(&mut self.calls) {
+            let backend_id = if call.id.is_empty() { format!("call_{}_{}", self.rounds, index) } else { call.id };
+            let id = format!("srvtoolu_{}", backend_id.trim_start_matches("srvtoolu_"));
+            let input: Value = serde_json::from_str(&call.arguments).unwrap_or_else(|_| json!({}));
+            let spec = self.tools.iter().find(|t| t.name == call.name).expect("only server tool calls are buffered").clone();
+            let uses = self.uses.entry(spec.name.clone()).or_default();
+            *uses += 1;
+            let content = if spec.max_uses.is_some_and(|max| *uses > max) {
+                log::warn!("⚠️  Server tool '{}' called more than max_uses={} times", spec.name, spec.max_uses.unwrap_or_default());
+                Err("max_uses_exceeded")
+            } else {
+                match spec.kind {
+                    ServerToolKind::WebSearch => self.web_search(&spec, &input).await,
+                    ServerToolKind::CodeExecution => self.code_execution(&id, &input).await,
+                }
+            };
+            let (block_type, error_type) = match spec.kind {
+                ServerToolKind::WebSearch => ("web_search_tool_result", "web_search_tool_result_error"),
+                ServerToolKind::CodeExecution => ("code_execution_tool_result", "code_execution_tool_result_error"),
+            };
+            let content = content.unwrap_or_else(|code| json!({ "type": error_type, "error_code": code }));
+            let result_text = server_tool_result_text(&content);
+            let result_block = json!({ "type": block_type, "tool_use_id": id, "content": content });
+            outputs.push(ServerToolOutput {
+                id,
+                name: spec.name,
+                input,
+                result_block,
+                backend_id,
+                arguments: call.arguments,
+                result_text,
+            });
+        }
+        outputs
+    }
+
+    /// Request continuing the turn with the results of `outputs`, or `None` after `MAX_SERVER_TOOL_ROUNDS`
+    pub fn next_request(&mut self, outputs: &[ServerToolOutput]) -> Option<OAIChatReq> {
+        if self.rounds >= MAX_SERVER_TOOL_ROUNDS {
+            return None;
+        }
+        self.rounds += 1;
+        let tool_calls = outputs
+            .iter()
+            .map(|o| json!({ "id": o.backend_id, "type": "function", "function": { "name": o.name, "arguments": o.arguments } }))
+            .collect();
+        self.base.messages.push(OAIMessage {
+            role: "assistant".into(),
+            content: Value::String(std::mem::take(&mut self.text)),
+            tool_call_id: None,
+            tool_calls: Some(tool_calls),
+            prefix: None,
+        });
+        for output in outputs {
+            self.base.messages.push(OAIMessage {
+                role: "tool".into(),
+                content: Value::String(output.result_text.clone()),
+                tool_call_id: Some(output.backend_id.clone()),
+                tool_calls: None,
+                prefix: None,
+            });
+        }
+        Some(self.base.clone())
+    }
+
+    async fn web_search(&mut self, spec: &ServerToolSpec, input: &Value) -> Result<Value, &'static str> {
+        let config = self.config.web_search.as_ref().ok_or("unavailable")?;
+        let query = input["query"].as_str().map(str::trim).filter(|q| !q.is_empty()).ok_or("invalid_tool_input")?;
+        self.web_search_requests += 1;
+        log::info!("🔎 Web search: {:?}", query);
+        let response = self
+            .client
+            .get(&config.url)
+            .query(&[("q", query), ("format", "json")])
+            .timeout(Duration::from_secs(WEB_SEARCH_TIMEOUT_SECS))
+            .send()
+            .await
+            .map_err(|e| {
+                log::warn!("⚠️  Web search request failed: {}", e);
+                "unavailable"
+            })?;
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err("too_many_requests");
+        }
+        let body: Value = match response.error_for_status() {
+            Ok(response) => response.json().await.map_err(|_| "unavailable")?,
+            Err(e) => {
+                log::warn!("⚠️  Web search failed: {}", e);
+                return Err("unavailable");
+            }
+        };
+        let results: Vec<Value> = body["results"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|r| r["url"].as_str().is_some_and(|url| domain_allowed(url, &spec.allowed_domains, &spec.blocked_domains)))
+            .take(config.max_results)
+            .map(|r| {
+                let url = r["url"].as_str().unwrap_or_default();
+                json!({
+                    "type": "web_search_result",
+                    "url": url,
+                    "title": r["title"].as_str().unwrap_or(url),
+                    // Opaque to clients; carries the snippet so the result can be replayed from history
+                    "encrypted_content": STANDARD.encode(r["content"].as_str().unwrap_or_default()),
+                    "page_age": r["publishedDate"].as_str(),
+                })
+            })
+            .collect();
+        log::debug!("🔎 Web search returned {} result(s)", results.len());
+        Ok(Value::Array(results))
+    }
+
+    async fn code_execution(&mut self, id: &str, input: &Value) -> Result<Value, &'static str> {
+        let config = self.config.code_execution.as_ref().ok_or("unavailable")?;
+        let code = input["code"].as_str().filter(|c| !c.trim().is_empty()).ok_or("invalid_tool_input")?;
+        self.code_execution_requests += 1;
+        log::info!("🐍 Running {} bytes of code ({:?} sandbox)", code.len(), config.sandbox);
+        let (stdout, stderr, return_code) = config.run(id, code).await?;
+        log::debug!("🐍 Code execution finished with return code {}", return_code);
+        Ok(json!({ "type": "code_execution_result", "stdout": stdout, "stderr": stderr, "return_code": return_code, "content": [] }))
+    }
+}
+
+/// Host of `url` matches `allowed_domains` (when set) and none of `blocked_domains`
+fn domain_allowed(url: &str, allowed: &[String], blocked: &[String]) -> bool {
+    let host = url.split("://").nth(1).unwrap_or(url).split(['/', '?', '#']).next().unwrap_or_default();
+    let host = host.rsplit('@').next().unwrap_or(host).split(':').next().unwrap_or(host).to_ascii_lowercase();
+    let matches = |domain: &String| {
+        let domain = domain.trim().trim_start_matches("*.").to_ascii_lowercase();
+        host == domain || host.ends_with(&format!(".{}", domain))
+    };
+    (allowed.is_empty() || allowed.iter().any(matches)) && !blocked.iter().any(matches)
+}
+
+/// Text the model sees for the content of a server tool result block
+pub fn server_tool_result_text(content: &Value) -> String {
+    if let Some(results) = content.as_array() {
+        return web_search_results_text(results);
+    }
+    let error = content["error_code"].as_str().unwrap_or("unavailable");
+    match content["type"].as_str() {
+        Some("code_execution_result" | "bash_code_execution_result") => {
+            let mut text = format!("Return code: {}", content["return_code"].as_i64().unwrap_or_default());
+            for stream in ["stdout", "stderr"] {
+                if let Some(output) = content[stream].as_str().filter(|o| !o.is_empty()) {
+                    text.push_str(&format!("\n{}:\n{}", stream, output));
+                }
+            }
+            text
+        }
+        Some("text_editor_code_execution_result") => match &content["content"] {
+            Value::String(file) => file.clone(),
+            // Create and edit results describe the change rather than the file
+            _ => serde_json::to_string(content).unwrap_or_default(),
+        },
+        Some("code_execution_tool_result_error" | "bash_code_execution_tool_result_error" | "text_editor_code_execution_tool_result_error") => {
+            format!("Code execution failed: {}", error)
+        }
+        _ => format!("Web search failed: {}", error),
+    }
+}
+
+/// Text standing in for a `container_upload` block, since the proxy has no containers to upload to
+pub fn container_upload_text(file_id: &str) -> String {
+    format!("[File {} was attached for code execution, but it is not available in this proxy's sandbox]", file_id)
+}
+
+/// Warning block text for code execution container features the proxy can't emulate: reusing a
+/// `container` across requests and `container_upload` files
+pub fn container_notice(container: Option<&Value>, uploads: usize) -> Option<String> {
+    let mut problems = Vec::new();
+    if let Some(container) = container {
+        match container.as_str().or_else(|| container["id"].as_str()) {
+            Some(id) => problems.push(format!("container '{}' was not reused", id)),
+            None => problems.push("the requested container was not created".to_string()),
+        }
+        if container["skills"].as_array().is_some_and(|skills| !skills.is_empty()) {
+            problems.push("container skills are not loaded".to_string());
+        }
+    }
+    if uploads > 0 {
+        problems.push(format!("{} uploaded file(s) are not available to code", uploads));
+    }
+    if problems.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "[proxy warning: code_execution_container] This proxy doesn't support code execution containers: {}. Code runs in a fresh sandbox on every call.",
+        problems.join(", ")
+    ))
+}
+
+fn web_search_results_text(results: &[Value]) -> String {
+    if results.is_empty() {
+        return "No results found.".into();
+    }
+    results
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            let snippet = r["encrypted_content"]
+                .as_str()
+                .and_then(|c| STANDARD.decode(c).ok())
+                .and_then(|c| String::from_utf8(c).ok())
+                .unwrap_or_default();
+            let mut text = format!("[{}] {}\nURL: {}", i + 1, r["title"].as_str().unwrap_or_default(), r["url"].as_str().unwrap_or_default());
+            if !snippet.is_empty() {
+                text.push('\n');
+                text.push_str(&snippet);
+            }
+            text
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tools(json: Value) -> Option<Vec<ClaudeTool>> {
+        Some(serde_json::from_value(json).unwrap())
+    }
+
+    #[test]
+    fn test_prepare_server_tools() {
+        let config = ServerToolConfig {
+            web_search: Some(WebSearchConfig { url: "http://search.local/search".into(), max_results: 5 }),
+            code_execution: None,
+        };
+        let declared = json!([
+            {"name": "read_file", "input_schema": {"type": "object"}},
+            {"type": "web_search_20250305", "name": "web_search", "max_uses": 2, "allowed_domains": ["docs.rs"]},
+            {"type": "code_execution_20250522", "name": "code_execution"},
+            {"type": "bash_20250124", "name": "bash"}
+        ]);
+
+        let mut list = tools(declared.clone());
+        let specs = prepare_server_tools(&mut list, &config);
+        let names: Vec<&str> = list.as_ref().unwrap().iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["read_file", "web_search"]);
+        assert_eq!(list.unwrap()[1].input_schema["required"], json!(["query"]));
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].max_uses, Some(2));
+
+        let mut list = tools(declared);
+        assert!(prepare_server_tools(&mut list, &ServerToolConfig::default()).is_empty());
+        assert_eq!(list.unwrap().len(), 1, "server tools without an executor are dropped");
+    }
+
+    #[test]
+    fn test_session_buffers_server_calls_only() {
+        let specs = vec![ServerToolSpec {
+            kind: ServerToolKind::WebSearch,
+            name: "web_search".into(),
+            max_uses: None,
+            allowed_domains: Vec::new(),
+            blocked_domains: Vec::new(),
+        }];
+        let mut session = ServerToolSession::new(specs, ServerToolConfig::default(), reqwest::Client::new(), &OAIChatReq::default()).unwrap();
+        assert!(!session.push_call_delta(0, Some("call_a"), Some("read_file"), Some("{}")));
+        assert!(session.push_call_delta(1, Some("call_b"), Some("web_search"), Some("{\"query\":")));
+        assert!(session.push_call_delta(1, None, None, Some("\"rust\"}")));
+        assert!(!session.push_call_delta(0, None, None, Some("more")));
+        assert!(!session.push_call("toolu_m_1", "read_file", &json!({"path": "a.rs"})));
+        assert!(session.push_call("toolu_m_2", "web_search", &json!({"query": "tokio"})));
+        assert_eq!(session.calls.len(), 2, "appended after the native call");
+        assert!(session.has_calls());
+    }
+
+    #[tokio::test]
+    async fn test_sandbox_output_is_capped_and_names_unique() {
+        let output = vec![b'x'; CODE_EXECUTION_MAX_OUTPUT_BYTES * 3];
+        let read = read_capped(Some(output.as_slice())).await;
+        assert_eq!(read.len(), CODE_EXECUTION_MAX_OUTPUT_BYTES + 1);
+        assert!(truncate_output(&read).ends_with("[output truncated]"));
+        assert!(read_capped(None::<&[u8]>).await.is_empty());
+
+        assert_ne!(container_name("call_0_0"), container_name("call_0_0"));
+        assert!(container_name("toolu/1").ends_with("-toolu-1"));
+    }
+
+    #[test]
+    fn test_domain_filters() {
+        let allowed = vec!["docs.rs".to_string()];
+        assert!(domain_allowed("https://docs.rs/serde", &allowed, &[]));
+        assert!(domain_allowed("https://www.docs.rs:443/x", &allowed, &[]));
+        assert!(!domain_allowed("https://notdocs.rs/", &allowed, &[]));
+        assert!(!domain_allowed("https://spam.example.com/", &[], &["example.com".to_string()]));
+    }
+
+    #[test]
+    fn test_server_tool_result_text() {
+        let content = json!([{
+            "type": "web_search_result",
+            "url": "https://www.rust-lang.org",
+            "title": "Rust",
+            "encrypted_content": STANDARD.encode("A language empowering everyone"),
+            "page_age": null
+        }]);
+        assert_eq!(server_tool_result_text(&content), "[1] Rust\nURL: https://www.rust-lang.org\nA language empowering everyone");
+        let error = json!({"type": "web_search_tool_result_error", "error_code": "max_uses_exceeded"});
+        assert_eq!(server_tool_result_text(&error), "Web search failed: max_uses_exceeded");
+        let run = json!({"type": "code_execution_result", "stdout": "4\n", "stderr": "", "return_code": 0, "content": []});
+        assert_eq!(server_tool_result_text(&run), "Return code: 0\nstdout:\n4\n");
+        let error = json!({"type": "code_execution_tool_result_error", "error_code": "code_execution_exceeded"});
+        assert_eq!(server_tool_result_text(&error), "Code execution failed: code_execution_exceeded");
+        let bash = json!({"type": "bash_code_execution_result", "stdout": "", "stderr": "ls: x: No such file", "return_code": 2});
+        assert_eq!(server_tool_result_text(&bash), "Return code: 2\nstderr:\nls: x: No such file");
+        let view = json!({"type": "text_editor_code_execution_result", "file_type": "text", "content": "print(1)\n"});
+        assert_eq!(server_tool_result_text(&view), "print(1)\n");
+    }
+
+    #[test]
+    fn test_container_notice() {
+        assert_eq!(container_notice(None, 0), None);
+        let notice = container_notice(Some(&json!("container_011")), 2).unwrap();
+        assert!(notice.starts_with("[proxy warning: code_execution_container]"));
+        assert!(notice.contains("container 'container_011' was not reused, 2 uploaded file(s) are not available"));
+        let skills = json!({"skills": [{"type": "anthropic", "skill_id": "xlsx"}]});
+        assert!(container_notice(Some(&skills), 0).unwrap().contains("was not created, container skills are not loaded"));
+    }
+}
@@ -0,0 +1,166 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::config::{env_or, env_parse};
+use crate::constants::{DEFAULT_SHADOW_MAX_CONCURRENT, DEFAULT_SHADOW_PERCENT, SHADOW_ERROR_PREVIEW_CHARS};
+use crate::models::BackendRegistry;
+use crate::services::chaos::chance;
+
+/// Latency and failures of one side of the shadowed requests
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ShadowSide {
+    pub requests: u64,
+    /// Connection failures and non-2xx responses
+    pub errors: u64,
+    /// Mean time to response headers over successful requests
+    pub avg_headers_ms: u64,
+    pub max_headers_ms: u64,
+    #[serde(skip)]
+    headers_ms_total: u64,
+}
+
+impl ShadowSide {
+    fn record(&mut self, headers: Option<Duration>) {
+        self.requests += 1;
+        let Some(headers) = headers else {
+            self.errors += 1;
+            return;
+        };
+        let ms = headers.as_millis() as u64;
+        self.headers_ms_total += ms;
+        self.max_headers_ms = self.max_headers_ms.max(ms);
+        self.avg_headers_ms = self.headers_ms_total / (self.requests - self.errors).max(1);
+    }
+}
+
+/// Shadowed traffic in `/admin/stats`: the production backend and the shadow backend on the same requests
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ShadowSnapshot {
+    pub backend: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub percent: f64,
+    /// Sampled requests not mirrored because `SHADOW_MAX_CONCURRENT` shadow requests were in flight
+    pub skipped: u64,
+    pub primary: ShadowSide,
+    pub shadow: ShadowSide,
+    /// Mean duration of complete shadow responses, body included
+    pub shadow_avg_total_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_shadow_error: Option<String>,
+    #[serde(skip)]
+    shadow_total_ms: u64,
+}
+
+/// Mirrors a share of `/v1/messages` requests to a second backend (`SHADOW_BACKEND`); its
+/// responses are read and discarded, only their timing and status are kept
+pub struct Shadow {
+    /// Named backend from `BACKENDS`
+    pub backend: String,
+    /// Model sent to the shadow backend (`SHADOW_MODEL`); the client's model when unset
+    pub model: Option<String>,
+    /// Bearer token for shadow requests (`SHADOW_API_KEY`); client keys are never sent
+    pub api_key: String,
+    rate: f64,
+    /// Shadow requests in flight (`SHADOW_MAX_CONCURRENT`)
+    permits: Arc<Semaphore>,
+    snapshot: Mutex<ShadowSnapshot>,
+}
+
+impl Shadow {
+    pub fn new(backend: String, model: Option<String>, api_key: String, percent: f64, max_concurrent: usize) -> Self {
+        let percent = percent.clamp(0.0, 100.0);
+        let snapshot = ShadowSnapshot { backend: backend.clone(), model: model.clone(), percent, ..Default::default() };
+        Self { backend, model, api_key, rate: percent / 100.0, permits: Arc::new(Semaphore::new(max_concurrent)), snapshot: Mutex::new(snapshot) }
+    }
+
+    /// `SHADOW_BACKEND` (a `BACKENDS` name) with `SHADOW_API_KEY`, `SHADOW_PERCENT`, `SHADOW_MODEL` and
+    /// `SHADOW_MAX_CONCURRENT`; `None` when unset, naming an unknown backend or without a key
+    pub fn from_env(backends: &BackendRegistry) -> Option<Self> {
+        let backend = std::env::var("SHADOW_BACKEND").ok().map(|b| b.trim().to_string()).filter(|b| !b.is_empty())?;
+        if backends.get(&backend).is_none() {
+            log::error!("❌ SHADOW_BACKEND '{}' is not a configured backend - shadowing disabled", backend);
+            return None;
+        }
+        let Some(api_key) = std::env::var("SHADOW_API_KEY").ok().filter(|k| !k.trim().is_empty()) else {
+            log::error!("❌ SHADOW_BACKEND is set without SHADOW_API_KEY - shadowing disabled");
+            return None;
+        };
+        let percent: f64 = env_or("SHADOW_PERCENT", DEFAULT_SHADOW_PERCENT);
+        let model = std::env::var("SHADOW_MODEL").ok().map(|m| m.trim().to_string()).filter(|m| !m.is_empty());
+        let max_concurrent = env_parse("SHADOW_MAX_CONCURRENT").filter(|&n| n > 0).unwrap_or(DEFAULT_SHADOW_MAX_CONCURRENT);
+        log::info!("👥 Shadowing {}% of requests to backend '{}' (at most {} at a time)", percent, backend, max_concurrent);
+        Some(Self::new(backend, model, api_key, percent, max_concurrent))
+    }
+
+    /// Whether to mirror the current request: a slot to hold for the shadow request, `None` when
+    /// it isn't sampled or `SHADOW_MAX_CONCURRENT` shadow requests are already in flight
+    pub fn sample(&self) -> Option<OwnedSemaphorePermit> {
+        if !chance(self.rate) {
+            return None;
+        }
+        let permit = self.permits.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            self.lock().skipped += 1;
+        }
+        permit
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ShadowSnapshot> {
+        self.snapshot.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Time to response headers of the production backend on a mirrored request; `None` when it failed
+    pub fn record_primary(&self, headers: Option<Duration>) {
+        self.lock().primary.record(headers);
+    }
+
+    /// Outcome of a shadow request: time to headers and to the end of the body, or the error
+    pub fn record_shadow(&self, outcome: Result<(Duration, Duration), String>) {
+        let mut snapshot = self.lock();
+        match outcome {
+            Ok((headers, total)) => {
+                snapshot.shadow.record(Some(headers));
+                snapshot.shadow_total_ms += total.as_millis() as u64;
+                let succeeded = (snapshot.shadow.requests - snapshot.shadow.errors).max(1);
+                snapshot.shadow_avg_total_ms = snapshot.shadow_total_ms / succeeded;
+            }
+            Err(e) => {
+                snapshot.shadow.record(None);
+                snapshot.last_shadow_error = Some(e.chars().take(SHADOW_ERROR_PREVIEW_CHARS).collect());
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> ShadowSnapshot {
+        self.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_compares_both_sides() {
+        let shadow = Shadow::new("candidate".into(), None, "key".into(), 250.0, 1);
+        let permit = shadow.sample();
+        assert!(permit.is_some(), "percent is capped at 100");
+        assert!(shadow.sample().is_none(), "one shadow request at a time");
+        drop(permit);
+        assert!(shadow.sample().is_some());
+        shadow.record_primary(Some(Duration::from_millis(100)));
+        shadow.record_primary(Some(Duration::from_millis(300)));
+        shadow.record_shadow(Ok((Duration::from_millis(50), Duration::from_millis(900))));
+        shadow.record_shadow(Err("502 Bad Gateway".into()));
+
+        let snapshot = shadow.snapshot();
+        assert_eq!(snapshot.percent, 100.0);
+        assert_eq!(snapshot.skipped, 1);
+        assert_eq!((snapshot.primary.requests, snapshot.primary.errors, snapshot.primary.avg_headers_ms), (2, 0, 200));
+        assert_eq!((snapshot.shadow.requests, snapshot.shadow.errors, snapshot.shadow.avg_headers_ms), (2, 1, 50));
+        assert_eq!(snapshot.shadow_avg_total_ms, 900);
+        assert_eq!(snapshot.last_shadow_error.as_deref(), Some("502 Bad Gateway"));
+        assert!(Shadow::new("candidate".into(), None, "key".into(), 0.0, 1).sample().is_none());
+    }
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use crate::constants::*;
+use crate::services::transform::{CompletionSummary, StreamErrorKind, Transform, TransformContext};
+use crate::services::BackendTimeout;
+
+struct InFlight {
+    model: String,
+    client: String,
+    started: Instant,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorEntry {
+    pub ts_ms: u64,
+    pub request_id: String,
+    pub model: String,
+    /// HTTP status from the backend, when the error wasn't raised mid-stream
+    pub status: Option<u16>,
+    pub message: String,
+    /// Diagnostic headers of the failed backend response
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub backend_headers: BTreeMap<String, String>,
+}
+
+/// Diagnostic headers of one backend response, looked up by `/debug/requests/:id`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct BackendResponseEntry {
+    pub ts_ms: u64,
+    pub request_id: String,
+    pub backend: String,
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ModelTraffic {
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InFlightEntry {
+    pub request_id: String,
+    pub model: String,
+    pub client: String,
+    pub elapsed_ms: u64,
+}
+
+/// Point-in-time view of live traffic for the dashboard
+#[derive(Debug, Serialize)]
+pub struct StatsSnapshot {
+    pub uptime_secs: u64,
+    pub in_flight: Vec<InFlightEntry>,
+    pub recent_errors: Vec<ErrorEntry>,
+    pub models: BTreeMap<String, ModelTraffic>,
+    /// Completed requests and output tokens per second over the last `STATS_THROUGHPUT_WINDOW_SECS`
+    pub requests_per_min: u64,
+    pub output_tokens_per_sec: f64,
+    /// Mid-stream failures per backend and kind
+    pub stream_errors: BTreeMap<String, BTreeMap<StreamErrorKind, u64>>,
+    /// Expired connect, first-token and total deadlines per backend
+    pub timeouts: BTreeMap<String, BTreeMap<BackendTimeout, u64>>,
+}
+
+#[derive(Default)]
+struct StatsInner {
+    in_flight: HashMap<String, InFlight>,
+    recent_errors: VecDeque<ErrorEntry>,
+    recent_responses: VecDeque<BackendResponseEntry>,
+    models: BTreeMap<String, ModelTraffic>,
+    completions: VecDeque<(Instant, u32)>,
+    stream_errors: BTreeMap<String, BTreeMap<StreamErrorKind, u64>>,
+    timeouts: BTreeMap<String, BTreeMap<BackendTimeout, u64>>,
+}
+
+impl StatsInner {
+    fn push_error(&mut self, mut entry: ErrorEntry) {
+        if let Some(response) = self.recent_responses.iter().rev().find(|r| r.request_id == entry.request_id) {
+            entry.backend_headers = response.headers.clone();
+        }
+        if self.recent_errors.len() == STATS_RECENT_ERRORS {
+            self.recent_errors.pop_front();
+        }
+        self.recent_errors.push_back(entry);
+    }
+
+    fn prune_completions(&mut self, now: Instant) {
+        let window = Duration::from_secs(STATS_THROUGHPUT_WINDOW_SECS);
+        while self.completions.front().is_some_and(|(t, _)| now.duration_since(*t) > window) {
+            self.completions.pop_front();
+        }
+    }
+}
+
+/// Marks a request, in `TransformContext::extensions`, whose backend error `Stats::record_error`
+/// already counted, so the synthetic response written for it isn't counted again on completion
+#[derive(Clone, Copy)]
+pub struct ErrorRecorded;
+
+/// In-memory traffic statistics: in-flight requests, per-model totals, recent errors and throughput
+pub struct Stats {
+    started: Instant,
+    inner: Mutex<StatsInner>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            inner: Mutex::new(StatsInner::default()),
+        }
+    }
+}
+
+/// Keeps a request listed as in flight until dropped
+pub struct InFlightGuard {
+    stats: Arc<Stats>,
+    request_id: String,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.stats.lock().in_flight.remove(&self.request_id);
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+impl Stats {
+    fn lock(&self) -> std::sync::MutexGuard<'_, StatsInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// Track a request as in flight for as long as the returned guard lives
+    pub fn begin(self: &Arc<Self>, request_id: &str, model: &str, client: &str) -> InFlightGuard {
+        self.lock().in_flight.insert(request_id.to_string(), InFlight {
+            model: model.to_string(),
+            client: client.to_string(),
+            started: Instant::now(),
+        });
+        InFlightGuard { stats: self.clone(), request_id: request_id.to_string() }
+    }
+
+    /// Record a request that failed before a response could be streamed
+    pub fn record_error(&self, request_id: &str, model: &str, status: Option<u16>, message: &str) {
+        let mut inner = self.lock();
+        let traffic = inner.models.entry(model.to_string()).or_default();
+        traffic.requests += 1;
+        traffic.errors += 1;
+        inner.push_error(ErrorEntry {
+            ts_ms: now_ms(),
+            request_id: request_id.to_string(),
+            model: model.to_string(),
+            status,
+            message: message.chars().take(STATS_ERROR_MESSAGE_CHARS).collect(),
+            backend_headers: BTreeMap::new(),
+        });
+    }
+
+    /// Count a request the proxy made on its own behalf, such as a compaction summary
+    pub fn record_usage(&self, model: &str, input_tokens: u64, output_tokens: u64) {
+        let mut inner = self.lock();
+        let traffic = inner.models.entry(model.to_string()).or_default();
+        traffic.requests += 1;
+        traffic.input_tokens += input_tokens;
+        traffic.output_tokens += output_tokens;
+    }
+
+    /// Keep the diagnostic headers of a backend response (several per request after route failover)
+    pub fn record_backend_response(&self, request_id: &str, backend: &str, status: u16, headers: BTreeMap<String, String>) {
+        let mut inner = self.lock();
+        if inner.recent_responses.len() == STATS_RECENT_RESPONSES {
+            inner.recent_responses.pop_front();
+        }
+        inner.recent_responses.push_back(BackendResponseEntry {
+            ts_ms: now_ms(),
+            request_id: request_id.to_string(),
+            backend: backend.to_string(),
+            status,
+            headers,
+        });
+    }
+
+    /// Backend responses of a recent request, oldest first
+    pub fn backend_responses(&self, request_id: &str) -> Vec<BackendResponseEntry> {
+        self.lock().recent_responses.iter().filter(|r| r.request_id == request_id).cloned().collect()
+    }
+
+    /// Count a backend's mid-stream failures
+    pub fn record_stream_errors(&self, backend: &str, errors: &BTreeMap<StreamErrorKind, u32>) {
+        if errors.is_empty() {
+            return;
+        }
+        let mut inner = self.lock();
+        let counts = inner.stream_errors.entry(backend.to_string()).or_default();
+        for (kind, count) in errors {
+            *counts.entry(*kind).or_default() += *count as u64;
+        }
+    }
+
+    /// Count an expired backend deadline
+    pub fn record_timeout(&self, backend: &str, timeout: BackendTimeout) {
+        *self.lock().timeouts.entry(backend.to_string()).or_default().entry(timeout).or_default() += 1;
+    }
+
+    fn record_completion(&self, request_id: &str, model: &str, summary: &CompletionSummary) {
+        let now = Instant::now();
+        let mut inner = self.lock();
+        let traffic = inner.models.entry(model.to_string()).or_default();
+        traffic.requests += 1;
+        traffic.input_tokens += summary.input_tokens as u64;
+        traffic.output_tokens += summary.output_tokens as u64;
+        if summary.fatal_error {
+            traffic.errors += 1;
+            inner.push_error(ErrorEntry {
+                ts_ms: now_ms(),
+                request_id: request_id.to_string(),
+                model: model.to_string(),
+                status: None,
+                message: format!("stream ended with stop_reason={}", summary.stop_reason),
+                backend_headers: BTreeMap::new(),
+            });
+        }
+        inner.completions.push_back((now, summary.output_tokens));
+        inner.prune_completions(now);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let now = Instant::now();
+        let mut inner = self.lock();
+        inner.prune_completions(now);
+        let mut in_flight: Vec<InFlightEntry> = inner
+            .in_flight
+            .iter()
+            .map(|(id, r)| InFlightEntry {
+                request_id: id.clone(),
+                model: r.model.clone(),
+                client: r.client.clone(),
+                elapsed_ms: now.duration_since(r.started).as_millis() as u64,
+            })
+            .collect();
+        in_flight.sort_by_key(|r| std::cmp::Reverse(r.elapsed_ms));
+        let window_secs = STATS_THROUGHPUT_WINDOW_SECS as f64;
+        let output_tokens: u64 = inner.completions.iter().map(|(_, t)| *t as u64).sum();
+        StatsSnapshot {
+            uptime_secs: self.uptime_secs(),
+            in_flight,
+            recent_errors: inner.recent_errors.iter().rev().cloned().collect(),
+            models: inner.models.clone(),
+            requests_per_min: (inner.completions.len() as f64 * 60.0 / window_secs).round() as u64,
+            output_tokens_per_sec: output_tokens as f64 / window_secs,
+            stream_errors: inner.stream_errors.clone(),
+            timeouts: inner.timeouts.clone(),
+        }
+    }
+}
+
+impl Transform for Stats {
+    fn name(&self) -> &str {
+        "stats"
+    }
+
+    fn handles_stream_events(&self) -> bool {
+        false
+    }
+
+    fn on_complete<'a>(
+        &'a self,
+        ctx: &'a mut TransformContext,
+        summary: &'a CompletionSummary,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if ctx.extensions.get::<ErrorRecorded>().is_none() {
+                self.record_completion(&ctx.request_id, &ctx.model, summary);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(output_tokens: u32, fatal_error: bool) -> CompletionSummary {
+        CompletionSummary {
+            stop_reason: if fatal_error { "error" } else { "end_turn" }.into(),
+            input_tokens: 100,
+            output_tokens,
+            fatal_error,
+            stream_errors: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_in_flight_guard_removes_on_drop() {
+        let stats = Arc::new(Stats::default());
+        let guard = stats.begin("msg_1", "qwen", "claude-code/1.0.0");
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.in_flight.len(), 1);
+        assert_eq!(snapshot.in_flight[0].model, "qwen");
+        drop(guard);
+        assert!(stats.snapshot().in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_completions_and_errors_aggregate_per_model() {
+        let stats = Stats::default();
+        stats.record_completion("msg_1", "qwen", &summary(30, false));
+        stats.record_completion("msg_2", "qwen", &summary(0, true));
+        stats.record_error("msg_3", "llama", Some(400), "bad request");
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.models["qwen"], ModelTraffic { requests: 2, errors: 1, input_tokens: 200, output_tokens: 30 });
+        assert_eq!(snapshot.models["llama"].errors, 1);
+        assert_eq!(snapshot.recent_errors.len(), 2);
+        assert_eq!(snapshot.recent_errors[0].status, Some(400));
+        assert!(snapshot.output_tokens_per_sec > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_recorded_error_is_counted_once() {
+        let stats = Stats::default();
+        stats.record_error("msg_1", "qwen", Some(400), "bad request");
+        let mut ctx = TransformContext::new("msg_1".into(), "qwen".into());
+        ctx.extensions.insert(ErrorRecorded);
+        stats.on_complete(&mut ctx, &summary(0, true)).await;
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.models["qwen"], ModelTraffic { requests: 1, errors: 1, input_tokens: 0, output_tokens: 0 });
+        assert_eq!(snapshot.recent_errors.len(), 1);
+    }
+
+    #[test]
+    fn test_stream_errors_count_per_backend() {
+        let stats = Stats::default();
+        stats.record_stream_errors("local", &BTreeMap::from([(StreamErrorKind::MalformedChunk, 2)]));
+        stats.record_stream_errors("local", &BTreeMap::from([(StreamErrorKind::MalformedChunk, 1), (StreamErrorKind::StallTimeout, 1)]));
+        stats.record_stream_errors("default", &BTreeMap::new());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.stream_errors.len(), 1);
+        assert_eq!(snapshot.stream_errors["local"], BTreeMap::from([(StreamErrorKind::MalformedChunk, 3), (StreamErrorKind::StallTimeout, 1)]));
+        assert_eq!(serde_json::to_value(&snapshot.stream_errors).unwrap()["local"]["stall_timeout"], 1);
+    }
+
+    #[test]
+    fn test_timeouts_count_per_backend() {
+        let stats = Stats::default();
+        stats.record_timeout("local", BackendTimeout::FirstToken);
+        stats.record_timeout("local", BackendTimeout::FirstToken);
+        stats.record_timeout("default", BackendTimeout::Connect);
+        let timeouts = serde_json::to_value(stats.snapshot().timeouts).unwrap();
+        assert_eq!(timeouts, serde_json::json!({"default": {"connect": 1}, "local": {"first_token": 2}}));
+    }
+
+    #[test]
+    fn test_errors_carry_backend_headers() {
+        let stats = Stats::default();
+        let headers = BTreeMap::from([("x-request-id".to_string(), "req_9".to_string())]);
+        stats.record_backend_response("msg_1", "cheap", 503, BTreeMap::new());
+        stats.record_backend_response("msg_1", "default", 429, headers.clone());
+        stats.record_error("msg_1", "glm", Some(429), "rate limited");
+        assert_eq!(stats.snapshot().recent_errors[0].backend_headers, headers);
+        let backends: Vec<String> = stats.backend_responses("msg_1").into_iter().map(|r| r.backend).collect();
+        assert_eq!(backends, ["cheap", "default"]);
+        assert!(stats.backend_responses("msg_2").is_empty());
+    }
+
+    #[test]
+    fn test_recent_errors_are_capped() {
+        let stats = Stats::default();
+        for i in 0..STATS_RECENT_ERRORS + 5 {
+            stats.record_error(&format!("msg_{}", i), "m", Some(500), "boom");
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.recent_errors.len(), STATS_RECENT_ERRORS);
+        assert_eq!(snapshot.recent_errors[0].request_id, format!("msg_{}", STATS_RECENT_ERRORS + 4));
+    }
+}
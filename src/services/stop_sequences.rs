@@ -0,0 +1,113 @@
+/// Proxy-side `stop_sequences` enforcement for backends that ignore the `stop` array.
+///
+/// Text that could be the start of a stop sequence is held back until the next delta
+/// shows whether it matches, so a sequence split across deltas is still caught and never
+/// reaches the client.
+pub struct StopSequenceScanner {
+    stops: Vec<String>,
+    pending: String,
+}
+
+impl StopSequenceScanner {
+    /// `None` when there are no non-empty stop sequences to enforce
+    pub fn new(stops: &[String]) -> Option<Self> {
+        let stops: Vec<String> = stops.iter().filter(|s| !s.is_empty()).cloned().collect();
+        if stops.is_empty() {
+            return None;
+        }
+        Some(Self {
+            stops,
+            pending: String::new(),
+        })
+    }
+
+    /// Scan the next text delta; returns the text safe to emit and the matched stop sequence, if any
+    pub fn push(&mut self, text: &str) -> (String, Option<String>) {
+        let mut buf = std::mem::take(&mut self.pending);
+        buf.push_str(text);
+
+        let earliest = self
+            .stops
+            .iter()
+            .filter_map(|stop| buf.find(stop.as_str()).map(|pos| (pos, stop)))
+            .min_by_key(|(pos, _)| *pos);
+        if let Some((pos, stop)) = earliest {
+            let stop = stop.clone();
+            buf.truncate(pos);
+            return (buf, Some(stop));
+        }
+
+        let hold = self.holdback_len(&buf);
+        self.pending = buf.split_off(buf.len() - hold);
+        (buf, None)
+    }
+
+    /// Release any held-back text once the stream ends without a match
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Length of the longest suffix of `buf` that is a proper prefix of some stop sequence
+    fn holdback_len(&self, buf: &str) -> usize {
+        self.stops
+            .iter()
+            .flat_map(|stop| {
+                stop.char_indices()
+                    .skip(1)
+                    .map(|(i, _)| &stop[..i])
+                    .filter(|prefix| buf.ends_with(prefix))
+                    .map(str::len)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scanner(stops: &[&str]) -> StopSequenceScanner {
+        StopSequenceScanner::new(&stops.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn test_match_within_one_delta() {
+        let mut s = scanner(&["END"]);
+        assert_eq!(s.push("hello END world"), ("hello ".to_string(), Some("END".to_string())));
+    }
+
+    #[test]
+    fn test_match_split_across_deltas() {
+        let mut s = scanner(&["\n\nHuman:"]);
+        assert_eq!(s.push("answer\n"), ("answer".to_string(), None));
+        assert_eq!(s.push("\nHum"), (String::new(), None));
+        assert_eq!(s.push("an: next"), (String::new(), Some("\n\nHuman:".to_string())));
+    }
+
+    #[test]
+    fn test_false_prefix_is_released() {
+        let mut s = scanner(&["STOP"]);
+        assert_eq!(s.push("ST"), (String::new(), None));
+        assert_eq!(s.push("ART"), ("START".to_string(), None));
+        assert_eq!(s.finish(), "");
+    }
+
+    #[test]
+    fn test_finish_releases_pending_text() {
+        let mut s = scanner(&["STOP"]);
+        assert_eq!(s.push("abc S"), ("abc ".to_string(), None));
+        assert_eq!(s.finish(), "S");
+    }
+
+    #[test]
+    fn test_earliest_match_wins() {
+        let mut s = scanner(&["b", "a"]);
+        assert_eq!(s.push("xab"), ("x".to_string(), Some("a".to_string())));
+    }
+
+    #[test]
+    fn test_empty_stop_list() {
+        assert!(StopSequenceScanner::new(&[String::new()]).is_none());
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use serde::Serialize;
+use tokio::sync::Notify;
+use crate::config::env_or;
+use crate::constants::*;
+
+/// Global accounting of memory held by streaming state (SSE parser buffers and event channels).
+///
+/// New streams are shed when their baseline doesn't fit under the limit; a running stream whose
+/// parser buffer would exceed it waits for other streams to release memory before reading more
+/// from the backend.
+pub struct StreamMemory {
+    /// 0 = unlimited (accounting only)
+    limit: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    streams: AtomicUsize,
+    shed: AtomicU64,
+    released: Notify,
+    /// Streams in `resize_or_wait`; releases only wake anyone while there are some
+    waiters: AtomicUsize,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct StreamMemorySnapshot {
+    pub used_bytes: usize,
+    pub peak_bytes: usize,
+    pub limit_bytes: usize,
+    pub streams: usize,
+    /// Streams rejected or cut off because the limit was reached
+    pub shed: u64,
+}
+
+impl StreamMemory {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            streams: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+            released: Notify::new(),
+            waiters: AtomicUsize::new(0),
+        }
+    }
+
+    /// Limit from `STREAM_MEMORY_LIMIT_MB` (0 disables the limit)
+    pub fn from_env() -> Self {
+        let limit_mb: usize = env_or("STREAM_MEMORY_LIMIT_MB", DEFAULT_STREAM_MEMORY_LIMIT_MB);
+        Self::new(limit_mb * 1024 * 1024)
+    }
+
+    fn try_add(&self, bytes: usize) -> bool {
+        // SeqCst pairs with `release`, so a waiter either sees the freed bytes or gets notified
+        let added = self.used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+            let next = used + bytes;
+            (self.limit == 0 || next <= self.limit).then_some(next)
+        });
+        match added {
+            Ok(previous) => {
+                self.peak.fetch_max(previous + bytes, Ordering::Relaxed);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            self.released.notify_waiters();
+        }
+    }
+
+    /// Account for a new stream, or `None` (counted as shed) when it doesn't fit
+    pub fn reserve(self: &Arc<Self>, bytes: usize) -> Option<MemoryLease> {
+        if !self.try_add(bytes) {
+            self.record_shed();
+            return None;
+        }
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        Some(MemoryLease { memory: self.clone(), bytes })
+    }
+
+    pub fn record_shed(&self) {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StreamMemorySnapshot {
+        StreamMemorySnapshot {
+            used_bytes: self.used.load(Ordering::Acquire),
+            peak_bytes: self.peak.load(Ordering::Relaxed),
+            limit_bytes: self.limit,
+            streams: self.streams.load(Ordering::Relaxed),
+            shed: self.shed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One stream's share of `StreamMemory`, released on drop
+pub struct MemoryLease {
+    memory: Arc<StreamMemory>,
+    bytes: usize,
+}
+
+impl MemoryLease {
+    /// Adjust the accounted size; growing fails when it would exceed the limit
+    pub fn resize(&mut self, bytes: usize) -> bool {
+        if bytes <= self.bytes {
+            self.memory.release(self.bytes - bytes);
+        } else if !self.memory.try_add(bytes - self.bytes) {
+            return false;
+        }
+        self.bytes = bytes;
+        true
+    }
+
+    /// Like `resize`, but waits up to `timeout` for other streams to release memory
+    pub async fn resize_or_wait(&mut self, bytes: usize, timeout: Duration) -> bool {
+        if self.resize(bytes) {
+            return true;
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        let memory = self.memory.clone();
+        memory.waiters.fetch_add(1, Ordering::SeqCst);
+        let _waiting = WaiterGuard(&memory);
+        loop {
+            let released = memory.released.notified();
+            tokio::pin!(released);
+            // Register before checking so a release between the check and the await isn't missed
+            released.as_mut().enable();
+            if self.resize(bytes) {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return false;
+            }
+        }
+    }
+}
+
+/// Counts a stream in `StreamMemory::waiters` until dropped, also when the wait is cancelled
+struct WaiterGuard<'a>(&'a StreamMemory);
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.0.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for MemoryLease {
+    fn drop(&mut self) {
+        self.memory.streams.fetch_sub(1, Ordering::Relaxed);
+        self.memory.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_sheds_over_limit_and_releases_on_drop() {
+        let memory = Arc::new(StreamMemory::new(100));
+        let a = memory.reserve(60).unwrap();
+        assert!(memory.reserve(60).is_none());
+        assert_eq!(memory.snapshot(), StreamMemorySnapshot { used_bytes: 60, peak_bytes: 60, limit_bytes: 100, streams: 1, shed: 1 });
+        drop(a);
+        assert_eq!(memory.snapshot().used_bytes, 0);
+        assert!(memory.reserve(60).is_some());
+    }
+
+    #[test]
+    fn test_lease_resize() {
+        let memory = Arc::new(StreamMemory::new(100));
+        let mut lease = memory.reserve(10).unwrap();
+        assert!(lease.resize(90));
+        assert!(!lease.resize(110));
+        assert!(lease.resize(20));
+        assert_eq!(memory.snapshot().used_bytes, 20);
+        assert_eq!(memory.snapshot().peak_bytes, 90);
+    }
+
+    #[test]
+    fn test_unlimited_only_accounts() {
+        let memory = Arc::new(StreamMemory::new(0));
+        let _lease = memory.reserve(usize::MAX / 2).unwrap();
+        assert_eq!(memory.snapshot().streams, 1);
+    }
+
+    #[tokio::test]
+    async fn test_resize_waits_for_release() {
+        let memory = Arc::new(StreamMemory::new(100));
+        let other = memory.reserve(80).unwrap();
+        let mut lease = memory.reserve(10).unwrap();
+        assert!(!lease.resize_or_wait(50, Duration::from_millis(10)).await);
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(other);
+        });
+        assert!(lease.resize_or_wait(50, Duration::from_secs(1)).await);
+        release.await.unwrap();
+        assert_eq!(memory.waiters.load(Ordering::SeqCst), 0);
+    }
+}
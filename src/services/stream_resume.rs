@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use axum::response::sse::Event;
+use tokio::sync::{mpsc, Notify};
+use crate::config::env_or;
+use crate::constants::{STREAM_RESUME_MAX_BYTES, STREAM_RESUME_MAX_EVENTS};
+
+/// SSE `id` of event `seq` of a response
+pub fn event_id(message_id: &str, seq: u64) -> String {
+    format!("{}:{}", message_id, seq)
+}
+
+#[derive(Default)]
+struct ReplayState {
+    /// Event name and JSON data, indexed by sequence number
+    events: Vec<(&'static str, String)>,
+    /// Size of the kept event data
+    bytes: usize,
+    /// Events sent, including those past the limit
+    sent: u64,
+    /// Set once more than `STREAM_RESUME_MAX_EVENTS` events or `STREAM_RESUME_MAX_BYTES` were
+    /// sent; the events are dropped and the response can no longer be resumed
+    overflowed: bool,
+    finished_at: Option<Instant>,
+}
+
+/// Events of one response, kept so a client that reconnects with `Last-Event-ID` can resume it
+pub struct ReplayBuffer {
+    message_id: String,
+    /// Key fingerprint of the client that started the response
+    owner: String,
+    state: Mutex<ReplayState>,
+    notify: Notify,
+}
+
+impl ReplayBuffer {
+    fn lock(&self) -> MutexGuard<'_, ReplayState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record a sent event; returns its sequence number
+    pub fn record(&self, event: &'static str, data: &str) -> u64 {
+        let mut state = self.lock();
+        let seq = state.sent;
+        state.sent += 1;
+        if state.overflowed {
+            return seq;
+        }
+        if state.events.len() >= STREAM_RESUME_MAX_EVENTS || state.bytes + data.len() > STREAM_RESUME_MAX_BYTES {
+            log::warn!(
+                "⏯️  {} sent over {} events or {} bytes - it can no longer be resumed",
+                self.message_id, STREAM_RESUME_MAX_EVENTS, STREAM_RESUME_MAX_BYTES
+            );
+            state.overflowed = true;
+            state.events = Vec::new();
+            state.bytes = 0;
+        } else {
+            state.bytes += data.len();
+            state.events.push((event, data.to_string()));
+        }
+        drop(state);
+        self.notify.notify_waiters();
+        seq
+    }
+
+    /// The response ended; the buffer expires after the grace window
+    pub fn finish(&self) {
+        self.lock().finished_at.get_or_insert_with(Instant::now);
+        self.notify.notify_waiters();
+    }
+
+    pub fn message_id(&self) -> &str {
+        &self.message_id
+    }
+}
+
+/// Replay buffers of recent responses (`STREAM_RESUME_SECS`)
+pub struct StreamResume {
+    /// How long a finished response stays resumable
+    grace: Duration,
+    streams: Mutex<HashMap<String, Arc<ReplayBuffer>>>,
+}
+
+impl StreamResume {
+    pub fn new(grace: Duration) -> Self {
+        Self { grace, streams: Mutex::default() }
+    }
+
+    /// `STREAM_RESUME_SECS`; `None` when unset or 0
+    pub fn from_env() -> Option<Self> {
+        let secs: u64 = env_or("STREAM_RESUME_SECS", 0);
+        if secs == 0 {
+            return None;
+        }
+        log::info!("⏯️  Stream resume: responses can be resumed with Last-Event-ID for {}s after they finish", secs);
+        Some(Self::new(Duration::from_secs(secs)))
+    }
+
+    fn streams(&self) -> MutexGuard<'_, HashMap<String, Arc<ReplayBuffer>>> {
+        let mut streams = self.streams.lock().unwrap_or_else(|e| e.into_inner());
+        streams.retain(|_, buffer| buffer.lock().finished_at.is_none_or(|at| at.elapsed() < self.grace));
+        streams
+    }
+
+    /// Start recording response `message_id` of the client with key fingerprint `owner`
+    pub fn register(&self, message_id: &str, owner: &str) -> Arc<ReplayBuffer> {
+        let buffer = Arc::new(ReplayBuffer {
+            message_id: message_id.to_string(),
+            owner: owner.to_string(),
+            state: Mutex::default(),
+            notify: Notify::new(),
+        });
+        self.streams().insert(message_id.to_string(), buffer.clone());
+        buffer
+    }
+
+    /// Buffer and last received sequence number for a `Last-Event-ID`, when the same client can
+    /// still resume that response; clients without a key (`owner` empty) never can
+    pub fn find(&self, last_event_id: &str, owner: &str) -> Option<(Arc<ReplayBuffer>, u64)> {
+        if owner.is_empty() {
+            return None;
+        }
+        let (message_id, seq) = last_event_id.trim().rsplit_once(':')?;
+        let seq = seq.parse().ok()?;
+        let buffer = self.streams().get(message_id)?.clone();
+        let resumable = buffer.owner == owner && !buffer.lock().overflowed;
+        resumable.then_some((buffer, seq))
+    }
+}
+
+/// Send the events after `after` to `tx`, then follow the response until it finishes
+pub async fn replay_events(buffer: Arc<ReplayBuffer>, after: u64, tx: mpsc::Sender<Event>) {
+    let Some(mut next) = usize::try_from(after).ok().and_then(|after| after.checked_add(1)) else { return };
+    loop {
+        // Registered before reading the state, so an event recorded in between still wakes us
+        let notified = buffer.notify.notified();
+        let (events, finished) = {
+            let state = buffer.lock();
+            if state.overflowed {
+                return;
+            }
+            (state.events.get(next..).unwrap_or_default().to_vec(), state.finished_at.is_some())
+        };
+        for (event, data) in events {
+            let event = Event::default().id(event_id(&buffer.message_id, next as u64)).event(event).data(data);
+            if tx.send(event).await.is_err() {
+                return;
+            }
+            next += 1;
+        }
+        if finished {
+            return;
+        }
+        notified.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_checks_owner_and_id() {
+        let resume = StreamResume::new(Duration::from_secs(60));
+        let buffer = resume.register("msg_1", "owner");
+        buffer.record("message_start", "{}");
+        assert_eq!(resume.find("msg_1:0", "owner").map(|(_, seq)| seq), Some(0));
+        assert!(resume.find("msg_1:0", "someone else").is_none());
+        assert!(resume.find("msg_2:0", "owner").is_none());
+        assert!(resume.find("msg_1", "owner").is_none());
+
+        let keyless = resume.register("msg_3", "");
+        keyless.record("message_start", "{}");
+        assert!(resume.find("msg_3:0", "").is_none(), "clients without a key share no owner");
+    }
+
+    #[test]
+    fn test_large_responses_are_not_kept() {
+        let resume = StreamResume::new(Duration::from_secs(60));
+        let buffer = resume.register("msg_1", "owner");
+        let chunk = "x".repeat(STREAM_RESUME_MAX_BYTES / 2);
+        buffer.record("content_block_delta", &chunk);
+        assert!(resume.find("msg_1:0", "owner").is_some());
+        buffer.record("content_block_delta", &chunk);
+        buffer.record("content_block_delta", &chunk);
+        assert!(resume.find("msg_1:0", "owner").is_none());
+        assert_eq!(buffer.lock().bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_replay_after_last_sequence_number_ends() {
+        let resume = StreamResume::new(Duration::from_secs(60));
+        let buffer = resume.register("msg_1", "owner");
+        let (tx, mut rx) = mpsc::channel(1);
+        replay_events(buffer, u64::MAX, tx).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_finished_streams_expire() {
+        let resume = StreamResume::new(Duration::ZERO);
+        let buffer = resume.register("msg_1", "owner");
+        assert!(resume.find("msg_1:0", "owner").is_some(), "running responses never expire");
+        buffer.finish();
+        assert!(resume.find("msg_1:0", "owner").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replay_follows_live_events() {
+        let resume = StreamResume::new(Duration::from_secs(60));
+        let buffer = resume.register("msg_1", "owner");
+        for event in ["message_start", "content_block_start", "content_block_delta"] {
+            buffer.record(event, "{}");
+        }
+        let (tx, mut rx) = mpsc::channel(16);
+        let task = tokio::spawn(replay_events(buffer.clone(), 0, tx));
+        tokio::task::yield_now().await;
+        assert_eq!(buffer.record("content_block_stop", "{}"), 3);
+        buffer.finish();
+        task.await.unwrap();
+
+        let mut replayed = 0;
+        while rx.try_recv().is_ok() {
+            replayed += 1;
+        }
+        assert_eq!(replayed, 3, "events 1-3 are sent after Last-Event-ID 0");
+    }
+}
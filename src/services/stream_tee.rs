@@ -0,0 +1,309 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use futures::future::BoxFuture;
+use reqwest::Client;
+use serde_json::{json, Value};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use crate::constants::*;
+use crate::services::transform::{StreamEvent, Transform, TransformContext};
+
+/// Which stream(s) get duplicated to the sink
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TeeSource {
+    Claude,
+    Backend,
+    Both,
+}
+
+impl TeeSource {
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "claude" => Some(TeeSource::Claude),
+            "backend" => Some(TeeSource::Backend),
+            "both" => Some(TeeSource::Both),
+            _ => None,
+        }
+    }
+}
+
+/// Destination for teed events
+#[derive(Debug, Clone, PartialEq)]
+pub enum TeeSink {
+    /// Append JSON lines to a file
+    File(String),
+    /// POST batches of records as a JSON array
+    Http(String),
+    /// Produce records to a Kafka topic (requires the `kafka` feature)
+    Kafka { brokers: Vec<String>, topic: String },
+}
+
+impl TeeSink {
+    /// Parse `file:/path`, `http(s)://...` or `kafka://broker1,broker2/topic`
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if let Some(path) = s.strip_prefix("file:") {
+            Some(TeeSink::File(path.to_string()))
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Some(TeeSink::Http(s.to_string()))
+        } else if let Some(rest) = s.strip_prefix("kafka://") {
+            let (brokers, topic) = rest.split_once('/')?;
+            if topic.is_empty() {
+                return None;
+            }
+            Some(TeeSink::Kafka {
+                brokers: brokers.split(',').map(String::from).collect(),
+                topic: topic.to_string(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Non-blocking duplicator of streamed events.
+///
+/// Records are queued on a bounded channel with `try_send`; when the sink can't keep up
+/// records are dropped rather than slowing down the client stream.
+pub struct StreamTee {
+    source: TeeSource,
+    tx: mpsc::Sender<Value>,
+}
+
+impl StreamTee {
+    /// Build from `STREAM_TEE_SINK` / `STREAM_TEE_SOURCE`; `None` when no sink is configured
+    pub fn from_env(client: Client) -> Option<Self> {
+        let raw = std::env::var("STREAM_TEE_SINK").ok()?;
+        let Some(sink) = TeeSink::parse(&raw) else {
+            log::error!("❌ Invalid STREAM_TEE_SINK '{}' (expected file:/path, http(s)://url or kafka://brokers/topic)", raw);
+            return None;
+        };
+        let source = std::env::var("STREAM_TEE_SOURCE")
+            .ok()
+            .and_then(|s| TeeSource::parse(&s))
+            .unwrap_or(TeeSource::Claude);
+        log::info!("   Stream tee: {:?} events → {:?}", source, sink);
+        Some(Self::spawn(sink, source, client))
+    }
+
+    fn spawn(sink: TeeSink, source: TeeSource, client: Client) -> Self {
+        let (tx, rx) = mpsc::channel(STREAM_TEE_QUEUE_SIZE);
+        tokio::spawn(run_sink(sink, rx, client));
+        Self { source, tx }
+    }
+
+    pub fn tees_backend(&self) -> bool {
+        matches!(self.source, TeeSource::Backend | TeeSource::Both)
+    }
+
+    fn tees_claude(&self) -> bool {
+        matches!(self.source, TeeSource::Claude | TeeSource::Both)
+    }
+
+    fn record(&self, record: Value) {
+        if self.tx.try_send(record).is_err() {
+            log::debug!("⚠️  Stream tee queue full, dropping record");
+        }
+    }
+
+    /// Tee one raw backend SSE payload
+    pub fn record_backend(&self, request_id: &str, data: &str) {
+        let data = serde_json::from_str::<Value>(data).unwrap_or_else(|_| Value::String(data.to_string()));
+        self.record(json!({
+            "ts_ms": now_ms(),
+            "request_id": request_id,
+            "source": "backend",
+            "data": data,
+        }));
+    }
+}
+
+impl Transform for StreamTee {
+    fn name(&self) -> &str {
+        "stream_tee"
+    }
+
+    fn on_stream_event<'a>(
+        &'a self,
+        ctx: &'a mut TransformContext,
+        event: &'a mut StreamEvent,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if self.tees_claude() {
+                self.record(json!({
+                    "ts_ms": now_ms(),
+                    "request_id": ctx.request_id,
+                    "model": ctx.model,
+                    "client": ctx.client.to_string(),
+                    "source": "claude",
+                    "event": event.event,
+                    "data": event.data,
+                }));
+            }
+        })
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0)
+}
+
+/// Drain queued records into the sink in batches
+async fn run_sink(sink: TeeSink, mut rx: mpsc::Receiver<Value>, client: Client) {
+    let mut writer = match SinkWriter::open(sink, client).await {
+        Ok(w) => w,
+        Err(e) => {
+            log::error!("❌ Failed to open stream tee sink: {}", e);
+            return;
+        }
+    };
+
+    let mut batch = Vec::with_capacity(STREAM_TEE_BATCH_SIZE);
+    loop {
+        let flush_at = tokio::time::sleep(Duration::from_millis(STREAM_TEE_FLUSH_INTERVAL_MS));
+        tokio::pin!(flush_at);
+        let closed = loop {
+            tokio::select! {
+                rec = rx.recv() => match rec {
+                    Some(rec) => {
+                        batch.push(rec);
+                        if batch.len() >= STREAM_TEE_BATCH_SIZE {
+                            break false;
+                        }
+                    }
+                    None => break true,
+                },
+                _ = &mut flush_at => break false,
+            }
+        };
+        if !batch.is_empty() {
+            if let Err(e) = writer.write_batch(&batch).await {
+                log::warn!("⚠️  Stream tee write failed ({} records dropped): {}", batch.len(), e);
+            }
+            batch.clear();
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+enum SinkWriter {
+    File(tokio::io::BufWriter<tokio::fs::File>),
+    Http { client: Client, url: String },
+    #[cfg(feature = "kafka")]
+    Kafka(rskafka::client::partition::PartitionClient),
+}
+
+impl SinkWriter {
+    async fn open(sink: TeeSink, client: Client) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        match sink {
+            TeeSink::File(path) => {
+                let file = tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+                Ok(SinkWriter::File(tokio::io::BufWriter::new(file)))
+            }
+            TeeSink::Http(url) => Ok(SinkWriter::Http { client, url }),
+            #[cfg(feature = "kafka")]
+            TeeSink::Kafka { brokers, topic } => {
+                let kafka = rskafka::client::ClientBuilder::new(brokers).build().await?;
+                let partition = kafka
+                    .partition_client(topic, 0, rskafka::client::partition::UnknownTopicHandling::Retry)
+                    .await?;
+                Ok(SinkWriter::Kafka(partition))
+            }
+            #[cfg(not(feature = "kafka"))]
+            TeeSink::Kafka { .. } => Err("Kafka sink requires building with the 'kafka' feature".into()),
+        }
+    }
+
+    async fn write_batch(&mut self, batch: &[Value]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            SinkWriter::File(w) => {
+                for rec in batch {
+                    w.write_all(rec.to_string().as_bytes()).await?;
+                    w.write_all(b"\n").await?;
+                }
+                w.flush().await?;
+            }
+            SinkWriter::Http { client, url } => {
+                let res = client.post(url.as_str()).json(batch).send().await?;
+                if !res.status().is_success() {
+                    return Err(format!("sink returned {}", res.status()).into());
+                }
+            }
+            #[cfg(feature = "kafka")]
+            SinkWriter::Kafka(partition) => {
+                let records = batch
+                    .iter()
+                    .map(|rec| rskafka::record::Record {
+                        key: rec.get("request_id").and_then(|v| v.as_str()).map(|s| s.as_bytes().to_vec()),
+                        value: Some(rec.to_string().into_bytes()),
+                        headers: Default::default(),
+                        timestamp: rskafka::chrono::DateTime::from_timestamp_millis(now_ms() as i64).unwrap_or_default(),
+                    })
+                    .collect();
+                partition
+                    .produce(records, rskafka::client::partition::Compression::NoCompression)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file_sink() {
+        assert_eq!(TeeSink::parse("file:/tmp/tee.jsonl"), Some(TeeSink::File("/tmp/tee.jsonl".into())));
+    }
+
+    #[test]
+    fn test_parse_http_sink() {
+        assert_eq!(
+            TeeSink::parse("https://collector.local/ingest"),
+            Some(TeeSink::Http("https://collector.local/ingest".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_kafka_sink() {
+        assert_eq!(
+            TeeSink::parse("kafka://k1:9092,k2:9092/traces"),
+            Some(TeeSink::Kafka {
+                brokers: vec!["k1:9092".into(), "k2:9092".into()],
+                topic: "traces".into()
+            })
+        );
+        assert_eq!(TeeSink::parse("kafka://k1:9092"), None);
+    }
+
+    #[test]
+    fn test_parse_unknown_sink() {
+        assert_eq!(TeeSink::parse("ftp://nope"), None);
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_writes_json_lines() {
+        let path = std::env::temp_dir().join(format!("tee_test_{}.jsonl", now_ms()));
+        let tee = StreamTee::spawn(TeeSink::File(path.display().to_string()), TeeSource::Both, Client::new());
+        tee.record_backend("msg_1", r#"{"choices":[]}"#);
+        drop(tee);
+
+        // The writer task exits (and flushes) once the queue is closed
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if let Ok(content) = std::fs::read_to_string(&path) {
+                if !content.is_empty() {
+                    let rec: Value = serde_json::from_str(content.lines().next().unwrap()).unwrap();
+                    assert_eq!(rec["request_id"], "msg_1");
+                    assert_eq!(rec["source"], "backend");
+                    assert_eq!(rec["data"], json!({"choices": []}));
+                    let _ = std::fs::remove_file(&path);
+                    return;
+                }
+            }
+        }
+        panic!("tee file was not written");
+    }
+}
@@ -0,0 +1,1339 @@
+use std::{borrow::Cow, collections::{BTreeMap, HashSet}, sync::Arc};
+use serde_json::{json, Value};
+use crate::constants::CHARS_PER_TOKEN;
+use crate::models::OAIStreamChunk;
+use crate::services::{format_backend_error, BudgetVerdict, ClaudeSseEmitter, CompletionSummary, Continuation, DebugSidecar, DroppedFeature, EmulatedOutput, ErrorVerbosity,
+                      ExtraChoiceBuffer, ExtraChoices, MessageTemplates, Moderation, refusal_text, Released, ResponseModerator, ServerToolOutput, SseEvent, ServerToolSession, StopSequenceScanner, StreamErrorKind, ThinkingBudget,
+                      ToolActionScanner, ToolBuf, ToolsMap};
+use crate::utils::content_extraction::{annotation_to_citation, claude_cache_usage, translate_finish_reason, ContentFilterStopReason};
+use crate::utils::tool_ids::ToolIdMap;
+use crate::utils::tool_schema::strip_null_arguments;
+
+/// Approximate token count of streamed text
+fn approx_tokens(text: &str) -> u32 {
+    std::cmp::max(1, text.len() / CHARS_PER_TOKEN) as u32
+}
+
+/// Message of a backend `error` object, or the whole object when it has none
+fn error_details(error: &Value) -> String {
+    let message = error
+        .get("message")
+        .or_else(|| error.get("type"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("Unknown error");
+    if message.is_empty() {
+        serde_json::to_string(error).unwrap_or_else(|_| "Unknown backend error".into())
+    } else {
+        message.to_string()
+    }
+}
+
+fn preview(data: &str) -> String {
+    if data.len() > 500 {
+        let end = (0..=500).rev().find(|&i| data.is_char_boundary(i)).unwrap_or(0);
+        format!("{}...", &data[..end])
+    } else {
+        data.to_string()
+    }
+}
+
+/// Translation of one backend chat completion stream into Claude SSE events.
+///
+/// Holds the per-request state: block indexes, which thinking/text block is open, tool call
+/// buffers, stop reason and usage. Backend payloads are fed in with `handle_payload` (or parsed
+/// chunks with `handle_chunk`); `finish` closes open blocks and ends the message. Reading the
+/// backend, follow-up requests and draining stay with the caller.
+pub struct StreamTranslator {
+    pub sse: ClaudeSseEmitter,
+    /// Auto-continuation of answers truncated at max_tokens (AUTO_CONTINUE_TOKENS)
+    pub continuation: Option<Continuation>,
+    /// Server tool calls held back for the proxy to run
+    pub server_tools: Option<ServerToolSession>,
+    tool_ids: ToolIdMap,
+    /// Client-side stop sequence enforcement (ENFORCE_STOP_SEQUENCES)
+    stop_scanner: Option<StopSequenceScanner>,
+    /// Response text checks against the moderation endpoint (MODERATION_URL)
+    moderator: Option<ResponseModerator>,
+    /// Thinking checks, held apart from the answer text since they go to different blocks
+    thinking_moderator: Option<ResponseModerator>,
+    /// Tool call arguments are held back until the turn ends and checked whole
+    tool_moderation: Option<Arc<Moderation>>,
+    /// Tools sent strict (STRICT_TOOLS): their arguments are held so the `null`s standing in for
+    /// omitted optional properties can be dropped
+    strict_tools: HashSet<String>,
+    /// Logprobs and annotations forwarded as `proxy_debug` events (`x-proxy-debug`)
+    debug: Option<DebugSidecar>,
+    thinking_budget: Option<ThinkingBudget>,
+    /// Fenced JSON actions → tool_use blocks (TOOL_EMULATION)
+    tool_scanner: Option<ToolActionScanner>,
+    /// Emulated calls sent to the client; calls to server tools go to `server_tools` instead
+    emulated_tool_calls: usize,
+    extra_choices: ExtraChoiceBuffer,
+    content_filter_stop_reason: ContentFilterStopReason,
+    content_filter_notice: Option<String>,
+    /// How much of a backend error the error block shows (ERROR_VERBOSITY)
+    error_verbosity: ErrorVerbosity,
+    /// Wording of the error block (MESSAGE_TEMPLATES)
+    templates: Arc<MessageTemplates>,
+
+    // Block indexing
+    next_block_index: i32,
+    thinking_open: bool,
+    thinking_index: i32,
+    text_open: bool,
+    text_index: i32,
+    tools: ToolsMap,
+    /// Index of the tool block still streaming arguments; closed before any other block starts
+    open_tool: Option<i32>,
+
+    /// The round ended: `[DONE]`, a matched stop sequence or an error
+    pub done: bool,
+    /// Translation failed; the rest of the backend stream is useless
+    pub fatal_error: bool,
+    /// Default `end_turn`, updated when the backend provides a finish_reason
+    pub stop_reason: &'static str,
+    /// The backend sent a finish_reason this round, so a stream closed without `[DONE]` wasn't cut off
+    pub finished: bool,
+    /// Backend stopped with `content_filter` (CONTENT_FILTER_STOP_REASON / CONTENT_FILTER_NOTICE)
+    content_filtered: bool,
+    matched_stop: Option<String>,
+    output_tokens: u32,
+    /// Output tokens of earlier requests when the answer was continued
+    continued_output_tokens: u32,
+    /// Claude-style prompt cache fields, when the backend reports cache hits
+    cache_usage: Option<Value>,
+    /// Sources already cited, since some backends repeat annotations in every chunk
+    cited_urls: HashSet<String>,
+    /// Mid-stream failures by kind, reported in `message_delta` and `/admin/stats`
+    stream_errors: BTreeMap<StreamErrorKind, u32>,
+}
+
+impl StreamTranslator {
+    pub fn new(sse: ClaudeSseEmitter, tool_ids: ToolIdMap) -> Self {
+        Self {
+            sse,
+            continuation: None,
+            server_tools: None,
+            tool_ids,
+            stop_scanner: None,
+            moderator: None,
+            thinking_moderator: None,
+            tool_moderation: None,
+            strict_tools: HashSet::new(),
+            debug: None,
+            thinking_budget: None,
+            tool_scanner: None,
+            emulated_tool_calls: 0,
+            extra_choices: ExtraChoiceBuffer::new(ExtraChoices::default()),
+            content_filter_stop_reason: ContentFilterStopReason::default(),
+            content_filter_notice: None,
+            error_verbosity: ErrorVerbosity::default(),
+            templates: Default::default(),
+            next_block_index: 0,
+            thinking_open: false,
+            thinking_index: -1,
+            text_open: false,
+            text_index: -1,
+            tools: ToolsMap::new(),
+            open_tool: None,
+            done: false,
+            fatal_error: false,
+            stop_reason: "end_turn",
+            finished: false,
+            content_filtered: false,
+            matched_stop: None,
+            output_tokens: 0,
+            continued_output_tokens: 0,
+            cache_usage: None,
+            cited_urls: HashSet::new(),
+            stream_errors: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_stop_scanner(mut self, scanner: Option<StopSequenceScanner>) -> Self {
+        self.stop_scanner = scanner;
+        self
+    }
+
+    pub fn with_moderation(mut self, moderation: Option<&Arc<Moderation>>) -> Self {
+        let moderation = moderation.filter(|m| m.checks_responses());
+        self.moderator = moderation.and_then(|m| m.response_moderator());
+        self.thinking_moderator = moderation.and_then(|m| m.response_moderator());
+        self.tool_moderation = moderation.cloned();
+        self
+    }
+
+    pub fn with_strict_tools(mut self, names: HashSet<String>) -> Self {
+        self.strict_tools = names;
+        self
+    }
+
+    pub fn with_debug_sidecar(mut self, debug: Option<DebugSidecar>) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    pub fn with_thinking_budget(mut self, budget: Option<ThinkingBudget>) -> Self {
+        self.thinking_budget = budget;
+        self
+    }
+
+    pub fn with_tool_scanner(mut self, scanner: Option<ToolActionScanner>) -> Self {
+        self.tool_scanner = scanner;
+        self
+    }
+
+    pub fn with_continuation(mut self, continuation: Option<Continuation>) -> Self {
+        self.continuation = continuation;
+        self
+    }
+
+    pub fn with_server_tools(mut self, session: Option<ServerToolSession>) -> Self {
+        self.server_tools = session;
+        self
+    }
+
+    pub fn with_extra_choices(mut self, mode: ExtraChoices) -> Self {
+        self.extra_choices = ExtraChoiceBuffer::new(mode);
+        self
+    }
+
+    pub fn with_content_filter(mut self, stop_reason: ContentFilterStopReason, notice: Option<String>) -> Self {
+        self.content_filter_stop_reason = stop_reason;
+        self.content_filter_notice = notice;
+        self
+    }
+
+    pub fn with_error_format(mut self, verbosity: ErrorVerbosity, templates: Arc<MessageTemplates>) -> Self {
+        self.error_verbosity = verbosity;
+        self.templates = templates;
+        self
+    }
+
+    /// Stop sequence matched by the proxy, if any
+    pub fn matched_stop(&self) -> Option<&str> {
+        self.matched_stop.as_deref()
+    }
+
+    /// The backend stopped at max_tokens in the middle of a text answer
+    pub fn truncated(&self) -> bool {
+        self.stop_reason == "max_tokens" && self.text_open && self.tools.is_empty()
+    }
+
+    /// Client tool calls were streamed this turn
+    pub fn has_tool_calls(&self) -> bool {
+        !self.tools.is_empty() || self.emulated_tool_calls > 0
+    }
+
+    /// Count a failure reading or parsing the backend stream
+    pub fn record_stream_error(&mut self, kind: StreamErrorKind) {
+        *self.stream_errors.entry(kind).or_default() += 1;
+    }
+
+    /// End the stream with an `error` stop reason
+    pub fn fail(&mut self) {
+        self.stop_reason = "error";
+        self.done = true;
+        self.fatal_error = true;
+    }
+
+    /// Continue the same message with the stream of a follow-up request
+    pub fn next_round(&mut self) {
+        self.stop_reason = "end_turn";
+        self.finished = false;
+        self.continued_output_tokens += std::mem::take(&mut self.output_tokens);
+        self.done = false;
+    }
+
+    /// Standalone text block, such as a warning about an unsupported request feature
+    pub async fn notice(&mut self, text: &str) {
+        let index = self.next_index();
+        let _ = self.sse.text_block(index, text).await;
+    }
+
+    fn next_index(&mut self) -> i32 {
+        self.next_block_index += 1;
+        self.next_block_index - 1
+    }
+
+    async fn open_text(&mut self) {
+        if !self.text_open {
+            self.close_tool_block().await;
+            self.text_index = self.next_index();
+            let _ = self.sse.text_start(self.text_index).await;
+            self.text_open = true;
+        }
+    }
+
+    /// Tell an `x-proxy-debug: dropped` client which request features didn't reach the backend
+    pub async fn report_dropped(&mut self, dropped: &[DroppedFeature]) {
+        if let Some(event) = self.debug.and_then(|debug| debug.dropped_event(dropped)) {
+            let _ = self.sse.debug(event).await;
+        }
+    }
+
+    /// A `proxy_debug` event with the chunk's logprobs and annotations, for clients that asked for them
+    async fn send_debug(&mut self, logprobs: Option<&Value>, annotations: Option<&Vec<Value>>) {
+        if let Some(event) = self.debug.and_then(|debug| debug.event(logprobs, annotations)) {
+            let _ = self.sse.debug(event).await;
+        }
+    }
+
+    async fn close_open_blocks(&mut self) {
+        self.close_content_blocks().await;
+        self.close_tool_block().await;
+    }
+
+    /// Close the open thinking and text blocks, if any
+    async fn close_content_blocks(&mut self) {
+        for (open, index) in [(&mut self.thinking_open, self.thinking_index), (&mut self.text_open, self.text_index)] {
+            if *open {
+                let _ = self.sse.block_stop(index).await;
+                *open = false;
+            }
+        }
+    }
+
+    /// Close the tool block whose arguments were streaming, if any
+    async fn close_tool_block(&mut self) {
+        if let Some(index) = self.open_tool.take() {
+            let _ = self.sse.block_stop(index).await;
+        }
+    }
+
+    /// Check and release the text held back by response moderation; `false` when it was blocked,
+    /// `Err` when the client disconnected
+    async fn flush_moderation(&mut self) -> Result<bool, ()> {
+        let Some(moderator) = self.moderator.as_mut() else { return Ok(true) };
+        let released = moderator.flush().await;
+        self.release_text(released).await
+    }
+
+    /// Check and release the thinking held back by response moderation; `false` when it was blocked
+    async fn flush_thinking_moderation(&mut self) -> bool {
+        let Some(moderator) = self.thinking_moderator.as_mut() else { return true };
+        let released = moderator.flush().await;
+        self.release_thinking(released).await
+    }
+
+    /// Send answer text that passed moderation, then refuse if a later window was flagged
+    async fn release_text(&mut self, released: Released) -> Result<bool, ()> {
+        self.push_text(&released.text).await?;
+        match released.blocked {
+            Some(categories) => {
+                self.refuse(&categories).await;
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Send thinking that passed moderation into the open thinking block, then refuse if a later
+    /// window was flagged
+    async fn release_thinking(&mut self, released: Released) -> bool {
+        if !released.text.is_empty() && self.thinking_open {
+            let _ = self.sse.thinking_delta(self.thinking_index, &released.text).await;
+            log::debug!("🧠 OUTPUT: Streamed thinking delta ({} chars)", released.text.len());
+        }
+        match released.blocked {
+            Some(categories) => {
+                self.refuse(&categories).await;
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Check the tool calls held back for moderation or strict tools and send each as a complete
+    /// block; a flagged call drops them all and refuses
+    async fn release_tool_args(&mut self) -> bool {
+        self.close_tool_block().await;
+        if let Some(moderation) = self.tool_moderation.clone() {
+            let inputs: Vec<String> = self.tools.values().filter(|tb| !tb.pending_args.is_empty()).map(|tb| tb.pending_args.clone()).collect();
+            if let Some(categories) = moderation.check_tool_inputs(&inputs).await {
+                self.tools.clear();
+                self.refuse(&categories).await;
+                return false;
+            }
+        }
+        for tb in self.tools.values_mut().filter(|tb| !tb.has_sent_start) {
+            let (Some(id), Some(name)) = (&tb.id, &tb.name) else { continue };
+            let args = if self.strict_tools.contains(name) { strip_null_arguments(&tb.pending_args) } else { std::mem::take(&mut tb.pending_args) };
+            tb.block_index = self.next_block_index;
+            self.next_block_index += 1;
+            log::info!("🔧 Tool call started: id={}, name={}", id, name);
+            let _ = self.sse.tool_start(tb.block_index, "tool_use", id, name).await;
+            if !args.is_empty() {
+                let _ = self.sse.input_json_delta(tb.block_index, &args).await;
+            }
+            let _ = self.sse.block_stop(tb.block_index).await;
+            tb.pending_args.clear();
+            tb.has_sent_start = true;
+        }
+        true
+    }
+
+    /// End the response with a `refusal` stop reason after moderation flagged its text
+    async fn refuse(&mut self, categories: &[String]) {
+        log::warn!("🛡️  Moderation blocked the response ({}), cancelling backend stream", categories.join(", "));
+        log::info!(target: "metrics", "moderation: stage=response, action=blocked, categories={}", categories.join("|"));
+        self.moderator = None;
+        self.thinking_moderator = None;
+        self.tool_moderation = None;
+        if let Some(scanner) = self.stop_scanner.as_mut() {
+            scanner.finish();
+        }
+        self.close_open_blocks().await;
+        self.notice(&refusal_text(&self.templates, categories)).await;
+        self.stop_reason = "refusal";
+        self.done = true;
+    }
+
+    /// Emit streamed answer text through the stop sequence and tool action scanners
+    async fn push_text(&mut self, c: &str) -> Result<(), ()> {
+        if c.is_empty() {
+            return Ok(());
+        }
+        let c = match self.stop_scanner.as_mut() {
+            Some(scanner) => {
+                let (text, matched) = scanner.push(c);
+                self.matched_stop = matched;
+                Cow::Owned(text)
+            }
+            None => Cow::Borrowed(c),
+        };
+        let pieces = match self.tool_scanner.as_mut() {
+            Some(scanner) => scanner.push(&c),
+            None => vec![EmulatedOutput::Text(c.into_owned())],
+        };
+        for piece in &pieces {
+            self.output_tokens += match piece {
+                EmulatedOutput::Text(c) => approx_tokens(c),
+                EmulatedOutput::ToolUse { input, .. } => approx_tokens(&input.to_string()),
+            };
+        }
+        self.emit_output(pieces).await
+    }
+
+    /// Release the text the stop sequence scanner holds back; it goes through the tool action
+    /// scanner like any other text
+    async fn flush_stop_scanner(&mut self) -> Result<(), ()> {
+        let Some(held) = self.stop_scanner.as_mut().map(StopSequenceScanner::finish).filter(|t| !t.is_empty()) else {
+            return Ok(());
+        };
+        let pieces = match self.tool_scanner.as_mut() {
+            Some(scanner) => scanner.push(&held),
+            None => vec![EmulatedOutput::Text(held)],
+        };
+        self.emit_output(pieces).await
+    }
+
+    /// Emit text and tool calls released by the stop sequence and tool action scanners; `Err`
+    /// when the client disconnected
+    async fn emit_output(&mut self, pieces: Vec<EmulatedOutput>) -> Result<(), ()> {
+        for piece in pieces {
+            match piece {
+                EmulatedOutput::Text(c) if !c.is_empty() => {
+                    // Close thinking block if still open (thinking comes before text)
+                    if self.thinking_open {
+                        let _ = self.sse.block_stop(self.thinking_index).await;
+                        self.thinking_open = false;
+                        log::info!("🧠 OUTPUT: Closed thinking block before text (index={})", self.thinking_index);
+                    }
+                    self.open_text().await;
+                    if let Some(session) = self.server_tools.as_mut() {
+                        session.push_text(&c);
+                    }
+                    self.sse.text_delta(self.text_index, &c).await?;
+                }
+                EmulatedOutput::Text(_) => {}
+                EmulatedOutput::ToolUse { id, name, input } => self.send_emulated_tool_use(&id, &name, input).await,
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit a complete tool_use block for a tool call parsed from emulated output
+    async fn send_emulated_tool_use(&mut self, id: &str, name: &str, input: Value) {
+        // Server tool calls are held back for the proxy to run, as native ones are
+        if self.server_tools.as_mut().is_some_and(|session| session.push_call(id, name, &input)) {
+            log::debug!("🛰️  Emulated server tool call held back: id={}, name={}", id, name);
+            return;
+        }
+        log::info!("🔧 Emulated tool call: id={}, name={}", id, name);
+        self.emulated_tool_calls += 1;
+        self.close_open_blocks().await;
+        let index = self.next_index();
+        let _ = self.sse.tool_block(index, "tool_use", id, name, &input).await;
+    }
+
+    /// Emit a backend error as a text block and end the stream
+    async fn backend_error(&mut self, details: &str, raw: &str) -> Result<(), ()> {
+        self.record_stream_error(StreamErrorKind::BackendError);
+        self.error_block(&format_backend_error(details, raw, self.error_verbosity, &self.templates)).await
+    }
+
+    /// Emit `text` as the last block and end the stream with an `error` stop reason
+    pub async fn error_block(&mut self, text: &str) -> Result<(), ()> {
+        self.fail();
+        // Close any open thinking, text or tool block before emitting the error
+        self.close_open_blocks().await;
+        let index = self.next_index();
+        self.sse.text_start(index).await?;
+        self.sse.text_delta(index, text).await?;
+        self.sse.block_stop(index).await
+    }
+
+    /// Translate one backend SSE event: a named `error` event is a backend error whatever its
+    /// payload looks like and `ping` is a keep-alive; everything else is an ordinary payload
+    pub async fn handle_event(&mut self, event: &SseEvent) -> Result<(), ()> {
+        match event.event.as_deref() {
+            Some("error") => {
+                let details = match serde_json::from_str::<Value>(&event.data) {
+                    Ok(value) => error_details(value.get("error").unwrap_or(&value)),
+                    Err(_) => event.data.trim().to_string(),
+                };
+                log::warn!("⚠️  Backend sent an error event: {}", details);
+                self.backend_error(&details, &event.data).await
+            }
+            Some("ping") => Ok(()),
+            _ => self.handle_payload(&event.data).await,
+        }
+    }
+
+    /// Translate one SSE `data:` payload from the backend; `Err` once the client is gone
+    pub async fn handle_payload(&mut self, data: &str) -> Result<(), ()> {
+        let data = data.trim();
+        if data == "[DONE]" {
+            log::debug!("🏁 Received [DONE] marker from backend");
+            self.done = true;
+            return Ok(());
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = match serde_json::from_str::<OAIStreamChunk>(data) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                // Only if strict parsing fails, inspect the generic JSON for an error structure
+                if let Ok(val) = serde_json::from_str::<Value>(data) {
+                    if let Some(error) = val.get("error") {
+                        let details = error_details(error);
+                        log::warn!("⚠️  Backend returned error in chunk: {}", details);
+                        return self.backend_error(&details, data).await;
+                    }
+                    if val.is_object() {
+                        log::warn!("⚠️  Chunk missing 'choices' field ({} chars), structure: {}", data.len(), preview(data));
+                        return Ok(());
+                    }
+                }
+                log::warn!("⚠️  JSON parse failed ({} chars): {}\nResponse preview: {}", data.len(), e, preview(data));
+                self.record_stream_error(StreamErrorKind::MalformedChunk);
+                return Ok(());
+            }
+        };
+
+        if let Some(error) = &chunk.error {
+            let details = error_details(error);
+            log::warn!("⚠️  Backend returned error: {}", details);
+            return self.backend_error(&details, data).await;
+        }
+        self.handle_chunk(&chunk).await
+    }
+
+    /// Translate one parsed chunk; backend `error` objects are handled by `handle_payload`
+    pub async fn handle_chunk(&mut self, chunk: &OAIStreamChunk) -> Result<(), ()> {
+        // Check if backend provides usage statistics (more accurate than our approximation).
+        // Usage often arrives in a final chunk with no choices, so check before skipping those.
+        if let Some(usage) = &chunk.usage {
+            if let Some(prompt_tokens) = usage.prompt_tokens {
+                log::debug!("📊 Backend reported prompt tokens: {}", prompt_tokens);
+            }
+            // total_tokens includes the prompt, so it only stands in when completion_tokens is missing
+            if let Some(completion_tokens) = usage.completion_tokens {
+                self.output_tokens = completion_tokens;
+                log::debug!("📊 Backend reported completion tokens: {}", completion_tokens);
+            } else if let Some(total_tokens) = usage.total_tokens {
+                self.output_tokens = total_tokens.saturating_sub(usage.prompt_tokens.unwrap_or(0));
+                log::debug!("📊 Backend reported total tokens: {}", total_tokens);
+            }
+            if let Some(cache) = claude_cache_usage(usage) {
+                log::debug!("📊 Backend reported prompt cache usage: {}", cache);
+                self.cache_usage = Some(cache);
+            }
+        }
+
+        for extra in chunk.choices.iter().filter(|c| c.index != 0) {
+            self.extra_choices.push(extra);
+        }
+        let Some(choice) = chunk.choices.iter().find(|c| c.index == 0) else {
+            log::debug!("⚠️  Chunk has no choice 0, skipping");
+            return Ok(());
+        };
+
+        if let Some(reason) = &choice.finish_reason {
+            self.finished = true;
+            self.stop_reason = translate_finish_reason(Some(reason));
+            if reason == "content_filter" {
+                self.stop_reason = self.content_filter_stop_reason.as_str();
+                self.content_filtered = true;
+            }
+            log::debug!("📍 Backend finish_reason: {} → Claude stop_reason: {}", reason, self.stop_reason);
+        }
+
+        // Non-streaming complete response (fallback)
+        if let Some(message) = &choice.message {
+            log::debug!("📦 Received non-streaming complete response, converting to SSE");
+            if let Some(content) = message.get("content").and_then(|v| v.as_str()) {
+                if let Some(moderator) = self.moderator.as_mut() {
+                    let released = moderator.push(content).await;
+                    if !self.release_text(released).await? || !self.flush_moderation().await? {
+                        return Ok(());
+                    }
+                } else {
+                    self.open_text().await;
+                    self.sse.text_delta(self.text_index, content).await?;
+                }
+            }
+            self.send_debug(choice.logprobs.as_ref(), message.get("annotations").and_then(Value::as_array)).await;
+            return Ok(());
+        }
+
+        let Some(d) = &choice.delta else {
+            log::debug!("⚠️  Chunk has no delta or message, skipping");
+            return Ok(());
+        };
+
+        // Reasoning/thinking content - stream as proper thinking blocks (not while continuing an answer)
+        let continuing = self.continuation.as_ref().is_some_and(|c| c.requests > 0);
+        if let Some(mut r) = d.reasoning_text().filter(|_| !continuing) {
+            // Approximate reasoning tokens; counted as output even when truncated
+            let reasoning_tokens = approx_tokens(&r);
+            if !r.is_empty() {
+                self.output_tokens += reasoning_tokens;
+            }
+            match self.thinking_budget.as_mut().map(|b| b.admit(reasoning_tokens)) {
+                Some(BudgetVerdict::Drop) => r = Cow::Borrowed(""),
+                Some(BudgetVerdict::Marker(marker)) => r = Cow::Owned(marker),
+                _ => {}
+            }
+            if !r.is_empty() {
+                // Answer text held for moderation goes out before the new thinking
+                if !self.flush_moderation().await? {
+                    return Ok(());
+                }
+                // Interleaved thinking: reasoning after text closes the text block and
+                // opens a new thinking block; later text opens a new text block
+                if self.text_open && !self.thinking_open {
+                    self.flush_stop_scanner().await?;
+                }
+                if self.text_open && !self.thinking_open {
+                    let _ = self.sse.block_stop(self.text_index).await;
+                    self.text_open = false;
+                    log::info!("🧠 OUTPUT: Closed text block for interleaved thinking (index={})", self.text_index);
+                }
+                if !self.thinking_open {
+                    // Reasoning between tool calls closes the previous tool block first
+                    self.close_tool_block().await;
+                    self.thinking_index = self.next_index();
+                    let _ = self.sse.thinking_start(self.thinking_index).await;
+                    self.thinking_open = true;
+                    log::info!("🧠 OUTPUT: Opened thinking block (index={})", self.thinking_index);
+                }
+                match self.thinking_moderator.as_mut() {
+                    Some(moderator) => {
+                        let released = moderator.push(&r).await;
+                        if !self.release_thinking(released).await {
+                            return Ok(());
+                        }
+                    }
+                    None => {
+                        let _ = self.sse.thinking_delta(self.thinking_index, &r).await;
+                        log::debug!("🧠 OUTPUT: Streamed thinking delta ({} chars)", r.len());
+                    }
+                }
+            }
+        }
+
+        // Text deltas
+        if let Some(c) = &d.content {
+            if let Some(continuation) = self.continuation.as_mut() {
+                continuation.push_text(c);
+            }
+            // Thinking held for moderation goes out before the answer
+            if !self.flush_thinking_moderation().await {
+                return Ok(());
+            }
+            match self.moderator.as_mut() {
+                Some(moderator) => {
+                    let released = moderator.push(c).await;
+                    if !self.release_text(released).await? {
+                        return Ok(());
+                    }
+                }
+                None => self.push_text(c).await?,
+            }
+        }
+
+        if let Some(seq) = &self.matched_stop {
+            log::info!("🛑 Stop sequence {:?} matched, cancelling backend stream", seq);
+            self.stop_reason = "stop_sequence";
+            self.done = true;
+            return Ok(());
+        }
+
+        // Source annotations → Claude citations on the current text block
+        if let Some(annotations) = &d.annotations {
+            for citation in annotations.iter().filter_map(annotation_to_citation) {
+                if !self.text_open {
+                    log::debug!("⚠️  Dropping citation received outside a text block");
+                    continue;
+                }
+                let url = citation["url"].as_str().unwrap_or_default().to_string();
+                if !self.cited_urls.insert(url) {
+                    continue;
+                }
+                let _ = self.sse.citation_delta(self.text_index, citation).await;
+            }
+        }
+        self.send_debug(choice.logprobs.as_ref(), d.annotations.as_ref()).await;
+
+        // Tool call deltas (legacy `function_call` streams are treated as tool call 0)
+        let legacy_call = d.legacy_tool_call(|| format!("toolu_{}_0", self.sse.message_id()));
+        let Some(tool_calls) = d.tool_calls.as_deref().or(legacy_call.as_ref().map(std::slice::from_ref)) else {
+            return Ok(());
+        };
+        if tool_calls.is_empty() {
+            return Ok(());
+        }
+
+        // Release thinking and text held back by moderation and the stop sequence scanner
+        if !self.flush_thinking_moderation().await || !self.flush_moderation().await? {
+            return Ok(());
+        }
+        self.flush_stop_scanner().await?;
+        self.close_content_blocks().await;
+
+        for tc in tool_calls {
+            let idx = tc.index.unwrap_or(0);
+            let function = tc.function.as_ref();
+
+            // Server tool calls are held back and run once the backend's turn ends
+            if let Some(session) = self.server_tools.as_mut().filter(|_| !self.tools.contains_key(&idx)) {
+                let (name, args) = (function.and_then(|f| f.name.as_deref()), function.and_then(|f| f.arguments.as_deref()));
+                if session.push_call_delta(idx, tc.id.as_deref(), name, args) {
+                    continue;
+                }
+            }
+
+            let tb = self.tools.entry(idx).or_insert_with(|| ToolBuf {
+                block_index: self.next_block_index,
+                id: None,
+                name: None,
+                pending_args: String::new(),
+                has_sent_start: false,
+            });
+            if let Some(id) = &tc.id {
+                tb.id = Some(self.tool_ids.client_id(id));
+            }
+            if let Some(name) = function.and_then(|f| f.name.clone()) {
+                tb.name = Some(name);
+            }
+            // Arguments are buffered until the block can start
+            if let Some(args) = function.and_then(|f| f.arguments.as_deref()) {
+                tb.pending_args.push_str(args);
+            }
+
+            // Under response moderation, or for strict tools, the whole call waits for the end of the turn
+            let held = self.tool_moderation.is_some() || tb.name.as_ref().is_some_and(|name| self.strict_tools.contains(name));
+
+            // The block starts once ID and name are known; only then is its index assigned, and
+            // the previous tool block is closed so blocks never overlap
+            if let (false, false, Some(id), Some(name)) = (held, tb.has_sent_start, &tb.id, &tb.name) {
+                if let Some(open) = self.open_tool.take() {
+                    let _ = self.sse.block_stop(open).await;
+                }
+                tb.block_index = self.next_block_index;
+                self.next_block_index += 1;
+                if self.sse.tool_start(tb.block_index, "tool_use", id, name).await.is_err() {
+                    log::debug!("🔌 Client disconnected during tool start");
+                    return Err(());
+                }
+                log::info!("🔧 Tool call started: id={}, name={}", id, name);
+                tb.has_sent_start = true;
+                self.open_tool = Some(tb.block_index);
+            }
+
+            if tb.has_sent_start && !tb.pending_args.is_empty() {
+                if self.sse.input_json_delta(tb.block_index, &tb.pending_args).await.is_err() {
+                    log::debug!("🔌 Client disconnected during tool args");
+                    return Err(());
+                }
+                tb.pending_args.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the server tool calls held back this round and emit their blocks; `None` without any
+    pub async fn run_server_tools(&mut self) -> Option<Vec<ServerToolOutput>> {
+        self.server_tools.as_ref()?;
+        // An emulated call left unclosed at the end of the round is still a call
+        let held = self.tool_scanner.as_mut().map(ToolActionScanner::finish).unwrap_or_default();
+        self.emit_output(held).await.ok()?;
+        if !self.server_tools.as_ref()?.has_calls() {
+            return None;
+        }
+        if !self.flush_thinking_moderation().await || !self.flush_moderation().await.ok()? {
+            return None;
+        }
+        self.close_open_blocks().await;
+        let outputs = self.server_tools.as_mut()?.execute().await;
+        for output in &outputs {
+            // The `server_tool_use` block of each call is followed by its result block
+            log::info!("🛰️  Server tool call: id={}, name={}", output.id, output.name);
+            let index = self.next_index();
+            let _ = self.sse.tool_block(index, "server_tool_use", &output.id, &output.name, &output.input).await;
+            let index = self.next_index();
+            let _ = self.sse.whole_block(index, output.result_block.clone()).await;
+        }
+        Some(outputs)
+    }
+
+    /// Release held-back text, close open blocks and end the message
+    pub async fn finish(&mut self, input_tokens: u32) -> CompletionSummary {
+        // Release thinking and text held back by moderation, the stop sequence and tool action scanners
+        let _ = self.flush_thinking_moderation().await && self.flush_moderation().await.unwrap_or(false);
+        let _ = self.flush_stop_scanner().await;
+        let tail = self.tool_scanner.as_mut().map(ToolActionScanner::finish).unwrap_or_default();
+        let _ = self.emit_output(tail).await;
+        if self.emulated_tool_calls > 0 && self.stop_reason == "end_turn" {
+            self.stop_reason = "tool_use";
+        }
+
+        if self.thinking_open {
+            log::info!("🧠 OUTPUT: Closed thinking block at end (index={})", self.thinking_index);
+        }
+        self.close_open_blocks().await;
+        self.release_tool_args().await;
+        for text in std::mem::take(&mut self.extra_choices).finish() {
+            let index = self.next_index();
+            let _ = self.sse.text_block(index, &text).await;
+        }
+        if let Some(notice) = self.content_filter_notice.as_deref().filter(|_| self.content_filtered) {
+            log::info!("🚫 Backend filtered the response - appending notice (index={})", self.next_block_index);
+            let _ = self.sse.text_block(self.next_block_index, notice).await;
+        }
+
+        let output_tokens = self.output_tokens + self.continued_output_tokens;
+        let mut usage = json!({ "output_tokens": output_tokens });
+        if let (Some(Value::Object(cache)), Some(usage)) = (self.cache_usage.take(), usage.as_object_mut()) {
+            usage.extend(cache);
+        }
+        if let Some(session) = self.server_tools.as_ref().filter(|s| s.web_search_requests + s.code_execution_requests > 0) {
+            usage["server_tool_use"] = json!({
+                "web_search_requests": session.web_search_requests,
+                "code_execution_requests": session.code_execution_requests,
+            });
+        }
+        // A client that left still gets a summary: usage, logs and traces are recorded either way
+        if self.sse.message_delta(self.stop_reason, self.matched_stop.as_deref(), usage, &self.stream_errors).await.is_err() {
+            log::debug!("🔌 Client disconnected before message_delta");
+        } else if self.sse.message_stop().await.is_err() {
+            log::debug!("🔌 Client disconnected before message_stop");
+        }
+        self.summary(input_tokens)
+    }
+
+    /// Outcome of the message so far, for `on_complete`
+    pub fn summary(&mut self, input_tokens: u32) -> CompletionSummary {
+        CompletionSummary {
+            stop_reason: self.stop_reason.to_string(),
+            input_tokens,
+            output_tokens: self.output_tokens + self.continued_output_tokens,
+            fatal_error: self.fatal_error,
+            stream_errors: std::mem::take(&mut self.stream_errors),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::streaming::tests::recording_emitter;
+    use crate::utils::tool_ids::ToolIdFormat;
+
+    /// Translator and the receiver that keeps its client connected
+    fn translator() -> (StreamTranslator, tokio::sync::mpsc::Receiver<axum::response::sse::Event>) {
+        let (sse, _, rx) = recording_emitter();
+        (StreamTranslator::new(sse, ToolIdMap::new(ToolIdFormat::Passthrough)), rx)
+    }
+
+    fn delta(delta: Value) -> OAIStreamChunk {
+        serde_json::from_value(json!({ "choices": [{ "index": 0, "delta": delta }] })).unwrap()
+    }
+
+    fn finish(reason: &str) -> OAIStreamChunk {
+        serde_json::from_value(json!({ "choices": [{ "index": 0, "delta": {}, "finish_reason": reason }] })).unwrap()
+    }
+
+    /// Events as `(type, index, block or delta type)`, leaving out payloads
+    fn outline(events: &[(&'static str, Value)]) -> Vec<(&'static str, i64, String)> {
+        events
+            .iter()
+            .map(|(name, data)| {
+                let kind = data.get("content_block").or(data.get("delta")).and_then(|v| v["type"].as_str());
+                (*name, data["index"].as_i64().unwrap_or(-1), kind.unwrap_or_default().to_string())
+            })
+            .collect()
+    }
+
+    /// Feed `chunks` and finish the message
+    async fn run(chunks: Vec<OAIStreamChunk>) -> (StreamTranslator, Vec<(&'static str, Value)>, CompletionSummary) {
+        let (sse, recorder, _rx) = recording_emitter();
+        let mut t = StreamTranslator::new(sse, ToolIdMap::new(ToolIdFormat::Passthrough));
+        for chunk in &chunks {
+            t.handle_chunk(chunk).await.unwrap();
+        }
+        let summary = t.finish(10).await;
+        (t, recorder.events(), summary)
+    }
+
+    #[tokio::test]
+    async fn test_debug_sidecar_forwards_logprobs() {
+        let (sse, recorder, _rx) = recording_emitter();
+        let debug = DebugSidecar { logprobs: Some(0), annotations: false, dropped: false };
+        let mut t = StreamTranslator::new(sse, ToolIdMap::new(ToolIdFormat::Passthrough)).with_debug_sidecar(Some(debug));
+        let token = json!({"token": "Hi", "logprob": -0.25, "top_logprobs": []});
+        let chunk = serde_json::from_value(json!({"choices": [{"index": 0, "delta": {"content": "Hi"}, "logprobs": {"content": [token]}}]})).unwrap();
+        t.handle_chunk(&chunk).await.unwrap();
+        t.handle_chunk(&delta(json!({"content": "!"}))).await.unwrap();
+        let events = recorder.events();
+        let debug: Vec<&Value> = events.iter().filter(|(name, _)| *name == "proxy_debug").map(|(_, data)| data).collect();
+        assert_eq!(debug, [&json!({"type": "proxy_debug", "logprobs": [token]})]);
+        assert_eq!(events[2].0, "proxy_debug", "sent after the chunk's text");
+    }
+
+    #[tokio::test]
+    async fn test_text_stream() {
+        let usage = serde_json::from_value(json!({ "choices": [], "usage": { "prompt_tokens": 10, "completion_tokens": 7 } })).unwrap();
+        let (_, events, summary) = run(vec![delta(json!({"content": "Hel"})), delta(json!({"content": "lo"})), finish("stop"), usage]).await;
+        assert_eq!(
+            outline(&events),
+            [
+                ("content_block_start", 0, "text".into()),
+                ("content_block_delta", 0, "text_delta".into()),
+                ("content_block_delta", 0, "text_delta".into()),
+                ("content_block_stop", 0, String::new()),
+                ("message_delta", -1, String::new()),
+                ("message_stop", -1, String::new()),
+            ]
+        );
+        assert_eq!(events[2].1["delta"]["text"], "lo");
+        assert_eq!(events[4].1["delta"]["stop_reason"], "end_turn");
+        assert_eq!(events[4].1["usage"], json!({ "output_tokens": 7 }));
+        assert!(events[4].1.get("proxy_stream_errors").is_none());
+        assert_eq!((summary.stop_reason.as_str(), summary.output_tokens), ("end_turn", 7));
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_thinking_opens_new_blocks() {
+        let (_, events, _) = run(vec![
+            delta(json!({"reasoning_content": "plan"})),
+            delta(json!({"content": "step one"})),
+            delta(json!({"reasoning_content": "rethink"})),
+            delta(json!({"content": "step two"})),
+        ])
+        .await;
+        let starts: Vec<_> = outline(&events).into_iter().filter(|(name, _, _)| *name == "content_block_start").collect();
+        assert_eq!(
+            starts,
+            [
+                ("content_block_start", 0, "thinking".into()),
+                ("content_block_start", 1, "text".into()),
+                ("content_block_start", 2, "thinking".into()),
+                ("content_block_start", 3, "text".into()),
+            ]
+        );
+        let stops = events.iter().filter(|(name, _)| *name == "content_block_stop").count();
+        assert_eq!(stops, 4, "every block is closed exactly once");
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_thinking_between_tool_calls() {
+        let (_, events, _) = run(vec![
+            delta(json!({"reasoning_content": "look it up"})),
+            delta(json!({"tool_calls": [{"index": 0, "id": "call_1", "function": {"name": "weather", "arguments": "{}"}}]})),
+            delta(json!({"reasoning_content": "and the other city"})),
+            delta(json!({"tool_calls": [{"index": 1, "id": "call_2", "function": {"name": "weather", "arguments": "{}"}}]})),
+            finish("tool_calls"),
+        ])
+        .await;
+        assert_eq!(
+            outline(&events),
+            [
+                ("content_block_start", 0, "thinking".into()),
+                ("content_block_delta", 0, "thinking_delta".into()),
+                ("content_block_stop", 0, String::new()),
+                ("content_block_start", 1, "tool_use".into()),
+                ("content_block_delta", 1, "input_json_delta".into()),
+                ("content_block_stop", 1, String::new()),
+                ("content_block_start", 2, "thinking".into()),
+                ("content_block_delta", 2, "thinking_delta".into()),
+                ("content_block_stop", 2, String::new()),
+                ("content_block_start", 3, "tool_use".into()),
+                ("content_block_delta", 3, "input_json_delta".into()),
+                ("content_block_stop", 3, String::new()),
+                ("message_delta", -1, String::new()),
+                ("message_stop", -1, String::new()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_deltas() {
+        let (t, events, summary) = run(vec![
+            delta(json!({"content": "Checking."})),
+            // Arguments before the name are held until the block can start
+            delta(json!({"tool_calls": [{"index": 0, "function": {"arguments": "{\"city\":"}}]})),
+            delta(json!({"tool_calls": [{"index": 0, "id": "call_1", "function": {"name": "weather"}}]})),
+            delta(json!({"tool_calls": [{"index": 0, "function": {"arguments": "\"Oslo\"}"}}]})),
+            finish("tool_calls"),
+        ])
+        .await;
+        assert!(t.has_tool_calls());
+        assert_eq!(
+            outline(&events)[..6],
+            [
+                ("content_block_start", 0, "text".into()),
+                ("content_block_delta", 0, "text_delta".into()),
+                ("content_block_stop", 0, String::new()),
+                ("content_block_start", 1, "tool_use".into()),
+                ("content_block_delta", 1, "input_json_delta".into()),
+                ("content_block_delta", 1, "input_json_delta".into()),
+            ]
+        );
+        assert_eq!(events[3].1["content_block"], json!({"type": "tool_use", "id": "call_1", "name": "weather", "input": {}}));
+        let args: String = events[4..6].iter().map(|(_, e)| e["delta"]["partial_json"].as_str().unwrap()).collect();
+        assert_eq!(args, r#"{"city":"Oslo"}"#);
+        assert_eq!(summary.stop_reason, "tool_use");
+    }
+
+    #[tokio::test]
+    async fn test_strict_tool_arguments_drop_nulls() {
+        let (sse, recorder, _rx) = recording_emitter();
+        let mut t = StreamTranslator::new(sse, ToolIdMap::new(ToolIdFormat::Passthrough)).with_strict_tools(HashSet::from(["read".to_string()]));
+        t.handle_chunk(&delta(json!({"tool_calls": [{"index": 0, "id": "call_1", "function": {"name": "read", "arguments": "{\"path\":\"a.rs\","}}]}))).await.unwrap();
+        t.handle_chunk(&delta(json!({"tool_calls": [{"index": 0, "function": {"arguments": "\"limit\":null}"}}]}))).await.unwrap();
+        assert!(recorder.events().is_empty(), "the whole call waits for the end of the turn");
+        t.finish(0).await;
+        let events = recorder.events();
+        let args: Vec<&str> = events.iter().filter_map(|(_, e)| e["delta"]["partial_json"].as_str()).collect();
+        assert_eq!(args, [r#"{"path":"a.rs"}"#]);
+    }
+
+    #[tokio::test]
+    async fn test_backend_error_payload_ends_stream() {
+        let (mut t, _rx) = translator();
+        t.handle_payload("not json").await.unwrap();
+        assert!(!t.done, "unparsable payloads are skipped");
+        t.handle_payload(r#"{"choices":[{"index":0,"delta":{"content":"partial"}}]}"#).await.unwrap();
+        t.handle_payload(r#"{"error":{"message":"model overloaded"}}"#).await.unwrap();
+        assert!(t.done && t.fatal_error);
+        assert_eq!(t.stop_reason, "error");
+        assert!(!t.text_open, "text block is closed before the error block");
+        assert_eq!(t.next_block_index, 2);
+
+        // Both failures are classified and reported with the final message_delta
+        let summary = t.finish(0).await;
+        assert_eq!(summary.stream_errors, BTreeMap::from([(StreamErrorKind::MalformedChunk, 1), (StreamErrorKind::BackendError, 1)]));
+    }
+
+    #[tokio::test]
+    async fn test_finish_summarizes_after_client_disconnect() {
+        let (mut t, rx) = translator();
+        t.handle_chunk(&delta(json!({"content": "partial answer"}))).await.unwrap();
+        t.output_tokens = 3;
+        drop(rx);
+        let summary = t.finish(7).await;
+        assert_eq!((summary.stop_reason.as_str(), summary.input_tokens, summary.output_tokens), ("end_turn", 7, 3));
+    }
+
+    #[tokio::test]
+    async fn test_named_error_event_ends_stream() {
+        let (mut t, _rx) = translator();
+        t.handle_event(&SseEvent { event: Some("ping".into()), data: "{}".into() }).await.unwrap();
+        assert!(!t.done);
+        // The payload has no `error` wrapper; the event name alone marks it as an error
+        t.handle_event(&SseEvent { event: Some("error".into()), data: r#"{"message":"upstream overloaded"}"#.into() }).await.unwrap();
+        assert!(t.done && t.fatal_error);
+        assert_eq!(t.stream_errors[&StreamErrorKind::BackendError], 1);
+    }
+
+    #[tokio::test]
+    async fn test_moderation_refuses_flagged_text() {
+        use crate::services::{moderation::tests::flagging_moderation, ModerationAction};
+        let moderation = flagging_moderation(ModerationAction::Block).await;
+        let (sse, recorder, _rx) = recording_emitter();
+        let mut t = StreamTranslator::new(sse, ToolIdMap::new(ToolIdFormat::Passthrough)).with_moderation(Some(&moderation));
+        t.handle_chunk(&delta(json!({"content": "Sure"}))).await.unwrap();
+        t.handle_chunk(&delta(json!({"content": ", a bomb"}))).await.unwrap();
+        let summary = t.finish(0).await;
+        assert_eq!(summary.stop_reason, "refusal");
+
+        let texts: Vec<_> = recorder.events().iter().filter_map(|(_, e)| e["delta"]["text"].as_str().map(str::to_string)).collect();
+        // "Sure" filled a window and passed; the flagged window never reaches the client
+        assert_eq!(texts, ["Sure".to_string(), refusal_text(&MessageTemplates::default(), &["violence".into()])]);
+    }
+
+    #[tokio::test]
+    async fn test_moderation_checks_thinking_and_tool_arguments() {
+        use crate::services::{moderation::tests::flagging_moderation, ModerationAction};
+        let moderation = flagging_moderation(ModerationAction::Block).await;
+
+        let (sse, recorder, _rx) = recording_emitter();
+        let mut t = StreamTranslator::new(sse, ToolIdMap::new(ToolIdFormat::Passthrough)).with_moderation(Some(&moderation));
+        t.handle_chunk(&delta(json!({"reasoning_content": "plan a bomb"}))).await.unwrap();
+        t.handle_chunk(&delta(json!({"content": "Hello"}))).await.unwrap();
+        assert!(t.done, "flagged thinking is refused before the answer starts");
+        assert_eq!(t.finish(0).await.stop_reason, "refusal");
+        assert!(recorder.events().iter().all(|(_, e)| e["delta"]["thinking"].is_null() && e["delta"]["text"] != "Hello"));
+
+        let (sse, recorder, _rx) = recording_emitter();
+        let mut t = StreamTranslator::new(sse, ToolIdMap::new(ToolIdFormat::Passthrough)).with_moderation(Some(&moderation));
+        let call = |args: &str| delta(json!({"tool_calls": [{"index": 0, "id": "call_1", "function": {"name": "search", "arguments": args}}]}));
+        t.handle_chunk(&call("{\"q\": \"bo")).await.unwrap();
+        t.handle_chunk(&call("mb\"}")).await.unwrap();
+        assert_eq!(t.finish(0).await.stop_reason, "refusal");
+        let events = recorder.events();
+        assert!(events.iter().all(|(_, e)| e["delta"]["partial_json"].is_null()), "flagged arguments are withheld");
+        assert_eq!(events.iter().filter(|(name, _)| *name == "content_block_stop").count(), 1, "only the refusal notice");
+    }
+
+    #[tokio::test]
+    async fn test_emulated_server_tool_calls_stay_with_the_proxy() {
+        use crate::models::OAIChatReq;
+        use crate::services::{prepare_server_tools, ServerToolConfig, WebSearchConfig};
+
+        let config = ServerToolConfig { web_search: Some(WebSearchConfig { url: "http://search.local/search".into(), max_results: 5 }), code_execution: None };
+        let mut tools = Some(serde_json::from_value(json!([{"type": "web_search_20250305", "name": "web_search"}])).unwrap());
+        let session = ServerToolSession::new(prepare_server_tools(&mut tools, &config), config, reqwest::Client::new(), &OAIChatReq::default());
+        let (sse, recorder, _rx) = recording_emitter();
+        let mut t = StreamTranslator::new(sse, ToolIdMap::new(ToolIdFormat::Passthrough))
+            .with_tool_scanner(Some(ToolActionScanner::new("m", HashSet::from(["web_search".to_string(), "read_file".to_string()]))))
+            .with_server_tools(session);
+        t.handle_chunk(&delta(json!({"content": "Looking.\n```tool_call\n{\"tool\": \"web_search\", \"input\": {\"query\": \"tokio\"}}\n```\n"}))).await.unwrap();
+        t.handle_chunk(&delta(json!({"content": "```tool_call\n{\"tool\": \"read_file\", \"input\": {\"path\": \"a.rs\"}}\n```"}))).await.unwrap();
+        t.handle_chunk(&finish("stop")).await.unwrap();
+
+        assert!(t.server_tools.as_ref().unwrap().has_calls(), "the search waits for the proxy to run it");
+        assert!(t.has_tool_calls(), "the client call goes out first");
+        let events = recorder.events();
+        let tool_uses: Vec<&Value> = events.iter().filter_map(|(_, e)| e.get("content_block").filter(|b| b["type"] == "tool_use")).collect();
+        assert_eq!(tool_uses.len(), 1);
+        assert_eq!(tool_uses[0]["name"], "read_file");
+        assert_eq!(t.finish(0).await.stop_reason, "tool_use");
+    }
+
+    #[tokio::test]
+    async fn test_done_marker_and_truncation() {
+        let (mut t, _rx) = translator();
+        t.handle_chunk(&delta(json!({"content": "a long answer"}))).await.unwrap();
+        t.handle_chunk(&finish("length")).await.unwrap();
+        assert!(t.truncated());
+        t.handle_payload("[DONE]").await.unwrap();
+        assert!(t.done && !t.fatal_error);
+
+        t.next_round();
+        assert!(!t.done);
+        assert_eq!(t.stop_reason, "end_turn");
+        assert_eq!(t.continued_output_tokens, 3);
+    }
+
+    /// Golden transcripts: recorded backend SSE replayed through the `/v1/messages` handler and
+    /// compared with a checked-in transcript.
+    ///
+    /// Each directory under `tests/golden/` is one fixture:
+    ///
+    /// - `request.json` - the Claude request sent to the proxy
+    /// - `backend.sse` - the raw stream the mock backend returns, sent one SSE event per chunk
+    /// - `expected.sse` - the Claude events the proxy must emit, one `event:`/`data:` pair per event
+    ///
+    /// The message id, including inside ids derived from it, is replaced with `msg_golden` before
+    /// comparing. To add a fixture, create the first two files and run `GOLDEN_UPDATE=1 cargo test golden`
+    /// to record `expected.sse`, then review it.
+    mod golden {
+        use std::path::{Path, PathBuf};
+        use axum::{
+            body::Body,
+            extract::State,
+            http::{HeaderMap, HeaderValue, Uri},
+            response::IntoResponse,
+            routing::post,
+            Router,
+        };
+        use crate::models::app::tests::test_app;
+        use crate::models::{App, ClaudeRequest, ModelInfo};
+        use crate::services::ClientIp;
+        use super::*;
+
+        /// Directory holding one subdirectory per fixture
+        fn fixtures_dir() -> PathBuf {
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+        }
+
+        struct Fixture {
+            name: String,
+            dir: PathBuf,
+            request: Value,
+            backend_sse: String,
+            /// `None` until recorded with `GOLDEN_UPDATE=1`
+            expected: Option<String>,
+        }
+
+        impl Fixture {
+            fn load(dir: &Path) -> Result<Self, String> {
+                let read = |file: &str| std::fs::read_to_string(dir.join(file)).map_err(|e| format!("{}/{}: {}", dir.display(), file, e));
+                let request = serde_json::from_str(&read("request.json")?).map_err(|e| format!("{}/request.json: {}", dir.display(), e))?;
+                Ok(Self {
+                    name: dir.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                    dir: dir.to_path_buf(),
+                    request,
+                    backend_sse: read("backend.sse")?,
+                    expected: read("expected.sse").ok(),
+                })
+            }
+        }
+
+        /// All fixtures under `dir`, sorted by name
+        fn load_fixtures(dir: &Path) -> Result<Vec<Fixture>, String> {
+            let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+            let mut dirs: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_dir()).collect();
+            dirs.sort();
+            dirs.iter().map(|d| Fixture::load(d)).collect()
+        }
+
+        /// Serve `sse` as the chat completions stream, one SSE event per body chunk
+        async fn spawn_backend(sse: String) -> String {
+            let events: Arc<Vec<String>> = Arc::new(sse.split_inclusive("\n\n").map(str::to_string).collect());
+            let router = Router::new().route(
+                "/v1/chat/completions",
+                post(|State(events): State<Arc<Vec<String>>>| async move {
+                    let chunks = futures::stream::iter(events.iter().cloned().map(Ok::<_, std::convert::Infallible>).collect::<Vec<_>>());
+                    ([("content-type", "text/event-stream")], Body::from_stream(chunks))
+                }),
+            ).with_state(events);
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind mock backend");
+            let addr = listener.local_addr().expect("mock backend address");
+            tokio::spawn(async move { axum::serve(listener, router).await.ok() });
+            format!("http://{}/v1/chat/completions", addr)
+        }
+
+        /// Test app talking to `backend_url`, whose model cache lists only `model`
+            async fn golden_app(backend_url: String, model: &str) -> App {
+                let app = test_app(backend_url);
+                let model = ModelInfo { id: model.to_string(), input_price_usd: None, output_price_usd: None, supported_features: Vec::new() };
+                *app.models_cache.write().await = Some(Arc::new(vec![model]));
+                app
+            }
+
+        /// Claude SSE body → normalized transcript (`event:`/`data:` pairs, message id replaced)
+        fn normalize_transcript(body: &[u8]) -> String {
+            let mut out = String::new();
+            let mut event = String::new();
+            let mut message_id: Option<String> = None;
+            for line in String::from_utf8_lossy(body).lines() {
+                if let Some(name) = line.strip_prefix("event:") {
+                    event = name.trim().to_string();
+                } else if let Some(data) = line.strip_prefix("data:") {
+                    let mut data = data.trim().to_string();
+                    if message_id.is_none() {
+                        message_id = serde_json::from_str::<Value>(&data)
+                            .ok()
+                            .and_then(|v| v.pointer("/message/id")?.as_str().filter(|id| id.starts_with("msg_")).map(str::to_string));
+                    }
+                    if let Some(id) = &message_id {
+                        data = data.replace(id.as_str(), "msg_golden");
+                    }
+                    let data: Value = serde_json::from_str(&data).unwrap_or(Value::String(data));
+                    out.push_str(&format!("event: {}\ndata: {}\n\n", event, data));
+                }
+            }
+            out
+        }
+
+        /// Run a fixture through the handler and return the normalized Claude transcript
+        async fn replay(fixture: &Fixture) -> Result<String, String> {
+            let request: ClaudeRequest = serde_json::from_value(fixture.request.clone()).map_err(|e| format!("request.json: {}", e))?;
+            let app = golden_app(spawn_backend(fixture.backend_sse.clone()).await, &request.model).await;
+            let mut headers = HeaderMap::new();
+            headers.insert("x-api-key", HeaderValue::from_static("cpk_golden"));
+            let response = crate::handlers::messages(State(app), headers, Uri::from_static("/v1/messages"), ClientIp([127, 0, 0, 1].into()), axum::Json(request))
+                .await
+                .map_err(|e| format!("handler rejected the request: {} {}", e.status, e.code))?
+                .into_response();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.map_err(|e| e.to_string())?;
+            Ok(normalize_transcript(&body))
+        }
+
+        /// First differing event between two transcripts, for readable failures
+        fn first_difference(expected: &str, actual: &str) -> String {
+            let expected: Vec<&str> = expected.split_terminator("\n\n").collect();
+            let actual: Vec<&str> = actual.split_terminator("\n\n").collect();
+            let index = expected.iter().zip(&actual).position(|(e, a)| e != a).unwrap_or(expected.len().min(actual.len()));
+            format!(
+                "event #{} differs ({} expected events, {} actual)\n  expected: {}\n  actual:   {}",
+                index,
+                expected.len(),
+                actual.len(),
+                expected.get(index).unwrap_or(&"<end of transcript>"),
+                actual.get(index).unwrap_or(&"<end of transcript>"),
+            )
+        }
+
+        /// Replay a fixture and compare (or, with `GOLDEN_UPDATE=1`, record) its transcript
+        async fn check(fixture: &Fixture) -> Result<(), String> {
+            let actual = replay(fixture).await?;
+            if std::env::var("GOLDEN_UPDATE").is_ok_and(|v| v == "1") {
+                return std::fs::write(fixture.dir.join("expected.sse"), actual).map_err(|e| e.to_string());
+            }
+            match &fixture.expected {
+                None => Err("no expected.sse (record it with GOLDEN_UPDATE=1)".into()),
+                Some(expected) if *expected != actual => Err(first_difference(expected, &actual)),
+                Some(_) => Ok(()),
+            }
+        }
+
+        #[tokio::test]
+        async fn test_golden_transcripts() {
+            let fixtures = load_fixtures(&fixtures_dir()).unwrap();
+            assert!(!fixtures.is_empty(), "no fixtures in {}", fixtures_dir().display());
+            let mut failures = Vec::new();
+            for fixture in &fixtures {
+                if let Err(e) = check(fixture).await {
+                    failures.push(format!("{}: {}", fixture.name, e));
+                }
+            }
+            assert!(failures.is_empty(), "golden transcript mismatches:\n{}", failures.join("\n"));
+        }
+
+        #[test]
+        fn test_normalize_transcript() {
+            let body = b"event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_123\"}}\n\n: keep-alive\n\n\
+                event: content_block_start\ndata: {\"content_block\":{\"id\":\"toolu_msg_123_0\"}}\n\n";
+            assert_eq!(
+                normalize_transcript(body),
+                "event: message_start\ndata: {\"message\":{\"id\":\"msg_golden\"},\"type\":\"message_start\"}\n\n\
+                 event: content_block_start\ndata: {\"content_block\":{\"id\":\"toolu_msg_golden_0\"}}\n\n"
+            );
+        }
+
+        #[test]
+        fn test_first_difference() {
+            let diff = first_difference("event: a\ndata: 1\n\nevent: b\ndata: 2\n\n", "event: a\ndata: 1\n\n");
+            assert!(diff.starts_with("event #1 differs (2 expected events, 1 actual)"));
+            assert!(diff.ends_with("<end of transcript>"));
+        }
+    }
+}
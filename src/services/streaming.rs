@@ -1,116 +1,404 @@
-use std::collections::HashMap;
+use std::{collections::{BTreeMap, HashMap}, str::FromStr, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use serde_json::{json, Value};
+use crate::models::OAIChoice;
+use crate::constants::{BACKEND_JSON_BODY_MAX_BYTES, DEFAULT_SSE_BUFFER_HARD_CAP_KB, DEFAULT_SSE_BUFFER_LIMIT_KB, STREAMING_FALLBACK_SECS, STREAMING_REJECTIONS};
+use crate::services::{BlockDelta, ChaosStream, CompletionSummary, EventSender, StreamErrorKind, StreamEvent, DEBUG_EVENT};
+
+/// What the SSE parser does when an unfinished event outgrows its buffer limit (`SSE_BUFFER_POLICY`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SseBufferPolicy {
+    /// Drop the unfinished event and carry on with the next one
+    #[default]
+    Clear,
+    /// End the stream with an error
+    Abort,
+    /// Keep buffering up to the hard cap (`SSE_BUFFER_HARD_CAP_KB`), then end the stream with an error
+    Grow,
+}
+
+impl FromStr for SseBufferPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "clear" => Ok(SseBufferPolicy::Clear),
+            "abort" => Ok(SseBufferPolicy::Abort),
+            "grow" => Ok(SseBufferPolicy::Grow),
+            _ => Err(()),
+        }
+    }
+}
 
-/// Maximum buffer size before clearing (1MB)
-const MAX_BUFFER_SIZE: usize = 1_048_576;
+/// Bytes an unfinished backend event may hold (`SSE_BUFFER_LIMIT_KB`) and what happens past that
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SseBufferLimit {
+    pub policy: SseBufferPolicy,
+    pub limit_bytes: usize,
+    /// Ceiling for `Grow`
+    pub hard_cap_bytes: usize,
+}
 
-/// Simple SSE event parser that accumulates lines until a blank line, then yields the combined `data:` payload.
+impl Default for SseBufferLimit {
+    fn default() -> Self {
+        Self {
+            policy: SseBufferPolicy::default(),
+            limit_bytes: DEFAULT_SSE_BUFFER_LIMIT_KB * 1024,
+            hard_cap_bytes: DEFAULT_SSE_BUFFER_HARD_CAP_KB * 1024,
+        }
+    }
+}
+
+/// One event from an SSE stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    /// The `event:` field; `None` for unnamed events (type `message`)
+    pub event: Option<String>,
+    /// `data:` lines joined with `\n`
+    pub data: String,
+}
+
+/// Simple SSE event parser that accumulates lines until a blank line, then yields the event's
+/// `event:` name and combined `data:` payload.
 /// This follows the SSE spec: multiple `data:` lines per event are joined by `\n`.
-/// Uses Vec<u8> buffer to handle split UTF-8 characters safely.
+///
+/// Bytes are buffered in a `BytesMut` and only decoded once a whole line is available, so
+/// multi-byte UTF-8 characters split across chunks come out intact. Complete lines are split
+/// off the front without moving the rest of the buffer, and the newline search resumes where
+/// the previous chunk's search stopped.
 pub struct SseEventParser {
-    buf: Vec<u8>,
-    // Accumulates data: lines for the current event until blank line.
-    cur_data_lines: Vec<String>,
+    buf: BytesMut,
+    /// Bytes of `buf` already known to contain no newline
+    scanned: usize,
+    /// `data:` lines of the current event, joined with `\n`, until a blank line
+    cur_data: String,
+    has_data: bool,
+    /// `event:` name of the current event
+    cur_event: Option<String>,
+    limit: SseBufferLimit,
+    /// The unfinished event is past `limit_bytes` (counted once per event under `Grow`)
+    over_limit: bool,
+    /// Times the limit was hit since `take_limit_hits`
+    limit_hits: u32,
+    /// The limit ended the stream; further input is ignored
+    aborted: bool,
 }
 
 impl SseEventParser {
     pub fn new() -> Self {
+        Self::with_limit(SseBufferLimit::default())
+    }
+
+    pub fn with_limit(limit: SseBufferLimit) -> Self {
         Self {
-            buf: Vec::with_capacity(16 * 1024),
-            cur_data_lines: Vec::with_capacity(4),
+            buf: BytesMut::with_capacity(16 * 1024),
+            scanned: 0,
+            cur_data: String::new(),
+            has_data: false,
+            cur_event: None,
+            limit,
+            over_limit: false,
+            limit_hits: 0,
+            aborted: false,
         }
     }
 
-    /// Feed bytes and extract zero or more complete SSE event payloads (already joined).
+    /// How often the buffer limit was hit since the last call
+    pub fn take_limit_hits(&mut self) -> u32 {
+        std::mem::take(&mut self.limit_hits)
+    }
+
+    /// The buffer limit ended the stream (`Abort`, or `Grow` past the hard cap)
+    pub fn aborted(&self) -> bool {
+        self.aborted
+    }
+
+    /// Bytes currently held for incomplete lines and events
+    pub fn buffered_bytes(&self) -> usize {
+        self.buf.capacity() + self.cur_data.capacity()
+    }
+
+    /// Feed bytes and extract the payloads of zero or more complete SSE events (already joined)
     pub fn push_and_drain_events(&mut self, chunk: &[u8]) -> Vec<String> {
-        // Check buffer size limit to prevent unbounded growth
-        if self.buf.len() + chunk.len() > MAX_BUFFER_SIZE {
-            log::warn!(
-                "⚠️  SSE buffer exceeded {}MB limit (current: {} bytes, incoming: {} bytes). Clearing buffer to prevent memory exhaustion.",
-                MAX_BUFFER_SIZE / 1_048_576,
-                self.buf.len(),
-                chunk.len()
-            );
-            // Clear buffer and start fresh with new chunk
-            self.buf.clear();
-            self.cur_data_lines.clear();
-        }
+        self.push_and_drain(chunk).into_iter().map(|e| e.data).collect()
+    }
 
+    /// Feed bytes and extract zero or more complete SSE events with their `event:` names
+    pub fn push_and_drain(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        if self.aborted {
+            return Vec::new();
+        }
         self.buf.extend_from_slice(chunk);
         let mut out = Vec::new();
 
-        loop {
-            // Find next newline
-            let Some(pos) = self.buf.iter().position(|&b| b == b'\n') else { break };
+        while let Some(offset) = self.buf[self.scanned..].iter().position(|&b| b == b'\n') {
+            // Take the line including the newline; this only advances the buffer's start
+            let line = self.buf.split_to(self.scanned + offset + 1);
+            self.scanned = 0;
 
-            // Take the line including the newline
-            let line_bytes: Vec<u8> = self.buf.drain(..=pos).collect();
-
-            // Trim newline and possible carriage return
-            let mut len = line_bytes.len();
-            if len > 0 && line_bytes[len - 1] == b'\n' {
-                len -= 1;
-                if len > 0 && line_bytes[len - 1] == b'\r' {
-                    len -= 1;
-                }
+            // Blank line => event terminator
+            if let Some(event) = self.push_line(trim_line_end(&line)) {
+                out.push(event);
             }
+        }
+        self.scanned = self.buf.len();
+        self.enforce_limit();
 
-            let trimmed = &line_bytes[..len];
+        out
+    }
 
-            // Blank line => event terminator
-            if trimmed.is_empty() {
-                if !self.cur_data_lines.is_empty() {
-                    let payload = self.cur_data_lines.join("\n");
-                    self.cur_data_lines.clear();
-                    out.push(payload);
+    /// Apply the buffer policy to what is left of the unfinished event, so a backend that never
+    /// ends its lines can't grow the buffer without bound
+    fn enforce_limit(&mut self) {
+        let pending = self.buf.len() + self.cur_data.len();
+        if pending <= self.limit.limit_bytes {
+            self.over_limit = false;
+            return;
+        }
+        let limit_kb = self.limit.limit_bytes / 1024;
+        match self.limit.policy {
+            SseBufferPolicy::Clear => {
+                log::warn!("⚠️  SSE event exceeded the {}KB buffer limit ({} bytes) - dropping it", limit_kb, pending);
+                self.limit_hits += 1;
+                self.reset();
+            }
+            SseBufferPolicy::Grow if pending <= self.limit.hard_cap_bytes => {
+                if !std::mem::replace(&mut self.over_limit, true) {
+                    log::warn!("⚠️  SSE event exceeded the {}KB buffer limit ({} bytes) - growing up to {}KB", limit_kb, pending, self.limit.hard_cap_bytes / 1024);
+                    self.limit_hits += 1;
+                }
+            }
+            SseBufferPolicy::Abort | SseBufferPolicy::Grow => {
+                log::warn!("⚠️  SSE event exceeded the buffer limit ({} bytes) - ending the stream", pending);
+                if !self.over_limit {
+                    self.limit_hits += 1;
                 }
-                continue;
+                self.aborted = true;
+                self.reset();
             }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buf = BytesMut::new();
+        self.scanned = 0;
+        self.cur_data = String::new();
+        self.has_data = false;
+        self.cur_event = None;
+    }
 
-            // Only collect `data:` lines, ignore others (e.g., `event:`/`id:`)
-            // Check for "data:" prefix (bytes: [100, 97, 116, 97, 58])
-            if trimmed.starts_with(b"data:") {
-                let data_content = &trimmed[5..];
-                // Convert safely to string now that we have full lines
-                // We use from_utf8_lossy here which is safe because we are at line boundaries
-                // (assuming SSE lines are valid UTF-8, which they should be)
-                let s = String::from_utf8_lossy(data_content).trim_start().to_string();
-                self.cur_data_lines.push(s);
+    /// Handle one complete line; returns the event when the line ends one
+    fn push_line(&mut self, line: &[u8]) -> Option<SseEvent> {
+        if line.is_empty() {
+            // An event without data isn't dispatched, and its name doesn't carry over
+            let event = self.cur_event.take();
+            if !std::mem::take(&mut self.has_data) {
+                return None;
             }
+            return Some(SseEvent { event, data: std::mem::take(&mut self.cur_data) });
         }
 
-        out
+        if let Some(name) = line.strip_prefix(b"event:") {
+            let name = String::from_utf8_lossy(name).trim().to_string();
+            self.cur_event = Some(name).filter(|n| !n.is_empty());
+            return None;
+        }
+        // Other fields (`id:`, `retry:`) and comments are ignored
+        let data = line.strip_prefix(b"data:")?;
+        if std::mem::replace(&mut self.has_data, true) {
+            self.cur_data.push('\n');
+        }
+        // Whole lines never end inside a UTF-8 sequence, so this only replaces genuinely invalid bytes
+        match std::str::from_utf8(data) {
+            Ok(text) => self.cur_data.push_str(text.trim_start()),
+            Err(_) => self.cur_data.push_str(String::from_utf8_lossy(data).trim_start()),
+        }
+        None
     }
 
     /// Flush at end-of-stream (if the server doesn't send a final blank line).
-    pub fn flush(mut self) -> Option<String> {
-        // If there is data in buf that doesn't end in newline, we should try to process it
+    pub fn flush(mut self) -> Option<SseEvent> {
+        // Process a remaining line that didn't end in a newline
         if !self.buf.is_empty() {
-             // Process remaining buffer as one last line
-             let line_bytes = std::mem::take(&mut self.buf);
-             let mut len = line_bytes.len();
-             // Trim logic (though unlikely to have trailing \n here due to loop condition)
-             if len > 0 && line_bytes[len - 1] == b'\n' {
-                len -= 1;
-                if len > 0 && line_bytes[len - 1] == b'\r' {
-                    len -= 1;
-                }
-             }
-             let trimmed = &line_bytes[..len];
-
-             if trimmed.starts_with(b"data:") {
-                 let data_content = &trimmed[5..];
-                 let s = String::from_utf8_lossy(data_content).trim_start().to_string();
-                 self.cur_data_lines.push(s);
-             }
+            let line = std::mem::take(&mut self.buf);
+            self.push_line(trim_line_end(&line));
+        }
+        self.push_line(b"")
+    }
+}
+
+/// Strip a trailing `\n` or `\r\n`
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Whether a backend streams its responses (`STREAMING`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum StreamingMode {
+    /// Send `stream: true` until the backend rejects it, then stop streaming that model for a while
+    #[default]
+    Auto,
+    On,
+    /// Send `stream: false` and replay the buffered completion as SSE
+    Off,
+}
+
+impl FromStr for StreamingMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(StreamingMode::Auto),
+            "on" | "true" => Ok(StreamingMode::On),
+            "off" | "false" => Ok(StreamingMode::Off),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Streaming support of one backend: configured, or detected per model in `auto` mode and shared
+/// by all requests to the backend. A detection lasts `STREAMING_FALLBACK_SECS`, so a backend
+/// that gains streaming support, or rejected it by mistake, is tried again
+#[derive(Debug, Clone, Default)]
+pub struct StreamingSupport {
+    mode: StreamingMode,
+    /// Models that rejected `stream: true`, with when they did
+    unsupported: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl StreamingSupport {
+    pub fn new(mode: StreamingMode) -> Self {
+        Self { mode, ..Default::default() }
+    }
+
+    /// Send `stream: true` to this backend for `model`
+    pub fn enabled(&self, model: &str) -> bool {
+        match self.mode {
+            StreamingMode::On => true,
+            StreamingMode::Off => false,
+            StreamingMode::Auto => {
+                let mut unsupported = self.unsupported.lock().unwrap_or_else(|e| e.into_inner());
+                unsupported.retain(|_, since| since.elapsed() < Duration::from_secs(STREAMING_FALLBACK_SECS));
+                !unsupported.contains_key(model)
+            }
         }
+    }
+
+    /// A rejected streaming request may be resent without streaming
+    pub fn can_fall_back(&self) -> bool {
+        self.mode == StreamingMode::Auto
+    }
+
+    /// Stop streaming `model` on this backend for `STREAMING_FALLBACK_SECS`; true unless it was
+    /// already stopped
+    pub fn mark_unsupported(&self, model: &str) -> bool {
+        if self.mode != StreamingMode::Auto {
+            return false;
+        }
+        let mut unsupported = self.unsupported.lock().unwrap_or_else(|e| e.into_inner());
+        let fresh = unsupported.get(model).is_none_or(|since| since.elapsed() >= Duration::from_secs(STREAMING_FALLBACK_SECS));
+        if fresh {
+            unsupported.insert(model.to_string(), Instant::now());
+        }
+        fresh
+    }
+}
+
+/// Whether a backend error response says `stream: true` isn't supported: an OpenAI-style error
+/// with `param: "stream"`, or one of the known wordings in `STREAMING_REJECTIONS`
+pub fn rejects_streaming(status: reqwest::StatusCode, body: &str) -> bool {
+    if !matches!(status.as_u16(), 400 | 422 | 501) {
+        return false;
+    }
+    let param = serde_json::from_str::<Value>(body).ok().and_then(|v| v["error"]["param"].as_str().map(str::to_string));
+    if param.as_deref() == Some("stream") {
+        return true;
+    }
+    let body = body.to_ascii_lowercase();
+    STREAMING_REJECTIONS.iter().any(|rejection| body.contains(rejection))
+}
+
+/// The backend answered with one `application/json` body instead of an SSE stream
+pub fn is_json_body(res: &reqwest::Response) -> bool {
+    res.headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"))
+}
+
+/// Backend response body as SSE bytes. Non-streamed responses (`stream: false`, or backends that
+/// ignore `stream: true`) are one `application/json` chat completion; that body is read whole, up
+/// to `BACKEND_JSON_BODY_MAX_BYTES`, and replayed as a single chunk.
+pub fn backend_event_stream(res: reqwest::Response) -> ChaosStream {
+    if !is_json_body(&res) {
+        return res.bytes_stream().boxed();
+    }
+    log::info!("📦 Backend answered with a JSON body - translating the whole completion");
+    futures::stream::once(async move {
+        let body = read_json_body(res, BACKEND_JSON_BODY_MAX_BYTES).await?;
+        Ok(body.map_or_else(|e| error_sse(&e), |body| completion_to_sse(&body)))
+    })
+    .boxed()
+}
 
-        if !self.cur_data_lines.is_empty() {
-            let payload = self.cur_data_lines.join("\n");
-            Some(payload)
-        } else {
-            None
+/// Read a JSON body, giving up with an error message once it grows past `max_bytes`
+async fn read_json_body(res: reqwest::Response, max_bytes: usize) -> reqwest::Result<Result<BytesMut, String>> {
+    let too_large = || {
+        log::warn!("⚠️  Backend JSON body exceeds {} bytes - not reading it", max_bytes);
+        Err(format!("backend response body exceeds {} bytes", max_bytes))
+    };
+    if res.content_length().is_some_and(|len| len > max_bytes as u64) {
+        return Ok(too_large());
+    }
+    let mut body = BytesMut::new();
+    let mut chunks = res.bytes_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > max_bytes {
+            return Ok(too_large());
         }
+        body.extend_from_slice(&chunk);
     }
+    Ok(Ok(body))
+}
+
+/// A backend `error` payload, which the translator turns into an error block
+fn error_sse(message: &str) -> Bytes {
+    Bytes::from(format!("data: {}\n\ndata: [DONE]\n\n", json!({ "error": { "message": message } })))
+}
+
+/// SSE payloads for a whole chat completion: each choice's `message` becomes its `delta`, and
+/// tool calls get the `index` that streamed tool call deltas carry, and `usage` follows in a
+/// final chunk as when streaming. Other bodies (such as an `error` object) are passed on as they are.
+pub fn completion_to_sse(body: &[u8]) -> Bytes {
+    let mut completion = match serde_json::from_slice::<Value>(body) {
+        Ok(value) => value,
+        Err(e) => {
+            log::warn!("⚠️  Backend JSON body is not valid JSON ({} bytes): {}", body.len(), e);
+            return error_sse(&format!("backend response is not valid JSON: {}", e));
+        }
+    };
+    for choice in completion.get_mut("choices").and_then(Value::as_array_mut).into_iter().flatten() {
+        let Some(mut message) = choice.as_object_mut().and_then(|c| c.remove("message")) else {
+            continue;
+        };
+        for (index, call) in message.get_mut("tool_calls").and_then(Value::as_array_mut).into_iter().flatten().enumerate() {
+            if call.get("index").is_none() {
+                call["index"] = json!(index);
+            }
+        }
+        choice["delta"] = message;
+    }
+    let mut sse = String::new();
+    let usage = completion.as_object_mut().and_then(|c| c.remove("usage"));
+    sse.push_str(&format!("data: {}\n\n", completion));
+    if let Some(usage) = usage {
+        sse.push_str(&format!("data: {}\n\n", json!({ "choices": [], "usage": usage })));
+    }
+    sse.push_str("data: [DONE]\n\n");
+    Bytes::from(sse)
 }
 
 #[derive(Clone)]
@@ -122,10 +410,266 @@ pub struct ToolBuf {
     pub has_sent_start: bool,
 }
 
-pub type ToolsMap = HashMap<usize, ToolBuf>;
+/// Tool call buffers by backend tool index; ordered so blocks are closed in the order they were opened
+pub type ToolsMap = BTreeMap<usize, ToolBuf>;
+
+/// What happens to `choices` past index 0 (`EXTRA_CHOICES`); backends only send them when
+/// configured to generate `n > 1` completions
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ExtraChoices {
+    /// Forward choice 0 only and warn about the rest
+    #[default]
+    Warn,
+    /// Also send the text of each extra choice as its own text block at the end of the message
+    Blocks,
+}
+
+impl FromStr for ExtraChoices {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "warn" => Ok(ExtraChoices::Warn),
+            "blocks" => Ok(ExtraChoices::Blocks),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Choices past index 0 seen in one response
+#[derive(Debug, Default)]
+pub struct ExtraChoiceBuffer {
+    mode: ExtraChoices,
+    /// Text per choice index; only collected in `Blocks` mode
+    texts: BTreeMap<usize, String>,
+    chunks: usize,
+}
+
+impl ExtraChoiceBuffer {
+    pub fn new(mode: ExtraChoices) -> Self {
+        Self { mode, ..Default::default() }
+    }
+
+    pub fn push(&mut self, choice: &OAIChoice) {
+        if self.chunks == 0 {
+            log::warn!(
+                "⚠️  Backend returned more than one choice (index {}); is it set to generate n > 1? {}",
+                choice.index,
+                match self.mode {
+                    ExtraChoices::Warn => "Only choice 0 is forwarded (EXTRA_CHOICES=warn)",
+                    ExtraChoices::Blocks => "Extra choices are appended as text blocks (EXTRA_CHOICES=blocks)",
+                }
+            );
+        }
+        self.chunks += 1;
+        let text = self.texts.entry(choice.index).or_default();
+        if self.mode == ExtraChoices::Blocks {
+            if let Some(content) = choice.delta.as_ref().and_then(|d| d.content.as_deref()) {
+                text.push_str(content);
+            } else if let Some(content) = choice.message.as_ref().and_then(|m| m.get("content")).and_then(|c| c.as_str()) {
+                text.push_str(content);
+            }
+        }
+    }
+
+    /// Text of each extra choice to emit, in index order (empty unless `Blocks`)
+    pub fn finish(self) -> Vec<String> {
+        if self.chunks > 0 {
+            log::warn!("⚠️  Backend sent {} chunk(s) for {} extra choice(s)", self.chunks, self.texts.len());
+        }
+        self.texts.into_values().filter(|text| !text.is_empty()).collect()
+    }
+}
+
+/// Claude SSE events for one response, built in one place.
+///
+/// Wraps the request's `EventSender`, so every event still passes through coalescing, splitting,
+/// thinking output and transforms. Block indexes are chosen by the caller. Every method returns
+/// `Err` once the client has disconnected.
+pub struct ClaudeSseEmitter {
+    tx: EventSender,
+}
+
+impl ClaudeSseEmitter {
+    pub fn new(tx: EventSender) -> Self {
+        Self { tx }
+    }
+
+    /// Message ID sent in `message_start`
+    pub fn message_id(&self) -> &str {
+        &self.tx.ctx.request_id
+    }
+
+    pub async fn message_start(&mut self, model: &str, input_tokens: u32) -> Result<(), ()> {
+        let message = json!({
+            "id": self.tx.ctx.request_id,
+            "type": "message",
+            "role": "assistant",
+            "content": [],
+            "model": model,
+            "stop_reason": Value::Null,
+            "stop_sequence": Value::Null,
+            "usage": {
+                "input_tokens": input_tokens,
+                "output_tokens": 0,
+                "cache_creation_input_tokens": 0,
+                "cache_read_input_tokens": 0
+            }
+        });
+        self.tx.send("message_start", json!({ "type": "message_start", "message": message })).await
+    }
+
+    /// Open a block; `content_block` is sent as is (empty `text`/`thinking`, `tool_use` with `input: {}`, ...)
+    pub async fn block_start(&mut self, index: i32, content_block: Value) -> Result<(), ()> {
+        let event = json!({ "type": "content_block_start", "index": index, "content_block": content_block });
+        self.tx.send("content_block_start", event).await
+    }
+
+    pub async fn text_start(&mut self, index: i32) -> Result<(), ()> {
+        self.block_start(index, json!({ "type": "text", "text": "" })).await
+    }
+
+    pub async fn thinking_start(&mut self, index: i32) -> Result<(), ()> {
+        self.block_start(index, json!({ "type": "thinking", "thinking": "" })).await
+    }
+
+    /// Open a `tool_use` or `server_tool_use` block; the input follows as `input_json_delta`
+    pub async fn tool_start(&mut self, index: i32, block_type: &str, id: &str, name: &str) -> Result<(), ()> {
+        self.block_start(index, json!({ "type": block_type, "id": id, "name": name, "input": {} })).await
+    }
+
+    async fn delta(&mut self, index: i32, delta: BlockDelta<'_>) -> Result<(), ()> {
+        let event = StreamEvent::block_delta(index as u64, delta);
+        self.tx.send(event.event, event.data).await
+    }
+
+    pub async fn text_delta(&mut self, index: i32, text: &str) -> Result<(), ()> {
+        self.delta(index, BlockDelta::Text { text }).await
+    }
+
+    pub async fn thinking_delta(&mut self, index: i32, thinking: &str) -> Result<(), ()> {
+        self.delta(index, BlockDelta::Thinking { thinking }).await
+    }
+
+    pub async fn input_json_delta(&mut self, index: i32, partial_json: &str) -> Result<(), ()> {
+        self.delta(index, BlockDelta::InputJson { partial_json }).await
+    }
+
+    pub async fn citation_delta(&mut self, index: i32, citation: Value) -> Result<(), ()> {
+        self.delta(index, BlockDelta::Citations { citation }).await
+    }
+
+    /// Non-standard `proxy_debug` event, only sent to clients that asked for it (`x-proxy-debug`)
+    pub async fn debug(&mut self, event: Value) -> Result<(), ()> {
+        self.tx.send(DEBUG_EVENT, event).await
+    }
+
+    pub async fn block_stop(&mut self, index: i32) -> Result<(), ()> {
+        self.tx.send("content_block_stop", json!({ "type": "content_block_stop", "index": index })).await
+    }
+
+    /// A complete text block
+    pub async fn text_block(&mut self, index: i32, text: &str) -> Result<(), ()> {
+        self.text_start(index).await?;
+        self.text_delta(index, text).await?;
+        self.block_stop(index).await
+    }
+
+    /// A complete `tool_use` or `server_tool_use` block with its whole input in one delta
+    pub async fn tool_block(&mut self, index: i32, block_type: &str, id: &str, name: &str, input: &Value) -> Result<(), ()> {
+        self.tool_start(index, block_type, id, name).await?;
+        self.input_json_delta(index, &input.to_string()).await?;
+        self.block_stop(index).await
+    }
+
+    /// A block sent whole in `content_block_start`, such as a server tool result
+    pub async fn whole_block(&mut self, index: i32, content_block: Value) -> Result<(), ()> {
+        self.block_start(index, content_block).await?;
+        self.block_stop(index).await
+    }
+
+    /// `usage` is merged into the event as is; it should carry at least `output_tokens`
+    /// `stream_errors`, when there were any, go in a non-standard `proxy_stream_errors` field
+    pub async fn message_delta(
+        &mut self,
+        stop_reason: &str,
+        stop_sequence: Option<&str>,
+        usage: Value,
+        stream_errors: &BTreeMap<StreamErrorKind, u32>,
+    ) -> Result<(), ()> {
+        let mut event = json!({
+            "type": "message_delta",
+            "delta": { "stop_reason": stop_reason, "stop_sequence": stop_sequence },
+            "usage": usage
+        });
+        if !stream_errors.is_empty() {
+            event["proxy_stream_errors"] = json!(stream_errors);
+        }
+        self.tx.send("message_delta", event).await
+    }
+
+    pub async fn message_stop(&mut self) -> Result<(), ()> {
+        self.tx.send("message_stop", json!({ "type": "message_stop" })).await
+    }
+
+    /// A whole response consisting of one text block, for answers the proxy writes itself
+    pub async fn text_message(&mut self, model: &str, input_tokens: u32, text: &str, stop_reason: &str, output_tokens: u32) -> Result<(), ()> {
+        self.message_start(model, input_tokens).await?;
+        self.text_block(0, text).await?;
+        self.message_delta(stop_reason, None, json!({ "output_tokens": output_tokens }), &BTreeMap::new()).await?;
+        self.message_stop().await
+    }
+
+    /// Send any coalesced delta now
+    pub async fn flush(&mut self) -> Result<(), ()> {
+        self.tx.flush().await
+    }
+
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        self.tx.flush_deadline()
+    }
+
+    pub async fn complete(&mut self, summary: &CompletionSummary) {
+        self.tx.complete(summary).await
+    }
+}
+
+/// How draining the rest of a backend stream ended
+#[derive(Debug, PartialEq)]
+pub enum DrainOutcome {
+    /// The backend closed the stream within budget
+    Finished { bytes: usize },
+    /// The byte or time budget ran out; dropping the stream aborts the request
+    BudgetExceeded { bytes: usize },
+}
+
+/// Read what's left of a backend stream after translation finished, so a completed generation
+/// isn't seen as a cancellation, but give up after `max_bytes` or `timeout`.
+pub async fn drain_with_budget<S, B, E>(stream: &mut S, max_bytes: usize, timeout: Duration) -> DrainOutcome
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    let mut bytes = 0;
+    let drain = async {
+        while let Some(item) = stream.next().await {
+            if let Ok(chunk) = item {
+                bytes += chunk.as_ref().len();
+                if bytes > max_bytes {
+                    return false;
+                }
+            }
+        }
+        true
+    };
+    match tokio::time::timeout(timeout, drain).await {
+        Ok(true) => DrainOutcome::Finished { bytes },
+        _ => DrainOutcome::BudgetExceeded { bytes },
+    }
+}
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     // ============================================================================
@@ -200,6 +744,17 @@ mod tests {
         assert_eq!(events[0], "payload");
     }
 
+    #[test]
+    fn test_sse_parser_keeps_event_names() {
+        let mut parser = SseEventParser::new();
+        let events = parser.push_and_drain(
+            b"event: message_start\ndata: {\"a\":1}\n\nevent: ping\n\ndata: unnamed\n\nevent:error\r\ndata: boom\r\n\n",
+        );
+        let named = |event: Option<&str>, data: &str| SseEvent { event: event.map(str::to_string), data: data.to_string() };
+        // A name without data is dropped along with its event
+        assert_eq!(events, [named(Some("message_start"), r#"{"a":1}"#), named(None, "unnamed"), named(Some("error"), "boom")]);
+    }
+
     #[test]
     fn test_sse_parser_empty_data() {
         let mut parser = SseEventParser::new();
@@ -273,7 +828,7 @@ mod tests {
         let _ = parser.push_and_drain_events(b"data: incomplete\n");
 
         // flush() consumes the parser and returns accumulated data lines
-        let flushed = parser.flush();
+        let flushed = parser.flush().map(|e| e.data);
         // The "data: incomplete\n" was parsed, data line was accumulated
         assert_eq!(flushed, Some("incomplete".to_string()));
     }
@@ -285,7 +840,7 @@ mod tests {
         let _ = parser.push_and_drain_events(b"data: partial");
 
         // flush() handles the remaining bytes
-        let flushed = parser.flush();
+        let flushed = parser.flush().map(|e| e.data);
         assert_eq!(flushed, Some("partial".to_string()));
     }
 
@@ -323,8 +878,8 @@ mod tests {
     fn test_sse_parser_buffer_limit_exceeded() {
         let mut parser = SseEventParser::new();
 
-        // Create a chunk that exceeds MAX_BUFFER_SIZE (1MB)
-        let huge_data = vec![b'x'; MAX_BUFFER_SIZE + 1000];
+        // Create a chunk that exceeds the default 1MB limit
+        let huge_data = vec![b'x'; DEFAULT_SSE_BUFFER_LIMIT_KB * 1024 + 1000];
 
         // This should trigger the buffer clear warning
         let events = parser.push_and_drain_events(&huge_data);
@@ -334,6 +889,37 @@ mod tests {
         assert_eq!(events.len(), 0);
     }
 
+    #[test]
+    fn test_sse_parser_buffer_policies() {
+        let limit = |policy| SseBufferLimit { policy, limit_bytes: 64, hard_cap_bytes: 128 };
+        let big = format!("data: {}", "x".repeat(100));
+
+        // Clear drops the oversized event and keeps parsing
+        let mut parser = SseEventParser::with_limit(limit(SseBufferPolicy::Clear));
+        assert_eq!(parser.push_and_drain_events(format!("data: a\n\n{}", big).as_bytes()), ["a"]);
+        assert_eq!(parser.push_and_drain_events(b"\n\ndata: b\n\n"), ["b"]);
+        assert_eq!((parser.take_limit_hits(), parser.aborted()), (1, false));
+
+        // Abort delivers the complete events before the oversized one, then nothing more
+        let mut parser = SseEventParser::with_limit(limit(SseBufferPolicy::Abort));
+        assert_eq!(parser.push_and_drain_events(format!("data: a\n\n{}", big).as_bytes()), ["a"]);
+        assert!(parser.aborted());
+        assert!(parser.push_and_drain_events(b"\n\ndata: b\n\n").is_empty());
+        assert_eq!(parser.take_limit_hits(), 1);
+
+        // Grow keeps the event intact below the hard cap, counting the limit once
+        let mut parser = SseEventParser::with_limit(limit(SseBufferPolicy::Grow));
+        assert!(parser.push_and_drain_events(big.as_bytes()).is_empty());
+        assert!(parser.push_and_drain_events(b"yy").is_empty());
+        assert_eq!(parser.push_and_drain_events(b"\n\n")[0].len(), 102);
+        assert_eq!((parser.take_limit_hits(), parser.aborted()), (1, false));
+        assert!(parser.push_and_drain_events(format!("{}{}", big, big).as_bytes()).is_empty());
+        assert_eq!((parser.take_limit_hits(), parser.aborted()), (1, true));
+
+        assert_eq!("ABORT".parse::<SseBufferPolicy>(), Ok(SseBufferPolicy::Abort));
+        assert!("truncate".parse::<SseBufferPolicy>().is_err());
+    }
+
     #[test]
     fn test_sse_parser_real_world_openai_chunk() {
         let mut parser = SseEventParser::new();
@@ -401,4 +987,200 @@ mod tests {
         assert_eq!(events2.len(), 1);
         assert_eq!(events2[0], "price: €");
     }
+
+    #[test]
+    fn test_sse_parser_line_split_over_many_chunks() {
+        let mut parser = SseEventParser::new();
+        let input = b"data: {\"a\":1}\r\n\r\ndata: tail\n\n";
+        let mut events = Vec::new();
+        for byte in input.chunks(1) {
+            events.extend(parser.push_and_drain_events(byte));
+        }
+        assert_eq!(events, vec![r#"{"a":1}"#.to_string(), "tail".to_string()]);
+    }
+
+    #[test]
+    fn test_sse_parser_invalid_utf8_is_replaced() {
+        let mut parser = SseEventParser::new();
+        let events = parser.push_and_drain_events(b"data: a\xffb\n\n");
+        assert_eq!(events, vec!["a\u{FFFD}b".to_string()]);
+    }
+
+    // ============================================================================
+    // Drain budget tests
+    // ============================================================================
+
+    fn chunks(sizes: &[usize]) -> Vec<Result<Vec<u8>, ()>> {
+        sizes.iter().map(|n| Ok(vec![b'x'; *n])).collect()
+    }
+
+    #[tokio::test]
+    async fn test_drain_finishes_within_budget() {
+        let mut stream = futures::stream::iter(chunks(&[10, 20]));
+        let outcome = drain_with_budget(&mut stream, 100, Duration::from_secs(1)).await;
+        assert_eq!(outcome, DrainOutcome::Finished { bytes: 30 });
+    }
+
+    #[tokio::test]
+    async fn test_drain_stops_at_byte_budget() {
+        let mut stream = futures::stream::iter(chunks(&[60, 60, 60]));
+        let outcome = drain_with_budget(&mut stream, 100, Duration::from_secs(1)).await;
+        assert_eq!(outcome, DrainOutcome::BudgetExceeded { bytes: 120 });
+    }
+
+    #[tokio::test]
+    async fn test_drain_stops_at_time_budget() {
+        let mut stream = futures::stream::iter(chunks(&[5])).chain(futures::stream::pending());
+        let outcome = drain_with_budget(&mut stream, 100, Duration::from_millis(20)).await;
+        assert_eq!(outcome, DrainOutcome::BudgetExceeded { bytes: 5 });
+    }
+
+    #[test]
+    fn test_streaming_support_fallback() {
+        let auto = StreamingSupport::new(StreamingMode::Auto);
+        let shared = auto.clone();
+        assert!(auto.enabled("m") && auto.can_fall_back());
+        assert!(shared.mark_unsupported("m"));
+        assert!(!auto.mark_unsupported("m"), "only the first detection is reported");
+        assert!(!auto.enabled("m"), "detection is shared by clones of the backend");
+        assert!(auto.enabled("other"), "detection is per model");
+
+        auto.unsupported.lock().unwrap().insert("m".into(), Instant::now() - Duration::from_secs(STREAMING_FALLBACK_SECS));
+        assert!(auto.enabled("m"), "detection expires");
+
+        let on = StreamingSupport::new(StreamingMode::On);
+        assert!(!on.mark_unsupported("m") && on.enabled("m") && !on.can_fall_back());
+        assert!(!StreamingSupport::new(StreamingMode::Off).enabled("m"));
+        assert_eq!("OFF".parse(), Ok(StreamingMode::Off));
+    }
+
+    #[test]
+    fn test_rejects_streaming() {
+        let bad_request = reqwest::StatusCode::BAD_REQUEST;
+        assert!(rejects_streaming(bad_request, r#"{"error":{"message":"Streaming is not supported for this model"}}"#));
+        assert!(rejects_streaming(reqwest::StatusCode::UNPROCESSABLE_ENTITY, "stream: invalid value true"));
+        assert!(rejects_streaming(bad_request, r#"{"error":{"message":"Unsupported value","param":"stream"}}"#));
+        assert!(!rejects_streaming(bad_request, "max_tokens is too large"));
+        assert!(!rejects_streaming(bad_request, r#"{"error":{"message":"Invalid stream_options: include_usage is not supported"}}"#));
+        assert!(!rejects_streaming(bad_request, "upstream returned an invalid response"));
+        assert!(!rejects_streaming(reqwest::StatusCode::TOO_MANY_REQUESTS, "streaming not supported"));
+    }
+
+    #[test]
+    fn test_completion_to_sse() {
+        let body = json!({
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": "Sure.",
+                    "reasoning_content": "thinking",
+                    "tool_calls": [
+                        {"id": "call_a", "type": "function", "function": {"name": "a", "arguments": "{}"}},
+                        {"id": "call_b", "type": "function", "function": {"name": "b", "arguments": "{}"}}
+                    ]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 9}
+        });
+        let sse = completion_to_sse(body.to_string().as_bytes());
+        let events = SseEventParser::new().push_and_drain_events(&sse);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[2], "[DONE]");
+
+        let chunk: crate::models::OAIStreamChunk = serde_json::from_str(&events[0]).unwrap();
+        let delta = chunk.choices[0].delta.as_ref().unwrap();
+        assert_eq!(delta.content.as_deref(), Some("Sure."));
+        assert_eq!(delta.reasoning_text().as_deref(), Some("thinking"));
+        let indexes: Vec<_> = delta.tool_calls.iter().flatten().map(|tc| tc.index).collect();
+        assert_eq!(indexes, [Some(0), Some(1)]);
+        assert_eq!(chunk.choices[0].finish_reason.as_deref(), Some("tool_calls"));
+        assert!(chunk.choices[0].message.is_none());
+        let usage: crate::models::OAIStreamChunk = serde_json::from_str(&events[1]).unwrap();
+        assert_eq!(usage.usage.unwrap().completion_tokens, Some(9));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_or_oversized_json_body_becomes_an_error() {
+        let sse = completion_to_sse(b"{\"choices\": [");
+        let events = SseEventParser::new().push_and_drain_events(&sse);
+        assert!(serde_json::from_str::<Value>(&events[0]).unwrap()["error"]["message"].as_str().unwrap().starts_with("backend response is not valid JSON"));
+        assert_eq!(events[1], "[DONE]");
+
+        let res: reqwest::Response = axum::http::Response::builder()
+            .header("content-type", "application/json")
+            .body(vec![b' '; 64])
+            .unwrap()
+            .into();
+        assert_eq!(read_json_body(res, 32).await.unwrap(), Err("backend response body exceeds 32 bytes".to_string()));
+    }
+
+    #[test]
+    fn test_extra_choice_buffer() {
+        let choice = |index: usize, text: &str| -> OAIChoice {
+            serde_json::from_value(serde_json::json!({ "index": index, "delta": { "content": text } })).unwrap()
+        };
+        let mut blocks = ExtraChoiceBuffer::new(ExtraChoices::Blocks);
+        for (index, text) in [(2, "b"), (1, "a"), (2, "c")] {
+            blocks.push(&choice(index, text));
+        }
+        assert_eq!(blocks.finish(), ["a", "bc"]);
+
+        let mut warn = ExtraChoiceBuffer::new(ExtraChoices::Warn);
+        warn.push(&choice(1, "a"));
+        assert!(warn.finish().is_empty());
+    }
+
+    /// Records the events reaching the transform chain
+    pub(crate) struct Recorder(std::sync::Mutex<Vec<(&'static str, Value)>>);
+
+    impl Recorder {
+        pub(crate) fn events(&self) -> Vec<(&'static str, Value)> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl crate::services::Transform for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn on_stream_event<'a>(
+            &'a self,
+            _ctx: &'a mut crate::services::TransformContext,
+            event: &'a mut crate::services::StreamEvent,
+        ) -> futures::future::BoxFuture<'a, ()> {
+            self.0.lock().unwrap().push((event.event, event.data.clone()));
+            Box::pin(async {})
+        }
+    }
+
+    /// Emitter for message `msg_1` whose events are recorded; keep the receiver alive while sending
+    pub(crate) fn recording_emitter() -> (ClaudeSseEmitter, std::sync::Arc<Recorder>, tokio::sync::mpsc::Receiver<axum::response::sse::Event>) {
+        let recorder = std::sync::Arc::new(Recorder(Default::default()));
+        let mut chain = crate::services::TransformChain::default();
+        chain.register(recorder.clone());
+        let (tx, rx) = tokio::sync::mpsc::channel(256);
+        let ctx = crate::services::TransformContext::new("msg_1".into(), "model".into());
+        (ClaudeSseEmitter::new(EventSender::new(tx, std::sync::Arc::new(chain), ctx)), recorder, rx)
+    }
+
+    #[tokio::test]
+    async fn test_sse_emitter_text_message() {
+        let (mut sse, recorder, _rx) = recording_emitter();
+
+        sse.text_message("model", 12, "hello", "end_turn", 3).await.unwrap();
+        let events = recorder.events();
+        let names: Vec<&str> = events.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            ["message_start", "content_block_start", "content_block_delta", "content_block_stop", "message_delta", "message_stop"]
+        );
+        assert_eq!(events[0].1["message"]["id"], "msg_1");
+        assert_eq!(events[0].1["message"]["usage"]["input_tokens"], 12);
+        assert_eq!(events[2].1, json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "hello"}}));
+        assert_eq!(events[4].1["delta"], json!({"stop_reason": "end_turn", "stop_sequence": null}));
+        assert_eq!(events[4].1["usage"], json!({"output_tokens": 3}));
+    }
 }
@@ -0,0 +1,95 @@
+//! systemd integration: `sd_notify` readiness signaling and socket activation (`LISTEN_FDS`).
+//!
+//! Both are no-ops when the proxy isn't started by systemd, and on non-Unix platforms.
+use std::env;
+
+/// First file descriptor passed by systemd socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Send a state update (e.g. `READY=1`, `STOPPING=1`) to `NOTIFY_SOCKET`. Returns whether it was sent.
+#[cfg(unix)]
+pub fn notify(state: &str) -> bool {
+    use std::os::unix::net::UnixDatagram;
+    let Some(path) = env::var_os("NOTIFY_SOCKET").filter(|p| !p.is_empty()) else {
+        return false;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let path = path.to_string_lossy();
+        match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            _ => socket.send_to(state.as_bytes(), path.as_ref()),
+        }
+    });
+    match result {
+        Ok(_) => true,
+        Err(e) => {
+            log::warn!("⚠️  Failed to notify systemd ({}): {}", state, e);
+            false
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> bool {
+    false
+}
+
+/// Number of sockets passed to this process, per `LISTEN_PID` / `LISTEN_FDS`
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, own_pid: u32) -> usize {
+    if listen_pid.and_then(|p| p.trim().parse::<u32>().ok()) != Some(own_pid) {
+        return 0;
+    }
+    listen_fds.and_then(|n| n.trim().parse().ok()).unwrap_or(0)
+}
+
+/// Variables of the socket activation protocol, kept from child processes
+pub const ACTIVATION_VARS: &[&str] = &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+/// Take the first socket passed by systemd socket activation, if any.
+///
+/// The environment is left alone, since changing it races with other threads; child processes
+/// get the activation variables removed instead (`ACTIVATION_VARS`), and `LISTEN_PID` wouldn't
+/// match them anyway. Needs a Tokio runtime.
+#[cfg(unix)]
+pub fn take_listener() -> std::io::Result<Option<tokio::net::TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+    let count = listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    if count == 0 {
+        return Ok(None);
+    }
+    if count > 1 {
+        log::warn!("⚠️  systemd passed {} sockets; only the first is used", count);
+    }
+    // SAFETY: systemd guarantees fds SD_LISTEN_FDS_START.. are open and owned by this process
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(listener).map(Some)
+}
+
+#[cfg(not(unix))]
+pub fn take_listener() -> std::io::Result<Option<tokio::net::TcpListener>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds_requires_matching_pid() {
+        assert_eq!(listen_fds(Some("42"), Some("1"), 42), 1);
+        assert_eq!(listen_fds(Some("41"), Some("1"), 42), 0);
+        assert_eq!(listen_fds(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("x"), 42), 0);
+    }
+}
@@ -0,0 +1,392 @@
+use std::{collections::HashSet, str::FromStr};
+use serde_json::{json, Value};
+use crate::models::OAIChatReq;
+use crate::services::transform::{BlockDelta, StreamEvent};
+use crate::utils::model_matches_pattern;
+
+/// How Claude's `thinking` request field is expressed to the backend
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ThinkingDialect {
+    /// Forward `thinking: {type, budget_tokens}` verbatim (previous behaviour)
+    #[default]
+    Anthropic,
+    /// vLLM / SGLang with Qwen-style templates: `chat_template_kwargs: {enable_thinking: true}`
+    ChatTemplateKwargs,
+    /// OpenRouter: `reasoning: {max_tokens}`
+    OpenRouter,
+    /// OpenAI: `reasoning_effort: low|medium|high`, derived from the budget
+    OpenAI,
+    /// Drop the field entirely
+    None,
+}
+
+impl FromStr for ThinkingDialect {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "anthropic" => Ok(ThinkingDialect::Anthropic),
+            "chat_template_kwargs" => Ok(ThinkingDialect::ChatTemplateKwargs),
+            "openrouter" => Ok(ThinkingDialect::OpenRouter),
+            "openai" | "reasoning_effort" => Ok(ThinkingDialect::OpenAI),
+            "none" => Ok(ThinkingDialect::None),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Per-model dialect overrides from `THINKING_DIALECT_MODELS` (`pattern=dialect`, `*` suffix wildcard)
+pub fn parse_dialect_overrides(entries: &[String]) -> Vec<(String, ThinkingDialect)> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(pattern, dialect)| Some((pattern.trim().to_ascii_lowercase(), dialect.parse().ok()?)));
+            if parsed.is_none() {
+                log::warn!("⚠️  Ignoring malformed THINKING_DIALECT_MODELS entry '{}'", entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Pick the dialect for `model`: first matching override, otherwise the backend default
+pub fn dialect_for_model(model: &str, overrides: &[(String, ThinkingDialect)], default: ThinkingDialect) -> ThinkingDialect {
+    overrides
+        .iter()
+        .find(|(pattern, _)| model_matches_pattern(model, pattern))
+        .map(|(_, dialect)| *dialect)
+        .unwrap_or(default)
+}
+
+/// Map a thinking budget to an OpenAI reasoning effort level
+fn effort_for_budget(budget_tokens: u64) -> &'static str {
+    match budget_tokens {
+        0..4096 => "low",
+        4096..16384 => "medium",
+        _ => "high",
+    }
+}
+
+/// Rewrite `oai.thinking` into the backend's dialect
+pub fn apply_thinking_dialect(oai: &mut OAIChatReq, dialect: ThinkingDialect) {
+    if dialect == ThinkingDialect::Anthropic {
+        return;
+    }
+    let Some(thinking) = oai.thinking.take() else {
+        return;
+    };
+    if thinking["type"].as_str() == Some("disabled") {
+        return;
+    }
+    let budget = thinking["budget_tokens"].as_u64();
+    match dialect {
+        ThinkingDialect::Anthropic | ThinkingDialect::None => {}
+        ThinkingDialect::ChatTemplateKwargs => {
+            let kwargs = oai.chat_template_kwargs.get_or_insert_with(|| json!({}));
+            kwargs["enable_thinking"] = Value::Bool(true);
+        }
+        ThinkingDialect::OpenRouter => {
+            oai.reasoning = Some(match budget {
+                Some(max_tokens) => json!({ "max_tokens": max_tokens }),
+                None => json!({ "enabled": true }),
+            });
+        }
+        ThinkingDialect::OpenAI => {
+            oai.reasoning_effort = Some(budget.map(effort_for_budget).unwrap_or("medium").to_string());
+        }
+    }
+}
+
+/// What happens to streamed thinking once `budget_tokens` is used up (`THINKING_BUDGET_ENFORCEMENT`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum BudgetEnforcement {
+    /// Forward all thinking regardless of budget (previous behaviour)
+    #[default]
+    Off,
+    /// Drop thinking deltas past the budget
+    Silent,
+    /// Drop thinking deltas past the budget after a one-line truncation marker
+    Marker,
+}
+
+impl FromStr for BudgetEnforcement {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(BudgetEnforcement::Off),
+            "silent" => Ok(BudgetEnforcement::Silent),
+            "marker" => Ok(BudgetEnforcement::Marker),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Decision for one streamed thinking delta
+#[derive(Debug, PartialEq)]
+pub enum BudgetVerdict {
+    Forward,
+    /// Budget just ran out: send this marker instead of the delta
+    Marker(String),
+    Drop,
+}
+
+/// Tracks approximate thinking tokens against the request's `budget_tokens`
+pub struct ThinkingBudget {
+    budget: u32,
+    used: u32,
+    mode: BudgetEnforcement,
+    exhausted: bool,
+}
+
+impl ThinkingBudget {
+    /// `None` when there is nothing to enforce
+    pub fn new(budget_tokens: Option<u32>, mode: BudgetEnforcement) -> Option<Self> {
+        if mode == BudgetEnforcement::Off {
+            return None;
+        }
+        Some(Self {
+            budget: budget_tokens?,
+            used: 0,
+            mode,
+            exhausted: false,
+        })
+    }
+
+    pub fn admit(&mut self, tokens: u32) -> BudgetVerdict {
+        if self.exhausted {
+            return BudgetVerdict::Drop;
+        }
+        if self.used + tokens <= self.budget {
+            self.used += tokens;
+            return BudgetVerdict::Forward;
+        }
+        self.exhausted = true;
+        log::info!("🧠 OUTPUT: Thinking budget of {} tokens exceeded, truncating reasoning", self.budget);
+        match self.mode {
+            BudgetEnforcement::Marker => BudgetVerdict::Marker(format!(
+                "\n\n[Thinking budget of {} tokens exceeded, truncating reasoning]",
+                self.budget
+            )),
+            _ => BudgetVerdict::Drop,
+        }
+    }
+}
+
+/// How thinking blocks reach the client (`THINKING_OUTPUT`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ThinkingOutput {
+    /// Claude thinking blocks (previous behaviour)
+    #[default]
+    Blocks,
+    /// Remove thinking entirely
+    Drop,
+    /// Visible text blocks fenced with `<thinking>` markers, for clients that can't render thinking
+    Text,
+}
+
+impl FromStr for ThinkingOutput {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "blocks" => Ok(ThinkingOutput::Blocks),
+            "drop" => Ok(ThinkingOutput::Drop),
+            "text" => Ok(ThinkingOutput::Text),
+            _ => Err(()),
+        }
+    }
+}
+
+const THINKING_TEXT_OPEN: &str = "<thinking>\n";
+const THINKING_TEXT_CLOSE: &str = "\n</thinking>\n\n";
+
+/// Rewrites outgoing events according to a non-default `ThinkingOutput` mode.
+///
+/// Dropped blocks are removed from the stream and later block indices are shifted down
+/// so the client still sees contiguous indices.
+pub struct ThinkingFilter {
+    mode: ThinkingOutput,
+    thinking_blocks: HashSet<u64>,
+    dropped: Vec<u64>,
+}
+
+impl ThinkingFilter {
+    /// `None` for the default mode, which needs no rewriting
+    pub fn new(mode: ThinkingOutput) -> Option<Self> {
+        (mode != ThinkingOutput::Blocks).then(|| Self {
+            mode,
+            thinking_blocks: HashSet::new(),
+            dropped: Vec::new(),
+        })
+    }
+
+    pub fn apply(&mut self, mut event: StreamEvent) -> Vec<StreamEvent> {
+        let Some(index) = event.data["index"].as_u64() else {
+            return vec![event];
+        };
+
+        if event.event == "content_block_start" {
+            let block_type = event.data["content_block"]["type"].as_str().unwrap_or_default();
+            if block_type == "thinking" || block_type == "redacted_thinking" {
+                self.thinking_blocks.insert(index);
+                if self.mode == ThinkingOutput::Drop {
+                    self.dropped.push(index);
+                    return Vec::new();
+                }
+                event.data["content_block"] = json!({ "type": "text", "text": "" });
+                let index = self.remap(index);
+                event.data["index"] = json!(index);
+                return vec![event, text_delta(index, THINKING_TEXT_OPEN)];
+            }
+        }
+
+        if self.thinking_blocks.contains(&index) {
+            if self.mode == ThinkingOutput::Drop {
+                return Vec::new();
+            }
+            let index = self.remap(index);
+            return match event.event {
+                "content_block_delta" => match event.data["delta"]["thinking"].as_str() {
+                    Some(text) => vec![text_delta(index, text)],
+                    // signature_delta has no text equivalent
+                    None => Vec::new(),
+                },
+                "content_block_stop" => {
+                    event.data["index"] = json!(index);
+                    vec![text_delta(index, THINKING_TEXT_CLOSE), event]
+                }
+                _ => vec![event],
+            };
+        }
+
+        event.data["index"] = json!(self.remap(index));
+        vec![event]
+    }
+
+    fn remap(&self, index: u64) -> u64 {
+        index - self.dropped.iter().filter(|&&d| d < index).count() as u64
+    }
+}
+
+fn text_delta(index: u64, text: &str) -> StreamEvent {
+    StreamEvent::block_delta(index, BlockDelta::Text { text })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> OAIChatReq {
+        OAIChatReq {
+            model: "m".into(),
+            thinking: Some(json!({"type": "enabled", "budget_tokens": 8000})),
+            stream: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_chat_template_kwargs_dialect() {
+        let mut req = request();
+        apply_thinking_dialect(&mut req, ThinkingDialect::ChatTemplateKwargs);
+        assert!(req.thinking.is_none());
+        assert_eq!(req.chat_template_kwargs, Some(json!({"enable_thinking": true})));
+    }
+
+    #[test]
+    fn test_openrouter_dialect_keeps_budget() {
+        let mut req = request();
+        apply_thinking_dialect(&mut req, ThinkingDialect::OpenRouter);
+        assert_eq!(req.reasoning, Some(json!({"max_tokens": 8000})));
+    }
+
+    #[test]
+    fn test_openai_dialect_maps_budget_to_effort() {
+        let mut req = request();
+        apply_thinking_dialect(&mut req, ThinkingDialect::OpenAI);
+        assert_eq!(req.reasoning_effort.as_deref(), Some("medium"));
+        assert_eq!(effort_for_budget(1024), "low");
+        assert_eq!(effort_for_budget(32000), "high");
+    }
+
+    #[test]
+    fn test_none_and_anthropic_dialects() {
+        let mut req = request();
+        apply_thinking_dialect(&mut req, ThinkingDialect::Anthropic);
+        assert!(req.thinking.is_some());
+        apply_thinking_dialect(&mut req, ThinkingDialect::None);
+        assert!(req.thinking.is_none());
+        assert!(req.reasoning.is_none() && req.reasoning_effort.is_none() && req.chat_template_kwargs.is_none());
+    }
+
+    #[test]
+    fn test_budget_forwards_until_exhausted() {
+        let mut budget = ThinkingBudget::new(Some(10), BudgetEnforcement::Marker).unwrap();
+        assert_eq!(budget.admit(6), BudgetVerdict::Forward);
+        assert_eq!(budget.admit(4), BudgetVerdict::Forward);
+        assert!(matches!(budget.admit(1), BudgetVerdict::Marker(m) if m.contains("10 tokens")));
+        assert_eq!(budget.admit(1), BudgetVerdict::Drop);
+    }
+
+    #[test]
+    fn test_silent_budget_and_disabled_enforcement() {
+        let mut budget = ThinkingBudget::new(Some(1), BudgetEnforcement::Silent).unwrap();
+        assert_eq!(budget.admit(2), BudgetVerdict::Drop);
+        assert!(ThinkingBudget::new(Some(1), BudgetEnforcement::Off).is_none());
+        assert!(ThinkingBudget::new(None, BudgetEnforcement::Marker).is_none());
+    }
+
+    fn block_events() -> Vec<StreamEvent> {
+        let ev = |event: &'static str, data: Value| StreamEvent { event, data };
+        vec![
+            ev("content_block_start", json!({"type": "content_block_start", "index": 0, "content_block": {"type": "thinking", "thinking": ""}})),
+            ev("content_block_delta", json!({"type": "content_block_delta", "index": 0, "delta": {"type": "thinking_delta", "thinking": "hmm"}})),
+            ev("content_block_stop", json!({"type": "content_block_stop", "index": 0})),
+            ev("content_block_start", json!({"type": "content_block_start", "index": 1, "content_block": {"type": "text", "text": ""}})),
+            ev("content_block_delta", json!({"type": "content_block_delta", "index": 1, "delta": {"type": "text_delta", "text": "answer"}})),
+            ev("content_block_stop", json!({"type": "content_block_stop", "index": 1})),
+            ev("message_stop", json!({"type": "message_stop"})),
+        ]
+    }
+
+    #[test]
+    fn test_drop_mode_removes_thinking_and_renumbers() {
+        let mut filter = ThinkingFilter::new(ThinkingOutput::Drop).unwrap();
+        let out: Vec<StreamEvent> = block_events().into_iter().flat_map(|e| filter.apply(e)).collect();
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[0].data["index"], 0);
+        assert_eq!(out[1].data["delta"]["text"], "answer");
+        assert_eq!(out[3].event, "message_stop");
+    }
+
+    #[test]
+    fn test_text_mode_fences_thinking() {
+        let mut filter = ThinkingFilter::new(ThinkingOutput::Text).unwrap();
+        let out: Vec<StreamEvent> = block_events().into_iter().flat_map(|e| filter.apply(e)).collect();
+        assert_eq!(out[0].data["content_block"]["type"], "text");
+        let text: String = out
+            .iter()
+            .filter(|e| e.data["index"] == 0)
+            .filter_map(|e| e.data["delta"]["text"].as_str())
+            .collect();
+        assert_eq!(text, format!("{}hmm{}", THINKING_TEXT_OPEN, THINKING_TEXT_CLOSE));
+        assert_eq!(out.iter().filter(|e| e.data["index"] == 1).count(), 3);
+    }
+
+    #[test]
+    fn test_blocks_mode_needs_no_filter() {
+        assert!(ThinkingFilter::new(ThinkingOutput::Blocks).is_none());
+    }
+
+    #[test]
+    fn test_model_overrides() {
+        let overrides = parse_dialect_overrides(&["qwen3*=chat_template_kwargs".into(), "o3=openai".into(), "bad".into()]);
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(dialect_for_model("Qwen3-32B", &overrides, ThinkingDialect::None), ThinkingDialect::ChatTemplateKwargs);
+        assert_eq!(dialect_for_model("o3", &overrides, ThinkingDialect::None), ThinkingDialect::OpenAI);
+        assert_eq!(dialect_for_model("o3-mini", &overrides, ThinkingDialect::None), ThinkingDialect::None);
+    }
+}
@@ -0,0 +1,305 @@
+//! Tool use for backends without function calling (`TOOL_EMULATION`).
+//!
+//! Tool definitions are rendered into the system prompt and the model is asked to answer
+//! with a fenced JSON action. The streamed reply is scanned for such actions, which are
+//! turned back into Claude `tool_use` blocks.
+use std::collections::HashSet;
+use serde_json::{json, Value};
+use crate::models::{OAIChatReq, OAIMessage, OAITool};
+
+const FENCE: &str = "```";
+
+/// Render tool definitions and the action format into system prompt text
+pub fn render_tool_prompt(tools: &[OAITool]) -> String {
+    let mut prompt = String::from(
+        "You can call the tools below. To call a tool, reply with a fenced block in exactly this form \
+         and then stop to wait for the result:\n\n\
+         ```tool_call\n{\"tool\": \"<tool name>\", \"input\": {<arguments matching the tool's schema>}}\n```\n\n\
+         Tool results are sent back to you in the next user message. Only call tools listed here.\n\n\
+         Available tools:\n",
+    );
+    for tool in tools {
+        let f = &tool.function;
+        prompt.push_str(&format!("\n## {}\n", f.name));
+        if let Some(description) = f.description.as_deref().filter(|d| !d.is_empty()) {
+            prompt.push_str(description);
+            prompt.push('\n');
+        }
+        prompt.push_str(&format!("Input schema: {}\n", f.parameters));
+    }
+    prompt
+}
+
+/// Rewrite a request so it no longer uses native tools. Returns whether anything was emulated.
+///
+/// Tool definitions move into the system prompt and the history is rewritten by `emulate_history`.
+pub fn emulate_tools(oai: &mut OAIChatReq) -> bool {
+    let Some(tools) = oai.tools.take().filter(|t| !t.is_empty()) else {
+        return false;
+    };
+    oai.tool_choice = None;
+    oai.parallel_tool_calls = None;
+
+    let prompt = render_tool_prompt(&tools);
+    match oai.messages.iter_mut().find(|m| m.role == "system") {
+        Some(system) => {
+            let existing = system.content.as_str().unwrap_or_default();
+            system.content = Value::String(format!("{}\n\n{}", existing, prompt).trim_start().to_string());
+        }
+        None => oai.messages.insert(0, OAIMessage {
+            role: "system".into(),
+            content: Value::String(prompt),
+            tool_call_id: None,
+            tool_calls: None,
+            prefix: None,
+        }),
+    }
+    emulate_history(&mut oai.messages);
+    true
+}
+
+/// Earlier assistant `tool_calls` become fenced actions and tool results become user messages;
+/// also applied to the follow-up requests of proxy-run server tools
+pub fn emulate_history(messages: &mut [OAIMessage]) {
+    for m in messages.iter_mut() {
+        if let Some(calls) = m.tool_calls.take() {
+            let mut text = m.content.as_str().unwrap_or_default().to_string();
+            for call in calls {
+                let input = call["function"]["arguments"]
+                    .as_str()
+                    .and_then(|args| serde_json::from_str::<Value>(args).ok())
+                    .unwrap_or_else(|| json!({}));
+                let action = json!({ "tool": call["function"]["name"], "input": input });
+                text.push_str(&format!("\n\n{}tool_call\n{}\n{}", FENCE, action, FENCE));
+            }
+            m.content = Value::String(text.trim_start().to_string());
+        } else if m.role == "tool" {
+            let result = match &m.content {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            let id = m.tool_call_id.take().unwrap_or_default();
+            m.role = "user".into();
+            m.content = Value::String(format!("[Result of tool call {}]\n{}", id, result));
+        }
+    }
+}
+
+/// Piece of emulated output, in stream order
+#[derive(Debug, PartialEq)]
+pub enum EmulatedOutput {
+    Text(String),
+    ToolUse { id: String, name: String, input: Value },
+}
+
+/// Finds fenced JSON actions in streamed text.
+///
+/// Text inside a code fence is held back until the fence closes; fences that don't hold an
+/// action, or whose action names a tool the request didn't declare, are released unchanged as text.
+pub struct ToolActionScanner {
+    id_prefix: String,
+    tools: HashSet<String>,
+    calls: usize,
+    pending: String,
+    in_fence: bool,
+}
+
+impl ToolActionScanner {
+    pub fn new(id_prefix: &str, tools: HashSet<String>) -> Self {
+        Self {
+            id_prefix: id_prefix.to_string(),
+            tools,
+            calls: 0,
+            pending: String::new(),
+            in_fence: false,
+        }
+    }
+
+    pub fn push(&mut self, text: &str) -> Vec<EmulatedOutput> {
+        self.pending.push_str(text);
+        let mut out = Vec::new();
+        loop {
+            if !self.in_fence {
+                if let Some(pos) = self.pending.find(FENCE) {
+                    let rest = self.pending.split_off(pos);
+                    push_text(&mut out, std::mem::replace(&mut self.pending, rest));
+                    self.in_fence = true;
+                    continue;
+                }
+                // Hold back trailing backticks that may start a fence
+                let hold = self.pending.len() - self.pending.trim_end_matches('`').len();
+                let held = self.pending.split_off(self.pending.len() - hold);
+                push_text(&mut out, std::mem::replace(&mut self.pending, held));
+                return out;
+            }
+            let Some(close) = self.pending[FENCE.len()..].find(FENCE) else {
+                return out;
+            };
+            let rest = self.pending.split_off(FENCE.len() + close + FENCE.len());
+            let block = std::mem::replace(&mut self.pending, rest);
+            self.in_fence = false;
+            out.push(self.parse_block(block));
+        }
+    }
+
+    /// Release whatever is still held once the stream ends; an unclosed fence may still be an action
+    pub fn finish(&mut self) -> Vec<EmulatedOutput> {
+        let block = std::mem::take(&mut self.pending);
+        if block.is_empty() {
+            return Vec::new();
+        }
+        if std::mem::take(&mut self.in_fence) {
+            return vec![self.parse_block(block)];
+        }
+        vec![EmulatedOutput::Text(block)]
+    }
+
+    fn parse_block(&mut self, block: String) -> EmulatedOutput {
+        let body = block[FENCE.len()..].trim_end().trim_end_matches(FENCE);
+        // Skip the info string (`tool_call`, `json`, ...)
+        let body = body.split_once('\n').map_or("", |(_, rest)| rest).trim();
+        let Some((name, input)) = serde_json::from_str::<Value>(body).ok().and_then(parse_action) else {
+            return EmulatedOutput::Text(block);
+        };
+        if !self.tools.contains(&name) {
+            log::debug!("🔧 Emulated action names undeclared tool '{}', kept as text", name);
+            return EmulatedOutput::Text(block);
+        }
+        self.calls += 1;
+        EmulatedOutput::ToolUse {
+            id: format!("toolu_{}_{}", self.id_prefix, self.calls),
+            name,
+            input,
+        }
+    }
+}
+
+/// Accepts `{"tool"|"name": ..., "input"|"arguments"|"parameters": {...}}`
+fn parse_action(action: Value) -> Option<(String, Value)> {
+    let name = action.get("tool").or_else(|| action.get("name"))?.as_str()?.to_string();
+    let input = ["input", "arguments", "parameters"]
+        .iter()
+        .find_map(|k| action.get(*k))
+        .cloned()
+        .unwrap_or_else(|| json!({}));
+    let input = match input {
+        // Some models double-encode arguments the way OpenAI does
+        Value::String(s) => serde_json::from_str(&s).ok().filter(Value::is_object)?,
+        Value::Object(_) => input,
+        _ => return None,
+    };
+    Some((name, input))
+}
+
+fn push_text(out: &mut Vec<EmulatedOutput>, text: String) {
+    if !text.is_empty() {
+        out.push(EmulatedOutput::Text(text));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OAIFunction;
+
+    fn tool(name: &str) -> OAITool {
+        OAITool {
+            type_: "function".into(),
+            function: OAIFunction {
+                name: name.into(),
+                description: Some("Reads a file".into()),
+                parameters: json!({"type": "object", "properties": {"path": {"type": "string"}}}),
+                strict: None,
+            },
+            cache_control: None,
+        }
+    }
+
+    fn msg(role: &str, content: Value) -> OAIMessage {
+        OAIMessage { role: role.into(), content, tool_call_id: None, tool_calls: None, prefix: None }
+    }
+
+    fn names(tools: &[&str]) -> HashSet<String> {
+        tools.iter().map(|t| t.to_string()).collect()
+    }
+
+    fn collect(scanner: &mut ToolActionScanner, deltas: &[&str]) -> Vec<EmulatedOutput> {
+        let mut out: Vec<EmulatedOutput> = deltas.iter().flat_map(|d| scanner.push(d)).collect();
+        out.extend(scanner.finish());
+        out
+    }
+
+    #[test]
+    fn test_emulate_tools_rewrites_request() {
+        let mut call = msg("assistant", json!("Let me look."));
+        call.tool_calls = Some(vec![json!({
+            "id": "toolu_1", "type": "function",
+            "function": {"name": "read", "arguments": "{\"path\":\"a.rs\"}"}
+        })]);
+        let mut result = msg("tool", json!("fn main() {}"));
+        result.tool_call_id = Some("toolu_1".into());
+        let mut oai = OAIChatReq {
+            messages: vec![msg("system", json!("Be brief.")), msg("user", json!("hi")), call, result],
+            tools: Some(vec![tool("read")]),
+            tool_choice: Some(json!("auto")),
+            ..Default::default()
+        };
+
+        assert!(emulate_tools(&mut oai));
+        assert!(oai.tools.is_none() && oai.tool_choice.is_none());
+        let system = oai.messages[0].content.as_str().unwrap();
+        assert!(system.starts_with("Be brief."));
+        assert!(system.contains("## read") && system.contains("Reads a file"));
+        let assistant = oai.messages[2].content.as_str().unwrap();
+        assert!(oai.messages[2].tool_calls.is_none());
+        assert!(assistant.contains("```tool_call\n{\"input\":{\"path\":\"a.rs\"},\"tool\":\"read\"}\n```"));
+        assert_eq!(oai.messages[3].role, "user");
+        assert!(oai.messages[3].tool_call_id.is_none());
+        assert!(oai.messages[3].content.as_str().unwrap().contains("fn main() {}"));
+    }
+
+    #[test]
+    fn test_emulate_tools_without_tools_is_noop() {
+        let mut oai = OAIChatReq { tools: Some(vec![]), ..Default::default() };
+        assert!(!emulate_tools(&mut oai));
+        assert!(oai.messages.is_empty());
+    }
+
+    #[test]
+    fn test_action_split_across_deltas() {
+        let mut s = ToolActionScanner::new("msg_1", names(&["read"]));
+        let out = collect(&mut s, &["Reading it.\n`", "``tool_call\n{\"tool\": \"read\", ", "\"input\": {\"path\": \"a.rs\"}}\n``", "`"]);
+        assert_eq!(out, vec![
+            EmulatedOutput::Text("Reading it.\n".into()),
+            EmulatedOutput::ToolUse { id: "toolu_msg_1_1".into(), name: "read".into(), input: json!({"path": "a.rs"}) },
+        ]);
+        assert_eq!(s.calls, 1);
+    }
+
+    #[test]
+    fn test_plain_code_block_is_released_as_text() {
+        let mut s = ToolActionScanner::new("m", names(&["read"]));
+        let out = collect(&mut s, &["Example:\n```rust\nfn main() {}\n```\nDone"]);
+        assert_eq!(out, vec![
+            EmulatedOutput::Text("Example:\n".into()),
+            EmulatedOutput::Text("```rust\nfn main() {}\n```".into()),
+            EmulatedOutput::Text("\nDone".into()),
+        ]);
+        assert_eq!(s.calls, 0);
+    }
+
+    #[test]
+    fn test_unclosed_action_at_end_of_stream() {
+        let mut s = ToolActionScanner::new("m", names(&["ls"]));
+        let out = collect(&mut s, &["```json\n{\"name\": \"ls\", \"arguments\": \"{\\\"dir\\\": \\\".\\\"}\"}\n"]);
+        assert_eq!(out, vec![EmulatedOutput::ToolUse { id: "toolu_m_1".into(), name: "ls".into(), input: json!({"dir": "."}) }]);
+    }
+
+    #[test]
+    fn test_action_for_undeclared_tool_is_released_as_text() {
+        let mut s = ToolActionScanner::new("m", names(&["read"]));
+        let block = "```tool_call\n{\"tool\": \"rm\", \"input\": {\"path\": \"/\"}}\n```";
+        assert_eq!(collect(&mut s, &[block]), vec![EmulatedOutput::Text(block.into())]);
+        assert_eq!(s.calls, 0);
+    }
+}
@@ -0,0 +1,250 @@
+use std::{collections::HashSet, str::FromStr};
+use serde_json::Value;
+use crate::constants::{TOOL_NOTICE_MAX_NAMES, TOOL_RELEVANCE_MIN_WORD_CHARS};
+use crate::models::{ClaudeMessage, ClaudeTool};
+use crate::utils::content_extraction::{content_parts, PartKind};
+
+/// Which tools go when a request has more than `MAX_TOOLS` / `MAX_TOOL_SCHEMA_BYTES` (`TOOL_LIMIT_POLICY`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ToolLimitPolicy {
+    /// Keep tools in the order the client sent them until the limits are reached
+    #[default]
+    Truncate,
+    /// Keep the tools whose name and description best match the latest user message
+    Relevance,
+    /// Refuse the request with a 400
+    Reject,
+}
+
+impl FromStr for ToolLimitPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "truncate" => Ok(ToolLimitPolicy::Truncate),
+            "relevance" => Ok(ToolLimitPolicy::Relevance),
+            "reject" => Ok(ToolLimitPolicy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Limits on the tool definitions forwarded to the backend; both unset means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ToolLimits {
+    /// `MAX_TOOLS`
+    pub max_tools: Option<usize>,
+    /// Combined size of names, descriptions and schemas (`MAX_TOOL_SCHEMA_BYTES`)
+    pub max_schema_bytes: Option<usize>,
+    pub policy: ToolLimitPolicy,
+}
+
+/// A request over the tool limits under `TOOL_LIMIT_POLICY=reject`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolLimitError {
+    TooMany { count: usize, limit: usize },
+    SchemasTooLarge { bytes: usize, limit: usize },
+}
+
+impl ToolLimitError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::TooMany { .. } => "too_many_tools",
+            Self::SchemasTooLarge { .. } => "tool_schemas_too_large",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Self::TooMany { count, limit } => format!("tools: {} tools exceed the proxy's limit of {} (MAX_TOOLS)", count, limit),
+            Self::SchemasTooLarge { bytes, limit } => {
+                format!("tools: {} bytes of tool definitions exceed the proxy's limit of {} (MAX_TOOL_SCHEMA_BYTES)", bytes, limit)
+            }
+        }
+    }
+}
+
+fn tool_bytes(tool: &ClaudeTool) -> usize {
+    tool.name.len() + tool.description.as_deref().map_or(0, str::len) + tool.input_schema.to_string().len()
+}
+
+impl ToolLimits {
+    /// Drop tools until the request fits; returns the names of the omitted tools.
+    ///
+    /// Server tools, the tool `tool_choice` names and tools already called in the conversation are
+    /// always kept, so the history stays valid for the backend.
+    pub fn apply(&self, tools: &mut Vec<ClaudeTool>, messages: &[ClaudeMessage], tool_choice: Option<&Value>) -> Result<Vec<String>, ToolLimitError> {
+        let bytes: usize = tools.iter().map(tool_bytes).sum();
+        let over_count = self.max_tools.filter(|&limit| tools.len() > limit);
+        let over_bytes = self.max_schema_bytes.filter(|&limit| bytes > limit);
+        if over_count.is_none() && over_bytes.is_none() {
+            return Ok(Vec::new());
+        }
+        if self.policy == ToolLimitPolicy::Reject {
+            return Err(match over_count {
+                Some(limit) => ToolLimitError::TooMany { count: tools.len(), limit },
+                None => ToolLimitError::SchemasTooLarge { bytes, limit: over_bytes.unwrap_or_default() },
+            });
+        }
+
+        let called = called_tools(messages);
+        let chosen = tool_choice.filter(|c| c["type"] == "tool").and_then(|c| c["name"].as_str());
+        let pinned = |tool: &ClaudeTool| {
+            tool.type_.as_deref().is_some_and(|t| t != "custom") || chosen == Some(tool.name.as_str()) || called.contains(tool.name.as_str())
+        };
+        let scores: Vec<usize> = match self.policy {
+            ToolLimitPolicy::Relevance => {
+                let words = query_words(messages);
+                tools.iter().map(|tool| relevance(tool, &words)).collect()
+            }
+            _ => vec![0; tools.len()],
+        };
+        // Pinned tools claim their share of the limits first; the sort is stable, so equal
+        // scores keep the client's order
+        let mut order: Vec<usize> = (0..tools.len()).collect();
+        order.sort_by_key(|&i| (!pinned(&tools[i]), std::cmp::Reverse(scores[i])));
+
+        let (mut kept, mut kept_bytes) = (vec![false; tools.len()], 0);
+        let mut count = 0;
+        for i in order {
+            let size = tool_bytes(&tools[i]);
+            let fits = self.max_tools.is_none_or(|limit| count < limit) && self.max_schema_bytes.is_none_or(|limit| kept_bytes + size <= limit);
+            if fits || pinned(&tools[i]) {
+                kept[i] = true;
+                count += 1;
+                kept_bytes += size;
+            }
+        }
+        let mut omitted = Vec::new();
+        let mut kept = kept.into_iter();
+        tools.retain(|tool| {
+            let keep = kept.next().unwrap_or(true);
+            if !keep {
+                omitted.push(tool.name.clone());
+            }
+            keep
+        });
+        Ok(omitted)
+    }
+}
+
+/// Warning block listing tools dropped by the limits
+pub fn omitted_tools_notice(omitted: &[String], total: usize) -> String {
+    let mut names = omitted.iter().take(TOOL_NOTICE_MAX_NAMES).cloned().collect::<Vec<_>>().join(", ");
+    if omitted.len() > TOOL_NOTICE_MAX_NAMES {
+        names.push_str(&format!(" and {} more", omitted.len() - TOOL_NOTICE_MAX_NAMES));
+    }
+    format!(
+        "The proxy omitted {} of {} tools to stay within the backend's tool limits (MAX_TOOLS / MAX_TOOL_SCHEMA_BYTES); these tools were not available: {}",
+        omitted.len(),
+        total,
+        names
+    )
+}
+
+/// Names of tools the assistant has called in the conversation
+fn called_tools(messages: &[ClaudeMessage]) -> HashSet<&str> {
+    messages
+        .iter()
+        .filter(|m| m.role == "assistant")
+        .filter_map(|m| m.content.as_array())
+        .flatten()
+        .filter(|block| block["type"] == "tool_use")
+        .filter_map(|block| block["name"].as_str())
+        .collect()
+}
+
+/// Lowercase words of the latest user message with text (tool results don't count)
+fn query_words(messages: &[ClaudeMessage]) -> HashSet<String> {
+    let text = messages
+        .iter()
+        .rev()
+        .filter(|m| m.role == "user")
+        .map(|m| {
+            content_parts(&m.content).texts.into_iter().filter(|(kind, _)| *kind == PartKind::Text).map(|(_, t)| t).collect::<Vec<_>>().join(" ")
+        })
+        .find(|text| !text.trim().is_empty())
+        .unwrap_or_default();
+    words(&text).into_iter().filter(|w| w.chars().count() >= TOOL_RELEVANCE_MIN_WORD_CHARS).collect()
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).map(str::to_lowercase).collect()
+}
+
+/// Query words found in the tool's name (which count triple) and description
+fn relevance(tool: &ClaudeTool, query: &HashSet<String>) -> usize {
+    let name: HashSet<String> = words(&tool.name).into_iter().collect();
+    let description: HashSet<String> = words(tool.description.as_deref().unwrap_or_default()).into_iter().collect();
+    query.iter().map(|w| 3 * name.contains(w) as usize + description.contains(w) as usize).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tools(names: &[(&str, &str)]) -> Vec<ClaudeTool> {
+        let defs: Vec<Value> = names
+            .iter()
+            .map(|(name, description)| json!({"name": name, "description": description, "input_schema": {"type": "object"}}))
+            .collect();
+        serde_json::from_value(Value::Array(defs)).unwrap()
+    }
+
+    fn messages(value: Value) -> Vec<ClaudeMessage> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn names(tools: &[ClaudeTool]) -> Vec<&str> {
+        tools.iter().map(|t| t.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_under_limits_is_untouched() {
+        let mut list = tools(&[("a", ""), ("b", "")]);
+        let limits = ToolLimits { max_tools: Some(2), ..Default::default() };
+        assert_eq!(limits.apply(&mut list, &[], None), Ok(vec![]));
+        assert_eq!(list.len(), 2);
+    }
+
+    #[test]
+    fn test_truncate_keeps_called_and_chosen_tools() {
+        let mut list = tools(&[("a", ""), ("b", ""), ("c", ""), ("d", "")]);
+        let history = messages(json!([
+            {"role": "user", "content": "go"},
+            {"role": "assistant", "content": [{"type": "tool_use", "id": "toolu_1", "name": "d", "input": {}}]},
+            {"role": "user", "content": [{"type": "tool_result", "tool_use_id": "toolu_1", "content": "ok"}]},
+        ]));
+        let limits = ToolLimits { max_tools: Some(2), ..Default::default() };
+        let omitted = limits.apply(&mut list, &history, Some(&json!({"type": "tool", "name": "c"}))).unwrap();
+        assert_eq!(names(&list), ["c", "d"]);
+        assert_eq!(omitted, ["a", "b"]);
+    }
+
+    #[test]
+    fn test_relevance_ranks_by_latest_user_message() {
+        let mut list = tools(&[
+            ("slack_post", "Post a message to a Slack channel"),
+            ("github_create_issue", "Open an issue in a GitHub repository"),
+            ("jira_search", "Search Jira tickets"),
+        ]);
+        let history = messages(json!([{"role": "user", "content": "Please open a GitHub issue about the crash"}]));
+        let limits = ToolLimits { max_tools: Some(1), policy: ToolLimitPolicy::Relevance, ..Default::default() };
+        let omitted = limits.apply(&mut list, &history, None).unwrap();
+        assert_eq!(names(&list), ["github_create_issue"]);
+        assert_eq!(omitted, ["slack_post", "jira_search"]);
+    }
+
+    #[test]
+    fn test_schema_bytes_and_reject() {
+        let mut list = tools(&[("a", "x"), ("b", "y"), ("c", "z")]);
+        let per_tool = tool_bytes(&list[0]);
+        let limits = ToolLimits { max_schema_bytes: Some(2 * per_tool), ..Default::default() };
+        assert_eq!(limits.apply(&mut list.clone(), &[], None).unwrap(), ["c"]);
+
+        let reject = ToolLimits { max_tools: Some(2), policy: ToolLimitPolicy::Reject, ..Default::default() };
+        assert_eq!(reject.apply(&mut list, &[], None).unwrap_err().code(), "too_many_tools");
+        assert_eq!(list.len(), 3);
+    }
+}
@@ -0,0 +1,556 @@
+use std::{collections::BTreeMap, sync::Arc, time::Instant};
+use axum::{
+    http::{Extensions, StatusCode},
+    response::sse::Event,
+};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use crate::models::{ClaudeRequest, OAIChatReq};
+use crate::services::client_info::ClientInfo;
+use crate::services::coalesce::{CoalesceConfig, DeltaCoalescer};
+use crate::services::delta_split::{split_delta, SplitConfig};
+use crate::services::pii::{PiiRestorer, PiiVault};
+use crate::services::stream_resume::{event_id, ReplayBuffer};
+use crate::services::thinking::{ThinkingFilter, ThinkingOutput};
+
+/// Result of a request-side hook; `Err` rejects the request with the given status and code
+pub type TransformResult = Result<(), (StatusCode, &'static str)>;
+
+/// A single Claude SSE event on its way to the client
+#[derive(Debug, Clone)]
+pub struct StreamEvent {
+    pub event: &'static str,
+    pub data: Value,
+}
+
+impl StreamEvent {
+    /// `content_block_delta` event for block `index`
+    pub fn block_delta(index: u64, delta: BlockDelta<'_>) -> Self {
+        let data = ContentBlockDelta { r#type: "content_block_delta", index, delta };
+        Self { event: "content_block_delta", data: serde_json::to_value(data).expect("deltas serialize to JSON") }
+    }
+}
+
+#[derive(Serialize)]
+struct ContentBlockDelta<'a> {
+    r#type: &'static str,
+    index: u64,
+    delta: BlockDelta<'a>,
+}
+
+/// `delta` of a `content_block_delta` event
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum BlockDelta<'a> {
+    #[serde(rename = "text_delta")]
+    Text { text: &'a str },
+    #[serde(rename = "thinking_delta")]
+    Thinking { thinking: &'a str },
+    #[serde(rename = "input_json_delta")]
+    InputJson { partial_json: &'a str },
+    #[serde(rename = "citations_delta")]
+    Citations { citation: Value },
+}
+
+impl<'a> BlockDelta<'a> {
+    pub fn text_of(kind: TextDeltaKind, text: &'a str) -> Self {
+        match kind {
+            TextDeltaKind::Text => Self::Text { text },
+            TextDeltaKind::Thinking => Self::Thinking { thinking: text },
+            TextDeltaKind::InputJson => Self::InputJson { partial_json: text },
+        }
+    }
+}
+
+/// Deltas that carry a piece of text, which can be merged, split or rewritten
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextDeltaKind {
+    Text,
+    Thinking,
+    InputJson,
+}
+
+impl TextDeltaKind {
+    /// From the delta's `type`
+    pub fn parse(delta_type: &str) -> Option<Self> {
+        match delta_type {
+            "text_delta" => Some(Self::Text),
+            "thinking_delta" => Some(Self::Thinking),
+            "input_json_delta" => Some(Self::InputJson),
+            _ => None,
+        }
+    }
+
+    /// Field of the delta holding the text
+    pub fn field(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Thinking => "thinking",
+            Self::InputJson => "partial_json",
+        }
+    }
+}
+
+/// Per-request state shared by all hooks of a single request
+pub struct TransformContext {
+    pub request_id: String,
+    pub model: String,
+    /// Calling client, for tagging metrics and usage records
+    pub client: ClientInfo,
+    /// Stable, non-reversible identifier of the client's API key
+    pub key_fingerprint: Option<String>,
+    /// Diagnostic headers of the backend response (`x-request-id`, rate limits, ...)
+    pub backend_headers: BTreeMap<String, String>,
+    /// `MODEL_EXPERIMENTS` experiment the request was assigned in, for tagging usage records
+    pub experiment: Option<String>,
+    /// Betas the client enabled (`anthropic-beta`)
+    pub betas: Vec<String>,
+    /// Scratch space for transforms that need to carry state between hooks
+    pub extensions: Extensions,
+    /// The request is only checked (`/v1/messages/validate`) and never sent; transforms should
+    /// rewrite it as usual but skip anything with effects outside it
+    pub dry_run: bool,
+}
+
+impl TransformContext {
+    pub fn new(request_id: String, model: String) -> Self {
+        Self {
+            request_id,
+            model,
+            client: ClientInfo::default(),
+            key_fingerprint: None,
+            backend_headers: BTreeMap::new(),
+            experiment: None,
+            betas: Vec::new(),
+            extensions: Extensions::new(),
+            dry_run: false,
+        }
+    }
+}
+
+/// Kind of failure seen while reading a backend stream, counted per backend in `/admin/stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamErrorKind {
+    /// The connection broke before the stream ended
+    ConnectionReset,
+    /// A `data:` payload that isn't JSON
+    MalformedChunk,
+    /// The backend sent an `error` object instead of a chunk
+    BackendError,
+    /// Reading the stream timed out
+    StallTimeout,
+    /// An event outgrew the SSE buffer limit (`SSE_BUFFER_LIMIT_KB`)
+    BufferLimit,
+}
+
+impl StreamErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ConnectionReset => "connection_reset",
+            Self::MalformedChunk => "malformed_chunk",
+            Self::BackendError => "backend_error",
+            Self::StallTimeout => "stall_timeout",
+            Self::BufferLimit => "buffer_limit",
+        }
+    }
+}
+
+/// Final outcome of a streamed response, handed to `on_complete`
+#[derive(Debug, Clone)]
+pub struct CompletionSummary {
+    pub stop_reason: String,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub fatal_error: bool,
+    /// Mid-stream failures by kind; some (malformed chunks) don't end the stream
+    pub stream_errors: BTreeMap<StreamErrorKind, u32>,
+}
+
+/// Request/response rewriting hook.
+///
+/// All hooks default to no-ops, so an implementation only overrides the stages it cares about.
+/// Hooks run in registration order.
+pub trait Transform: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Called with the incoming Claude request before validation and conversion
+    fn on_claude_request<'a>(
+        &'a self,
+        _ctx: &'a mut TransformContext,
+        _req: &'a mut ClaudeRequest,
+    ) -> BoxFuture<'a, TransformResult> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Called with the converted OpenAI request right before it is sent to the backend
+    fn on_oai_request<'a>(
+        &'a self,
+        _ctx: &'a mut TransformContext,
+        _req: &'a mut OAIChatReq,
+    ) -> BoxFuture<'a, TransformResult> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Whether `on_stream_event` does anything; transforms that return `false` are skipped per
+    /// event, which keeps the streaming hot path free of their boxed futures
+    fn handles_stream_events(&self) -> bool {
+        true
+    }
+
+    /// Called for every Claude SSE event before it is sent to the client
+    fn on_stream_event<'a>(
+        &'a self,
+        _ctx: &'a mut TransformContext,
+        _event: &'a mut StreamEvent,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// Whether this request's response should run to the end after the client disconnects, so
+    /// `on_complete` sees the whole message
+    fn outlives_client(&self, _ctx: &TransformContext) -> bool {
+        false
+    }
+
+    /// Called once the stream has finished (whether or not the client is still connected)
+    fn on_complete<'a>(
+        &'a self,
+        _ctx: &'a mut TransformContext,
+        _summary: &'a CompletionSummary,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// Ordered list of registered transforms
+#[derive(Default, Clone)]
+pub struct TransformChain {
+    transforms: Vec<Arc<dyn Transform>>,
+    /// Subset of `transforms` that handle stream events
+    stream_transforms: Vec<Arc<dyn Transform>>,
+}
+
+impl TransformChain {
+    pub fn register(&mut self, transform: Arc<dyn Transform>) {
+        log::info!("🧩 Registered transform: {}", transform.name());
+        if transform.handles_stream_events() {
+            self.stream_transforms.push(transform.clone());
+        }
+        self.transforms.push(transform);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    /// Whether any registered transform handles stream events
+    pub fn handles_stream_events(&self) -> bool {
+        !self.stream_transforms.is_empty()
+    }
+
+    /// Whether any registered transform wants the response finished without the client
+    pub fn outlives_client(&self, ctx: &TransformContext) -> bool {
+        self.transforms.iter().any(|t| t.outlives_client(ctx))
+    }
+
+    pub async fn on_claude_request(&self, ctx: &mut TransformContext, req: &mut ClaudeRequest) -> TransformResult {
+        for t in &self.transforms {
+            t.on_claude_request(ctx, req).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn on_oai_request(&self, ctx: &mut TransformContext, req: &mut OAIChatReq) -> TransformResult {
+        for t in &self.transforms {
+            t.on_oai_request(ctx, req).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn on_stream_event(&self, ctx: &mut TransformContext, event: &mut StreamEvent) {
+        for t in &self.stream_transforms {
+            t.on_stream_event(ctx, event).await;
+        }
+    }
+
+    pub async fn on_complete(&self, ctx: &mut TransformContext, summary: &CompletionSummary) {
+        for t in &self.transforms {
+            t.on_complete(ctx, summary).await;
+        }
+    }
+}
+
+/// SSE sender that runs every outgoing event through the transform chain
+pub struct EventSender {
+    tx: mpsc::Sender<Event>,
+    chain: Arc<TransformChain>,
+    coalescer: Option<DeltaCoalescer>,
+    split: Option<SplitConfig>,
+    thinking_filter: Option<ThinkingFilter>,
+    /// Original values put back in place of PII placeholders (`PII_RESTORE`)
+    pii_restorer: Option<PiiRestorer>,
+    /// Sent events are kept for `Last-Event-ID` resume (`STREAM_RESUME_SECS`)
+    replay: Option<Arc<ReplayBuffer>>,
+    pub ctx: TransformContext,
+}
+
+impl EventSender {
+    pub fn new(tx: mpsc::Sender<Event>, chain: Arc<TransformChain>, ctx: TransformContext) -> Self {
+        Self {
+            tx,
+            chain,
+            coalescer: None,
+            split: None,
+            thinking_filter: None,
+            pii_restorer: PiiRestorer::new(ctx.extensions.get::<PiiVault>()),
+            replay: None,
+            ctx,
+        }
+    }
+
+    /// Merge consecutive small deltas before they reach transforms and the client
+    pub fn with_coalescing(mut self, config: Option<CoalesceConfig>) -> Self {
+        self.coalescer = config.map(DeltaCoalescer::new);
+        self
+    }
+
+    /// Break oversized deltas into smaller, paced deltas
+    pub fn with_splitting(mut self, config: Option<SplitConfig>) -> Self {
+        self.split = config;
+        self
+    }
+
+    /// Drop thinking blocks or turn them into fenced text blocks
+    pub fn with_thinking_output(mut self, mode: ThinkingOutput) -> Self {
+        self.thinking_filter = ThinkingFilter::new(mode);
+        self
+    }
+
+    /// Give events SSE ids and record them for resume. A client that disconnects no longer ends
+    /// the response, so it can reconnect and pick up the rest.
+    pub fn with_replay(mut self, buffer: Option<Arc<ReplayBuffer>>) -> Self {
+        self.replay = buffer;
+        self
+    }
+
+    /// Send one event; `Err` means the client has disconnected
+    pub async fn send(&mut self, event: &'static str, data: Value) -> Result<(), ()> {
+        let ev = StreamEvent { event, data };
+        let Some(restorer) = &mut self.pii_restorer else {
+            return self.send_filtered(ev).await;
+        };
+        for ev in restorer.apply(ev) {
+            self.send_filtered(ev).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_filtered(&mut self, ev: StreamEvent) -> Result<(), ()> {
+        let Some(filter) = &mut self.thinking_filter else {
+            return self.send_coalesced(ev).await;
+        };
+        for ev in filter.apply(ev) {
+            self.send_coalesced(ev).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_coalesced(&mut self, ev: StreamEvent) -> Result<(), ()> {
+        let Some(coalescer) = &mut self.coalescer else {
+            return self.emit(ev).await;
+        };
+        for ev in coalescer.push(ev) {
+            self.emit(ev).await?;
+        }
+        Ok(())
+    }
+
+    /// Send any buffered delta now
+    pub async fn flush(&mut self) -> Result<(), ()> {
+        match self.coalescer.as_mut().and_then(DeltaCoalescer::take) {
+            Some(ev) => self.emit(ev).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Deadline for flushing a buffered delta when the backend goes quiet
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        self.coalescer.as_ref().and_then(DeltaCoalescer::deadline)
+    }
+
+    async fn emit(&mut self, ev: StreamEvent) -> Result<(), ()> {
+        let Some(split) = self.split else {
+            return self.emit_one(ev).await;
+        };
+        for (i, piece) in split_delta(ev, split.max_bytes).into_iter().enumerate() {
+            if i > 0 && !split.delay.is_zero() {
+                tokio::time::sleep(split.delay).await;
+            }
+            self.emit_one(piece).await?;
+        }
+        Ok(())
+    }
+
+    async fn emit_one(&mut self, mut ev: StreamEvent) -> Result<(), ()> {
+        if self.chain.handles_stream_events() {
+            self.chain.on_stream_event(&mut self.ctx, &mut ev).await;
+        }
+        let Some(replay) = &self.replay else {
+            // Serialize straight into the event's buffer instead of through an intermediate String
+            let event = Event::default().event(ev.event).json_data(&ev.data).map_err(|_| ())?;
+            if self.tx.send(event).await.is_err() && !self.chain.outlives_client(&self.ctx) {
+                return Err(());
+            }
+            return Ok(());
+        };
+        let data = ev.data.to_string();
+        let seq = replay.record(ev.event, &data);
+        let event = Event::default().id(event_id(replay.message_id(), seq)).event(ev.event).data(data);
+        // A disconnected client doesn't end the response: it may reconnect and resume
+        let _ = self.tx.send(event).await;
+        Ok(())
+    }
+
+    pub async fn complete(&mut self, summary: &CompletionSummary) {
+        if !self.chain.is_empty() {
+            self.chain.on_complete(&mut self.ctx, summary).await;
+        }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        if let Some(replay) = &self.replay {
+            replay.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct Suffix(&'static str);
+
+    impl Transform for Suffix {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn on_stream_event<'a>(
+            &'a self,
+            _ctx: &'a mut TransformContext,
+            event: &'a mut StreamEvent,
+        ) -> BoxFuture<'a, ()> {
+            Box::pin(async move {
+                let text = event.data["text"].as_str().unwrap_or("").to_string();
+                event.data["text"] = json!(format!("{}{}", text, self.0));
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain_runs_in_registration_order() {
+        let mut chain = TransformChain::default();
+        chain.register(Arc::new(Suffix("a")));
+        chain.register(Arc::new(Suffix("b")));
+
+        let mut ctx = TransformContext::new("msg_1".into(), "model".into());
+        let mut ev = StreamEvent { event: "content_block_delta", data: json!({"text": "x"}) };
+        chain.on_stream_event(&mut ctx, &mut ev).await;
+
+        assert_eq!(ev.data["text"], "xab");
+    }
+
+    #[tokio::test]
+    async fn test_empty_chain_is_noop() {
+        let chain = TransformChain::default();
+        assert!(chain.is_empty());
+
+        let mut ctx = TransformContext::new("msg_1".into(), "model".into());
+        let mut ev = StreamEvent { event: "message_stop", data: json!({"type": "message_stop"}) };
+        chain.on_stream_event(&mut ctx, &mut ev).await;
+
+        assert_eq!(ev.data, json!({"type": "message_stop"}));
+    }
+
+    struct NoStreamHook;
+
+    impl Transform for NoStreamHook {
+        fn name(&self) -> &str {
+            "no_stream_hook"
+        }
+
+        fn handles_stream_events(&self) -> bool {
+            false
+        }
+
+        fn on_stream_event<'a>(
+            &'a self,
+            _ctx: &'a mut TransformContext,
+            _event: &'a mut StreamEvent,
+        ) -> BoxFuture<'a, ()> {
+            panic!("stream hook called on a transform that doesn't handle stream events")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain_skips_transforms_without_stream_hooks() {
+        let mut chain = TransformChain::default();
+        chain.register(Arc::new(NoStreamHook));
+        assert!(!chain.is_empty());
+        assert!(!chain.handles_stream_events());
+
+        let mut ctx = TransformContext::new("msg_1".into(), "model".into());
+        let mut ev = StreamEvent { event: "message_stop", data: json!({"type": "message_stop"}) };
+        chain.on_stream_event(&mut ctx, &mut ev).await;
+    }
+
+    #[test]
+    fn test_block_delta_shape() {
+        let ev = StreamEvent::block_delta(3, BlockDelta::text_of(TextDeltaKind::InputJson, "{\"a\""));
+        assert_eq!(ev.event, "content_block_delta");
+        assert_eq!(ev.data, json!({"type": "content_block_delta", "index": 3, "delta": {"type": "input_json_delta", "partial_json": "{\"a\""}}));
+        let ev = StreamEvent::block_delta(0, BlockDelta::Citations { citation: json!({"url": "u"}) });
+        assert_eq!(ev.data["delta"], json!({"type": "citations_delta", "citation": {"url": "u"}}));
+    }
+
+    #[tokio::test]
+    async fn test_event_sender_applies_chain() {
+        let mut chain = TransformChain::default();
+        chain.register(Arc::new(Suffix("!")));
+        let (tx, mut rx) = mpsc::channel(4);
+        let ctx = TransformContext::new("msg_1".into(), "model".into());
+        let mut sender = EventSender::new(tx, Arc::new(chain), ctx);
+
+        assert!(sender.send("content_block_delta", json!({"text": "hi"})).await.is_ok());
+        assert!(rx.recv().await.is_some());
+
+        drop(rx);
+        assert!(sender.send("message_stop", json!({})).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_event_sender_outlives_client_for_keyed_store() {
+        let dir = std::env::temp_dir().join(format!("transform_store_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut chain = TransformChain::default();
+        chain.register(Arc::new(crate::services::MessageStore::new(dir.clone(), std::time::Duration::from_secs(60))));
+        let chain = Arc::new(chain);
+
+        let (tx, rx) = mpsc::channel(4);
+        drop(rx);
+        let mut keyless = EventSender::new(tx.clone(), chain.clone(), TransformContext::new("msg_1".into(), "model".into()));
+        assert!(keyless.send("message_stop", json!({})).await.is_err());
+
+        let mut ctx = TransformContext::new("msg_2".into(), "model".into());
+        ctx.key_fingerprint = Some("owner".into());
+        let mut keyed = EventSender::new(tx, chain, ctx);
+        assert!(keyed.send("message_stop", json!({})).await.is_ok(), "the message is finished for the store");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use axum::http::{HeaderName, HeaderValue};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::json;
+use crate::config::env_list;
+use crate::constants::{WARMUP_ERROR_PREVIEW_CHARS, WARMUP_TIMEOUT_SECS};
+use crate::models::{BackendRegistry, OAIChatReq, OAIMessage};
+use crate::services::json_body;
+
+/// Progress of one warm-up target
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmupStatus {
+    Pending,
+    Ok,
+    Failed,
+}
+
+/// Last warm-up outcome of one backend/model pair, as reported by `/readyz`
+#[derive(Debug, Clone, Serialize)]
+pub struct WarmupResult {
+    pub backend: String,
+    pub model: String,
+    pub status: WarmupStatus,
+    /// What started the last run: `startup` or `circuit_recovery`
+    pub trigger: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Unix seconds when the last run finished
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+}
+
+/// A backend/model pair from `WARMUP_MODELS`
+#[derive(Debug, Clone)]
+struct WarmupTarget {
+    backend: String,
+    url: String,
+    model: String,
+    gzip_min_bytes: usize,
+}
+
+/// Tiny generations sent to each `WARMUP_MODELS` entry at startup and after the circuit breaker
+/// closes, so the first real request doesn't pay for loading the model
+pub struct Warmup {
+    client: Client,
+    /// Bearer token for warm-up requests (`WARMUP_API_KEY`); clients' keys are never reused
+    api_key: Option<String>,
+    extra_headers: Vec<(HeaderName, HeaderValue)>,
+    targets: Vec<WarmupTarget>,
+    results: Mutex<Vec<WarmupResult>>,
+}
+
+impl Warmup {
+    /// `WARMUP_MODELS` entries are `model` (default backend) or `backend=model`; `None` when unset
+    pub fn from_env(client: Client, backends: &BackendRegistry, extra_headers: Vec<(HeaderName, HeaderValue)>) -> Option<Self> {
+        let targets = parse_targets(&env_list("WARMUP_MODELS"), backends);
+        if targets.is_empty() {
+            return None;
+        }
+        let results = targets
+            .iter()
+            .map(|t| WarmupResult {
+                backend: t.backend.clone(),
+                model: t.model.clone(),
+                status: WarmupStatus::Pending,
+                trigger: "startup",
+                latency_ms: None,
+                error: None,
+                finished_at: None,
+            })
+            .collect();
+        Some(Self {
+            client,
+            api_key: std::env::var("WARMUP_API_KEY").ok().filter(|k| !k.trim().is_empty()),
+            extra_headers,
+            targets,
+            results: Mutex::new(results),
+        })
+    }
+
+    /// Warm up every target concurrently; `trigger` is reported with the results
+    pub async fn run(&self, trigger: &'static str) {
+        log::info!("🔥 Warming up {} backend model(s) ({})", self.targets.len(), trigger);
+        for result in self.results.lock().unwrap().iter_mut() {
+            result.status = WarmupStatus::Pending;
+            result.trigger = trigger;
+        }
+        let runs = self.targets.iter().enumerate().map(|(i, target)| async move {
+            let started = Instant::now();
+            let outcome = self.warm(target).await;
+            let latency_ms = started.elapsed().as_millis() as u64;
+            match &outcome {
+                Ok(()) => log::info!("🔥 Warmed up {} on '{}' in {}ms", target.model, target.backend, latency_ms),
+                Err(e) => log::warn!("⚠️  Warm-up of {} on '{}' failed: {}", target.model, target.backend, e),
+            }
+            let mut results = self.results.lock().unwrap();
+            let result = &mut results[i];
+            result.status = if outcome.is_ok() { WarmupStatus::Ok } else { WarmupStatus::Failed };
+            result.latency_ms = Some(latency_ms);
+            result.error = outcome.err();
+            result.finished_at = SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+        });
+        futures::future::join_all(runs).await;
+    }
+
+    async fn warm(&self, target: &WarmupTarget) -> Result<(), String> {
+        let oai = OAIChatReq {
+            model: target.model.clone(),
+            messages: vec![OAIMessage {
+                role: "user".into(),
+                content: json!("Hi"),
+                tool_call_id: None,
+                tool_calls: None,
+                prefix: None,
+            }],
+            max_tokens: Some(1),
+            stream: false,
+            ..Default::default()
+        };
+        let mut req = self.client.post(&target.url).timeout(Duration::from_secs(WARMUP_TIMEOUT_SECS));
+        for (name, value) in &self.extra_headers {
+            req = req.header(name, value);
+        }
+        if let Some(key) = &self.api_key {
+            req = req.bearer_auth(key);
+        }
+        let res = json_body(req, &oai, target.gzip_min_bytes).send().await.map_err(|e| e.to_string())?;
+        let status = res.status();
+        let body = res.text().await.map_err(|e| e.to_string())?;
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(format!("{} {}", status.as_u16(), body.chars().take(WARMUP_ERROR_PREVIEW_CHARS).collect::<String>()))
+        }
+    }
+
+    pub fn results(&self) -> Vec<WarmupResult> {
+        self.results.lock().unwrap().clone()
+    }
+}
+
+/// Resolve `model` / `backend=model` entries; unknown backends are skipped with a warning
+fn parse_targets(entries: &[String], backends: &BackendRegistry) -> Vec<WarmupTarget> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let (name, model) = entry.split_once('=').unwrap_or(("default", entry));
+            let Some(backend) = backends.get(name.trim()) else {
+                log::warn!("⚠️  Ignoring WARMUP_MODELS entry '{}': unknown backend '{}'", entry, name.trim());
+                return None;
+            };
+            Some(WarmupTarget {
+                backend: backend.name.clone(),
+                url: backend.url.clone(),
+                model: model.trim().to_string(),
+                gzip_min_bytes: backend.options.request_gzip_min_bytes,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_targets() {
+        let backends = BackendRegistry::new("http://main/v1/chat/completions".into(), &["local=http://local/v1/chat/completions".into()]);
+        let entries = ["zai-org/GLM-4.5-Air".into(), "local=qwen3:8b".into(), "missing=m".into()];
+        let targets = parse_targets(&entries, &backends);
+        let pairs: Vec<(&str, &str, &str)> = targets.iter().map(|t| (t.backend.as_str(), t.url.as_str(), t.model.as_str())).collect();
+        assert_eq!(pairs, vec![
+            ("default", "http://main/v1/chat/completions", "zai-org/GLM-4.5-Air"),
+            ("local", "http://local/v1/chat/completions", "qwen3:8b"),
+        ]);
+    }
+}
@@ -0,0 +1,117 @@
+use base64::Engine;
+use serde_json::{json, Value};
+use crate::constants::{MP3_BYTES_PER_SECOND, TOKENS_PER_AUDIO_SECOND, WAV_BYTES_PER_SECOND};
+
+#[derive(Debug, PartialEq)]
+pub enum AudioError {
+    UnsupportedMediaType(String),
+}
+
+impl AudioError {
+    /// Short error code returned to the client
+    pub fn code(&self) -> &'static str {
+        match self {
+            AudioError::UnsupportedMediaType(_) => "unsupported_audio_media_type",
+        }
+    }
+}
+
+/// OpenAI `input_audio` format for a media type (`wav` or `mp3`)
+pub fn audio_format(media_type: &str) -> Result<&'static str, AudioError> {
+    match media_type.trim().to_ascii_lowercase().as_str() {
+        "audio/wav" | "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => Ok("wav"),
+        "audio/mpeg" | "audio/mp3" => Ok("mp3"),
+        other => Err(AudioError::UnsupportedMediaType(other.to_string())),
+    }
+}
+
+/// Base64 payload, with a `data:` URI prefix removed
+fn payload(data: &str) -> &str {
+    match data.trim_start().strip_prefix("data:") {
+        Some(uri) => uri.split_once(',').map_or("", |(_, payload)| payload),
+        None => data,
+    }
+    .trim()
+}
+
+/// OpenAI content part for a Claude audio block
+pub fn input_audio_part(media_type: &str, data: &str) -> Result<Value, AudioError> {
+    let format = audio_format(media_type)?;
+    Ok(json!({ "type": "input_audio", "input_audio": { "data": payload(data), "format": format } }))
+}
+
+/// WAV byte rate from the `fmt ` chunk of a canonical RIFF header
+fn wav_byte_rate(payload: &str) -> Option<usize> {
+    let head: String = payload.chars().take(48).collect();
+    let header = base64::engine::general_purpose::STANDARD.decode(head).ok()?;
+    if header.get(..4)? != b"RIFF" || header.get(8..16)? != b"WAVEfmt " {
+        return None;
+    }
+    let rate = u32::from_le_bytes(header.get(28..32)?.try_into().ok()?) as usize;
+    (rate > 0).then_some(rate)
+}
+
+/// Estimated input tokens of one audio clip, from its duration
+pub fn estimate_audio_tokens(media_type: &str, data: &str) -> usize {
+    let payload = payload(data);
+    let bytes = payload.trim_end_matches('=').len() * 3 / 4;
+    let bytes_per_second = match audio_format(media_type) {
+        Ok("wav") => wav_byte_rate(payload).unwrap_or(WAV_BYTES_PER_SECOND),
+        _ => MP3_BYTES_PER_SECOND,
+    };
+    (bytes * TOKENS_PER_AUDIO_SECOND).div_ceil(bytes_per_second).max(1)
+}
+
+/// Estimated tokens of all audio blocks in a message's content
+pub fn audio_tokens_in_content(content: &Value) -> usize {
+    let Some(blocks) = content.as_array() else { return 0 };
+    blocks
+        .iter()
+        .filter(|b| b.get("type").and_then(Value::as_str) == Some("audio"))
+        .filter_map(|b| {
+            let source = b.get("source")?;
+            Some(estimate_audio_tokens(source.get("media_type")?.as_str()?, source.get("data")?.as_str()?))
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_audio_part() {
+        assert_eq!(
+            input_audio_part("audio/x-wav", "UklGRg=="),
+            Ok(json!({ "type": "input_audio", "input_audio": { "data": "UklGRg==", "format": "wav" } }))
+        );
+        assert_eq!(
+            input_audio_part("audio/mpeg", "data:audio/mpeg;base64,SUQz"),
+            Ok(json!({ "type": "input_audio", "input_audio": { "data": "SUQz", "format": "mp3" } }))
+        );
+        assert_eq!(input_audio_part("audio/ogg", "T2dnUw=="), Err(AudioError::UnsupportedMediaType("audio/ogg".into())));
+    }
+
+    #[test]
+    fn test_estimate_audio_tokens() {
+        // 44-byte header declaring 8000 bytes/s, followed by 2 seconds of samples
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0\x40\x1f\0\0\x40\x1f\0\0\x01\0\x08\0data\0\0\0\0".to_vec();
+        wav.extend(vec![0u8; 16_000]);
+        let wav = base64::engine::general_purpose::STANDARD.encode(wav);
+        assert_eq!(estimate_audio_tokens("audio/wav", &wav), 21);
+
+        let mp3 = "A".repeat(MP3_BYTES_PER_SECOND * 4 / 3 * 3);
+        assert_eq!(estimate_audio_tokens("audio/mpeg", &mp3), 30);
+        assert_eq!(estimate_audio_tokens("audio/mpeg", "SUQz"), 1);
+    }
+
+    #[test]
+    fn test_audio_tokens_in_content() {
+        let content = json!([
+            { "type": "text", "text": "transcribe" },
+            { "type": "audio", "source": { "type": "base64", "media_type": "audio/mpeg", "data": "A".repeat(MP3_BYTES_PER_SECOND * 4 / 3) } },
+        ]);
+        assert_eq!(audio_tokens_in_content(&content), 10);
+        assert_eq!(audio_tokens_in_content(&json!("text only")), 0);
+    }
+}
@@ -1,66 +1,71 @@
+use std::str::FromStr;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde_json::{json, Value};
+use crate::models::ClaudeImageSource;
+
+/// What a piece of message text is, for the `count_tokens` breakdown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartKind {
+    Text,
+    Thinking,
+    ToolUse,
+    ToolResult,
+}
+
+/// Countable pieces of Claude message content: texts in block order, and image sources
+/// (including images inside tool results)
+#[derive(Debug, Default)]
+pub struct ContentParts<'a> {
+    pub texts: Vec<(PartKind, String)>,
+    pub images: Vec<&'a Value>,
+}
 
-/// Extract text content from Claude content value (string or array of blocks)
-/// Returns tuple: (text_content, image_count)
-pub fn extract_text_from_content(content: &Value) -> (String, usize) {
+/// Split Claude content (string or array of blocks) into countable pieces
+pub fn content_parts(content: &Value) -> ContentParts<'_> {
+    let mut parts = ContentParts::default();
     if let Some(s) = content.as_str() {
-        return (s.to_string(), 0);
-    }
-    if let Some(blocks) = content.as_array() {
-        let mut texts: Vec<String> = Vec::new();
-        let mut image_count = 0;
-        for block in blocks {
-            if let Some(obj) = block.as_object() {
-                let block_type = obj.get("type").and_then(|t| t.as_str());
-                match block_type {
-                    Some("text") => {
-                        if let Some(text) = obj.get("text").and_then(|t| t.as_str()) {
-                            texts.push(text.to_string());
-                        }
-                    }
-                    Some("image") => {
-                        image_count += 1;
-                    }
-                    Some("tool_result") => {
-                        if let Some(result_content) = obj.get("content") {
-                            if let Some(text) = result_content.as_str() {
-                                texts.push(text.to_string());
-                            } else if let Some(arr) = result_content.as_array() {
-                                for item in arr {
-                                    if let Some(text_obj) = item.as_object() {
-                                        if text_obj.get("type").and_then(|t| t.as_str()) == Some("text") {
-                                            if let Some(text) =
-                                                text_obj.get("text").and_then(|t| t.as_str())
-                                            {
-                                                texts.push(text.to_string());
-                                            }
-                                        }
-                                    } else if let Some(text) = item.as_str() {
-                                        texts.push(text.to_string());
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Some("tool_use") => {
-                        if let Some(name) = obj.get("name").and_then(|n| n.as_str()) {
-                            texts.push(name.to_string());
-                        }
-                        if let Some(input) = obj.get("input") {
-                            if let Ok(input_str) = serde_json::to_string(input) {
-                                texts.push(input_str);
+        parts.texts.push((PartKind::Text, s.to_string()));
+        return parts;
+    }
+    for block in content.as_array().into_iter().flatten() {
+        let Some(obj) = block.as_object() else {
+            if let Some(s) = block.as_str() {
+                parts.texts.push((PartKind::Text, s.to_string()));
+            }
+            continue;
+        };
+        let text = |key: &str| obj.get(key).and_then(|t| t.as_str()).map(str::to_string);
+        match obj.get("type").and_then(|t| t.as_str()) {
+            Some("text") => parts.texts.extend(text("text").map(|t| (PartKind::Text, t))),
+            Some("thinking") => parts.texts.extend(text("thinking").map(|t| (PartKind::Thinking, t))),
+            Some("image") => parts.images.push(obj.get("source").unwrap_or(&Value::Null)),
+            Some("search_result") => parts.texts.extend(search_result_value_to_text(block).map(|t| (PartKind::Text, t))),
+            Some("tool_result") => match obj.get("content") {
+                Some(Value::String(text)) => parts.texts.push((PartKind::ToolResult, text.clone())),
+                Some(Value::Array(items)) => {
+                    for item in items {
+                        let result_text = match item.get("type").and_then(|t| t.as_str()) {
+                            Some("search_result") => search_result_value_to_text(item),
+                            Some("text") => item.get("text").and_then(|t| t.as_str()).map(str::to_string),
+                            Some("image") => {
+                                parts.images.push(item.get("source").unwrap_or(&Value::Null));
+                                None
                             }
-                        }
+                            _ => item.as_str().map(str::to_string),
+                        };
+                        parts.texts.extend(result_text.map(|t| (PartKind::ToolResult, t)));
                     }
-                    _ => {}
                 }
-            } else if let Some(s) = block.as_str() {
-                texts.push(s.to_string());
+                _ => {}
+            },
+            Some("tool_use") => {
+                let call = [text("id"), text("name"), obj.get("input").and_then(|i| serde_json::to_string(i).ok())];
+                parts.texts.extend(call.into_iter().flatten().map(|t| (PartKind::ToolUse, t)));
             }
+            _ => {}
         }
-        return (texts.join("\n"), image_count);
     }
-    (String::new(), 0)
+    parts
 }
 
 /// Convert Claude system prompt value (string or array of blocks) into OpenAI system content
@@ -93,6 +98,8 @@ pub fn serialize_tool_result_content(content: &Value) -> String {
                 if let Some(obj) = item.as_object() {
                     if obj.get("type").and_then(|t| t.as_str()) == Some("text") {
                         obj.get("text").and_then(|t| t.as_str()).map(String::from)
+                    } else if obj.get("type").and_then(|t| t.as_str()) == Some("search_result") {
+                        search_result_value_to_text(item)
                     } else {
                         Some(serde_json::to_string(item).unwrap_or_else(|_| "{}".into()))
                     }
@@ -108,18 +115,94 @@ pub fn serialize_tool_result_content(content: &Value) -> String {
     serde_json::to_string(content).unwrap_or_else(|_| "{}".into())
 }
 
-/// Build OpenAI tools array from Claude tools
-pub fn build_oai_tools(tools: Option<Vec<crate::models::ClaudeTool>>) -> Option<Vec<crate::models::OAITool>> {
+/// Render a Claude `search_result` block as plain text for backends without citation support
+pub fn search_result_to_text(source: &str, title: &str, content: &Value) -> String {
+    let body = match content {
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items
+            .iter()
+            .filter_map(|item| item.get("text").and_then(|t| t.as_str()).or_else(|| item.as_str()))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    };
+    format!("[Search result: {}]\nSource: {}\n{}", title, source, body)
+}
+
+/// Convert a Claude `document` block: text documents become a text part headed by the title, others
+/// (PDFs) an OpenAI `file` part carrying a data URL
+pub fn document_part(source: &ClaudeImageSource, title: Option<&str>) -> Value {
+    let text = match source.source_type.as_str() {
+        "text" => Some(source.data.clone()),
+        _ if source.media_type.starts_with("text/") => {
+            BASE64.decode(source.data.trim()).ok().and_then(|bytes| String::from_utf8(bytes).ok())
+        }
+        _ => None,
+    };
+    match text {
+        Some(text) => json!({
+            "type": "text",
+            "text": title.map(|title| format!("{}\n\n{}", title, text)).unwrap_or(text)
+        }),
+        None => json!({
+            "type": "file",
+            "file": {
+                "filename": title.unwrap_or("document"),
+                "file_data": format!("data:{};base64,{}", source.media_type, source.data)
+            }
+        }),
+    }
+}
+
+/// `search_result_to_text` for an untyped JSON block
+fn search_result_value_to_text(block: &Value) -> Option<String> {
+    let source = block.get("source")?.as_str()?;
+    let title = block.get("title").and_then(|t| t.as_str()).unwrap_or("");
+    Some(search_result_to_text(source, title, block.get("content").unwrap_or(&Value::Null)))
+}
+
+/// Convert an OpenAI `url_citation` annotation into a Claude citation object
+pub fn annotation_to_citation(annotation: &Value) -> Option<Value> {
+    if annotation.get("type").and_then(|t| t.as_str()) != Some("url_citation") {
+        return None;
+    }
+    let cite = annotation.get("url_citation").unwrap_or(annotation);
+    let url = cite.get("url")?.as_str()?;
+    Some(json!({
+        "type": "web_search_result_location",
+        "url": url,
+        "title": cite.get("title").and_then(|t| t.as_str()).unwrap_or(url),
+        "cited_text": cite.get("content").and_then(|t| t.as_str()).unwrap_or(""),
+        "encrypted_index": "",
+    }))
+}
+
+/// Build OpenAI tools array from Claude tools; `cache_control` is kept only with `prompt_caching`
+pub fn build_oai_tools(
+    tools: Option<Vec<crate::models::ClaudeTool>>,
+    cleaning: &crate::utils::tool_schema::SchemaCleaning,
+    prompt_caching: bool,
+) -> Option<Vec<crate::models::OAITool>> {
     match tools {
         Some(ts) if !ts.is_empty() => Some(
             ts.into_iter()
-                .map(|t| crate::models::OAITool {
-                    type_: "function".into(),
-                    function: crate::models::OAIFunction {
-                        name: t.name,
-                        description: t.description,
-                        parameters: t.input_schema,
-                    },
+                .map(|mut t| {
+                    if !cleaning.is_noop() {
+                        cleaning.clean_schema(&mut t.input_schema);
+                        if let Some(d) = t.description.as_mut() {
+                            cleaning.shorten(d);
+                        }
+                    }
+                    crate::models::OAITool {
+                        type_: "function".into(),
+                        function: crate::models::OAIFunction {
+                            name: t.name,
+                            description: t.description,
+                            parameters: t.input_schema,
+                            strict: None,
+                        },
+                        cache_control: t.cache_control.filter(|_| prompt_caching),
+                    }
                 })
                 .collect::<Vec<_>>(),
         ),
@@ -206,7 +289,7 @@ pub fn translate_finish_reason(oai_reason: Option<&str>) -> &'static str {
         Some("stop") => "end_turn",
         Some("length") => "max_tokens",
         Some("tool_calls") | Some("function_call") => "tool_use",
-        Some("content_filter") => "end_turn", // No direct equivalent
+        Some("content_filter") => "refusal",
         Some("error") => "error",
         Some(other) => {
             log::debug!("⚠️  Unknown finish_reason '{}', using 'end_turn'", other);
@@ -216,15 +299,78 @@ pub fn translate_finish_reason(oai_reason: Option<&str>) -> &'static str {
     }
 }
 
+/// Claude stop_reason reported for OpenAI `content_filter` finishes (`CONTENT_FILTER_STOP_REASON`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ContentFilterStopReason {
+    #[default]
+    Refusal,
+    /// Report filtered responses as normal completions (previous behaviour)
+    EndTurn,
+}
+
+impl ContentFilterStopReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentFilterStopReason::Refusal => "refusal",
+            ContentFilterStopReason::EndTurn => "end_turn",
+        }
+    }
+}
+
+impl FromStr for ContentFilterStopReason {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "refusal" => Ok(ContentFilterStopReason::Refusal),
+            "end_turn" => Ok(ContentFilterStopReason::EndTurn),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Map backend prompt-cache statistics to Claude usage fields.
+///
+/// Returns `None` when the backend reported no cache information. Claude's `input_tokens`
+/// excludes cached tokens, so it is recomputed from `prompt_tokens` when available.
+pub fn claude_cache_usage(usage: &crate::models::OAIUsage) -> Option<Value> {
+    let cache_read = usage
+        .prompt_tokens_details
+        .as_ref()
+        .and_then(|d| d.cached_tokens)
+        .or(usage.prompt_cache_hit_tokens);
+    let cache_creation = usage.cache_creation_input_tokens;
+    if cache_read.is_none() && cache_creation.is_none() {
+        return None;
+    }
+    let cache_read = cache_read.unwrap_or(0);
+    let cache_creation = cache_creation.unwrap_or(0);
+
+    let mut fields = json!({
+        "cache_creation_input_tokens": cache_creation,
+        "cache_read_input_tokens": cache_read,
+    });
+    if let Some(prompt_tokens) = usage.prompt_tokens {
+        fields["input_tokens"] = json!(prompt_tokens.saturating_sub(cache_read + cache_creation));
+    }
+    Some(fields)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use serde_json::json;
 
     // ============================================================================
-    // extract_text_from_content tests
+    // content_parts tests
     // ============================================================================
 
+    /// All texts joined, and the image count
+    fn extract_text_from_content(content: &Value) -> (String, usize) {
+        let parts = content_parts(content);
+        (parts.texts.into_iter().map(|(_, text)| text).collect::<Vec<_>>().join("\n"), parts.images.len())
+    }
+
     #[test]
     fn test_extract_text_simple_string() {
         let content = json!("Hello, world!");
@@ -296,6 +442,27 @@ mod tests {
         assert_eq!(images, 0);
     }
 
+    #[test]
+    fn test_content_parts_kinds() {
+        let content = json!([
+            {"type": "thinking", "thinking": "Let me look", "signature": "sig"},
+            {"type": "tool_use", "id": "toolu_1", "name": "read", "input": {"path": "a.rs"}},
+            {"type": "tool_result", "tool_use_id": "toolu_1", "content": [
+                {"type": "text", "text": "fn main() {}"},
+                {"type": "image", "source": {"type": "base64", "data": "..."}}
+            ]}
+        ]);
+        let parts = content_parts(&content);
+        assert_eq!(parts.texts, vec![
+            (PartKind::Thinking, "Let me look".to_string()),
+            (PartKind::ToolUse, "toolu_1".to_string()),
+            (PartKind::ToolUse, "read".to_string()),
+            (PartKind::ToolUse, r#"{"path":"a.rs"}"#.to_string()),
+            (PartKind::ToolResult, "fn main() {}".to_string()),
+        ]);
+        assert_eq!(parts.images, vec![&json!({"type": "base64", "data": "..."})]);
+    }
+
     #[test]
     fn test_extract_text_tool_result_string() {
         let content = json!([
@@ -520,6 +687,19 @@ mod tests {
         assert_eq!(parallel, None);
     }
 
+    #[test]
+    fn test_build_oai_tools_forwards_cache_control_with_prompt_caching() {
+        let tools: Vec<crate::models::ClaudeTool> = serde_json::from_value(json!([
+            { "name": "read", "input_schema": { "type": "object" }, "cache_control": { "type": "ephemeral" } }
+        ]))
+        .unwrap();
+        let cleaning = crate::utils::tool_schema::SchemaCleaning::default();
+        let cached = build_oai_tools(Some(tools.clone()), &cleaning, true).unwrap();
+        assert_eq!(serde_json::to_value(&cached[0]).unwrap()["cache_control"], json!({ "type": "ephemeral" }));
+        let plain = build_oai_tools(Some(tools), &cleaning, false).unwrap();
+        assert!(serde_json::to_value(&plain[0]).unwrap().get("cache_control").is_none());
+    }
+
     #[test]
     fn test_convert_tool_choice_disable_parallel() {
         let (result, parallel) = convert_tool_choice(Some(json!({
@@ -556,7 +736,14 @@ mod tests {
 
     #[test]
     fn test_translate_finish_reason_content_filter() {
-        assert_eq!(translate_finish_reason(Some("content_filter")), "end_turn");
+        assert_eq!(translate_finish_reason(Some("content_filter")), "refusal");
+    }
+
+    #[test]
+    fn test_content_filter_stop_reason_from_str() {
+        assert_eq!("refusal".parse(), Ok(ContentFilterStopReason::Refusal));
+        assert_eq!(" END_TURN ".parse(), Ok(ContentFilterStopReason::EndTurn));
+        assert!("stop".parse::<ContentFilterStopReason>().is_err());
     }
 
     #[test]
@@ -578,4 +765,112 @@ mod tests {
     fn test_translate_finish_reason_empty_string() {
         assert_eq!(translate_finish_reason(Some("")), "end_turn");
     }
+
+    // ============================================================================
+    // claude_cache_usage tests
+    // ============================================================================
+
+    fn usage(value: Value) -> crate::models::OAIUsage {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_claude_cache_usage_openai_cached_tokens() {
+        let u = usage(json!({"prompt_tokens": 1000, "prompt_tokens_details": {"cached_tokens": 800}}));
+        assert_eq!(
+            claude_cache_usage(&u),
+            Some(json!({"input_tokens": 200, "cache_read_input_tokens": 800, "cache_creation_input_tokens": 0}))
+        );
+    }
+
+    #[test]
+    fn test_claude_cache_usage_deepseek_hit_tokens() {
+        let u = usage(json!({"prompt_tokens": 500, "prompt_cache_hit_tokens": 300, "prompt_cache_miss_tokens": 200}));
+        assert_eq!(claude_cache_usage(&u).unwrap()["cache_read_input_tokens"], 300);
+        assert_eq!(claude_cache_usage(&u).unwrap()["input_tokens"], 200);
+    }
+
+    #[test]
+    fn test_claude_cache_usage_creation_without_prompt_tokens() {
+        let u = usage(json!({"cache_creation_input_tokens": 64}));
+        assert_eq!(
+            claude_cache_usage(&u),
+            Some(json!({"cache_read_input_tokens": 0, "cache_creation_input_tokens": 64}))
+        );
+    }
+
+    #[test]
+    fn test_claude_cache_usage_absent() {
+        assert_eq!(claude_cache_usage(&usage(json!({"prompt_tokens": 10}))), None);
+    }
+
+    // ============================================================================
+    // citation tests
+    // ============================================================================
+
+    #[test]
+    fn test_search_result_to_text() {
+        let content = json!([{"type": "text", "text": "Rust 1.80 released"}, {"type": "text", "text": "with LazyLock"}]);
+        assert_eq!(
+            search_result_to_text("https://blog.rust-lang.org", "Rust 1.80", &content),
+            "[Search result: Rust 1.80]\nSource: https://blog.rust-lang.org\nRust 1.80 released\nwith LazyLock"
+        );
+    }
+
+    #[test]
+    fn test_serialize_tool_result_with_search_result() {
+        let content = json!([{
+            "type": "search_result",
+            "source": "kb://doc/1",
+            "title": "Doc",
+            "content": [{"type": "text", "text": "body"}],
+            "citations": {"enabled": true}
+        }]);
+        assert_eq!(serialize_tool_result_content(&content), "[Search result: Doc]\nSource: kb://doc/1\nbody");
+    }
+
+    #[test]
+    fn test_extract_text_counts_search_results() {
+        let content = json!([{"type": "search_result", "source": "s", "title": "t", "content": [{"type": "text", "text": "abc"}]}]);
+        let (text, images) = extract_text_from_content(&content);
+        assert!(text.contains("abc"));
+        assert_eq!(images, 0);
+    }
+
+    #[test]
+    fn test_annotation_to_citation() {
+        let annotation = json!({
+            "type": "url_citation",
+            "url_citation": {"url": "https://example.com", "title": "Example", "start_index": 0, "end_index": 5}
+        });
+        assert_eq!(
+            annotation_to_citation(&annotation),
+            Some(json!({
+                "type": "web_search_result_location",
+                "url": "https://example.com",
+                "title": "Example",
+                "cited_text": "",
+                "encrypted_index": ""
+            }))
+        );
+        assert_eq!(annotation_to_citation(&json!({"type": "file_citation"})), None);
+    }
+
+    #[test]
+    fn test_document_part() {
+        let source = |source_type: &str, media_type: &str, data: &str| ClaudeImageSource {
+            source_type: source_type.into(),
+            media_type: media_type.into(),
+            data: data.into(),
+        };
+        assert_eq!(
+            document_part(&source("text", "text/plain", "line one"), Some("notes.txt")),
+            json!({ "type": "text", "text": "notes.txt\n\nline one" })
+        );
+        assert_eq!(document_part(&source("base64", "text/csv", "YSxi"), None), json!({ "type": "text", "text": "a,b" }));
+        assert_eq!(
+            document_part(&source("base64", "application/pdf", "JVBERi0="), Some("spec.pdf")),
+            json!({ "type": "file", "file": { "filename": "spec.pdf", "file_data": "data:application/pdf;base64,JVBERi0=" } })
+        );
+    }
 }
@@ -0,0 +1,324 @@
+use serde_json::{json, Value};
+use crate::models::OAIMessage;
+
+/// Rename `system` messages for backends that expect OpenAI's newer `developer` role (or vice versa)
+pub fn rename_system_role(msgs: &mut [OAIMessage], system_role: &str) {
+    for m in msgs.iter_mut() {
+        if (m.role == "system" || m.role == "developer") && m.role != system_role {
+            m.role = system_role.to_string();
+        }
+    }
+}
+
+/// Merge consecutive messages with the same role for strict-alternation chat templates.
+///
+/// Tool messages are never merged (each answers its own `tool_call_id`). Returns the number
+/// of messages folded into their predecessor.
+pub fn merge_same_role(msgs: &mut Vec<OAIMessage>) -> usize {
+    let before = msgs.len();
+    let mut merged: Vec<OAIMessage> = Vec::with_capacity(msgs.len());
+    for m in msgs.drain(..) {
+        match merged.last_mut() {
+            Some(prev) if prev.role == m.role && m.role != "tool" && prev.tool_calls.is_none() => {
+                prev.content = concat_content(std::mem::take(&mut prev.content), m.content);
+                prev.tool_calls = m.tool_calls;
+                prev.prefix = m.prefix;
+            }
+            _ => merged.push(m),
+        }
+    }
+    *msgs = merged;
+    before - msgs.len()
+}
+
+/// Text of the user turn inserted when a conversation doesn't start with one
+const PLACEHOLDER_USER_TURN: &str = "(continue)";
+
+/// Outcome of `repair_ordering`
+#[derive(Debug, Default, PartialEq)]
+pub struct OrderingRepair {
+    pub changed: bool,
+    /// `tool_call_id`s of the results dropped for answering no call in the conversation
+    pub orphaned: Vec<String>,
+}
+
+/// Fix orderings strict backends reject.
+///
+/// - A user turn is inserted when the first non-system message isn't from the user
+/// - Tool messages are moved directly after the assistant message whose `tool_calls` they answer
+/// - Tool messages with no matching call are dropped and reported in `orphaned`
+pub fn repair_ordering(msgs: &mut Vec<OAIMessage>) -> OrderingRepair {
+    let original: Vec<(String, Option<String>)> =
+        msgs.iter().map(|m| (m.role.clone(), m.tool_call_id.clone())).collect();
+
+    let (mut tools, rest): (Vec<OAIMessage>, Vec<OAIMessage>) = msgs.drain(..).partition(|m| m.role == "tool");
+    let mut repaired = Vec::with_capacity(rest.len() + tools.len() + 1);
+    for m in rest {
+        let call_ids: Vec<String> = m
+            .tool_calls
+            .iter()
+            .flatten()
+            .filter_map(|c| c["id"].as_str().map(str::to_string))
+            .collect();
+        repaired.push(m);
+        for id in call_ids {
+            if let Some(pos) = tools.iter().position(|t| t.tool_call_id.as_deref() == Some(id.as_str())) {
+                repaired.push(tools.remove(pos));
+            }
+        }
+    }
+    // Whatever is left answers no call in this conversation
+    let orphaned: Vec<String> = tools.into_iter().map(|t| t.tool_call_id.unwrap_or_default()).collect();
+
+    let first_turn = repaired.iter().position(|m| m.role != "system" && m.role != "developer");
+    if let Some(i) = first_turn.filter(|&i| repaired[i].role != "user") {
+        repaired.insert(i, OAIMessage {
+            role: "user".into(),
+            content: Value::String(PLACEHOLDER_USER_TURN.into()),
+            tool_call_id: None,
+            tool_calls: None,
+            prefix: None,
+        });
+    }
+
+    *msgs = repaired;
+    let changed = msgs.len() != original.len()
+        || msgs.iter().zip(&original).any(|(m, (role, id))| &m.role != role || &m.tool_call_id != id);
+    OrderingRepair { changed, orphaned }
+}
+
+/// Notice telling the client which tool results were dropped by `repair_ordering`
+pub fn orphaned_results_notice(orphaned: &[String]) -> String {
+    format!(
+        "The proxy dropped {} tool result(s) that answer no tool call in this conversation (REPAIR_ORDERING): {}",
+        orphaned.len(),
+        orphaned.join(", ")
+    )
+}
+
+/// Fold tool traffic into plain text for backends that reject the `tool` role (`TOOL_RESULTS_AS_TEXT`).
+///
+/// Assistant `tool_calls` become `[Tool call <id>: <name>]` lines followed by the arguments, and
+/// tool results become user text headed `[Result of tool call <id>]`, merged with neighbouring user
+/// messages so turns still alternate. Returns the number of calls and results folded.
+pub fn fold_tool_messages(msgs: &mut Vec<OAIMessage>) -> usize {
+    let mut folded = 0;
+    let mut out: Vec<OAIMessage> = Vec::with_capacity(msgs.len());
+    let mut last_folded = false;
+    for mut m in msgs.drain(..) {
+        if let Some(calls) = m.tool_calls.take() {
+            let mut text = m.content.as_str().unwrap_or_default().to_string();
+            for call in &calls {
+                let function = &call["function"];
+                text.push_str(&format!(
+                    "\n\n[Tool call {}: {}]\n{}",
+                    call["id"].as_str().unwrap_or_default(),
+                    function["name"].as_str().unwrap_or_default(),
+                    function["arguments"].as_str().unwrap_or("{}")
+                ));
+            }
+            folded += calls.len();
+            m.content = Value::String(text.trim_start().to_string());
+        }
+        let is_result = m.role == "tool";
+        if is_result {
+            folded += 1;
+            m = tool_result_as_user(m);
+        }
+        match out.last_mut() {
+            Some(prev) if m.role == "user" && prev.role == "user" && (is_result || last_folded) => {
+                prev.content = concat_content(std::mem::take(&mut prev.content), m.content);
+            }
+            _ => out.push(m),
+        }
+        last_folded = is_result;
+    }
+    *msgs = out;
+    folded
+}
+
+/// User message carrying a tool result as text
+fn tool_result_as_user(tool: OAIMessage) -> OAIMessage {
+    let id = tool.tool_call_id.unwrap_or_default();
+    let text = match tool.content {
+        Value::String(s) => s,
+        other => other.to_string(),
+    };
+    OAIMessage {
+        role: "user".into(),
+        content: Value::String(format!("[Result of tool call {}]\n{}", id, text)),
+        tool_call_id: None,
+        tool_calls: None,
+        prefix: None,
+    }
+}
+
+/// Join two OpenAI message contents (strings or content-part arrays)
+fn concat_content(a: Value, b: Value) -> Value {
+    let is_empty = |v: &Value| v.is_null() || v.as_str() == Some("");
+    if is_empty(&a) {
+        return b;
+    }
+    if is_empty(&b) {
+        return a;
+    }
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => Value::String(format!("{}\n\n{}", a, b)),
+        (a, b) => {
+            let mut parts = into_parts(a);
+            parts.extend(into_parts(b));
+            Value::Array(parts)
+        }
+    }
+}
+
+fn into_parts(v: Value) -> Vec<Value> {
+    match v {
+        Value::Array(parts) => parts,
+        Value::String(s) => vec![json!({ "type": "text", "text": s })],
+        other => vec![json!({ "type": "text", "text": other.to_string() })],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(role: &str, content: Value) -> OAIMessage {
+        OAIMessage {
+            role: role.into(),
+            content,
+            tool_call_id: None,
+            tool_calls: None,
+            prefix: None,
+        }
+    }
+
+    fn tool(id: &str, content: &str) -> OAIMessage {
+        OAIMessage { tool_call_id: Some(id.into()), ..msg("tool", json!(content)) }
+    }
+
+    fn calls(ids: &[&str]) -> OAIMessage {
+        let mut m = msg("assistant", Value::Null);
+        m.tool_calls = Some(ids.iter().map(|id| json!({"id": id, "type": "function"})).collect());
+        m
+    }
+
+    fn roles(msgs: &[OAIMessage]) -> Vec<&str> {
+        msgs.iter().map(|m| m.role.as_str()).collect()
+    }
+
+    #[test]
+    fn test_repair_leaves_valid_conversation_alone() {
+        let mut msgs = vec![
+            msg("system", json!("sys")),
+            msg("user", json!("hi")),
+            calls(&["a"]),
+            tool("a", "ok"),
+            msg("assistant", json!("done")),
+        ];
+        assert_eq!(repair_ordering(&mut msgs), OrderingRepair::default());
+        assert_eq!(roles(&msgs), ["system", "user", "assistant", "tool", "assistant"]);
+    }
+
+    #[test]
+    fn test_repair_inserts_user_turn_before_leading_assistant() {
+        let mut msgs = vec![msg("system", json!("sys")), msg("assistant", json!("hello")), msg("user", json!("hi"))];
+        assert!(repair_ordering(&mut msgs).changed);
+        assert_eq!(roles(&msgs), ["system", "user", "assistant", "user"]);
+        assert_eq!(msgs[1].content, json!(PLACEHOLDER_USER_TURN));
+    }
+
+    #[test]
+    fn test_repair_moves_tool_results_after_their_call() {
+        let mut msgs = vec![
+            msg("user", json!("hi")),
+            calls(&["a", "b"]),
+            msg("user", json!("interjection")),
+            tool("b", "second"),
+            tool("a", "first"),
+        ];
+        assert!(repair_ordering(&mut msgs).changed);
+        assert_eq!(roles(&msgs), ["user", "assistant", "tool", "tool", "user"]);
+        assert_eq!(msgs[2].tool_call_id.as_deref(), Some("a"));
+        assert_eq!(msgs[3].tool_call_id.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn test_repair_drops_orphan_tool_results() {
+        let mut msgs = vec![msg("user", json!("hi")), tool("gone", "result"), calls(&["a"]), tool("a", "ok"), msg("user", json!("next"))];
+        let repair = repair_ordering(&mut msgs);
+        assert!(repair.changed);
+        assert_eq!(repair.orphaned, ["gone"]);
+        assert_eq!(roles(&msgs), ["user", "assistant", "tool", "user"]);
+        assert!(orphaned_results_notice(&repair.orphaned).contains("gone"));
+    }
+
+    #[test]
+    fn test_rename_system_role() {
+        let mut msgs = vec![msg("system", json!("be brief")), msg("user", json!("hi"))];
+        rename_system_role(&mut msgs, "developer");
+        assert_eq!(msgs[0].role, "developer");
+        rename_system_role(&mut msgs, "system");
+        assert_eq!(msgs[0].role, "system");
+        assert_eq!(msgs[1].role, "user");
+    }
+
+    #[test]
+    fn test_merge_consecutive_user_messages() {
+        let mut msgs = vec![msg("user", json!("a")), msg("user", json!("b")), msg("assistant", json!("c"))];
+        assert_eq!(merge_same_role(&mut msgs), 1);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].content, json!("a\n\nb"));
+    }
+
+    #[test]
+    fn test_merge_mixed_content_becomes_parts() {
+        let image = json!([{"type": "image_url", "image_url": {"url": "data:x"}}]);
+        let mut msgs = vec![msg("user", json!("look")), msg("user", image)];
+        merge_same_role(&mut msgs);
+        assert_eq!(msgs[0].content.as_array().unwrap().len(), 2);
+        assert_eq!(msgs[0].content[0], json!({"type": "text", "text": "look"}));
+    }
+
+    #[test]
+    fn test_tool_messages_and_tool_calls_are_not_merged() {
+        let mut with_calls = msg("assistant", json!(""));
+        with_calls.tool_calls = Some(vec![json!({"id": "t1"})]);
+        let mut msgs = vec![
+            with_calls,
+            msg("assistant", json!("after")),
+            msg("tool", json!("r1")),
+            msg("tool", json!("r2")),
+        ];
+        assert_eq!(merge_same_role(&mut msgs), 0);
+        assert_eq!(msgs.len(), 4);
+    }
+
+    #[test]
+    fn test_merge_keeps_later_tool_calls() {
+        let mut second = msg("assistant", json!(""));
+        second.tool_calls = Some(vec![json!({"id": "t1"})]);
+        let mut msgs = vec![msg("assistant", json!("thinking out loud")), second];
+        merge_same_role(&mut msgs);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].content, json!("thinking out loud"));
+        assert!(msgs[0].tool_calls.is_some());
+    }
+
+    #[test]
+    fn test_fold_tool_messages() {
+        let mut msgs = vec![msg("user", json!("list files")), calls(&["call_1", "call_2"]), tool("call_1", "a.rs"), tool("call_2", "b.rs"), msg("user", json!("thanks"))];
+        assert_eq!(fold_tool_messages(&mut msgs), 4);
+        assert_eq!(roles(&msgs), ["user", "assistant", "user"]);
+        assert!(msgs[1].tool_calls.is_none());
+        let assistant = msgs[1].content.as_str().unwrap();
+        assert!(assistant.starts_with("[Tool call call_1: "), "{}", assistant);
+        assert!(assistant.contains("[Tool call call_2: "));
+        assert_eq!(
+            msgs[2].content,
+            json!("[Result of tool call call_1]\na.rs\n\n[Result of tool call call_2]\nb.rs\n\nthanks")
+        );
+        assert!(msgs.iter().all(|m| m.role != "tool" && m.tool_call_id.is_none()));
+    }
+}
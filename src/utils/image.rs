@@ -0,0 +1,412 @@
+use base64::Engine;
+use serde_json::Value;
+use crate::constants::{DEFAULT_MAX_IMAGES, DEFAULT_MAX_IMAGE_BYTES, IMAGE_HEADER_MAX_BYTES, IMAGE_MAX_LONG_EDGE, IMAGE_MAX_TOKENS, IMAGE_PIXELS_PER_TOKEN, TOKENS_PER_IMAGE};
+use crate::utils::model_matches_pattern;
+
+/// Media types Claude accepts for base64 image blocks, and the default `IMAGE_FORMATS`
+pub const SUPPORTED_IMAGE_MEDIA_TYPES: &[&str] = &["image/jpeg", "image/png", "image/gif", "image/webp"];
+
+/// Formats the `image-transcode` build can decode; others, such as HEIC and AVIF, are rejected
+/// without trying
+const TRANSCODABLE_MEDIA_TYPES: &[&str] = &["image/jpeg", "image/png", "image/gif", "image/webp", "image/bmp"];
+
+/// Base64 characters decoded to detect the image format (48 bytes of image data)
+const SNIFF_BASE64_CHARS: usize = 64;
+
+#[derive(Debug, PartialEq)]
+pub enum ImageError {
+    UnsupportedMediaType(String),
+    /// Not a base64 payload (or a `data:` URI without one), or bytes that couldn't be decoded as an image
+    InvalidData,
+    /// More images than `MAX_IMAGES` allows
+    TooManyImages { limit: usize },
+    /// An image over `MAX_IMAGE_BYTES` (decoded size)
+    TooLarge { bytes: usize, limit: usize },
+}
+
+impl ImageError {
+    /// Short error code returned to the client
+    pub fn code(&self) -> &'static str {
+        match self {
+            ImageError::UnsupportedMediaType(_) => "unsupported_image_media_type",
+            ImageError::InvalidData => "invalid_image_data",
+            ImageError::TooManyImages { .. } => "too_many_images",
+            ImageError::TooLarge { .. } => "image_too_large",
+        }
+    }
+}
+
+/// Which image formats a backend accepts, and whether others are converted (`IMAGE_FORMATS`, `IMAGE_TRANSCODE`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImagePolicy {
+    pub formats: Vec<String>,
+    /// Convert other formats to PNG (or JPEG) instead of rejecting them; needs the `image-transcode` feature
+    pub transcode: bool,
+}
+
+impl Default for ImagePolicy {
+    fn default() -> Self {
+        Self { formats: SUPPORTED_IMAGE_MEDIA_TYPES.iter().map(|t| t.to_string()).collect(), transcode: false }
+    }
+}
+
+impl ImagePolicy {
+    /// Formats given as `png`, `jpg` or `image/png`; an empty list keeps the default
+    pub fn new(formats: &[String], transcode: bool) -> Self {
+        let mut policy = Self { transcode, ..Self::default() };
+        if !formats.is_empty() {
+            policy.formats = formats
+                .iter()
+                .map(|f| if f.contains('/') { normalize_media_type(f) } else { normalize_media_type(&format!("image/{}", f)) })
+                .collect();
+        }
+        policy
+    }
+
+    fn allows(&self, media_type: &str) -> bool {
+        self.formats.iter().any(|f| f == media_type)
+    }
+}
+
+/// Image count and size limits for one request (`MAX_IMAGES`, `MAX_IMAGE_BYTES`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageLimits {
+    pub max_images: usize,
+    pub max_image_bytes: usize,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self { max_images: DEFAULT_MAX_IMAGES, max_image_bytes: DEFAULT_MAX_IMAGE_BYTES }
+    }
+}
+
+/// Per-model replacement for either limit (`IMAGE_LIMITS_MODELS` entry `pattern=count:bytes`)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImageLimitsOverride {
+    pub max_images: Option<usize>,
+    pub max_image_bytes: Option<usize>,
+}
+
+/// Parse `IMAGE_LIMITS_MODELS` entries; either side of `count:bytes` may be empty (`llava*=1`, `qwen-vl*=:1048576`)
+pub fn parse_image_limit_overrides(entries: &[String]) -> Vec<(String, ImageLimitsOverride)> {
+    let optional = |v: &str| if v.trim().is_empty() { Some(None) } else { v.trim().parse().ok().map(Some) };
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let parsed = entry.split_once('=').and_then(|(pattern, limits)| {
+                let (count, bytes) = limits.split_once(':').unwrap_or((limits, ""));
+                let limits = ImageLimitsOverride { max_images: optional(count)?, max_image_bytes: optional(bytes)? };
+                Some((pattern.trim().to_string(), limits))
+            });
+            if parsed.is_none() {
+                log::warn!("⚠️  Ignoring malformed IMAGE_LIMITS_MODELS entry '{}'", entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+impl ImageLimits {
+    /// Limits for `model`: the first matching override replaces the limits it sets
+    pub fn for_model(self, model: &str, overrides: &[(String, ImageLimitsOverride)]) -> Self {
+        match overrides.iter().find(|(pattern, _)| model_matches_pattern(model, pattern)) {
+            Some((_, o)) => Self {
+                max_images: o.max_images.unwrap_or(self.max_images),
+                max_image_bytes: o.max_image_bytes.unwrap_or(self.max_image_bytes),
+            },
+            None => self,
+        }
+    }
+
+    /// Check the `count`-th image (1-based) of a request, whose base64 `data` may be a data URI
+    pub fn check(&self, count: usize, data: &str) -> Result<(), ImageError> {
+        if count > self.max_images {
+            return Err(ImageError::TooManyImages { limit: self.max_images });
+        }
+        let payload = data.split_once(";base64,").map_or(data, |(_, payload)| payload);
+        let bytes = payload.trim_end().trim_end_matches('=').len() * 3 / 4;
+        if bytes > self.max_image_bytes {
+            return Err(ImageError::TooLarge { bytes, limit: self.max_image_bytes });
+        }
+        Ok(())
+    }
+}
+
+/// Lowercased media type, with the common `image/jpg` alias mapped to `image/jpeg`
+fn normalize_media_type(media_type: &str) -> String {
+    match media_type.trim().to_ascii_lowercase().as_str() {
+        "image/jpg" => "image/jpeg".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Image format from the file signature
+pub fn sniff_media_type(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some("image/png"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'B', b'M', ..] => Some("image/bmp"),
+        [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => Some("image/tiff"),
+        [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] => match brand.get(..4)? {
+            b"avif" | b"avis" => Some("image/avif"),
+            b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" | b"mif1" | b"msf1" => Some("image/heic"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Format of a base64 payload, from its first bytes
+fn sniff_base64(payload: &str) -> Result<Option<&'static str>, ImageError> {
+    let head: String = payload.chars().filter(|c| !c.is_ascii_whitespace()).take(SNIFF_BASE64_CHARS).collect();
+    // Whole 4-character groups only, so a prefix of a longer payload still decodes
+    let head = &head[..head.len() - head.len() % 4];
+    let bytes = base64::engine::general_purpose::STANDARD.decode(head).map_err(|_| ImageError::InvalidData)?;
+    Ok(sniff_media_type(&bytes))
+}
+
+/// OpenAI `image_url` value for a Claude base64 image source.
+///
+/// Clients sometimes send `data` as a complete data URI; it is unwrapped rather than prefixed a
+/// second time. The format detected from the bytes wins over the declared media type, and formats
+/// the backend doesn't accept are transcoded (when enabled) or rejected. Transcoding decodes the
+/// whole image, so it runs on the blocking pool.
+pub async fn image_data_uri(media_type: &str, data: &str, policy: &ImagePolicy) -> Result<String, ImageError> {
+    let (declared, payload) = match data.trim_start().strip_prefix("data:") {
+        Some(uri) => {
+            let (header, payload) = uri.split_once(',').ok_or(ImageError::InvalidData)?;
+            let uri_media_type = header.strip_suffix(";base64").ok_or(ImageError::InvalidData)?;
+            // Parameters such as `;charset=...` may precede `;base64`
+            (normalize_media_type(uri_media_type.split(';').next().unwrap_or_default()), payload.trim())
+        }
+        None => (normalize_media_type(media_type), data.trim()),
+    };
+    let media_type = match sniff_base64(payload)? {
+        Some(actual) if actual != declared => {
+            log::warn!("🖼️ Image declared as {} is {} - using the detected type", declared, actual);
+            actual.to_string()
+        }
+        _ => declared,
+    };
+    if policy.allows(&media_type) {
+        return Ok(format!("data:{};base64,{}", media_type, payload));
+    }
+    if policy.transcode && TRANSCODABLE_MEDIA_TYPES.contains(&media_type.as_str()) {
+        let (payload, policy) = (payload.to_string(), policy.clone());
+        return tokio::task::spawn_blocking(move || transcode(&payload, &media_type, &policy))
+            .await
+            .map_err(|_| ImageError::InvalidData)?;
+    }
+    Err(ImageError::UnsupportedMediaType(media_type))
+}
+
+/// Re-encode as PNG, or JPEG when the backend doesn't take PNG
+#[cfg(feature = "image-transcode")]
+fn transcode(payload: &str, media_type: &str, policy: &ImagePolicy) -> Result<String, ImageError> {
+    use image::ImageFormat;
+    let (target, format) = if policy.allows("image/png") {
+        ("image/png", ImageFormat::Png)
+    } else if policy.allows("image/jpeg") {
+        ("image/jpeg", ImageFormat::Jpeg)
+    } else {
+        return Err(ImageError::UnsupportedMediaType(media_type.to_string()));
+    };
+    let engine = base64::engine::general_purpose::STANDARD;
+    let bytes = engine.decode(payload).map_err(|_| ImageError::InvalidData)?;
+    let decoded = image::load_from_memory(&bytes).map_err(|e| {
+        log::warn!("🖼️ Can't transcode {} image: {}", media_type, e);
+        ImageError::UnsupportedMediaType(media_type.to_string())
+    })?;
+    let decoded = if format == ImageFormat::Jpeg { image::DynamicImage::ImageRgb8(decoded.to_rgb8()) } else { decoded };
+    let mut out = std::io::Cursor::new(Vec::new());
+    decoded.write_to(&mut out, format).map_err(|_| ImageError::InvalidData)?;
+    log::info!("🖼️ Transcoded {} image ({} bytes) to {} ({} bytes)", media_type, bytes.len(), target, out.get_ref().len());
+    Ok(format!("data:{};base64,{}", target, engine.encode(out.get_ref())))
+}
+
+#[cfg(not(feature = "image-transcode"))]
+fn transcode(_payload: &str, media_type: &str, _policy: &ImagePolicy) -> Result<String, ImageError> {
+    Err(ImageError::UnsupportedMediaType(media_type.to_string()))
+}
+
+/// Width and height from a JPEG, PNG, GIF or WebP header
+pub fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| Some(u16::from_be_bytes([*bytes.get(i)?, *bytes.get(i + 1)?]) as u32);
+    let le16 = |i: usize| Some(u16::from_le_bytes([*bytes.get(i)?, *bytes.get(i + 1)?]) as u32);
+    let le24 = |i: usize| Some(u32::from_le_bytes([*bytes.get(i)?, *bytes.get(i + 1)?, *bytes.get(i + 2)?, 0]));
+    match sniff_media_type(bytes)? {
+        "image/png" => Some((be16(16)? << 16 | be16(18)?, be16(20)? << 16 | be16(22)?)),
+        "image/gif" => Some((le16(6)?, le16(8)?)),
+        "image/webp" => match bytes.get(12..16)? {
+            b"VP8 " => Some((le16(26)? & 0x3FFF, le16(28)? & 0x3FFF)),
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(bytes.get(21..25)?.try_into().ok()?);
+                Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+            }
+            b"VP8X" => Some((le24(24)? + 1, le24(27)? + 1)),
+            _ => None,
+        },
+        "image/jpeg" => {
+            // Walk the segments up to the start-of-frame marker that holds the size
+            let mut i = 2;
+            loop {
+                if *bytes.get(i)? != 0xFF {
+                    return None;
+                }
+                let marker = *bytes.get(i + 1)?;
+                match marker {
+                    0xFF => i += 1,
+                    0xD0..=0xD9 | 0x01 => i += 2,
+                    0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => return Some((be16(i + 7)?, be16(i + 5)?)),
+                    _ => i += 2 + be16(i + 2)? as usize,
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Claude's token estimate for a `width`×`height` image: scaled to fit the long-edge limit, then
+/// one token per 750 pixels, capped
+pub fn image_tokens_for_size(width: u32, height: u32) -> usize {
+    let scale = (IMAGE_MAX_LONG_EDGE / width.max(height).max(1) as f64).min(1.0);
+    let pixels = width as f64 * scale * height as f64 * scale;
+    ((pixels / IMAGE_PIXELS_PER_TOKEN).ceil() as usize).clamp(1, IMAGE_MAX_TOKENS)
+}
+
+/// Input tokens of a Claude image `source`: from its size for base64 images, a flat estimate otherwise.
+/// Only the first `IMAGE_HEADER_MAX_BYTES` of the image are decoded.
+pub fn image_source_tokens(source: &Value) -> usize {
+    let data = source.get("data").and_then(Value::as_str).unwrap_or_default();
+    let payload = data.split_once(";base64,").map_or(data, |(_, payload)| payload);
+    // Whole 4-character groups, so the prefix decodes without the padding of the full payload
+    let mut prefix: String = payload.chars().filter(|c| !c.is_ascii_whitespace()).take(IMAGE_HEADER_MAX_BYTES / 3 * 4).collect();
+    prefix.truncate(prefix.len() / 4 * 4);
+    base64::engine::general_purpose::STANDARD
+        .decode(prefix)
+        .ok()
+        .and_then(|bytes| image_dimensions(&bytes))
+        .map_or(TOKENS_PER_IMAGE, |(width, height)| image_tokens_for_size(width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn uri(media_type: &str, data: &str) -> Result<String, ImageError> {
+        image_data_uri(media_type, data, &ImagePolicy::default()).await
+    }
+
+    #[tokio::test]
+    async fn test_plain_base64_is_prefixed() {
+        assert_eq!(uri("image/png", "iVBORw0KGgo=").await, Ok("data:image/png;base64,iVBORw0KGgo=".into()));
+        assert_eq!(uri("IMAGE/JPG", "/9j/4AAQ").await, Ok("data:image/jpeg;base64,/9j/4AAQ".into()));
+    }
+
+    #[tokio::test]
+    async fn test_data_uri_is_not_double_prefixed() {
+        assert_eq!(uri("image/png", "data:image/png;base64,iVBORw0KGgo=").await, Ok("data:image/png;base64,iVBORw0KGgo=".into()));
+        // The URI's own media type describes the bytes
+        assert_eq!(uri("image/png", "data:image/jpeg;base64,/9j/").await, Ok("data:image/jpeg;base64,/9j/".into()));
+        assert_eq!(uri("image/gif", "data:image/gif;name=a.gif;base64,R0lG").await, Ok("data:image/gif;base64,R0lG".into()));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_inputs_are_rejected() {
+        assert_eq!(uri("image/bmp", "Qk0=").await, Err(ImageError::UnsupportedMediaType("image/bmp".into())));
+        assert_eq!(uri("image/png", "data:image/svg+xml;base64,PHN2Zz4=").await, Err(ImageError::UnsupportedMediaType("image/svg+xml".into())));
+        assert_eq!(uri("image/png", "data:image/png,rawbytes").await, Err(ImageError::InvalidData));
+        assert_eq!(uri("image/png", "data:image/png;base64").await, Err(ImageError::InvalidData));
+        assert_eq!(uri("image/png", "<svg></svg>").await, Err(ImageError::InvalidData));
+    }
+
+    #[test]
+    fn test_image_dimensions_and_tokens() {
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 13, b'I', b'H', b'D', b'R'];
+        png.extend_from_slice(&1000u32.to_be_bytes());
+        png.extend_from_slice(&500u32.to_be_bytes());
+        assert_eq!(image_dimensions(&png), Some((1000, 500)));
+        assert_eq!(image_dimensions(b"GIF89a\x40\x01\xF0\x00"), Some((320, 240)));
+        // SOI, an APP0 segment, then SOF0 with height 600 and width 800
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0, 0, 0xFF, 0xC0, 0x00, 0x11, 0x08, 0x02, 0x58, 0x03, 0x20];
+        assert_eq!(image_dimensions(&jpeg), Some((800, 600)));
+        assert_eq!(image_dimensions(b"not an image"), None);
+
+        assert_eq!(image_tokens_for_size(1000, 500), 667);
+        // Scaled to a 1568px long edge, then capped
+        assert_eq!(image_tokens_for_size(4000, 3000), IMAGE_MAX_TOKENS);
+        assert_eq!(image_tokens_for_size(1, 1), 1);
+
+        let source = serde_json::json!({"type": "base64", "media_type": "image/png", "data": base64::engine::general_purpose::STANDARD.encode(&png)});
+        assert_eq!(image_source_tokens(&source), 667);
+        assert_eq!(image_source_tokens(&serde_json::json!({"type": "url", "url": "https://example.com/a.png"})), TOKENS_PER_IMAGE);
+
+        // Only the header is decoded: the rest of a large payload isn't even looked at
+        png.resize(IMAGE_HEADER_MAX_BYTES * 2, 0);
+        let data = format!("{}not base64", base64::engine::general_purpose::STANDARD.encode(&png));
+        assert_eq!(image_source_tokens(&serde_json::json!({"type": "base64", "media_type": "image/png", "data": data})), 667);
+    }
+
+    #[tokio::test]
+    async fn test_detected_type_overrides_declared() {
+        assert_eq!(uri("image/png", "/9j/4AAQ").await, Ok("data:image/jpeg;base64,/9j/4AAQ".into()));
+        assert_eq!(uri("image/jpeg", "R0lGODlhAQAB").await, Ok("data:image/gif;base64,R0lGODlhAQAB".into()));
+        // HEIC labelled as JPEG would otherwise fail at the backend
+        assert_eq!(uri("image/jpeg", "AAAAGGZ0eXBoZWljAAAAAA==").await, Err(ImageError::UnsupportedMediaType("image/heic".into())));
+    }
+
+    #[test]
+    fn test_sniff_media_type() {
+        assert_eq!(sniff_media_type(b"\x89PNG\r\n\x1a\n...."), Some("image/png"));
+        assert_eq!(sniff_media_type(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff_media_type(b"\0\0\0\x1cftypavif"), Some("image/avif"));
+        assert_eq!(sniff_media_type(b"\0\0\0\x1cftypmp42"), None);
+        assert_eq!(sniff_media_type(b"<svg>"), None);
+    }
+
+    #[tokio::test]
+    async fn test_backend_formats() {
+        let policy = ImagePolicy::new(&["png".into(), "JPG".into()], false);
+        assert_eq!(policy.formats, vec!["image/png", "image/jpeg"]);
+        assert_eq!(image_data_uri("image/png", "iVBORw0KGgo=", &policy).await, Ok("data:image/png;base64,iVBORw0KGgo=".into()));
+        assert_eq!(image_data_uri("image/webp", "UklGRgAAAABXRUJQ", &policy).await, Err(ImageError::UnsupportedMediaType("image/webp".into())));
+        assert_eq!(ImagePolicy::new(&[], true).formats, ImagePolicy::default().formats);
+    }
+
+    #[test]
+    fn test_image_limits() {
+        let limits = ImageLimits { max_images: 2, max_image_bytes: 8 };
+        assert_eq!(limits.check(2, "iVBORw0KGgo="), Ok(()));
+        assert_eq!(limits.check(3, "iVBORw0KGgo="), Err(ImageError::TooManyImages { limit: 2 }));
+        assert_eq!(limits.check(1, "data:image/png;base64,iVBORw0KGgoAAAA"), Err(ImageError::TooLarge { bytes: 11, limit: 8 }));
+
+        let overrides = parse_image_limit_overrides(&["llava*=1".into(), "qwen-vl=:1024".into(), "bad=x:1".into(), "noequals".into()]);
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(limits.for_model("LLaVA-13b", &overrides), ImageLimits { max_images: 1, max_image_bytes: 8 });
+        assert_eq!(limits.for_model("qwen-vl", &overrides), ImageLimits { max_images: 2, max_image_bytes: 1024 });
+        assert_eq!(limits.for_model("gpt-4o", &overrides), limits);
+    }
+
+    #[cfg(feature = "image-transcode")]
+    #[tokio::test]
+    async fn test_transcode() {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let mut bmp = std::io::Cursor::new(Vec::new());
+        image::RgbaImage::from_pixel(2, 2, image::Rgba([255, 0, 0, 255])).write_to(&mut bmp, image::ImageFormat::Bmp).unwrap();
+        let bmp = engine.encode(bmp.get_ref());
+
+        let png = image_data_uri("image/bmp", &bmp, &ImagePolicy::new(&["png".into()], true)).await.unwrap();
+        let png = engine.decode(png.strip_prefix("data:image/png;base64,").unwrap()).unwrap();
+        assert_eq!(image::load_from_memory(&png).unwrap().width(), 2);
+
+        let jpeg = image_data_uri("image/bmp", &bmp, &ImagePolicy::new(&["jpeg".into()], true)).await.unwrap();
+        assert!(jpeg.starts_with("data:image/jpeg;base64,/9j/"));
+        assert_eq!(
+            image_data_uri("image/heic", "AAAAGGZ0eXBoZWljAAAAAA==", &ImagePolicy::new(&["png".into()], true)).await,
+            Err(ImageError::UnsupportedMediaType("image/heic".into()))
+        );
+    }
+}
@@ -1,4 +1,10 @@
+pub mod audio;
 pub mod content_extraction;
+pub mod conversation;
+pub mod image;
 pub mod model_normalization;
+pub mod prefill;
+pub mod tool_ids;
+pub mod tool_schema;
 
 pub use model_normalization::*;
\ No newline at end of file
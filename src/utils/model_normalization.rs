@@ -1,12 +1,14 @@
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::models::ModelInfo;
+use crate::constants::OPENROUTER_MODEL_VARIANTS;
+use crate::models::ModelsCache;
 
-/// Passthrough model with case-correction from cache
-pub async fn normalize_model_name(model: &str, models_cache: &Arc<RwLock<Option<Vec<ModelInfo>>>>) -> String {
+/// Passthrough model with case-correction from cache.
+///
+/// OpenRouter routing suffixes (`:free`, `:nitro`, `@preset/...`) are kept as sent while the model
+/// id before them is case-corrected, unless the cache lists the suffixed id itself.
+pub async fn normalize_model_name(model: &str, models_cache: &ModelsCache) -> String {
     let model_lower = model.to_lowercase();
-    let cache = models_cache.read().await;
-    if let Some(models) = cache.as_ref() {
+    let cache = models_cache.read().await.clone();
+    if let Some(models) = cache {
         if models.iter().any(|m| m.id == model) {
             return model.to_string();
         }
@@ -14,6 +16,66 @@ pub async fn normalize_model_name(model: &str, models_cache: &Arc<RwLock<Option<
             log::info!("🔄 Model: {} → {} (case-corrected)", model, matched.id);
             return matched.id.clone();
         }
+        let (base, suffix) = split_model_suffix(model);
+        if !suffix.is_empty() {
+            if let Some(matched) = models.iter().find(|m| m.id.eq_ignore_ascii_case(base)) {
+                if matched.id != base {
+                    log::info!("🔄 Model: {} → {}{} (case-corrected, suffix kept)", model, matched.id, suffix);
+                }
+                return format!("{}{}", matched.id, suffix);
+            }
+        }
     }
     model.to_string()
-}
\ No newline at end of file
+}
+
+/// Split `model` into its id and an OpenRouter routing suffix: a known `:variant`
+/// (`OPENROUTER_MODEL_VARIANTS`) and/or `@preset/...`. Other colons, such as Ollama tags in
+/// `qwen3:8b`, are part of the id.
+pub fn split_model_suffix(model: &str) -> (&str, &str) {
+    let rest = match model.find('@') {
+        Some(at) if at > 0 => &model[..at],
+        _ => model,
+    };
+    let base = match rest.rsplit_once(':') {
+        Some((base, variant)) if !base.is_empty() && OPENROUTER_MODEL_VARIANTS.iter().any(|v| v.eq_ignore_ascii_case(variant)) => base,
+        _ => rest,
+    };
+    model.split_at(base.len())
+}
+
+/// Case-insensitive model match where a trailing `*` in `pattern` matches a prefix
+pub fn model_matches_pattern(model: &str, pattern: &str) -> bool {
+    let model = model.to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_suffix('*') {
+        Some(prefix) => model.starts_with(prefix),
+        None => model == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+    use crate::models::ModelInfo;
+
+    #[test]
+    fn test_split_model_suffix() {
+        assert_eq!(split_model_suffix("meta-llama/llama-3.3-70b-instruct:free"), ("meta-llama/llama-3.3-70b-instruct", ":free"));
+        assert_eq!(split_model_suffix("openai/gpt-4o:nitro@preset/coding"), ("openai/gpt-4o", ":nitro@preset/coding"));
+        assert_eq!(split_model_suffix("openai/gpt-4o@preset/coding"), ("openai/gpt-4o", "@preset/coding"));
+        assert_eq!(split_model_suffix("qwen3:8b"), ("qwen3:8b", ""));
+        assert_eq!(split_model_suffix("@preset/coding"), ("@preset/coding", ""));
+    }
+
+    #[tokio::test]
+    async fn test_suffix_survives_case_correction() {
+        let info = |id: &str| ModelInfo { id: id.into(), input_price_usd: None, output_price_usd: None, supported_features: Vec::new() };
+        let cache: ModelsCache = Arc::new(RwLock::new(Some(Arc::new(vec![info("Qwen/Qwen3-Coder"), info("deepseek/deepseek-r1:free")]))));
+        assert_eq!(normalize_model_name("qwen/qwen3-coder:NITRO", &cache).await, "Qwen/Qwen3-Coder:NITRO");
+        assert_eq!(normalize_model_name("DeepSeek/DeepSeek-R1:free", &cache).await, "deepseek/deepseek-r1:free");
+        assert_eq!(normalize_model_name("unknown/model:free", &cache).await, "unknown/model:free");
+    }
+}
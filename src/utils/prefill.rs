@@ -0,0 +1,151 @@
+use std::str::FromStr;
+use serde_json::{json, Value};
+use crate::models::OAIChatReq;
+
+/// How a trailing assistant message ("prefill") is sent to the backend
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PrefillMode {
+    /// Forward as a completed assistant turn (previous behaviour)
+    #[default]
+    Passthrough,
+    /// vLLM / SGLang: `continue_final_message: true, add_generation_prompt: false`
+    Continue,
+    /// DeepSeek / Mistral style: `"prefix": true` on the final assistant message
+    Prefix,
+    /// Drop the assistant message and ask the model to continue from it in the last user turn
+    Splice,
+}
+
+impl FromStr for PrefillMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "passthrough" => Ok(PrefillMode::Passthrough),
+            "continue" => Ok(PrefillMode::Continue),
+            "prefix" => Ok(PrefillMode::Prefix),
+            "splice" => Ok(PrefillMode::Splice),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Apply the prefill translation when the request ends with a non-empty assistant message.
+///
+/// Returns `true` if a prefill was found and rewritten.
+pub fn apply_prefill(oai: &mut OAIChatReq, mode: PrefillMode) -> bool {
+    let Some(last) = oai.messages.last() else {
+        return false;
+    };
+    let prefill = match last.content.as_str() {
+        Some(text) if last.role == "assistant" && !text.is_empty() && last.tool_calls.is_none() => text.to_string(),
+        _ => return false,
+    };
+
+    match mode {
+        PrefillMode::Passthrough => return false,
+        PrefillMode::Continue => {
+            oai.continue_final_message = Some(true);
+            oai.add_generation_prompt = Some(false);
+        }
+        PrefillMode::Prefix => {
+            if let Some(last) = oai.messages.last_mut() {
+                last.prefix = Some(true);
+            }
+        }
+        PrefillMode::Splice => {
+            let Some(user) = oai.messages.iter_mut().rev().skip(1).find(|m| m.role == "user") else {
+                return false;
+            };
+            let instruction = format!(
+                "\n\nBegin your response with exactly the following text, then continue it. \
+                 Do not repeat it in your reply; output only what comes after it:\n{}",
+                prefill
+            );
+            match &mut user.content {
+                Value::String(s) => s.push_str(&instruction),
+                Value::Array(blocks) => blocks.push(json!({ "type": "text", "text": instruction })),
+                _ => return false,
+            }
+            oai.messages.pop();
+        }
+    }
+    log::info!("✍️  Assistant prefill ({} chars) sent as {:?}", prefill.len(), mode);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OAIMessage;
+
+    fn message(role: &str, content: Value) -> OAIMessage {
+        OAIMessage {
+            role: role.into(),
+            content,
+            tool_call_id: None,
+            tool_calls: None,
+            prefix: None,
+        }
+    }
+
+    fn request(last_assistant: &str) -> OAIChatReq {
+        OAIChatReq {
+            model: "m".into(),
+            messages: vec![message("user", json!("Write JSON")), message("assistant", json!(last_assistant))],
+            max_tokens: None,
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            stop: None,
+            tools: None,
+            tool_choice: None,
+            thinking: None,
+            parallel_tool_calls: None,
+            metadata: None,
+            stream: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_continue_mode_sets_vllm_flags() {
+        let mut req = request("{\"a\":");
+        assert!(apply_prefill(&mut req, PrefillMode::Continue));
+        assert_eq!(req.continue_final_message, Some(true));
+        assert_eq!(req.add_generation_prompt, Some(false));
+        assert_eq!(req.messages.len(), 2);
+    }
+
+    #[test]
+    fn test_prefix_mode_marks_last_message() {
+        let mut req = request("{\"a\":");
+        assert!(apply_prefill(&mut req, PrefillMode::Prefix));
+        assert_eq!(req.messages[1].prefix, Some(true));
+        assert_eq!(req.continue_final_message, None);
+    }
+
+    #[test]
+    fn test_splice_mode_moves_prefill_into_user_turn() {
+        let mut req = request("{\"a\":");
+        assert!(apply_prefill(&mut req, PrefillMode::Splice));
+        assert_eq!(req.messages.len(), 1);
+        assert!(req.messages[0].content.as_str().unwrap().ends_with("{\"a\":"));
+    }
+
+    #[test]
+    fn test_passthrough_and_non_prefill_requests_are_untouched() {
+        let mut req = request("{\"a\":");
+        assert!(!apply_prefill(&mut req, PrefillMode::Passthrough));
+
+        let mut req = request("");
+        assert!(!apply_prefill(&mut req, PrefillMode::Continue));
+        assert_eq!(req.continue_final_message, None);
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!("Continue".parse(), Ok(PrefillMode::Continue));
+        assert!("bogus".parse::<PrefillMode>().is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Prefix of tool_use IDs sent to the client for backend-generated tool calls
+const CLIENT_PREFIX: &str = "toolu_";
+
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Tool call ID rules of a backend (`TOOL_ID_FORMAT`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ToolIdFormat {
+    /// IDs are forwarded unchanged in both directions
+    #[default]
+    Passthrough,
+    /// Mistral: exactly 9 characters from `[a-zA-Z0-9]`
+    Mistral,
+}
+
+impl FromStr for ToolIdFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "passthrough" => Ok(ToolIdFormat::Passthrough),
+            "mistral" => Ok(ToolIdFormat::Mistral),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToolIdFormat {
+    fn accepts(&self, id: &str) -> bool {
+        match self {
+            ToolIdFormat::Passthrough => true,
+            ToolIdFormat::Mistral => id.len() == 9 && id.bytes().all(|b| b.is_ascii_alphanumeric()),
+        }
+    }
+
+    /// Deterministic backend ID for `id`; `salt` resolves collisions
+    fn derive(&self, id: &str, salt: u64) -> String {
+        let mut hash = id.bytes().fold(0xcbf29ce484222325u64 ^ salt, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+        (0..9)
+            .map(|_| {
+                let c = BASE62[(hash % 62) as usize] as char;
+                hash /= 62;
+                c
+            })
+            .collect()
+    }
+}
+
+/// Tool ID mapping for one request.
+///
+/// IDs from the conversation history are rewritten into IDs the backend accepts, consistently for a
+/// `tool_use` and its `tool_result`. Backend IDs in the response reach the client with a `toolu_`
+/// prefix, which is stripped again on the next turn, so the same call keeps the same backend ID
+/// across the round trip without any state outside the request.
+#[derive(Debug, Default)]
+pub struct ToolIdMap {
+    format: ToolIdFormat,
+    to_backend: HashMap<String, String>,
+    to_client: HashMap<String, String>,
+}
+
+impl ToolIdMap {
+    pub fn new(format: ToolIdFormat) -> Self {
+        Self { format, ..Default::default() }
+    }
+
+    /// Backend ID for a Claude `tool_use` / `tool_result` ID
+    pub fn backend_id(&mut self, id: &str) -> String {
+        if self.format == ToolIdFormat::Passthrough {
+            return id.to_string();
+        }
+        if let Some(mapped) = self.to_backend.get(id) {
+            return mapped.clone();
+        }
+        let unprefixed = id.strip_prefix(CLIENT_PREFIX).unwrap_or(id);
+        let mut mapped = if self.format.accepts(unprefixed) { unprefixed.to_string() } else { self.format.derive(id, 0) };
+        let mut salt = 1;
+        while self.to_client.contains_key(&mapped) {
+            mapped = self.format.derive(id, salt);
+            salt += 1;
+        }
+        self.to_backend.insert(id.to_string(), mapped.clone());
+        self.to_client.insert(mapped.clone(), id.to_string());
+        mapped
+    }
+
+    /// Claude ID for a tool call ID from the backend response
+    pub fn client_id(&self, backend_id: &str) -> String {
+        if self.format == ToolIdFormat::Passthrough {
+            return backend_id.to_string();
+        }
+        match self.to_client.get(backend_id) {
+            Some(original) => original.clone(),
+            None if backend_id.starts_with(CLIENT_PREFIX) => backend_id.to_string(),
+            None => format!("{}{}", CLIENT_PREFIX, backend_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passthrough_keeps_ids() {
+        let mut ids = ToolIdMap::new(ToolIdFormat::Passthrough);
+        assert_eq!(ids.backend_id("toolu_01A09q90qw90lq917835lq9"), "toolu_01A09q90qw90lq917835lq9");
+        assert_eq!(ids.client_id("call_abc"), "call_abc");
+    }
+
+    #[test]
+    fn test_mistral_ids_round_trip() {
+        let mut ids = ToolIdMap::new(ToolIdFormat::Mistral);
+        let long = ids.backend_id("toolu_01A09q90qw90lq917835lq9");
+        assert!(ToolIdFormat::Mistral.accepts(&long), "{}", long);
+        assert_eq!(ids.backend_id("toolu_01A09q90qw90lq917835lq9"), long, "tool_result maps like its tool_use");
+        assert_eq!(ids.client_id(&long), "toolu_01A09q90qw90lq917835lq9");
+
+        // A backend ID handed to the client comes back unchanged on the next turn
+        let client = ids.client_id("D681PevKs");
+        assert_eq!(client, "toolu_D681PevKs");
+        assert_eq!(ToolIdMap::new(ToolIdFormat::Mistral).backend_id(&client), "D681PevKs");
+    }
+
+    #[test]
+    fn test_mistral_ids_do_not_collide() {
+        let mut ids = ToolIdMap::new(ToolIdFormat::Mistral);
+        let first = ids.backend_id("toolu_abc123XYZ");
+        let second = ids.backend_id("abc123XYZ");
+        assert_eq!(first, "abc123XYZ");
+        assert_ne!(first, second);
+        assert!(ToolIdFormat::Mistral.accepts(&second));
+    }
+}
@@ -0,0 +1,431 @@
+use std::str::FromStr;
+use serde_json::{json, Value};
+use crate::constants::{COMPACT_SCHEMA_KEYWORDS, TOKEN_EFFICIENT_DESCRIPTION_CHARS};
+use crate::models::{OAIChatReq, OAITool};
+
+/// Keywords whose values map names to subschemas rather than being schemas themselves
+const SCHEMA_MAPS: &[&str] = &["properties", "patternProperties", "$defs", "definitions"];
+
+/// Per-backend rewriting of tool definitions for backends with strict schema validation
+#[derive(Clone, Debug, Default)]
+pub struct SchemaCleaning {
+    /// JSON Schema keywords removed at every level, e.g. `$schema`, `format`, `additionalProperties`
+    pub strip_keywords: Vec<String>,
+    /// Tool and property descriptions longer than this many characters are truncated
+    pub max_description_chars: Option<usize>,
+    /// Descriptions are collapsed to a single line before truncating
+    pub collapse_whitespace: bool,
+}
+
+impl SchemaCleaning {
+    pub fn is_noop(&self) -> bool {
+        self.strip_keywords.is_empty() && self.max_description_chars.is_none() && !self.collapse_whitespace
+    }
+
+    /// Tighter cleaning for clients that enabled the token-efficient tools beta: annotation keywords
+    /// are dropped and descriptions collapsed to one line of at most `TOKEN_EFFICIENT_DESCRIPTION_CHARS`
+    pub fn compact(&self) -> Self {
+        let mut strip_keywords = self.strip_keywords.clone();
+        strip_keywords.extend(COMPACT_SCHEMA_KEYWORDS.iter().map(|k| k.to_string()));
+        Self {
+            strip_keywords,
+            max_description_chars: Some(self.max_description_chars.map_or(TOKEN_EFFICIENT_DESCRIPTION_CHARS, |max| max.min(TOKEN_EFFICIENT_DESCRIPTION_CHARS))),
+            collapse_whitespace: true,
+        }
+    }
+
+    /// Strip unsupported keywords and shorten descriptions throughout a schema
+    pub fn clean_schema(&self, schema: &mut Value) {
+        match schema {
+            Value::Object(map) => {
+                map.retain(|k, _| !self.strip_keywords.iter().any(|s| s == k));
+                for (key, value) in map.iter_mut() {
+                    if SCHEMA_MAPS.contains(&key.as_str()) {
+                        // Property names are not keywords: only clean the subschemas
+                        if let Value::Object(subschemas) = value {
+                            subschemas.values_mut().for_each(|s| self.clean_schema(s));
+                        }
+                    } else if key == "description" {
+                        if let Value::String(d) = value {
+                            self.shorten(d);
+                        }
+                    } else {
+                        self.clean_schema(value);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|s| self.clean_schema(s)),
+            _ => {}
+        }
+    }
+
+    /// Shorten a description to `max_description_chars`, ending it with an ellipsis
+    pub fn shorten(&self, description: &mut String) {
+        if self.collapse_whitespace {
+            *description = description.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        let Some(max) = self.max_description_chars else {
+            return;
+        };
+        if let Some((cut, _)) = description.char_indices().nth(max) {
+            description.truncate(cut);
+            description.push('…');
+        }
+    }
+}
+
+/// How tool arguments are constrained to their schemas (`STRICT_TOOLS`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum StrictTools {
+    #[default]
+    Off,
+    /// `"strict": true` on function definitions (OpenAI structured outputs)
+    OpenAI,
+    /// Strict function definitions plus `guided_json` when `tool_choice` names a single tool
+    Vllm,
+}
+
+impl FromStr for StrictTools {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" | "false" | "none" => Ok(StrictTools::Off),
+            "openai" | "true" => Ok(StrictTools::OpenAI),
+            "vllm" => Ok(StrictTools::Vllm),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Rewrite a schema into the subset strict mode accepts, or `None` if it can't be made strict.
+///
+/// Every object gets `additionalProperties: false` and lists all properties as required;
+/// properties that were optional become nullable (see `strip_null_arguments`). `oneOf` becomes
+/// `anyOf` and `allOf` is merged into its schema. Free-form objects, `patternProperties` and
+/// `allOf` parts that contradict each other have no strict equivalent.
+pub fn make_strict(schema: &Value) -> Option<Value> {
+    let mut strict = schema.clone();
+    strictify(&mut strict, true).then_some(strict)
+}
+
+fn strictify(schema: &mut Value, root: bool) -> bool {
+    let Value::Object(map) = schema else {
+        return true;
+    };
+    if map.contains_key("patternProperties") {
+        return false;
+    }
+    // Strict mode only knows `anyOf`, which constrains generation the same way
+    if let Some(one_of) = map.remove("oneOf") {
+        if map.contains_key("anyOf") {
+            return false;
+        }
+        map.insert("anyOf".into(), one_of);
+    }
+    if let Some(all_of) = map.remove("allOf") {
+        if !merge_all_of(map, all_of) {
+            return false;
+        }
+    }
+    let is_object = map.get("type").and_then(Value::as_str) == Some("object") || map.contains_key("properties");
+    if is_object {
+        match map.get("additionalProperties") {
+            None | Some(Value::Bool(false)) => {}
+            Some(_) => return false,
+        }
+        if !map.contains_key("properties") && !root {
+            return false;
+        }
+        let required: Vec<String> = map
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|r| r.iter().filter_map(|v| v.as_str().map(str::to_string)).collect())
+            .unwrap_or_default();
+        let properties = map.entry("properties").or_insert_with(|| json!({}));
+        let Value::Object(properties) = properties else {
+            return false;
+        };
+        for (name, property) in properties.iter_mut() {
+            if !strictify(property, false) {
+                return false;
+            }
+            if !required.contains(name) {
+                make_nullable(property);
+            }
+        }
+        let all: Vec<Value> = properties.keys().map(|k| Value::String(k.clone())).collect();
+        map.insert("required".into(), Value::Array(all));
+        map.insert("additionalProperties".into(), Value::Bool(false));
+    }
+    for key in ["items", "anyOf", "$defs", "definitions"] {
+        let ok = match map.get_mut(key) {
+            Some(Value::Array(subschemas)) => subschemas.iter_mut().all(|s| strictify(s, false)),
+            Some(Value::Object(defs)) if key.contains("def") => defs.values_mut().all(|s| strictify(s, false)),
+            Some(items @ Value::Object(_)) => strictify(items, false),
+            _ => true,
+        };
+        if !ok {
+            return false;
+        }
+    }
+    true
+}
+
+/// Merge the parts of an `allOf` into the schema holding it: properties and required lists add
+/// up, any other keyword must agree
+fn merge_all_of(map: &mut serde_json::Map<String, Value>, all_of: Value) -> bool {
+    let Value::Array(parts) = all_of else {
+        return false;
+    };
+    for part in parts {
+        let Value::Object(part) = part else {
+            return false;
+        };
+        for (key, value) in part {
+            match (key.as_str(), map.get_mut(&key), value) {
+                ("properties", Some(Value::Object(existing)), Value::Object(properties)) => existing.extend(properties),
+                ("required", Some(Value::Array(existing)), Value::Array(required)) => existing.extend(required),
+                (_, Some(existing), value) => {
+                    if *existing != value {
+                        return false;
+                    }
+                }
+                (_, None, value) => {
+                    map.insert(key, value);
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Tool arguments from a strict schema without the `null`s standing in for omitted optional
+/// properties, so clients see the arguments their schema describes; unparsable arguments are
+/// returned unchanged
+pub fn strip_null_arguments(arguments: &str) -> String {
+    fn strip(value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                map.retain(|_, v| !v.is_null());
+                map.values_mut().for_each(strip);
+            }
+            Value::Array(items) => items.iter_mut().for_each(strip),
+            _ => {}
+        }
+    }
+    match serde_json::from_str::<Value>(arguments) {
+        Ok(mut value) => {
+            strip(&mut value);
+            value.to_string()
+        }
+        Err(_) => arguments.to_string(),
+    }
+}
+
+/// Allow `null` for a formerly optional property
+fn make_nullable(schema: &mut Value) {
+    let Value::Object(map) = schema else {
+        return;
+    };
+    match map.get_mut("type") {
+        Some(Value::String(t)) if t != "null" => {
+            let t = std::mem::take(t);
+            map.insert("type".into(), json!([t, "null"]));
+        }
+        Some(Value::Array(types)) if !types.iter().any(|t| t == "null") => types.push(json!("null")),
+        Some(_) => {}
+        None => {
+            let original = Value::Object(std::mem::take(map));
+            map.insert("anyOf".into(), json!([original, {"type": "null"}]));
+        }
+    }
+    // An enum must list null as well or the null type is unusable
+    if let Some(Value::Array(values)) = map.get_mut("enum") {
+        if !values.contains(&Value::Null) {
+            values.push(Value::Null);
+        }
+    }
+}
+
+/// Mark tool definitions strict, skipping tools in `exclude` and schemas that can't be made strict.
+/// Returns the names of tools left non-strict.
+pub fn apply_strict_tools(oai: &mut OAIChatReq, mode: StrictTools, exclude: &[String]) -> Vec<String> {
+    let mut skipped = Vec::new();
+    if mode == StrictTools::Off {
+        return skipped;
+    }
+    let tools: &mut [OAITool] = oai.tools.as_deref_mut().unwrap_or_default();
+    for tool in tools.iter_mut() {
+        let f = &mut tool.function;
+        if exclude.iter().any(|name| name == &f.name) {
+            continue;
+        }
+        match make_strict(&f.parameters) {
+            Some(schema) => {
+                f.parameters = schema;
+                f.strict = Some(true);
+            }
+            None => skipped.push(f.name.clone()),
+        }
+    }
+    if mode == StrictTools::Vllm {
+        let forced = oai.tool_choice.as_ref().and_then(|c| c["function"]["name"].as_str());
+        oai.guided_json = forced
+            .and_then(|name| tools.iter().find(|t| t.function.name == name && t.function.strict == Some(true)))
+            .map(|t| t.function.parameters.clone());
+    }
+    skipped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn cleaning(strip: &[&str], max: Option<usize>) -> SchemaCleaning {
+        SchemaCleaning {
+            strip_keywords: strip.iter().map(|s| s.to_string()).collect(),
+            max_description_chars: max,
+            collapse_whitespace: false,
+        }
+    }
+
+    #[test]
+    fn test_make_strict_requires_all_and_nulls_optional() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "path": {"type": "string"},
+                "limit": {"type": "integer"},
+                "mode": {"enum": ["a", "b"]}
+            },
+            "required": ["path"]
+        });
+        let strict = make_strict(&schema).unwrap();
+        assert_eq!(strict["additionalProperties"], json!(false));
+        assert_eq!(strict["required"], json!(["limit", "mode", "path"]));
+        assert_eq!(strict["properties"]["path"]["type"], json!("string"));
+        assert_eq!(strict["properties"]["limit"]["type"], json!(["integer", "null"]));
+        assert_eq!(strict["properties"]["mode"]["anyOf"][1], json!({"type": "null"}));
+    }
+
+    #[test]
+    fn test_make_strict_handles_one_of_and_all_of() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "target": {"oneOf": [{"type": "string"}, {"type": "integer"}]}
+            },
+            "allOf": [
+                {"properties": {"path": {"type": "string"}}, "required": ["path"]},
+                {"properties": {"depth": {"type": "integer"}}}
+            ],
+            "required": ["target"]
+        });
+        let strict = make_strict(&schema).unwrap();
+        assert!(strict.get("allOf").is_none());
+        assert_eq!(strict["required"], json!(["depth", "path", "target"]));
+        assert_eq!(strict["properties"]["target"]["anyOf"], json!([{"type": "string"}, {"type": "integer"}]));
+        assert_eq!(strict["properties"]["depth"]["type"], json!(["integer", "null"]));
+        assert_eq!(strict["properties"]["path"]["type"], json!("string"));
+
+        let conflicting = json!({"allOf": [{"type": "object"}, {"type": "string"}]});
+        assert!(make_strict(&conflicting).is_none());
+    }
+
+    #[test]
+    fn test_strip_null_arguments() {
+        assert_eq!(strip_null_arguments(r#"{"path":"a.rs","limit":null,"opts":{"depth":null,"all":true}}"#), r#"{"opts":{"all":true},"path":"a.rs"}"#);
+        assert_eq!(strip_null_arguments("{\"path\": "), "{\"path\": ");
+    }
+
+    #[test]
+    fn test_make_strict_rejects_free_form_objects() {
+        let open = json!({"type": "object", "properties": {"env": {"type": "object", "additionalProperties": {"type": "string"}}}});
+        assert!(make_strict(&open).is_none());
+        let untyped = json!({"type": "object", "properties": {"meta": {"type": "object"}}});
+        assert!(make_strict(&untyped).is_none());
+        assert!(make_strict(&json!({"type": "object"})).is_some());
+    }
+
+    #[test]
+    fn test_apply_strict_tools_with_exclusions_and_guided_json() {
+        use crate::models::OAIFunction;
+        let tool = |name: &str, parameters: Value| OAITool {
+            type_: "function".into(),
+            function: OAIFunction { name: name.into(), description: None, parameters, strict: None },
+            cache_control: None,
+        };
+        let params = json!({"type": "object", "properties": {"q": {"type": "string"}}, "required": ["q"]});
+        let mut oai = OAIChatReq {
+            tools: Some(vec![
+                tool("search", params.clone()),
+                tool("skip_me", params.clone()),
+                tool("free", json!({"type": "object", "additionalProperties": true})),
+            ]),
+            tool_choice: Some(json!({"type": "function", "function": {"name": "search"}})),
+            ..Default::default()
+        };
+        let skipped = apply_strict_tools(&mut oai, StrictTools::Vllm, &["skip_me".to_string()]);
+        assert_eq!(skipped, vec!["free".to_string()]);
+        let tools = oai.tools.as_ref().unwrap();
+        assert_eq!(tools[0].function.strict, Some(true));
+        assert_eq!(tools[1].function.strict, None);
+        assert_eq!(oai.guided_json.unwrap()["additionalProperties"], json!(false));
+    }
+
+    #[test]
+    fn test_strips_keywords_at_every_level() {
+        let mut schema = json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "additionalProperties": false,
+            "properties": {
+                "url": {"type": "string", "format": "uri"},
+                "items": {"type": "array", "items": {"type": "string", "format": "date-time"}}
+            }
+        });
+        cleaning(&["$schema", "format", "additionalProperties"], None).clean_schema(&mut schema);
+        assert_eq!(schema, json!({
+            "type": "object",
+            "properties": {
+                "url": {"type": "string"},
+                "items": {"type": "array", "items": {"type": "string"}}
+            }
+        }));
+    }
+
+    #[test]
+    fn test_property_named_like_a_keyword_is_kept() {
+        let mut schema = json!({"type": "object", "properties": {"format": {"type": "string", "format": "uri"}}});
+        cleaning(&["format"], None).clean_schema(&mut schema);
+        assert_eq!(schema, json!({"type": "object", "properties": {"format": {"type": "string"}}}));
+    }
+
+    #[test]
+    fn test_truncates_descriptions_on_char_boundary() {
+        let mut schema = json!({"type": "object", "properties": {"q": {"type": "string", "description": "héllo world"}}});
+        cleaning(&[], Some(3)).clean_schema(&mut schema);
+        assert_eq!(schema["properties"]["q"]["description"], "hél…");
+
+        let mut short = "ok".to_string();
+        cleaning(&[], Some(3)).shorten(&mut short);
+        assert_eq!(short, "ok");
+    }
+
+    #[test]
+    fn test_compact_drops_annotations_and_collapses_descriptions() {
+        let compact = cleaning(&["format"], Some(1_000)).compact();
+        assert_eq!(compact.max_description_chars, Some(TOKEN_EFFICIENT_DESCRIPTION_CHARS));
+        let mut schema = json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "properties": {
+                "title": {"type": "string", "title": "Title", "format": "uri", "examples": ["x"], "description": "The page\n\n   title"}
+            }
+        });
+        compact.clean_schema(&mut schema);
+        assert_eq!(schema, json!({"type": "object", "properties": {"title": {"type": "string", "description": "The page title"}}}));
+    }
+}